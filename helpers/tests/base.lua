@@ -0,0 +1,85 @@
+-- exercises the basic library, the output is compared with the reference interpreter
+print(type(nil), type(true), type(1), type("s"), type({}), type(print))
+print(tostring(nil), tostring(false), tostring(12), tostring(1.5), tostring(-0.0), tostring(1e100))
+local mt = {__tostring = function(t) return "point(" .. t.x .. ", " .. t.y .. ")" end}
+local p = setmetatable({x = 1, y = 2}, mt)
+print(tostring(p), p)
+print(pcall(function() return setmetatable({}, {__name = "MyType"}) < 1 end))
+
+print(tonumber("10"), tonumber("  0x1F  "), tonumber("1e2"), tonumber("0x1p4"), tonumber("abc"), tonumber(""))
+print(tonumber("ff", 16), tonumber("zz", 36), tonumber("-101", 2), tonumber("8", 8), tonumber("7fffffffffffffff", 16))
+print(tonumber(42), tonumber(nil), pcall(tonumber))
+print(pcall(tonumber, "10", 99))
+print(pcall(tonumber, 10, 16))
+
+local t = {10, 20, 30, nil, 50}
+local keys = {}
+for i, v in ipairs(t) do keys[#keys + 1] = i .. "=" .. v end
+print(#keys, keys[1], keys[2], keys[3])
+local count = 0
+for k, v in pairs({a = 1, b = 2, c = 3, 4, 5}) do count = count + 1 end
+print("pairs", count)
+local proxy = setmetatable({}, {__pairs = function(t) return function(_, k) if not k then return 1, "one" end end, t, nil end})
+for k, v in pairs(proxy) do print("__pairs", k, v) end
+print(next({}), next({7}), rawequal(next, pairs({})))
+print(pcall(next, {}, "missing"))
+
+print(select("#"), select("#", nil, nil), select(2, "a", "b", "c"), select(-1, "a", "b", "c"))
+print(pcall(select, 0, "a"))
+
+local r = setmetatable({}, {__index = function(t, k) return k * 2 end, __newindex = function(t, k, v) rawset(t, k, v + 1) end, __len = function() return 99 end})
+print(r[21], rawget(r, 21), #r, rawlen(r), rawlen("abc"))
+r[1] = 1
+print(r[1], rawget(r, 1), rawequal(r, r), rawequal(r, {}))
+print(pcall(rawset, {}, nil, 1))
+print(pcall(rawlen, 5))
+
+print(assert(1, "unused", 3))
+print(pcall(assert, false))
+print(pcall(assert, nil, "custom message"))
+print(select(2, pcall(assert, false, {code = 1})).code)
+print(pcall(error))
+print(pcall(error, "plain", 0))
+print(pcall(error, "with position"))
+local function lvl2() error("level two", 2) end
+print(pcall(function() lvl2() end))
+print(select("#", pcall(error, nil)))
+
+local protected = setmetatable({}, {__metatable = "locked"})
+print(getmetatable(protected), pcall(setmetatable, protected, {}))
+print(getmetatable({}) == nil)
+print(pcall(setmetatable, 1, {}))
+print(pcall(setmetatable, {}, 1))
+
+print(xpcall(function() error("oops") end, function(m) return "handled: " .. m end))
+print(xpcall(function(a, b) return a + b end, print, 3, 4))
+
+print(load("return 1", "chunk", "b"))
+local calls = 0
+print(load(function() calls = calls + 1; if calls < 3 then return calls == 1 and "\27Lua" or 1 end end))
+
+print(collectgarbage("isrunning"), collectgarbage("step"), collectgarbage("incremental"), collectgarbage("generational"), collectgarbage("incremental"))
+print(pcall(collectgarbage, "bogus"))
+warn("@on")
+print(pcall(warn))
+
+local a, b = pcall(function() local x = nil; return x.field end)
+print(a, b)
+print(pcall(function() return {} < {} end))
+print(pcall(function() return {} .. "" end))
+print(pcall(function() undefined_global() end))
+local eq = {__eq = function() return true end}
+print(setmetatable({}, eq) == setmetatable({}, eq), {} == {})
+local arith = setmetatable({}, {__add = function(a, b) return "added" end, __concat = function(a, b) return "joined" end, __call = function(self, x) return "called " .. x end, __lt = function() return true end})
+print(arith + 1, 1 + arith, arith .. "x", "x" .. arith, arith(5), arith < arith)
+do
+  local closed = setmetatable({}, {__close = function(o, e) print("closing", e) end})
+  local x <close> = closed
+  print("in block")
+end
+print(pcall(function()
+  local y <close> = setmetatable({}, {__close = function(o, e) print("closing on error", e) end})
+  error("boom", 0)
+end))
+print(pcall(function() local z <close> = 42 end))
+print(_VERSION, _G._G == _G, _G.print == print)
@@ -0,0 +1,7 @@
+local divmod, a, b = ...
+local function wrap(x, y)
+  -- tail call into the native function
+  return divmod(x, y)
+end
+local q, r = wrap(a, b)
+return q, r, wrap(a, b)
@@ -0,0 +1,185 @@
+-- debug library: stack introspection, locals, upvalues, hooks and tracebacks
+
+local function fields(t)
+  local keys = {}
+  for k in pairs(t) do keys[#keys + 1] = k end
+  table.sort(keys)
+  local out = {}
+  for _, k in ipairs(keys) do
+    local v = t[k]
+    if type(v) == "function" or type(v) == "table" then v = type(v) end
+    out[#out + 1] = k .. "=" .. tostring(v)
+  end
+  return table.concat(out, " ")
+end
+
+-- getinfo on functions
+local function add(a, b)
+  return a + b
+end
+print(fields(debug.getinfo(add)))
+print(fields(debug.getinfo(print)))
+print(fields(debug.getinfo(add, "L").activelines))
+print(debug.getinfo(print, "L").activelines)
+print(fields(debug.getinfo(function(...) return ... end, "Su")))
+print(pcall(debug.getinfo, add, ">S"))
+print(pcall(debug.getinfo, add, "Sx"))
+print(pcall(debug.getinfo, {}))
+print(debug.getinfo(100), debug.getinfo(-1))
+
+-- getinfo on levels
+local function where()
+  local info = debug.getinfo(2, "nSl")
+  return info.short_src .. ":" .. info.currentline .. " " .. tostring(info.name) .. " " .. info.namewhat .. " " .. info.what
+end
+local function caller()
+  return (where())
+end
+print(caller())
+local t = {method = function(self) return where() end}
+print(t:method())
+print(fields(debug.getinfo(1, "Slnt")))
+print(debug.getinfo(1, "f").func ~= nil, debug.getinfo(0, "n").name)
+local function tail() return debug.getinfo(1, "t").istailcall end
+local function calls_tail() return tail() end
+print(calls_tail(), (tail()))
+
+-- getlocal and setlocal
+local function locals(a, b, ...)
+  local c = a + b
+  local names = {}
+  local i = 1
+  while true do
+    local name, value = debug.getlocal(1, i)
+    if not name then break end
+    if type(value) == "table" then value = type(value) end
+    names[#names + 1] = name .. "=" .. tostring(value)
+    i = i + 1
+  end
+  local j = -1
+  while true do
+    local name, value = debug.getlocal(1, j)
+    if not name then break end
+    names[#names + 1] = name .. "=" .. tostring(value)
+    j = j - 1
+  end
+  return table.concat(names, " ")
+end
+print(locals(1, 2, "x", "y"))
+print(locals(10, 20))
+print(debug.getlocal(locals, 1), debug.getlocal(locals, 2), debug.getlocal(locals, 3), debug.getlocal(print, 1))
+print(pcall(debug.getlocal, 50, 1))
+local function set()
+  local x = 1
+  print(debug.setlocal(1, 1, 42), debug.setlocal(1, 10, 0))
+  return x
+end
+print(set())
+print(pcall(debug.setlocal, 50, 1, 1))
+local function varargs(...)
+  print(debug.setlocal(1, -2, "changed"), debug.setlocal(1, -5, 0))
+  return ...
+end
+print(varargs(1, 2, 3))
+
+-- upvalues
+local counter = 0
+local other = "other"
+local function inc() counter = counter + 1; return counter, other end
+print(debug.getupvalue(inc, 1), debug.getupvalue(inc, 2), debug.getupvalue(inc, 3))
+print(debug.setupvalue(inc, 1, 10), debug.setupvalue(inc, 5, 0))
+print(inc(), counter)
+print(debug.getupvalue(print, 1))
+print(pcall(debug.getupvalue, 1, 1))
+local function make()
+  local shared = 0
+  return function() shared = shared + 1; return shared end, function() return shared end
+end
+local f1, f2 = make()
+local g1, g2 = make()
+print(debug.upvalueid(f1, 1) == debug.upvalueid(f2, 1), debug.upvalueid(f1, 1) == debug.upvalueid(g1, 1))
+print(type(debug.upvalueid(f1, 1)), debug.upvalueid(f1, 2))
+f1()
+debug.upvaluejoin(g2, 1, f1, 1)
+print(g2(), debug.upvalueid(g2, 1) == debug.upvalueid(f1, 1))
+print(pcall(debug.upvaluejoin, f1, 3, g1, 1))
+print(pcall(debug.upvaluejoin, print, 1, g1, 1))
+
+-- metatables and user values
+local mt = {__metatable = "locked"}
+local object = setmetatable({}, mt)
+print(getmetatable(object), debug.getmetatable(object) == mt)
+print(debug.setmetatable(object, nil) == object, getmetatable(object))
+print(pcall(debug.setmetatable, object, 1))
+debug.setmetatable(10, {__index = {double = function(n) return n * 2 end}})
+print((21):double(), debug.getmetatable(1.5) ~= nil)
+debug.setmetatable(10, nil)
+print(debug.getmetatable(10), pcall(function() return (1):double() end))
+print(debug.getuservalue(1), debug.getuservalue(io.stdout), debug.getuservalue(io.stdout, 0))
+print(pcall(debug.setuservalue, {}, 1))
+print(debug.setuservalue(io.stdout, 1))
+
+-- hooks
+local events = {}
+local function hook(event, line)
+  local info = debug.getinfo(2, "Sn")
+  events[#events + 1] = event .. ":" .. (line or info.what .. ":" .. tostring(info.name))
+end
+local function hooked(n)
+  local s = 0
+  for i = 1, n do
+    s = s + i
+  end
+  return s
+end
+debug.sethook(hook, "crl")
+hooked(2)
+debug.sethook()
+print(table.concat(events, " "))
+print(debug.gethook())
+debug.sethook(hook, "l", 0)
+print(debug.gethook() == hook, select(2, debug.gethook()), select(3, debug.gethook()))
+debug.sethook()
+local count = 0
+debug.sethook(function(event) count = count + 1 end, "", 10)
+hooked(100)
+debug.sethook()
+print(count > 30, count < 1000)
+events = {}
+local function tailed(...) return select("#", ...) end
+local function transfer(...) return tailed(...) end
+debug.sethook(function(event)
+  local info = debug.getinfo(2, "nr")
+  events[#events + 1] = event .. ":" .. tostring(info.name) .. ":" .. info.ftransfer .. ":" .. info.ntransfer
+end, "cr")
+transfer(1, 2, 3)
+debug.sethook()
+print(table.concat(events, " "))
+print(pcall(debug.sethook, hook))
+print(pcall(debug.sethook, 1, "c"))
+print(pcall(function()
+  debug.sethook(function()
+    debug.sethook()
+    error("from hook")
+  end, "l")
+  local x = 1
+end))
+
+-- tracebacks
+local function strip(s)
+  -- the bottom of the stack belongs to the host: the standalone interpreter runs the chunk
+  -- from a C function, shown as '[C]: in ?', while hosts calling it directly have no frame
+  return (string.gsub(s, "\n\t%[C%]: in %?$", ""))
+end
+local function level3() return debug.traceback("message", 1) end
+local function level2() return level3() end
+print(strip(level2()))
+print(strip(debug.traceback()))
+print(debug.traceback({}) ~= nil, debug.traceback(nil, 100), debug.traceback(12))
+-- native functions are frames too, from level 0 the traceback itself
+print(strip(debug.traceback("from level 0", 0)))
+print(strip(select(2, pcall(debug.traceback, "under pcall", 0))))
+print(strip((string.gsub("x", "x", function() return debug.traceback("in gsub", 0) end))))
+local function deep(n) if n == 0 then return debug.traceback() end return (deep(n - 1)) end
+print(strip(deep(30)))
+print(strip(select(2, xpcall(function() local x = nil; return x.y end, debug.traceback))))
@@ -0,0 +1,11 @@
+local function add(a, b) return a + b end
+local s = string.dump(add)
+print(#s > 0, s:sub(1, 4) == "\27Lua")
+local f = load(s)
+print(f(2, 3))
+local g = load(string.dump(function(x) return x * 2 end, true))
+print(g(21))
+print(pcall(string.dump, print))
+print(pcall(string.dump, 1))
+print(string.dump(load("return 1")) == string.dump(load("return 1")))
+print(#string.dump(add, true) < #s)
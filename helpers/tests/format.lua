@@ -0,0 +1,37 @@
+-- string.format against the reference printf behaviour
+local f = string.format
+print(f("%d %i %u %c%c %x %X %o %%", 42, -7, 3, 72, 105, 255, 255, 8))
+print(f("[%5d][%-5d][%05d][%+d][% d][%.3d][%.0d][%5.3d]", 42, 42, 42, 42, 42, 7, 0, 7))
+print(f("[%#x][%#X][%#o][%#o][%#5x][%x][%u][%o]", 255, 255, 8, 0, 0, -1, -1, -1))
+print(f("%d %x", (-9223372036854775807 - 1), (-9223372036854775807 - 1)), f("%d", 3.0), f("%x", "16"))
+print(f("[%e][%E][%.2e][%#.0e][%+e][%12.4e][%-12.4e]", 12345.678, 1e-10, 0.0, 5.0, 1.5, -3.25, 3.25))
+print(f("[%f][%.2f][%.0f][%#.0f][%010.3f][%-10.1f][% f][%.20f]", 3.14159, 2.675, 2.5, 5.0, -3.14159, 1.25, 1.0, 0.1))
+print(f("[%g][%g][%g][%g][%.3g][%#g][%G][%g][%.0g]", 100000.0, 1000000.0, 1e-5, 0.0001, 3.14159, 1.0, 1e-20, 2^63, 123.0))
+print(f("[%a][%A][%.0a][%.1a][%.3a][%a][%a][%#a][%20a]", 1.0, 255.5, 1.5, 1.96875, 1.0, 0.1, -0.0, 1.0, 0.5))
+print(f("[%f][%e][%g][%5.1f][%-6f][%+f][%010f][%a]", 1/0, -1/0, 1/0, 1/0, -1/0, 1/0, 1/0, -1/0))
+print(f("[%s][%10s][%-10s][%.2s][%5.1s][%s][%s][%s]", "abc", "abc", "abc", "abc", "abc", 12, 1.5, nil))
+print(f("%s %s", true, setmetatable({}, {__tostring = function() return "custom" end})))
+print(string.match(f("%s", setmetatable({}, {__name = "MyType"})), "^MyType: ") ~= nil)
+print(f("%q", "line1\nline2\t\"quoted\" back\\slash \0 \0001 \r\127"))
+print(f("%q %q %q %q %q %q %q", 1, -1, (-9223372036854775807 - 1), 9223372036854775807, 1.0, 0.1, -2.5))
+print(f("%q %q %q %q %q", 1/0, -1/0, nil, true, false))
+print(f("%q", 2^-1074), f("%q", 1e300), f("%5s", "\200\201") == "   \200\201")
+print(f("%s", string.rep("x", 120)) == string.rep("x", 120), #f("%-5s", string.rep("y", 150)))
+print(f("no conversions"), f(""), f("%c", 0) == "\0", f("%p", 1), f("%p", nil))
+print(pcall(f, "%d", 3.5))
+print(pcall(f, "%d", "x"))
+print(pcall(f, "%d"))
+print(pcall(f, "%10q", "x"))
+print(pcall(f, "%q", {}))
+print(pcall(f, "%y", 1))
+print(pcall(f, "%", 1))
+print(pcall(f, "%ld", 1))
+print(pcall(f, "%123d", 1))
+print(pcall(f, "%#d", 1))
+print(pcall(f, "%.3c", 65))
+print(pcall(f, "%0-5d", 1))
+print(pcall(f, "%-----------------------d", 1))
+print(pcall(f, "%5s", "a\0b"))
+print(pcall(f, "%s", setmetatable({}, {__tostring = function() return 1 end})))
+print(pcall(f, "%s", setmetatable({}, {__tostring = function() return {} end})))
+print(pcall(f))
@@ -0,0 +1,106 @@
+-- io library: files, formats, lines, seek, popen and error results
+local name = os.tmpname()
+
+print(io.type(io.stdout), io.type(42), io.type(nil))
+print(io.write("written ", 1, " ", 2.5, " ", 1.0, " ", 2^63, "\n") == io.stdout)
+
+local f = assert(io.open(name, "w"))
+print(io.type(f))
+print(f:write("line one\n", "line two\n", 42, " ", -3.5e3, " 0x1Fp2 nan\n", "last") == f)
+print(f:close())
+print(io.type(f))
+print(pcall(f.write, f, "x"))
+print(select(2, pcall(f.close, f)))
+print(tostring(f))
+
+f = assert(io.open(name, "r"))
+print(f:read("l"))
+print(f:read("L"))
+print(f:read("n", "n", "n"))
+print(f:read("n"))
+print(f:read(0))
+print(f:read(3), f:read("a"))
+print(f:read("a"), f:read("l"), f:read(0), f:read(1))
+print(f:seek("set", 5), f:read(3))
+print(f:seek(), f:seek("cur", 1), f:read("*l"))
+print(f:seek("end"), f:read(1))
+print(f:write("x"))
+f:close()
+
+for l in io.lines(name) do io.write("[", l, "]") end
+print()
+for a, b in io.lines(name, 4, "l") do print(a, b) end
+for l in io.lines(name, "L") do io.write(l) end
+print()
+
+f = assert(io.open(name))
+local n = 0
+for c in f:lines(1) do n = n + 1 end
+print(n, io.type(f))
+print(pcall(f.lines(f)))
+f:close()
+print(pcall(f.lines, f))
+
+f = assert(io.open(name, "a+"))
+f:write("\nappended")
+f:seek("set")
+print(f:read("a"))
+f:close()
+
+f = assert(io.open(name, "w+"))
+f:write("12 0x10 .5 1e2 - 9")
+f:seek("set")
+print(f:read("n", "n", "n", "n"))
+print(f:read("n"))
+print(f:read("a"))
+f:close()
+
+print(io.open("/nonexistent/dir/file"))
+print(pcall(io.open, name, "rw"))
+print(pcall(io.open, name, "r+bx"))
+print(io.open(name, "r+b"):close())
+print(pcall(io.lines, "/nonexistent/dir/file"))
+print(pcall(io.read, "x"))
+print(pcall(io.read, {}))
+print(io.stdout:close())
+print(io.stdout:setvbuf("no"))
+print(pcall(io.stdout.setvbuf, io.stdout, "sometimes"))
+print(pcall(io.stdout.seek, io.stdout, "here"))
+print(pcall(io.lines, name, table.unpack({}, 1, 251)))
+
+local old = io.output()
+print(io.output(name) ~= old, io.type(io.output()))
+io.write("through default output\n")
+print(io.close())
+print(pcall(io.write, "x"))
+io.output(old)
+print(io.output() == io.stdout)
+io.input(name)
+print(io.read())
+print(io.read())
+io.close(io.input())
+print(pcall(io.read))
+io.input(io.stdin)
+
+local t = io.tmpfile()
+t:write("temporary")
+t:seek("set", 4)
+print(t:read("a"))
+t:close()
+
+local p = io.popen("echo from the shell; echo second")
+print(p:read("l"), p:read("a"))
+print(p:close())
+print(io.popen("exit 3"):close())
+p = io.popen("cat > " .. name, "w")
+p:write("piped in")
+print(p:close())
+print(io.open(name):read("a"))
+print(pcall(io.popen, "ls", "rw"))
+
+do
+  local h <close> = assert(io.open(name))
+  f = h
+end
+print(io.type(f))
+print(os.remove(name))
@@ -0,0 +1,10 @@
+-- compiled with a luac built with LUA_32BITS: 4 byte integers and floats
+local t = {1.5, 2, "x", 100000}
+local function sum(list)
+  local s = 0
+  for _, v in ipairs(list) do
+    if type(v) == "number" then s = s + v end
+  end
+  return s
+end
+print(sum(t), #t, 0.5, -7)
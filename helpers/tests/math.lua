@@ -0,0 +1,38 @@
+print(math.pi, math.huge, -math.huge, math.maxinteger, math.mininteger)
+print(math.abs(-3), math.abs(-3.5), math.abs(math.mininteger), math.abs(-0.0))
+print(math.floor(3.7), math.floor(-3.7), math.floor(5), math.floor(1e100), math.floor(-0.0))
+print(math.ceil(3.2), math.ceil(-3.2), math.ceil(5), math.ceil(2^63), math.ceil("2.5"))
+print(math.fmod(7, 3), math.fmod(-7, 3), math.fmod(7, -3), math.fmod(math.mininteger, -1))
+print(math.fmod(7.5, 2), math.fmod(-7.5, 2), math.fmod(1, 0.0), math.fmod(5, math.huge))
+print(pcall(math.fmod, 1, 0))
+print(math.modf(3.7), math.modf(-3.7), math.modf(5), math.modf(math.huge), math.modf(-math.huge))
+print(math.sqrt(16), math.sqrt(2), math.exp(0), math.exp(1))
+print(math.log(1), math.log(8, 2), math.log(1000, 10), math.log(81, 3), math.log(0))
+print(math.sin(0), math.cos(0), math.tan(1), math.asin(1), math.acos(0))
+print(math.atan(1), math.atan(1, -1), math.atan(-0.0, -1), math.deg(math.pi), math.rad(180))
+print(math.tointeger(3.0), math.tointeger(3.5), math.tointeger("8"), math.tointeger({}), math.tointeger(2^63))
+print(pcall(math.tointeger))
+print(math.type(1), math.type(1.0), math.type("1"), math.type(nil))
+print(pcall(math.type))
+print(math.ult(1, 2), math.ult(-1, 2), math.ult(2, -1))
+print(math.max(3, 7.5, -1), math.min(3, 7.5, -1), math.max(2, 2.0), math.min("a", "b"))
+print(pcall(math.max))
+print(pcall(math.min, 1, {}))
+
+print(math.randomseed(42))
+local values = {}
+for i = 1, 5 do values[#values + 1] = math.random(100) end
+print(table.concat(values, " "))
+print(math.random(), math.random(0), math.random(-10, 10), math.random(3, 3))
+print(math.random(math.mininteger, math.maxinteger), math.random(1, 1 << 40))
+print(math.randomseed(7, 11))
+print(math.random(0), math.random(6), math.random())
+math.randomseed(42)
+print(math.random(100))
+print(pcall(math.random, 2, 1))
+print(pcall(math.random, 1, 2, 3))
+print(pcall(math.random, 1.5))
+local a, b = math.randomseed()
+print(math.type(a), math.type(b))
+local r = math.random()
+print(r >= 0 and r < 1)
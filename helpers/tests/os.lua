@@ -0,0 +1,47 @@
+local function fields(t)
+  return string.format("%d-%d-%d %d:%d:%d yday=%d wday=%d", t.year, t.month, t.day, t.hour, t.min, t.sec, t.yday, t.wday)
+end
+
+print(os.date("!%Y-%m-%d %H:%M:%S", 0), os.date("!%c", 86400 * 365))
+print(os.date("!%A %a %B %b %j %p %y %C %e %D %T %%", 1700000000))
+print(os.date("!%Ey %OS %F %R %u %w", 1234567890))
+print(os.date("!literal text, no specifiers", 0), os.date("!", 0) == "")
+print(fields(os.date("!*t", 1700000000)), os.date("!*t", 0).isdst)
+print(pcall(os.date, "%Q"))
+print(pcall(os.date, "%E"))
+print(pcall(os.date, "%Ez"))
+print(pcall(os.date, "*t", 1.5))
+print(type(os.date()), type(os.date("*t").isdst), math.type(os.time()))
+
+-- normalisation of out of range fields
+local t = {year = 2023, month = 14, day = 35, hour = 25, min = 61, sec = -1}
+local stamp = os.time(t)
+print(fields(t), math.type(stamp))
+local a = os.time({year = 2024, month = 1, day = 10, hour = 0})
+local b = os.time({year = 2024, month = 1, day = 1, hour = 0})
+print(a - b, os.difftime(a, b), os.difftime(b, a), math.type(os.difftime(a, b)))
+print(os.time({year = 2024, month = 1, day = 1}) - b)
+print(pcall(os.time, {year = 2024, month = 1}))
+print(pcall(os.time, {year = 2024, month = "x", day = 1}))
+print(pcall(os.time, {year = 2024, month = 1.5, day = 1}))
+print(pcall(os.time, {year = 2024, month = 1, day = 2^40}))
+print(pcall(os.time, 5))
+print(pcall(os.difftime, 1))
+
+local c = os.clock()
+print(math.type(c), c >= 0)
+print(os.getenv("RUSTYMOON_SURELY_UNSET_VARIABLE"))
+print(pcall(os.getenv))
+
+local name = os.tmpname()
+print(type(name), string.sub(name, 1, 9))
+local renamed = name .. ".renamed"
+print(os.rename(name, renamed))
+print(os.remove(renamed))
+local ok, message, errno = os.remove(renamed)
+print(ok, message == renamed .. ": No such file or directory", errno)
+print(os.rename(renamed, name))
+print(os.execute())
+print(os.execute("exit 3"))
+print(os.execute("true"))
+print(os.execute("kill -9 $$"))
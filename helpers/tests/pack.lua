@@ -0,0 +1,59 @@
+-- string.pack / string.unpack / string.packsize
+local function hex(s)
+  return (string.gsub(s, ".", function(c) return string.format("%02x", string.byte(c)) end))
+end
+local pack, unpack, packsize = string.pack, string.unpack, string.packsize
+
+print(hex(pack("i4", 100)), hex(pack(">i4", 100)), hex(pack("<i2 >i2 =i2", 1, 1, 1)))
+print(hex(pack("b B h H", -1, 255, -2, 65535)))
+print(hex(pack("j J", -1, -1)), hex(pack("T", 10)), hex(pack("l L", -3, 3)))
+print(hex(pack("f d n", 1.5, -2.25, 0.1)), hex(pack(">f", 1.5)))
+print(hex(pack("i16", -2)), hex(pack(">I16", 5)), hex(pack("i3", -100)), hex(pack("I7", 1 << 50)))
+print(hex(pack("s1", "hi")), hex(pack(">s2", "abc")), hex(pack("s", "x")), hex(pack("z", "zz")))
+print(hex(pack("c5", "ab")), hex(pack("c0", "")), hex(pack("x x", 1)))
+print(hex(pack("!4 b i4", 1, 2)), hex(pack("!8 b d", 1, 1.0)), hex(pack("!2 b i8", 1, 2)), hex(pack("b Xi4 b", 1, 2)))
+print(hex(pack("!b Xd", 1)), hex(pack(" < i2 > i2 ", 3, 3)))
+
+print(unpack("i4", pack("i4", -100)))
+print(unpack("<i2 >i2 B b", "\1\0\0\1\255\255"))
+print(unpack("j J", pack("j J", -5, -1)))
+print(unpack("f d n", pack("f d n", 1.5, -2.25, 0.1)))
+print(unpack("i16", pack("i16", -2)), unpack("I16", pack("I16", 12345)))
+print(unpack("i3 I3", pack("i3 I3", -100, 100)))
+print(unpack("s1 z c3", pack("s1 z c3", "hello", "world", "abc")))
+print(unpack("!4 b i4", pack("!4 b i4", 7, 8)))
+print(unpack("b", "\1\2\3", 2), unpack("b", "\1\2\3", -1), unpack("z", "a\0b\0", 3))
+print(unpack("", "abc"), unpack("", "abc", 4))
+print(unpack("I1 I1 I1", "\1\2\3"))
+
+print(packsize("i4"), packsize("!8 b d"), packsize("b i8"), packsize("!b i8"), packsize("c10 x"), packsize(""))
+print(pcall(packsize, "!4 i3"))
+print(packsize("!4 b Xi4"), packsize("j n"))
+
+print(pcall(pack, "i17", 1))
+print(pcall(pack, "i0", 1))
+print(pcall(pack, "b", 128))
+print(pcall(pack, "B", 256))
+print(pcall(pack, "B", -1))
+print(pcall(pack, "i2", 40000))
+print(pcall(pack, "c", "x"))
+print(pcall(pack, "c2", "xyz"))
+print(pcall(pack, "y", 1))
+print(pcall(pack, "z", "a\0b"))
+print(pcall(pack, "s1", string.rep("x", 256)))
+print(pcall(pack, "!3 i4", 1))
+print(pcall(pack, "X", 1))
+print(pcall(pack, "Xc1", 1))
+print(pcall(pack, "i4", "x"))
+print(pcall(pack, "i4", 1.5))
+print(pcall(pack, "d"))
+print(pcall(packsize, "s"))
+print(pcall(packsize, "z"))
+print(pcall(packsize, "c2147483647 c2"))
+print(pcall(unpack, "i4", "abc"))
+print(pcall(unpack, "z", "abc"))
+print(pcall(unpack, "s1", "\5abc"))
+print(pcall(unpack, "i9", "\0\0\0\0\0\0\0\0\1"))
+print(pcall(unpack, "i9", "\255\255\255\255\255\255\255\255\255"))
+print(pcall(unpack, "b", "abc", 5))
+print(pcall(unpack, "b", "abc", 4))
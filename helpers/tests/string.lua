@@ -0,0 +1,77 @@
+-- string library, called through the 'string' table
+
+print(string.len("hello"), string.len(""), string.len("a\0b"))
+print(string.sub("hello", 2), string.sub("hello", -3, -2), string.sub("hello", 0), string.sub("hello", 10), string.sub("hello", 3, 2))
+print(string.sub("hello", -100, 100), string.sub(12345, 2, 3))
+print(string.upper("Hello, World"), string.lower("Hello, World"), string.reverse("abc\0d"))
+print(string.rep("ab", 3), string.rep("ab", 3, ","), string.rep("x", 0), string.rep("x", -1), string.rep("", 5, ""))
+print(string.byte("ABC"), string.byte("ABC", 2), string.byte("ABC", 1, -1), string.byte("ABC", 10))
+print(select("#", string.byte("", 1)), string.byte("ABC", -1))
+print(string.char(72, 105), string.char(), string.len(string.char(0, 255)))
+print(pcall(string.char, 256))
+print(pcall(string.rep, "x", 1 << 40))
+
+-- find
+print(string.find("hello world", "o w"), string.find("hello world", "l"), string.find("hello", "xyz"))
+print(string.find("hello", "l", 1, true), string.find("a.b", ".", 1, true), string.find("a.b", "%."))
+print(string.find("hello", "", 3), string.find("hello", "", 10), string.find("hello", "", 6))
+print(string.find("hello", "l+"), string.find("hello", "^h"), string.find("hello", "^e"), string.find("hello", "o$"))
+print(string.find("key = value", "(%w+)%s*=%s*(%w+)"))
+print(string.find("abc", "()b()"))
+print(string.find("hello", "l", -2), string.find("hello", "h", -100))
+
+-- match
+print(string.match("hello 123 world", "%d+"), string.match("hello", "(h)(e)"), string.match("hello", "x"))
+print(string.match("  trim  ", "^%s*(.-)%s*$") .. "|")
+print(string.match("f(a(b)c)d", "%b()"), string.match("THE (quick) fox", "%f[%a]%a+"))
+print(string.match("[[x]]", "[%]]+"), string.match("a-b", "[a%-]+"), string.match("2024-01-15", "(%d+)-(%d+)-(%d+)"))
+print(string.match("say \"hi\" now", "([\"'])(.-)%1"))
+print(string.match("abc", "()"), string.match("abc", "()", 4), string.match("x", "a?x"), string.match("aaa", "a-$"))
+print(string.match("\0abc\0", "%z"), string.match("hello", ".-(l+)"), string.match("ab12", "%x+"), string.match("A_b", "[%u_]+"))
+print(string.match("hello", "[^aeiou]+"), string.match("hello", "[a-f]+"), string.match("#$%", "%p+"), string.match("a\tb", "%c"))
+
+-- gmatch
+for w in string.gmatch("one two  three", "%a+") do print(w) end
+for k, v in string.gmatch("a=1, b=2, c=3", "(%w+)=(%w+)") do print(k, v) end
+for p in string.gmatch("abc", "()") do print(p) end
+for w in string.gmatch("hello world", "o", 6) do print("o at", w) end
+local it = string.gmatch("xyz", ".")
+print(it(), it(), it(), it())
+
+-- gsub
+print(string.gsub("hello world", "o", "0"))
+print(string.gsub("hello world", "o", "0", 1))
+print(string.gsub("hello world", "(%w+)", "<%1>"))
+print(string.gsub("hello world", "%w+", "%0 %0"))
+print(string.gsub("abc", "", "-"))
+print(string.gsub("hello", "l", "%%"))
+print(string.gsub("abc", "%w", {a = "1", c = false}))
+print(string.gsub("$name is $age", "%$(%w+)", {name = "Bob", age = 42}))
+print(string.gsub("abc", ".", function(c) return string.byte(c) end))
+print(string.gsub("abc", ".", function(c) if c == "b" then return nil end return c .. c end))
+print(string.gsub("abc", "()", "%1"))
+print(string.gsub("hello world", "^h", "H"))
+print(string.gsub("  x  ", "^%s+", ""))
+print(string.gsub(12345, "3", "x"), string.gsub(12345, "9", "x"))
+print(string.gsub("abc", "b", 7))
+
+-- errors
+print(pcall(string.find, "a", "%"))
+print(pcall(string.find, "a", "[a"))
+print(pcall(string.find, "a", "%b"))
+print(pcall(string.find, "a", "%fx"))
+print(pcall(string.find, "a", "(a"))
+print(pcall(string.find, "a", "a)"))
+print(pcall(string.find, "a", "%1"))
+print(pcall(string.find, "a", "(a)%2"))
+print(pcall(string.gsub, "a", "a", "%2"))
+print(pcall(string.gsub, "a", "a", "%x"))
+print(pcall(string.gsub, "a", "a", "%"))
+print(pcall(string.gsub, "a", "a", {a = {}}))
+print(pcall(string.gsub, "a", "a", function() return {} end))
+print(pcall(string.gsub, "a", "a"))
+print(pcall(string.match, string.rep("a", 300), string.rep(".?", 300)))
+print(pcall(string.find, "a", string.rep("()", 33)))
+print(pcall(string.len))
+print(pcall(string.sub, "x", {}))
+print(pcall(string.sub, "x", 1.5))
@@ -0,0 +1,33 @@
+-- strings share a metatable whose __index is the string table
+local s = "Hello, World"
+print(s:upper(), s:lower(), s:len(), s:sub(1, 5), s:rep(2, "|"))
+print(("%d items at %5.2f"):format(3, 1.5))
+print(("x"):byte(), s:find("World"), s:match("(%a+), (%a+)"))
+print(s:gsub("o", "0"))
+for word in s:gmatch("%a+") do io.write(word, ";") end
+print()
+print(getmetatable("") == getmetatable(s), getmetatable("").__index == string)
+
+-- new string functions are visible to methods
+function string.shout(str) return str:upper() .. "!" end
+print(("hi"):shout())
+string.shout = nil
+print(pcall(function() return ("hi"):shout() end))
+
+-- arithmetic converts numerical strings
+print("10" + 1, "3" * "4", -"2", "0x10" / 2, "7" % "3", "2" ^ "3", "7" // 2.0, " 5 " - 1)
+print(math.type("10" + 1), math.type("10.0" + 1))
+print(pcall(function() return "abc" + 1 end))
+print(pcall(function() return {} + "1" end))
+print(pcall(function() return -"x" end))
+print(pcall(function() return "1" // "0" end))
+print(pcall(function() return "3" & 1 end))
+local mt = {__add = function(a, b) return "added" end}
+print("1" + setmetatable({}, mt), "x" + setmetatable({}, mt))
+
+-- other types have no metatable until someone sets one
+print(getmetatable(1), getmetatable(true), getmetatable(print))
+debug.setmetatable(0, {__index = math})
+print((2.5):floor(), (16):sqrt())
+debug.setmetatable(0, nil)
+print(getmetatable(1), pcall(function() return (1):floor() end))
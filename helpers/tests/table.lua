@@ -0,0 +1,79 @@
+local function show(t, n)
+  local parts = {}
+  for i = 1, n or #t do parts[#parts + 1] = tostring(t[i]) end
+  print(table.concat(parts, ","))
+end
+
+local t = {10, 20, 30}
+table.insert(t, 40)
+table.insert(t, 1, 5)
+table.insert(t, 3, 15)
+show(t)
+print(table.remove(t), table.remove(t, 1), table.remove(t, 2))
+show(t)
+print(table.remove({}), #t)
+print(pcall(table.insert, t, 9, 1))
+print(pcall(table.insert, t, 1, 2, 3))
+print(pcall(table.remove, t, 7))
+
+print(table.concat({1, 2, "three", 4.5}, "-"))
+print(table.concat({"a", "b", "c"}, ", ", 2, 3))
+print(table.concat({}, "x"), table.concat({"a"}, "x", 3, 2))
+print(pcall(table.concat, {1, {}, 3}))
+
+local p = table.pack(1, nil, 3)
+print(p.n, p[1], p[2], p[3])
+print(table.unpack({1, 2, 3}))
+print(table.unpack({1, 2, 3}, 2))
+print(table.unpack({1, 2, 3}, 2, 5))
+print(select("#", table.unpack({}, 1, 0)))
+print(pcall(table.unpack, {}, 1, 1e8))
+print(pcall(table.unpack, {}, -(9223372036854775807 - 1) + 0, 9223372036854775807))
+
+local m = {1, 2, 3, 4, 5}
+table.move(m, 1, 3, 2)
+show(m)
+m = {1, 2, 3, 4, 5}
+table.move(m, 2, 5, 1)
+show(m)
+local dest = table.move({1, 2, 3}, 1, 3, 3, {})
+show(dest, 5)
+print(pcall(table.move, {}, 1, 9223372036854775807, 2))
+print(pcall(table.move, {}, 1, 2, 9223372036854775807))
+
+local s = {5, 3, 8, 1, 9, 2, 7, 4, 6, 0}
+table.sort(s)
+show(s)
+table.sort(s, function(a, b) return a > b end)
+show(s)
+local words = {"pear", "apple", "fig", "banana"}
+table.sort(words)
+show(words)
+print(pcall(table.sort, {3, 1, 2}, 1))
+print(pcall(table.sort, {1, "x", 2}))
+local big = {}
+for i = 1, 200 do big[i] = (i * 37) % 101 end
+print(pcall(table.sort, big, function(a, b) return true end))
+local ok = true
+table.sort(big)
+for i = 2, #big do if big[i - 1] > big[i] then ok = false end end
+print(ok)
+
+-- proxies going through metamethods
+local store = {}
+local log = {}
+local proxy = setmetatable({}, {
+  __index = function(_, k) return store[k] end,
+  __newindex = function(_, k, v) log[#log + 1] = k; store[k] = v end,
+  __len = function() return #store end,
+})
+table.insert(proxy, "a")
+table.insert(proxy, "b")
+table.insert(proxy, 1, "c")
+show(store)
+print(table.concat(proxy, "+"), table.unpack(proxy))
+table.sort(proxy)
+show(store)
+print(table.remove(proxy, 1), #store, #log)
+print(pcall(table.insert, setmetatable({}, {__len = function() return 2.5 end}), 1))
+print(pcall(table.concat, 42))
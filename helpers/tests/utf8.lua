@@ -0,0 +1,41 @@
+local s = "häll€ 𝄞!"
+print(utf8.len(s), #s, utf8.len(""), utf8.len(s, 3), utf8.len(s, -5), utf8.len(s, 1, 2))
+print(utf8.char(72, 228, 8364, 119070), utf8.char(), utf8.char(0x41))
+print(#utf8.char(0x7FFFFFFF), string.byte(utf8.char(0x7FFFFFFF), 1, -1))
+print(#utf8.char(0x10FFFF), #utf8.char(0x110000), #utf8.char(0x3FFFFFF), #utf8.char(0x4000000))
+print(pcall(utf8.char, 0x80000000))
+print(pcall(utf8.char, -1))
+print(utf8.codepoint(s), utf8.codepoint(s, 2), utf8.codepoint(s, 1, -1))
+print(select("#", utf8.codepoint(s, 3, 2)))
+print(pcall(utf8.codepoint, s, 3))
+print(pcall(utf8.codepoint, s, 0))
+print(pcall(utf8.codepoint, s, 1, 100))
+for p, c in utf8.codes(s) do print(p, c) end
+for p, c in utf8.codes("ab\u{7FFFFFFF}", true) do print(p, c) end
+print(pcall(function() for p, c in utf8.codes("ab\u{7FFFFFFF}") do end end))
+print(pcall(function() for p, c in utf8.codes("a\xffb") do end end))
+print(pcall(function() for p, c in utf8.codes("a\xe4\x80") do end end))
+print(pcall(utf8.codes, "\x80abc"))
+
+-- overlong sequences and surrogates
+print(utf8.len("\xC0\x80"), utf8.len("\xE0\x80\x80"), utf8.len("\xF0\x80\x80\x80"))
+print(utf8.len("\xED\xA0\x80"), utf8.len("\xED\xA0\x80", 1, -1, true))
+print(utf8.len("\xF4\x90\x80\x80"), utf8.len("\xF4\x90\x80\x80", 1, -1, true))
+print(utf8.len("abc\xE4"), utf8.len("\xFE\x80\x80\x80\x80\x80\x80", 1, -1, true))
+print(pcall(utf8.codepoint, "\xED\xA0\x80"))
+print(utf8.codepoint("\xED\xA0\x80", 1, 1, true), utf8.codepoint("\xF4\x90\x80\x80", 1, 1, true))
+print(pcall(utf8.len, "abc", 5))
+print(pcall(utf8.len, "abc", 1, 4))
+print(utf8.len("abc", 4), utf8.len("abc", 4, 3))
+
+-- offsets
+print(utf8.offset(s, 1), utf8.offset(s, 2), utf8.offset(s, 3), utf8.offset(s, 6), utf8.offset(s, 9), utf8.offset(s, 10))
+print(utf8.offset(s, -1), utf8.offset(s, -2), utf8.offset(s, -9), utf8.offset(s, -10))
+print(utf8.offset(s, 0, 3), utf8.offset(s, 0, 6), utf8.offset(s, 0, 11), utf8.offset(s, 2, 5))
+print(pcall(utf8.offset, s, 1, 3))
+print(pcall(utf8.offset, s, 1, 100))
+print(utf8.offset("", 1), utf8.offset("", -1), utf8.offset("abc", 5))
+
+local count = 0
+for c in string.gmatch(s, utf8.charpattern) do count = count + 1 end
+print(count, #utf8.charpattern)
@@ -0,0 +1,409 @@
+/*
+ * Control-flow graphs of function prototypes. The code splits into basic blocks at jump
+ * targets and after branches; dominators and natural loops are computed over the blocks, and
+ * the graph of a function, or of a chunk with its nested functions, can be written in DOT
+ */
+
+use std::{collections::BTreeSet, fmt::Write};
+
+use crate::{core::{instruction::Instruction, types::Proto}, vm::debug::{chunk_id, get_func_line}};
+
+/**
+ * Instructions from 'start' up to 'end', entered only at 'start' and left only after 'end - 1'
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+}
+
+/**
+ * The blocks whose back edges go to 'header': 'latches' are the blocks they come from
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NaturalLoop {
+    pub header: usize,
+    pub latches: Vec<usize>,
+    pub blocks: BTreeSet<usize>,
+}
+
+#[derive(Debug)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    /* the block of each instruction */
+    block_of: Vec<usize>,
+}
+
+/**
+ * The instructions that can run after the one at 'pc', in a function of 'len' instructions.
+ * Tests skip the jump after them when the condition fails, and for loops either go back to
+ * their body or fall out of the loop
+ */
+pub fn successors(pc: usize, instruction: &Instruction, len: usize) -> Vec<usize> {
+    use Instruction::*;
+    let next = pc as i64 + 1;
+    let targets = match *instruction {
+        Jmp { sj } => vec![next + sj as i64],
+        Eq { .. } | Lt { .. } | Le { .. } | EqK { .. } | EqI { .. } | LtI { .. } | LeI { .. } | GtI { .. } | GeI { .. }
+        | Test { .. } | TestSet { .. } => vec![next, next + 1],
+        LFalseSkip { .. } => vec![next + 1],
+        /* FORPREP skips the loop when it runs no iteration, TFORPREP goes to the TFORCALL */
+        ForPrep { bx, .. } => vec![next, next + bx as i64 + 1],
+        TForPrep { bx, .. } => vec![next + bx as i64],
+        ForLoop { bx, .. } | TForLoop { bx, .. } => vec![next, next - bx as i64],
+        Return { .. } | Return0 { .. } | Return1 { .. } | TailCall { .. } => vec![],
+        _ => vec![next],
+    };
+    let mut targets: Vec<usize> = targets.into_iter().filter(|&target| (0..len as i64).contains(&target)).map(|target| target as usize).collect();
+    targets.dedup();
+    targets
+}
+
+/* an instruction after which control may not go on to the next one */
+fn ends_block(instruction: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(instruction,
+        Jmp { .. } | Eq { .. } | Lt { .. } | Le { .. } | EqK { .. } | EqI { .. } | LtI { .. } | LeI { .. } | GtI { .. }
+        | GeI { .. } | Test { .. } | TestSet { .. } | LFalseSkip { .. } | ForPrep { .. } | TForPrep { .. }
+        | ForLoop { .. } | TForLoop { .. } | Return { .. } | Return0 { .. } | Return1 { .. } | TailCall { .. })
+}
+
+impl Cfg {
+    pub fn new(proto: &Proto) -> Self {
+        let code: Vec<Instruction> = proto.code.iter().map(|instruction| instruction.decoded()).collect();
+        let len = code.len();
+        let mut leaders = BTreeSet::new();
+        if len > 0 {
+            leaders.insert(0);
+        }
+        for (pc, instruction) in code.iter().enumerate() {
+            if ends_block(instruction) {
+                leaders.extend(successors(pc, instruction, len));
+                if pc + 1 < len {
+                    leaders.insert(pc + 1);
+                }
+            }
+        }
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let mut block_of = vec![0; len];
+        let mut blocks: Vec<BasicBlock> = starts.iter().enumerate()
+            .map(|(i, &start)| {
+                let end = starts.get(i + 1).copied().unwrap_or(len);
+                block_of[start..end].fill(i);
+                BasicBlock { start, end, successors: Vec::new(), predecessors: Vec::new() }
+            })
+            .collect();
+        for i in 0..blocks.len() {
+            let last = blocks[i].end - 1;
+            let mut targets: Vec<usize> = successors(last, &code[last], len).into_iter().map(|pc| block_of[pc]).collect();
+            targets.dedup();
+            for &target in &targets {
+                blocks[target].predecessors.push(i);
+            }
+            blocks[i].successors = targets;
+        }
+        Cfg { blocks, block_of }
+    }
+
+    /**
+     * The block holding the instruction at 'pc'
+     */
+    pub fn block_at(&self, pc: usize) -> usize {
+        self.block_of[pc]
+    }
+
+    /* blocks reachable from the entry, in reverse postorder */
+    fn reverse_postorder(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        if self.blocks.is_empty() {
+            return order;
+        }
+        let mut visited = vec![false; self.blocks.len()];
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((block, next)) = stack.pop() {
+            match self.blocks[block].successors.get(next) {
+                Some(&successor) => {
+                    stack.push((block, next + 1));
+                    if !visited[successor] {
+                        visited[successor] = true;
+                        stack.push((successor, 0));
+                    }
+                }
+                None => order.push(block),
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /**
+     * The immediate dominator of each block: the entry is its own and blocks that can't be
+     * reached have none. This is the iterative algorithm of Cooper, Harvey and Kennedy
+     */
+    pub fn dominators(&self) -> Vec<Option<usize>> {
+        let order = self.reverse_postorder();
+        let mut rank = vec![usize::MAX; self.blocks.len()];
+        for (i, &block) in order.iter().enumerate() {
+            rank[block] = i;
+        }
+        let mut idom = vec![None; self.blocks.len()];
+        if order.is_empty() {
+            return idom;
+        }
+        idom[0] = Some(0);
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while rank[a] > rank[b] {
+                    a = idom[a].unwrap();
+                }
+                while rank[b] > rank[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &order[1..] {
+                let mut processed = self.blocks[block].predecessors.iter().copied().filter(|&p| idom[p].is_some());
+                let Some(first) = processed.next() else { continue };
+                let new = processed.fold(first, |dominator, p| intersect(&idom, p, dominator));
+                if idom[block] != Some(new) {
+                    idom[block] = Some(new);
+                    changed = true;
+                }
+            }
+        }
+        idom
+    }
+
+    /**
+     * Whether every path from the entry to 'b' goes through 'a', given the immediate dominators
+     */
+    pub fn dominates(idom: &[Option<usize>], a: usize, mut b: usize) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match idom[b] {
+                Some(parent) if parent != b => b = parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /**
+     * The blocks where the dominance of each block ends: those with a predecessor it dominates
+     * that it doesn't strictly dominate themselves
+     */
+    pub fn dominance_frontiers(&self, idom: &[Option<usize>]) -> Vec<BTreeSet<usize>> {
+        let mut frontiers = vec![BTreeSet::new(); self.blocks.len()];
+        for (block, node) in self.blocks.iter().enumerate() {
+            let Some(dominator) = idom[block] else { continue };
+            /* the entry is also entered from outside the function */
+            if node.predecessors.len() < 2 && block != 0 {
+                continue;
+            }
+            for &predecessor in &node.predecessors {
+                let mut runner = predecessor;
+                while let Some(parent) = idom[runner] {
+                    if runner == dominator && block != 0 {
+                        break;
+                    }
+                    frontiers[runner].insert(block);
+                    if runner == parent {
+                        break;
+                    }
+                    runner = parent;
+                }
+            }
+        }
+        frontiers
+    }
+
+    /**
+     * Loops as edges going back to a block dominating their source, with the blocks reaching
+     * that source without going through the header. Back edges to one header make one loop
+     */
+    pub fn natural_loops(&self) -> Vec<NaturalLoop> {
+        let idom = self.dominators();
+        let mut loops: Vec<NaturalLoop> = Vec::new();
+        for (latch, block) in self.blocks.iter().enumerate() {
+            if idom[latch].is_none() {
+                continue;
+            }
+            for &header in &block.successors {
+                if !Cfg::dominates(&idom, header, latch) {
+                    continue;
+                }
+                let index = match loops.iter().position(|l| l.header == header) {
+                    Some(index) => index,
+                    None => {
+                        loops.push(NaturalLoop { header, latches: Vec::new(), blocks: BTreeSet::from([header]) });
+                        loops.len() - 1
+                    }
+                };
+                let found = &mut loops[index];
+                found.latches.push(latch);
+                let mut work = vec![latch];
+                while let Some(b) = work.pop() {
+                    if found.blocks.insert(b) {
+                        work.extend(self.blocks[b].predecessors.iter().copied().filter(|&p| idom[p].is_some()));
+                    }
+                }
+            }
+        }
+        loops.sort_by_key(|l| self.blocks[l.header].start);
+        loops
+    }
+
+    /**
+     * The graph in DOT, blocks listing their instructions. Loop headers are drawn with a
+     * double border and back edges dashed
+     */
+    pub fn to_dot(&self, proto: &Proto) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph \"{}\" {{", escape(&function_name(proto))).unwrap();
+        writeln!(out, "  node [shape=box fontname=\"monospace\"]").unwrap();
+        self.write_dot(&mut out, proto, "", "  ");
+        out.push_str("}\n");
+        out
+    }
+
+    fn write_dot(&self, out: &mut String, proto: &Proto, prefix: &str, indent: &str) {
+        let idom = self.dominators();
+        let headers: BTreeSet<usize> = self.natural_loops().iter().map(|l| l.header).collect();
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for pc in block.start..block.end {
+                let line = get_func_line(proto, pc).map_or(String::new(), |line| format!("[{}] ", line));
+                write!(label, "{} {}{}\\l", pc + 1, line, escape(&proto.code[pc].decoded().to_string())).unwrap();
+            }
+            let border = if headers.contains(&i) { " peripheries=2" } else { "" };
+            writeln!(out, "{}{}b{} [label=\"{}\"{}]", indent, prefix, i, label, border).unwrap();
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            for &successor in &block.successors {
+                let back = idom[i].is_some() && Cfg::dominates(&idom, successor, i);
+                writeln!(out, "{}{}b{} -> {}b{}{}", indent, prefix, i, prefix, successor, if back { " [style=dashed]" } else { "" }).unwrap();
+            }
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/* as luac -l names functions: main or function, with the source and the line defining them */
+fn function_name(proto: &Proto) -> String {
+    let source = proto.fn_name.as_ref().map_or("?".to_string(), |name| chunk_id(&name.to_string()));
+    format!("{} <{}:{}>", if proto.line_defined == 0 { "main" } else { "function" }, source, proto.line_defined)
+}
+
+/**
+ * The graphs of a chunk's functions in one DOT digraph, a cluster per function named after
+ * its path of indices from the main function
+ */
+pub fn chunk_dot(main: &Proto) -> String {
+    fn function(out: &mut String, proto: &Proto, path: &str) {
+        writeln!(out, "  subgraph cluster_{} {{", path).unwrap();
+        writeln!(out, "    label=\"{}\"", escape(&function_name(proto))).unwrap();
+        Cfg::new(proto).write_dot(out, proto, &format!("{}_", path), "    ");
+        out.push_str("  }\n");
+        for (i, child) in proto.fns.iter().enumerate() {
+            function(out, child, &format!("{}_{}", path, i));
+        }
+    }
+    let mut out = String::new();
+    out.push_str("digraph chunk {\n  node [shape=box fontname=\"monospace\"]\n");
+    function(&mut out, main, "f");
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::{Cfg, chunk_dot};
+    use crate::{compiler::codegen::compile, core::{parser::parse_all, types::Proto}};
+
+    fn proto(source: &str) -> Proto {
+        compile(source.as_bytes(), "=test").unwrap()
+    }
+
+    #[test]
+    fn split_blocks() {
+        let f = proto("local x = ...\nif x then x = 1 else x = 2 end\nreturn x\n");
+        let cfg = Cfg::new(&f);
+        let covered: usize = cfg.blocks.iter().map(|block| block.end - block.start).sum();
+        assert_eq!(covered, f.code.len());
+        let entry = &cfg.blocks[0];
+        assert_eq!(entry.successors.len(), 2);
+        /* the return luac adds after the last statement can't be reached here */
+        let exit = cfg.block_at(f.code.len() - 2);
+        assert!(cfg.blocks[exit].successors.is_empty());
+        assert!(cfg.blocks[cfg.block_at(f.code.len() - 1)].predecessors.is_empty());
+        assert_eq!(cfg.blocks[exit].predecessors.len(), 2);
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            for &successor in &block.successors {
+                assert!(cfg.blocks[successor].predecessors.contains(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn dominators_and_loops() {
+        let f = proto("local n = 0\nwhile n < 10 do\n  for i = 1, 3 do n = n + i end\nend\nrepeat n = n - 1 until n < 0\nreturn n\n");
+        let cfg = Cfg::new(&f);
+        let idom = cfg.dominators();
+        assert_eq!(idom[0], Some(0));
+        for block in 1..cfg.blocks.len() - 1 {
+            assert!(Cfg::dominates(&idom, 0, block));
+        }
+        let loops = cfg.natural_loops();
+        assert_eq!(loops.len(), 3);
+        let (outer, inner) = (&loops[0], &loops[1]);
+        assert!(inner.blocks.is_subset(&outer.blocks));
+        assert!(outer.blocks.contains(&inner.header));
+        for l in &loops {
+            assert!(l.blocks.iter().all(|&block| Cfg::dominates(&idom, l.header, block)));
+        }
+        let exit = cfg.block_at(f.code.len() - 2);
+        assert!(loops.iter().all(|l| !l.blocks.contains(&exit)));
+        /* the body of the repeat and the jump back */
+        let latch = loops[2].latches[0];
+        assert_eq!(loops[2].blocks, BTreeSet::from([loops[2].header, latch]));
+    }
+
+    #[test]
+    fn unreachable_blocks() {
+        let f = proto("do return 1 end\nlocal x = 2\nreturn x\n");
+        let cfg = Cfg::new(&f);
+        let idom = cfg.dominators();
+        assert_eq!(idom[cfg.block_at(f.code.len() - 1)], None);
+        assert!(cfg.natural_loops().is_empty());
+    }
+
+    #[test]
+    fn chunk_graph() {
+        let f = parse_all(include_bytes!("../../helpers/tests/base.out")).unwrap();
+        let dot = chunk_dot(&f);
+        assert!(dot.starts_with("digraph chunk {\n"));
+        assert_eq!(dot.matches("subgraph cluster_").count(), 1 + count_functions(&f));
+        assert!(dot.contains("label=\"main <base.lua:0>\""));
+        assert!(dot.contains("[style=dashed]"));
+        let single = Cfg::new(&f).to_dot(&f);
+        assert!(single.starts_with("digraph \"main <base.lua:0>\" {\n"));
+        assert_eq!(single.matches(" -> ").count(), Cfg::new(&f).blocks.iter().map(|block| block.successors.len()).sum::<usize>());
+    }
+
+    fn count_functions(f: &Proto) -> usize {
+        f.fns.iter().map(|child| 1 + count_functions(child)).sum()
+    }
+}
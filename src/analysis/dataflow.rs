@@ -0,0 +1,354 @@
+/*
+ * Register dataflow of function prototypes: the registers each instruction reads and writes,
+ * which registers are live, which writes reach each instruction and the chains between
+ * writes and reads. Ranges left open by calls and varargs ("up to the top") are taken to run
+ * to the function's last register
+ */
+
+use std::{collections::{BTreeMap, BTreeSet}, fmt};
+
+use crate::core::{instruction::Instruction, types::Proto};
+
+use super::cfg::successors;
+
+/**
+ * A set of registers, one bit each
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegisterSet([u64; 4]);
+
+impl RegisterSet {
+    pub fn insert(&mut self, reg: u8) {
+        self.0[reg as usize / 64] |= 1 << (reg % 64);
+    }
+
+    pub fn remove(&mut self, reg: u8) {
+        self.0[reg as usize / 64] &= !(1 << (reg % 64));
+    }
+
+    pub fn contains(&self, reg: u8) -> bool {
+        self.0[reg as usize / 64] & (1 << (reg % 64)) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| *word == 0)
+    }
+
+    /* adds the registers of 'other', telling whether there were new ones */
+    pub fn union(&mut self, other: &RegisterSet) -> bool {
+        let before = self.0;
+        for (word, other) in self.0.iter_mut().zip(other.0) {
+            *word |= other;
+        }
+        before != self.0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(|&reg| self.contains(reg))
+    }
+}
+
+impl FromIterator<u8> for RegisterSet {
+    fn from_iter<T: IntoIterator<Item = u8>>(iter: T) -> Self {
+        let mut set = RegisterSet::default();
+        for reg in iter {
+            set.insert(reg);
+        }
+        set
+    }
+}
+
+impl fmt::Display for RegisterSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let regs: Vec<String> = self.iter().map(|reg| format!("r{}", reg)).collect();
+        write!(f, "{{{}}}", regs.join(" "))
+    }
+}
+
+/**
+ * The registers an instruction reads and writes. A 'conditional' write may leave the old
+ * value, so it doesn't end the life of the register it writes
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Effects {
+    pub uses: Vec<u8>,
+    pub defs: Vec<u8>,
+    pub conditional: bool,
+}
+
+/* registers from 'first' up to 'last' excluded, or up to the top when 'open' */
+fn range(first: u8, last: usize, open: bool, top: usize) -> Vec<u8> {
+    let last = if open { top } else { last }.min(u8::MAX as usize + 1);
+    (first as usize..last).map(|reg| reg as u8).collect()
+}
+
+/**
+ * What the instruction at 'pc' reads and writes. MMBIN repeats the operation of the
+ * instruction before it when that one fails, so its effects are counted there
+ */
+pub fn effects(proto: &Proto, pc: usize) -> Effects {
+    use Instruction::*;
+    let top = (proto.max_stack_size as usize).max(1);
+    let plain = |uses: Vec<u8>, defs: Vec<u8>| Effects { uses, defs, conditional: false };
+    let rk = |c: u8, k: bool| if k { vec![] } else { vec![c] };
+    match proto.code[pc].decoded() {
+        Move { a, b } => plain(vec![b], vec![a]),
+        LoadI { a, .. } | LoadF { a, .. } | LoadK { a, .. } | LoadKX { a } | LoadFalse { a } | LFalseSkip { a } | LoadTrue { a }
+        | GetUpval { a, .. } | GetTabUp { a, .. } | NewTable { a, .. } => plain(vec![], vec![a]),
+        Closure { a, bx } => {
+            /* the registers its upvalues capture */
+            let captured = proto.fns.get(bx as usize).map_or(vec![], |child| child.upvalues.iter().filter(|u| u.instack).map(|u| u.idx).collect());
+            plain(captured, vec![a])
+        }
+        LoadNil { a, b } => plain(vec![], range(a, a as usize + b as usize + 1, false, top)),
+        SetUpval { a, .. } => plain(vec![a], vec![]),
+        GetTable { a, b, c } => plain(vec![b, c], vec![a]),
+        GetI { a, b, .. } | GetField { a, b, .. } => plain(vec![b], vec![a]),
+        SetTabUp { c, k, .. } => plain(rk(c, k), vec![]),
+        SetTable { a, b, c, k } => plain([vec![a, b], rk(c, k)].concat(), vec![]),
+        SetI { a, c, k, .. } | SetField { a, c, k, .. } => plain([vec![a], rk(c, k)].concat(), vec![]),
+        Self_ { a, b, c, k } => plain([vec![b], rk(c, k)].concat(), vec![a, a.saturating_add(1)]),
+        AddI { a, b, .. } | AddK { a, b, .. } | SubK { a, b, .. } | MulK { a, b, .. } | ModK { a, b, .. } | PowK { a, b, .. }
+        | DivK { a, b, .. } | IDivK { a, b, .. } | BAndK { a, b, .. } | BOrK { a, b, .. } | BXorK { a, b, .. } | ShrI { a, b, .. }
+        | ShlI { a, b, .. } | Unm { a, b } | BNot { a, b } | Not { a, b } | Len { a, b } => plain(vec![b], vec![a]),
+        Add { a, b, c } | Sub { a, b, c } | Mul { a, b, c } | Mod { a, b, c } | Pow { a, b, c } | Div { a, b, c }
+        | IDiv { a, b, c } | BAnd { a, b, c } | BOr { a, b, c } | BXor { a, b, c } | Shl { a, b, c } | Shr { a, b, c } => plain(vec![b, c], vec![a]),
+        Concat { a, b } => plain(range(a, a as usize + b as usize, false, top), vec![a]),
+        Tbc { a } => plain(vec![a], vec![]),
+        SetList { a, b, .. } => plain(range(a, a as usize + b as usize + 1, b == 0, top), vec![]),
+        Eq { a, b, .. } | Lt { a, b, .. } | Le { a, b, .. } => plain(vec![a, b], vec![]),
+        EqK { a, .. } | EqI { a, .. } | LtI { a, .. } | LeI { a, .. } | GtI { a, .. } | GeI { a, .. } | Test { a, .. }
+        | Return1 { a } => plain(vec![a], vec![]),
+        TestSet { a, b, .. } => Effects { uses: vec![b], defs: vec![a], conditional: true },
+        Call { a, b, c } => plain(range(a, a as usize + b as usize, b == 0, top), range(a, a as usize + c as usize - (c != 0) as usize, c == 0, top)),
+        TailCall { a, b, .. } => plain(range(a, a as usize + b as usize, b == 0, top), vec![]),
+        Return { a, b, .. } => plain(range(a, (a as usize + b as usize).saturating_sub(1), b == 0, top), vec![]),
+        ForPrep { a, .. } => plain(range(a, a as usize + 3, false, top), range(a, a as usize + 4, false, top)),
+        ForLoop { a, .. } => plain(range(a, a as usize + 3, false, top), vec![a, a.saturating_add(1), a.saturating_add(3)]),
+        TForPrep { a, .. } => plain(vec![a.saturating_add(3)], vec![]),
+        TForCall { a, c } => plain(range(a, a as usize + 3, false, top), range(a.saturating_add(4), a as usize + 4 + c as usize, false, top)),
+        TForLoop { a, .. } => Effects { uses: vec![a.saturating_add(4)], defs: vec![a.saturating_add(2)], conditional: true },
+        VarArg { a, c } => plain(vec![], range(a, a as usize + c as usize - (c != 0) as usize, c == 0, top)),
+        MmBin { .. } | MmBinI { .. } | MmBinK { .. } | Close { .. } | Jmp { .. } | Return0 { .. } | VarArgPrep { .. }
+        | ExtraArg { .. } => Effects::default(),
+    }
+}
+
+/* the instructions that can run before each one */
+fn predecessors(proto: &Proto) -> Vec<Vec<usize>> {
+    let len = proto.code.len();
+    let mut predecessors = vec![Vec::new(); len];
+    for pc in 0..len {
+        for successor in successors(pc, &proto.code[pc].decoded(), len) {
+            predecessors[successor].push(pc);
+        }
+    }
+    predecessors
+}
+
+/**
+ * The registers live before and after each instruction: read on some path from there before
+ * being written
+ */
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    pub live_in: Vec<RegisterSet>,
+    pub live_out: Vec<RegisterSet>,
+}
+
+impl Liveness {
+    pub fn new(proto: &Proto) -> Self {
+        let len = proto.code.len();
+        let effects: Vec<Effects> = (0..len).map(|pc| effects(proto, pc)).collect();
+        let predecessors = predecessors(proto);
+        let mut live_in = vec![RegisterSet::default(); len];
+        let mut live_out = vec![RegisterSet::default(); len];
+        let mut work: Vec<usize> = (0..len).collect();
+        let mut queued = vec![true; len];
+        while let Some(pc) = work.pop() {
+            queued[pc] = false;
+            let mut out = RegisterSet::default();
+            for successor in successors(pc, &proto.code[pc].decoded(), len) {
+                out.union(&live_in[successor]);
+            }
+            let mut live = out;
+            if !effects[pc].conditional {
+                for &reg in &effects[pc].defs {
+                    live.remove(reg);
+                }
+            }
+            for &reg in &effects[pc].uses {
+                live.insert(reg);
+            }
+            live_out[pc] = out;
+            if live != live_in[pc] {
+                live_in[pc] = live;
+                for &predecessor in &predecessors[pc] {
+                    if !queued[predecessor] {
+                        queued[predecessor] = true;
+                        work.push(predecessor);
+                    }
+                }
+            }
+        }
+        Liveness { live_in, live_out }
+    }
+
+    /**
+     * Writes whose registers aren't live after them, pairs of the instruction and register
+     */
+    pub fn dead_stores(&self, proto: &Proto) -> Vec<(usize, u8)> {
+        (0..proto.code.len())
+            .flat_map(|pc| effects(proto, pc).defs.into_iter().map(move |reg| (pc, reg)))
+            .filter(|&(pc, reg)| !self.live_out[pc].contains(reg))
+            .collect()
+    }
+}
+
+/**
+ * A write of a register: by the instruction at 'pc', or on entry to the function when None,
+ * which for the parameters is their argument
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Definition {
+    pub pc: Option<usize>,
+    pub reg: u8,
+}
+
+impl fmt::Display for Definition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pc {
+            Some(pc) => write!(f, "r{}@{}", self.reg, pc + 1),
+            None => write!(f, "r{}@entry", self.reg),
+        }
+    }
+}
+
+/**
+ * The writes that may have given each register its value before each instruction
+ */
+pub fn reaching_definitions(proto: &Proto) -> Vec<BTreeSet<Definition>> {
+    let len = proto.code.len();
+    let top = proto.max_stack_size;
+    let effects: Vec<Effects> = (0..len).map(|pc| effects(proto, pc)).collect();
+    let mut reach_in = vec![BTreeSet::new(); len];
+    let mut reach_out: Vec<Option<BTreeSet<Definition>>> = vec![None; len];
+    if len == 0 {
+        return reach_in;
+    }
+    reach_in[0] = (0..top).map(|reg| Definition { pc: None, reg }).collect();
+    let mut work = vec![0];
+    while let Some(pc) = work.pop() {
+        let mut out = reach_in[pc].clone();
+        if !effects[pc].conditional {
+            out.retain(|definition| !effects[pc].defs.contains(&definition.reg));
+        }
+        out.extend(effects[pc].defs.iter().map(|&reg| Definition { pc: Some(pc), reg }));
+        if reach_out[pc].as_ref() == Some(&out) {
+            continue;
+        }
+        for successor in successors(pc, &proto.code[pc].decoded(), len) {
+            let before = reach_in[successor].len();
+            reach_in[successor].extend(out.iter().copied());
+            if reach_in[successor].len() != before || reach_out[successor].is_none() {
+                work.push(successor);
+            }
+        }
+        reach_out[pc] = Some(out);
+    }
+    reach_in
+}
+
+/**
+ * Def-use chains: the instructions reading each write and the writes each read may see
+ */
+#[derive(Debug, Clone, Default)]
+pub struct DefUse {
+    pub uses: BTreeMap<Definition, Vec<usize>>,
+    pub defs: BTreeMap<(usize, u8), Vec<Definition>>,
+}
+
+impl DefUse {
+    pub fn new(proto: &Proto) -> Self {
+        let reaching = reaching_definitions(proto);
+        let mut chains = DefUse::default();
+        for (pc, reach) in reaching.iter().enumerate() {
+            let effects = effects(proto, pc);
+            for &reg in &effects.defs {
+                chains.uses.entry(Definition { pc: Some(pc), reg }).or_default();
+            }
+            for reg in effects.uses {
+                let seen: Vec<Definition> = reach.iter().filter(|definition| definition.reg == reg).copied().collect();
+                for definition in &seen {
+                    chains.uses.entry(*definition).or_default().push(pc);
+                }
+                chains.defs.insert((pc, reg), seen);
+            }
+        }
+        chains
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Definition, DefUse, Effects, Liveness, effects, reaching_definitions};
+    use crate::{compiler::codegen::compile, core::{builder::ProtoBuilder, instruction::Instruction, types::Proto}};
+
+    /* a function of the instructions given, with 10 registers */
+    fn function(code: &[Instruction]) -> Proto {
+        let mut f = ProtoBuilder::new("=test");
+        for instruction in code {
+            f.emit(instruction.encode().unwrap());
+        }
+        let mut proto = f.build().unwrap();
+        proto.max_stack_size = 10;
+        proto
+    }
+
+    fn effects_of(instruction: Instruction) -> (Vec<u8>, Vec<u8>) {
+        let Effects { uses, defs, .. } = effects(&function(&[instruction]), 0);
+        (uses, defs)
+    }
+
+    #[test]
+    fn register_ranges() {
+        assert_eq!(effects_of(Instruction::Call { a: 2, b: 3, c: 2 }), (vec![2, 3, 4], vec![2]));
+        assert_eq!(effects_of(Instruction::Call { a: 7, b: 0, c: 0 }), (vec![7, 8, 9], vec![7, 8, 9]));
+        assert_eq!(effects_of(Instruction::Call { a: 1, b: 1, c: 1 }), (vec![1], vec![]));
+        assert_eq!(effects_of(Instruction::LoadNil { a: 1, b: 2 }), (vec![], vec![1, 2, 3]));
+        assert_eq!(effects_of(Instruction::Concat { a: 3, b: 3 }), (vec![3, 4, 5], vec![3]));
+        assert_eq!(effects_of(Instruction::SetList { a: 1, b: 2, c: 0, k: false }), (vec![1, 2, 3], vec![]));
+        assert_eq!(effects_of(Instruction::SetList { a: 8, b: 0, c: 0, k: false }), (vec![8, 9], vec![]));
+        assert_eq!(effects_of(Instruction::VarArg { a: 4, c: 3 }), (vec![], vec![4, 5]));
+        assert_eq!(effects_of(Instruction::TForCall { a: 0, c: 2 }), (vec![0, 1, 2], vec![4, 5]));
+        assert_eq!(effects_of(Instruction::Return { a: 2, b: 3, c: 0, k: false }), (vec![2, 3], vec![]));
+        assert_eq!(effects_of(Instruction::SetField { a: 0, key_k: 1, c: 2, k: true }), (vec![0], vec![]));
+        assert!(effects(&function(&[Instruction::TestSet { a: 1, b: 2, k: false }]), 0).conditional);
+    }
+
+    #[test]
+    fn liveness_and_dead_stores() {
+        let f = compile(b"local a, b = ...\nlocal c = a + 1\nc = b\nreturn c\n", "=test").unwrap();
+        let liveness = Liveness::new(&f);
+        /* 'a + 1' is overwritten before being read */
+        let add = (0..f.code.len()).find(|&pc| matches!(f.code[pc].decoded(), Instruction::AddI { .. })).unwrap();
+        assert!(liveness.dead_stores(&f).contains(&(add, 2)));
+        assert!(liveness.live_in[add].contains(0) && liveness.live_in[add].contains(1));
+        assert!(!liveness.live_out[add].contains(0));
+        assert!(liveness.live_in[0].is_empty());
+    }
+
+    #[test]
+    fn chains_through_branches() {
+        let f = compile(b"local x, c = ...\nif c then x = 1 end\nreturn x\n", "=test").unwrap();
+        let ret = (0..f.code.len()).find(|&pc| matches!(f.code[pc].decoded(), Instruction::Return { .. })).unwrap();
+        let load = (0..f.code.len()).find(|&pc| matches!(f.code[pc].decoded(), Instruction::LoadI { .. })).unwrap();
+        let reaching = reaching_definitions(&f);
+        let x: Vec<Definition> = reaching[ret].iter().filter(|definition| definition.reg == 0).copied().collect();
+        assert_eq!(x, vec![Definition { pc: Some(1), reg: 0 }, Definition { pc: Some(load), reg: 0 }]);
+        let chains = DefUse::new(&f);
+        assert_eq!(chains.defs[&(ret, 0)], x);
+        assert_eq!(chains.uses[&Definition { pc: Some(load), reg: 0 }], vec![ret]);
+        assert_eq!(chains.uses[&Definition { pc: Some(1), reg: 0 }], vec![ret]);
+    }
+}
@@ -0,0 +1,8 @@
+/*
+ * Analyses of compiled functions, for reviewing bytecode rather than running it
+ */
+
+pub mod cfg;
+pub mod dataflow;
+pub mod ssa;
+pub mod verify;
@@ -0,0 +1,266 @@
+/*
+ * Static single assignment form of function prototypes. Each write of a register makes a new
+ * version of it and blocks reached with different versions start with phi nodes choosing
+ * among them, placed on the dominance frontiers of the writes where the register is live
+ */
+
+use std::{collections::BTreeSet, fmt};
+
+use crate::core::{instruction::Instruction, types::Proto};
+
+use super::{cfg::Cfg, dataflow::{Effects, Liveness, effects}};
+
+/**
+ * A version of a register, 0 being its value on entry to the function
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Value {
+    pub reg: u8,
+    pub version: u32,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "r{}_{}", self.reg, self.version)
+    }
+}
+
+/**
+ * 'dest' takes the value of 'args' coming from the predecessor block it is paired with
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phi {
+    pub dest: Value,
+    pub args: Vec<(usize, Value)>,
+}
+
+/**
+ * An instruction with the versions it reads and makes. A conditional write also reads the
+ * version it may leave in place
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SsaInstruction {
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub uses: Vec<Value>,
+    pub defs: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SsaBlock {
+    pub phis: Vec<Phi>,
+    pub instructions: Vec<SsaInstruction>,
+}
+
+/**
+ * A function in SSA form, with a block for each of its control-flow graph's blocks
+ */
+#[derive(Debug)]
+pub struct Ssa {
+    pub cfg: Cfg,
+    pub blocks: Vec<SsaBlock>,
+}
+
+/* the versions each register has at some point of the renaming, and how many it has had */
+struct Versions {
+    stacks: Vec<Vec<u32>>,
+    count: Vec<u32>,
+}
+
+impl Versions {
+    fn current(&self, reg: u8) -> Value {
+        Value { reg, version: *self.stacks[reg as usize].last().unwrap() }
+    }
+
+    fn fresh(&mut self, reg: u8) -> Value {
+        self.count[reg as usize] += 1;
+        let version = self.count[reg as usize];
+        self.stacks[reg as usize].push(version);
+        Value { reg, version }
+    }
+}
+
+impl Ssa {
+    pub fn new(proto: &Proto) -> Self {
+        let cfg = Cfg::new(proto);
+        let idom = cfg.dominators();
+        let frontiers = cfg.dominance_frontiers(&idom);
+        let liveness = Liveness::new(proto);
+        let effects: Vec<_> = (0..proto.code.len()).map(|pc| effects(proto, pc)).collect();
+        let mut blocks = vec![SsaBlock::default(); cfg.blocks.len()];
+
+        /* phis where a register written in a block meets other versions of it */
+        let mut writers = vec![BTreeSet::new(); u8::MAX as usize + 1];
+        for (b, block) in cfg.blocks.iter().enumerate().filter(|(b, _)| idom[*b].is_some()) {
+            for effect in &effects[block.start..block.end] {
+                for &reg in &effect.defs {
+                    writers[reg as usize].insert(b);
+                }
+            }
+        }
+        for (reg, written) in writers.iter_mut().enumerate() {
+            let reg = reg as u8;
+            if reg >= proto.max_stack_size && written.is_empty() {
+                continue;
+            }
+            if !cfg.blocks.is_empty() {
+                written.insert(0);
+            }
+            let mut work: Vec<usize> = written.iter().copied().collect();
+            let mut placed = BTreeSet::new();
+            while let Some(b) = work.pop() {
+                for &frontier in &frontiers[b] {
+                    if !liveness.live_in[cfg.blocks[frontier].start].contains(reg) || !placed.insert(frontier) {
+                        continue;
+                    }
+                    blocks[frontier].phis.push(Phi { dest: Value { reg, version: 0 }, args: Vec::new() });
+                    if written.insert(frontier) {
+                        work.push(frontier);
+                    }
+                }
+            }
+        }
+
+        /* renaming along the dominator tree, then the blocks it doesn't reach on their own */
+        let mut children = vec![Vec::new(); cfg.blocks.len()];
+        for (b, dominator) in idom.iter().enumerate() {
+            if let Some(dominator) = dominator.filter(|&dominator| dominator != b) {
+                children[dominator].push(b);
+            }
+        }
+        let mut versions = Versions { stacks: vec![vec![0]; u8::MAX as usize + 1], count: vec![0; u8::MAX as usize + 1] };
+        let mut walk: Vec<(usize, bool)> = if cfg.blocks.is_empty() { vec![] } else { vec![(0, true)] };
+        let mut pushed: Vec<Vec<u8>> = vec![Vec::new(); cfg.blocks.len()];
+        while let Some((b, enter)) = walk.pop() {
+            if !enter {
+                for &reg in &pushed[b] {
+                    versions.stacks[reg as usize].pop();
+                }
+                continue;
+            }
+            pushed[b] = rename(proto, &cfg, &effects, &mut blocks, &mut versions, b);
+            for &successor in &cfg.blocks[b].successors {
+                for phi in &mut blocks[successor].phis {
+                    phi.args.push((b, versions.current(phi.dest.reg)));
+                }
+            }
+            walk.push((b, false));
+            walk.extend(children[b].iter().rev().map(|&child| (child, true)));
+        }
+        for b in (0..cfg.blocks.len()).filter(|&b| idom[b].is_none()) {
+            for reg in rename(proto, &cfg, &effects, &mut blocks, &mut versions, b) {
+                versions.stacks[reg as usize].pop();
+            }
+        }
+        Ssa { cfg, blocks }
+    }
+}
+
+/* gives the phis and instructions of block 'b' their versions, returning the registers written */
+fn rename(proto: &Proto, cfg: &Cfg, effects: &[Effects], blocks: &mut [SsaBlock], versions: &mut Versions, b: usize) -> Vec<u8> {
+    let mut written = Vec::new();
+    for phi in &mut blocks[b].phis {
+        phi.dest = versions.fresh(phi.dest.reg);
+        written.push(phi.dest.reg);
+    }
+    for (pc, effect) in effects.iter().enumerate().take(cfg.blocks[b].end).skip(cfg.blocks[b].start) {
+        let mut uses: Vec<Value> = effect.uses.iter().map(|&reg| versions.current(reg)).collect();
+        if effect.conditional {
+            uses.extend(effect.defs.iter().map(|&reg| versions.current(reg)));
+        }
+        let defs = effect.defs.iter().map(|&reg| versions.fresh(reg)).collect();
+        written.extend(&effect.defs);
+        blocks[b].instructions.push(SsaInstruction { pc, instruction: proto.code[pc].decoded(), uses, defs });
+    }
+    written
+}
+
+/**
+ * The blocks with their phis and, after each instruction, the versions it makes and reads
+ */
+impl fmt::Display for Ssa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |values: &[Value]| values.iter().map(Value::to_string).collect::<Vec<_>>().join(" ");
+        for (b, block) in self.blocks.iter().enumerate() {
+            let predecessors: Vec<String> = self.cfg.blocks[b].predecessors.iter().map(|p| format!("b{}", p)).collect();
+            writeln!(f, "b{}:{}", b, if predecessors.is_empty() { String::new() } else { format!("   ; from {}", predecessors.join(" ")) })?;
+            for phi in &block.phis {
+                let args: Vec<String> = phi.args.iter().map(|(p, value)| format!("b{} {}", p, value)).collect();
+                writeln!(f, "      {} = phi({})", phi.dest, args.join(", "))?;
+            }
+            for instruction in &block.instructions {
+                let text = format!("{:<5} {}", instruction.pc + 1, instruction.instruction);
+                if instruction.uses.is_empty() && instruction.defs.is_empty() {
+                    writeln!(f, "{}", text)?;
+                } else {
+                    let effect = format!("{} <- {}", list(&instruction.defs), list(&instruction.uses));
+                    writeln!(f, "{:<32} ; {}", text, effect.trim())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, rc::Rc};
+
+    use super::{Cfg, Ssa, Value};
+    use crate::{compiler::codegen::compile, core::parser::parse_all};
+
+    #[test]
+    fn loop_phis() {
+        let f = compile(b"local n, s = ...\nwhile n > 0 do s = s + n; n = n - 1 end\nreturn s\n", "=test").unwrap();
+        let ssa = Ssa::new(&f);
+        assert_eq!(ssa.to_string(), "\
+b0:
+1     VARARGPREP 0
+2     VARARG    0 3              ; r0_1 r1_1 <-
+b1:   ; from b0 b3
+      r0_2 = phi(b0 r0_1, b3 r0_3)
+      r1_2 = phi(b0 r1_1, b3 r1_3)
+3     GTI       0 0 0 0          ; <- r0_2
+b2:   ; from b1
+4     JMP       5
+b3:   ; from b1
+5     ADD       1 1 0            ; r1_3 <- r1_2 r0_2
+6     MMBIN     1 0 6
+7     ADDI      0 0 -1           ; r0_3 <- r0_2
+8     MMBINI    0 1 7
+9     JMP       -7
+b4:   ; from b2
+10    RETURN    1 2 1            ; <- r1_2
+b5:
+11    RETURN    2 1 1
+");
+    }
+
+    /* every version is made once and read only where the block making it dominates */
+    #[test]
+    fn single_assignment() {
+        let f = parse_all(include_bytes!("../../helpers/tests/string.out")).unwrap();
+        let mut protos = vec![Rc::new(f)];
+        while let Some(proto) = protos.pop() {
+            let ssa = Ssa::new(&proto);
+            let idom = ssa.cfg.dominators();
+            let mut made = HashMap::new();
+            for (b, block) in ssa.blocks.iter().enumerate() {
+                let defs = block.phis.iter().map(|phi| phi.dest).chain(block.instructions.iter().flat_map(|i| i.defs.iter().copied()));
+                for value in defs {
+                    assert!(made.insert(value, b).is_none(), "{} made twice", value);
+                }
+            }
+            for (b, block) in ssa.blocks.iter().enumerate().filter(|(b, _)| idom[*b].is_some()) {
+                for value in block.instructions.iter().flat_map(|i| i.uses.iter()).filter(|value| value.version > 0) {
+                    assert!(Cfg::dominates(&idom, made[value], b), "{} read in b{}", value, b);
+                }
+                for phi in &block.phis {
+                    assert_eq!(phi.args.len(), ssa.cfg.blocks[b].predecessors.iter().filter(|p| idom[**p].is_some()).count());
+                    assert!(phi.args.iter().all(|(p, value)| *value == Value { reg: phi.dest.reg, version: 0 } || Cfg::dominates(&idom, made[value], *p)));
+                }
+            }
+            protos.extend(proto.fns.iter().cloned());
+        }
+    }
+}
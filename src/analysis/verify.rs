@@ -0,0 +1,331 @@
+/*
+ * Static checks of binary chunks before they run. The VM trusts its prototypes: operands
+ * index registers, constants, upvalues and nested functions unchecked, and instructions rely
+ * on the ones next to them (EXTRAARG, MMBIN, open results). A chunk passing 'verify' keeps
+ * all of those in range, so untrusted bytecode can be loaded
+ */
+
+use crate::core::{instruction::Instruction, parser::LoadResult, types::Proto};
+
+use super::dataflow::effects;
+
+/* the events MMBIN instructions can ask for: __add up to __shr */
+const FIRST_ARITH_EVENT: u8 = 6;
+const LAST_ARITH_EVENT: u8 = 17;
+
+/**
+ * Checks a main function and the functions nested in it
+ */
+pub fn verify(main: &Proto) -> LoadResult<()> {
+    function(main, None)
+}
+
+fn name(proto: &Proto) -> String {
+    if proto.line_defined == 0 { "main function".to_string() } else { format!("function at line {}", proto.line_defined) }
+}
+
+fn fail<T>(proto: &Proto, pc: usize, what: &str) -> LoadResult<T> {
+    Err(format!("{} at instruction {} of {}", what, pc + 1, name(proto)))
+}
+
+/* a function wrong as a whole rather than in one of its instructions */
+fn fail_function<T>(proto: &Proto, what: &str) -> LoadResult<T> {
+    Err(format!("{} in {}", what, name(proto)))
+}
+
+/* the kind of arithmetic instruction, 0 for registers, 1 for an immediate and 2 for a constant */
+fn arith_kind(instruction: &Instruction) -> Option<u8> {
+    use Instruction::*;
+    match instruction {
+        Add { .. } | Sub { .. } | Mul { .. } | Mod { .. } | Pow { .. } | Div { .. } | IDiv { .. } | BAnd { .. } | BOr { .. }
+        | BXor { .. } | Shl { .. } | Shr { .. } => Some(0),
+        AddI { .. } | ShrI { .. } | ShlI { .. } => Some(1),
+        AddK { .. } | SubK { .. } | MulK { .. } | ModK { .. } | PowK { .. } | DivK { .. } | IDivK { .. } | BAndK { .. }
+        | BOrK { .. } | BXorK { .. } => Some(2),
+        _ => None,
+    }
+}
+
+fn metamethod_kind(instruction: &Instruction) -> Option<(u8, u8)> {
+    match *instruction {
+        Instruction::MmBin { tm, .. } => Some((0, tm)),
+        Instruction::MmBinI { tm, .. } => Some((1, tm)),
+        Instruction::MmBinK { tm, .. } => Some((2, tm)),
+        _ => None,
+    }
+}
+
+/* the first register of results left open up to the top, for the RETURN after a TAILCALL of a C function */
+fn open_results(instruction: &Instruction) -> Option<u8> {
+    match *instruction {
+        Instruction::Call { a, c: 0, .. } | Instruction::VarArg { a, c: 0 } | Instruction::TailCall { a, .. } => Some(a),
+        _ => None,
+    }
+}
+
+/* the first register of values taken up to the top */
+fn open_arguments(instruction: &Instruction) -> Option<u8> {
+    match *instruction {
+        Instruction::Call { a, b: 0, .. } | Instruction::TailCall { a, b: 0, .. } | Instruction::Return { a, b: 0, .. }
+        | Instruction::SetList { a, b: 0, .. } => Some(a),
+        _ => None,
+    }
+}
+
+/* the constants an instruction reads, with the EXTRAARG after it for LOADKX */
+fn constants(instruction: &Instruction, next: Option<&Instruction>) -> Vec<u32> {
+    use Instruction::*;
+    let rk = |c: u8, k: bool| if k { vec![c as u32] } else { vec![] };
+    match *instruction {
+        LoadK { bx, .. } => vec![bx],
+        LoadKX { .. } => match next {
+            Some(ExtraArg { ax }) => vec![*ax],
+            _ => vec![],
+        },
+        GetTabUp { key_k, .. } | GetField { key_k, .. } => vec![key_k as u32],
+        SetTabUp { key_k, c, k, .. } | SetField { key_k, c, k, .. } => [vec![key_k as u32], rk(c, k)].concat(),
+        SetTable { c, k, .. } | SetI { c, k, .. } | Self_ { c, k, .. } => rk(c, k),
+        EqK { b_k, .. } | MmBinK { b_k, .. } => vec![b_k as u32],
+        AddK { c, .. } | SubK { c, .. } | MulK { c, .. } | ModK { c, .. } | PowK { c, .. } | DivK { c, .. } | IDivK { c, .. }
+        | BAndK { c, .. } | BOrK { c, .. } | BXorK { c, .. } => vec![c as u32],
+        _ => vec![],
+    }
+}
+
+/* registers an instruction names beyond those it reads and writes, as the start of an open range */
+fn named_registers(instruction: &Instruction) -> Vec<u8> {
+    use Instruction::*;
+    match *instruction {
+        MmBin { a, b, .. } => vec![a, b],
+        MmBinI { a, .. } | MmBinK { a, .. } | Close { a } => vec![a],
+        _ => open_results(instruction).or_else(|| open_arguments(instruction)).into_iter().collect(),
+    }
+}
+
+fn function(proto: &Proto, parent: Option<&Proto>) -> LoadResult<()> {
+    use Instruction::*;
+    let code: Vec<Instruction> = proto.code.iter().map(|instruction| instruction.decoded()).collect();
+    let len = code.len();
+    let top = proto.max_stack_size;
+    match code.last() {
+        None => return fail_function(proto, "no code"),
+        Some(Return { .. } | Return0 { .. } | Return1 { .. }) => {}
+        Some(_) => return fail(proto, len - 1, "function not ending in a return"),
+    }
+    if proto.num_params > top {
+        return fail_function(proto, "parameters beyond the stack size");
+    }
+    if !proto.line_info.is_empty() && proto.line_info.len() != len || proto.abs_line_info.iter().any(|info| info.pc >= len) {
+        return fail_function(proto, "line information not matching the code");
+    }
+    if proto.loc_vars.iter().any(|local| local.start_pc > local.end_pc || local.end_pc > len) {
+        return fail_function(proto, "local variable beyond the code");
+    }
+    if let Some(parent) = parent {
+        for upvalue in &proto.upvalues {
+            let limit = if upvalue.instack { parent.max_stack_size as usize } else { parent.upvalues.len() };
+            if upvalue.idx as usize >= limit {
+                return fail_function(proto, &format!("upvalue {} not in the enclosing function", upvalue.idx));
+            }
+        }
+    }
+    for (pc, instruction) in code.iter().enumerate() {
+        let next = code.get(pc + 1);
+        let previous = pc.checked_sub(1).map(|previous| &code[previous]);
+        let extended = |instruction: &Instruction| matches!(instruction, LoadKX { .. } | NewTable { .. } | SetList { k: true, .. });
+
+        let effects = effects(proto, pc);
+        let registers = effects.uses.iter().chain(&effects.defs).copied().chain(named_registers(instruction));
+        if let Some(reg) = registers.into_iter().find(|&reg| reg >= top) {
+            return fail(proto, pc, &format!("register {} beyond the stack size", reg));
+        }
+        if let Some(k) = constants(instruction, next).into_iter().find(|&k| k as usize >= proto.constants.len()) {
+            return fail(proto, pc, &format!("constant {} out of range", k));
+        }
+        let upvalue = match *instruction {
+            GetUpval { b, .. } | SetUpval { b, .. } | GetTabUp { b, .. } => Some(b),
+            SetTabUp { a, .. } => Some(a),
+            _ => None,
+        };
+        if let Some(upvalue) = upvalue.filter(|&upvalue| upvalue as usize >= proto.upvalues.len()) {
+            return fail(proto, pc, &format!("upvalue {} out of range", upvalue));
+        }
+        if let Closure { bx, .. } = instruction {
+            if *bx as usize >= proto.fns.len() {
+                return fail(proto, pc, &format!("function {} out of range", bx));
+            }
+        }
+
+        /* instructions going on to one other than the next */
+        let next_pc = pc as i64 + 1;
+        let targets = match *instruction {
+            Jmp { sj } => vec![next_pc + sj as i64],
+            Eq { .. } | Lt { .. } | Le { .. } | EqK { .. } | EqI { .. } | LtI { .. } | LeI { .. } | GtI { .. } | GeI { .. }
+            | Test { .. } | TestSet { .. } | LFalseSkip { .. } => vec![next_pc + 1],
+            ForPrep { bx, .. } | TForPrep { bx, .. } => vec![next_pc + bx as i64],
+            ForLoop { bx, .. } | TForLoop { bx, .. } => vec![next_pc - bx as i64],
+            _ => vec![],
+        };
+        for target in targets {
+            if !(0..len as i64).contains(&target) {
+                return fail(proto, pc, "jump out of the function");
+            }
+            if target == 0 && matches!(code[0], VarArgPrep { .. }) {
+                return fail(proto, pc, "jump back to VARARGPREP");
+            }
+            if matches!(code[target as usize], ExtraArg { .. }) {
+                return fail(proto, pc, "jump into an EXTRAARG");
+            }
+            if open_arguments(&code[target as usize]).is_some() {
+                return fail(proto, pc, "jump past the instruction leaving open arguments");
+            }
+        }
+        let loop_end = |bx: u32| &code[pc + 1 + bx as usize];
+        match *instruction {
+            ForPrep { a, bx } if !matches!(loop_end(bx), ForLoop { a: end, .. } if *end == a) => return fail(proto, pc, "FORPREP without its FORLOOP"),
+            TForPrep { a, bx } if !matches!(loop_end(bx), TForCall { a: end, .. } if *end == a) => return fail(proto, pc, "TFORPREP without its TFORCALL"),
+            TForCall { a, .. } if !matches!(next, Some(TForLoop { a: end, .. }) if *end == a) => return fail(proto, pc, "TFORCALL without its TFORLOOP"),
+            VarArgPrep { a } if pc != 0 || !proto.is_vararg || a != proto.num_params => return fail(proto, pc, "misplaced VARARGPREP"),
+            // the frame of a vararg function is moved back by its parameters when it returns
+            Return { c, .. } | TailCall { c, .. } if c != if proto.is_vararg { proto.num_params + 1 } else { 0 } => {
+                return fail(proto, pc, &format!("parameter count {} not matching the function", c))
+            },
+            _ => {}
+        }
+
+        /* instructions working in pairs */
+        if extended(instruction) && !matches!(next, Some(ExtraArg { .. })) {
+            return fail(proto, pc, "missing EXTRAARG");
+        }
+        if matches!(instruction, ExtraArg { .. }) && !previous.is_some_and(extended) {
+            return fail(proto, pc, "EXTRAARG without an instruction to extend");
+        }
+        if let Some(kind) = arith_kind(instruction) {
+            if next.and_then(metamethod_kind).is_none_or(|(mm_kind, _)| mm_kind != kind) {
+                return fail(proto, pc, "arithmetic without its MMBIN");
+            }
+        }
+        if let Some((kind, tm)) = metamethod_kind(instruction) {
+            if previous.and_then(arith_kind) != Some(kind) {
+                return fail(proto, pc, "MMBIN not following an arithmetic instruction");
+            }
+            if !(FIRST_ARITH_EVENT..=LAST_ARITH_EVENT).contains(&tm) {
+                return fail(proto, pc, &format!("MMBIN with event {}", tm));
+            }
+        }
+        if let Some(first) = open_results(instruction) {
+            if next.and_then(open_arguments).is_none_or(|consumer| consumer > first) {
+                return fail(proto, pc, "open results not taken by the next instruction");
+            }
+        }
+        if open_arguments(instruction).is_some() && previous.and_then(open_results).is_none() {
+            return fail(proto, pc, "open arguments not left by the instruction before");
+        }
+    }
+    for child in &proto.fns {
+        function(child, Some(proto))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::verify;
+    use crate::{core::{builder::ProtoBuilder, dump::dump, instruction::Instruction, parser::parse_all, types::Proto}, vm::LuaVm};
+
+    fn function(code: &[Instruction]) -> Proto {
+        let mut f = ProtoBuilder::new("=test");
+        for instruction in code {
+            f.emit(instruction.encode().unwrap());
+        }
+        f.build().unwrap()
+    }
+
+    fn rejected(f: &Proto) -> String {
+        verify(f).unwrap_err()
+    }
+
+    #[test]
+    fn accept_luac_chunks() {
+        let chunks: [&[u8]; 6] = [
+            include_bytes!("../../helpers/luac.out"),
+            include_bytes!("../../helpers/out1"),
+            include_bytes!("../../helpers/tests/base.out"),
+            include_bytes!("../../helpers/tests/call_native.out"),
+            include_bytes!("../../helpers/tests/string.out"),
+            include_bytes!("../../helpers/tests/debug.out"),
+        ];
+        for chunk in chunks {
+            let f = parse_all(chunk).unwrap();
+            assert_eq!(verify(&f), Ok(()));
+            assert_eq!(verify(&parse_all(&dump(&f, true)).unwrap()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn reject_operands() {
+        use Instruction::*;
+        let mut f = function(&[Move { a: 0, b: 5 }, Return0 { a: 0 }]);
+        f.max_stack_size = 2;
+        assert_eq!(rejected(&f), "register 5 beyond the stack size at instruction 1 of main function");
+        assert_eq!(rejected(&function(&[LoadK { a: 0, bx: 3 }, Return0 { a: 0 }])), "constant 3 out of range at instruction 1 of main function");
+        assert_eq!(rejected(&function(&[GetUpval { a: 0, b: 1 }, Return0 { a: 0 }])), "upvalue 1 out of range at instruction 1 of main function");
+        assert_eq!(rejected(&function(&[Closure { a: 0, bx: 0 }, Return0 { a: 0 }])), "function 0 out of range at instruction 1 of main function");
+        assert_eq!(rejected(&function(&[Jmp { sj: 5 }, Return0 { a: 0 }])), "jump out of the function at instruction 1 of main function");
+        let vararg = |code: &[Instruction]| {
+            let mut f = function(code);
+            (f.is_vararg, f.max_stack_size) = (true, 8);
+            f
+        };
+        let skip_open_results = [VarArgPrep { a: 0 }, Jmp { sj: 1 }, Call { a: 5, b: 1, c: 0 }, Return { a: 5, b: 0, c: 1, k: false }];
+        assert_eq!(rejected(&vararg(&skip_open_results)), "jump past the instruction leaving open arguments at instruction 2 of main function");
+        let drop_parameters = [VarArgPrep { a: 0 }, Return { a: 0, b: 1, c: 200, k: false }];
+        assert_eq!(rejected(&vararg(&drop_parameters)), "parameter count 200 not matching the function at instruction 2 of main function");
+        let keep_parameters = [Return { a: 0, b: 1, c: 1, k: false }];
+        assert_eq!(rejected(&function(&keep_parameters)), "parameter count 1 not matching the function at instruction 1 of main function");
+        let skip_into_extra_arg = [Jmp { sj: 1 }, LoadKX { a: 0 }, ExtraArg { ax: 0 }, Return0 { a: 0 }];
+        assert_eq!(rejected(&function(&skip_into_extra_arg)), "jump into an EXTRAARG at instruction 1 of main function");
+        let test_into_extra_arg = [Test { a: 0, k: false }, LoadKX { a: 0 }, ExtraArg { ax: 0 }, Return0 { a: 0 }];
+        assert_eq!(rejected(&function(&test_into_extra_arg)), "jump into an EXTRAARG at instruction 1 of main function");
+        assert_eq!(rejected(&function(&[Move { a: 0, b: 0 }])), "function not ending in a return at instruction 1 of main function");
+    }
+
+    #[test]
+    fn reject_broken_pairs() {
+        use Instruction::*;
+        let cases = [
+            (vec![NewTable { a: 0, b: 0, c: 0, k: false }, Return0 { a: 0 }], "missing EXTRAARG at instruction 1"),
+            (vec![Move { a: 0, b: 0 }, ExtraArg { ax: 0 }, Return0 { a: 0 }], "EXTRAARG without an instruction to extend at instruction 2"),
+            (vec![Add { a: 0, b: 0, c: 0 }, Return0 { a: 0 }], "arithmetic without its MMBIN at instruction 1"),
+            (vec![AddI { a: 0, b: 0, sc: 1 }, MmBin { a: 0, b: 0, tm: 6 }, Return0 { a: 0 }], "arithmetic without its MMBIN at instruction 1"),
+            (vec![MmBin { a: 0, b: 0, tm: 6 }, Return0 { a: 0 }], "MMBIN not following an arithmetic instruction at instruction 1"),
+            (vec![Add { a: 0, b: 0, c: 0 }, MmBin { a: 0, b: 0, tm: 24 }, Return0 { a: 0 }], "MMBIN with event 24 at instruction 2"),
+            (vec![Call { a: 0, b: 1, c: 0 }, Return0 { a: 0 }], "open results not taken by the next instruction at instruction 1"),
+            (vec![Return { a: 0, b: 0, c: 0, k: false }], "open arguments not left by the instruction before at instruction 1"),
+            (vec![Move { a: 0, b: 0 }, VarArgPrep { a: 0 }, Return0 { a: 0 }], "misplaced VARARGPREP at instruction 2"),
+        ];
+        for (code, message) in cases {
+            assert_eq!(rejected(&function(&code)), format!("{} of main function", message));
+        }
+    }
+
+    #[test]
+    fn reject_foreign_upvalues() {
+        let mut child = ProtoBuilder::new("=test");
+        child.lines_defined(1, 2).upvalue("x", false, 3);
+        child.emit(Instruction::Return0 { a: 0 }.encode().unwrap());
+        let mut f = ProtoBuilder::new("=test");
+        f.child(child.build().unwrap());
+        f.emit(Instruction::Closure { a: 0, bx: 0 }.encode().unwrap());
+        f.emit(Instruction::Return0 { a: 0 }.encode().unwrap());
+        assert_eq!(rejected(&f.build().unwrap()), "upvalue 3 not in the enclosing function in function at line 1");
+    }
+
+    #[test]
+    fn load_verified_chunks() {
+        let f = function(&[Instruction::Jmp { sj: -2 }, Instruction::Return0 { a: 0 }]);
+        let mut vm = LuaVm::new();
+        let message = vm.load_chunk(&dump(&f, false), "=bad", "b").unwrap_err();
+        assert_eq!(message, "bad: bad binary format (jump out of the function at instruction 1 of main function)");
+    }
+}
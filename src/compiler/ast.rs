@@ -0,0 +1,180 @@
+/*
+ * Abstract syntax tree of Lua 5.4 chunks. Every node keeps the span of source it was read
+ * from: code generation takes its line information from them
+ */
+
+use std::rc::Rc;
+
+use crate::core::types::string::LuaString;
+
+use super::lexer::Span;
+
+pub type Name = Rc<LuaString>;
+
+pub type Block = Vec<Stat>;
+
+/**
+ * The main function of a chunk: a vararg function without parameters
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub block: Block,
+    /* from the first token to the last one */
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatKind {
+    /* a function call used as a statement */
+    Call(Expr),
+    /* targets are names and indexed expressions */
+    Assign { targets: Vec<Expr>, values: Vec<Expr> },
+    Local { names: Vec<LocalName>, values: Vec<Expr> },
+    LocalFunction { name: LocalName, body: Box<FunctionBody> },
+    /* function a.b.c:m() ... end */
+    Function { path: Vec<Name>, method: Option<Name>, body: Box<FunctionBody> },
+    /* if/elseif branches in order, then the optional else block */
+    If { branches: Vec<IfBranch>, else_block: Option<Block> },
+    While { condition: Expr, block: Block },
+    NumericFor { var: LocalName, start: Expr, limit: Expr, step: Option<Expr>, block: Block },
+    GenericFor { names: Vec<LocalName>, values: Vec<Expr>, block: Block },
+    Repeat { block: Block, condition: Expr },
+    Do(Block),
+    /* always the last statement of its block */
+    Return(Vec<Expr>),
+    Break,
+    Goto(Name),
+    Label(Name),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IfBranch {
+    pub condition: Expr,
+    pub block: Block,
+    /* from 'if'/'elseif' to the end of the block */
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attrib {
+    Const,
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalName {
+    pub name: Name,
+    pub attrib: Option<Attrib>,
+    pub span: Span,
+}
+
+/**
+ * Parameters and body of a function; the span goes from '(' to 'end'. Methods get an implicit
+ * 'self' parameter before 'params'
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionBody {
+    pub params: Vec<LocalName>,
+    pub is_vararg: bool,
+    pub is_method: bool,
+    pub block: Block,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Nil,
+    True,
+    False,
+    Vararg,
+    Int(i64),
+    Float(f64),
+    String(Rc<LuaString>),
+    Function(Box<FunctionBody>),
+    Table(Vec<Field>),
+    Name(Name),
+    /* a.b is an index with a string key */
+    Index { object: Box<Expr>, key: Box<Expr> },
+    Call { function: Box<Expr>, args: Vec<Expr> },
+    MethodCall { object: Box<Expr>, method: Name, args: Vec<Expr> },
+    /* parentheses truncate multiple results and make variables plain values */
+    Paren(Box<Expr>),
+    Binary { op: BinOp, left: Box<Expr>, right: Box<Expr>, op_span: Span },
+    Unary { op: UnOp, operand: Box<Expr>, op_span: Span },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    /* { value } */
+    Positional(Expr),
+    /* { name = value } */
+    Named { key: Name, key_span: Span, value: Expr },
+    /* { [key] = value } */
+    Keyed { key: Expr, value: Expr },
+}
+
+/*
+ * ORDER OPR, same as the binary opcodes and metamethods
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add, Sub, Mul, Mod, Pow, Div, IDiv,
+    BAnd, BOr, BXor, Shl, Shr,
+    Concat,
+    Eq, Lt, Le, Ne, Gt, Ge,
+    And, Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Minus,
+    BNot,
+    Not,
+    Len,
+}
+
+/* priority of unary operators */
+pub const UNARY_PRIORITY: u8 = 12;
+
+impl BinOp {
+    /**
+     * Left and right priorities: right associative operators have a lower right priority
+     */
+    pub fn priority(self) -> (u8, u8) {
+        match self {
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Mod => (11, 11),
+            BinOp::Pow => (14, 13),
+            BinOp::Div | BinOp::IDiv => (11, 11),
+            BinOp::BAnd => (6, 6),
+            BinOp::BOr => (4, 4),
+            BinOp::BXor => (5, 5),
+            BinOp::Shl | BinOp::Shr => (7, 7),
+            BinOp::Concat => (9, 8),
+            BinOp::Eq | BinOp::Lt | BinOp::Le | BinOp::Ne | BinOp::Gt | BinOp::Ge => (3, 3),
+            BinOp::And => (2, 2),
+            BinOp::Or => (1, 1),
+        }
+    }
+}
+
+impl Expr {
+    /**
+     * Calls and '...' can produce any number of values
+     */
+    pub fn is_multi(&self) -> bool {
+        matches!(self.kind, ExprKind::Call { .. } | ExprKind::MethodCall { .. } | ExprKind::Vararg)
+    }
+}
@@ -0,0 +1,3122 @@
+/*
+ * Code generation from the syntax tree (lcode.c and the code emitting half of lparser.c).
+ * The output matches luac instruction by instruction, debug information included. luac
+ * stamps every instruction with the line of the last token consumed when it was emitted, so
+ * the generator walks the chunk's tokens along with the tree to know that line
+ */
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::core::{opcodes::{self, LuaOpcode, MAXARG_A, MAXARG_B, MAXARG_C, MAXARG_BX, MAXARG_AX, MAXARG_SJ, OFFSET_SBX, OFFSET_SJ, OFFSET_SC}, types::{LineInfoWriter, LocVar, Proto, TValue, UpvalueDescription, string::LuaString, number::{float_to_integer, F2IMode}}};
+use crate::vm::{arith::{arith, to_integer_strict}, debug::chunk_id, meta::TMS};
+
+use super::{ast::{BinOp, Block, Chunk, Expr, ExprKind, Field, FunctionBody, Attrib, LocalName, Name, Stat, StatKind, UnOp}, lexer::{self, LexResult, Position, SpannedToken, Token}, parser};
+
+const NO_JUMP: i32 = -1;
+const MULTRET: i32 = -1;
+
+/* invalid register that fits in 8 bits */
+const NO_REG: u32 = MAXARG_A;
+/* constants that can be used as an RK operand */
+const MAXINDEXRK: u32 = MAXARG_B;
+
+/* maximum number of registers in a Lua function (must fit in 8 bits) */
+const MAXREGS: u32 = 255;
+const MAXVARS: usize = 200;
+const MAXUPVAL: usize = 255;
+/* number of list items to accumulate before a SETLIST instruction */
+const LFIELDS_PER_FLUSH: u32 = 50;
+/* longest string kept as a short (interned) string */
+const LUAI_MAXSHORTLEN: usize = 40;
+
+/**
+ * Compiles a text chunk into its main function, as luac would
+ */
+pub fn compile(source: &[u8], chunk_name: &str) -> LexResult<Proto> {
+    let chunk = parser::parse(source, chunk_name)?;
+    let tokens = lexer::tokenize_with_text(source, chunk_name)?;
+    CodeGen::new(chunk_name, tokens).main_func(&chunk)
+}
+
+fn create_abck(op: LuaOpcode, a: u32, b: u32, c: u32, k: bool) -> u32 {
+    op as u32 | a << 7 | (k as u32) << 15 | b << 16 | c << 24
+}
+
+fn create_abx(op: LuaOpcode, a: u32, bx: u32) -> u32 {
+    op as u32 | a << 7 | bx << 15
+}
+
+fn create_ax(op: LuaOpcode, ax: u32) -> u32 {
+    op as u32 | ax << 7
+}
+
+fn get_opcode(i: u32) -> LuaOpcode {
+    LuaOpcode::from((i & 0x7f) as u8)
+}
+
+fn get_a(i: u32) -> u32 {
+    i >> 7 & 0xff
+}
+
+fn get_b(i: u32) -> u32 {
+    i >> 16 & 0xff
+}
+
+fn get_k(i: u32) -> bool {
+    i >> 15 & 1 != 0
+}
+
+fn get_sj(i: u32) -> i32 {
+    (i >> 7 & MAXARG_SJ) as i32 - OFFSET_SJ
+}
+
+fn set_arg(i: &mut u32, pos: u32, size: u32, value: u32) {
+    let mask = ((1u32 << size) - 1) << pos;
+    *i = (*i & !mask) | (value << pos & mask);
+}
+
+fn set_opcode(i: &mut u32, op: LuaOpcode) {
+    set_arg(i, 0, 7, op as u32);
+}
+
+fn set_a(i: &mut u32, a: u32) {
+    set_arg(i, 7, 8, a);
+}
+
+fn set_b(i: &mut u32, b: u32) {
+    set_arg(i, 16, 8, b);
+}
+
+fn set_c(i: &mut u32, c: u32) {
+    set_arg(i, 24, 8, c);
+}
+
+fn set_k(i: &mut u32, k: bool) {
+    set_arg(i, 15, 1, k as u32);
+}
+
+fn set_bx(i: &mut u32, bx: u32) {
+    set_arg(i, 15, 17, bx);
+}
+
+fn set_sj(i: &mut u32, sj: i32) {
+    set_arg(i, 7, 25, (sj + OFFSET_SJ) as u32);
+}
+
+/**
+ * Whether 'i' fits in an sC operand
+ */
+fn fits_c(i: i64) -> bool {
+    (i as u64).wrapping_add(OFFSET_SC as u64) <= MAXARG_C as u64
+}
+
+/**
+ * Whether 'i' fits in an sBx operand
+ */
+fn fits_bx(i: i64) -> bool {
+    -(OFFSET_SBX as i64) <= i && i <= (MAXARG_BX as i64 - OFFSET_SBX as i64)
+}
+
+fn int_to_sc(i: i32) -> u32 {
+    (i + OFFSET_SC) as u32
+}
+
+/**
+ * luaO_ceillog2
+ */
+fn ceil_log2(x: u32) -> u32 {
+    32 - (x - 1).leading_zeros()
+}
+
+fn before(a: Position, b: Position) -> bool {
+    (a.line, a.column) < (b.line, b.column)
+}
+
+fn name_of(name: &str) -> Name {
+    Rc::new(LuaString::from(name))
+}
+
+fn same_name(a: &Name, b: &Name) -> bool {
+    a.as_bytes() == b.as_bytes()
+}
+
+#[derive(Debug, Clone)]
+enum ExpKind {
+    /* when the expression is the last of a list, an empty list */
+    Void,
+    Nil,
+    True,
+    False,
+    /* constant: index in 'constants' */
+    K(u32),
+    KFlt(f64),
+    KInt(i64),
+    KStr(Rc<LuaString>),
+    /* value in a fixed register */
+    NonReloc(u32),
+    /* local variable: its register and its index among the active variables */
+    Local { ridx: u32, vidx: usize },
+    Upval(u32),
+    /* compile-time <const> variable: absolute index in 'actvar' */
+    Const(usize),
+    /* table and key in registers */
+    Indexed { t: u32, idx: u32 },
+    /* upvalue table, constant string key */
+    IndexUp { t: u32, idx: u32 },
+    /* table in a register, key is a small integer */
+    IndexI { t: u32, idx: u32 },
+    /* table in a register, constant string key */
+    IndexStr { t: u32, idx: u32 },
+    /* test or comparison: pc of its jump */
+    Jmp(i32),
+    /* value can go to any register: pc of the instruction producing it */
+    Reloc(i32),
+    Call(i32),
+    Vararg(i32),
+}
+
+/**
+ * expdesc: an expression being compiled, plus its patch lists of jumps taken when true ('t')
+ * and when false ('f')
+ */
+#[derive(Debug, Clone)]
+struct ExpDesc {
+    k: ExpKind,
+    t: i32,
+    f: i32,
+}
+
+impl ExpDesc {
+    fn new(k: ExpKind) -> Self {
+        Self { k, t: NO_JUMP, f: NO_JUMP }
+    }
+
+    fn has_jumps(&self) -> bool {
+        self.t != self.f
+    }
+
+    fn has_multret(&self) -> bool {
+        matches!(self.k, ExpKind::Call(_) | ExpKind::Vararg(_))
+    }
+
+    fn is_indexed(&self) -> bool {
+        matches!(self.k, ExpKind::Indexed { .. } | ExpKind::IndexUp { .. } | ExpKind::IndexI { .. } | ExpKind::IndexStr { .. })
+    }
+
+    /**
+     * The register of an expression already discharged to one
+     */
+    fn reg(&self) -> u32 {
+        match self.k {
+            ExpKind::NonReloc(reg) => reg,
+            _ => unreachable!("expression is not in a register"),
+        }
+    }
+
+    /**
+     * The instruction producing the expression
+     */
+    fn pc(&self) -> i32 {
+        match self.k {
+            ExpKind::Jmp(pc) | ExpKind::Reloc(pc) | ExpKind::Call(pc) | ExpKind::Vararg(pc) => pc,
+            _ => unreachable!("expression has no instruction"),
+        }
+    }
+
+    /**
+     * tonumeral: the value of a numeric constant
+     */
+    fn numeral(&self) -> Option<TValue> {
+        if self.has_jumps() {
+            return None;
+        }
+        match self.k {
+            ExpKind::KInt(i) => Some(TValue::NUMINT(i)),
+            ExpKind::KFlt(f) => Some(TValue::NUMFLT(f)),
+            _ => None,
+        }
+    }
+
+    fn is_kint(&self) -> bool {
+        matches!(self.k, ExpKind::KInt(_)) && !self.has_jumps()
+    }
+
+    fn int_value(&self) -> i64 {
+        match self.k {
+            ExpKind::KInt(i) => i,
+            _ => unreachable!("expression is not an integer constant"),
+        }
+    }
+
+    /**
+     * isCint: an integer constant that fits in argument C
+     */
+    fn is_cint(&self) -> bool {
+        self.is_kint() && (self.int_value() as u64) <= MAXARG_C as u64
+    }
+
+    /**
+     * isSCint: an integer constant that fits in argument sC
+     */
+    fn is_scint(&self) -> bool {
+        self.is_kint() && fits_c(self.int_value())
+    }
+
+    /**
+     * isSCnumber: a number constant with an integral value fitting in argument sC, as that
+     * argument. 'is_float' is set for floats even when the number doesn't fit, as luac does
+     */
+    fn sc_number(&self, is_float: &mut bool) -> Option<u32> {
+        let i = match self.k {
+            ExpKind::KInt(i) => i,
+            ExpKind::KFlt(f) => match float_to_integer(f, F2IMode::Exact) {
+                Some(i) => {
+                    *is_float = true;
+                    i
+                },
+                None => return None,
+            },
+            _ => return None,
+        };
+        if !self.has_jumps() && fits_c(i) { Some(int_to_sc(i as i32)) } else { None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VarKind {
+    Regular = 0,
+    Const = 1,
+    ToClose = 2,
+    /* compile-time constant: it has no register */
+    CompileTimeConst = 3,
+}
+
+/**
+ * Vardesc: an active local variable
+ */
+#[derive(Debug, Clone)]
+struct VarDesc {
+    name: Name,
+    kind: VarKind,
+    /* register holding the variable */
+    ridx: u32,
+    /* index of the variable in the prototype's 'loc_vars' */
+    pidx: usize,
+    /* value of a compile-time constant */
+    value: TValue,
+}
+
+/**
+ * Labeldesc: a label or a pending goto
+ */
+#[derive(Debug, Clone)]
+struct LabelDesc {
+    name: Name,
+    pc: i32,
+    line: u32,
+    /* number of active variables at that position */
+    nactvar: usize,
+    /* goto that escapes upvalues */
+    close: bool,
+}
+
+/**
+ * BlockCnt: a block being compiled
+ */
+#[derive(Debug, Clone)]
+struct BlockCnt {
+    first_label: usize,
+    first_goto: usize,
+    /* number of active locals outside the block */
+    nactvar: usize,
+    /* some variable in the block is an upvalue */
+    upval: bool,
+    is_loop: bool,
+    /* inside the scope of a to-be-closed variable */
+    inside_tbc: bool,
+}
+
+/**
+ * FuncState: a function being compiled, with the prototype it becomes
+ */
+struct FuncState {
+    line_defined: u32,
+    last_line_defined: u32,
+    num_params: u8,
+    is_vararg: bool,
+    max_stack_size: u8,
+    code: Vec<u32>,
+    lines: LineInfoWriter,
+    constants: Vec<TValue>,
+    protos: Vec<Rc<Proto>>,
+    upvalues: Vec<UpvalueDescription>,
+    loc_vars: Vec<LocVar>,
+    blocks: Vec<BlockCnt>,
+    /* pc of the last jump target */
+    last_target: i32,
+    /* first free register */
+    freereg: u32,
+    /* number of active local variables */
+    nactvar: usize,
+    /* index of the first local variable of this function in 'actvar' */
+    first_local: usize,
+    /* index of the first label of this function in 'labels' */
+    first_label: usize,
+    /* the function needs to close upvalues when returning */
+    needclose: bool,
+}
+
+impl FuncState {
+    fn new(line_defined: u32, first_local: usize, first_label: usize) -> Self {
+        Self {
+            line_defined,
+            last_line_defined: 0,
+            num_params: 0,
+            is_vararg: false,
+            max_stack_size: 2,
+            code: Vec::new(),
+            lines: LineInfoWriter::new(line_defined as i64),
+            constants: Vec::new(),
+            protos: Vec::new(),
+            upvalues: Vec::new(),
+            loc_vars: Vec::new(),
+            blocks: Vec::new(),
+            last_target: 0,
+            freereg: 0,
+            nactvar: 0,
+            first_local,
+            first_label,
+            needclose: false,
+        }
+    }
+
+    fn pc(&self) -> i32 {
+        self.code.len() as i32
+    }
+
+    fn block(&mut self) -> &mut BlockCnt {
+        self.blocks.last_mut().expect("function without a block")
+    }
+
+    fn remove_last_instruction(&mut self) {
+        self.lines.remove_last();
+        self.code.pop();
+    }
+
+    /**
+     * getjump: destination of the jump at 'pc', NO_JUMP at the end of a list
+     */
+    fn get_jump(&self, pc: i32) -> i32 {
+        let offset = get_sj(self.code[pc as usize]);
+        if offset == NO_JUMP { NO_JUMP } else { pc + 1 + offset }
+    }
+
+    /**
+     * fixjump: jumps have 25 bits, so no function can have code long enough for them to fail
+     */
+    fn fix_jump(&mut self, pc: i32, dest: i32) {
+        let offset = dest - (pc + 1);
+        debug_assert!(dest != NO_JUMP && -OFFSET_SJ <= offset && offset <= MAXARG_SJ as i32 - OFFSET_SJ);
+        set_sj(&mut self.code[pc as usize], offset);
+    }
+
+    /**
+     * getjumpcontrol: the test controlling the jump at 'pc', or the jump itself
+     */
+    fn jump_control(&self, pc: i32) -> usize {
+        let pc = pc as usize;
+        if pc >= 1 && get_opcode(self.code[pc - 1]).is_test() { pc - 1 } else { pc }
+    }
+
+    /**
+     * patchtestreg: makes the TESTSET controlling 'node' store into 'reg', or turns it into
+     * a TEST when there is no register to store. False for other jumps
+     */
+    fn patch_test_reg(&mut self, node: i32, reg: u32) -> bool {
+        let control = self.jump_control(node);
+        let i = self.code[control];
+        if get_opcode(i) != LuaOpcode::TESTSET_ABk {
+            return false;
+        }
+        if reg != NO_REG && reg != get_b(i) {
+            set_a(&mut self.code[control], reg);
+        } else {
+            self.code[control] = create_abck(LuaOpcode::TEST_Ak, get_b(i), 0, 0, get_k(i));
+        }
+        true
+    }
+
+    /**
+     * removevalues: no test in the list produces a value
+     */
+    fn remove_values(&mut self, mut list: i32) {
+        while list != NO_JUMP {
+            self.patch_test_reg(list, NO_REG);
+            list = self.get_jump(list);
+        }
+    }
+
+    /**
+     * patchlistaux: tests producing values jump to 'vtarget' with their value in 'reg', the
+     * others to 'dtarget'
+     */
+    fn patch_list_aux(&mut self, mut list: i32, vtarget: i32, reg: u32, dtarget: i32) {
+        while list != NO_JUMP {
+            let next = self.get_jump(list);
+            if self.patch_test_reg(list, reg) {
+                self.fix_jump(list, vtarget);
+            } else {
+                self.fix_jump(list, dtarget);
+            }
+            list = next;
+        }
+    }
+
+    /**
+     * luaK_concat: appends the jump list 'l2' to 'l1'
+     */
+    fn concat(&mut self, l1: &mut i32, l2: i32) {
+        if l2 == NO_JUMP {
+            return;
+        }
+        if *l1 == NO_JUMP {
+            *l1 = l2;
+            return;
+        }
+        let mut list = *l1;
+        loop {
+            let next = self.get_jump(list);
+            if next == NO_JUMP {
+                break;
+            }
+            list = next;
+        }
+        self.fix_jump(list, l2);
+    }
+
+    /**
+     * luaK_getlabel: the current pc, marked as a jump target
+     */
+    fn get_label(&mut self) -> i32 {
+        self.last_target = self.pc();
+        self.pc()
+    }
+
+    fn patch_list(&mut self, list: i32, target: i32) {
+        self.patch_list_aux(list, target, NO_REG, target);
+    }
+
+    fn patch_to_here(&mut self, list: i32) {
+        let here = self.get_label();
+        self.patch_list(list, here);
+    }
+
+    /**
+     * previousinstruction: the last instruction, unless a jump may land between it and the
+     * next one
+     */
+    fn previous_instruction(&self) -> Option<usize> {
+        if self.pc() > self.last_target { Some(self.code.len() - 1) } else { None }
+    }
+
+    /**
+     * luaK_finish: final pass turning returns into their general form where needed and
+     * threading jumps to jumps
+     */
+    fn finish(&mut self) {
+        for i in 0..self.code.len() {
+            let op = get_opcode(self.code[i]);
+            match op {
+                LuaOpcode::RETURN0 | LuaOpcode::RETURN1_A | LuaOpcode::RETURN_ABCk | LuaOpcode::TAILCALL_ABCk => {
+                    if matches!(op, LuaOpcode::RETURN0 | LuaOpcode::RETURN1_A) {
+                        if !(self.needclose || self.is_vararg) {
+                            continue;
+                        }
+                        set_opcode(&mut self.code[i], LuaOpcode::RETURN_ABCk);
+                    }
+                    if self.needclose {
+                        set_k(&mut self.code[i], true);
+                    }
+                    if self.is_vararg {
+                        set_c(&mut self.code[i], self.num_params as u32 + 1);
+                    }
+                },
+                LuaOpcode::JMP_sJ => {
+                    let target = self.final_target(i);
+                    self.fix_jump(i as i32, target as i32);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /**
+     * finaltarget: where a chain of jumps ends, giving up after 100 of them
+     */
+    fn final_target(&self, mut i: usize) -> usize {
+        for _ in 0..100 {
+            let instruction = self.code[i];
+            if get_opcode(instruction) != LuaOpcode::JMP_sJ {
+                break;
+            }
+            i = (i as i32 + get_sj(instruction) + 1) as usize;
+        }
+        i
+    }
+
+    fn into_proto(self, source: &Rc<LuaString>) -> Proto {
+        Proto {
+            fn_name: Some(source.clone()),
+            line_defined: self.line_defined as usize,
+            last_line_defined: self.last_line_defined as usize,
+            num_params: self.num_params,
+            is_vararg: self.is_vararg,
+            max_stack_size: self.max_stack_size,
+            constants: self.constants,
+            code: self.code.into_iter().map(opcodes::decode).collect(),
+            fns: self.protos,
+            upvalues: self.upvalues,
+            line_info: self.lines.line_info,
+            abs_line_info: self.lines.abs_line_info,
+            loc_vars: self.loc_vars,
+        }
+    }
+}
+
+/**
+ * Keys of the cache of constant indices. Like the table luac uses for it, integral floats
+ * share the key of the integer
+ */
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(u64),
+    Str(Vec<u8>),
+}
+
+impl ConstKey {
+    fn number(f: f64) -> Self {
+        match float_to_integer(f, F2IMode::Exact) {
+            Some(i) => ConstKey::Int(i),
+            None => ConstKey::Float(f.to_bits()),
+        }
+    }
+}
+
+/**
+ * luaV_rawequalobj over constants, telling integers from floats
+ */
+fn same_constant(a: &TValue, b: &TValue) -> bool {
+    match (a, b) {
+        (TValue::NIL, TValue::NIL) => true,
+        (TValue::TBOOLEAN(x), TValue::TBOOLEAN(y)) => x == y,
+        (TValue::NUMINT(x), TValue::NUMINT(y)) => x == y,
+        (TValue::NUMFLT(x), TValue::NUMFLT(y)) => x == y,
+        (TValue::STR(x), TValue::STR(y)) => x.as_bytes() == y.as_bytes(),
+        _ => false,
+    }
+}
+
+fn const_to_exp(value: &TValue) -> ExpKind {
+    match value {
+        TValue::NUMINT(i) => ExpKind::KInt(*i),
+        TValue::NUMFLT(f) => ExpKind::KFlt(*f),
+        TValue::TBOOLEAN(true) => ExpKind::True,
+        TValue::TBOOLEAN(false) => ExpKind::False,
+        TValue::STR(s) => ExpKind::KStr(s.clone()),
+        _ => ExpKind::Nil,
+    }
+}
+
+fn bin_tm(op: BinOp) -> TMS {
+    match op {
+        BinOp::Add => TMS::ADD,
+        BinOp::Sub => TMS::SUB,
+        BinOp::Mul => TMS::MUL,
+        BinOp::Mod => TMS::MOD,
+        BinOp::Pow => TMS::POW,
+        BinOp::Div => TMS::DIV,
+        BinOp::IDiv => TMS::IDIV,
+        BinOp::BAnd => TMS::BAND,
+        BinOp::BOr => TMS::BOR,
+        BinOp::BXor => TMS::BXOR,
+        BinOp::Shl => TMS::SHL,
+        BinOp::Shr => TMS::SHR,
+        _ => unreachable!("operator has no metamethod event"),
+    }
+}
+
+/**
+ * binopr2op: the opcode of an operator, counting from the opcode of the first operator of
+ * its group (ORDER OPR - ORDER OP)
+ */
+fn bin_opcode(op: BinOp, base_op: BinOp, base: LuaOpcode) -> LuaOpcode {
+    LuaOpcode::from(base as u8 + (op as u8 - base_op as u8))
+}
+
+/**
+ * validop: folding must not raise errors, so bitwise operands must be integral and
+ * divisors can't be 0
+ */
+fn valid_op(op: TMS, v1: &TValue, v2: &TValue) -> bool {
+    match op {
+        TMS::BAND | TMS::BOR | TMS::BXOR | TMS::SHL | TMS::SHR | TMS::BNOT => {
+            to_integer_strict(v1).is_some() && to_integer_strict(v2).is_some()
+        },
+        TMS::DIV | TMS::IDIV | TMS::MOD => match v2 {
+            TValue::NUMINT(i) => *i != 0,
+            TValue::NUMFLT(f) => *f != 0.0,
+            _ => false,
+        },
+        _ => true,
+    }
+}
+
+/**
+ * Constructor being compiled (ConsControl)
+ */
+struct ConsControl {
+    /* last list item read */
+    v: ExpDesc,
+    /* number of record elements */
+    nh: u32,
+    /* number of array elements already stored */
+    na: u32,
+    /* number of array elements pending to be stored */
+    to_store: u32,
+}
+
+struct CodeGen<'a> {
+    chunk_name: &'a str,
+    source: Rc<LuaString>,
+    /* every token with how errors show it */
+    tokens: Vec<(SpannedToken, String)>,
+    /* index of the current token */
+    cursor: usize,
+    /* line of the last token consumed, when the lexer had read one token further */
+    lookahead_line: Option<u32>,
+    /* list item starting with a name, which luac reads one token past */
+    lookahead_name: Option<Position>,
+    funcs: Vec<FuncState>,
+    /* active local variables of all open functions; entries past 'nactvars' are stale */
+    actvar: Vec<VarDesc>,
+    nactvars: usize,
+    /* pending gotos and active labels of all open functions */
+    gotos: Vec<LabelDesc>,
+    labels: Vec<LabelDesc>,
+    /* index of each constant, shared by all functions */
+    cache: HashMap<ConstKey, usize>,
+}
+
+impl<'a> CodeGen<'a> {
+    fn new(chunk_name: &'a str, tokens: Vec<(SpannedToken, String)>) -> Self {
+        Self {
+            chunk_name,
+            source: name_of(chunk_name),
+            tokens,
+            cursor: 0,
+            lookahead_line: None,
+            lookahead_name: None,
+            funcs: Vec::new(),
+            actvar: Vec::new(),
+            nactvars: 0,
+            gotos: Vec::new(),
+            labels: Vec::new(),
+            cache: HashMap::new(),
+        }
+    }
+
+    /*
+     * Token cursor
+     */
+
+    /**
+     * Makes the first token at or after 'pos' the current one
+     */
+    fn seek(&mut self, pos: Position) {
+        self.cursor = self.tokens.partition_point(|(token, _)| before(token.span.start, pos));
+        self.lookahead_line = None;
+    }
+
+    /**
+     * luaX_next
+     */
+    fn advance(&mut self) {
+        self.cursor += 1;
+        self.lookahead_line = None;
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.cursor].0.token
+    }
+
+    /**
+     * ls->linenumber: the lexer stands at the end of the current token
+     */
+    fn line(&self) -> u32 {
+        self.tokens[self.cursor].0.span.end.line
+    }
+
+    /**
+     * ls->lastline: the line of the last token consumed, where instructions go
+     */
+    fn last_line(&self) -> u32 {
+        match self.lookahead_line {
+            Some(line) => line,
+            None if self.cursor == 0 => 1,
+            None => self.tokens[self.cursor - 1].0.span.end.line,
+        }
+    }
+
+    fn skip_semicolons(&mut self) {
+        while self.token() == &Token::Char(b';') {
+            self.advance();
+        }
+    }
+
+    /**
+     * block_follow
+     */
+    fn block_follow(&self, with_until: bool) -> bool {
+        match self.token() {
+            Token::Else | Token::Elseif | Token::End | Token::Eos => true,
+            Token::Until => with_until,
+            _ => false,
+        }
+    }
+
+    /*
+     * Errors
+     */
+
+    /**
+     * luaX_syntaxerror: a message about the current token
+     */
+    fn syntax_error(&self, message: &str) -> String {
+        format!("{}:{}: {} near {}", chunk_id(self.chunk_name), self.line(), message, self.tokens[self.cursor].1)
+    }
+
+    /**
+     * luaK_semerror: like a syntax error, without the token
+     */
+    fn semantic_error(&self, message: &str) -> String {
+        format!("{}:{}: {}", chunk_id(self.chunk_name), self.line(), message)
+    }
+
+    fn check_limit(&self, value: usize, limit: usize, what: &str) -> LexResult<()> {
+        if value <= limit {
+            return Ok(());
+        }
+        let line = self.fs().line_defined;
+        let place = if line == 0 { "main function".to_string() } else { format!("function at line {}", line) };
+        Err(self.syntax_error(&format!("too many {} (limit is {}) in {}", what, limit, place)))
+    }
+
+    /*
+     * Emitting code (lcode.c)
+     */
+
+    fn fs(&self) -> &FuncState {
+        self.funcs.last().expect("no function being compiled")
+    }
+
+    fn fs_mut(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().expect("no function being compiled")
+    }
+
+    fn pc(&self) -> i32 {
+        self.fs().pc()
+    }
+
+    fn instruction(&mut self, pc: i32) -> &mut u32 {
+        &mut self.fs_mut().code[pc as usize]
+    }
+
+    /**
+     * luaK_code: emits an instruction at the line of the last token consumed
+     */
+    fn code(&mut self, i: u32) -> i32 {
+        let line = self.last_line() as i64;
+        let fs = self.fs_mut();
+        fs.code.push(i);
+        fs.lines.save(line);
+        fs.pc() - 1
+    }
+
+    fn code_abck(&mut self, op: LuaOpcode, a: u32, b: u32, c: u32, k: bool) -> i32 {
+        self.code(create_abck(op, a, b, c, k))
+    }
+
+    fn code_abc(&mut self, op: LuaOpcode, a: u32, b: u32, c: u32) -> i32 {
+        self.code_abck(op, a, b, c, false)
+    }
+
+    fn code_abx(&mut self, op: LuaOpcode, a: u32, bx: u32) -> i32 {
+        self.code(create_abx(op, a, bx))
+    }
+
+    fn code_asbx(&mut self, op: LuaOpcode, a: u32, sbx: i32) -> i32 {
+        self.code(create_abx(op, a, (sbx + OFFSET_SBX) as u32))
+    }
+
+    fn code_extra_arg(&mut self, ax: u32) -> i32 {
+        debug_assert!(ax <= MAXARG_AX);
+        self.code(create_ax(LuaOpcode::EXTRAARG_Ax, ax))
+    }
+
+    /**
+     * luaK_fixline: moves the last instruction to 'line'
+     */
+    fn fix_line(&mut self, line: u32) {
+        let fs = self.fs_mut();
+        fs.lines.remove_last();
+        fs.lines.save(line as i64);
+    }
+
+    /**
+     * luaK_nil: merges with a previous LOADNIL when the ranges touch
+     */
+    fn nil(&mut self, mut from: u32, n: u32) {
+        let mut last = from + n - 1;
+        if let Some(prev) = self.fs().previous_instruction() {
+            let previous = self.fs().code[prev];
+            if get_opcode(previous) == LuaOpcode::LOADNIL_ABC {
+                let pfrom = get_a(previous);
+                let plast = pfrom + get_b(previous);
+                if (pfrom <= from && from <= plast + 1) || (from <= pfrom && pfrom <= last + 1) {
+                    from = from.min(pfrom);
+                    last = last.max(plast);
+                    let instruction = &mut self.fs_mut().code[prev];
+                    set_a(instruction, from);
+                    set_b(instruction, last - from);
+                    return;
+                }
+            }
+        }
+        self.code_abc(LuaOpcode::LOADNIL_ABC, from, n - 1, 0);
+    }
+
+    fn jump(&mut self) -> i32 {
+        self.code(create_abx(LuaOpcode::JMP_sJ, 0, 0) | ((NO_JUMP + OFFSET_SJ) as u32) << 7)
+    }
+
+    fn jump_to(&mut self, target: i32) {
+        let jump = self.jump();
+        self.fs_mut().patch_list(jump, target);
+    }
+
+    fn ret(&mut self, first: u32, nret: i32) {
+        let op = match nret {
+            0 => LuaOpcode::RETURN0,
+            1 => LuaOpcode::RETURN1_A,
+            _ => LuaOpcode::RETURN_ABCk,
+        };
+        self.code_abc(op, first, (nret + 1) as u32, 0);
+    }
+
+    fn cond_jump(&mut self, op: LuaOpcode, a: u32, b: u32, c: u32, k: bool) -> i32 {
+        self.code_abck(op, a, b, c, k);
+        self.jump()
+    }
+
+    fn get_label(&mut self) -> i32 {
+        self.fs_mut().get_label()
+    }
+
+    fn concat(&mut self, l1: &mut i32, l2: i32) {
+        self.fs_mut().concat(l1, l2);
+    }
+
+    fn patch_to_here(&mut self, list: i32) {
+        self.fs_mut().patch_to_here(list);
+    }
+
+    /**
+     * luaK_codek: LOADK, or LOADKX with an extra argument for big indices
+     */
+    fn code_k(&mut self, reg: u32, k: u32) -> i32 {
+        if k <= MAXARG_BX {
+            self.code_abx(LuaOpcode::LOADK_ABx, reg, k)
+        } else {
+            let pc = self.code_abx(LuaOpcode::LOADKX_A, reg, 0);
+            self.code_extra_arg(k);
+            pc
+        }
+    }
+
+    /**
+     * luaK_checkstack: keeps track of the maximum stack size
+     */
+    fn check_stack(&mut self, n: u32) -> LexResult<()> {
+        let new_stack = self.fs().freereg + n;
+        if new_stack > self.fs().max_stack_size as u32 {
+            if new_stack >= MAXREGS {
+                return Err(self.syntax_error("function or expression needs too many registers"));
+            }
+            self.fs_mut().max_stack_size = new_stack as u8;
+        }
+        Ok(())
+    }
+
+    fn reserve_regs(&mut self, n: u32) -> LexResult<()> {
+        self.check_stack(n)?;
+        self.fs_mut().freereg += n;
+        Ok(())
+    }
+
+    /**
+     * freereg: frees a register unless it belongs to a local variable
+     */
+    fn free_reg(&mut self, reg: u32) {
+        if reg >= self.nvarstack() {
+            let fs = self.fs_mut();
+            fs.freereg -= 1;
+            debug_assert_eq!(reg, fs.freereg);
+        }
+    }
+
+    fn free_regs(&mut self, r1: u32, r2: u32) {
+        if r1 > r2 {
+            self.free_reg(r1);
+            self.free_reg(r2);
+        } else {
+            self.free_reg(r2);
+            self.free_reg(r1);
+        }
+    }
+
+    fn free_exp(&mut self, e: &ExpDesc) {
+        if let ExpKind::NonReloc(reg) = e.k {
+            self.free_reg(reg);
+        }
+    }
+
+    /**
+     * freeexps: frees in the right order the registers of two expressions
+     */
+    fn free_exps(&mut self, e1: &ExpDesc, e2: &ExpDesc) {
+        match (&e1.k, &e2.k) {
+            (ExpKind::NonReloc(r1), ExpKind::NonReloc(r2)) => self.free_regs(*r1, *r2),
+            (ExpKind::NonReloc(r1), _) => self.free_reg(*r1),
+            (_, ExpKind::NonReloc(r2)) => self.free_reg(*r2),
+            _ => {},
+        }
+    }
+
+    /**
+     * addk: reuses the index of an equal constant, telling integers from floats. The cache is
+     * shared by all functions, so an index may belong to another function
+     */
+    fn add_k(&mut self, key: ConstKey, value: TValue) -> u32 {
+        let fs = self.funcs.last_mut().expect("no function being compiled");
+        if let Some(&k) = self.cache.get(&key) {
+            if k < fs.constants.len() && same_constant(&fs.constants[k], &value) {
+                return k as u32;
+            }
+        }
+        let k = fs.constants.len();
+        self.cache.insert(key, k);
+        fs.constants.push(value);
+        k as u32
+    }
+
+    fn string_k(&mut self, s: &Rc<LuaString>) -> u32 {
+        self.add_k(ConstKey::Str(s.as_bytes().to_vec()), TValue::STR(s.clone()))
+    }
+
+    fn int_k(&mut self, i: i64) -> u32 {
+        self.add_k(ConstKey::Int(i), TValue::NUMINT(i))
+    }
+
+    /**
+     * luaK_numberK: integral floats need a key apart from the integers; it is the float plus
+     * its smallest significant fraction
+     */
+    fn number_k(&mut self, r: f64) -> u32 {
+        match float_to_integer(r, F2IMode::Exact) {
+            None => self.add_k(ConstKey::Float(r.to_bits()), TValue::NUMFLT(r)),
+            Some(ik) => {
+                let q = 2f64.powi(-52);
+                let k = if ik == 0 { q } else { r + r * q };
+                self.add_k(ConstKey::number(k), TValue::NUMFLT(r))
+            },
+        }
+    }
+
+    fn bool_k(&mut self, b: bool) -> u32 {
+        self.add_k(ConstKey::Bool(b), TValue::TBOOLEAN(b))
+    }
+
+    fn nil_k(&mut self) -> u32 {
+        self.add_k(ConstKey::Nil, TValue::NIL)
+    }
+
+    /**
+     * luaK_int: LOADI when the integer fits in sBx
+     */
+    fn int(&mut self, reg: u32, i: i64) {
+        if fits_bx(i) {
+            self.code_asbx(LuaOpcode::LOADI_AsBx, reg, i as i32);
+        } else {
+            let k = self.int_k(i);
+            self.code_k(reg, k);
+        }
+    }
+
+    fn float(&mut self, reg: u32, f: f64) {
+        match float_to_integer(f, F2IMode::Exact) {
+            Some(fi) if fits_bx(fi) => {
+                self.code_asbx(LuaOpcode::LOADF_AsBx, reg, fi as i32);
+            },
+            _ => {
+                let k = self.number_k(f);
+                self.code_k(reg, k);
+            },
+        }
+    }
+
+    /**
+     * luaK_setreturns: a call or vararg expression produces 'nresults' values
+     */
+    fn set_returns(&mut self, e: &mut ExpDesc, nresults: i32) -> LexResult<()> {
+        match e.k {
+            ExpKind::Call(pc) => set_c(self.instruction(pc), (nresults + 1) as u32),
+            ExpKind::Vararg(pc) => {
+                set_c(self.instruction(pc), (nresults + 1) as u32);
+                let freereg = self.fs().freereg;
+                set_a(self.instruction(pc), freereg);
+                self.reserve_regs(1)?;
+            },
+            _ => unreachable!("expression has no multiple results"),
+        }
+        Ok(())
+    }
+
+    fn set_multret(&mut self, e: &mut ExpDesc) -> LexResult<()> {
+        self.set_returns(e, MULTRET)
+    }
+
+    /**
+     * luaK_setoneret: a call or vararg expression produces one value
+     */
+    fn set_one_ret(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            ExpKind::Call(pc) => {
+                let reg = get_a(*self.instruction(pc));
+                e.k = ExpKind::NonReloc(reg);
+            },
+            ExpKind::Vararg(pc) => {
+                set_c(self.instruction(pc), 2);
+                e.k = ExpKind::Reloc(pc);
+            },
+            _ => {},
+        }
+    }
+
+    /**
+     * str2K: a string constant becomes an index in 'constants'
+     */
+    fn str_to_k(&mut self, e: &mut ExpDesc) {
+        if let ExpKind::KStr(s) = &e.k {
+            let s = s.clone();
+            e.k = ExpKind::K(self.string_k(&s));
+        }
+    }
+
+    /**
+     * luaK_dischargevars: variables become values
+     */
+    fn discharge_vars(&mut self, e: &mut ExpDesc) {
+        e.k = match e.k {
+            ExpKind::Const(idx) => const_to_exp(&self.actvar[idx].value),
+            ExpKind::Local { ridx, .. } => ExpKind::NonReloc(ridx),
+            ExpKind::Upval(idx) => ExpKind::Reloc(self.code_abc(LuaOpcode::GETUPVAL_AB, 0, idx, 0)),
+            ExpKind::IndexUp { t, idx } => ExpKind::Reloc(self.code_abc(LuaOpcode::GETTABUP_ABC, 0, t, idx)),
+            ExpKind::IndexI { t, idx } => {
+                self.free_reg(t);
+                ExpKind::Reloc(self.code_abc(LuaOpcode::GETI_ABC, 0, t, idx))
+            },
+            ExpKind::IndexStr { t, idx } => {
+                self.free_reg(t);
+                ExpKind::Reloc(self.code_abc(LuaOpcode::GETFIELD_ABC, 0, t, idx))
+            },
+            ExpKind::Indexed { t, idx } => {
+                self.free_regs(t, idx);
+                ExpKind::Reloc(self.code_abc(LuaOpcode::GETTABLE_ABC, 0, t, idx))
+            },
+            ExpKind::Vararg(_) | ExpKind::Call(_) => {
+                self.set_one_ret(e);
+                return;
+            },
+            _ => return,
+        };
+    }
+
+    /**
+     * discharge2reg: puts the value in 'reg'; jumps are left alone
+     */
+    fn discharge_to_reg(&mut self, e: &mut ExpDesc, reg: u32) {
+        self.discharge_vars(e);
+        match e.k.clone() {
+            ExpKind::Nil => self.nil(reg, 1),
+            ExpKind::False => {
+                self.code_abc(LuaOpcode::LOADFALSE_A, reg, 0, 0);
+            },
+            ExpKind::True => {
+                self.code_abc(LuaOpcode::LOADTRUE_A, reg, 0, 0);
+            },
+            ExpKind::KStr(s) => {
+                let k = self.string_k(&s);
+                self.code_k(reg, k);
+            },
+            ExpKind::K(k) => {
+                self.code_k(reg, k);
+            },
+            ExpKind::KFlt(f) => self.float(reg, f),
+            ExpKind::KInt(i) => self.int(reg, i),
+            ExpKind::Reloc(pc) => set_a(self.instruction(pc), reg),
+            ExpKind::NonReloc(r) => {
+                if reg != r {
+                    self.code_abc(LuaOpcode::MOVE_AB, reg, r, 0);
+                }
+            },
+            ExpKind::Jmp(_) => return,
+            kind => unreachable!("can't discharge {:?}", kind),
+        }
+        e.k = ExpKind::NonReloc(reg);
+    }
+
+    fn discharge_to_any_reg(&mut self, e: &mut ExpDesc) -> LexResult<()> {
+        if !matches!(e.k, ExpKind::NonReloc(_)) {
+            self.reserve_regs(1)?;
+            let reg = self.fs().freereg - 1;
+            self.discharge_to_reg(e, reg);
+        }
+        Ok(())
+    }
+
+    /**
+     * code_loadbool: the instruction may be a jump target
+     */
+    fn code_loadbool(&mut self, a: u32, op: LuaOpcode) -> i32 {
+        self.get_label();
+        self.code_abc(op, a, 0, 0)
+    }
+
+    /**
+     * need_value: some jump in the list isn't controlled by a TESTSET
+     */
+    fn need_value(&self, mut list: i32) -> bool {
+        let fs = self.fs();
+        while list != NO_JUMP {
+            let control = fs.code[fs.jump_control(list)];
+            if get_opcode(control) != LuaOpcode::TESTSET_ABk {
+                return true;
+            }
+            list = fs.get_jump(list);
+        }
+        false
+    }
+
+    /**
+     * exp2reg: puts the final value of the expression, jumps included, in 'reg'
+     */
+    fn exp_to_reg(&mut self, e: &mut ExpDesc, reg: u32) {
+        self.discharge_to_reg(e, reg);
+        if let ExpKind::Jmp(pc) = e.k {
+            self.concat(&mut e.t, pc);
+        }
+        if e.has_jumps() {
+            let mut p_f = NO_JUMP;
+            let mut p_t = NO_JUMP;
+            if self.need_value(e.t) || self.need_value(e.f) {
+                let fj = if matches!(e.k, ExpKind::Jmp(_)) { NO_JUMP } else { self.jump() };
+                p_f = self.code_loadbool(reg, LuaOpcode::LFALSESKIP_A);
+                p_t = self.code_loadbool(reg, LuaOpcode::LOADTRUE_A);
+                self.patch_to_here(fj);
+            }
+            let fs = self.fs_mut();
+            let end = fs.get_label();
+            fs.patch_list_aux(e.f, end, reg, p_f);
+            fs.patch_list_aux(e.t, end, reg, p_t);
+        }
+        e.f = NO_JUMP;
+        e.t = NO_JUMP;
+        e.k = ExpKind::NonReloc(reg);
+    }
+
+    /**
+     * luaK_exp2nextreg: puts the value in the next free register
+     */
+    fn exp_to_next_reg(&mut self, e: &mut ExpDesc) -> LexResult<()> {
+        self.discharge_vars(e);
+        self.free_exp(e);
+        self.reserve_regs(1)?;
+        let reg = self.fs().freereg - 1;
+        self.exp_to_reg(e, reg);
+        Ok(())
+    }
+
+    /**
+     * luaK_exp2anyreg: puts the value in some register and returns it
+     */
+    fn exp_to_any_reg(&mut self, e: &mut ExpDesc) -> LexResult<u32> {
+        self.discharge_vars(e);
+        if let ExpKind::NonReloc(reg) = e.k {
+            if !e.has_jumps() {
+                return Ok(reg);
+            }
+            if reg >= self.nvarstack() {
+                self.exp_to_reg(e, reg);
+                return Ok(reg);
+            }
+        }
+        self.exp_to_next_reg(e)?;
+        Ok(e.reg())
+    }
+
+    /**
+     * luaK_exp2anyregup: upvalues can stay where they are
+     */
+    fn exp_to_any_reg_up(&mut self, e: &mut ExpDesc) -> LexResult<()> {
+        if !matches!(e.k, ExpKind::Upval(_)) || e.has_jumps() {
+            self.exp_to_any_reg(e)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * luaK_exp2val: a register or a constant
+     */
+    fn exp_to_val(&mut self, e: &mut ExpDesc) -> LexResult<()> {
+        if e.has_jumps() {
+            self.exp_to_any_reg(e)?;
+        } else {
+            self.discharge_vars(e);
+        }
+        Ok(())
+    }
+
+    /**
+     * luaK_exp2K: turns a constant into a K expression when its index fits in an operand
+     */
+    fn exp_to_k(&mut self, e: &mut ExpDesc) -> bool {
+        if e.has_jumps() {
+            return false;
+        }
+        let info = match e.k.clone() {
+            ExpKind::True => self.bool_k(true),
+            ExpKind::False => self.bool_k(false),
+            ExpKind::Nil => self.nil_k(),
+            ExpKind::KInt(i) => self.int_k(i),
+            ExpKind::KFlt(f) => self.number_k(f),
+            ExpKind::KStr(s) => self.string_k(&s),
+            ExpKind::K(k) => k,
+            _ => return false,
+        };
+        if info <= MAXINDEXRK {
+            e.k = ExpKind::K(info);
+            true
+        } else {
+            false
+        }
+    }
+
+    /**
+     * exp2RK: a constant operand or a register; true for constants
+     */
+    fn exp_to_rk(&mut self, e: &mut ExpDesc) -> LexResult<bool> {
+        if self.exp_to_k(e) {
+            Ok(true)
+        } else {
+            self.exp_to_any_reg(e)?;
+            Ok(false)
+        }
+    }
+
+    fn k_or_reg(e: &ExpDesc) -> u32 {
+        match e.k {
+            ExpKind::K(k) | ExpKind::NonReloc(k) => k,
+            _ => unreachable!("operand is neither a constant nor a register"),
+        }
+    }
+
+    fn code_abrk(&mut self, op: LuaOpcode, a: u32, b: u32, ec: &mut ExpDesc) -> LexResult<()> {
+        let k = self.exp_to_rk(ec)?;
+        self.code_abck(op, a, b, Self::k_or_reg(ec), k);
+        Ok(())
+    }
+
+    /**
+     * luaK_storevar
+     */
+    fn store_var(&mut self, var: &ExpDesc, ex: &mut ExpDesc) -> LexResult<()> {
+        match var.k {
+            ExpKind::Local { ridx, .. } => {
+                self.free_exp(ex);
+                self.exp_to_reg(ex, ridx);
+                return Ok(());
+            },
+            ExpKind::Upval(idx) => {
+                let e = self.exp_to_any_reg(ex)?;
+                self.code_abc(LuaOpcode::SETUPVAL_AB, e, idx, 0);
+            },
+            ExpKind::IndexUp { t, idx } => self.code_abrk(LuaOpcode::SETTABUP_ABC, t, idx, ex)?,
+            ExpKind::IndexI { t, idx } => self.code_abrk(LuaOpcode::SETI_ABC, t, idx, ex)?,
+            ExpKind::IndexStr { t, idx } => self.code_abrk(LuaOpcode::SETFIELD_ABC, t, idx, ex)?,
+            ExpKind::Indexed { t, idx } => self.code_abrk(LuaOpcode::SETTABLE_ABC, t, idx, ex)?,
+            _ => unreachable!("invalid variable kind to store"),
+        }
+        self.free_exp(ex);
+        Ok(())
+    }
+
+    /**
+     * luaK_self: e:key(e, ...)
+     */
+    fn self_(&mut self, e: &mut ExpDesc, key: &mut ExpDesc) -> LexResult<()> {
+        self.exp_to_any_reg(e)?;
+        let ereg = e.reg();
+        self.free_exp(e);
+        let base = self.fs().freereg;
+        e.k = ExpKind::NonReloc(base);
+        self.reserve_regs(2)?;
+        self.code_abrk(LuaOpcode::SELF_ABC, base, ereg, key)?;
+        self.free_exp(key);
+        Ok(())
+    }
+
+    fn negate_condition(&mut self, e: &ExpDesc) {
+        let fs = self.fs_mut();
+        let control = fs.jump_control(e.pc());
+        let k = get_k(fs.code[control]);
+        set_k(&mut fs.code[control], !k);
+    }
+
+    /**
+     * jumponcond: a jump taken when the expression is 'cond'. A preceding NOT is folded into
+     * the test
+     */
+    fn jump_on_cond(&mut self, e: &mut ExpDesc, cond: bool) -> LexResult<i32> {
+        if let ExpKind::Reloc(pc) = e.k {
+            let ie = *self.instruction(pc);
+            if get_opcode(ie) == LuaOpcode::NOT_AB {
+                self.fs_mut().remove_last_instruction();
+                return Ok(self.cond_jump(LuaOpcode::TEST_Ak, get_b(ie), 0, 0, !cond));
+            }
+        }
+        self.discharge_to_any_reg(e)?;
+        self.free_exp(e);
+        Ok(self.cond_jump(LuaOpcode::TESTSET_ABk, NO_REG, e.reg(), 0, cond))
+    }
+
+    /**
+     * luaK_goiftrue: falls through when true, jumps when false
+     */
+    fn go_if_true(&mut self, e: &mut ExpDesc) -> LexResult<()> {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::Jmp(pc) => {
+                self.negate_condition(e);
+                pc
+            },
+            ExpKind::K(_) | ExpKind::KFlt(_) | ExpKind::KInt(_) | ExpKind::KStr(_) | ExpKind::True => NO_JUMP,
+            _ => self.jump_on_cond(e, false)?,
+        };
+        self.concat(&mut e.f, pc);
+        self.patch_to_here(e.t);
+        e.t = NO_JUMP;
+        Ok(())
+    }
+
+    /**
+     * luaK_goiffalse: falls through when false, jumps when true
+     */
+    fn go_if_false(&mut self, e: &mut ExpDesc) -> LexResult<()> {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::Jmp(pc) => pc,
+            ExpKind::Nil | ExpKind::False => NO_JUMP,
+            _ => self.jump_on_cond(e, true)?,
+        };
+        self.concat(&mut e.t, pc);
+        self.patch_to_here(e.f);
+        e.f = NO_JUMP;
+        Ok(())
+    }
+
+    fn code_not(&mut self, e: &mut ExpDesc) -> LexResult<()> {
+        match e.k {
+            ExpKind::Nil | ExpKind::False => e.k = ExpKind::True,
+            ExpKind::K(_) | ExpKind::KFlt(_) | ExpKind::KInt(_) | ExpKind::KStr(_) | ExpKind::True => e.k = ExpKind::False,
+            ExpKind::Jmp(_) => self.negate_condition(e),
+            ExpKind::Reloc(_) | ExpKind::NonReloc(_) => {
+                self.discharge_to_any_reg(e)?;
+                self.free_exp(e);
+                e.k = ExpKind::Reloc(self.code_abc(LuaOpcode::NOT_AB, 0, e.reg(), 0));
+            },
+            _ => unreachable!("cannot negate {:?}", e.k),
+        }
+        std::mem::swap(&mut e.f, &mut e.t);
+        let fs = self.fs_mut();
+        fs.remove_values(e.f);
+        fs.remove_values(e.t);
+        Ok(())
+    }
+
+    /**
+     * isKstr: a constant short string whose index fits in argument B
+     */
+    fn is_kstr(&self, e: &ExpDesc) -> bool {
+        match e.k {
+            ExpKind::K(k) if !e.has_jumps() && k <= MAXARG_B => {
+                matches!(&self.fs().constants[k as usize], TValue::STR(s) if s.as_bytes().len() <= LUAI_MAXSHORTLEN)
+            },
+            _ => false,
+        }
+    }
+
+    /**
+     * luaK_indexed: t[k], where 't' is in a register or an upvalue. Upvalues can only be
+     * indexed by constant strings
+     */
+    fn indexed(&mut self, t: &mut ExpDesc, k: &mut ExpDesc) -> LexResult<()> {
+        self.str_to_k(k);
+        if matches!(t.k, ExpKind::Upval(_)) && !self.is_kstr(k) {
+            self.exp_to_any_reg(t)?;
+        }
+        if let ExpKind::Upval(idx) = t.k {
+            t.k = ExpKind::IndexUp { t: idx, idx: Self::k_or_reg(k) };
+            return Ok(());
+        }
+        let treg = match t.k {
+            ExpKind::Local { ridx, .. } => ridx,
+            ExpKind::NonReloc(reg) => reg,
+            _ => unreachable!("indexed expression is not in a register"),
+        };
+        t.k = if self.is_kstr(k) {
+            ExpKind::IndexStr { t: treg, idx: Self::k_or_reg(k) }
+        } else if k.is_cint() {
+            ExpKind::IndexI { t: treg, idx: k.int_value() as u32 }
+        } else {
+            ExpKind::Indexed { t: treg, idx: self.exp_to_any_reg(k)? }
+        };
+        Ok(())
+    }
+
+    /**
+     * constfolding: folds operations over numeric constants that can't raise errors, as long
+     * as the result is neither NaN nor a float 0
+     */
+    fn const_folding(&mut self, op: TMS, e1: &mut ExpDesc, e2: &ExpDesc) -> bool {
+        let (v1, v2) = match (e1.numeral(), e2.numeral()) {
+            (Some(v1), Some(v2)) => (v1, v2),
+            _ => return false,
+        };
+        if !valid_op(op, &v1, &v2) {
+            return false;
+        }
+        match arith(op, &v1, &v2) {
+            Ok(Some(TValue::NUMINT(i))) => e1.k = ExpKind::KInt(i),
+            Ok(Some(TValue::NUMFLT(n))) if !n.is_nan() && n != 0.0 => e1.k = ExpKind::KFlt(n),
+            _ => return false,
+        }
+        true
+    }
+
+    fn code_unexpval(&mut self, op: LuaOpcode, e: &mut ExpDesc, line: u32) -> LexResult<()> {
+        let r = self.exp_to_any_reg(e)?;
+        self.free_exp(e);
+        e.k = ExpKind::Reloc(self.code_abc(op, 0, r, 0));
+        self.fix_line(line);
+        Ok(())
+    }
+
+    /**
+     * finishbinexpval: the operation followed by the instruction calling its metamethod
+     */
+    #[allow(clippy::too_many_arguments)]
+    fn finish_binexpval(&mut self, e1: &mut ExpDesc, e2: &ExpDesc, op: LuaOpcode, v2: u32, flip: bool, line: u32, mmop: LuaOpcode, event: TMS) -> LexResult<()> {
+        let v1 = self.exp_to_any_reg(e1)?;
+        let pc = self.code_abc(op, 0, v1, v2);
+        self.free_exps(e1, e2);
+        e1.k = ExpKind::Reloc(pc);
+        self.fix_line(line);
+        self.code_abck(mmop, v1, v2, event as u32, flip);
+        self.fix_line(line);
+        Ok(())
+    }
+
+    fn code_binexpval(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc, line: u32) -> LexResult<()> {
+        let opcode = bin_opcode(op, BinOp::Add, LuaOpcode::ADD_ABC);
+        let v2 = self.exp_to_any_reg(e2)?;
+        self.finish_binexpval(e1, e2, opcode, v2, false, line, LuaOpcode::MMBIN_ABC, bin_tm(op))
+    }
+
+    fn code_bini(&mut self, op: LuaOpcode, e1: &mut ExpDesc, e2: &ExpDesc, flip: bool, line: u32, event: TMS) -> LexResult<()> {
+        let v2 = int_to_sc(e2.int_value() as i32);
+        self.finish_binexpval(e1, e2, op, v2, flip, line, LuaOpcode::MMBINI_AsBCk, event)
+    }
+
+    fn code_bink(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &ExpDesc, flip: bool, line: u32) -> LexResult<()> {
+        let v2 = Self::k_or_reg(e2);
+        let opcode = bin_opcode(op, BinOp::Add, LuaOpcode::ADDK_ABC);
+        self.finish_binexpval(e1, e2, opcode, v2, flip, line, LuaOpcode::MMBINK_ABCk, bin_tm(op))
+    }
+
+    /**
+     * finishbinexpneg: codes the operation with the negated constant; the metamethod still
+     * gets the original one
+     */
+    fn finish_binexpneg(&mut self, e1: &mut ExpDesc, e2: &ExpDesc, op: LuaOpcode, line: u32, event: TMS) -> LexResult<bool> {
+        if !e2.is_kint() {
+            return Ok(false);
+        }
+        let i2 = e2.int_value();
+        if !(fits_c(i2) && fits_c(i2.wrapping_neg())) {
+            return Ok(false);
+        }
+        let v2 = i2 as i32;
+        self.finish_binexpval(e1, e2, op, int_to_sc(-v2), false, line, LuaOpcode::MMBINI_AsBCk, event)?;
+        let pc = self.pc() - 1;
+        set_b(self.instruction(pc), int_to_sc(v2));
+        Ok(true)
+    }
+
+    fn code_bin_nok(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc, flip: bool, line: u32) -> LexResult<()> {
+        if flip {
+            std::mem::swap(e1, e2);
+        }
+        self.code_binexpval(op, e1, e2, line)
+    }
+
+    fn code_arith(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc, flip: bool, line: u32) -> LexResult<()> {
+        if e2.numeral().is_some() && self.exp_to_k(e2) {
+            self.code_bink(op, e1, e2, flip, line)
+        } else {
+            self.code_bin_nok(op, e1, e2, flip, line)
+        }
+    }
+
+    /**
+     * codecommutative: a numeric first operand is swapped to the second place, where it can
+     * be an immediate or constant operand
+     */
+    fn code_commutative(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc, line: u32) -> LexResult<()> {
+        let mut flip = false;
+        if e1.numeral().is_some() {
+            std::mem::swap(e1, e2);
+            flip = true;
+        }
+        if op == BinOp::Add && e2.is_scint() {
+            self.code_bini(LuaOpcode::ADDI_ABsC, e1, e2, flip, line, TMS::ADD)
+        } else {
+            self.code_arith(op, e1, e2, flip, line)
+        }
+    }
+
+    fn code_bitwise(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc, line: u32) -> LexResult<()> {
+        let mut flip = false;
+        if matches!(e1.k, ExpKind::KInt(_)) {
+            std::mem::swap(e1, e2);
+            flip = true;
+        }
+        if matches!(e2.k, ExpKind::KInt(_)) && self.exp_to_k(e2) {
+            self.code_bink(op, e1, e2, flip, line)
+        } else {
+            self.code_bin_nok(op, e1, e2, flip, line)
+        }
+    }
+
+    /**
+     * codeorder: '<' and '<=', with an immediate operand when one side is a small number
+     */
+    fn code_order(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc) -> LexResult<()> {
+        let mut is_float = false;
+        let (r1, r2, opcode) = if let Some(im) = e2.sc_number(&mut is_float) {
+            (self.exp_to_any_reg(e1)?, im, bin_opcode(op, BinOp::Lt, LuaOpcode::LTI_AsBk))
+        } else if let Some(im) = e1.sc_number(&mut is_float) {
+            (self.exp_to_any_reg(e2)?, im, bin_opcode(op, BinOp::Lt, LuaOpcode::GTI_AsBk))
+        } else {
+            let r1 = self.exp_to_any_reg(e1)?;
+            let r2 = self.exp_to_any_reg(e2)?;
+            (r1, r2, bin_opcode(op, BinOp::Lt, LuaOpcode::LT_ABk))
+        };
+        self.free_exps(e1, e2);
+        e1.k = ExpKind::Jmp(self.cond_jump(opcode, r1, r2, is_float as u32, true));
+        Ok(())
+    }
+
+    /**
+     * codeeq: '==' and '~='; the first operand was already made a register or a constant
+     */
+    fn code_eq(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc) -> LexResult<()> {
+        if !matches!(e1.k, ExpKind::NonReloc(_)) {
+            std::mem::swap(e1, e2);
+        }
+        let r1 = self.exp_to_any_reg(e1)?;
+        let mut is_float = false;
+        let (opcode, r2) = if let Some(im) = e2.sc_number(&mut is_float) {
+            (LuaOpcode::EQI_AsBk, im)
+        } else if self.exp_to_rk(e2)? {
+            (LuaOpcode::EQK_ABk, Self::k_or_reg(e2))
+        } else {
+            (LuaOpcode::EQ_ABk, self.exp_to_any_reg(e2)?)
+        };
+        self.free_exps(e1, e2);
+        e1.k = ExpKind::Jmp(self.cond_jump(opcode, r1, r2, is_float as u32, op == BinOp::Eq));
+        Ok(())
+    }
+
+    /**
+     * luaK_prefix
+     */
+    fn prefix(&mut self, op: UnOp, e: &mut ExpDesc, line: u32) -> LexResult<()> {
+        self.discharge_vars(e);
+        let fake = ExpDesc::new(ExpKind::KInt(0));
+        match op {
+            UnOp::Minus => {
+                if !self.const_folding(TMS::UNM, e, &fake) {
+                    self.code_unexpval(LuaOpcode::UNM_AB, e, line)?;
+                }
+            },
+            UnOp::BNot => {
+                if !self.const_folding(TMS::BNOT, e, &fake) {
+                    self.code_unexpval(LuaOpcode::BNOT_AB, e, line)?;
+                }
+            },
+            UnOp::Len => self.code_unexpval(LuaOpcode::LEN_AB, e, line)?,
+            UnOp::Not => self.code_not(e)?,
+        }
+        Ok(())
+    }
+
+    /**
+     * luaK_infix: the first operand, before reading the second one
+     */
+    fn infix(&mut self, op: BinOp, v: &mut ExpDesc) -> LexResult<()> {
+        self.discharge_vars(v);
+        match op {
+            BinOp::And => self.go_if_true(v)?,
+            BinOp::Or => self.go_if_false(v)?,
+            BinOp::Concat => self.exp_to_next_reg(v)?,
+            BinOp::Eq | BinOp::Ne => {
+                if v.numeral().is_none() {
+                    self.exp_to_rk(v)?;
+                }
+            },
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                if v.sc_number(&mut false).is_none() {
+                    self.exp_to_any_reg(v)?;
+                }
+            },
+            _ => {
+                if v.numeral().is_none() {
+                    self.exp_to_any_reg(v)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /**
+     * codeconcat: 'e1 .. e2' where 'e2' is a concatenation extends it
+     */
+    fn code_concat(&mut self, e1: &mut ExpDesc, e2: &ExpDesc, line: u32) {
+        if let Some(prev) = self.fs().previous_instruction() {
+            let ie2 = self.fs().code[prev];
+            if get_opcode(ie2) == LuaOpcode::CONCAT_AB {
+                let n = get_b(ie2);
+                self.free_exp(e2);
+                let instruction = &mut self.fs_mut().code[prev];
+                set_a(instruction, e1.reg());
+                set_b(instruction, n + 1);
+                return;
+            }
+        }
+        self.code_abc(LuaOpcode::CONCAT_AB, e1.reg(), 2, 0);
+        self.free_exp(e2);
+        self.fix_line(line);
+    }
+
+    /**
+     * luaK_posfix: the operation, once both operands are read
+     */
+    fn posfix(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc, line: u32) -> LexResult<()> {
+        self.discharge_vars(e2);
+        if op as u8 <= BinOp::Shr as u8 && self.const_folding(bin_tm(op), e1, e2) {
+            return Ok(());
+        }
+        match op {
+            BinOp::And => {
+                self.concat(&mut e2.f, e1.f);
+                *e1 = e2.clone();
+            },
+            BinOp::Or => {
+                self.concat(&mut e2.t, e1.t);
+                *e1 = e2.clone();
+            },
+            BinOp::Concat => {
+                self.exp_to_next_reg(e2)?;
+                self.code_concat(e1, e2, line);
+            },
+            BinOp::Add | BinOp::Mul => self.code_commutative(op, e1, e2, line)?,
+            BinOp::Sub => {
+                if !self.finish_binexpneg(e1, e2, LuaOpcode::ADDI_ABsC, line, TMS::SUB)? {
+                    self.code_arith(op, e1, e2, false, line)?;
+                }
+            },
+            BinOp::Div | BinOp::IDiv | BinOp::Mod | BinOp::Pow => self.code_arith(op, e1, e2, false, line)?,
+            BinOp::BAnd | BinOp::BOr | BinOp::BXor => self.code_bitwise(op, e1, e2, line)?,
+            BinOp::Shl => {
+                if e1.is_scint() {
+                    std::mem::swap(e1, e2);
+                    self.code_bini(LuaOpcode::SHLI_ABsC, e1, e2, true, line, TMS::SHL)?;
+                } else if !self.finish_binexpneg(e1, e2, LuaOpcode::SHRI_ABsC, line, TMS::SHL)? {
+                    self.code_binexpval(op, e1, e2, line)?;
+                }
+            },
+            BinOp::Shr => {
+                if e2.is_scint() {
+                    self.code_bini(LuaOpcode::SHRI_ABsC, e1, e2, false, line, TMS::SHR)?;
+                } else {
+                    self.code_binexpval(op, e1, e2, line)?;
+                }
+            },
+            BinOp::Eq | BinOp::Ne => self.code_eq(op, e1, e2)?,
+            BinOp::Gt | BinOp::Ge => {
+                std::mem::swap(e1, e2);
+                let op = if op == BinOp::Gt { BinOp::Lt } else { BinOp::Le };
+                self.code_order(op, e1, e2)?;
+            },
+            BinOp::Lt | BinOp::Le => self.code_order(op, e1, e2)?,
+        }
+        Ok(())
+    }
+
+    /**
+     * luaK_settablesize: sizes of a NEWTABLE, known once the constructor is read
+     */
+    fn set_table_size(&mut self, pc: i32, ra: u32, asize: u32, hsize: u32) {
+        let rb = if hsize != 0 { ceil_log2(hsize) + 1 } else { 0 };
+        let extra = asize / (MAXARG_C + 1);
+        let rc = asize % (MAXARG_C + 1);
+        let fs = self.fs_mut();
+        fs.code[pc as usize] = create_abck(LuaOpcode::NEWTABLE_ABCk, ra, rb, rc, extra > 0);
+        fs.code[pc as usize + 1] = create_ax(LuaOpcode::EXTRAARG_Ax, extra);
+    }
+
+    /**
+     * luaK_setlist: stores 'to_store' values after the table in its next positions;
+     * 'nelems' are the ones already stored
+     */
+    fn set_list(&mut self, base: u32, nelems: u32, to_store: i32) {
+        let to_store = if to_store == MULTRET { 0 } else { to_store as u32 };
+        if nelems <= MAXARG_C {
+            self.code_abc(LuaOpcode::SETLIST_ABCk, base, to_store, nelems);
+        } else {
+            let extra = nelems / (MAXARG_C + 1);
+            self.code_abck(LuaOpcode::SETLIST_ABCk, base, to_store, nelems % (MAXARG_C + 1), true);
+            self.code_extra_arg(extra);
+        }
+        self.fs_mut().freereg = base + 1;
+    }
+
+    /*
+     * Variables and scopes (lparser.c)
+     */
+
+    fn var_desc(&self, vidx: usize) -> &VarDesc {
+        &self.actvar[self.fs().first_local + vidx]
+    }
+
+    /**
+     * reglevel: the register level of the first 'nvar' variables, skipping compile-time
+     * constants
+     */
+    fn reg_level(&self, nvar: usize) -> u32 {
+        let first = self.fs().first_local;
+        (0..nvar).rev()
+            .map(|i| &self.actvar[first + i])
+            .find(|var| var.kind != VarKind::CompileTimeConst)
+            .map_or(0, |var| var.ridx + 1)
+    }
+
+    /**
+     * luaY_nvarstack: registers taken by the active variables
+     */
+    fn nvarstack(&self) -> u32 {
+        self.reg_level(self.fs().nactvar)
+    }
+
+    /**
+     * new_localvar: a variable that isn't active until 'adjust_local_vars'
+     */
+    fn new_local_var(&mut self, name: Name) -> LexResult<usize> {
+        let first = self.fs().first_local;
+        self.check_limit(self.nactvars + 1 - first, MAXVARS, "local variables")?;
+        let var = VarDesc { name, kind: VarKind::Regular, ridx: 0, pidx: 0, value: TValue::NIL };
+        if self.nactvars < self.actvar.len() {
+            self.actvar[self.nactvars] = var;
+        } else {
+            self.actvar.push(var);
+        }
+        self.nactvars += 1;
+        Ok(self.nactvars - 1 - first)
+    }
+
+    fn new_local_var_literal(&mut self, name: &str) -> LexResult<usize> {
+        self.new_local_var(name_of(name))
+    }
+
+    /**
+     * adjustlocalvars: the last 'nvars' variables created enter their scope
+     */
+    fn adjust_local_vars(&mut self, nvars: usize) {
+        let reg_level = self.nvarstack();
+        for reg in reg_level..reg_level + nvars as u32 {
+            let vidx = self.fs().nactvar;
+            self.fs_mut().nactvar += 1;
+            let index = self.fs().first_local + vidx;
+            let name = self.actvar[index].name.clone();
+            let pc = self.pc() as usize;
+            let fs = self.funcs.last_mut().expect("no function being compiled");
+            fs.loc_vars.push(LocVar { name: Some(name), start_pc: pc, end_pc: 0 });
+            let var = &mut self.actvar[index];
+            var.ridx = reg;
+            var.pidx = fs.loc_vars.len() - 1;
+        }
+    }
+
+    /**
+     * removevars: variables leave their scope, down to 'to_level' active ones
+     */
+    fn remove_vars(&mut self, to_level: usize) {
+        let removed = self.fs().nactvar - to_level;
+        self.nactvars -= removed;
+        while self.fs().nactvar > to_level {
+            self.fs_mut().nactvar -= 1;
+            let vidx = self.fs().nactvar;
+            let var = self.var_desc(vidx);
+            if var.kind != VarKind::CompileTimeConst {
+                let pidx = var.pidx;
+                let pc = self.pc() as usize;
+                self.fs_mut().loc_vars[pidx].end_pc = pc;
+            }
+        }
+    }
+
+    /**
+     * searchvar: an active variable of the function at 'level' by name
+     */
+    fn search_var(&self, level: usize, name: &Name) -> Option<ExpDesc> {
+        let fs = &self.funcs[level];
+        (0..fs.nactvar).rev().find_map(|i| {
+            let var = &self.actvar[fs.first_local + i];
+            if !same_name(&var.name, name) {
+                None
+            } else if var.kind == VarKind::CompileTimeConst {
+                Some(ExpDesc::new(ExpKind::Const(fs.first_local + i)))
+            } else {
+                Some(ExpDesc::new(ExpKind::Local { ridx: var.ridx, vidx: i }))
+            }
+        })
+    }
+
+    /**
+     * markupval: the block where the variable was declared must close it
+     */
+    fn mark_upval(&mut self, level: usize, vidx: usize) {
+        let fs = &mut self.funcs[level];
+        let block = fs.blocks.iter_mut().rev().find(|block| block.nactvar <= vidx).expect("variable outside every block");
+        block.upval = true;
+        fs.needclose = true;
+    }
+
+    /**
+     * marktobeclosed: the current block has a to-be-closed variable
+     */
+    fn mark_to_be_closed(&mut self) {
+        let fs = self.fs_mut();
+        let block = fs.block();
+        block.upval = true;
+        block.inside_tbc = true;
+        fs.needclose = true;
+    }
+
+    /**
+     * newupvalue: an upvalue of the function at 'level' for the variable 'v' of the enclosing one
+     */
+    fn new_upvalue(&mut self, level: usize, name: &Name, v: &ExpDesc) -> LexResult<u32> {
+        let nups = self.funcs[level].upvalues.len();
+        if nups + 1 > MAXUPVAL {
+            let line = self.funcs[level].line_defined;
+            let place = if line == 0 { "main function".to_string() } else { format!("function at line {}", line) };
+            return Err(self.syntax_error(&format!("too many upvalues (limit is {}) in {}", MAXUPVAL, place)));
+        }
+        let prev = &self.funcs[level - 1];
+        let upvalue = match v.k {
+            ExpKind::Local { ridx, vidx } => UpvalueDescription {
+                instack: true,
+                idx: ridx as u8,
+                kind: self.actvar[prev.first_local + vidx].kind as u8,
+                name: Some(name.clone()),
+            },
+            ExpKind::Upval(idx) => UpvalueDescription {
+                instack: false,
+                idx: idx as u8,
+                kind: prev.upvalues[idx as usize].kind,
+                name: Some(name.clone()),
+            },
+            _ => unreachable!("upvalue of a non variable"),
+        };
+        self.funcs[level].upvalues.push(upvalue);
+        Ok(nups as u32)
+    }
+
+    /**
+     * singlevaraux: a local, an upvalue or a constant of the function at 'level' or of an
+     * enclosing one; Void when it is global. 'base' tells the variable is used in that function
+     */
+    fn single_var_aux(&mut self, level: Option<usize>, name: &Name, base: bool) -> LexResult<ExpDesc> {
+        let Some(level) = level else {
+            return Ok(ExpDesc::new(ExpKind::Void));
+        };
+        if let Some(var) = self.search_var(level, name) {
+            if let (ExpKind::Local { vidx, .. }, false) = (&var.k, base) {
+                self.mark_upval(level, *vidx);
+            }
+            return Ok(var);
+        }
+        let idx = match self.funcs[level].upvalues.iter().position(|up| up.name.as_ref().is_some_and(|n| same_name(n, name))) {
+            Some(idx) => idx as u32,
+            None => {
+                let var = self.single_var_aux(level.checked_sub(1), name, false)?;
+                match var.k {
+                    ExpKind::Local { .. } | ExpKind::Upval(_) => self.new_upvalue(level, name, &var)?,
+                    _ => return Ok(var),
+                }
+            },
+        };
+        Ok(ExpDesc::new(ExpKind::Upval(idx)))
+    }
+
+    /**
+     * singlevar: globals are fields of _ENV. The name was just consumed
+     */
+    fn single_var(&mut self, name: &Name) -> LexResult<ExpDesc> {
+        let level = Some(self.funcs.len() - 1);
+        let mut var = self.single_var_aux(level, name, true)?;
+        if let ExpKind::Void = var.k {
+            var = self.single_var_aux(level, &name_of("_ENV"), true)?;
+            debug_assert!(!matches!(var.k, ExpKind::Void));
+            self.exp_to_any_reg_up(&mut var)?;
+            let mut key = ExpDesc::new(ExpKind::KStr(name.clone()));
+            self.indexed(&mut var, &mut key)?;
+        }
+        Ok(var)
+    }
+
+    /**
+     * adjust_assign: 'nvars' values out of 'nexps' expressions
+     */
+    fn adjust_assign(&mut self, nvars: usize, nexps: usize, e: &mut ExpDesc) -> LexResult<()> {
+        let needed = nvars as i32 - nexps as i32;
+        if e.has_multret() {
+            self.set_returns(e, (needed + 1).max(0))?;
+        } else {
+            if !matches!(e.k, ExpKind::Void) {
+                self.exp_to_next_reg(e)?;
+            }
+            if needed > 0 {
+                let freereg = self.fs().freereg;
+                self.nil(freereg, needed as u32);
+            }
+        }
+        if needed > 0 {
+            self.reserve_regs(needed as u32)?;
+        } else {
+            let fs = self.fs_mut();
+            fs.freereg = (fs.freereg as i32 + needed) as u32;
+        }
+        Ok(())
+    }
+
+    fn enter_block(&mut self, is_loop: bool) {
+        let fs = self.funcs.last_mut().expect("no function being compiled");
+        let inside_tbc = fs.blocks.last().is_some_and(|block| block.inside_tbc);
+        fs.blocks.push(BlockCnt {
+            first_label: self.labels.len(),
+            first_goto: self.gotos.len(),
+            nactvar: fs.nactvar,
+            upval: false,
+            is_loop,
+            inside_tbc,
+        });
+    }
+
+    /**
+     * leaveblock: ends the scope of the block's variables and labels; its pending gotos move
+     * to the enclosing block
+     */
+    fn leave_block(&mut self) -> LexResult<BlockCnt> {
+        let block = self.fs().blocks.last().expect("no block to leave").clone();
+        let stack_level = self.reg_level(block.nactvar);
+        self.remove_vars(block.nactvar);
+        let has_close = block.is_loop && self.create_label(name_of("break"), 0, false)?;
+        let nested = self.fs().blocks.len() > 1;
+        if !has_close && nested && block.upval {
+            self.code_abc(LuaOpcode::CLOSE_A, stack_level, 0, 0);
+        }
+        self.fs_mut().freereg = stack_level;
+        self.labels.truncate(block.first_label);
+        self.fs_mut().blocks.pop();
+        if nested {
+            self.move_gotos_out(&block);
+        } else if block.first_goto < self.gotos.len() {
+            return Err(self.undef_goto(&self.gotos[block.first_goto]));
+        }
+        Ok(block)
+    }
+
+    /**
+     * movegotosout: pending gotos of a block belong now to the enclosing one
+     */
+    fn move_gotos_out(&mut self, block: &BlockCnt) {
+        let block_level = self.reg_level(block.nactvar);
+        for i in block.first_goto..self.gotos.len() {
+            if self.reg_level(self.gotos[i].nactvar) > block_level {
+                self.gotos[i].close |= block.upval;
+            }
+            self.gotos[i].nactvar = block.nactvar;
+        }
+    }
+
+    fn undef_goto(&self, goto: &LabelDesc) -> String {
+        if goto.name.as_bytes() == b"break" {
+            self.semantic_error(&format!("break outside loop at line {}", goto.line))
+        } else {
+            self.semantic_error(&format!("no visible label '{}' for <goto> at line {}", goto.name, goto.line))
+        }
+    }
+
+    /**
+     * findlabel: an active label of the current function
+     */
+    fn find_label(&self, name: &Name) -> Option<usize> {
+        (self.fs().first_label..self.labels.len()).find(|i| same_name(&self.labels[*i].name, name))
+    }
+
+    fn new_label_entry(&self, name: Name, line: u32, pc: i32) -> LabelDesc {
+        LabelDesc { name, pc, line, nactvar: self.fs().nactvar, close: false }
+    }
+
+    fn new_goto_entry(&mut self, name: Name, line: u32, pc: i32) {
+        let goto = self.new_label_entry(name, line, pc);
+        self.gotos.push(goto);
+    }
+
+    /**
+     * solvegoto: the pending goto 'g' jumps to 'label'
+     */
+    fn solve_goto(&mut self, g: usize, label: &LabelDesc) -> LexResult<()> {
+        let goto = &self.gotos[g];
+        if goto.nactvar < label.nactvar {
+            let var = &self.var_desc(goto.nactvar).name;
+            return Err(self.semantic_error(&format!(
+                "<goto {}> at line {} jumps into the scope of local '{}'", goto.name, goto.line, var)));
+        }
+        let pc = goto.pc;
+        self.fs_mut().patch_list(pc, label.pc);
+        self.gotos.remove(g);
+        Ok(())
+    }
+
+    /**
+     * solvegotos: the pending gotos of the current block to a new label; true when some of
+     * them need to close upvalues
+     */
+    fn solve_gotos(&mut self, label: &LabelDesc) -> LexResult<bool> {
+        let mut i = self.fs().blocks.last().expect("no block").first_goto;
+        let mut needs_close = false;
+        while i < self.gotos.len() {
+            if same_name(&self.gotos[i].name, &label.name) {
+                needs_close |= self.gotos[i].close;
+                self.solve_goto(i, label)?;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(needs_close)
+    }
+
+    /**
+     * createlabel: a label at the current position. When it is the last statement of its
+     * block, locals are already out of scope for it. True when a CLOSE was added
+     */
+    fn create_label(&mut self, name: Name, line: u32, last: bool) -> LexResult<bool> {
+        let pc = self.get_label();
+        let mut label = self.new_label_entry(name, line, pc);
+        if last {
+            label.nactvar = self.fs().blocks.last().expect("no block").nactvar;
+        }
+        self.labels.push(label.clone());
+        if self.solve_gotos(&label)? {
+            let level = self.nvarstack();
+            self.code_abc(LuaOpcode::CLOSE_A, level, 0, 0);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /*
+     * Functions
+     */
+
+    fn open_func(&mut self, line_defined: u32) {
+        let fs = FuncState::new(line_defined, self.nactvars, self.labels.len());
+        self.funcs.push(fs);
+        self.enter_block(false);
+    }
+
+    /**
+     * close_func: the final return, then the prototype
+     */
+    fn close_func(&mut self) -> LexResult<Proto> {
+        let level = self.nvarstack();
+        self.ret(level, 0);
+        self.leave_block()?;
+        let mut fs = self.funcs.pop().expect("no function to close");
+        fs.finish();
+        Ok(fs.into_proto(&self.source))
+    }
+
+    /**
+     * setvararg
+     */
+    fn set_vararg(&mut self, num_params: u8) {
+        self.fs_mut().is_vararg = true;
+        self.code_abc(LuaOpcode::VARARGPREP_A, num_params as u32, 0, 0);
+    }
+
+    /**
+     * mainfunc: a vararg function with _ENV as its only upvalue
+     */
+    fn main_func(mut self, chunk: &Chunk) -> LexResult<Proto> {
+        self.open_func(0);
+        self.set_vararg(0);
+        self.fs_mut().upvalues.push(UpvalueDescription { instack: true, idx: 0, kind: VarKind::Regular as u8, name: Some(name_of("_ENV")) });
+        self.statlist(&chunk.block)?;
+        debug_assert_eq!(self.token(), &Token::Eos);
+        self.close_func()
+    }
+
+    /**
+     * body: compiles a function with the current token at '(', leaving its closure in the
+     * next register of the enclosing one
+     */
+    fn body(&mut self, body: &FunctionBody, line: u32) -> LexResult<ExpDesc> {
+        self.open_func(line);
+        self.advance();
+        if body.is_method {
+            self.new_local_var_literal("self")?;
+            self.adjust_local_vars(1);
+        }
+        for param in &body.params {
+            self.seek(param.span.end);
+            self.new_local_var(param.name.clone())?;
+        }
+        let nparams = body.params.len();
+        if body.is_vararg {
+            if self.token() == &Token::Char(b',') {
+                self.advance();
+            }
+            self.advance();
+        }
+        self.adjust_local_vars(nparams);
+        let num_params = self.fs().nactvar as u8;
+        self.fs_mut().num_params = num_params;
+        if body.is_vararg {
+            self.set_vararg(num_params);
+        }
+        let nactvar = self.fs().nactvar as u32;
+        self.reserve_regs(nactvar)?;
+        self.advance();
+        self.statlist(&body.block)?;
+        let last_line = self.line();
+        self.fs_mut().last_line_defined = last_line;
+        self.advance();
+        /* codeclosure comes before the final return of the function */
+        let child = self.funcs.pop().expect("no function being compiled");
+        let mut closure = ExpDesc::new(ExpKind::Reloc(self.code_abx(LuaOpcode::CLOSURE_ABx, 0, self.fs().protos.len() as u32)));
+        let closure_result = self.exp_to_next_reg(&mut closure);
+        self.funcs.push(child);
+        closure_result?;
+        let proto = self.close_func()?;
+        let fs = self.fs_mut();
+        if fs.protos.len() >= MAXARG_BX as usize {
+            return Err(format!("too many functions (limit is {})", MAXARG_BX));
+        }
+        fs.protos.push(Rc::new(proto));
+        Ok(closure)
+    }
+
+    /*
+     * Expressions
+     */
+
+    /**
+     * explist: all values but the last one go to consecutive registers
+     */
+    fn explist(&mut self, exprs: &[Expr]) -> LexResult<(ExpDesc, usize)> {
+        let mut e = self.expr(&exprs[0])?;
+        for expr in &exprs[1..] {
+            self.seek(expr.span.start);
+            self.exp_to_next_reg(&mut e)?;
+            e = self.expr(expr)?;
+        }
+        Ok((e, exprs.len()))
+    }
+
+    /**
+     * exp1: an expression in the next register
+     */
+    fn exp1(&mut self, expr: &Expr) -> LexResult<()> {
+        let mut e = self.expr(expr)?;
+        self.exp_to_next_reg(&mut e)
+    }
+
+    /**
+     * cond: the jumps taken when the condition is false
+     */
+    fn cond(&mut self, expr: &Expr) -> LexResult<i32> {
+        let mut v = self.expr(expr)?;
+        if let ExpKind::Nil = v.k {
+            v.k = ExpKind::False;
+        }
+        self.go_if_true(&mut v)?;
+        Ok(v.f)
+    }
+
+    /**
+     * Compiles an expression, leaving the current token right after it
+     */
+    fn expr(&mut self, expr: &Expr) -> LexResult<ExpDesc> {
+        let k = match &expr.kind {
+            ExprKind::Nil => ExpKind::Nil,
+            ExprKind::True => ExpKind::True,
+            ExprKind::False => ExpKind::False,
+            ExprKind::Int(i) => ExpKind::KInt(*i),
+            ExprKind::Float(f) => ExpKind::KFlt(*f),
+            ExprKind::String(s) => ExpKind::KStr(s.clone()),
+            ExprKind::Vararg => {
+                self.seek(expr.span.start);
+                let pc = self.code_abc(LuaOpcode::VARARG_AC, 0, 0, 1);
+                self.advance();
+                return Ok(ExpDesc::new(ExpKind::Vararg(pc)));
+            },
+            ExprKind::Function(body) => {
+                self.seek(expr.span.start);
+                self.advance();
+                let line = self.line();
+                return self.body(body, line);
+            },
+            ExprKind::Table(fields) => return self.constructor(expr, fields),
+            ExprKind::Name(name) => {
+                self.seek(expr.span.start);
+                let lookahead = self.lookahead_name.take() == Some(expr.span.start);
+                self.advance();
+                if lookahead {
+                    self.lookahead_line = Some(self.line());
+                }
+                return self.single_var(name);
+            },
+            ExprKind::Paren(inner) => {
+                self.seek(expr.span.start);
+                self.advance();
+                let mut v = self.expr(inner)?;
+                self.seek(expr.span.end);
+                self.discharge_vars(&mut v);
+                return Ok(v);
+            },
+            ExprKind::Index { object, key } => {
+                let mut v = self.expr(object)?;
+                self.exp_to_any_reg_up(&mut v)?;
+                if self.token() == &Token::Char(b'.') {
+                    self.advance();
+                    self.advance();
+                    let mut k = self.expr_constant(key);
+                    self.indexed(&mut v, &mut k)?;
+                } else {
+                    self.advance();
+                    let mut k = self.expr(key)?;
+                    self.exp_to_val(&mut k)?;
+                    self.seek(expr.span.end);
+                    self.indexed(&mut v, &mut k)?;
+                }
+                return Ok(v);
+            },
+            ExprKind::Call { function, args } => {
+                let mut v = self.expr(function)?;
+                self.exp_to_next_reg(&mut v)?;
+                return self.func_args(v, args, expr);
+            },
+            ExprKind::MethodCall { object, method, args } => {
+                let mut v = self.expr(object)?;
+                self.advance();
+                self.advance();
+                let mut key = ExpDesc::new(ExpKind::KStr(method.clone()));
+                self.self_(&mut v, &mut key)?;
+                return self.func_args(v, args, expr);
+            },
+            ExprKind::Binary { op, left, right, op_span } => {
+                let mut v1 = self.expr(left)?;
+                self.seek(right.span.start);
+                self.infix(*op, &mut v1)?;
+                let mut v2 = self.expr(right)?;
+                self.posfix(*op, &mut v1, &mut v2, op_span.end.line)?;
+                return Ok(v1);
+            },
+            ExprKind::Unary { op, operand, op_span } => {
+                let mut e = self.expr(operand)?;
+                self.prefix(*op, &mut e, op_span.end.line)?;
+                return Ok(e);
+            },
+        };
+        self.seek(expr.span.start);
+        self.advance();
+        Ok(ExpDesc::new(k))
+    }
+
+    /**
+     * The string key of a field selection
+     */
+    fn expr_constant(&self, key: &Expr) -> ExpDesc {
+        match &key.kind {
+            ExprKind::String(s) => ExpDesc::new(ExpKind::KStr(s.clone())),
+            _ => unreachable!("field name is not a string"),
+        }
+    }
+
+    /**
+     * funcargs: the current token starts the arguments of a call to 'f', which is in the
+     * next register
+     */
+    fn func_args(&mut self, mut f: ExpDesc, args: &[Expr], call: &Expr) -> LexResult<ExpDesc> {
+        let line = self.line();
+        let mut args_e = match self.token() {
+            Token::Char(b'(') => {
+                self.advance();
+                let mut e = ExpDesc::new(ExpKind::Void);
+                if !args.is_empty() {
+                    e = self.explist(args)?.0;
+                    if e.has_multret() {
+                        self.set_multret(&mut e)?;
+                    }
+                }
+                self.seek(call.span.end);
+                e
+            },
+            Token::Char(b'{') => self.expr(&args[0])?,
+            _ => {
+                self.advance();
+                self.expr_constant(&args[0])
+            },
+        };
+        let base = f.reg();
+        let nparams = if args_e.has_multret() {
+            MULTRET
+        } else {
+            if !matches!(args_e.k, ExpKind::Void) {
+                self.exp_to_next_reg(&mut args_e)?;
+            }
+            (self.fs().freereg - (base + 1)) as i32
+        };
+        f.k = ExpKind::Call(self.code_abc(LuaOpcode::CALL_ABC, base, (nparams + 1) as u32, 2));
+        self.fix_line(line);
+        self.fs_mut().freereg = base + 1;
+        Ok(f)
+    }
+
+    /**
+     * Where a constructor field starts, for the separator before it to be consumed
+     */
+    fn seek_field(&mut self, field: &Field) {
+        match field {
+            Field::Positional(value) => self.seek(value.span.start),
+            Field::Named { key_span, .. } => self.seek(key_span.start),
+            Field::Keyed { key, .. } => {
+                self.seek(key.span.start);
+                self.cursor -= 1;
+            },
+        }
+    }
+
+    /**
+     * constructor: NEWTABLE gets its sizes once all the fields are known
+     */
+    fn constructor(&mut self, expr: &Expr, fields: &[Field]) -> LexResult<ExpDesc> {
+        self.seek(expr.span.start);
+        let pc = self.code_abc(LuaOpcode::NEWTABLE_ABCk, 0, 0, 0);
+        self.code_extra_arg(0);
+        let t = ExpDesc::new(ExpKind::NonReloc(self.fs().freereg));
+        self.reserve_regs(1)?;
+        let mut cc = ConsControl { v: ExpDesc::new(ExpKind::Void), nh: 0, na: 0, to_store: 0 };
+        self.advance();
+        for field in fields {
+            self.seek_field(field);
+            self.close_list_field(&mut cc, &t)?;
+            match field {
+                Field::Positional(value) => {
+                    if matches!(self.token(), Token::Name(_)) {
+                        self.lookahead_name = Some(value.span.start);
+                    }
+                    cc.v = self.expr(value)?;
+                    cc.to_store += 1;
+                },
+                Field::Named { key, value, .. } => {
+                    let key = ExpDesc::new(ExpKind::KStr(key.clone()));
+                    self.advance();
+                    self.rec_field(&mut cc, &t, key, value)?;
+                },
+                Field::Keyed { key, value } => {
+                    self.advance();
+                    let mut k = self.expr(key)?;
+                    self.exp_to_val(&mut k)?;
+                    self.advance();
+                    self.rec_field(&mut cc, &t, k, value)?;
+                },
+            }
+        }
+        self.seek(expr.span.end);
+        self.last_list_field(&mut cc, &t)?;
+        self.set_table_size(pc, t.reg(), cc.na, cc.nh);
+        Ok(t)
+    }
+
+    /**
+     * recfield: the key was read; the current token is '='
+     */
+    fn rec_field(&mut self, cc: &mut ConsControl, t: &ExpDesc, mut key: ExpDesc, value: &Expr) -> LexResult<()> {
+        let reg = self.fs().freereg;
+        cc.nh += 1;
+        self.advance();
+        let mut tab = t.clone();
+        self.indexed(&mut tab, &mut key)?;
+        let mut val = self.expr(value)?;
+        self.store_var(&tab, &mut val)?;
+        self.fs_mut().freereg = reg;
+        Ok(())
+    }
+
+    /**
+     * closelistfield: the previous list item goes to a register, flushed every
+     * LFIELDS_PER_FLUSH items
+     */
+    fn close_list_field(&mut self, cc: &mut ConsControl, t: &ExpDesc) -> LexResult<()> {
+        if let ExpKind::Void = cc.v.k {
+            return Ok(());
+        }
+        self.exp_to_next_reg(&mut cc.v)?;
+        cc.v = ExpDesc::new(ExpKind::Void);
+        if cc.to_store == LFIELDS_PER_FLUSH {
+            self.set_list(t.reg(), cc.na, cc.to_store as i32);
+            cc.na += cc.to_store;
+            cc.to_store = 0;
+        }
+        Ok(())
+    }
+
+    /**
+     * lastlistfield: a last item with multiple results stores them all
+     */
+    fn last_list_field(&mut self, cc: &mut ConsControl, t: &ExpDesc) -> LexResult<()> {
+        if cc.to_store == 0 {
+            return Ok(());
+        }
+        if cc.v.has_multret() {
+            self.set_multret(&mut cc.v)?;
+            self.set_list(t.reg(), cc.na, MULTRET);
+            cc.to_store -= 1;
+        } else {
+            if !matches!(cc.v.k, ExpKind::Void) {
+                self.exp_to_next_reg(&mut cc.v)?;
+            }
+            self.set_list(t.reg(), cc.na, cc.to_store as i32);
+        }
+        cc.na += cc.to_store;
+        Ok(())
+    }
+
+    /*
+     * Statements
+     */
+
+    /**
+     * statlist: leaves the current token at what closes the block
+     */
+    fn statlist(&mut self, block: &[Stat]) -> LexResult<()> {
+        let mut i = 0;
+        while i < block.len() {
+            i = self.statement(block, i)?;
+        }
+        if let Some(last) = block.last() {
+            self.seek(last.span.end);
+        }
+        self.skip_semicolons();
+        Ok(())
+    }
+
+    /**
+     * block: a statement list in its own scope
+     */
+    fn block(&mut self, block: &Block) -> LexResult<()> {
+        self.enter_block(false);
+        self.statlist(block)?;
+        self.leave_block()?;
+        Ok(())
+    }
+
+    /**
+     * Compiles the statement at 'i', returning the index of the next one to compile
+     */
+    fn statement(&mut self, block: &[Stat], i: usize) -> LexResult<usize> {
+        let stat = &block[i];
+        self.seek(stat.span.start);
+        let line = self.line();
+        let mut next = i + 1;
+        match &stat.kind {
+            StatKind::Call(call) => {
+                let v = self.expr(call)?;
+                set_c(self.instruction(v.pc()), 1);
+            },
+            StatKind::Assign { targets, values } => self.assignment(targets, values)?,
+            StatKind::Local { names, values } => self.local_stat(stat, names, values)?,
+            StatKind::LocalFunction { name, body } => {
+                self.seek(name.span.end);
+                let fvar = self.fs().nactvar;
+                self.new_local_var(name.name.clone())?;
+                self.adjust_local_vars(1);
+                let line = self.line();
+                self.body(body, line)?;
+                let pidx = self.var_desc(fvar).pidx;
+                let pc = self.pc() as usize;
+                self.fs_mut().loc_vars[pidx].start_pc = pc;
+            },
+            StatKind::Function { path, method, body } => {
+                self.advance();
+                self.advance();
+                let mut v = self.single_var(&path[0])?;
+                for name in path[1..].iter().chain(method) {
+                    self.field_sel(&mut v, name)?;
+                }
+                let mut b = self.body(body, line)?;
+                self.check_readonly(&v)?;
+                self.store_var(&v, &mut b)?;
+                self.fix_line(line);
+            },
+            StatKind::If { branches, else_block } => {
+                let mut escape = NO_JUMP;
+                for branch in branches {
+                    self.seek(branch.span.start);
+                    self.test_then_block(&branch.condition, &branch.block, &mut escape)?;
+                }
+                if let Some(block) = else_block {
+                    self.advance();
+                    self.block(block)?;
+                }
+                self.seek(stat.span.end);
+                self.patch_to_here(escape);
+            },
+            StatKind::While { condition, block } => {
+                self.advance();
+                let while_init = self.get_label();
+                let cond_exit = self.cond(condition)?;
+                self.enter_block(true);
+                self.advance();
+                self.block(block)?;
+                self.jump_to(while_init);
+                self.advance();
+                self.leave_block()?;
+                self.patch_to_here(cond_exit);
+            },
+            StatKind::Repeat { block, condition } => {
+                let repeat_init = self.get_label();
+                self.enter_block(true);
+                self.enter_block(false);
+                self.advance();
+                self.statlist(block)?;
+                self.advance();
+                let mut cond_exit = self.cond(condition)?;
+                let scope = self.leave_block()?;
+                if scope.upval {
+                    let exit = self.jump();
+                    self.patch_to_here(cond_exit);
+                    let level = self.reg_level(scope.nactvar);
+                    self.code_abc(LuaOpcode::CLOSE_A, level, 0, 0);
+                    cond_exit = self.jump();
+                    self.patch_to_here(exit);
+                }
+                self.fs_mut().patch_list(cond_exit, repeat_init);
+                self.leave_block()?;
+            },
+            StatKind::NumericFor { var, start, limit, step, block } => {
+                self.enter_block(true);
+                self.seek(var.span.end);
+                let base = self.fs().freereg;
+                for _ in 0..3 {
+                    self.new_local_var_literal("(for state)")?;
+                }
+                self.new_local_var(var.name.clone())?;
+                self.exp1(start)?;
+                self.exp1(limit)?;
+                match step {
+                    Some(step) => self.exp1(step)?,
+                    None => {
+                        let freereg = self.fs().freereg;
+                        self.int(freereg, 1);
+                        self.reserve_regs(1)?;
+                    },
+                }
+                self.adjust_local_vars(3);
+                self.for_body(base, line, 1, false, block)?;
+                self.advance();
+                self.leave_block()?;
+            },
+            StatKind::GenericFor { names, values, block } => {
+                self.enter_block(true);
+                self.seek(names[0].span.end);
+                let base = self.fs().freereg;
+                for _ in 0..4 {
+                    self.new_local_var_literal("(for state)")?;
+                }
+                for name in names {
+                    self.seek(name.span.end);
+                    self.new_local_var(name.name.clone())?;
+                }
+                self.advance();
+                let line = self.line();
+                let (mut e, nexps) = self.explist(values)?;
+                self.adjust_assign(4, nexps, &mut e)?;
+                self.adjust_local_vars(4);
+                self.mark_to_be_closed();
+                self.check_stack(3)?;
+                self.for_body(base, line, names.len(), true, block)?;
+                self.advance();
+                self.leave_block()?;
+            },
+            StatKind::Do(block) => {
+                self.advance();
+                self.block(block)?;
+                self.advance();
+            },
+            StatKind::Return(values) => self.ret_stat(values)?,
+            StatKind::Break => {
+                self.advance();
+                let jump = self.jump();
+                self.new_goto_entry(name_of("break"), line, jump);
+            },
+            StatKind::Goto(name) => {
+                self.advance();
+                let line = self.line();
+                self.advance();
+                match self.find_label(name) {
+                    None => {
+                        let jump = self.jump();
+                        self.new_goto_entry(name.clone(), line, jump);
+                    },
+                    Some(label) => {
+                        /* backward jump: the label is visible, so it needs no scope checks */
+                        let label_level = self.reg_level(self.labels[label].nactvar);
+                        if self.nvarstack() > label_level {
+                            self.code_abc(LuaOpcode::CLOSE_A, label_level, 0, 0);
+                        }
+                        let jump = self.jump();
+                        let target = self.labels[label].pc;
+                        self.fs_mut().patch_list(jump, target);
+                    },
+                }
+            },
+            StatKind::Label(name) => next = self.label_stat(block, i, name, line)?,
+        }
+        let level = self.nvarstack();
+        self.fs_mut().freereg = level;
+        Ok(next)
+    }
+
+    /**
+     * fieldsel: '.' or ':' and a name after 'v'
+     */
+    fn field_sel(&mut self, v: &mut ExpDesc, name: &Name) -> LexResult<()> {
+        self.exp_to_any_reg_up(v)?;
+        self.advance();
+        self.advance();
+        let mut key = ExpDesc::new(ExpKind::KStr(name.clone()));
+        self.indexed(v, &mut key)
+    }
+
+    /**
+     * check_readonly: <const> variables and upvalues can't be assigned
+     */
+    fn check_readonly(&self, e: &ExpDesc) -> LexResult<()> {
+        let name = match e.k {
+            ExpKind::Const(idx) => Some(&self.actvar[idx].name),
+            ExpKind::Local { vidx, .. } => {
+                let var = self.var_desc(vidx);
+                if var.kind != VarKind::Regular { Some(&var.name) } else { None }
+            },
+            ExpKind::Upval(idx) => {
+                let up = &self.fs().upvalues[idx as usize];
+                if up.kind != VarKind::Regular as u8 { up.name.as_ref() } else { None }
+            },
+            _ => None,
+        };
+        match name {
+            Some(name) => Err(self.semantic_error(&format!("attempt to assign to const variable '{}'", name))),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * check_conflict: a table or key of a previous target that is the local or upvalue 'v'
+     * being assigned too is copied first, so the assignments use the value it had before
+     */
+    fn check_conflict(&mut self, targets: &mut [ExpDesc], v: &ExpDesc) -> LexResult<()> {
+        let extra = self.fs().freereg;
+        let mut conflict = false;
+        for target in targets.iter_mut() {
+            match (&mut target.k, &v.k) {
+                (ExpKind::IndexUp { t, idx }, ExpKind::Upval(up)) if *t == *up => {
+                    conflict = true;
+                    target.k = ExpKind::IndexStr { t: extra, idx: *idx };
+                },
+                (ExpKind::Indexed { t, idx }, ExpKind::Local { ridx, .. }) => {
+                    if *t == *ridx {
+                        conflict = true;
+                        *t = extra;
+                    }
+                    if *idx == *ridx {
+                        conflict = true;
+                        *idx = extra;
+                    }
+                },
+                (ExpKind::IndexI { t, .. } | ExpKind::IndexStr { t, .. }, ExpKind::Local { ridx, .. }) if *t == *ridx => {
+                    conflict = true;
+                    *t = extra;
+                },
+                _ => {},
+            }
+        }
+        if conflict {
+            match v.k {
+                ExpKind::Local { ridx, .. } => self.code_abc(LuaOpcode::MOVE_AB, extra, ridx, 0),
+                _ => self.code_abc(LuaOpcode::GETUPVAL_AB, extra, v.reg_or_upval(), 0),
+            };
+            self.reserve_regs(1)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * restassign: stores go from the last target to the first
+     */
+    fn assignment(&mut self, targets: &[Expr], values: &[Expr]) -> LexResult<()> {
+        let mut vars: Vec<ExpDesc> = Vec::with_capacity(targets.len());
+        for target in targets {
+            let v = self.expr(target)?;
+            if !vars.is_empty() && !v.is_indexed() {
+                self.check_conflict(&mut vars, &v)?;
+            }
+            self.check_readonly(&v)?;
+            vars.push(v);
+        }
+        let (mut e, nexps) = self.explist(values)?;
+        let mut rest = vars.len();
+        if nexps == vars.len() {
+            self.set_one_ret(&mut e);
+            self.store_var(&vars[rest - 1], &mut e)?;
+            rest -= 1;
+        } else {
+            self.adjust_assign(vars.len(), nexps, &mut e)?;
+        }
+        for var in vars[..rest].iter().rev() {
+            let mut e = ExpDesc::new(ExpKind::NonReloc(self.fs().freereg - 1));
+            self.store_var(var, &mut e)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * localstat: a last <const> variable with a constant value takes no register
+     */
+    fn local_stat(&mut self, stat: &Stat, names: &[LocalName], values: &[Expr]) -> LexResult<()> {
+        let mut to_close = None;
+        let mut vidx = 0;
+        for (nvars, name) in names.iter().enumerate() {
+            self.seek(name.span.end);
+            vidx = self.new_local_var(name.name.clone())?;
+            let kind = match name.attrib {
+                Some(Attrib::Const) => VarKind::Const,
+                Some(Attrib::Close) => VarKind::ToClose,
+                None => VarKind::Regular,
+            };
+            let index = self.fs().first_local + vidx;
+            self.actvar[index].kind = kind;
+            if kind == VarKind::ToClose {
+                to_close = Some(self.fs().nactvar + nvars);
+            }
+        }
+        let nvars = names.len();
+        let (mut e, nexps) = if values.is_empty() {
+            self.seek(stat.span.end);
+            (ExpDesc::new(ExpKind::Void), 0)
+        } else {
+            self.explist(values)?
+        };
+        let index = self.fs().first_local + vidx;
+        let constant = if nvars == nexps && self.actvar[index].kind == VarKind::Const { self.exp_to_const(&e) } else { None };
+        match constant {
+            Some(value) => {
+                let var = &mut self.actvar[index];
+                var.kind = VarKind::CompileTimeConst;
+                var.value = value;
+                self.adjust_local_vars(nvars - 1);
+                self.fs_mut().nactvar += 1;
+            },
+            None => {
+                self.adjust_assign(nvars, nexps, &mut e)?;
+                self.adjust_local_vars(nvars);
+            },
+        }
+        if let Some(level) = to_close {
+            self.mark_to_be_closed();
+            let reg = self.reg_level(level);
+            self.code_abc(LuaOpcode::TBC_A, reg, 0, 0);
+        }
+        Ok(())
+    }
+
+    /**
+     * luaK_exp2const: the value of a constant expression
+     */
+    fn exp_to_const(&self, e: &ExpDesc) -> Option<TValue> {
+        if e.has_jumps() {
+            return None;
+        }
+        match &e.k {
+            ExpKind::False => Some(TValue::TBOOLEAN(false)),
+            ExpKind::True => Some(TValue::TBOOLEAN(true)),
+            ExpKind::Nil => Some(TValue::NIL),
+            ExpKind::KStr(s) => Some(TValue::STR(s.clone())),
+            ExpKind::Const(idx) => Some(self.actvar[*idx].value.clone()),
+            _ => e.numeral(),
+        }
+    }
+
+    /**
+     * test_then_block: a branch of an 'if'. 'if cond then break' jumps straight out of the loop
+     */
+    fn test_then_block(&mut self, condition: &Expr, block: &Block, escape: &mut i32) -> LexResult<()> {
+        self.advance();
+        let mut v = self.expr(condition)?;
+        self.advance();
+        let jf;
+        if self.token() == &Token::Break {
+            let line = self.line();
+            self.go_if_false(&mut v)?;
+            self.advance();
+            self.enter_block(false);
+            self.new_goto_entry(name_of("break"), line, v.t);
+            self.skip_semicolons();
+            if self.block_follow(false) {
+                self.leave_block()?;
+                return Ok(());
+            }
+            jf = self.jump();
+            self.statlist(&block[1..])?;
+        } else {
+            self.go_if_true(&mut v)?;
+            self.enter_block(false);
+            jf = v.f;
+            self.statlist(block)?;
+        }
+        self.leave_block()?;
+        if matches!(self.token(), Token::Else | Token::Elseif) {
+            let jump = self.jump();
+            self.concat(escape, jump);
+        }
+        self.patch_to_here(jf);
+        Ok(())
+    }
+
+    /**
+     * fixforjump: loop jumps are unsigned Bx offsets
+     */
+    fn fix_for_jump(&mut self, pc: i32, dest: i32, back: bool) -> LexResult<()> {
+        let mut offset = dest - (pc + 1);
+        if back {
+            offset = -offset;
+        }
+        if offset > MAXARG_BX as i32 {
+            return Err(self.syntax_error("control structure too long"));
+        }
+        set_bx(self.instruction(pc), offset as u32);
+        Ok(())
+    }
+
+    /**
+     * forbody: the current token is 'do'
+     */
+    fn for_body(&mut self, base: u32, line: u32, nvars: usize, is_generic: bool, block: &Block) -> LexResult<()> {
+        self.advance();
+        let prep = self.code_abx(if is_generic { LuaOpcode::TFORPREP_ABx } else { LuaOpcode::FORPREP_ABx }, base, 0);
+        self.enter_block(false);
+        self.adjust_local_vars(nvars);
+        self.reserve_regs(nvars as u32)?;
+        self.block(block)?;
+        self.leave_block()?;
+        let here = self.get_label();
+        self.fix_for_jump(prep, here, false)?;
+        if is_generic {
+            self.code_abc(LuaOpcode::TFORCALL_AC, base, 0, nvars as u32);
+            self.fix_line(line);
+        }
+        let end_for = self.code_abx(if is_generic { LuaOpcode::TFORLOOP_ABx } else { LuaOpcode::FORLOOP_ABx }, base, 0);
+        self.fix_for_jump(end_for, prep + 1, true)?;
+        self.fix_line(line);
+        Ok(())
+    }
+
+    /**
+     * retstat: a single call not in the scope of a to-be-closed variable is a tail call
+     */
+    fn ret_stat(&mut self, values: &[Expr]) -> LexResult<()> {
+        self.advance();
+        let mut first = self.nvarstack();
+        let nret = if values.is_empty() {
+            0
+        } else {
+            let (mut e, nret) = self.explist(values)?;
+            if e.has_multret() {
+                self.set_multret(&mut e)?;
+                let inside_tbc = self.fs().blocks.last().is_some_and(|block| block.inside_tbc);
+                if let (ExpKind::Call(pc), 1, false) = (&e.k, nret, inside_tbc) {
+                    set_opcode(self.instruction(*pc), LuaOpcode::TAILCALL_ABCk);
+                }
+                MULTRET
+            } else if nret == 1 {
+                first = self.exp_to_any_reg(&mut e)?;
+                1
+            } else {
+                self.exp_to_next_reg(&mut e)?;
+                nret as i32
+            }
+        };
+        self.ret(first, nret);
+        Ok(())
+    }
+
+    /**
+     * labelstat: the labels and empty statements right after a label are part of it, so a
+     * label followed only by them is the last statement of its block
+     */
+    fn label_stat(&mut self, block: &[Stat], i: usize, name: &Name, line: u32) -> LexResult<usize> {
+        self.advance();
+        self.advance();
+        self.advance();
+        let mut next = i + 1;
+        loop {
+            self.skip_semicolons();
+            if self.token() != &Token::DbColon {
+                break;
+            }
+            next = self.statement(block, next)?;
+        }
+        if let Some(label) = self.find_label(name) {
+            return Err(self.semantic_error(&format!("label '{}' already defined on line {}", name, self.labels[label].line)));
+        }
+        let last = self.block_follow(false);
+        self.create_label(name.clone(), line, last)?;
+        Ok(next)
+    }
+}
+
+impl ExpDesc {
+    /**
+     * The register of a local or the index of an upvalue
+     */
+    fn reg_or_upval(&self) -> u32 {
+        match self.k {
+            ExpKind::Local { ridx, .. } => ridx,
+            ExpKind::Upval(idx) => idx,
+            _ => unreachable!("expression is not a variable"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::compile;
+    use crate::core::{parser::parse_all, types::Proto};
+
+    /* everything luac writes for a function, with the path of nested functions in the messages */
+    fn assert_same_proto(expected: &Proto, actual: &Proto, path: &str) {
+        let code = |p: &Proto| p.code.iter().map(|i| i.raw()).collect::<Vec<_>>();
+        assert_eq!(code(expected), code(actual), "code of {}", path);
+        assert_eq!(format!("{:?}", expected.constants), format!("{:?}", actual.constants), "constants of {}", path);
+        assert_eq!(format!("{:?}", expected.upvalues), format!("{:?}", actual.upvalues), "upvalues of {}", path);
+        assert_eq!(expected.line_info, actual.line_info, "line info of {}", path);
+        assert_eq!(format!("{:?}", expected.abs_line_info), format!("{:?}", actual.abs_line_info), "absolute line info of {}", path);
+        assert_eq!(format!("{:?}", expected.loc_vars), format!("{:?}", actual.loc_vars), "locals of {}", path);
+        assert_eq!(
+            (expected.line_defined, expected.last_line_defined, expected.num_params, expected.is_vararg, expected.max_stack_size),
+            (actual.line_defined, actual.last_line_defined, actual.num_params, actual.is_vararg, actual.max_stack_size),
+            "header of {}", path);
+        assert_eq!(expected.fns.len(), actual.fns.len(), "functions of {}", path);
+        for (i, (e, a)) in expected.fns.iter().zip(&actual.fns).enumerate() {
+            assert_same_proto(e, a, &format!("{}/{}", path, i));
+        }
+    }
+
+    /* the fixtures were compiled with the reference luac from their directory */
+    fn assert_compiles_like_luac(source: &[u8], name: &str, luac: &[u8]) {
+        let expected = parse_all(luac).unwrap();
+        let actual = compile(source, &format!("@{}", name)).unwrap();
+        assert_same_proto(&expected, &actual, name);
+    }
+
+    #[test]
+    fn compile_opcodes() {
+        assert_compiles_like_luac(include_bytes!("../../helpers/opcodes1.lua"), "opcodes1.lua", include_bytes!("../../helpers/out1"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/opcodes2.lua"), "opcodes2.lua", include_bytes!("../../helpers/opcodes2.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/closures.lua"), "closures.lua", include_bytes!("../../helpers/closures.out"));
+    }
+
+    #[test]
+    fn compile_library_tests() {
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/base.lua"), "base.lua", include_bytes!("../../helpers/tests/base.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/call_native.lua"), "call_native.lua", include_bytes!("../../helpers/tests/call_native.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/debug.lua"), "debug.lua", include_bytes!("../../helpers/tests/debug.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/format.lua"), "format.lua", include_bytes!("../../helpers/tests/format.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/io.lua"), "io.lua", include_bytes!("../../helpers/tests/io.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/math.lua"), "math.lua", include_bytes!("../../helpers/tests/math.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/os.lua"), "os.lua", include_bytes!("../../helpers/tests/os.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/pack.lua"), "pack.lua", include_bytes!("../../helpers/tests/pack.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/string.lua"), "string.lua", include_bytes!("../../helpers/tests/string.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/string_methods.lua"), "string_methods.lua", include_bytes!("../../helpers/tests/string_methods.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/table.lua"), "table.lua", include_bytes!("../../helpers/tests/table.out"));
+        assert_compiles_like_luac(include_bytes!("../../helpers/tests/utf8.lua"), "utf8.lua", include_bytes!("../../helpers/tests/utf8.out"));
+    }
+
+    #[test]
+    fn compile_errors() {
+        let error = |source: &str| compile(source.as_bytes(), "=test").unwrap_err();
+        assert_eq!(error("local x <const> = 1; x = 2"), "test:1: attempt to assign to const variable 'x'");
+        assert_eq!(error("break"), "test:1: break outside loop at line 1");
+        assert_eq!(error("goto nowhere"), "test:1: no visible label 'nowhere' for <goto> at line 1");
+        assert_eq!(error("::a:: ::a::"), "test:1: label 'a' already defined on line 1");
+        assert_eq!(error("goto f\nlocal x\n::f:: print(x)"), "test:3: <goto f> at line 1 jumps into the scope of local 'x'");
+    }
+}
@@ -0,0 +1,1728 @@
+/*
+ * Decompiling prototypes back into syntax trees. Control structures are recognised from the
+ * shapes luac gives them: loops from their jumps back, if/else from tests jumping forward and
+ * and/or from chains of tests, in statements or leaving a value in a register. Registers hold
+ * the expressions not used yet until a statement consumes them. Locals come from the debug
+ * information; in stripped functions a register whose value can't be a temporary is a local,
+ * and the function is decompiled again knowing it
+ */
+
+use std::{collections::{BTreeSet, HashMap, HashSet}, rc::Rc};
+
+use crate::{core::{instruction::Instruction, types::{Proto, TValue, string::LuaString}}, vm::debug::get_func_line};
+
+use super::{ast::{Chunk, Block, Stat, StatKind, IfBranch, LocalName, Attrib, FunctionBody, Expr, ExprKind, Field, BinOp, UnOp, Name}, lexer::{Position, Span}, printer};
+
+/* restarts allowed for each function while finding the locals of stripped code */
+const MAX_ATTEMPTS: usize = 10_000;
+
+/**
+ * The main function of 'proto' as a chunk
+ */
+pub fn decompile(proto: &Proto) -> std::result::Result<Chunk, String> {
+    let mut names = Names::new(proto);
+    let upvalues = proto.upvalues.iter().enumerate()
+        .map(|(i, upvalue)| match &upvalue.name {
+            Some(name) => name.clone(),
+            None if i == 0 => Rc::new(LuaString::from("_ENV")),
+            None => names.fresh("u"),
+        })
+        .collect();
+    let body = function(proto, upvalues, &mut names)?;
+    Ok(Chunk { block: body.block, span: Span::default() })
+}
+
+/**
+ * Lua source for 'proto'
+ */
+pub fn decompile_source(proto: &Proto) -> std::result::Result<String, String> {
+    Ok(printer::print_chunk(&decompile(proto)?))
+}
+
+/**
+ * Names given to what stripped chunks don't name, avoiding every name the chunk uses
+ */
+struct Names {
+    used: HashSet<Vec<u8>>,
+    next: usize,
+}
+
+impl Names {
+    fn new(proto: &Proto) -> Self {
+        fn collect(proto: &Proto, used: &mut HashSet<Vec<u8>>) {
+            for constant in &proto.constants {
+                if let TValue::STR(s) = constant {
+                    used.insert(s.as_bytes().to_vec());
+                }
+            }
+            let locals = proto.loc_vars.iter().filter_map(|local| local.name.as_ref());
+            let upvalues = proto.upvalues.iter().filter_map(|upvalue| upvalue.name.as_ref());
+            for name in locals.chain(upvalues) {
+                used.insert(name.as_bytes().to_vec());
+            }
+            for child in &proto.fns {
+                collect(child, used);
+            }
+        }
+        let mut used = HashSet::new();
+        collect(proto, &mut used);
+        Self { used, next: 1 }
+    }
+
+    fn fresh(&mut self, prefix: &str) -> Name {
+        loop {
+            let name = format!("{}{}", prefix, self.next);
+            self.next += 1;
+            if self.used.insert(name.clone().into_bytes()) {
+                return Rc::new(LuaString::from(name));
+            }
+        }
+    }
+}
+
+fn span(line: u32) -> Span {
+    let position = Position { line, column: 0 };
+    Span { start: position, end: position }
+}
+
+fn expr(kind: ExprKind, line: u32) -> Expr {
+    Expr { kind, span: span(line) }
+}
+
+fn stat(kind: StatKind, line: u32) -> Stat {
+    Stat { kind, span: span(line) }
+}
+
+fn binary(op: BinOp, left: Expr, right: Expr, line: u32) -> Expr {
+    let start = left.span;
+    Expr { kind: ExprKind::Binary { op, left: Box::new(left), right: Box::new(right), op_span: span(line) }, span: start }
+}
+
+/**
+ * The negation of a tested expression, where only its truth matters
+ */
+fn not(e: Expr) -> Expr {
+    let line = e.span.start.line;
+    match e.kind {
+        ExprKind::Binary { op: BinOp::Eq, left, right, op_span } => Expr { kind: ExprKind::Binary { op: BinOp::Ne, left, right, op_span }, span: e.span },
+        ExprKind::Binary { op: BinOp::Ne, left, right, op_span } => Expr { kind: ExprKind::Binary { op: BinOp::Eq, left, right, op_span }, span: e.span },
+        ExprKind::Unary { op: UnOp::Not, operand, .. } => *operand,
+        ExprKind::True => expr(ExprKind::False, line),
+        ExprKind::False => expr(ExprKind::True, line),
+        kind => expr(ExprKind::Unary { op: UnOp::Not, operand: Box::new(Expr { kind, span: e.span }), op_span: e.span }, line),
+    }
+}
+
+/* binary operators by metamethod event, in ORDER TM from TM_ADD */
+fn event_op(tm: u8) -> Option<BinOp> {
+    const OPS: [BinOp; 12] = [
+        BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::Mod, BinOp::Pow, BinOp::Div, BinOp::IDiv,
+        BinOp::BAnd, BinOp::BOr, BinOp::BXor, BinOp::Shl, BinOp::Shr,
+    ];
+    OPS.get((tm as usize).checked_sub(6)?).copied()
+}
+
+fn is_test(instruction: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(instruction, Eq { .. } | Lt { .. } | Le { .. } | EqK { .. } | EqI { .. } | LtI { .. } | LeI { .. } | GtI { .. } | GeI { .. } | Test { .. } | TestSet { .. })
+}
+
+/**
+ * What a register holds while decompiling a statement
+ */
+#[derive(Debug, Clone)]
+enum Slot {
+    Empty,
+    /* a value not used yet: 'open' for calls and '...' keeping all their results */
+    Value { expr: Expr, open: bool },
+    /* a further result of the call or '...' in a register below */
+    Rest,
+    /* a method looked up by SELF; its object is the register above, as a Rest */
+    Method { object: Expr, name: Name },
+}
+
+impl Slot {
+    fn is_pending(&self) -> bool {
+        !matches!(self, Slot::Empty)
+    }
+}
+
+struct Local {
+    name: Name,
+    reg: u8,
+    start: usize,
+    /* None for locals found while decompiling, which last until the end of their block */
+    end: Option<usize>,
+}
+
+/**
+ * A test and its jump: control goes to 'target' when 'expr' is 'jump_if', else past the jump
+ */
+#[derive(Debug, Clone)]
+struct Node {
+    /* first instruction evaluating the test, where jumps to it land */
+    start: usize,
+    pc: usize,
+    expr: Expr,
+    jump_if: bool,
+    target: usize,
+    /* register holding the tested value, which and/or can leave as their result */
+    value_reg: Option<u8>,
+}
+
+/**
+ * State of a block being decompiled
+ */
+#[derive(Clone)]
+struct Frame {
+    stats: Vec<Stat>,
+    slots: Vec<Slot>,
+    /* last instruction writing each register, even once its value is used */
+    writers: Vec<Option<usize>>,
+    /* locals in scope, as indices in 'locals' */
+    active: Vec<usize>,
+    /* locals declared in this block and the statement declaring them */
+    declared: Vec<(usize, usize)>,
+    /* stores of a multiple assignment, last target first */
+    group: Vec<(Expr, Option<Expr>)>,
+    /* tests not known yet to be conditions or and/or, with the frame after each jump */
+    nodes: Vec<Node>,
+    snapshots: Vec<Snapshot>,
+    /* registers whose values the current instruction used */
+    consumed: Vec<u8>,
+    last_write: Option<(u8, usize)>,
+    /* first instruction of the statement being decompiled */
+    stat_pc: usize,
+}
+
+impl Frame {
+    fn new(active: Vec<usize>, writers: Vec<Option<usize>>, start: usize) -> Self {
+        Self {
+            stats: Vec::new(),
+            slots: vec![Slot::Empty; writers.len()],
+            writers,
+            active,
+            declared: Vec::new(),
+            group: Vec::new(),
+            nodes: Vec::new(),
+            snapshots: Vec::new(),
+            consumed: Vec::new(),
+            last_write: None,
+            stat_pc: start,
+        }
+    }
+
+    fn pending(&self) -> Option<u8> {
+        self.slots.iter().position(Slot::is_pending).map(|reg| reg as u8)
+    }
+}
+
+/**
+ * The loop a block is in: where 'break' goes, and the head and end of a repeat whose
+ * 'until' closes the block
+ */
+#[derive(Debug, Clone, Copy)]
+struct Loop {
+    break_to: usize,
+    until: Option<(usize, usize)>,
+}
+
+enum Failure {
+    /* something was learnt about locals or labels: decompile the function again */
+    Retry,
+    Error(String),
+}
+
+type Result<T> = std::result::Result<T, Failure>;
+
+fn error<T>(message: String) -> Result<T> {
+    Err(Failure::Error(message))
+}
+
+fn function(proto: &Proto, upvalues: Vec<Name>, names: &mut Names) -> std::result::Result<FunctionBody, String> {
+    let mut decompiler = Decompiler::new(proto, upvalues, names);
+    for _ in 0..MAX_ATTEMPTS {
+        match decompiler.attempt() {
+            Ok(body) => return Ok(body),
+            Err(Failure::Retry) => continue,
+            Err(Failure::Error(message)) => return Err(message),
+        }
+    }
+    Err(format!("can't find the locals of the function at line {}", proto.line_defined))
+}
+
+struct Decompiler<'a> {
+    proto: &'a Proto,
+    code: Vec<Instruction>,
+    /* line of each instruction, 0 when stripped */
+    lines: Vec<u32>,
+    upvalues: Vec<Name>,
+    names: &'a mut Names,
+    locals: Vec<Local>,
+    /* locals from the debug information, first in 'locals'; the rest are found while decompiling */
+    known: usize,
+    /* names given to unnamed locals, kept between attempts */
+    synthesized: HashMap<(usize, u8), Name>,
+    /* values found to be locals, by the instruction writing them and their register */
+    forced: HashSet<(usize, u8)>,
+    labels: BTreeSet<usize>,
+    /* instructions jumping back to each loop head */
+    back_jumps: HashMap<usize, Vec<usize>>,
+    /* locals declared in this attempt with their own syntax: parameters, loop variables and local functions */
+    announced: HashSet<usize>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(proto: &'a Proto, upvalues: Vec<Name>, names: &'a mut Names) -> Self {
+        let code: Vec<Instruction> = proto.code.iter().map(|instruction| instruction.decoded()).collect();
+        let lines = (0..code.len()).map(|pc| get_func_line(proto, pc).unwrap_or(0) as u32).collect();
+        let locals: Vec<Local> = proto.loc_vars.iter().enumerate()
+            .map(|(i, local)| {
+                let reg = proto.loc_vars[..i].iter().filter(|other| other.start_pc <= local.start_pc && local.start_pc < other.end_pc).count();
+                Local {
+                    name: local.name.clone().unwrap_or_else(|| Rc::new(LuaString::from("(unnamed)"))),
+                    reg: reg as u8,
+                    start: local.start_pc,
+                    end: Some(local.end_pc),
+                }
+            })
+            .collect();
+        let mut back_jumps: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (pc, instruction) in code.iter().enumerate() {
+            if let Instruction::Jmp { sj } = instruction {
+                let target = pc as i64 + 1 + *sj as i64;
+                if target <= pc as i64 && target >= 0 {
+                    back_jumps.entry(target as usize).or_default().push(pc);
+                }
+            }
+        }
+        let known = locals.len();
+        Self {
+            proto, code, lines, upvalues, names, locals, known,
+            synthesized: HashMap::new(),
+            forced: HashSet::new(),
+            labels: BTreeSet::new(),
+            back_jumps,
+            announced: HashSet::new(),
+        }
+    }
+
+    fn attempt(&mut self) -> Result<FunctionBody> {
+        self.locals.truncate(self.known);
+        self.announced.clear();
+        let line_defined = self.proto.line_defined as u32;
+        let mut params = Vec::new();
+        let mut active = Vec::new();
+        for reg in 0..self.proto.num_params {
+            let index = match self.locals.get(reg as usize) {
+                Some(local) if local.start == 0 && local.reg == reg => reg as usize,
+                _ => self.synthesize(usize::MAX, reg, 0, "p"),
+            };
+            self.announced.insert(index);
+            active.push(index);
+            params.push(LocalName { name: self.locals[index].name.clone(), attrib: None, span: span(line_defined) });
+        }
+        let writers = vec![None; 256];
+        let (block, _) = self.block(0, self.code.len(), active, writers, None, None)?;
+        let end = Position { line: self.proto.last_line_defined as u32, column: 0 };
+        Ok(FunctionBody {
+            params,
+            is_vararg: self.proto.is_vararg,
+            is_method: false,
+            block,
+            span: Span { start: Position { line: line_defined, column: 0 }, end },
+        })
+    }
+
+    /**
+     * Adds a local the debug information doesn't have, named the same in every attempt
+     */
+    fn synthesize(&mut self, key: usize, reg: u8, start: usize, prefix: &str) -> usize {
+        let names = &mut self.names;
+        let name = self.synthesized.entry((key, reg)).or_insert_with(|| names.fresh(prefix)).clone();
+        self.locals.push(Local { name, reg, start, end: None });
+        self.locals.len() - 1
+    }
+
+    fn line(&self, pc: usize) -> u32 {
+        self.lines.get(pc).copied().unwrap_or(0)
+    }
+
+    /* first line of the instructions in [from, to), where functions start at their definition */
+    fn stat_line(&self, from: usize, to: usize) -> u32 {
+        (from..to.min(self.code.len()))
+            .map(|pc| match self.code[pc] {
+                Instruction::Closure { bx, .. } if self.line(pc) != 0 => self.proto.fns[bx as usize].line_defined as u32,
+                _ => self.line(pc),
+            })
+            .filter(|line| *line != 0)
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn push(&self, f: &mut Frame, kind: StatKind, end: usize) {
+        let line = self.stat_line(f.stat_pc, end);
+        f.stats.push(stat(kind, line));
+        f.stat_pc = end;
+    }
+
+    fn local_at(&self, f: &Frame, reg: u8) -> Option<usize> {
+        f.active.iter().rev().copied().find(|&local| self.locals[local].reg == reg)
+    }
+
+    /* first register free of locals */
+    fn base(&self, f: &Frame) -> u8 {
+        f.active.iter().map(|&local| self.locals[local].reg + 1).max().unwrap_or(0)
+    }
+
+    fn local_name(&self, local: usize, line: u32) -> LocalName {
+        LocalName { name: self.locals[local].name.clone(), attrib: None, span: span(line) }
+    }
+
+    fn label(&self, pc: usize) -> Name {
+        Rc::new(LuaString::from(format!("L{}", pc)))
+    }
+
+    /**
+     * A register used where it can't hold a temporary: it is a local, declared after the
+     * instruction writing it
+     */
+    fn conflict(&mut self, f: &Frame, reg: u8) -> Failure {
+        match f.writers[reg as usize] {
+            Some(writer) if self.forced.insert((writer, reg)) => Failure::Retry,
+            _ => Failure::Error(format!("can't decompile register {} at instruction {}", reg, f.stat_pc + 1)),
+        }
+    }
+
+    fn read(&mut self, f: &mut Frame, reg: u8) -> Result<Expr> {
+        match std::mem::replace(&mut f.slots[reg as usize], Slot::Empty) {
+            Slot::Value { expr, .. } => {
+                f.consumed.push(reg);
+                Ok(expr)
+            }
+            Slot::Empty => match self.local_at(f, reg) {
+                Some(local) => Ok(expr(ExprKind::Name(self.locals[local].name.clone()), 0)),
+                None => Err(self.conflict(f, reg)),
+            },
+            slot => {
+                f.slots[reg as usize] = slot;
+                Err(self.conflict(f, reg))
+            }
+        }
+    }
+
+    /* a value being stored, None for a further result of a call stored by the same assignment */
+    fn read_value(&mut self, f: &mut Frame, reg: u8) -> Result<Option<Expr>> {
+        if let Slot::Rest = f.slots[reg as usize] {
+            f.slots[reg as usize] = Slot::Empty;
+            return Ok(None);
+        }
+        self.read(f, reg).map(Some)
+    }
+
+    fn rk(&mut self, f: &mut Frame, c: u8, k: bool, pc: usize) -> Result<Option<Expr>> {
+        if k {
+            self.constant(c as usize, pc).map(Some)
+        } else {
+            self.read_value(f, c)
+        }
+    }
+
+    /**
+     * Values in registers from 'from': 'count' of them, or up to an open call or '...'. The
+     * last is parenthesized when it would give several values but was cut to one
+     */
+    fn read_list(&mut self, f: &mut Frame, from: u8, count: Option<u8>) -> Result<Vec<Expr>> {
+        let end = match count {
+            Some(count) => from as usize + count as usize,
+            None => match (from as usize..f.slots.len()).find(|&reg| matches!(f.slots[reg], Slot::Value { open: true, .. })) {
+                Some(reg) => reg + 1,
+                None => return error(format!("no open value above register {}", from)),
+            },
+        };
+        let mut values = Vec::new();
+        for reg in from as usize..end {
+            if let Slot::Rest = f.slots[reg] {
+                f.slots[reg] = Slot::Empty;
+                f.consumed.push(reg as u8);
+                continue;
+            }
+            let open = matches!(f.slots[reg], Slot::Value { open: true, .. });
+            let e = self.read(f, reg as u8)?;
+            let expands = reg + 1 < end && matches!(f.slots[reg + 1], Slot::Rest);
+            if reg + 1 == end && !open && !expands && e.is_multi() {
+                let e_span = e.span;
+                values.push(Expr { kind: ExprKind::Paren(Box::new(e)), span: e_span });
+            } else {
+                values.push(e);
+            }
+        }
+        Ok(values)
+    }
+
+    /**
+     * Values used out of the order they were computed in are not temporaries
+     */
+    fn check_order(&mut self, f: &mut Frame) -> Result<()> {
+        if let Some(&low) = f.consumed.iter().min() {
+            let skipped = (low as usize + 1..f.slots.len()).any(|reg| f.slots[reg].is_pending() && !f.consumed.contains(&(reg as u8)));
+            if skipped {
+                return Err(self.conflict(f, low));
+            }
+        }
+        f.consumed.clear();
+        Ok(())
+    }
+
+    fn write(&mut self, f: &mut Frame, reg: u8, slot: Slot, pc: usize) -> Result<()> {
+        self.check_order(f)?;
+        if f.slots[reg as usize].is_pending() {
+            return Err(self.conflict(f, reg));
+        }
+        f.slots[reg as usize] = slot;
+        f.writers[reg as usize] = Some(pc);
+        f.last_write = Some((reg, pc));
+        Ok(())
+    }
+
+    /* the result of an instruction: a store when the register is a local */
+    fn assign(&mut self, f: &mut Frame, reg: u8, e: Expr, pc: usize) -> Result<()> {
+        match self.local_at(f, reg) {
+            Some(local) if !f.slots[reg as usize].is_pending() => {
+                let target = expr(ExprKind::Name(self.locals[local].name.clone()), self.line(pc));
+                f.writers[reg as usize] = Some(pc);
+                f.last_write = Some((reg, pc));
+                f.consumed.clear();
+                f.group.push((target, Some(e)));
+                Ok(())
+            }
+            _ => self.write(f, reg, Slot::Value { expr: e, open: false }, pc),
+        }
+    }
+
+    fn store(&mut self, f: &mut Frame, target: Expr, value: Option<Expr>) {
+        f.consumed.clear();
+        f.group.push((target, value));
+    }
+
+    /**
+     * The stores since the last statement as an assignment, first target first
+     */
+    fn flush_group(&mut self, f: &mut Frame, end: usize) -> Result<()> {
+        if f.group.is_empty() {
+            return Ok(());
+        }
+        if let Some(reg) = f.pending() {
+            return Err(self.conflict(f, reg));
+        }
+        let group: Vec<(Expr, Option<Expr>)> = f.group.drain(..).rev().collect();
+        let count = group.iter().filter(|(_, value)| value.is_some()).count();
+        let expanded = matches!(group.last(), Some((_, None)));
+        let mut targets = Vec::new();
+        let mut values = Vec::new();
+        for (target, value) in group {
+            targets.push(target);
+            values.extend(value);
+        }
+        if !expanded && count < targets.len() {
+            if let Some(last) = values.pop() {
+                let last_span = last.span;
+                values.push(if last.is_multi() { Expr { kind: ExprKind::Paren(Box::new(last)), span: last_span } } else { last });
+            }
+        }
+        let kind = function_stat(&targets, &values).unwrap_or(StatKind::Assign { targets, values });
+        self.push(f, kind, end);
+        Ok(())
+    }
+
+    fn constant(&self, k: usize, pc: usize) -> Result<Expr> {
+        let kind = match self.proto.constants.get(k) {
+            Some(TValue::NIL) => ExprKind::Nil,
+            Some(TValue::TBOOLEAN(true)) => ExprKind::True,
+            Some(TValue::TBOOLEAN(false)) => ExprKind::False,
+            Some(TValue::NUMINT(i)) => ExprKind::Int(*i),
+            Some(TValue::NUMFLT(n)) => ExprKind::Float(*n),
+            Some(TValue::STR(s)) => ExprKind::String(s.clone()),
+            _ => return error(format!("bad constant {} at instruction {}", k, pc + 1)),
+        };
+        Ok(expr(kind, self.line(pc)))
+    }
+
+    fn key_name(&self, k: usize, pc: usize) -> Result<Name> {
+        match self.proto.constants.get(k) {
+            Some(TValue::STR(s)) => Ok(s.clone()),
+            _ => error(format!("bad key constant {} at instruction {}", k, pc + 1)),
+        }
+    }
+
+    /* a field of an upvalue: a global when it is '_ENV' and no local or upvalue hides the name */
+    fn global(&self, f: &Frame, upvalue: u8, key: Name, pc: usize) -> Expr {
+        let line = self.line(pc);
+        let table = self.upvalues[upvalue as usize].clone();
+        let hidden = f.active.iter().any(|&local| self.locals[local].name == key) || self.upvalues.contains(&key);
+        if table.as_bytes() == b"_ENV" && printer::is_name(key.as_bytes()) && !hidden {
+            return expr(ExprKind::Name(key), line);
+        }
+        let object = expr(ExprKind::Name(table), line);
+        index(object, expr(ExprKind::String(key), line), line)
+    }
+}
+
+fn index(object: Expr, key: Expr, line: u32) -> Expr {
+    expr(ExprKind::Index { object: Box::new(object), key: Box::new(key) }, line)
+}
+
+/* 'function a.b.c()' for a function stored into a name or a field chain */
+fn function_stat(targets: &[Expr], values: &[Expr]) -> Option<StatKind> {
+    fn path(e: &Expr, out: &mut Vec<Name>) -> bool {
+        match &e.kind {
+            ExprKind::Name(name) => {
+                out.push(name.clone());
+                true
+            }
+            ExprKind::Index { object, key } => match &key.kind {
+                ExprKind::String(key) if printer::is_name(key.as_bytes()) => {
+                    let found = path(object, out);
+                    out.push(key.clone());
+                    found
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+    let ([target], [value]) = (targets, values) else { return None };
+    let ExprKind::Function(body) = &value.kind else { return None };
+    let mut names = Vec::new();
+    if !path(target, &mut names) {
+        return None;
+    }
+    let mut body = body.clone();
+    let mut method = None;
+    if names.len() > 1 && body.params.first().is_some_and(|param| param.name.as_bytes() == b"self") {
+        body.params.remove(0);
+        body.is_method = true;
+        method = names.pop();
+    }
+    Some(StatKind::Function { path: names, method, body })
+}
+
+impl Decompiler<'_> {
+    /**
+     * Decompiles the instruction at 'pc', one that isn't a test or a jump, and gives the next
+     */
+    fn instruction(&mut self, f: &mut Frame, pc: usize) -> Result<usize> {
+        use Instruction::*;
+        let line = self.line(pc);
+        f.consumed.clear();
+        let mut next = pc + 1;
+        match self.code[pc] {
+            Move { a, b } => {
+                let mut e = self.read(f, b)?;
+                e.span = span(line);
+                self.assign(f, a, e, pc)?;
+            }
+            LoadI { a, sbx } => self.assign(f, a, expr(ExprKind::Int(sbx as i64), line), pc)?,
+            LoadF { a, sbx } => self.assign(f, a, expr(ExprKind::Float(sbx as f64), line), pc)?,
+            LoadK { a, bx } => {
+                let e = self.constant(bx as usize, pc)?;
+                self.assign(f, a, e, pc)?;
+            }
+            LoadKX { a } => {
+                let Some(ExtraArg { ax }) = self.code.get(pc + 1) else { return error(format!("LOADKX without EXTRAARG at instruction {}", pc + 1)) };
+                let e = self.constant(*ax as usize, pc)?;
+                self.assign(f, a, e, pc)?;
+                next = pc + 2;
+            }
+            LoadFalse { a } => self.assign(f, a, expr(ExprKind::False, line), pc)?,
+            LoadTrue { a } => self.assign(f, a, expr(ExprKind::True, line), pc)?,
+            LoadNil { a, b } => {
+                for reg in a..=a + b {
+                    self.assign(f, reg, expr(ExprKind::Nil, line), pc)?;
+                }
+            }
+            GetUpval { a, b } => {
+                let e = expr(ExprKind::Name(self.upvalues[b as usize].clone()), line);
+                self.assign(f, a, e, pc)?;
+            }
+            SetUpval { a, b } => {
+                let value = self.read_value(f, a)?;
+                let target = expr(ExprKind::Name(self.upvalues[b as usize].clone()), line);
+                self.store(f, target, value);
+            }
+            GetTabUp { a, b, key_k } => {
+                let key = self.key_name(key_k as usize, pc)?;
+                let e = self.global(f, b, key, pc);
+                self.assign(f, a, e, pc)?;
+            }
+            GetTable { a, b, c } => {
+                let object = self.read(f, b)?;
+                let key = self.read(f, c)?;
+                self.assign(f, a, index(object, key, line), pc)?;
+            }
+            GetI { a, b, c } => {
+                let object = self.read(f, b)?;
+                self.assign(f, a, index(object, expr(ExprKind::Int(c as i64), line), line), pc)?;
+            }
+            GetField { a, b, key_k } => {
+                let object = self.read(f, b)?;
+                let key = self.constant(key_k as usize, pc)?;
+                self.assign(f, a, index(object, key, line), pc)?;
+            }
+            SetTabUp { a, key_k, c, k } => {
+                let value = self.rk(f, c, k, pc)?;
+                let key = self.key_name(key_k as usize, pc)?;
+                let target = self.global(f, a, key, pc);
+                self.store(f, target, value);
+            }
+            SetTable { a, b, c, k } => self.set(f, a, |d, f| d.read(f, b), c, k, pc)?,
+            SetI { a, b, c, k } => self.set(f, a, |_, _| Ok(expr(ExprKind::Int(b as i64), line)), c, k, pc)?,
+            SetField { a, key_k, c, k } => self.set(f, a, |d, _| d.constant(key_k as usize, pc), c, k, pc)?,
+            NewTable { a, .. } => {
+                self.write(f, a, Slot::Value { expr: expr(ExprKind::Table(Vec::new()), line), open: false }, pc)?;
+                if let Some(ExtraArg { .. }) = self.code.get(pc + 1) {
+                    next = pc + 2;
+                }
+            }
+            Self_ { a, b, c, k } => {
+                let object = self.read(f, b)?;
+                let name = match self.rk(f, c, k, pc)? {
+                    Some(Expr { kind: ExprKind::String(name), .. }) if printer::is_name(name.as_bytes()) => name,
+                    _ => return error(format!("bad method name at instruction {}", pc + 1)),
+                };
+                self.write(f, a, Slot::Method { object, name }, pc)?;
+                self.write(f, a + 1, Slot::Rest, pc)?;
+            }
+            AddI { a, b, sc } | ShrI { a, b, sc } | ShlI { a, b, sc } => {
+                let x = self.read(f, b)?;
+                let (op, imm, swap) = match (self.code[pc], self.code.get(pc + 1)) {
+                    (_, Some(MmBinI { sb, tm, k, .. })) if event_op(*tm).is_some() => (event_op(*tm).unwrap(), *sb, *k),
+                    (ShrI { .. }, _) => (BinOp::Shr, sc, false),
+                    (ShlI { .. }, _) => (BinOp::Shl, sc, true),
+                    _ => (BinOp::Add, sc, false),
+                };
+                let imm = expr(ExprKind::Int(imm as i64), line);
+                let e = if swap { binary(op, imm, x, line) } else { binary(op, x, imm, line) };
+                self.assign(f, a, e, pc)?;
+                next = self.skip_metamethod(pc);
+            }
+            AddK { a, b, c } | SubK { a, b, c } | MulK { a, b, c } | ModK { a, b, c } | PowK { a, b, c } | DivK { a, b, c }
+            | IDivK { a, b, c } | BAndK { a, b, c } | BOrK { a, b, c } | BXorK { a, b, c } => {
+                let x = self.read(f, b)?;
+                let constant = self.constant(c as usize, pc)?;
+                let (op, swap) = match self.code.get(pc + 1) {
+                    Some(MmBinK { tm, k, .. }) if event_op(*tm).is_some() => (event_op(*tm).unwrap(), *k),
+                    _ => (constant_op(&self.code[pc]), false),
+                };
+                let e = if swap { binary(op, constant, x, line) } else { binary(op, x, constant, line) };
+                self.assign(f, a, e, pc)?;
+                next = self.skip_metamethod(pc);
+            }
+            Add { a, b, c } | Sub { a, b, c } | Mul { a, b, c } | Mod { a, b, c } | Pow { a, b, c } | Div { a, b, c }
+            | IDiv { a, b, c } | BAnd { a, b, c } | BOr { a, b, c } | BXor { a, b, c } | Shl { a, b, c } | Shr { a, b, c } => {
+                let left = self.read(f, b)?;
+                let right = self.read(f, c)?;
+                let op = match self.code.get(pc + 1) {
+                    Some(MmBin { tm, .. }) if event_op(*tm).is_some() => event_op(*tm).unwrap(),
+                    _ => register_op(&self.code[pc]),
+                };
+                self.assign(f, a, binary(op, left, right, line), pc)?;
+                next = self.skip_metamethod(pc);
+            }
+            Close { a } if !matches!(self.code.get(pc + 1), Some(Jmp { .. })) => self.end_synthesized(f, a, pc),
+            MmBin { .. } | MmBinI { .. } | MmBinK { .. } | ExtraArg { .. } | Close { .. } | VarArgPrep { .. } => {}
+            Unm { a, b } | BNot { a, b } | Not { a, b } | Len { a, b } => {
+                let op = match self.code[pc] {
+                    Unm { .. } => UnOp::Minus,
+                    BNot { .. } => UnOp::BNot,
+                    Not { .. } => UnOp::Not,
+                    _ => UnOp::Len,
+                };
+                let operand = self.read(f, b)?;
+                let e = expr(ExprKind::Unary { op, operand: Box::new(operand), op_span: span(line) }, line);
+                self.assign(f, a, e, pc)?;
+            }
+            Concat { a, b } => {
+                let mut values = Vec::new();
+                for reg in a..a + b {
+                    values.push(self.read(f, reg)?);
+                }
+                let mut e = values.pop().ok_or_else(|| Failure::Error(format!("empty concatenation at instruction {}", pc + 1)))?;
+                while let Some(left) = values.pop() {
+                    e = binary(BinOp::Concat, left, e, line);
+                }
+                self.assign(f, a, e, pc)?;
+            }
+            Tbc { a } => {
+                if f.slots[a as usize].is_pending() {
+                    return Err(self.conflict(f, a));
+                }
+                let Some(Stat { kind: StatKind::Local { names, .. }, .. }) = f.stats.last_mut() else {
+                    return error(format!("TBC without a local at instruction {}", pc + 1));
+                };
+                if let Some(name) = names.last_mut() {
+                    name.attrib = Some(Attrib::Close);
+                }
+            }
+            Call { a, b, c } => {
+                let call = self.call(f, a, b, pc)?;
+                match c {
+                    0 => self.write(f, a, Slot::Value { expr: call, open: true }, pc)?,
+                    1 => {
+                        self.check_order(f)?;
+                        self.push(f, StatKind::Call(call), pc + 1);
+                    }
+                    _ => {
+                        self.write(f, a, Slot::Value { expr: call, open: false }, pc)?;
+                        for reg in a + 1..a + c - 1 {
+                            self.write(f, reg, Slot::Rest, pc)?;
+                        }
+                    }
+                }
+            }
+            TailCall { a, b, .. } => {
+                let call = self.call(f, a, b, pc)?;
+                self.check_order(f)?;
+                if let Some(Return { .. }) = self.code.get(pc + 1) {
+                    next = pc + 2;
+                }
+                self.push(f, StatKind::Return(vec![call]), next);
+            }
+            Return { a, b, .. } => {
+                let values = self.read_list(f, a, if b == 0 { None } else { Some(b - 1) })?;
+                self.check_order(f)?;
+                self.push_return(f, values, pc);
+            }
+            Return0 { .. } => self.push_return(f, Vec::new(), pc),
+            Return1 { a } => {
+                let value = self.read_list(f, a, Some(1))?;
+                self.push_return(f, value, pc);
+            }
+            Closure { a, bx } => self.closure(f, a, bx as usize, pc)?,
+            VarArg { a, c } => match c {
+                0 => self.write(f, a, Slot::Value { expr: expr(ExprKind::Vararg, line), open: true }, pc)?,
+                1 => {}
+                _ => {
+                    self.write(f, a, Slot::Value { expr: expr(ExprKind::Vararg, line), open: false }, pc)?;
+                    for reg in a + 1..a + c - 1 {
+                        self.write(f, reg, Slot::Rest, pc)?;
+                    }
+                }
+            },
+            SetList { a, k, .. } => {
+                let items = self.take_items(f, a + 1)?;
+                self.add_fields(f, a, items.into_iter().map(Field::Positional), pc)?;
+                if k {
+                    next = pc + 2;
+                }
+            }
+            _ => return error(format!("unexpected instruction {} in a block", pc + 1)),
+        }
+        Ok(next)
+    }
+
+    fn skip_metamethod(&self, pc: usize) -> usize {
+        match self.code.get(pc + 1) {
+            Some(Instruction::MmBin { .. } | Instruction::MmBinI { .. } | Instruction::MmBinK { .. }) => pc + 2,
+            _ => pc + 1,
+        }
+    }
+
+    /* a store into a table, or a field of a table constructor still being built */
+    fn set(&mut self, f: &mut Frame, a: u8, key: impl FnOnce(&mut Self, &mut Frame) -> Result<Expr>, c: u8, k: bool, pc: usize) -> Result<()> {
+        let line = self.line(pc);
+        if self.is_constructor(f, a) {
+            let value = self.rk(f, c, k, pc)?.ok_or_else(|| Failure::Error(format!("bad field at instruction {}", pc + 1)))?;
+            let key = key(self, f)?;
+            let field = match key.kind {
+                ExprKind::String(name) if printer::is_name(name.as_bytes()) => Field::Named { key: name, key_span: span(line), value },
+                _ => Field::Keyed { key, value },
+            };
+            return self.add_fields(f, a, [field].into_iter(), pc);
+        }
+        let value = self.rk(f, c, k, pc)?;
+        let key = key(self, f)?;
+        let object = self.read(f, a)?;
+        self.store(f, index(object, key, line), value);
+        Ok(())
+    }
+
+    fn is_constructor(&self, f: &Frame, reg: u8) -> bool {
+        matches!(f.slots.get(reg as usize), Some(Slot::Value { expr: Expr { kind: ExprKind::Table(_), .. }, .. }))
+    }
+
+    /* the values left in registers from 'from', as items of a list */
+    fn take_items(&mut self, f: &mut Frame, from: u8) -> Result<Vec<Expr>> {
+        match (from as usize..f.slots.len()).rev().find(|&reg| f.slots[reg].is_pending()) {
+            Some(last) => {
+                let first = (from as usize..=last).find(|&reg| f.slots[reg].is_pending()).unwrap();
+                self.read_list(f, first as u8, Some((last + 1 - first) as u8))
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /**
+     * Adds fields to the constructor in 'table', after the items computed before them
+     */
+    fn add_fields(&mut self, f: &mut Frame, table: u8, fields: impl Iterator<Item = Field>, pc: usize) -> Result<()> {
+        let items = self.take_items(f, table + 1)?;
+        f.consumed.clear();
+        match &mut f.slots[table as usize] {
+            Slot::Value { expr: Expr { kind: ExprKind::Table(list), .. }, .. } => {
+                list.extend(items.into_iter().map(Field::Positional));
+                list.extend(fields);
+                /* a local holding the table starts once it is built */
+                f.writers[table as usize] = Some(pc);
+                Ok(())
+            }
+            _ => error(format!("no table constructor at instruction {}", pc + 1)),
+        }
+    }
+
+    fn call(&mut self, f: &mut Frame, a: u8, b: u8, pc: usize) -> Result<Expr> {
+        let line = self.line(pc);
+        let method = match std::mem::replace(&mut f.slots[a as usize], Slot::Empty) {
+            Slot::Method { object, name } => {
+                f.slots[a as usize + 1] = Slot::Empty;
+                f.consumed.extend([a, a + 1]);
+                Some((object, name))
+            }
+            slot => {
+                f.slots[a as usize] = slot;
+                None
+            }
+        };
+        let first = if method.is_some() { a + 2 } else { a + 1 };
+        let count = match b {
+            0 => None,
+            _ => Some((a + b).saturating_sub(first)),
+        };
+        let args = self.read_list(f, first, count)?;
+        Ok(match method {
+            Some((object, method)) => expr(ExprKind::MethodCall { object: Box::new(object), method, args }, line),
+            None => {
+                let function = self.read(f, a)?;
+                expr(ExprKind::Call { function: Box::new(function), args }, line)
+            }
+        })
+    }
+
+    /* the return closing the function is implicit */
+    fn push_return(&mut self, f: &mut Frame, values: Vec<Expr>, pc: usize) {
+        if values.is_empty() && pc + 1 == self.code.len() {
+            f.stat_pc = pc + 1;
+            return;
+        }
+        self.push(f, StatKind::Return(values), pc + 1);
+    }
+
+    fn closure(&mut self, f: &mut Frame, a: u8, bx: usize, pc: usize) -> Result<()> {
+        let Some(child) = self.proto.fns.get(bx).cloned() else { return error(format!("bad function {} at instruction {}", bx, pc + 1)) };
+        /* a local function sees its own local, which a function assigned to a local doesn't */
+        let captures_itself = child.upvalues.iter().any(|upvalue| upvalue.instack && upvalue.idx == a)
+            && self.local_at(f, a).is_none() && !f.slots[a as usize].is_pending();
+        let named = (0..self.known).find(|&local| self.locals[local].start == pc + 1 && self.locals[local].reg == a && !self.announced.contains(&local));
+        let local = match named {
+            Some(local) if captures_itself => Some(local),
+            None if self.known == 0 && captures_itself => Some(self.synthesize(pc, a, pc + 1, "l")),
+            _ => None,
+        };
+        if let Some(local) = local {
+            self.announced.insert(local);
+            f.active.push(local);
+        }
+        let mut upvalues = Vec::new();
+        for upvalue in &child.upvalues {
+            let name = if upvalue.instack {
+                match self.local_at(f, upvalue.idx) {
+                    Some(local) if !f.slots[upvalue.idx as usize].is_pending() => self.locals[local].name.clone(),
+                    _ => return Err(self.conflict(f, upvalue.idx)),
+                }
+            } else {
+                self.upvalues[upvalue.idx as usize].clone()
+            };
+            upvalues.push(name);
+        }
+        let body = Box::new(function(&child, upvalues, self.names).map_err(Failure::Error)?);
+        match local {
+            Some(local) => {
+                f.declared.push((local, f.stats.len()));
+                let name = self.local_name(local, self.line(pc));
+                self.push(f, StatKind::LocalFunction { name, body }, pc + 1);
+                Ok(())
+            }
+            None => {
+                let line = if self.line(pc) == 0 { 0 } else { child.line_defined as u32 };
+                self.assign(f, a, expr(ExprKind::Function(body), line), pc)
+            }
+        }
+    }
+}
+
+fn constant_op(instruction: &Instruction) -> BinOp {
+    use Instruction::*;
+    match instruction {
+        SubK { .. } => BinOp::Sub,
+        MulK { .. } => BinOp::Mul,
+        ModK { .. } => BinOp::Mod,
+        PowK { .. } => BinOp::Pow,
+        DivK { .. } => BinOp::Div,
+        IDivK { .. } => BinOp::IDiv,
+        BAndK { .. } => BinOp::BAnd,
+        BOrK { .. } => BinOp::BOr,
+        BXorK { .. } => BinOp::BXor,
+        _ => BinOp::Add,
+    }
+}
+
+fn register_op(instruction: &Instruction) -> BinOp {
+    use Instruction::*;
+    match instruction {
+        Sub { .. } => BinOp::Sub,
+        Mul { .. } => BinOp::Mul,
+        Mod { .. } => BinOp::Mod,
+        Pow { .. } => BinOp::Pow,
+        Div { .. } => BinOp::Div,
+        IDiv { .. } => BinOp::IDiv,
+        BAnd { .. } => BinOp::BAnd,
+        BOr { .. } => BinOp::BOr,
+        BXor { .. } => BinOp::BXor,
+        Shl { .. } => BinOp::Shl,
+        Shr { .. } => BinOp::Shr,
+        _ => BinOp::Add,
+    }
+}
+
+/**
+ * Tests as a boolean expression: 'pos' has where each item starts and where the last one
+ * goes on to. With 'fin', the tests give the value of and/or: jumps to 'fin.0' leave the
+ * tested value in register 'fin.1', and 'leaf' is the value computed last
+ */
+struct Cond {
+    nodes: Vec<Node>,
+    leaf: Option<Expr>,
+    pos: Vec<usize>,
+    fin: Option<(usize, u8)>,
+}
+
+impl Cond {
+    fn len(&self) -> usize {
+        self.nodes.len() + self.leaf.is_some() as usize
+    }
+
+    fn parse(&self, t: &[usize], f: &[usize]) -> Option<Expr> {
+        self.range(0, self.len(), t, f)
+    }
+
+    /* items [i, j) with 't' and 'f' where control goes when they are true and false */
+    fn range(&self, i: usize, j: usize, t: &[usize], f: &[usize]) -> Option<Expr> {
+        if j == i + 1 {
+            return self.item(i, t, f);
+        }
+        for s in i + 1..j {
+            let at = [self.pos[s]];
+            for (op, left_t, left_f) in [(BinOp::And, &at[..], f), (BinOp::Or, t, &at[..])] {
+                if !self.exits(i, s, left_t, left_f) {
+                    continue;
+                }
+                if let Some(left) = self.range(i, s, left_t, left_f) {
+                    if let Some(right) = self.range(s, j, t, f) {
+                        let line = right.span.start.line;
+                        return Some(binary(op, left, right, line));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /* whether the jumps of items [i, s) stay inside them or go to 't' or 'f' */
+    fn exits(&self, i: usize, s: usize, t: &[usize], f: &[usize]) -> bool {
+        (i..s).all(|k| match self.nodes.get(k) {
+            Some(node) => self.pos[k + 1..=s].contains(&node.target) || t.contains(&node.target) || f.contains(&node.target),
+            None => false,
+        })
+    }
+
+    fn item(&self, i: usize, t: &[usize], f: &[usize]) -> Option<Expr> {
+        let Some(node) = self.nodes.get(i) else {
+            let (fin, _) = self.fin?;
+            return (t.contains(&fin) && f.contains(&fin)).then(|| self.leaf.clone()).flatten();
+        };
+        let next = self.pos[i + 1];
+        let e = node.expr.clone();
+        if let Some((fin, reg)) = self.fin.filter(|(fin, _)| *fin == node.target) {
+            /* jumping to the end leaves the tested value */
+            if node.value_reg != Some(reg) {
+                return None;
+            }
+            let valid = if node.jump_if { t.contains(&fin) && f.contains(&next) } else { f.contains(&fin) && t.contains(&next) };
+            return valid.then_some(e);
+        }
+        if t.contains(&node.target) && f.contains(&next) {
+            Some(if node.jump_if { e } else { not(e) })
+        } else if f.contains(&node.target) && t.contains(&next) {
+            Some(if node.jump_if { not(e) } else { e })
+        } else {
+            None
+        }
+    }
+}
+
+/* a frame after a test, without the statements it shares with the frame it was taken from */
+#[derive(Clone)]
+struct Snapshot {
+    frame: Frame,
+    stats: usize,
+}
+
+impl Decompiler<'_> {
+    /**
+     * Decompiles [start, end) as a block, with the locals in scope and the registers written
+     * before it. 'head' is the loop head at 'start' and the jump back making it one, and the
+     * condition of a repeat comes back with the block it closes
+     */
+    fn block(&mut self, start: usize, end: usize, active: Vec<usize>, writers: Vec<Option<usize>>, lp: Option<Loop>, head: Option<(usize, usize)>) -> Result<(Block, Option<Expr>)> {
+        let mut f = Frame::new(active, writers, start);
+        let mut pc = start;
+        loop {
+            if f.nodes.iter().any(|node| node.target == pc) {
+                self.reduce(&mut f, pc, None, None)?;
+            }
+            if !f.nodes.is_empty() && (pc >= end || self.is_boundary(&f, pc, head)) {
+                if let Some(condition) = self.resolve(&mut f, end, lp)? {
+                    return Ok((finish(f.stats), Some(condition)));
+                }
+                pc = f.stat_pc;
+                continue;
+            }
+            if !f.group.is_empty() && (pc >= end || !self.is_store(&f, pc)) {
+                self.flush_group(&mut f, pc)?;
+            }
+            if pc >= end {
+                break;
+            }
+            self.end_locals(&mut f, pc, end, false);
+            self.declare(&mut f, pc, false)?;
+            self.end_locals(&mut f, pc, end, true);
+            let at_head = head.is_some_and(|(h, _)| h == pc);
+            if self.labels.contains(&pc) && !at_head {
+                self.settle(&f)?;
+                f.stats.push(stat(StatKind::Label(self.label(pc)), 0));
+            }
+            let limit = match head {
+                Some((h, q)) if h == pc => q,
+                _ => end,
+            };
+            if let Some(q) = self.back_jumps.get(&pc).and_then(|jumps| jumps.iter().copied().filter(|&q| q < limit).max()) {
+                self.settle(&f)?;
+                f.stat_pc = pc;
+                pc = self.loop_stat(&mut f, pc, q)?;
+                continue;
+            }
+            pc = self.step(&mut f, pc, lp)?;
+            if !f.group.is_empty() && f.pending().is_none() {
+                self.flush_group(&mut f, pc)?;
+            }
+        }
+        self.declare(&mut f, end, true)?;
+        self.settle(&f)?;
+        Ok((finish(f.stats), None))
+    }
+
+    /* values left in registers between statements belong to locals */
+    fn settle(&mut self, f: &Frame) -> Result<()> {
+        match f.pending() {
+            Some(reg) => Err(self.conflict(f, reg)),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Tests, jumps and loops at 'pc', or else an ordinary instruction
+     */
+    fn step(&mut self, f: &mut Frame, pc: usize, lp: Option<Loop>) -> Result<usize> {
+        use Instruction::*;
+        match self.code[pc] {
+            instruction if is_test(&instruction) => {
+                self.node(f, pc)?;
+                Ok(pc + 2)
+            }
+            LFalseSkip { a } => {
+                if !matches!(self.code.get(pc + 1), Some(LoadTrue { a: b }) if *b == a) || !self.reduce(f, pc + 2, Some((pc, a)), None)? {
+                    return error(format!("can't decompile the comparison at instruction {}", pc + 1));
+                }
+                Ok(pc + 2)
+            }
+            Jmp { sj } => {
+                let target = (pc as i64 + 1 + sj as i64) as usize;
+                if let Some(a) = self.skips_pair(pc) {
+                    if f.nodes.is_empty() || !self.reduce(f, pc + 3, Some((pc + 1, a)), Some(pc))? {
+                        return error(format!("can't decompile the comparison at instruction {}", pc + 2));
+                    }
+                    return Ok(pc + 3);
+                }
+                if target == pc + 1 {
+                    return Ok(pc + 1);
+                }
+                self.settle(f)?;
+                let kind = match lp {
+                    Some(lp) if lp.break_to == target => StatKind::Break,
+                    _ => {
+                        if self.labels.insert(target) {
+                            return Err(Failure::Retry);
+                        }
+                        StatKind::Goto(self.label(target))
+                    }
+                };
+                self.push(f, kind, pc + 1);
+                Ok(pc + 1)
+            }
+            ForPrep { a, bx } => self.numeric_for(f, a, pc, pc + 1 + bx as usize),
+            TForPrep { a, bx } => self.generic_for(f, a, pc, pc + 1 + bx as usize),
+            _ => self.instruction(f, pc),
+        }
+    }
+
+    /* the register of a LFALSESKIP/LOADTRUE pair that the jump at 'pc' goes over */
+    fn skips_pair(&self, pc: usize) -> Option<u8> {
+        use Instruction::*;
+        match (self.code.get(pc), self.code.get(pc + 1), self.code.get(pc + 2)) {
+            (Some(Jmp { sj: 2 }), Some(LFalseSkip { a }), Some(LoadTrue { a: b })) if a == b => Some(*a),
+            _ => None,
+        }
+    }
+
+    fn node(&mut self, f: &mut Frame, pc: usize) -> Result<()> {
+        use Instruction::*;
+        let Some(Jmp { sj }) = self.code.get(pc + 1) else { return error(format!("test without a jump at instruction {}", pc + 1)) };
+        let target = (pc as i64 + 2 + *sj as i64) as usize;
+        let line = self.line(pc);
+        let immediate = |sb: i32, is_float: bool| expr(if is_float { ExprKind::Float(sb as f64) } else { ExprKind::Int(sb as i64) }, line);
+        f.consumed.clear();
+        let (e, jump_if, value_reg) = match self.code[pc] {
+            Eq { a, b, k } | Lt { a, b, k } | Le { a, b, k } => {
+                let op = match self.code[pc] {
+                    Eq { .. } => BinOp::Eq,
+                    Lt { .. } => BinOp::Lt,
+                    _ => BinOp::Le,
+                };
+                let left = self.read(f, a)?;
+                let right = self.read(f, b)?;
+                (binary(op, left, right, line), k, None)
+            }
+            EqK { a, b_k, k } => {
+                let left = self.read(f, a)?;
+                (binary(BinOp::Eq, left, self.constant(b_k as usize, pc)?, line), k, None)
+            }
+            EqI { a, sb, is_float, k } | LtI { a, sb, is_float, k } | LeI { a, sb, is_float, k } | GtI { a, sb, is_float, k } | GeI { a, sb, is_float, k } => {
+                let op = match self.code[pc] {
+                    EqI { .. } => BinOp::Eq,
+                    LtI { .. } => BinOp::Lt,
+                    LeI { .. } => BinOp::Le,
+                    GtI { .. } => BinOp::Gt,
+                    _ => BinOp::Ge,
+                };
+                let left = self.read(f, a)?;
+                (binary(op, left, immediate(sb, is_float), line), k, None)
+            }
+            Test { a, k } => (self.read(f, a)?, k, Some(a)),
+            TestSet { a, b, k } => (self.read(f, b)?, k, Some(a)),
+            _ => unreachable!(),
+        };
+        self.check_order(f)?;
+        let start = f.nodes.last().map_or(f.stat_pc, |node| node.pc + 2);
+        f.nodes.push(Node { start, pc, expr: e, jump_if, target, value_reg });
+        let mut frame = f.clone();
+        frame.stats = Vec::new();
+        frame.nodes = Vec::new();
+        frame.snapshots = Vec::new();
+        f.snapshots.push(Snapshot { frame, stats: f.stats.len() });
+        Ok(())
+    }
+
+    /**
+     * The last tests as and/or, or a comparison, leaving a value in a register at 'fin'.
+     * 'pair' is the LFALSESKIP/LOADTRUE pair turning comparisons into booleans and its
+     * register, and 'jump' the jump over it ending the value computed last
+     */
+    fn reduce(&mut self, f: &mut Frame, fin: usize, pair: Option<(usize, u8)>, jump: Option<usize>) -> Result<bool> {
+        let Some(last) = f.nodes.last() else { return Ok(false) };
+        let fall = last.pc + 2;
+        let (reg, leaf_end) = match (pair, jump) {
+            (Some((_, a)), Some(jump)) => (a, Some(jump)),
+            (Some((p, a)), None) if p == fall => (a, None),
+            (Some(_), None) => return Ok(false),
+            (None, _) => match f.last_write {
+                Some((reg, writer)) if writer >= fall && writer < fin => (reg, Some(fin)),
+                _ => return Ok(false),
+            },
+        };
+        let local = self.local_at(f, reg).filter(|_| !f.slots[reg as usize].is_pending());
+        /* the value computed last, pending or stored into a local */
+        let (leaf, stored) = match leaf_end {
+            None => (None, false),
+            Some(_) => match (&f.slots[reg as usize], local) {
+                (Slot::Value { expr, open: false }, _) if f.writers[reg as usize].is_some_and(|writer| writer >= fall) => (Some(expr.clone()), false),
+                (Slot::Empty, Some(local)) => match f.stats.last() {
+                    Some(Stat { kind: StatKind::Assign { targets, values }, .. }) if targets.len() == 1 && values.len() == 1
+                        && matches!(&targets[0].kind, ExprKind::Name(name) if *name == self.locals[local].name) => (Some(values[0].clone()), true),
+                    _ => return Ok(false),
+                },
+                _ => return Ok(false),
+            },
+        };
+        let (t, fl) = match pair {
+            Some((p, _)) => (vec![fin, p + 1], vec![fin, p]),
+            None => (vec![fin], vec![fin]),
+        };
+        let n = f.nodes.len();
+        for k in 0..n {
+            let from = f.nodes[k].start;
+            if !f.nodes[k..].iter().all(|node| node.target > from && node.target <= fin)
+                || f.nodes[..k].iter().any(|node| node.target > from && node.target < fin)
+                || f.snapshots[k].stats + stored as usize != f.stats.len()
+                || f.snapshots[k].frame.active.len() != f.active.len() {
+                continue;
+            }
+            let nodes = f.nodes[k..].to_vec();
+            let mut pos: Vec<usize> = nodes.iter().map(|node| node.start).collect();
+            pos.push(fall);
+            if leaf.is_some() {
+                pos.push(fin);
+            }
+            let cond = Cond { nodes, leaf: leaf.clone(), pos, fin: Some((fin, reg)) };
+            let Some(e) = cond.parse(&t, &fl) else { continue };
+            f.nodes.truncate(k);
+            f.snapshots.truncate(k);
+            if stored {
+                f.stats.pop();
+                f.stat_pc = from;
+            }
+            match local {
+                Some(local) => {
+                    let target = expr(ExprKind::Name(self.locals[local].name.clone()), self.line(fin - 1));
+                    f.group.push((target, Some(e)));
+                }
+                None => {
+                    f.slots[reg as usize] = Slot::Value { expr: e, open: false };
+                    f.writers[reg as usize] = Some(fin - 1);
+                }
+            }
+            f.last_write = Some((reg, fin - 1));
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+impl Decompiler<'_> {
+    /**
+     * The pending tests as the condition of a statement: the longest run of them leaving
+     * through a single place besides the code after them
+     */
+    fn resolve(&mut self, f: &mut Frame, end: usize, lp: Option<Loop>) -> Result<Option<Expr>> {
+        let nodes = f.nodes.clone();
+        for m in (1..=nodes.len()).rev() {
+            let fall = nodes[m - 1].pc + 2;
+            let inner: Vec<usize> = nodes[1..m].iter().map(|node| node.start).collect();
+            let exits: BTreeSet<usize> = nodes[..m].iter().map(|node| node.target).filter(|target| *target != fall && !inner.contains(target)).collect();
+            if exits.len() > 1 {
+                continue;
+            }
+            let exit = exits.first().copied().unwrap_or(fall);
+            let mut pos: Vec<usize> = nodes[..m].iter().map(|node| node.start).collect();
+            pos.push(fall);
+            let cond = Cond { nodes: nodes[..m].to_vec(), leaf: None, pos, fin: None };
+            let until = lp.and_then(|lp| lp.until).is_some_and(|(head, until_end)| exit == head && fall == until_end);
+            let is_if = exit == fall || (exit > fall && exit <= end);
+            let condition = if is_if || until { cond.parse(&[fall], &[exit]) } else { cond.parse(&[exit], &[fall]) };
+            let Some(condition) = condition else { continue };
+            let snapshot = f.snapshots[m - 1].clone();
+            let mut stats = std::mem::take(&mut f.stats);
+            stats.truncate(snapshot.stats);
+            *f = snapshot.frame;
+            f.stats = stats;
+            self.settle(f)?;
+            if until {
+                return Ok(Some(condition));
+            }
+            let line = self.stat_line(f.stat_pc, fall);
+            let (branches, else_block, next) = if is_if {
+                self.if_stat(f, condition, line, fall, exit, end, lp)?
+            } else {
+                let kind = match lp {
+                    Some(lp) if lp.break_to == exit && exit > end => StatKind::Break,
+                    _ => {
+                        if self.labels.insert(exit) {
+                            return Err(Failure::Retry);
+                        }
+                        StatKind::Goto(self.label(exit))
+                    }
+                };
+                let block = vec![stat(kind, line)];
+                (vec![IfBranch { condition, block, span: span(line) }], None, fall)
+            };
+            self.push(f, StatKind::If { branches, else_block }, next);
+            return Ok(None);
+        }
+        error(format!("can't decompile the condition at instruction {}", nodes[0].pc + 1))
+    }
+
+    /* an if statement whose then part starts at 'fall' and ends at 'exit' or the jump over an else part */
+    #[allow(clippy::too_many_arguments)]
+    fn if_stat(&mut self, f: &Frame, condition: Expr, line: u32, fall: usize, exit: usize, end: usize, lp: Option<Loop>) -> Result<(Vec<IfBranch>, Option<Block>, usize)> {
+        let jump = exit - 1;
+        let else_end = match self.code.get(jump) {
+            Some(Instruction::Jmp { sj }) if exit > fall && *sj >= 0 && exit + *sj as usize <= end && self.skips_pair(jump).is_none()
+                && !(jump > fall && is_test(&self.code[jump - 1])) => Some(exit + *sj as usize),
+            _ => None,
+        };
+        let then_end = if else_end.is_some() { jump } else { exit };
+        let (block, _) = self.block(fall, then_end, f.active.clone(), f.writers.clone(), lp, None)?;
+        let mut branches = vec![IfBranch { condition, block, span: span(line) }];
+        let mut else_block = None;
+        if let Some(else_end) = else_end {
+            let (mut block, _) = self.block(exit, else_end, f.active.clone(), f.writers.clone(), lp, None)?;
+            if let [Stat { kind: StatKind::If { .. }, .. }] = block.as_slice() {
+                let Some(Stat { kind: StatKind::If { branches: more, else_block: last }, .. }) = block.pop() else { unreachable!() };
+                branches.extend(more);
+                else_block = last;
+            } else if !block.is_empty() {
+                else_block = Some(block);
+            }
+        }
+        Ok((branches, else_block, else_end.unwrap_or(exit)))
+    }
+
+    /**
+     * A loop from 'head' to the jump back at 'q': a repeat when the jump is conditional, else
+     * a while whose condition is the test breaking out of it first
+     */
+    fn loop_stat(&mut self, f: &mut Frame, head: usize, q: usize) -> Result<usize> {
+        if q > head && is_test(&self.code[q - 1]) {
+            let lp = Loop { break_to: q + 1, until: Some((head, q + 1)) };
+            let (block, condition) = self.block(head, q + 1, f.active.clone(), f.writers.clone(), Some(lp), Some((head, q)))?;
+            let Some(condition) = condition else { return error(format!("can't find the condition of the loop at instruction {}", q + 1)) };
+            self.push(f, StatKind::Repeat { block, condition }, q + 1);
+        } else {
+            let lp = Loop { break_to: q + 1, until: None };
+            let (mut block, _) = self.block(head, q, f.active.clone(), f.writers.clone(), Some(lp), Some((head, q)))?;
+            let breaks = match block.first() {
+                Some(Stat { kind: StatKind::If { branches, else_block: None }, .. }) => {
+                    branches.len() == 1 && matches!(branches[0].block.as_slice(), [Stat { kind: StatKind::Break, .. }])
+                }
+                _ => false,
+            };
+            let condition = match breaks {
+                true => {
+                    let StatKind::If { mut branches, .. } = block.remove(0).kind else { unreachable!() };
+                    not(branches.remove(0).condition)
+                }
+                false => expr(ExprKind::True, self.line(head)),
+            };
+            self.push(f, StatKind::While { condition, block }, q + 1);
+        }
+        Ok(q + 1)
+    }
+
+    fn numeric_for(&mut self, f: &mut Frame, a: u8, pc: usize, q: usize) -> Result<usize> {
+        let start = self.read(f, a)?;
+        let limit = self.read(f, a + 1)?;
+        let step = self.read(f, a + 2)?;
+        f.consumed.clear();
+        self.settle(f)?;
+        let step = (!matches!(step.kind, ExprKind::Int(1))).then_some(step);
+        let var = self.loop_local(pc, a + 3);
+        let mut active = f.active.clone();
+        active.push(var);
+        let lp = Loop { break_to: q + 1, until: None };
+        let (block, _) = self.block(pc + 1, q, active, f.writers.clone(), Some(lp), None)?;
+        let var = self.local_name(var, self.line(pc));
+        self.push(f, StatKind::NumericFor { var, start, limit, step, block }, q + 1);
+        Ok(q + 1)
+    }
+
+    fn generic_for(&mut self, f: &mut Frame, a: u8, pc: usize, q: usize) -> Result<usize> {
+        let Some(Instruction::TForCall { c, .. }) = self.code.get(q).copied() else {
+            return error(format!("TFORPREP without TFORCALL at instruction {}", pc + 1));
+        };
+        let mut values = self.read_list(f, a, Some(4))?;
+        f.consumed.clear();
+        self.settle(f)?;
+        trim_nils(&mut values);
+        let vars: Vec<usize> = (0..c).map(|i| self.loop_local(pc, a + 4 + i)).collect();
+        let mut active = f.active.clone();
+        active.extend(&vars);
+        let lp = Loop { break_to: q + 2, until: None };
+        let (block, _) = self.block(pc + 1, q, active, f.writers.clone(), Some(lp), None)?;
+        let names = vars.into_iter().map(|var| self.local_name(var, self.line(pc))).collect();
+        self.push(f, StatKind::GenericFor { names, values, block }, q + 2);
+        Ok(q + 2)
+    }
+
+    /* a variable of the loop prepared at 'pc' */
+    fn loop_local(&mut self, pc: usize, reg: u8) -> usize {
+        let local = (0..self.known).find(|&local| self.locals[local].start == pc + 1 && self.locals[local].reg == reg)
+            .unwrap_or_else(|| self.synthesize(pc, reg, pc + 1, "l"));
+        self.announced.insert(local);
+        local
+    }
+
+    /**
+     * Declares the locals starting at 'pc' with the values waiting in their registers
+     */
+    fn declare(&mut self, f: &mut Frame, pc: usize, forced_only: bool) -> Result<()> {
+        let mut new = Vec::new();
+        if !forced_only {
+            for local in 0..self.known {
+                if self.locals[local].start != pc || self.announced.contains(&local) {
+                    continue;
+                }
+                self.announced.insert(local);
+                if self.locals[local].name.as_bytes().starts_with(b"(") {
+                    f.active.push(local);
+                } else {
+                    new.push(local);
+                }
+            }
+        }
+        for reg in 0..f.slots.len() {
+            let reg = reg as u8;
+            if let Some(writer) = self.forced_writer(f, reg, pc) {
+                if !new.iter().any(|&local| self.locals[local].reg == reg) {
+                    new.push(self.synthesize(writer, reg, pc, "l"));
+                }
+            }
+        }
+        if new.is_empty() {
+            return Ok(());
+        }
+        new.sort_by_key(|&local| self.locals[local].reg);
+        let low = self.locals[new[0]].reg;
+        self.end_synthesized(f, low, pc);
+        if let Some(reg) = (self.base(f)..low).find(|&reg| f.slots[reg as usize].is_pending()) {
+            return Err(self.conflict(f, reg));
+        }
+        let mut values = Vec::new();
+        let mut expanded = false;
+        for &local in &new {
+            match std::mem::replace(&mut f.slots[self.locals[local].reg as usize], Slot::Empty) {
+                Slot::Value { expr, open } => {
+                    values.push(expr);
+                    expanded = open;
+                }
+                Slot::Rest => expanded = true,
+                Slot::Empty => {
+                    values.push(expr(ExprKind::Nil, 0));
+                    expanded = false;
+                }
+                Slot::Method { .. } => return error(format!("method in a local at instruction {}", pc)),
+            }
+        }
+        trim_nils(&mut values);
+        if !expanded && values.len() < new.len() {
+            if let Some(last) = values.pop() {
+                let last_span = last.span;
+                values.push(if last.is_multi() { Expr { kind: ExprKind::Paren(Box::new(last)), span: last_span } } else { last });
+            }
+        }
+        let line = self.line(pc.saturating_sub(1));
+        let names = new.iter().map(|&local| self.local_name(local, line)).collect();
+        for &local in &new {
+            self.announced.insert(local);
+            f.declared.push((local, f.stats.len()));
+            f.active.push(local);
+        }
+        self.push(f, StatKind::Local { names, values }, pc);
+        Ok(())
+    }
+
+    /* without debug information, a local ends when its register is closed or declared again */
+    fn end_synthesized(&mut self, f: &mut Frame, reg: u8, pc: usize) {
+        let ending: Vec<usize> = f.active.iter().copied()
+            .filter(|&local| local >= self.known && self.locals[local].reg >= reg && self.locals[local].start < pc)
+            .collect();
+        for &local in &ending {
+            self.locals[local].end = Some(pc);
+        }
+        self.end_locals(f, pc, usize::MAX, false);
+    }
+
+    /* the instruction before 'pc' writing a value pending in 'reg' that is a local */
+    fn forced_writer(&self, f: &Frame, reg: u8, pc: usize) -> Option<usize> {
+        let writer = f.writers[reg as usize].filter(|&writer| writer < pc)?;
+        (f.slots[reg as usize].is_pending() && self.forced.contains(&(writer, reg))).then_some(writer)
+    }
+
+    /**
+     * Ends the scope of the locals ending at 'pc': those starting there too when 'same'. Inside
+     * a block, the statements since their declaration become a do block
+     */
+    fn end_locals(&mut self, f: &mut Frame, pc: usize, end: usize, same: bool) {
+        let ending: Vec<usize> = f.active.iter().copied()
+            .filter(|&local| self.locals[local].end == Some(pc) && (self.locals[local].start == pc) == same)
+            .collect();
+        if ending.is_empty() {
+            return;
+        }
+        let first = f.declared.iter().filter(|(local, _)| ending.contains(local)).map(|(_, index)| *index).min();
+        if let Some(first) = first.filter(|_| pc < end) {
+            let inner = f.stats.split_off(first);
+            let line = inner.first().map_or(0, |first| first.span.start.line);
+            let wrapped: Vec<usize> = f.declared.iter().filter(|(_, index)| *index >= first).map(|(local, _)| *local).collect();
+            f.declared.retain(|(_, index)| *index < first);
+            f.active.retain(|local| !wrapped.contains(local));
+            f.stats.push(stat(StatKind::Do(inner), line));
+        }
+        f.active.retain(|local| !ending.contains(local));
+    }
+
+    /**
+     * Whether pending tests must be settled before 'pc': a label or a loop is there, a scope
+     * changes, or the instruction is a statement of its own
+     */
+    fn is_boundary(&self, f: &Frame, pc: usize, head: Option<(usize, usize)>) -> bool {
+        use Instruction::*;
+        if self.labels.contains(&pc) {
+            return true;
+        }
+        if self.back_jumps.contains_key(&pc) && head.is_none_or(|(h, _)| h != pc) {
+            return true;
+        }
+        if (0..self.known).any(|local| self.locals[local].start == pc || self.locals[local].end == Some(pc)) {
+            return true;
+        }
+        if (0..f.slots.len()).any(|reg| self.forced_writer(f, reg as u8, pc).is_some()) {
+            return true;
+        }
+        match self.code[pc] {
+            instruction if is_test(&instruction) => false,
+            LFalseSkip { .. } | MmBin { .. } | MmBinI { .. } | MmBinK { .. } | ExtraArg { .. } => false,
+            Jmp { .. } => self.skips_pair(pc).is_none(),
+            SetTable { a, .. } | SetI { a, .. } | SetField { a, .. } | SetList { a, .. } => !self.is_constructor(f, a),
+            Call { c: 1, .. } => true,
+            instruction => match dest(&instruction) {
+                /* a local given the last value of and/or is part of it */
+                Some((first, last)) => self.writes_local(f, first, last) && !f.nodes.iter().any(|node| node.value_reg == Some(first) && node.target > pc),
+                None => true,
+            },
+        }
+    }
+
+    /* whether the instruction at 'pc' stores into a local or a table */
+    fn is_store(&self, f: &Frame, pc: usize) -> bool {
+        use Instruction::*;
+        match self.code[pc] {
+            SetTabUp { .. } | SetUpval { .. } => true,
+            SetTable { a, .. } | SetI { a, .. } | SetField { a, .. } => !self.is_constructor(f, a),
+            Call { .. } | VarArg { .. } | NewTable { .. } | Self_ { .. } => false,
+            instruction => dest(&instruction).is_some_and(|(first, last)| self.writes_local(f, first, last)),
+        }
+    }
+
+    fn writes_local(&self, f: &Frame, first: u8, last: u8) -> bool {
+        (first..=last).any(|reg| self.local_at(f, reg).is_some() && !f.slots[reg as usize].is_pending())
+    }
+}
+
+/* registers written by an instruction computing values */
+fn dest(instruction: &Instruction) -> Option<(u8, u8)> {
+    use Instruction::*;
+    match *instruction {
+        LoadNil { a, b } => Some((a, a + b)),
+        Self_ { a, .. } => Some((a, a + 1)),
+        Call { c: 1, .. } => None,
+        Move { a, .. } | LoadI { a, .. } | LoadF { a, .. } | LoadK { a, .. } | LoadKX { a } | LoadFalse { a } | LoadTrue { a }
+        | GetUpval { a, .. } | GetTabUp { a, .. } | GetTable { a, .. } | GetI { a, .. } | GetField { a, .. } | NewTable { a, .. }
+        | AddI { a, .. } | AddK { a, .. } | SubK { a, .. } | MulK { a, .. } | ModK { a, .. } | PowK { a, .. } | DivK { a, .. }
+        | IDivK { a, .. } | BAndK { a, .. } | BOrK { a, .. } | BXorK { a, .. } | ShrI { a, .. } | ShlI { a, .. }
+        | Add { a, .. } | Sub { a, .. } | Mul { a, .. } | Mod { a, .. } | Pow { a, .. } | Div { a, .. } | IDiv { a, .. }
+        | BAnd { a, .. } | BOr { a, .. } | BXor { a, .. } | Shl { a, .. } | Shr { a, .. }
+        | Unm { a, .. } | BNot { a, .. } | Not { a, .. } | Len { a, .. } | Concat { a, .. } | Closure { a, .. }
+        | VarArg { a, .. } | Call { a, .. } => Some((a, a)),
+        _ => None,
+    }
+}
+
+/* drops trailing nils, which locals get anyway, unless they cut a call or '...' to one value */
+fn trim_nils(values: &mut Vec<Expr>) {
+    while let Some(last) = values.last() {
+        let n = values.len();
+        if !matches!(last.kind, ExprKind::Nil) || (n > 1 && values[n - 2].is_multi()) {
+            break;
+        }
+        values.pop();
+    }
+}
+
+/* a return not ending its block goes in a do block */
+fn finish(mut stats: Vec<Stat>) -> Block {
+    let last = stats.len().saturating_sub(1);
+    for (i, s) in stats.iter_mut().enumerate() {
+        if i != last && matches!(s.kind, StatKind::Return(_)) {
+            let line = s.span.start.line;
+            let inner = std::mem::replace(s, stat(StatKind::Break, line));
+            *s = stat(StatKind::Do(vec![inner]), line);
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::decompile_source;
+    use crate::{compiler::codegen::compile, core::{dump::dump, parser::parse_all}, stdlib::test::run_chunk};
+
+    /* the chunk decompiled, compiled again and run, stripped of debug information or not */
+    fn round_trip(chunk: &[u8], strip: bool) -> String {
+        let proto = parse_all(&dump(&parse_all(chunk).unwrap(), strip)).unwrap();
+        let source = decompile_source(&proto).unwrap();
+        let name = proto.fn_name.as_ref().map_or("=?".to_string(), |name| name.to_string());
+        let recompiled = compile(source.as_bytes(), &name).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+        run_chunk(&dump(&recompiled, strip))
+    }
+
+    fn decompile(source: &str) -> String {
+        decompile_source(&compile(source.as_bytes(), "=test").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trip_fixtures() {
+        let fixtures: [(&[u8], &str); 6] = [
+            (include_bytes!("../../helpers/tests/base.out"), include_str!("../../helpers/tests/base.expected")),
+            (include_bytes!("../../helpers/tests/debug.out"), include_str!("../../helpers/tests/debug.expected")),
+            (include_bytes!("../../helpers/tests/string.out"), include_str!("../../helpers/tests/string.expected")),
+            (include_bytes!("../../helpers/tests/table.out"), include_str!("../../helpers/tests/table.expected")),
+            (include_bytes!("../../helpers/tests/math.out"), include_str!("../../helpers/tests/math.expected")),
+            (include_bytes!("../../helpers/tests/utf8.out"), include_str!("../../helpers/tests/utf8.expected")),
+        ];
+        for (chunk, expected) in fixtures {
+            assert_eq!(round_trip(chunk, false), expected);
+        }
+    }
+
+    /* base and debug print names of locals, which stripped chunks don't have */
+    #[test]
+    fn round_trip_stripped() {
+        let fixtures: [&[u8]; 4] = [
+            include_bytes!("../../helpers/tests/string.out"),
+            include_bytes!("../../helpers/tests/table.out"),
+            include_bytes!("../../helpers/tests/math.out"),
+            include_bytes!("../../helpers/tests/utf8.out"),
+        ];
+        for chunk in fixtures {
+            assert_eq!(round_trip(chunk, true), run_chunk(&dump(&parse_all(chunk).unwrap(), true)));
+        }
+    }
+
+    /* keywords without instructions of their own go on the nearest line free for them */
+    #[test]
+    fn statements() {
+        let source = "local t = {}\nfor i = 1, 10 do\n  if i % 2 == 0 then\n    t[#t + 1] = i\n  elseif i > 7 then\n    break\n  end\nend\nlocal n = 0\nwhile n < 3 do\n  n = n + 1\nend\nrepeat\n  n = n - 1\nuntil n == 0\nprint(t:concat(\",\"), n > 0 and \"up\" or \"down\")\n";
+        let decompiled = "local t = {}\nfor i = 1, 10 do\n  if i % 2 == 0 then\n    t[#t + 1] = i\n  elseif i > 7 then break\n  end\nend\n\nlocal n = 0\nwhile n < 3 do\n  n = n + 1\nend\n\nrepeat n = n - 1\nuntil n == 0\nprint(t:concat(\",\"), n > 0 and \"up\" or \"down\")\n";
+        assert_eq!(decompile(source), decompiled);
+        assert_eq!(decompile(decompiled), decompiled);
+    }
+}
@@ -0,0 +1,849 @@
+/*
+ * Lexical analyzer for Lua 5.4 source (llex.c). Works on bytes: names are ASCII, everything
+ * else in strings and comments passes through untouched
+ */
+
+use std::{borrow::Cow, rc::Rc};
+
+use crate::{core::types::{TValue, number, string::{LuaString, utf8_escape}}, vm::debug::chunk_id};
+
+/* chunks can't have more lines than this */
+const MAX_LINES: u32 = i32::MAX as u32;
+
+/* the UTF-8 byte order mark skipped at the start of source files */
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/**
+ * Lexical errors, and the syntax errors built on them, are full messages such as
+ * "file.lua:3: unfinished string near '\"abc'"
+ */
+pub type LexResult<T> = Result<T, String>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /* reserved words (ORDER RESERVED) */
+    And, Break, Do, Else, Elseif, End, False, For, Function, Goto, If, In, Local, Nil, Not, Or,
+    Repeat, Return, Then, True, Until, While,
+    /* multi-byte symbols */
+    IDiv, Concat, Dots, Eq, Ge, Le, Ne, Shl, Shr, DbColon,
+    Eos,
+    Float(f64),
+    Int(i64),
+    Name(Rc<LuaString>),
+    String(Rc<LuaString>),
+    /* single-byte symbols ('+', '{', ...) */
+    Char(u8),
+}
+
+impl Token {
+    pub(super) fn reserved(word: &[u8]) -> Option<Token> {
+        Some(match word {
+            b"and" => Token::And,
+            b"break" => Token::Break,
+            b"do" => Token::Do,
+            b"else" => Token::Else,
+            b"elseif" => Token::Elseif,
+            b"end" => Token::End,
+            b"false" => Token::False,
+            b"for" => Token::For,
+            b"function" => Token::Function,
+            b"goto" => Token::Goto,
+            b"if" => Token::If,
+            b"in" => Token::In,
+            b"local" => Token::Local,
+            b"nil" => Token::Nil,
+            b"not" => Token::Not,
+            b"or" => Token::Or,
+            b"repeat" => Token::Repeat,
+            b"return" => Token::Return,
+            b"then" => Token::Then,
+            b"true" => Token::True,
+            b"until" => Token::Until,
+            b"while" => Token::While,
+            _ => return None,
+        })
+    }
+
+    /**
+     * luaX_tokens: the fixed text of reserved words and symbols, the class of the others
+     */
+    pub fn text(&self) -> Cow<'static, str> {
+        Cow::Borrowed(match self {
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::IDiv => "//",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Eq => "==",
+            Token::Ge => ">=",
+            Token::Le => "<=",
+            Token::Ne => "~=",
+            Token::Shl => "<<",
+            Token::Shr => ">>",
+            Token::DbColon => "::",
+            Token::Eos => "<eof>",
+            Token::Float(_) => "<number>",
+            Token::Int(_) => "<integer>",
+            Token::Name(_) => "<name>",
+            Token::String(_) => "<string>",
+            Token::Char(c) => return Cow::Owned((*c as char).to_string()),
+        })
+    }
+
+    /**
+     * luaX_token2str: symbols and reserved words are quoted, control characters shown by code
+     */
+    pub fn describe(&self) -> String {
+        match self {
+            Token::Char(c) if !is_print(*c) => format!("'<\\{}>'", c),
+            Token::Eos | Token::Float(_) | Token::Int(_) | Token::Name(_) | Token::String(_) => self.text().into_owned(),
+            _ => format!("'{}'", self.text()),
+        }
+    }
+
+    /* tokens whose error text is the source they were read from */
+    fn has_source_text(&self) -> bool {
+        matches!(self, Token::Float(_) | Token::Int(_) | Token::Name(_) | Token::String(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub line: u32,
+    /* 1-based, in bytes */
+    pub column: u32,
+}
+
+/**
+ * Where a token was read from: 'end' is just past its last byte
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+fn is_alpha(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_alnum(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+fn is_print(c: u8) -> bool {
+    (0x20..0x7f).contains(&c)
+}
+
+fn is_newline(c: Option<u8>) -> bool {
+    matches!(c, Some(b'\n' | b'\r'))
+}
+
+fn hex_value(c: u8) -> u32 {
+    (c as char).to_digit(16).unwrap_or(0)
+}
+
+/**
+ * What luaL_loadfilex skips before a text chunk: a UTF-8 BOM and a first line starting with
+ * '#' (as in "#!/usr/bin/lua"). The line's newline is kept so line numbers don't change
+ */
+pub fn skip_comment(chunk: &[u8]) -> &[u8] {
+    let chunk = chunk.strip_prefix(BOM).unwrap_or(chunk);
+    if chunk.first() != Some(&b'#') {
+        return chunk;
+    }
+    match chunk.iter().position(|c| *c == b'\n') {
+        Some(newline) => &chunk[newline..],
+        None => b"\n",
+    }
+}
+
+pub struct Lexer<'a> {
+    source: &'a [u8],
+    chunk_name: String,
+    /* index of 'current' in 'source' */
+    offset: usize,
+    current: Option<u8>,
+    line: u32,
+    column: u32,
+    /* line of the last token consumed */
+    last_line: u32,
+    /* text of the token being read; raw text of the current token for error messages */
+    buffer: Vec<u8>,
+    token: SpannedToken,
+    lookahead: Option<SpannedToken>,
+}
+
+impl<'a> Lexer<'a> {
+    /**
+     * luaX_setinput: there is no current token until the first call to 'next'
+     */
+    pub fn new(source: &'a [u8], chunk_name: &str) -> Self {
+        Self {
+            source,
+            chunk_name: chunk_name.to_string(),
+            offset: 0,
+            current: source.first().copied(),
+            line: 1,
+            column: 1,
+            last_line: 1,
+            buffer: Vec::new(),
+            token: SpannedToken { token: Token::Eos, span: Span::default() },
+            lookahead: None,
+        }
+    }
+
+    pub fn chunk_name(&self) -> &str {
+        &self.chunk_name
+    }
+
+    pub fn token(&self) -> &Token {
+        &self.token.token
+    }
+
+    pub fn span(&self) -> Span {
+        self.token.span
+    }
+
+    /**
+     * The line being read, which is past the current token when it ended a line
+     */
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn last_line(&self) -> u32 {
+        self.last_line
+    }
+
+    /**
+     * luaX_next
+     */
+    pub fn next(&mut self) -> LexResult<()> {
+        self.last_line = self.line;
+        self.token = match self.lookahead.take() {
+            Some(token) => token,
+            None => self.scan()?,
+        };
+        Ok(())
+    }
+
+    /**
+     * luaX_lookahead: the token after the current one, without consuming it
+     */
+    pub fn lookahead(&mut self) -> LexResult<&Token> {
+        debug_assert!(self.lookahead.is_none());
+        let token = self.scan()?;
+        Ok(&self.lookahead.insert(token).token)
+    }
+
+    /**
+     * luaX_syntaxerror: a message about the current token
+     */
+    pub fn syntax_error(&self, message: &str) -> String {
+        let near = self.near(&self.token.token);
+        self.error(message, Some(&near))
+    }
+
+    /**
+     * lexerror: 'message' at the current line, followed by what it was read near
+     */
+    pub fn error(&self, message: &str, near: Option<&str>) -> String {
+        let message = format!("{}:{}: {}", chunk_id(&self.chunk_name), self.line, message);
+        match near {
+            Some(near) => format!("{} near {}", message, near),
+            None => message,
+        }
+    }
+
+    /**
+     * txtToken: names, strings and numerals are shown as they were written
+     */
+    fn near(&self, token: &Token) -> String {
+        if token.has_source_text() {
+            self.buffer_text()
+        } else {
+            token.describe()
+        }
+    }
+
+    fn buffer_text(&self) -> String {
+        /* the C message ends at the first '\0' */
+        let end = self.buffer.iter().position(|c| *c == 0).unwrap_or(self.buffer.len());
+        format!("'{}'", String::from_utf8_lossy(&self.buffer[..end]))
+    }
+
+    fn lex_error(&self, message: &str, near_buffer: bool) -> String {
+        if near_buffer {
+            self.error(message, Some(&self.buffer_text()))
+        } else {
+            self.error(message, Some(&Token::Eos.describe()))
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position { line: self.line, column: self.column }
+    }
+
+    fn next_char(&mut self) {
+        if self.current.is_some() {
+            self.offset += 1;
+            self.column += 1;
+        }
+        self.current = self.source.get(self.offset).copied();
+    }
+
+    fn save(&mut self, c: u8) {
+        self.buffer.push(c);
+    }
+
+    fn save_and_next(&mut self) {
+        if let Some(c) = self.current {
+            self.save(c);
+        }
+        self.next_char();
+    }
+
+    fn remove_saved(&mut self, count: usize) {
+        self.buffer.truncate(self.buffer.len() - count);
+    }
+
+    /**
+     * inclinenumber: skips any of \n, \r, \n\r or \r\n
+     */
+    fn inc_line_number(&mut self) -> LexResult<()> {
+        let old = self.current;
+        self.next_char();
+        if is_newline(self.current) && self.current != old {
+            self.next_char();
+        }
+        self.line += 1;
+        self.column = 1;
+        if self.line >= MAX_LINES {
+            return Err(self.error("chunk has too many lines", None));
+        }
+        Ok(())
+    }
+
+    fn check_next1(&mut self, c: u8) -> bool {
+        if self.current == Some(c) {
+            self.next_char();
+            true
+        } else {
+            false
+        }
+    }
+
+    /**
+     * check_next2: saves the current char when it is one of 'set'
+     */
+    fn check_next2(&mut self, set: &[u8; 2]) -> bool {
+        match self.current {
+            Some(c) if set.contains(&c) => {
+                self.save_and_next();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /**
+     * read_numeral: liberal about what it accepts, luaO_str2num rejects ill-formed numerals.
+     * The caller might have already read an initial dot
+     */
+    fn read_numeral(&mut self) -> LexResult<Token> {
+        let mut exponent = b"Ee";
+        let first = self.current;
+        self.save_and_next();
+        if first == Some(b'0') && self.check_next2(b"xX") {
+            exponent = b"Pp";
+        }
+        loop {
+            if self.check_next2(exponent) {
+                self.check_next2(b"-+");
+            } else if matches!(self.current, Some(c) if c.is_ascii_hexdigit() || c == b'.') {
+                self.save_and_next();
+            } else {
+                break;
+            }
+        }
+        /* a numeral touching a letter is forced into an error */
+        if matches!(self.current, Some(c) if is_alpha(c)) {
+            self.save_and_next();
+        }
+        match number::str_to_number(&self.buffer) {
+            Some(TValue::NUMINT(i)) => Ok(Token::Int(i)),
+            Some(TValue::NUMFLT(f)) => Ok(Token::Float(f)),
+            _ => Err(self.lex_error("malformed number", true)),
+        }
+    }
+
+    /**
+     * skip_sep: reads '[=*[' or ']=*]' leaving the last bracket. Returns the number of '='s
+     * plus 2 when well formed, 1 for a single bracket and 0 for an unfinished '[==...'
+     */
+    fn skip_sep(&mut self) -> usize {
+        let mut count = 0;
+        let bracket = self.current;
+        self.save_and_next();
+        while self.current == Some(b'=') {
+            self.save_and_next();
+            count += 1;
+        }
+        if self.current == bracket {
+            count + 2
+        } else if count == 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    /**
+     * read_long_string: comments aren't kept, 'is_string' tells which one is being read
+     */
+    fn read_long_string(&mut self, is_string: bool, sep: usize) -> LexResult<Option<Token>> {
+        let line = self.line;
+        self.save_and_next();
+        if is_newline(self.current) {
+            self.inc_line_number()?;
+        }
+        loop {
+            match self.current {
+                None => {
+                    let what = if is_string { "string" } else { "comment" };
+                    let message = format!("unfinished long {} (starting at line {})", what, line);
+                    return Err(self.lex_error(&message, false));
+                },
+                Some(b']') => {
+                    if self.skip_sep() == sep {
+                        self.save_and_next();
+                        break;
+                    }
+                },
+                Some(b'\n' | b'\r') => {
+                    self.save(b'\n');
+                    self.inc_line_number()?;
+                    if !is_string {
+                        self.buffer.clear();
+                    }
+                },
+                Some(_) => {
+                    if is_string {
+                        self.save_and_next();
+                    } else {
+                        self.next_char();
+                    }
+                },
+            }
+        }
+        Ok(is_string.then(|| {
+            let contents = self.buffer[sep..self.buffer.len() - sep].to_vec();
+            Token::String(Rc::new(LuaString::new(contents)))
+        }))
+    }
+
+    /**
+     * esccheck: the offending char goes into the message
+     */
+    fn escape_check(&mut self, condition: bool, message: &str) -> LexResult<()> {
+        if !condition {
+            if self.current.is_some() {
+                self.save_and_next();
+            }
+            return Err(self.lex_error(message, true));
+        }
+        Ok(())
+    }
+
+    fn get_hexa(&mut self) -> LexResult<u32> {
+        self.save_and_next();
+        let current = self.current;
+        self.escape_check(matches!(current, Some(c) if c.is_ascii_hexdigit()), "hexadecimal digit expected")?;
+        Ok(hex_value(current.unwrap_or(0)))
+    }
+
+    fn read_hexa_escape(&mut self) -> LexResult<u8> {
+        let r = self.get_hexa()?;
+        let r = (r << 4) + self.get_hexa()?;
+        self.remove_saved(2);
+        Ok(r as u8)
+    }
+
+    fn read_utf8_escape(&mut self) -> LexResult<u32> {
+        /* chars to be removed: '\', 'u', '{', and first digit */
+        let mut count = 4;
+        self.save_and_next();
+        self.escape_check(self.current == Some(b'{'), "missing '{'")?;
+        let mut r = self.get_hexa()?;
+        loop {
+            self.save_and_next();
+            let digit = match self.current {
+                Some(c) if c.is_ascii_hexdigit() => hex_value(c),
+                _ => break,
+            };
+            count += 1;
+            self.escape_check(r <= (0x7FFF_FFFF >> 4), "UTF-8 value too large")?;
+            r = (r << 4) + digit;
+        }
+        self.escape_check(self.current == Some(b'}'), "missing '}'")?;
+        self.next_char();
+        self.remove_saved(count);
+        Ok(r)
+    }
+
+    fn read_decimal_escape(&mut self) -> LexResult<u8> {
+        let mut r = 0;
+        let mut count = 0;
+        while count < 3 {
+            match self.current {
+                Some(c) if c.is_ascii_digit() => r = 10 * r + (c - b'0') as u32,
+                _ => break,
+            }
+            self.save_and_next();
+            count += 1;
+        }
+        self.escape_check(r <= u8::MAX as u32, "decimal escape too large")?;
+        self.remove_saved(count);
+        Ok(r as u8)
+    }
+
+    /**
+     * read_string: the delimiters and escapes are saved as read until the escape is decoded,
+     * so errors show the source text
+     */
+    fn read_string(&mut self, delimiter: u8) -> LexResult<Token> {
+        self.save_and_next();
+        while self.current != Some(delimiter) {
+            match self.current {
+                None => return Err(self.lex_error("unfinished string", false)),
+                Some(b'\n' | b'\r') => return Err(self.lex_error("unfinished string", true)),
+                Some(b'\\') => {
+                    self.save_and_next();
+                    let c = match self.current {
+                        Some(escape @ (b'a' | b'b' | b'f' | b'n' | b'r' | b't' | b'v' | b'\\' | b'"' | b'\'')) => {
+                            self.next_char();
+                            Some(match escape {
+                                b'a' => 0x07,
+                                b'b' => 0x08,
+                                b'f' => 0x0c,
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'v' => 0x0b,
+                                _ => escape,
+                            })
+                        },
+                        Some(b'x') => {
+                            let c = self.read_hexa_escape()?;
+                            self.next_char();
+                            Some(c)
+                        },
+                        Some(b'u') => {
+                            let code = self.read_utf8_escape()?;
+                            utf8_escape(&mut self.buffer, code);
+                            None
+                        },
+                        Some(b'\n' | b'\r') => {
+                            self.inc_line_number()?;
+                            Some(b'\n')
+                        },
+                        /* will raise an error next loop */
+                        None => None,
+                        Some(b'z') => {
+                            self.remove_saved(1);
+                            self.next_char();
+                            while matches!(self.current, Some(c) if number::is_space(c)) {
+                                if is_newline(self.current) {
+                                    self.inc_line_number()?;
+                                } else {
+                                    self.next_char();
+                                }
+                            }
+                            None
+                        },
+                        Some(c) => {
+                            self.escape_check(c.is_ascii_digit(), "invalid escape sequence")?;
+                            Some(self.read_decimal_escape()?)
+                        },
+                    };
+                    if let Some(c) = c {
+                        self.remove_saved(1);
+                        self.save(c);
+                    }
+                },
+                Some(_) => self.save_and_next(),
+            }
+        }
+        self.save_and_next();
+        let contents = self.buffer[1..self.buffer.len() - 1].to_vec();
+        Ok(Token::String(Rc::new(LuaString::new(contents))))
+    }
+
+    /**
+     * llex: the next token and where it was read
+     */
+    fn scan(&mut self) -> LexResult<SpannedToken> {
+        self.buffer.clear();
+        loop {
+            let start = self.position();
+            let token = match self.current {
+                Some(b'\n' | b'\r') => {
+                    self.inc_line_number()?;
+                    continue;
+                },
+                Some(b' ' | 0x0c | b'\t' | 0x0b) => {
+                    self.next_char();
+                    continue;
+                },
+                Some(b'-') => {
+                    self.next_char();
+                    if self.current != Some(b'-') {
+                        Token::Char(b'-')
+                    } else {
+                        self.next_char();
+                        if self.current == Some(b'[') {
+                            let sep = self.skip_sep();
+                            self.buffer.clear();
+                            if sep >= 2 {
+                                self.read_long_string(false, sep)?;
+                                self.buffer.clear();
+                                continue;
+                            }
+                        }
+                        while !is_newline(self.current) && self.current.is_some() {
+                            self.next_char();
+                        }
+                        continue;
+                    }
+                },
+                Some(b'[') => {
+                    let sep = self.skip_sep();
+                    if sep >= 2 {
+                        self.read_long_string(true, sep)?.expect("long strings are kept")
+                    } else if sep == 0 {
+                        return Err(self.lex_error("invalid long string delimiter", true));
+                    } else {
+                        Token::Char(b'[')
+                    }
+                },
+                Some(b'=') => {
+                    self.next_char();
+                    if self.check_next1(b'=') { Token::Eq } else { Token::Char(b'=') }
+                },
+                Some(b'<') => {
+                    self.next_char();
+                    if self.check_next1(b'=') {
+                        Token::Le
+                    } else if self.check_next1(b'<') {
+                        Token::Shl
+                    } else {
+                        Token::Char(b'<')
+                    }
+                },
+                Some(b'>') => {
+                    self.next_char();
+                    if self.check_next1(b'=') {
+                        Token::Ge
+                    } else if self.check_next1(b'>') {
+                        Token::Shr
+                    } else {
+                        Token::Char(b'>')
+                    }
+                },
+                Some(b'/') => {
+                    self.next_char();
+                    if self.check_next1(b'/') { Token::IDiv } else { Token::Char(b'/') }
+                },
+                Some(b'~') => {
+                    self.next_char();
+                    if self.check_next1(b'=') { Token::Ne } else { Token::Char(b'~') }
+                },
+                Some(b':') => {
+                    self.next_char();
+                    if self.check_next1(b':') { Token::DbColon } else { Token::Char(b':') }
+                },
+                Some(delimiter @ (b'"' | b'\'')) => self.read_string(delimiter)?,
+                Some(b'.') => {
+                    self.save_and_next();
+                    if self.check_next1(b'.') {
+                        if self.check_next1(b'.') { Token::Dots } else { Token::Concat }
+                    } else if !matches!(self.current, Some(c) if c.is_ascii_digit()) {
+                        Token::Char(b'.')
+                    } else {
+                        self.read_numeral()?
+                    }
+                },
+                Some(b'0'..=b'9') => self.read_numeral()?,
+                None => Token::Eos,
+                Some(c) if is_alpha(c) => {
+                    while matches!(self.current, Some(c) if is_alnum(c)) {
+                        self.save_and_next();
+                    }
+                    Token::reserved(&self.buffer)
+                        .unwrap_or_else(|| Token::Name(Rc::new(LuaString::new(self.buffer.clone()))))
+                },
+                Some(c) => {
+                    self.next_char();
+                    Token::Char(c)
+                },
+            };
+            return Ok(SpannedToken { token, span: Span { start, end: self.position() } });
+        }
+    }
+}
+
+/**
+ * Every token of a chunk, ending with Eos
+ */
+pub fn tokenize(source: &[u8], chunk_name: &str) -> LexResult<Vec<SpannedToken>> {
+    Ok(tokenize_with_text(source, chunk_name)?.into_iter().map(|(token, _)| token).collect())
+}
+
+/**
+ * Every token of a chunk along with how a syntax error shows it when it is the current one
+ */
+pub fn tokenize_with_text(source: &[u8], chunk_name: &str) -> LexResult<Vec<(SpannedToken, String)>> {
+    let mut lexer = Lexer::new(source, chunk_name);
+    let mut tokens = Vec::new();
+    loop {
+        lexer.next()?;
+        let near = lexer.near(&lexer.token.token);
+        let eos = lexer.token.token == Token::Eos;
+        tokens.push((lexer.token.clone(), near));
+        if eos {
+            return Ok(tokens);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use super::{tokenize, skip_comment, Token, Span, Position};
+
+    fn tokens(source: &str) -> Vec<Token> {
+        tokenize(source.as_bytes(), "=src").unwrap().into_iter().map(|t| t.token).collect()
+    }
+
+    fn name(name: &str) -> Token {
+        Token::Name(Rc::new(name.into()))
+    }
+
+    fn string(bytes: &[u8]) -> Token {
+        Token::String(Rc::new(bytes.into()))
+    }
+
+    fn error(source: &str) -> String {
+        tokenize(source.as_bytes(), "=src").unwrap_err()
+    }
+
+    #[test]
+    fn symbols_and_reserved_words() {
+        let source = "local function f(...) return a // b .. c ~= d >> 1 <= ::x:: end --[==[ long\n]==] -- short\n#";
+        let expected = [
+            Token::Local, Token::Function, name("f"), Token::Char(b'('), Token::Dots,
+            Token::Char(b')'), Token::Return, name("a"), Token::IDiv,
+            name("b"), Token::Concat, name("c"), Token::Ne,
+            name("d"), Token::Shr, Token::Int(1), Token::Le, Token::DbColon,
+            name("x"), Token::DbColon, Token::End, Token::Char(b'#'), Token::Eos,
+        ];
+        assert_eq!(tokens(source), expected);
+    }
+
+    #[test]
+    fn spans_track_lines_and_columns() {
+        let spans: Vec<Span> = tokenize(b"x =\r\n  'ab'\n\n[[\nz]]", "=src").unwrap().into_iter().map(|t| t.span).collect();
+        let span = |l1, c1, l2, c2| Span { start: Position { line: l1, column: c1 }, end: Position { line: l2, column: c2 } };
+        assert_eq!(spans, [span(1, 1, 1, 2), span(1, 3, 1, 4), span(2, 3, 2, 7), span(4, 1, 5, 4), span(5, 4, 5, 4)]);
+    }
+
+    #[test]
+    fn numerals() {
+        assert_eq!(tokens("3 0x10 3. .5e1 0x1p4 0x.8 1e309 0xffffffffffffffff 9223372036854775807 9223372036854775808"), [
+            Token::Int(3), Token::Int(16), Token::Float(3.0), Token::Float(5.0), Token::Float(16.0), Token::Float(0.5),
+            Token::Float(f64::INFINITY), Token::Int(-1), Token::Int(i64::MAX), Token::Float(9223372036854775808.0), Token::Eos,
+        ]);
+        assert_eq!(tokens("3-4 0xe+1"), [Token::Int(3), Token::Char(b'-'), Token::Int(4), Token::Int(0xe), Token::Char(b'+'), Token::Int(1), Token::Eos]);
+    }
+
+    #[test]
+    fn strings_and_escapes() {
+        assert_eq!(tokens(r#""\65\066\x43\u{44}\u{7FFFFFFF}\a\\\"" '\z
+              b\
+c'"#), [string(b"ABCD\xFD\xBF\xBF\xBF\xBF\xBF\x07\\\""), string(b"b\nc"), Token::Eos]);
+        assert_eq!(tokens("[==[\nx]]=]==] [[\r\n\r\n]]"), [string(b"x]]="), string(b"\n"), Token::Eos]);
+    }
+
+    #[test]
+    fn errors_match_reference() {
+        let cases = [
+            ("x = \"abc", "src:1: unfinished string near <eof>"),
+            ("x = \"abc\ny\"", "src:1: unfinished string near '\"abc'"),
+            ("x = [==[abc", "src:1: unfinished long string (starting at line 1) near <eof>"),
+            ("--[[ abc", "src:1: unfinished long comment (starting at line 1) near <eof>"),
+            ("x = [=", "src:1: invalid long string delimiter near '[='"),
+            ("x = \"\\q\"", "src:1: invalid escape sequence near '\"\\q'"),
+            ("x = \"\\xZZ\"", "src:1: hexadecimal digit expected near '\"\\xZ'"),
+            ("x = \"\\u{110000000}\"", "src:1: UTF-8 value too large near '\"\\u{110000000'"),
+            ("x = \"\\u{12\"", "src:1: missing '}' near '\"\\u{12\"'"),
+            ("x = \"\\u12\"", "src:1: missing '{' near '\"\\u1'"),
+            ("x = \"\\300\"", "src:1: decimal escape too large near '\"\\300\"'"),
+            ("x = 3x", "src:1: malformed number near '3x'"),
+            ("x = 0x", "src:1: malformed number near '0x'"),
+            ("x = 1e+", "src:1: malformed number near '1e+'"),
+            ("x = 3..4", "src:1: malformed number near '3..4'"),
+            ("x = '\\1\\2\\", "src:1: unfinished string near <eof>"),
+            ("x = \"\\z  \n\n  abc", "src:3: unfinished string near <eof>"),
+            ("x = 08.5e", "src:1: malformed number near '08.5e'"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(error(source), expected, "{:?}", source);
+        }
+        assert_eq!(tokenize(b"'", "@file.lua").unwrap_err(), "file.lua:1: unfinished string near <eof>");
+    }
+
+    #[test]
+    fn describe_tokens_like_reference() {
+        assert_eq!(Token::Char(b'@').describe(), "'@'");
+        assert_eq!(Token::Char(1).describe(), "'<\\1>'");
+        assert_eq!(Token::Elseif.describe(), "'elseif'");
+        assert_eq!(Token::Eos.describe(), "<eof>");
+        assert_eq!(name("x").describe(), "<name>");
+    }
+
+    #[test]
+    fn shebang_and_bom_are_skipped() {
+        assert_eq!(skip_comment(b"#!/usr/bin/lua\nprint(1)"), b"\nprint(1)");
+        assert_eq!(skip_comment(b"\xEF\xBB\xBF# comment"), b"\n");
+        assert_eq!(skip_comment(b"\xEF\xBB\xBFprint(1)"), b"print(1)");
+        assert_eq!(skip_comment(b"print(1)"), b"print(1)");
+    }
+}
@@ -0,0 +1,10 @@
+/*
+ * Compiling Lua source into function prototypes
+ */
+
+pub mod ast;
+pub mod codegen;
+pub mod lexer;
+pub mod parser;
+pub mod printer;
+pub mod decompile;
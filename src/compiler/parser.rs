@@ -0,0 +1,879 @@
+/*
+ * Recursive descent parser for Lua 5.4 (the grammar half of lparser.c). Syntax errors are the
+ * messages luac gives; checks that need scopes (const variables, gotos, limits) are left to
+ * code generation
+ */
+
+use crate::core::types::LUAI_MAXCCALLS;
+
+use super::{ast::{Chunk, Block, Stat, StatKind, IfBranch, LocalName, Attrib, FunctionBody, Expr, ExprKind, Field, BinOp, UnOp, Name, UNARY_PRIORITY}, lexer::{Lexer, Token, Span, Position, LexResult}};
+
+/**
+ * Parses a whole chunk; 'chunk_name' is the source as in luaL_loadbuffer ("@file", "=name"...)
+ */
+pub fn parse(source: &[u8], chunk_name: &str) -> LexResult<Chunk> {
+    Parser::new(source, chunk_name).main_func()
+}
+
+fn unary_op(token: &Token) -> Option<UnOp> {
+    match token {
+        Token::Not => Some(UnOp::Not),
+        Token::Char(b'-') => Some(UnOp::Minus),
+        Token::Char(b'~') => Some(UnOp::BNot),
+        Token::Char(b'#') => Some(UnOp::Len),
+        _ => None,
+    }
+}
+
+fn binary_op(token: &Token) -> Option<BinOp> {
+    Some(match token {
+        Token::Char(b'+') => BinOp::Add,
+        Token::Char(b'-') => BinOp::Sub,
+        Token::Char(b'*') => BinOp::Mul,
+        Token::Char(b'%') => BinOp::Mod,
+        Token::Char(b'^') => BinOp::Pow,
+        Token::Char(b'/') => BinOp::Div,
+        Token::IDiv => BinOp::IDiv,
+        Token::Char(b'&') => BinOp::BAnd,
+        Token::Char(b'|') => BinOp::BOr,
+        Token::Char(b'~') => BinOp::BXor,
+        Token::Shl => BinOp::Shl,
+        Token::Shr => BinOp::Shr,
+        Token::Concat => BinOp::Concat,
+        Token::Ne => BinOp::Ne,
+        Token::Eq => BinOp::Eq,
+        Token::Char(b'<') => BinOp::Lt,
+        Token::Le => BinOp::Le,
+        Token::Char(b'>') => BinOp::Gt,
+        Token::Ge => BinOp::Ge,
+        Token::And => BinOp::And,
+        Token::Or => BinOp::Or,
+        _ => return None,
+    })
+}
+
+fn is_variable(expr: &Expr) -> bool {
+    matches!(expr.kind, ExprKind::Name(_) | ExprKind::Index { .. })
+}
+
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    /* end of the last token consumed */
+    last_end: Position,
+    /* nesting of statements and expressions, bounded as the C stack is */
+    level: usize,
+    /* whether the functions being parsed are vararg, innermost last */
+    vararg: Vec<bool>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(source: &'a [u8], chunk_name: &str) -> Self {
+        Self {
+            lexer: Lexer::new(source, chunk_name),
+            last_end: Position { line: 1, column: 1 },
+            level: 0,
+            vararg: Vec::new(),
+        }
+    }
+
+    fn token(&self) -> &Token {
+        self.lexer.token()
+    }
+
+    fn next(&mut self) -> LexResult<()> {
+        self.last_end = self.lexer.span().end;
+        self.lexer.next()
+    }
+
+    fn start(&self) -> Position {
+        self.lexer.span().start
+    }
+
+    /**
+     * From 'start' to the end of the last token consumed
+     */
+    fn span_from(&self, start: Position) -> Span {
+        Span { start, end: self.last_end }
+    }
+
+    /**
+     * luaE_incCstack
+     */
+    fn enter_level(&mut self) -> LexResult<()> {
+        self.level += 1;
+        if self.level >= LUAI_MAXCCALLS {
+            return Err("C stack overflow".to_string());
+        }
+        Ok(())
+    }
+
+    fn leave_level(&mut self) {
+        self.level -= 1;
+    }
+
+    fn error_expected(&self, token: &Token) -> String {
+        self.lexer.syntax_error(&format!("{} expected", token.describe()))
+    }
+
+    fn test_next(&mut self, token: &Token) -> LexResult<bool> {
+        if self.token() == token {
+            self.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn check(&self, token: &Token) -> LexResult<()> {
+        if self.token() != token {
+            return Err(self.error_expected(token));
+        }
+        Ok(())
+    }
+
+    fn check_next(&mut self, token: &Token) -> LexResult<()> {
+        self.check(token)?;
+        self.next()
+    }
+
+    /**
+     * check_match: 'what' closes 'who' opened at 'line', which the message mentions when it
+     * isn't the current one
+     */
+    fn check_match(&mut self, what: &Token, who: &Token, line: u32) -> LexResult<()> {
+        if self.test_next(what)? {
+            return Ok(());
+        }
+        if line == self.lexer.line() {
+            Err(self.error_expected(what))
+        } else {
+            Err(self.lexer.syntax_error(&format!("{} expected (to close {} at line {})", what.describe(), who.describe(), line)))
+        }
+    }
+
+    /**
+     * str_checkname
+     */
+    fn check_name(&mut self) -> LexResult<(Name, Span)> {
+        match self.token() {
+            Token::Name(name) => {
+                let result = (name.clone(), self.lexer.span());
+                self.next()?;
+                Ok(result)
+            },
+            _ => Err(self.lexer.syntax_error("<name> expected")),
+        }
+    }
+
+    /**
+     * luaK_semerror: errors that aren't about the current token
+     */
+    fn semantic_error(&self, message: &str) -> String {
+        self.lexer.error(message, None)
+    }
+
+    fn block_follow(&self, with_until: bool) -> bool {
+        match self.token() {
+            Token::Else | Token::Elseif | Token::End | Token::Eos => true,
+            Token::Until => with_until,
+            _ => false,
+        }
+    }
+
+    /**
+     * mainfunc: the main function is always vararg
+     */
+    fn main_func(mut self) -> LexResult<Chunk> {
+        self.vararg.push(true);
+        self.next()?;
+        let start = self.start();
+        let block = self.statlist()?;
+        self.check(&Token::Eos)?;
+        Ok(Chunk { block, span: self.span_from(start) })
+    }
+
+    /**
+     * statlist -> { stat [';'] }, with 'return' as the last statement
+     */
+    fn statlist(&mut self) -> LexResult<Block> {
+        let mut block = Vec::new();
+        while !self.block_follow(true) {
+            let is_return = self.token() == &Token::Return;
+            block.extend(self.statement()?);
+            if is_return {
+                break;
+            }
+        }
+        Ok(block)
+    }
+
+    /**
+     * Empty statements (';') leave no node
+     */
+    fn statement(&mut self) -> LexResult<Option<Stat>> {
+        let start = self.start();
+        let line = self.lexer.line();
+        self.enter_level()?;
+        let kind = match self.token().clone() {
+            Token::Char(b';') => {
+                self.next()?;
+                self.leave_level();
+                return Ok(None);
+            },
+            Token::If => self.if_stat(line)?,
+            Token::While => self.while_stat(line)?,
+            Token::Do => {
+                self.next()?;
+                let block = self.statlist()?;
+                self.check_match(&Token::End, &Token::Do, line)?;
+                StatKind::Do(block)
+            },
+            Token::For => self.for_stat(line)?,
+            Token::Repeat => self.repeat_stat(line)?,
+            Token::Function => self.func_stat(line)?,
+            Token::Local => {
+                self.next()?;
+                if self.test_next(&Token::Function)? {
+                    self.local_func()?
+                } else {
+                    self.local_stat()?
+                }
+            },
+            Token::DbColon => {
+                self.next()?;
+                let (name, _) = self.check_name()?;
+                self.check_next(&Token::DbColon)?;
+                StatKind::Label(name)
+            },
+            Token::Return => {
+                self.next()?;
+                self.ret_stat()?
+            },
+            Token::Break => {
+                self.next()?;
+                StatKind::Break
+            },
+            Token::Goto => {
+                self.next()?;
+                StatKind::Goto(self.check_name()?.0)
+            },
+            _ => self.expr_stat()?,
+        };
+        self.leave_level();
+        Ok(Some(Stat { kind, span: self.span_from(start) }))
+    }
+
+    /**
+     * test_then_block -> [IF | ELSEIF] cond THEN block
+     */
+    fn test_then_block(&mut self) -> LexResult<IfBranch> {
+        let start = self.start();
+        self.next()?;
+        let condition = self.expr()?;
+        self.check_next(&Token::Then)?;
+        let block = self.statlist()?;
+        Ok(IfBranch { condition, block, span: self.span_from(start) })
+    }
+
+    /**
+     * ifstat -> IF cond THEN block {ELSEIF cond THEN block} [ELSE block] END
+     */
+    fn if_stat(&mut self, line: u32) -> LexResult<StatKind> {
+        let mut branches = vec![self.test_then_block()?];
+        while self.token() == &Token::Elseif {
+            branches.push(self.test_then_block()?);
+        }
+        let else_block = if self.test_next(&Token::Else)? { Some(self.statlist()?) } else { None };
+        self.check_match(&Token::End, &Token::If, line)?;
+        Ok(StatKind::If { branches, else_block })
+    }
+
+    /**
+     * whilestat -> WHILE cond DO block END
+     */
+    fn while_stat(&mut self, line: u32) -> LexResult<StatKind> {
+        self.next()?;
+        let condition = self.expr()?;
+        self.check_next(&Token::Do)?;
+        let block = self.statlist()?;
+        self.check_match(&Token::End, &Token::While, line)?;
+        Ok(StatKind::While { condition, block })
+    }
+
+    /**
+     * repeatstat -> REPEAT block UNTIL cond
+     */
+    fn repeat_stat(&mut self, line: u32) -> LexResult<StatKind> {
+        self.next()?;
+        let block = self.statlist()?;
+        self.check_match(&Token::Until, &Token::Repeat, line)?;
+        let condition = self.expr()?;
+        Ok(StatKind::Repeat { block, condition })
+    }
+
+    /**
+     * forstat -> FOR (fornum | forlist) END
+     */
+    fn for_stat(&mut self, line: u32) -> LexResult<StatKind> {
+        self.next()?;
+        let (name, span) = self.check_name()?;
+        let var = LocalName { name, attrib: None, span };
+        let kind = match self.token() {
+            Token::Char(b'=') => self.for_num(var)?,
+            Token::Char(b',') | Token::In => self.for_list(var)?,
+            _ => return Err(self.lexer.syntax_error("'=' or 'in' expected")),
+        };
+        self.check_match(&Token::End, &Token::For, line)?;
+        Ok(kind)
+    }
+
+    /**
+     * fornum -> NAME = exp,exp[,exp] forbody
+     */
+    fn for_num(&mut self, var: LocalName) -> LexResult<StatKind> {
+        self.next()?;
+        let start = self.expr()?;
+        self.check_next(&Token::Char(b','))?;
+        let limit = self.expr()?;
+        let step = if self.test_next(&Token::Char(b','))? { Some(self.expr()?) } else { None };
+        let block = self.for_body()?;
+        Ok(StatKind::NumericFor { var, start, limit, step, block })
+    }
+
+    /**
+     * forlist -> NAME {,NAME} IN explist forbody
+     */
+    fn for_list(&mut self, var: LocalName) -> LexResult<StatKind> {
+        let mut names = vec![var];
+        while self.test_next(&Token::Char(b','))? {
+            let (name, span) = self.check_name()?;
+            names.push(LocalName { name, attrib: None, span });
+        }
+        self.check_next(&Token::In)?;
+        let values = self.explist()?;
+        let block = self.for_body()?;
+        Ok(StatKind::GenericFor { names, values, block })
+    }
+
+    /**
+     * forbody -> DO block
+     */
+    fn for_body(&mut self) -> LexResult<Block> {
+        self.check_next(&Token::Do)?;
+        self.statlist()
+    }
+
+    /**
+     * funcstat -> FUNCTION funcname body, funcname -> NAME {'.' NAME} [':' NAME]
+     */
+    fn func_stat(&mut self, line: u32) -> LexResult<StatKind> {
+        self.next()?;
+        let mut path = vec![self.check_name()?.0];
+        while self.test_next(&Token::Char(b'.'))? {
+            path.push(self.check_name()?.0);
+        }
+        let method = if self.test_next(&Token::Char(b':'))? { Some(self.check_name()?.0) } else { None };
+        let body = self.body(method.is_some(), line)?;
+        Ok(StatKind::Function { path, method, body: Box::new(body) })
+    }
+
+    fn local_func(&mut self) -> LexResult<StatKind> {
+        let (name, span) = self.check_name()?;
+        let line = self.lexer.line();
+        let body = self.body(false, line)?;
+        Ok(StatKind::LocalFunction { name: LocalName { name, attrib: None, span }, body: Box::new(body) })
+    }
+
+    /**
+     * getlocalattribute -> ['<' NAME '>']
+     */
+    fn local_attrib(&mut self) -> LexResult<Option<Attrib>> {
+        if !self.test_next(&Token::Char(b'<'))? {
+            return Ok(None);
+        }
+        let (attrib, _) = self.check_name()?;
+        self.check_next(&Token::Char(b'>'))?;
+        match attrib.as_bytes() {
+            b"const" => Ok(Some(Attrib::Const)),
+            b"close" => Ok(Some(Attrib::Close)),
+            _ => Err(self.semantic_error(&format!("unknown attribute '{}'", attrib.to_str_lossy()))),
+        }
+    }
+
+    /**
+     * localstat -> LOCAL NAME attrib { ',' NAME attrib } ['=' explist]
+     */
+    fn local_stat(&mut self) -> LexResult<StatKind> {
+        let mut names = Vec::new();
+        let mut has_close = false;
+        loop {
+            let (name, span) = self.check_name()?;
+            let attrib = self.local_attrib()?;
+            if attrib == Some(Attrib::Close) {
+                if has_close {
+                    return Err(self.semantic_error("multiple to-be-closed variables in local list"));
+                }
+                has_close = true;
+            }
+            names.push(LocalName { name, attrib, span });
+            if !self.test_next(&Token::Char(b','))? {
+                break;
+            }
+        }
+        let values = if self.test_next(&Token::Char(b'='))? { self.explist()? } else { Vec::new() };
+        Ok(StatKind::Local { names, values })
+    }
+
+    /**
+     * retstat -> RETURN [explist] [';']
+     */
+    fn ret_stat(&mut self) -> LexResult<StatKind> {
+        let values = if self.block_follow(true) || self.token() == &Token::Char(b';') {
+            Vec::new()
+        } else {
+            self.explist()?
+        };
+        self.test_next(&Token::Char(b';'))?;
+        Ok(StatKind::Return(values))
+    }
+
+    /**
+     * exprstat -> func | assignment
+     */
+    fn expr_stat(&mut self) -> LexResult<StatKind> {
+        let expr = self.suffixed_exp()?;
+        if matches!(self.token(), Token::Char(b'=' | b',')) {
+            return self.rest_assign(expr);
+        }
+        if !matches!(expr.kind, ExprKind::Call { .. } | ExprKind::MethodCall { .. }) {
+            return Err(self.lexer.syntax_error("syntax error"));
+        }
+        Ok(StatKind::Call(expr))
+    }
+
+    /**
+     * restassign -> ',' suffixedexp restassign | '=' explist. Each extra target is a level
+     * of recursion in C
+     */
+    fn rest_assign(&mut self, first: Expr) -> LexResult<StatKind> {
+        let mut targets = vec![first];
+        let mut levels = 0;
+        loop {
+            if !is_variable(targets.last().unwrap()) {
+                return Err(self.lexer.syntax_error("syntax error"));
+            }
+            if !self.test_next(&Token::Char(b','))? {
+                break;
+            }
+            targets.push(self.suffixed_exp()?);
+            self.enter_level()?;
+            levels += 1;
+        }
+        self.check_next(&Token::Char(b'='))?;
+        let values = self.explist()?;
+        self.level -= levels;
+        Ok(StatKind::Assign { targets, values })
+    }
+
+    /**
+     * body -> '(' parlist ')' block END. 'line' is where the function is said to be defined
+     */
+    fn body(&mut self, is_method: bool, line: u32) -> LexResult<FunctionBody> {
+        let start = self.start();
+        self.check_next(&Token::Char(b'('))?;
+        let mut params = Vec::new();
+        let mut is_vararg = false;
+        if self.token() != &Token::Char(b')') {
+            loop {
+                match self.token() {
+                    Token::Name(_) => {
+                        let (name, span) = self.check_name()?;
+                        params.push(LocalName { name, attrib: None, span });
+                    },
+                    Token::Dots => {
+                        self.next()?;
+                        is_vararg = true;
+                    },
+                    _ => return Err(self.lexer.syntax_error("<name> or '...' expected")),
+                }
+                if is_vararg || !self.test_next(&Token::Char(b','))? {
+                    break;
+                }
+            }
+        }
+        self.check_next(&Token::Char(b')'))?;
+        self.vararg.push(is_vararg);
+        let block = self.statlist()?;
+        self.vararg.pop();
+        self.check_match(&Token::End, &Token::Function, line)?;
+        Ok(FunctionBody { params, is_vararg, is_method, block, span: self.span_from(start) })
+    }
+
+    /**
+     * explist -> expr { ',' expr }
+     */
+    fn explist(&mut self) -> LexResult<Vec<Expr>> {
+        let mut list = vec![self.expr()?];
+        while self.test_next(&Token::Char(b','))? {
+            list.push(self.expr()?);
+        }
+        Ok(list)
+    }
+
+    pub fn expr(&mut self) -> LexResult<Expr> {
+        Ok(self.subexpr(0)?.0)
+    }
+
+    /**
+     * subexpr -> (simpleexp | unop subexpr) { binop subexpr }, where binop is any binary
+     * operator with a priority higher than 'limit'. Returns the operator that stopped it
+     */
+    fn subexpr(&mut self, limit: u8) -> LexResult<(Expr, Option<BinOp>)> {
+        self.enter_level()?;
+        let start = self.start();
+        let mut expr = match unary_op(self.token()) {
+            Some(op) => {
+                let op_span = self.lexer.span();
+                self.next()?;
+                let operand = self.subexpr(UNARY_PRIORITY)?.0;
+                Expr { kind: ExprKind::Unary { op, operand: Box::new(operand), op_span }, span: self.span_from(start) }
+            },
+            None => self.simple_exp()?,
+        };
+        let mut op = binary_op(self.token());
+        while let Some(binop) = op {
+            let (left_priority, right_priority) = binop.priority();
+            if left_priority <= limit {
+                break;
+            }
+            let op_span = self.lexer.span();
+            self.next()?;
+            let (right, next_op) = self.subexpr(right_priority)?;
+            let kind = ExprKind::Binary { op: binop, left: Box::new(expr), right: Box::new(right), op_span };
+            expr = Expr { kind, span: self.span_from(start) };
+            op = next_op;
+        }
+        self.leave_level();
+        Ok((expr, op))
+    }
+
+    /**
+     * simpleexp -> FLT | INT | STRING | NIL | TRUE | FALSE | ... | constructor |
+     *              FUNCTION body | suffixedexp
+     */
+    fn simple_exp(&mut self) -> LexResult<Expr> {
+        let start = self.start();
+        let kind = match self.token().clone() {
+            Token::Float(f) => ExprKind::Float(f),
+            Token::Int(i) => ExprKind::Int(i),
+            Token::String(s) => ExprKind::String(s),
+            Token::Nil => ExprKind::Nil,
+            Token::True => ExprKind::True,
+            Token::False => ExprKind::False,
+            Token::Dots => {
+                if self.vararg.last() != Some(&true) {
+                    return Err(self.lexer.syntax_error("cannot use '...' outside a vararg function"));
+                }
+                ExprKind::Vararg
+            },
+            Token::Char(b'{') => return self.constructor(),
+            Token::Function => {
+                self.next()?;
+                let line = self.lexer.line();
+                let body = self.body(false, line)?;
+                return Ok(Expr { kind: ExprKind::Function(Box::new(body)), span: self.span_from(start) });
+            },
+            _ => return self.suffixed_exp(),
+        };
+        self.next()?;
+        Ok(Expr { kind, span: self.span_from(start) })
+    }
+
+    /**
+     * primaryexp -> NAME | '(' expr ')'
+     */
+    fn primary_exp(&mut self) -> LexResult<Expr> {
+        let start = self.start();
+        let kind = match self.token().clone() {
+            Token::Char(b'(') => {
+                let line = self.lexer.line();
+                self.next()?;
+                let inner = self.expr()?;
+                self.check_match(&Token::Char(b')'), &Token::Char(b'('), line)?;
+                ExprKind::Paren(Box::new(inner))
+            },
+            Token::Name(name) => {
+                self.next()?;
+                ExprKind::Name(name)
+            },
+            _ => return Err(self.lexer.syntax_error("unexpected symbol")),
+        };
+        Ok(Expr { kind, span: self.span_from(start) })
+    }
+
+    /**
+     * suffixedexp -> primaryexp { '.' NAME | '[' exp ']' | ':' NAME funcargs | funcargs }
+     */
+    fn suffixed_exp(&mut self) -> LexResult<Expr> {
+        let start = self.start();
+        let mut expr = self.primary_exp()?;
+        loop {
+            let kind = match self.token() {
+                Token::Char(b'.') => {
+                    self.next()?;
+                    let (name, span) = self.check_name()?;
+                    let key = Expr { kind: ExprKind::String(name), span };
+                    ExprKind::Index { object: Box::new(expr), key: Box::new(key) }
+                },
+                Token::Char(b'[') => {
+                    let key = self.y_index()?;
+                    ExprKind::Index { object: Box::new(expr), key: Box::new(key) }
+                },
+                Token::Char(b':') => {
+                    self.next()?;
+                    let (method, _) = self.check_name()?;
+                    let args = self.func_args()?;
+                    ExprKind::MethodCall { object: Box::new(expr), method, args }
+                },
+                Token::Char(b'(' | b'{') | Token::String(_) => {
+                    let args = self.func_args()?;
+                    ExprKind::Call { function: Box::new(expr), args }
+                },
+                _ => return Ok(expr),
+            };
+            expr = Expr { kind, span: self.span_from(start) };
+        }
+    }
+
+    /**
+     * funcargs -> '(' [ explist ] ')' | constructor | STRING
+     */
+    fn func_args(&mut self) -> LexResult<Vec<Expr>> {
+        let line = self.lexer.line();
+        match self.token().clone() {
+            Token::Char(b'(') => {
+                self.next()?;
+                let args = if self.token() == &Token::Char(b')') { Vec::new() } else { self.explist()? };
+                self.check_match(&Token::Char(b')'), &Token::Char(b'('), line)?;
+                Ok(args)
+            },
+            Token::Char(b'{') => Ok(vec![self.constructor()?]),
+            Token::String(s) => {
+                let span = self.lexer.span();
+                self.next()?;
+                Ok(vec![Expr { kind: ExprKind::String(s), span }])
+            },
+            _ => Err(self.lexer.syntax_error("function arguments expected")),
+        }
+    }
+
+    /**
+     * yindex -> '[' expr ']'
+     */
+    fn y_index(&mut self) -> LexResult<Expr> {
+        self.next()?;
+        let key = self.expr()?;
+        self.check_next(&Token::Char(b']'))?;
+        Ok(key)
+    }
+
+    /**
+     * constructor -> '{' [ field { sep field } [sep] ] '}', sep -> ',' | ';'
+     */
+    fn constructor(&mut self) -> LexResult<Expr> {
+        let start = self.start();
+        let line = self.lexer.line();
+        self.check_next(&Token::Char(b'{'))?;
+        let mut fields = Vec::new();
+        while self.token() != &Token::Char(b'}') {
+            fields.push(self.field()?);
+            if !self.test_next(&Token::Char(b','))? && !self.test_next(&Token::Char(b';'))? {
+                break;
+            }
+        }
+        self.check_match(&Token::Char(b'}'), &Token::Char(b'{'), line)?;
+        Ok(Expr { kind: ExprKind::Table(fields), span: self.span_from(start) })
+    }
+
+    /**
+     * field -> listfield | recfield, recfield -> (NAME | '['exp']') = exp
+     */
+    fn field(&mut self) -> LexResult<Field> {
+        match self.token() {
+            Token::Name(_) => {
+                if self.lexer.lookahead()? != &Token::Char(b'=') {
+                    return Ok(Field::Positional(self.expr()?));
+                }
+                let (key, key_span) = self.check_name()?;
+                self.check_next(&Token::Char(b'='))?;
+                Ok(Field::Named { key, key_span, value: self.expr()? })
+            },
+            Token::Char(b'[') => {
+                let key = self.y_index()?;
+                self.check_next(&Token::Char(b'='))?;
+                Ok(Field::Keyed { key, value: self.expr()? })
+            },
+            _ => Ok(Field::Positional(self.expr()?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+    use crate::compiler::ast::{Block, Stat, StatKind, Expr, ExprKind, Field, Attrib};
+
+    /* compact s-expressions, enough to check the shape of trees */
+    fn show_expr(expr: &Expr) -> String {
+        let list = |exprs: &[Expr]| exprs.iter().map(show_expr).collect::<Vec<_>>().join(" ");
+        match &expr.kind {
+            ExprKind::Nil => "nil".into(),
+            ExprKind::True => "true".into(),
+            ExprKind::False => "false".into(),
+            ExprKind::Vararg => "...".into(),
+            ExprKind::Int(i) => i.to_string(),
+            ExprKind::Float(f) => format!("{:?}", f),
+            ExprKind::String(s) => format!("{:?}", s.to_str_lossy()),
+            ExprKind::Function(body) => format!("(function {} {})", body.params.len(), show_block(&body.block)),
+            ExprKind::Table(fields) => {
+                let fields: Vec<String> = fields.iter().map(|field| match field {
+                    Field::Positional(value) => show_expr(value),
+                    Field::Named { key, value, .. } => format!("{}={}", key.to_str_lossy(), show_expr(value)),
+                    Field::Keyed { key, value } => format!("[{}]={}", show_expr(key), show_expr(value)),
+                }).collect();
+                format!("{{{}}}", fields.join(" "))
+            },
+            ExprKind::Name(name) => name.to_str_lossy().into_owned(),
+            ExprKind::Index { object, key } => format!("{}[{}]", show_expr(object), show_expr(key)),
+            ExprKind::Call { function, args } => format!("(call {} {})", show_expr(function), list(args)),
+            ExprKind::MethodCall { object, method, args } => format!("(self {} {} {})", show_expr(object), method.to_str_lossy(), list(args)),
+            ExprKind::Paren(inner) => format!("(paren {})", show_expr(inner)),
+            ExprKind::Binary { op, left, right, .. } => format!("({:?} {} {})", op, show_expr(left), show_expr(right)),
+            ExprKind::Unary { op, operand, .. } => format!("({:?} {})", op, show_expr(operand)),
+        }
+    }
+
+    fn show_block(block: &Block) -> String {
+        block.iter().map(show_stat).collect::<Vec<_>>().join("; ")
+    }
+
+    fn show_stat(stat: &Stat) -> String {
+        let list = |exprs: &[Expr]| exprs.iter().map(show_expr).collect::<Vec<_>>().join(" ");
+        match &stat.kind {
+            StatKind::Call(call) => show_expr(call),
+            StatKind::Assign { targets, values } => format!("{} = {}", list(targets), list(values)),
+            StatKind::Local { names, values } => {
+                let names: Vec<String> = names.iter().map(|n| match n.attrib {
+                    Some(Attrib::Const) => format!("{}<const>", n.name.to_str_lossy()),
+                    Some(Attrib::Close) => format!("{}<close>", n.name.to_str_lossy()),
+                    None => n.name.to_str_lossy().into_owned(),
+                }).collect();
+                format!("local {} = {}", names.join(" "), list(values))
+            },
+            StatKind::Goto(name) => format!("goto {}", name.to_str_lossy()),
+            StatKind::Label(name) => format!("::{}::", name.to_str_lossy()),
+            StatKind::Return(values) => format!("return {}", list(values)),
+            StatKind::Break => "break".into(),
+            StatKind::Do(block) => format!("do [{}]", show_block(block)),
+            StatKind::Function { path, method, body } => {
+                let path: Vec<String> = path.iter().map(|name| name.to_str_lossy().into_owned()).collect();
+                let method = method.as_ref().map(|m| format!(":{}", m.to_str_lossy())).unwrap_or_default();
+                format!("function {}{} {} [{}]", path.join("."), method, body.is_method, show_block(&body.block))
+            },
+            other => format!("{:?}", std::mem::discriminant(other)),
+        }
+    }
+
+    fn parse_block(source: &str) -> String {
+        show_block(&parse(source.as_bytes(), "=src").unwrap().block)
+    }
+
+    fn error(source: &str) -> String {
+        parse(source.as_bytes(), "=src").unwrap_err()
+    }
+
+    #[test]
+    fn operator_precedence_and_associativity() {
+        assert_eq!(parse_block("return 1 + 2 * 3 ^ -4 ^ 5, a .. b .. c, not a == b, a or b and c, 1 << 2 & 3 | 4 ~ 5, - - x // 2"),
+            "return (Add 1 (Mul 2 (Pow 3 (Minus (Pow 4 5))))) (Concat a (Concat b c)) (Eq (Not a) b) \
+             (Or a (And b c)) (BOr (BAnd (Shl 1 2) 3) (BXor 4 5)) (IDiv (Minus (Minus x)) 2)");
+        assert_eq!(parse_block("return #t > 1 ~= 2 <= a - b - c"), "return (Le (Ne (Gt (Len t) 1) 2) (Sub (Sub a b) c))");
+    }
+
+    #[test]
+    fn calls_indexing_and_assignments() {
+        assert_eq!(parse_block("a.b.c:m(1)('s'){x = 1, [2] = 3; 4,} s:upper() f'x' a, b[1], c.d = (f()), ..."),
+            "(call (call (self a[\"b\"][\"c\"] m 1) \"s\") {x=1 [2]=3 4}); (self s upper ); (call f \"x\"); \
+             a b[1] c[\"d\"] = (paren (call f )) ...");
+        assert_eq!(parse_block("local x <const>, y <close>, z = 1 function a.b:c(...) return self end"),
+            "local x<const> y<close> z = 1; function a.b:c true [return self]");
+        assert_eq!(parse_block("goto continue ::continue:: ;;; do break end"), "goto continue; ::continue::; do [break]");
+        assert_eq!(parse_block("return function(a, b, ...) return ... end"), "return (function 2 return ...)");
+    }
+
+    #[test]
+    fn spans_cover_source() {
+        let chunk = parse(b"local t = {\n  1,\n}\nprint(t)", "=src").unwrap();
+        assert_eq!((chunk.block[0].span.start.line, chunk.block[0].span.end.line), (1, 3));
+        assert_eq!((chunk.block[1].span.start.column, chunk.block[1].span.end.column), (1, 9));
+        assert_eq!((chunk.span.start.line, chunk.span.end.line), (1, 4));
+    }
+
+    #[test]
+    fn syntax_errors_match_reference() {
+        let cases = [
+            ("x = ", "src:1: unexpected symbol near <eof>"),
+            ("local function f()\n  return 1\n\n", "src:4: 'end' expected (to close 'function' at line 1) near <eof>"),
+            ("if x then", "src:1: 'end' expected near <eof>"),
+            ("if x then\nelse", "src:2: 'end' expected (to close 'if' at line 1) near <eof>"),
+            ("x = {1,\n 2", "src:2: '}' expected (to close '{' at line 1) near <eof>"),
+            ("x y", "src:1: syntax error near 'y'"),
+            ("1 = 2", "src:1: unexpected symbol near '1'"),
+            ("f() = 1", "src:1: syntax error near '='"),
+            ("x.y:z = 1", "src:1: function arguments expected near '='"),
+            ("local 1", "src:1: <name> expected near '1'"),
+            ("local x <foo> = 1", "src:1: unknown attribute 'foo'"),
+            ("local x <close>, y <close> = 1, 2", "src:1: multiple to-be-closed variables in local list"),
+            ("function f() return ... end", "src:1: cannot use '...' outside a vararg function near '...'"),
+            ("for x do end", "src:1: '=' or 'in' expected near 'do'"),
+            ("for x, y = 1 do end", "src:1: 'in' expected near '='"),
+            ("return 1 x", "src:1: <eof> expected near 'x'"),
+            ("x = function() end end", "src:1: <eof> expected near 'end'"),
+            ("::a", "src:1: '::' expected near <eof>"),
+            ("repeat x = 1", "src:1: 'until' expected near <eof>"),
+            ("x = a[1", "src:1: ']' expected near <eof>"),
+            ("function a.b:c.d() end", "src:1: '(' expected near '.'"),
+            ("(f)", "src:1: syntax error near <eof>"),
+            ("a, b", "src:1: '=' expected near <eof>"),
+            ("a, f() = 1", "src:1: syntax error near '='"),
+            ("for i = 1, 2, 3, 4 do end", "src:1: 'do' expected near ','"),
+            ("x = a.1", "src:1: unexpected symbol near '.1'"),
+            ("x ==== 2", "src:1: syntax error near '=='"),
+            ("return;;", "src:1: <eof> expected near ';'"),
+            ("x = {a b}", "src:1: '}' expected near 'b'"),
+            ("x = \u{1}", "src:1: unexpected symbol near '<\\1>'"),
+            ("local function g(a, b,) end", "src:1: <name> or '...' expected near ')'"),
+            ("function f(...,a) end", "src:1: ')' expected near ','"),
+            ("f(1,\n2", "src:2: ')' expected (to close '(' at line 1) near <eof>"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(error(source), expected, "{:?}", source);
+        }
+        assert!(parse(b"local x <const> = ... f{} = 1", "=src").is_err());
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |n| format!("return {}1{}", "(".repeat(n), ")".repeat(n));
+        assert!(parse(nested(150).as_bytes(), "=src").is_ok());
+        assert_eq!(parse(nested(300).as_bytes(), "=src").unwrap_err(), "C stack overflow");
+    }
+
+    #[test]
+    fn parses_library_fixtures() {
+        for source in [&include_bytes!("../../helpers/tests/string.lua")[..], include_bytes!("../../helpers/tests/debug.lua"),
+                       include_bytes!("../../helpers/tests/base.lua"), include_bytes!("../../helpers/closures.lua")] {
+            parse(source, "=src").unwrap();
+        }
+    }
+}
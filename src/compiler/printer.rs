@@ -0,0 +1,547 @@
+/*
+ * Printing syntax trees as Lua source. Statements, expressions and function ends go to the
+ * lines their spans give when they have them, so the source recompiles with the same line
+ * information; nodes without lines (default spans) start statements on a new line
+ */
+
+use super::{ast::{Chunk, Block, Stat, StatKind, IfBranch, LocalName, Attrib, FunctionBody, Expr, ExprKind, Field, BinOp, UnOp, UNARY_PRIORITY}, lexer::Token};
+
+/**
+ * Source for 'chunk'
+ */
+pub fn print_chunk(chunk: &Chunk) -> String {
+    let mut printer = Printer { out: String::new(), line: 1, depth: 0, fresh: true, opened: false };
+    for (i, stat) in chunk.block.iter().enumerate() {
+        let limit = chunk.block.get(i + 1).map_or(0, |next| next.span.start.line);
+        printer.stat(stat, limit);
+    }
+    if !printer.fresh {
+        printer.out.push('\n');
+    }
+    printer.out
+}
+
+/**
+ * Whether 'name' can be written as a name: 'a.name', 'name = ...'
+ */
+pub fn is_name(name: &[u8]) -> bool {
+    match name.split_first() {
+        Some((first, rest)) => {
+            (first.is_ascii_alphabetic() || *first == b'_')
+                && rest.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+                && Token::reserved(name).is_none()
+        },
+        None => false,
+    }
+}
+
+/**
+ * A string literal, with the escapes that keep it printable
+ */
+pub fn quote(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() + 2);
+    out.push('"');
+    for &c in bytes {
+        match c {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(c as char),
+            /* three digits, so a digit after it can't extend it */
+            _ => out.push_str(&format!("\\{:03}", c)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn float_literal(n: f64) -> String {
+    if n.is_nan() {
+        "(0/0)".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "1e999" } else { "-1e999" }.to_string()
+    } else {
+        /* Debug keeps a '.0' or an exponent, so the literal stays a float */
+        format!("{:?}", n)
+    }
+}
+
+/* expressions printed with a leading '-', which parse as unary minus */
+fn is_negative(expr: &Expr) -> bool {
+    match expr.kind {
+        ExprKind::Int(n) => n < 0 && n != i64::MIN,
+        ExprKind::Float(n) => n.is_sign_negative() && !n.is_nan(),
+        ExprKind::Unary { op: UnOp::Minus, .. } => true,
+        _ => false,
+    }
+}
+
+fn is_unary(expr: &Expr) -> bool {
+    matches!(expr.kind, ExprKind::Unary { .. }) || is_negative(expr)
+}
+
+/* expressions that can be called or indexed without parentheses */
+fn is_prefix(expr: &Expr) -> bool {
+    matches!(expr.kind, ExprKind::Name(_) | ExprKind::Index { .. } | ExprKind::Call { .. } | ExprKind::MethodCall { .. } | ExprKind::Paren(_))
+}
+
+/* a statement starting with '(' would continue a call in the previous one */
+fn starts_with_paren(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Name(_) => false,
+        ExprKind::Index { object, .. } | ExprKind::MethodCall { object, .. } => starts_with_paren(object),
+        ExprKind::Call { function, .. } => starts_with_paren(function),
+        _ => true,
+    }
+}
+
+fn binary_text(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Mod => "%",
+        BinOp::Pow => "^",
+        BinOp::Div => "/",
+        BinOp::IDiv => "//",
+        BinOp::BAnd => "&",
+        BinOp::BOr => "|",
+        BinOp::BXor => "~",
+        BinOp::Shl => "<<",
+        BinOp::Shr => ">>",
+        BinOp::Concat => "..",
+        BinOp::Eq => "==",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Ne => "~=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+        BinOp::And => "and",
+        BinOp::Or => "or",
+    }
+}
+
+struct Printer {
+    out: String,
+    /* line being written */
+    line: u32,
+    /* blocks the current line is nested in */
+    depth: usize,
+    /* nothing written on the current line yet */
+    fresh: bool,
+    /* a block was just opened: its first statement needs no ';' */
+    opened: bool,
+}
+
+impl Printer {
+    fn write(&mut self, text: &str) {
+        if self.fresh {
+            for _ in 0..self.depth {
+                self.out.push_str("  ");
+            }
+            self.fresh = false;
+        }
+        self.out.push_str(text);
+    }
+
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.line += 1;
+        self.fresh = true;
+    }
+
+    /* moves forward to 'line', never back */
+    fn at(&mut self, line: u32) {
+        while self.line < line {
+            self.newline();
+        }
+    }
+
+    fn start_stat(&mut self, line: u32) {
+        if line > self.line {
+            self.at(line);
+        } else if !self.fresh {
+            if line == 0 {
+                self.newline();
+            } else {
+                self.write(if self.opened { " " } else { "; " });
+            }
+        }
+        self.opened = false;
+    }
+
+    /**
+     * A keyword closing a block, on its own line unless that would push the code after it,
+     * which starts at 'limit', past its line
+     */
+    fn close(&mut self, keyword: &str, limit: u32) {
+        if !self.fresh {
+            if limit == 0 || self.line < limit {
+                self.newline();
+            } else {
+                self.write(" ");
+            }
+        }
+        self.write(keyword);
+    }
+
+    /* 'end' stays on the line closing the block when the code after it starts on the next line */
+    fn end(&mut self, limit: u32) {
+        self.close("end", if limit > 1 { limit - 1 } else { limit });
+    }
+
+    fn block(&mut self, block: &Block, limit: u32) {
+        self.depth += 1;
+        self.opened = true;
+        for (i, stat) in block.iter().enumerate() {
+            let next = block.get(i + 1).map_or(limit, |next| next.span.start.line);
+            self.stat(stat, next);
+        }
+        self.opened = false;
+        self.depth -= 1;
+    }
+
+    fn stat(&mut self, stat: &Stat, limit: u32) {
+        self.start_stat(stat.span.start.line);
+        match &stat.kind {
+            StatKind::Call(call) => {
+                if starts_with_paren(call) {
+                    self.write(";");
+                }
+                self.expr(call);
+            },
+            StatKind::Assign { targets, values } => {
+                if starts_with_paren(&targets[0]) {
+                    self.write(";");
+                }
+                self.exprs(targets);
+                self.write(" = ");
+                self.exprs(values);
+            },
+            StatKind::Local { names, values } => {
+                self.write("local ");
+                self.local_names(names);
+                if !values.is_empty() {
+                    self.write(" = ");
+                    self.exprs(values);
+                }
+            },
+            StatKind::LocalFunction { name, body } => {
+                self.write("local function ");
+                self.write(&name.name.to_string());
+                self.body(body, limit);
+            },
+            StatKind::Function { path, method, body } => {
+                self.write("function ");
+                let path: Vec<String> = path.iter().map(|name| name.to_string()).collect();
+                self.write(&path.join("."));
+                if let Some(method) = method {
+                    self.write(":");
+                    self.write(&method.to_string());
+                }
+                self.body(body, limit);
+            },
+            StatKind::If { branches, else_block } => self.if_stat(branches, else_block.as_ref(), limit),
+            StatKind::While { condition, block } => {
+                self.write("while ");
+                self.expr(condition);
+                self.write(" do");
+                self.block(block, limit);
+                self.end(limit);
+            },
+            StatKind::NumericFor { var, start, limit: for_limit, step, block } => {
+                self.write("for ");
+                self.write(&var.name.to_string());
+                self.write(" = ");
+                self.expr(start);
+                self.write(", ");
+                self.expr(for_limit);
+                if let Some(step) = step {
+                    self.write(", ");
+                    self.expr(step);
+                }
+                self.write(" do");
+                self.block(block, limit);
+                self.end(limit);
+            },
+            StatKind::GenericFor { names, values, block } => {
+                self.write("for ");
+                self.local_names(names);
+                self.write(" in ");
+                self.exprs(values);
+                self.write(" do");
+                self.block(block, limit);
+                self.end(limit);
+            },
+            StatKind::Repeat { block, condition } => {
+                self.write("repeat");
+                self.block(block, condition.span.start.line.max(limit));
+                self.close("until ", condition.span.start.line.max(limit));
+                self.expr(condition);
+            },
+            StatKind::Do(block) => {
+                self.write("do");
+                self.block(block, limit);
+                self.end(limit);
+            },
+            StatKind::Return(values) => {
+                self.write("return");
+                if !values.is_empty() {
+                    self.write(" ");
+                    self.exprs(values);
+                }
+            },
+            StatKind::Break => self.write("break"),
+            StatKind::Goto(label) => {
+                self.write("goto ");
+                self.write(&label.to_string());
+            },
+            StatKind::Label(label) => {
+                self.write("::");
+                self.write(&label.to_string());
+                self.write("::");
+            },
+        }
+    }
+
+    fn if_stat(&mut self, branches: &[IfBranch], else_block: Option<&Block>, limit: u32) {
+        for (i, branch) in branches.iter().enumerate() {
+            if i > 0 {
+                self.close("elseif ", branch.span.start.line);
+            } else {
+                self.write("if ");
+            }
+            self.expr(&branch.condition);
+            self.write(" then");
+            let next = branches.get(i + 1).map_or(limit, |next| next.span.start.line);
+            self.block(&branch.block, next);
+        }
+        if let Some(block) = else_block {
+            self.close("else", limit);
+            self.block(block, limit);
+        }
+        self.end(limit);
+    }
+
+    fn local_names(&mut self, names: &[LocalName]) {
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.write(&name.name.to_string());
+            match name.attrib {
+                Some(Attrib::Const) => self.write(" <const>"),
+                Some(Attrib::Close) => self.write(" <close>"),
+                None => {},
+            }
+        }
+    }
+
+    /* parameters, block and 'end' of a function, which goes to its last line */
+    fn body(&mut self, body: &FunctionBody, limit: u32) {
+        self.write("(");
+        let mut params: Vec<String> = body.params.iter().map(|param| param.name.to_string()).collect();
+        if body.is_vararg {
+            params.push("...".to_string());
+        }
+        self.write(&params.join(", "));
+        self.write(")");
+        let end = body.span.end.line;
+        self.block(&body.block, if end > 0 { end } else { limit });
+        if end > self.line {
+            self.at(end);
+            self.write("end");
+        } else if end > 0 {
+            if !self.fresh {
+                self.write(" ");
+            }
+            self.write("end");
+        } else {
+            self.end(limit);
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.expr(expr);
+        }
+    }
+
+    fn parenthesized(&mut self, expr: &Expr) {
+        self.write("(");
+        self.expr(expr);
+        self.write(")");
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        self.at(expr.span.start.line);
+        match &expr.kind {
+            ExprKind::Nil => self.write("nil"),
+            ExprKind::True => self.write("true"),
+            ExprKind::False => self.write("false"),
+            ExprKind::Vararg => self.write("..."),
+            ExprKind::Int(i64::MIN) => self.write("(-9223372036854775807 - 1)"),
+            ExprKind::Int(n) => self.write(&n.to_string()),
+            ExprKind::Float(n) => self.write(&float_literal(*n)),
+            ExprKind::String(s) => self.write(&quote(s.as_bytes())),
+            ExprKind::Function(body) => {
+                self.write("function");
+                self.body(body, 0);
+            },
+            ExprKind::Table(fields) => {
+                self.write("{");
+                for (i, field) in fields.iter().enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    match field {
+                        Field::Positional(value) => self.expr(value),
+                        Field::Named { key, value, .. } => {
+                            self.write(&key.to_string());
+                            self.write(" = ");
+                            self.expr(value);
+                        },
+                        Field::Keyed { key, value } => {
+                            self.write("[");
+                            self.expr(key);
+                            self.write("] = ");
+                            self.expr(value);
+                        },
+                    }
+                }
+                self.write("}");
+            },
+            ExprKind::Name(name) => self.write(&name.to_string()),
+            ExprKind::Index { object, key } => {
+                self.prefix(object);
+                match &key.kind {
+                    ExprKind::String(name) if is_name(name.as_bytes()) => {
+                        self.write(".");
+                        self.write(&name.to_string());
+                    },
+                    _ => {
+                        self.write("[");
+                        self.expr(key);
+                        self.write("]");
+                    },
+                }
+            },
+            ExprKind::Call { function, args } => {
+                self.prefix(function);
+                self.write("(");
+                self.exprs(args);
+                self.write(")");
+            },
+            ExprKind::MethodCall { object, method, args } => {
+                self.prefix(object);
+                self.write(":");
+                self.write(&method.to_string());
+                self.write("(");
+                self.exprs(args);
+                self.write(")");
+            },
+            ExprKind::Paren(inner) => self.parenthesized(inner),
+            ExprKind::Binary { op, left, right, .. } => {
+                let (left_priority, right_priority) = op.priority();
+                let left_parens = match &left.kind {
+                    ExprKind::Binary { op: inner, .. } => left_priority > inner.priority().1,
+                    _ => is_unary(left) && left_priority > UNARY_PRIORITY,
+                };
+                let right_parens = match &right.kind {
+                    ExprKind::Binary { op: inner, .. } => inner.priority().0 <= right_priority,
+                    _ => false,
+                };
+                if left_parens { self.parenthesized(left) } else { self.expr(left) }
+                self.write(" ");
+                self.write(binary_text(*op));
+                self.write(" ");
+                if right_parens { self.parenthesized(right) } else { self.expr(right) }
+            },
+            ExprKind::Unary { op, operand, .. } => {
+                self.write(match op {
+                    UnOp::Minus => "-",
+                    UnOp::BNot => "~",
+                    UnOp::Not => "not ",
+                    UnOp::Len => "#",
+                });
+                let parens = match &operand.kind {
+                    ExprKind::Binary { op, .. } => op.priority().0 <= UNARY_PRIORITY,
+                    /* '--' would start a comment */
+                    _ => *op == UnOp::Minus && is_negative(operand),
+                };
+                if parens { self.parenthesized(operand) } else { self.expr(operand) }
+            },
+        }
+    }
+
+    /* the object of a call or an index */
+    fn prefix(&mut self, expr: &Expr) {
+        if is_prefix(expr) {
+            self.expr(expr);
+        } else {
+            self.parenthesized(expr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::print_chunk;
+    use crate::compiler::{ast::{Expr, ExprKind, StatKind}, parser::parse};
+
+    fn reprint(source: &str) -> String {
+        print_chunk(&parse(source.as_bytes(), "=test").unwrap())
+    }
+
+    /* the parser keeps parentheses: without them the printer has to put back the needed ones */
+    fn unparen(expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Paren(inner) => {
+                unparen(inner);
+                *expr = (**inner).clone();
+            },
+            ExprKind::Binary { left, right, .. } => {
+                unparen(left);
+                unparen(right);
+            },
+            ExprKind::Unary { operand, .. } => unparen(operand),
+            _ => {},
+        }
+    }
+
+    fn reprint_unparen(source: &str) -> String {
+        let mut chunk = parse(source.as_bytes(), "=test").unwrap();
+        if let StatKind::Assign { values, .. } = &mut chunk.block[0].kind {
+            values.iter_mut().for_each(unparen);
+        }
+        print_chunk(&chunk)
+    }
+
+    #[test]
+    fn keep_lines() {
+        let source = "local x = 1\n\nif x then\n  print(x)\nend\nlocal function f(a, ...)\n  return a\nend\n";
+        assert_eq!(reprint(source), source);
+        assert_eq!(reprint("do local a = 1 local b = 2 end"), "do local a = 1; local b = 2\nend\n");
+    }
+
+    #[test]
+    fn parenthesize_by_priority() {
+        assert_eq!(reprint_unparen("x = (a + b) * c - (d - e) .. f .. g"), "x = (a + b) * c - (d - e) .. f .. g\n");
+        assert_eq!(reprint_unparen("x = (a .. b) .. c, a ^ (b ^ c), (a ^ b) ^ c, (-a) ^ b, -(a ^ b), - -a, -(a + b)"),
+            "x = (a .. b) .. c, a ^ b ^ c, (a ^ b) ^ c, (-a) ^ b, -a ^ b, -(-a), -(a + b)\n");
+        assert_eq!(reprint_unparen("x = not (a == b), (a or b) and c, a or b and c, 2 ^ -3"),
+            "x = not (a == b), (a or b) and c, a or b and c, 2 ^ -3\n");
+        assert_eq!(reprint(";(f or g)(\"s\"):m{1, k = 2, [3] = 4}"), ";(f or g)(\"s\"):m({1, k = 2, [3] = 4})\n");
+    }
+
+    #[test]
+    fn write_literals() {
+        assert_eq!(reprint("x = 'a\"\\\\\\n\\0001\\200', t['end'], t.a_1, 1.0, 1e300"),
+            "x = \"a\\\"\\\\\\n\\0001\\200\", t[\"end\"], t.a_1, 1.0, 1e300\n");
+    }
+}
@@ -0,0 +1,602 @@
+/*
+ * A textual form of prototypes. 'disassemble' lists a function with its nested ones and
+ * 'assemble' reads such a listing back into a Proto, so chunks can be listed, edited and
+ * rebuilt. A function looks like:
+ *
+ *   .function
+ *   .source "@file.lua"
+ *   .linedefined 0 0
+ *   .params 0 vararg
+ *   .maxstack 2
+ *   .upvalue "_ENV" instack=1 idx=0 kind=0
+ *   .constant "print"
+ *   .local "x" 2 5
+ *         [1] VARARGPREP 0
+ *   L1:   [2] JMP        L1
+ *   .function          ; nested functions follow the code, CLOSURE takes their index
+ *   ...
+ *   .end
+ *   .end
+ *
+ * Jumps name labels, and LOADKX, NEWTABLE and SETLIST are written with their whole operand
+ * and get their EXTRAARG when assembled. '.word' gives an encoded instruction as is
+ */
+
+use std::{collections::{BTreeMap, HashMap}, fmt::Write, rc::Rc};
+
+use super::{builder::ProtoBuilder, instruction::Instruction, opcodes::{self, LuaInstruction, LuaOpcode, MAXARG_BX, MAXARG_C}, types::{ABSLINEINFO, LocVar, Proto, TValue, UpvalueDescription, string::LuaString}};
+
+/**
+ * A string as listings write it, escaping quotes, backslashes and anything not printable
+ */
+fn quote(s: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &c in s {
+        match c {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x20..=0x7e => quoted.push(c as char),
+            _ => write!(quoted, "\\x{:02x}", c).unwrap(),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/**
+ * Floats always show a point, an exponent, 'inf' or 'NaN', which tells them from integers
+ */
+fn constant_text(k: &TValue) -> String {
+    match k {
+        TValue::NIL => "nil".to_string(),
+        TValue::TBOOLEAN(b) => b.to_string(),
+        TValue::NUMINT(i) => i.to_string(),
+        TValue::NUMFLT(f) => format!("{:?}", f),
+        TValue::STR(s) => quote(s.as_bytes()),
+        _ => unreachable!("constants are nil, booleans, numbers or strings"),
+    }
+}
+
+/**
+ * Line of each instruction, None when the function has no line information (luaG_getfuncline)
+ */
+fn instruction_lines(f: &Proto) -> Option<Vec<i64>> {
+    if f.line_info.is_empty() {
+        return None;
+    }
+    let mut line = f.line_defined as i64;
+    let lines = f.line_info.iter().enumerate().map(|(pc, &delta)| {
+        if delta == ABSLINEINFO {
+            line = f.abs_line_info.iter().find(|info| info.pc == pc).map_or(line, |info| info.line);
+        } else {
+            line += delta as i64;
+        }
+        line
+    });
+    Some(lines.collect())
+}
+
+/**
+ * Where a jump goes: the instruction its label marks
+ */
+fn jump_target(pc: usize, instruction: &Instruction) -> Option<i64> {
+    let next = pc as i64 + 1;
+    match *instruction {
+        Instruction::Jmp { sj } => Some(next + sj as i64),
+        Instruction::ForPrep { bx, .. } | Instruction::TForPrep { bx, .. } => Some(next + bx as i64),
+        Instruction::ForLoop { bx, .. } | Instruction::TForLoop { bx, .. } => Some(next - bx as i64),
+        _ => None,
+    }
+}
+
+fn is_jump(opcode: LuaOpcode) -> bool {
+    matches!(opcode,
+        LuaOpcode::JMP_sJ | LuaOpcode::FORPREP_ABx | LuaOpcode::FORLOOP_ABx | LuaOpcode::TFORPREP_ABx | LuaOpcode::TFORLOOP_ABx)
+}
+
+/**
+ * What an instruction refers to: constants and upvalue names
+ */
+fn comment(f: &Proto, instruction: &Instruction) -> Vec<String> {
+    let k = |i: u32| f.constants.get(i as usize).map(constant_text).unwrap_or_else(|| "?".to_string());
+    let upvalue = |i: u8| match f.upvalues.get(i as usize).and_then(|u| u.name.as_ref()) {
+        Some(name) => name.to_string(),
+        None => "-".to_string(),
+    };
+    let rk = |c: u8, is_k: bool| if is_k { vec![k(c.into())] } else { vec![] };
+    match *instruction {
+        Instruction::LoadK { bx, .. } => vec![k(bx)],
+        Instruction::GetUpval { b, .. } | Instruction::SetUpval { b, .. } => vec![upvalue(b)],
+        Instruction::GetTabUp { b, key_k, .. } => vec![upvalue(b), k(key_k.into())],
+        Instruction::GetField { key_k, .. } => vec![k(key_k.into())],
+        Instruction::SetTabUp { a, key_k, c, k: is_k } => [vec![upvalue(a), k(key_k.into())], rk(c, is_k)].concat(),
+        Instruction::SetField { key_k, c, k: is_k, .. } => [vec![k(key_k.into())], rk(c, is_k)].concat(),
+        Instruction::SetTable { c, k: is_k, .. } | Instruction::SetI { c, k: is_k, .. } | Instruction::Self_ { c, k: is_k, .. } => rk(c, is_k),
+        Instruction::EqK { b_k, .. } | Instruction::MmBinK { b_k, .. } => vec![k(b_k.into())],
+        Instruction::AddK { c, .. } | Instruction::SubK { c, .. } | Instruction::MulK { c, .. } |
+        Instruction::ModK { c, .. } | Instruction::PowK { c, .. } | Instruction::DivK { c, .. } |
+        Instruction::IDivK { c, .. } | Instruction::BAndK { c, .. } | Instruction::BOrK { c, .. } |
+        Instruction::BXorK { c, .. } => vec![k(c.into())],
+        _ => vec![],
+    }
+}
+
+/**
+ * The operand of LOADKX, NEWTABLE or SETLIST with the EXTRAARG after it, when assembling
+ * it gives back the same two instructions
+ */
+fn merged_operand(instruction: &Instruction, extra: Option<&LuaInstruction>) -> Option<u32> {
+    let ax = match extra.map(|extra| extra.decoded()) {
+        Some(Instruction::ExtraArg { ax }) => ax,
+        _ => return None,
+    };
+    let whole = |c: u8| ax.checked_mul(MAXARG_C + 1).and_then(|high| high.checked_add(c.into()));
+    match *instruction {
+        Instruction::LoadKX { .. } => Some(ax),
+        Instruction::NewTable { c, k, .. } if k == (ax > 0) => whole(c),
+        Instruction::SetList { c, k: true, .. } if ax > 0 => whole(c),
+        _ => None,
+    }
+}
+
+fn list_function(out: &mut String, f: &Proto, parent_source: Option<&Rc<LuaString>>, index: Option<usize>) {
+    match index {
+        Some(index) => writeln!(out, ".function          ; {}", index).unwrap(),
+        None => writeln!(out, ".function").unwrap(),
+    }
+    if index.is_none() {
+        if let Some(source) = &f.fn_name {
+            writeln!(out, ".source {}", quote(source.as_bytes())).unwrap();
+        }
+    } else if f.fn_name.as_ref() != parent_source {
+        writeln!(out, "; source {} isn't the one of the main function", f.fn_name.as_ref().map_or("none".to_string(), |s| quote(s.as_bytes()))).unwrap();
+    }
+    writeln!(out, ".linedefined {} {}", f.line_defined, f.last_line_defined).unwrap();
+    writeln!(out, ".params {}{}", f.num_params, if f.is_vararg { " vararg" } else { "" }).unwrap();
+    writeln!(out, ".maxstack {}", f.max_stack_size).unwrap();
+    for upvalue in &f.upvalues {
+        let name = upvalue.name.as_ref().map_or(String::new(), |name| quote(name.as_bytes()) + " ");
+        writeln!(out, ".upvalue {}instack={} idx={} kind={}", name, upvalue.instack as u8, upvalue.idx, upvalue.kind).unwrap();
+    }
+    for (i, k) in f.constants.iter().enumerate() {
+        writeln!(out, ".constant {:<24} ; {}", constant_text(k), i).unwrap();
+    }
+    for local in &f.loc_vars {
+        let name = local.name.as_ref().map_or("\"\"".to_string(), |name| quote(name.as_bytes()));
+        writeln!(out, ".local {} {} {}", name, local.start_pc, local.end_pc).unwrap();
+    }
+
+    let decoded: Vec<Instruction> = f.code.iter().map(|instruction| instruction.decoded()).collect();
+    let mut labels = BTreeMap::new();
+    for (pc, instruction) in decoded.iter().enumerate() {
+        if let Some(target) = jump_target(pc, instruction).filter(|target| (0..=f.code.len() as i64).contains(target)) {
+            labels.insert(target as usize, String::new());
+        }
+    }
+    for (i, name) in labels.values_mut().enumerate() {
+        *name = format!("L{}", i + 1);
+    }
+    let lines = instruction_lines(f);
+    let mut pc = 0;
+    while pc < f.code.len() {
+        let instruction = &decoded[pc];
+        let label = labels.get(&pc).map_or(String::new(), |name| format!("{}:", name));
+        let line = lines.as_ref().map_or(String::new(), |lines| format!("[{}]", lines[pc]));
+        let same_line = lines.as_ref().is_none_or(|lines| lines.get(pc + 1) == lines.get(pc));
+        let merged = merged_operand(instruction, f.code.get(pc + 1)).filter(|_| same_line && !labels.contains_key(&(pc + 1)));
+        let text = match (instruction, merged) {
+            (Instruction::LoadKX { a }, Some(ax)) => format!("{:<9} {} {}", "LOADKX", a, ax),
+            (Instruction::NewTable { a, b, .. }, Some(size)) => format!("{:<9} {} {} {}", "NEWTABLE", a, b, size),
+            (Instruction::SetList { a, b, .. }, Some(n)) => format!("{:<9} {} {} {}", "SETLIST", a, b, n),
+            (Instruction::LoadKX { .. } | Instruction::NewTable { .. } | Instruction::SetList { k: true, .. }, None) => {
+                // these would get an EXTRAARG of their own
+                format!(".word     0x{:08x}", f.code[pc].raw())
+            },
+            _ => match jump_target(pc, instruction).and_then(|target| labels.get(&usize::try_from(target).ok()?)) {
+                Some(label) => {
+                    let (operands, _) = instruction.operands();
+                    let a = if operands.len() > 1 { format!("{} ", operands[0]) } else { String::new() };
+                    format!("{:<9} {}{}", instruction.opcode().mnemonic(), a, label)
+                },
+                _ => instruction.to_string(),
+            },
+        };
+        let comment = comment(f, instruction);
+        if comment.is_empty() {
+            writeln!(out, "{:<6}{:<7}{}", label, line, text).unwrap();
+        } else {
+            writeln!(out, "{:<6}{:<7}{:<28} ; {}", label, line, text, comment.join(" ")).unwrap();
+        }
+        pc += if merged.is_some() { 2 } else { 1 };
+    }
+    if let Some(name) = labels.get(&f.code.len()) {
+        writeln!(out, "{}:", name).unwrap();
+    }
+    for (i, child) in f.fns.iter().enumerate() {
+        list_function(out, child, f.fn_name.as_ref(), Some(i));
+    }
+    writeln!(out, ".end").unwrap();
+}
+
+/**
+ * The listing of a main function and the functions nested in it
+ */
+pub fn disassemble(f: &Proto) -> String {
+    let mut out = String::new();
+    list_function(&mut out, f, None, None);
+    out
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(Vec<u8>),
+    /* an instruction's line, as [12] */
+    Line(i64),
+}
+
+fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Vec<u8>, String> {
+    let mut s = Vec::new();
+    loop {
+        match chars.next() {
+            None => return Err("unfinished string".to_string()),
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('n') => s.push(b'\n'),
+                Some('r') => s.push(b'\r'),
+                Some('t') => s.push(b'\t'),
+                Some('x') => {
+                    let hex: String = [chars.next(), chars.next()].iter().flatten().collect();
+                    let byte = u8::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape '\\x{}'", hex))?;
+                    s.push(byte);
+                },
+                Some(c @ ('"' | '\\')) => s.push(c as u8),
+                other => return Err(format!("invalid escape '\\{}'", other.map_or(String::new(), String::from))),
+            },
+            Some(c) => {
+                let mut buffer = [0; 4];
+                s.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            },
+        }
+    }
+}
+
+/**
+ * The tokens of a line, up to a ';' comment
+ */
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            c if c.is_whitespace() => {
+                chars.next();
+            },
+            '"' => {
+                chars.next();
+                tokens.push(Token::Str(parse_string(&mut chars)?));
+            },
+            '[' => {
+                chars.next();
+                let text: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                let line = text.trim().parse().map_err(|_| format!("invalid line '[{}]'", text))?;
+                tokens.push(Token::Line(line));
+            },
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == ';' || c == '"' || c == '[' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_int<T: std::str::FromStr>(token: Option<&Token>, what: &str) -> Result<T, String> {
+    match token {
+        Some(Token::Word(word)) => word.parse().map_err(|_| format!("invalid {} '{}'", what, word)),
+        _ => Err(format!("{} expected", what)),
+    }
+}
+
+fn parse_constant(token: Option<&Token>) -> Result<TValue, String> {
+    match token {
+        Some(Token::Str(s)) => Ok(TValue::STR(Rc::new(LuaString::from(s.as_slice())))),
+        Some(Token::Word(word)) => match word.as_str() {
+            "nil" => Ok(TValue::NIL),
+            "true" => Ok(TValue::TBOOLEAN(true)),
+            "false" => Ok(TValue::TBOOLEAN(false)),
+            _ => match word.parse::<i64>() {
+                Ok(i) => Ok(TValue::NUMINT(i)),
+                Err(_) => word.parse::<f64>().map(TValue::NUMFLT).map_err(|_| format!("invalid constant '{}'", word)),
+            },
+        },
+        _ => Err("constant expected".to_string()),
+    }
+}
+
+/**
+ * A function being read: its builder and whether any instruction gave a line
+ */
+struct FunctionState {
+    builder: ProtoBuilder,
+    has_lines: bool,
+}
+
+struct Assembler {
+    functions: Vec<FunctionState>,
+    main: Option<Proto>,
+}
+
+impl Assembler {
+    fn current(&mut self) -> Result<&mut FunctionState, String> {
+        self.functions.last_mut().ok_or_else(|| "directive outside of a function".to_string())
+    }
+
+    fn directive(&mut self, name: &str, args: &[Token]) -> Result<(), String> {
+        match name {
+            ".function" => {
+                if self.main.is_some() {
+                    return Err("only one main function".to_string());
+                }
+                // nested functions take the source of their parent when added to it
+                self.functions.push(FunctionState { builder: ProtoBuilder::with_source(None), has_lines: false });
+            },
+            ".end" => {
+                let state = self.functions.pop().ok_or_else(|| "'.end' outside of a function".to_string())?;
+                let mut proto = state.builder.build()?;
+                if !state.has_lines {
+                    proto.line_info.clear();
+                    proto.abs_line_info.clear();
+                }
+                match self.functions.last_mut() {
+                    Some(parent) => {
+                        parent.builder.child(proto);
+                    },
+                    None => self.main = Some(proto),
+                }
+            },
+            ".source" => {
+                if self.functions.len() != 1 {
+                    return Err("only the main function has a source".to_string());
+                }
+                let source = match args.first() {
+                    Some(Token::Str(s)) => Rc::new(LuaString::from(s.as_slice())),
+                    _ => return Err("source string expected".to_string()),
+                };
+                self.current()?.builder.source(source);
+            },
+            ".linedefined" => {
+                let (first, last) = (parse_int(args.first(), "line")?, parse_int(args.get(1), "line")?);
+                self.current()?.builder.lines_defined(first, last);
+            },
+            ".params" => {
+                let n = parse_int(args.first(), "number of parameters")?;
+                let vararg = match args.get(1) {
+                    None => false,
+                    Some(Token::Word(word)) if word == "vararg" => true,
+                    _ => return Err("'vararg' expected".to_string()),
+                };
+                self.current()?.builder.params(n, vararg);
+            },
+            ".maxstack" => {
+                let size = parse_int(args.first(), "stack size")?;
+                self.current()?.builder.max_stack_size(size);
+            },
+            ".upvalue" => {
+                let (name, fields) = match args.first() {
+                    Some(Token::Str(s)) => (Some(Rc::new(LuaString::from(s.as_slice()))), &args[1..]),
+                    _ => (None, args),
+                };
+                let mut values = HashMap::new();
+                for field in fields {
+                    let (key, value) = match field {
+                        Token::Word(word) => word.split_once('=').ok_or_else(|| format!("'key=value' expected, got '{}'", word))?,
+                        _ => return Err("'key=value' expected".to_string()),
+                    };
+                    let value: u8 = value.parse().map_err(|_| format!("invalid {} '{}'", key, value))?;
+                    values.insert(key.to_string(), value);
+                }
+                let field = |key: &str| values.get(key).copied().ok_or_else(|| format!("upvalue without '{}'", key));
+                let upvalue = UpvalueDescription { instack: field("instack")? != 0, idx: field("idx")?, kind: field("kind")?, name };
+                self.current()?.builder.add_upvalue(upvalue);
+            },
+            ".constant" => {
+                let value = parse_constant(args.first())?;
+                self.current()?.builder.add_constant(value);
+            },
+            ".local" => {
+                let name = match args.first() {
+                    Some(Token::Str(s)) => Some(Rc::new(LuaString::from(s.as_slice()))),
+                    _ => return Err("local name expected".to_string()),
+                };
+                let (start_pc, end_pc) = (parse_int(args.get(1), "pc")?, parse_int(args.get(2), "pc")?);
+                self.current()?.builder.add_local(LocVar { name, start_pc, end_pc });
+            },
+            ".word" => {
+                let raw = match args.first() {
+                    Some(Token::Word(word)) => u32::from_str_radix(word.trim_start_matches("0x"), 16).ok(),
+                    _ => None,
+                };
+                match raw.filter(|raw| (raw & 0x7f) <= LuaOpcode::EXTRAARG_Ax as u32) {
+                    Some(raw) => {
+                        self.current()?.builder.emit(opcodes::decode(raw));
+                    },
+                    None => return Err("encoded instruction expected".to_string()),
+                }
+            },
+            _ => return Err(format!("unknown directive '{}'", name)),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, args: &[Token]) -> Result<(), String> {
+        let opcode = LuaOpcode::from_mnemonic(mnemonic).ok_or_else(|| format!("unknown opcode '{}'", mnemonic))?;
+        let builder = &mut self.current()?.builder;
+        let mut operands = Vec::new();
+        let mut k = false;
+        let mut label = None;
+        for (i, arg) in args.iter().enumerate() {
+            let word = match arg {
+                Token::Word(word) => word,
+                _ => return Err("operand expected".to_string()),
+            };
+            let last = i == args.len() - 1;
+            let digits = word.strip_suffix('k').filter(|_| last).unwrap_or(word);
+            match digits.parse::<i64>() {
+                Ok(value) => {
+                    k = digits.len() != word.len();
+                    operands.push(value);
+                },
+                Err(_) if last && is_jump(opcode) => label = Some(word),
+                Err(_) => return Err(format!("invalid operand '{}'", word)),
+            }
+        }
+        if let Some(label) = label {
+            let a = match operands.as_slice() {
+                [] if opcode == LuaOpcode::JMP_sJ => 0,
+                [a] if opcode != LuaOpcode::JMP_sJ => u32::try_from(*a).map_err(|_| format!("operand A out of range ({})", a))?,
+                _ => return Err(format!("wrong operands for {}", mnemonic)),
+            };
+            builder.emit_jump(opcode, a, label)?;
+            return Ok(());
+        }
+        // the opcodes with an EXTRAARG, given their whole operand
+        let split = |n: i64| -> Result<(i64, u32), String> {
+            let n = u32::try_from(n).map_err(|_| format!("operand out of range ({})", n))?;
+            Ok(((n % (MAXARG_C + 1)).into(), n / (MAXARG_C + 1)))
+        };
+        let extra = match (opcode, operands.as_slice()) {
+            (LuaOpcode::NEWTABLE_ABCk | LuaOpcode::SETLIST_ABCk, _) if k => {
+                return Err(format!("the k of {} is set from its size", mnemonic));
+            },
+            (LuaOpcode::LOADK_ABx, &[a, bx]) if bx > MAXARG_BX as i64 => {
+                builder.emit(Instruction::from_operands(LuaOpcode::LOADKX_A, &[a], false)?.encode()?);
+                Some(u32::try_from(bx).map_err(|_| format!("operand out of range ({})", bx))?)
+            },
+            (LuaOpcode::LOADKX_A, &[a, ax]) => {
+                builder.emit(Instruction::from_operands(opcode, &[a], false)?.encode()?);
+                Some(u32::try_from(ax).map_err(|_| format!("operand out of range ({})", ax))?)
+            },
+            (LuaOpcode::NEWTABLE_ABCk, &[a, b, size]) => {
+                let (c, extra) = split(size)?;
+                builder.emit(Instruction::from_operands(opcode, &[a, b, c], extra > 0)?.encode()?);
+                Some(extra)
+            },
+            (LuaOpcode::SETLIST_ABCk, &[a, b, n]) if n > MAXARG_C as i64 => {
+                let (c, extra) = split(n)?;
+                builder.emit(Instruction::from_operands(opcode, &[a, b, c], true)?.encode()?);
+                Some(extra)
+            },
+            _ => {
+                builder.emit(Instruction::from_operands(opcode, &operands, k)?.encode()?);
+                None
+            },
+        };
+        if let Some(ax) = extra {
+            builder.emit(Instruction::ExtraArg { ax }.encode()?);
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, tokens: &[Token]) -> Result<(), String> {
+        let mut tokens = tokens;
+        while let Some(Token::Word(word)) = tokens.first() {
+            match word.strip_suffix(':') {
+                Some(label) if !label.is_empty() && !word.starts_with('.') => {
+                    self.current()?.builder.label(label)?;
+                    tokens = &tokens[1..];
+                },
+                _ => break,
+            }
+        }
+        if let Some(Token::Line(line)) = tokens.first() {
+            let state = self.current()?;
+            state.builder.line(*line);
+            state.has_lines = true;
+            tokens = &tokens[1..];
+        }
+        match tokens.first() {
+            None => Ok(()),
+            Some(Token::Word(word)) if word.starts_with('.') => self.directive(word, &tokens[1..]),
+            Some(Token::Word(word)) => self.instruction(word, &tokens[1..]),
+            Some(_) => Err("instruction or directive expected".to_string()),
+        }
+    }
+}
+
+/**
+ * The main function of a listing. Errors tell the line they are in
+ */
+pub fn assemble(text: &str) -> Result<Proto, String> {
+    let mut assembler = Assembler { functions: Vec::new(), main: None };
+    for (i, line) in text.lines().enumerate() {
+        tokenize(line).and_then(|tokens| assembler.line(&tokens)).map_err(|message| format!("line {}: {}", i + 1, message))?;
+    }
+    if !assembler.functions.is_empty() {
+        return Err("missing '.end'".to_string());
+    }
+    assembler.main.ok_or_else(|| "no function".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{assemble, disassemble};
+    use crate::core::{dump::dump, instruction::Instruction, parser::parse_all};
+
+    fn assert_round_trip(chunk: &[u8]) {
+        let proto = parse_all(chunk).unwrap();
+        let listing = disassemble(&proto);
+        let assembled = assemble(&listing).unwrap_or_else(|message| panic!("{}\n{}", message, listing));
+        assert_eq!(dump(&assembled, false), chunk, "{}", listing);
+    }
+
+    #[test]
+    fn reassemble_listings() {
+        assert_round_trip(include_bytes!("../../helpers/out1"));
+        assert_round_trip(include_bytes!("../../helpers/out2"));
+        assert_round_trip(include_bytes!("../../helpers/luac.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/base.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/debug.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/math.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/string.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/table.out"));
+    }
+
+    #[test]
+    fn add_extra_arguments() {
+        let proto = assemble(".function\n LOADK 0 200000\n NEWTABLE 1 2 300\n SETLIST 1 0 600\n L: JMP L\n.end").unwrap();
+        let code: Vec<Instruction> = proto.code.iter().map(|i| i.decoded()).collect();
+        assert_eq!(code, vec![
+            Instruction::LoadKX { a: 0 },
+            Instruction::ExtraArg { ax: 200000 },
+            Instruction::NewTable { a: 1, b: 2, c: 44, k: true },
+            Instruction::ExtraArg { ax: 1 },
+            Instruction::SetList { a: 1, b: 0, c: 88, k: true },
+            Instruction::ExtraArg { ax: 2 },
+            Instruction::Jmp { sj: -1 },
+        ]);
+        assert!(proto.line_info.is_empty() && proto.fn_name.is_none());
+        assert!(disassemble(&proto).contains("NEWTABLE  1 2 300"));
+    }
+
+    #[test]
+    fn report_errors() {
+        assert_eq!(assemble(".function\n MOVE 0\n.end").unwrap_err(), "line 2: MOVE takes 2 operands");
+        assert_eq!(assemble(".function\n FOO 1\n.end").unwrap_err(), "line 2: unknown opcode 'FOO'");
+        assert_eq!(assemble(".function\n JMP nowhere\n.end").unwrap_err(), "line 3: undefined label 'nowhere'");
+        assert_eq!(assemble(".function\n .constant \"a\\q\"\n.end").unwrap_err(), "line 2: invalid escape '\\q'");
+        assert_eq!(assemble(".function\n ADDI 0 1 200\n.end").unwrap_err(), "line 2: operand sC out of range (200 not in [-127, 128])");
+        assert_eq!(assemble(".function").unwrap_err(), "missing '.end'");
+    }
+}
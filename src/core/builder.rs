@@ -0,0 +1,285 @@
+/*
+ * Building prototypes by hand, for tests and tools that produce bytecode without going
+ * through the compiler. Jumps name labels and get their offsets once the function is complete
+ */
+
+use std::{collections::HashMap, rc::Rc};
+
+use super::{opcodes::{self, LuaInstruction, LuaOpcode}, types::{LineInfoWriter, LocVar, Proto, TValue, UpvalueDescription, string::LuaString}};
+
+/**
+ * A jump waiting for its label to be placed
+ */
+struct PendingJump {
+    pc: usize,
+    label: String,
+}
+
+pub struct ProtoBuilder {
+    /* None for functions without debug information */
+    source: Option<Rc<LuaString>>,
+    line_defined: usize,
+    last_line_defined: usize,
+    num_params: u8,
+    is_vararg: bool,
+    max_stack_size: u8,
+    code: Vec<LuaInstruction>,
+    /* line of each instruction */
+    lines: Vec<i64>,
+    /* line given to the next instructions */
+    line: i64,
+    constants: Vec<TValue>,
+    upvalues: Vec<UpvalueDescription>,
+    fns: Vec<Rc<Proto>>,
+    loc_vars: Vec<LocVar>,
+    labels: HashMap<String, usize>,
+    jumps: Vec<PendingJump>,
+}
+
+impl ProtoBuilder {
+    /**
+     * An empty function from 'source', with the registers of 'max_stack_size' growing as
+     * instructions write them
+     */
+    pub fn new(source: &str) -> Self {
+        Self::with_source(Some(Rc::new(LuaString::from(source))))
+    }
+
+    pub fn with_source(source: Option<Rc<LuaString>>) -> Self {
+        Self {
+            source,
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 0,
+            is_vararg: false,
+            max_stack_size: 2,
+            code: Vec::new(),
+            lines: Vec::new(),
+            line: 0,
+            constants: Vec::new(),
+            upvalues: Vec::new(),
+            fns: Vec::new(),
+            loc_vars: Vec::new(),
+            labels: HashMap::new(),
+            jumps: Vec::new(),
+        }
+    }
+
+    pub fn source(&mut self, source: Rc<LuaString>) -> &mut Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn params(&mut self, num_params: u8, is_vararg: bool) -> &mut Self {
+        self.num_params = num_params;
+        self.is_vararg = is_vararg;
+        self.max_stack_size = self.max_stack_size.max(num_params);
+        self
+    }
+
+    pub fn lines_defined(&mut self, line_defined: usize, last_line_defined: usize) -> &mut Self {
+        self.line_defined = line_defined;
+        self.last_line_defined = last_line_defined;
+        self
+    }
+
+    /**
+     * Registers needed beyond the ones instructions write, as call results
+     */
+    pub fn max_stack_size(&mut self, size: u8) -> &mut Self {
+        self.max_stack_size = self.max_stack_size.max(size);
+        self
+    }
+
+    /**
+     * Line of the instructions emitted from now on
+     */
+    pub fn line(&mut self, line: i64) -> &mut Self {
+        self.line = line;
+        self
+    }
+
+    /**
+     * Appends an instruction, returning its pc
+     */
+    pub fn emit(&mut self, instruction: LuaInstruction) -> usize {
+        if instruction.opcode.sets_a() && instruction.opcode != LuaOpcode::EXTRAARG_Ax {
+            self.max_stack_size = self.max_stack_size.max(instruction.args.get_A().saturating_add(1));
+        }
+        self.code.push(instruction);
+        self.lines.push(self.line);
+        self.code.len() - 1
+    }
+
+    /**
+     * Appends an instruction jumping to 'label': JMP, or one of the loop instructions, whose
+     * labels are the instruction they jump to (FORLOOP and TFORCALL for the preparations, the
+     * first instruction of the body for the loops)
+     */
+    pub fn emit_jump(&mut self, opcode: LuaOpcode, a: u32, label: &str) -> Result<usize, String> {
+        let instruction = match opcode {
+            LuaOpcode::JMP_sJ => opcodes::encode_sj(opcode, 0)?,
+            LuaOpcode::FORPREP_ABx | LuaOpcode::FORLOOP_ABx | LuaOpcode::TFORPREP_ABx | LuaOpcode::TFORLOOP_ABx => {
+                opcodes::encode_abx(opcode, a, 0)?
+            },
+            _ => return Err(format!("{:?} is not a jump", opcode)),
+        };
+        let pc = self.emit(instruction);
+        self.jumps.push(PendingJump { pc, label: label.to_string() });
+        Ok(pc)
+    }
+
+    /**
+     * Places 'label' at the next instruction
+     */
+    pub fn label(&mut self, name: &str) -> Result<(), String> {
+        if self.labels.insert(name.to_string(), self.code.len()).is_some() {
+            return Err(format!("label '{}' already defined", name));
+        }
+        Ok(())
+    }
+
+    /**
+     * Index of a constant, reusing an equal one. Integers and floats with the same value are
+     * different constants
+     */
+    pub fn constant(&mut self, value: TValue) -> usize {
+        let same = |k: &TValue| match (k, &value) {
+            (TValue::NUMINT(a), TValue::NUMINT(b)) => a == b,
+            (TValue::NUMFLT(a), TValue::NUMFLT(b)) => a.to_bits() == b.to_bits(),
+            (TValue::NUMINT(_), TValue::NUMFLT(_)) | (TValue::NUMFLT(_), TValue::NUMINT(_)) => false,
+            (a, b) => a.raw_equals(b),
+        };
+        match self.constants.iter().position(same) {
+            Some(k) => k,
+            None => self.add_constant(value),
+        }
+    }
+
+    /**
+     * Appends a constant even if an equal one exists
+     */
+    pub fn add_constant(&mut self, value: TValue) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    /**
+     * Declares an upvalue: register 'idx' of the enclosing function when 'instack', else its
+     * upvalue 'idx'
+     */
+    pub fn upvalue(&mut self, name: &str, instack: bool, idx: u8) -> usize {
+        self.add_upvalue(UpvalueDescription { instack, idx, kind: 0, name: Some(Rc::new(LuaString::from(name))) })
+    }
+
+    pub fn add_upvalue(&mut self, upvalue: UpvalueDescription) -> usize {
+        self.upvalues.push(upvalue);
+        self.upvalues.len() - 1
+    }
+
+    /**
+     * Adds a nested function, for CLOSURE to refer to by index. It takes the source of this one
+     */
+    pub fn child(&mut self, proto: Proto) -> usize {
+        self.fns.push(Rc::new(Proto { fn_name: self.source.clone(), ..proto }));
+        self.fns.len() - 1
+    }
+
+    pub fn local(&mut self, name: &str, start_pc: usize, end_pc: usize) -> &mut Self {
+        self.add_local(LocVar { name: Some(Rc::new(LuaString::from(name))), start_pc, end_pc })
+    }
+
+    pub fn add_local(&mut self, local: LocVar) -> &mut Self {
+        self.loc_vars.push(local);
+        self
+    }
+
+    /**
+     * The prototype, with its jumps resolved
+     */
+    pub fn build(mut self) -> Result<Proto, String> {
+        for jump in &self.jumps {
+            let target = *self.labels.get(&jump.label).ok_or_else(|| format!("undefined label '{}'", jump.label))?;
+            let offset = target as i32 - (jump.pc as i32 + 1);
+            let instruction = &self.code[jump.pc];
+            let (opcode, a) = (instruction.opcode, instruction.args.get_A() as u32);
+            self.code[jump.pc] = match opcode {
+                LuaOpcode::JMP_sJ => opcodes::encode_sj(opcode, offset)?,
+                LuaOpcode::FORPREP_ABx | LuaOpcode::TFORPREP_ABx if offset >= 0 => opcodes::encode_abx(opcode, a, offset as u32)?,
+                LuaOpcode::FORLOOP_ABx | LuaOpcode::TFORLOOP_ABx if offset <= 0 => opcodes::encode_abx(opcode, a, -offset as u32)?,
+                _ => return Err(format!("{:?} at {} can't jump to label '{}'", opcode, jump.pc, jump.label)),
+            };
+        }
+        let mut lines = LineInfoWriter::new(self.line_defined as i64);
+        self.lines.iter().for_each(|&line| lines.save(line));
+        Ok(Proto {
+            fn_name: self.source,
+            line_defined: self.line_defined,
+            last_line_defined: self.last_line_defined,
+            num_params: self.num_params,
+            is_vararg: self.is_vararg,
+            max_stack_size: self.max_stack_size,
+            constants: self.constants,
+            code: self.code,
+            fns: self.fns,
+            upvalues: self.upvalues,
+            line_info: lines.line_info,
+            abs_line_info: lines.abs_line_info,
+            loc_vars: self.loc_vars,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProtoBuilder;
+    use crate::core::{opcodes::{LuaOpcode, encode_abck, encode_abx}, types::TValue};
+
+    #[test]
+    fn resolve_labels() {
+        let mut f = ProtoBuilder::new("=test");
+        f.emit_jump(LuaOpcode::JMP_sJ, 0, "end").unwrap();
+        f.label("loop").unwrap();
+        f.emit(encode_abck(LuaOpcode::MOVE_AB, 0, 1, 0, false).unwrap());
+        f.emit_jump(LuaOpcode::JMP_sJ, 0, "loop").unwrap();
+        f.label("end").unwrap();
+        f.emit(encode_abck(LuaOpcode::RETURN0, 0, 0, 0, false).unwrap());
+        let proto = f.build().unwrap();
+        assert_eq!(proto.code[0].args.get_sJ(), 2);
+        assert_eq!(proto.code[2].args.get_sJ(), -2);
+    }
+
+    #[test]
+    fn check_labels() {
+        let mut f = ProtoBuilder::new("=test");
+        f.emit_jump(LuaOpcode::JMP_sJ, 0, "nowhere").unwrap();
+        assert_eq!(f.build().unwrap_err(), "undefined label 'nowhere'");
+        let mut f = ProtoBuilder::new("=test");
+        f.label("back").unwrap();
+        assert_eq!(f.label("back").unwrap_err(), "label 'back' already defined");
+        f.emit_jump(LuaOpcode::FORPREP_ABx, 0, "back").unwrap();
+        assert!(f.build().is_err());
+        assert!(ProtoBuilder::new("=test").emit_jump(LuaOpcode::MOVE_AB, 0, "l").is_err());
+    }
+
+    #[test]
+    fn intern_constants() {
+        let mut f = ProtoBuilder::new("=test");
+        assert_eq!(f.constant(TValue::NUMINT(1)), 0);
+        assert_eq!(f.constant(TValue::NUMFLT(1.0)), 1);
+        assert_eq!(f.constant(TValue::from_str("x")), 2);
+        assert_eq!(f.constant(TValue::NUMINT(1)), 0);
+        assert_eq!(f.constant(TValue::from_str("x")), 2);
+    }
+
+    #[test]
+    fn track_registers_and_lines() {
+        let mut f = ProtoBuilder::new("=test");
+        f.line(1).emit(encode_abx(LuaOpcode::LOADK_ABx, 5, 0).unwrap());
+        f.line(300).emit(encode_abck(LuaOpcode::RETURN0, 0, 0, 0, false).unwrap());
+        let proto = f.build().unwrap();
+        assert_eq!(proto.max_stack_size, 6);
+        assert_eq!(proto.line_info, vec![1, -0x80]);
+        assert_eq!((proto.abs_line_info[0].pc, proto.abs_line_info[0].line), (1, 300));
+    }
+}
@@ -0,0 +1,298 @@
+/*
+ * Serialising prototypes back to binary chunks (ldump.c), the inverse of the reader in
+ * parser.rs. Chunks use the layout of the reference luac, 8 byte little-endian integers and
+ * floats, unless retargeted to another one
+ */
+
+use std::rc::Rc;
+
+use super::{parser::{LUA_SIGNATURE, LUAC_VERSION, LUAC_FORMAT, LUAC_DATA, LUAC_INT, LUAC_NUM, Layout}, types::{Proto, TValue, string::LuaString}};
+
+/* longest string with the short string tag */
+const LUAI_MAXSHORTLEN: usize = 40;
+
+/*
+ * Variant tags of constants (ttypetag)
+ */
+const LUA_VNIL: u8 = 0;
+const LUA_VFALSE: u8 = 1;
+const LUA_VTRUE: u8 = 17;
+const LUA_VNUMINT: u8 = 3;
+const LUA_VNUMFLT: u8 = 19;
+const LUA_VSHRSTR: u8 = 4;
+const LUA_VLNGSTR: u8 = 20;
+
+struct LuaWriter {
+    buffer: Vec<u8>,
+    /* leave the debug information out */
+    strip: bool,
+    layout: Layout,
+}
+
+impl LuaWriter {
+    fn dump_byte(&mut self, b: u8) {
+        self.buffer.push(b);
+    }
+
+    /**
+     * dumpSize: 7 bits per byte, most significant first, the last byte marked with 0x80
+     */
+    fn dump_size(&mut self, mut x: u64) {
+        let mut bytes = vec![(x & 0x7f) as u8 | 0x80];
+        x >>= 7;
+        while x != 0 {
+            bytes.push((x & 0x7f) as u8);
+            x >>= 7;
+        }
+        self.buffer.extend(bytes.iter().rev());
+    }
+
+    fn dump_int(&mut self, x: usize) {
+        self.dump_size(x as u64);
+    }
+
+    /* the low 'size' bytes of 'x' in the byte order of the layout */
+    fn dump_word(&mut self, x: u64, size: usize) {
+        let bytes = &x.to_le_bytes()[..size];
+        if self.layout.big_endian {
+            self.buffer.extend(bytes.iter().rev());
+        } else {
+            self.buffer.extend_from_slice(bytes);
+        }
+    }
+
+    fn dump_integer(&mut self, x: i64) {
+        self.dump_word(x as u64, self.layout.int_size as usize);
+    }
+
+    fn dump_number(&mut self, x: f64) {
+        match self.layout.number_size {
+            4 => self.dump_word((x as f32).to_bits() as u64, 4),
+            _ => self.dump_word(x.to_bits(), 8),
+        }
+    }
+
+    /**
+     * dumpString: the size counts a trailing '\0' that isn't written; 0 means no string
+     */
+    fn dump_string(&mut self, s: Option<&Rc<LuaString>>) {
+        match s {
+            None => self.dump_size(0),
+            Some(s) => {
+                self.dump_size(s.as_bytes().len() as u64 + 1);
+                self.buffer.extend_from_slice(s.as_bytes());
+            },
+        }
+    }
+
+    fn dump_code(&mut self, f: &Proto) {
+        self.dump_int(f.code.len());
+        for instruction in &f.code {
+            self.dump_word(instruction.raw() as u64, 4);
+        }
+    }
+
+    fn dump_constants(&mut self, f: &Proto) {
+        self.dump_int(f.constants.len());
+        for constant in &f.constants {
+            match constant {
+                TValue::NIL => self.dump_byte(LUA_VNIL),
+                TValue::TBOOLEAN(false) => self.dump_byte(LUA_VFALSE),
+                TValue::TBOOLEAN(true) => self.dump_byte(LUA_VTRUE),
+                TValue::NUMFLT(n) => {
+                    self.dump_byte(LUA_VNUMFLT);
+                    self.dump_number(*n);
+                },
+                TValue::NUMINT(i) => {
+                    self.dump_byte(LUA_VNUMINT);
+                    self.dump_integer(*i);
+                },
+                TValue::STR(s) => {
+                    self.dump_byte(if s.as_bytes().len() <= LUAI_MAXSHORTLEN { LUA_VSHRSTR } else { LUA_VLNGSTR });
+                    self.dump_string(Some(s));
+                },
+                _ => unreachable!("constants are nil, booleans, numbers or strings"),
+            }
+        }
+    }
+
+    fn dump_upvalues(&mut self, f: &Proto) {
+        self.dump_int(f.upvalues.len());
+        for upvalue in &f.upvalues {
+            self.dump_byte(upvalue.instack as u8);
+            self.dump_byte(upvalue.idx);
+            self.dump_byte(upvalue.kind);
+        }
+    }
+
+    fn dump_protos(&mut self, f: &Proto) {
+        self.dump_int(f.fns.len());
+        for p in &f.fns {
+            self.dump_function(p, f.fn_name.as_ref());
+        }
+    }
+
+    fn dump_debug(&mut self, f: &Proto) {
+        let n = if self.strip { 0 } else { f.line_info.len() };
+        self.dump_int(n);
+        self.buffer.extend(f.line_info[..n].iter().map(|info| *info as u8));
+        let n = if self.strip { 0 } else { f.abs_line_info.len() };
+        self.dump_int(n);
+        for info in &f.abs_line_info[..n] {
+            self.dump_int(info.pc);
+            self.dump_int(info.line as usize);
+        }
+        let n = if self.strip { 0 } else { f.loc_vars.len() };
+        self.dump_int(n);
+        for var in &f.loc_vars[..n] {
+            self.dump_string(var.name.as_ref());
+            self.dump_int(var.start_pc);
+            self.dump_int(var.end_pc);
+        }
+        // functions loaded from stripped chunks have no names to write
+        let has_names = f.upvalues.iter().any(|upvalue| upvalue.name.is_some());
+        let n = if self.strip || !has_names { 0 } else { f.upvalues.len() };
+        self.dump_int(n);
+        for upvalue in &f.upvalues[..n] {
+            self.dump_string(upvalue.name.as_ref());
+        }
+    }
+
+    /**
+     * dumpFunction: nested functions with the source of their parent leave it out
+     */
+    fn dump_function(&mut self, f: &Proto, parent_source: Option<&Rc<LuaString>>) {
+        if self.strip || f.fn_name.as_ref() == parent_source {
+            self.dump_string(None);
+        } else {
+            self.dump_string(f.fn_name.as_ref());
+        }
+        self.dump_int(f.line_defined);
+        self.dump_int(f.last_line_defined);
+        self.dump_byte(f.num_params);
+        self.dump_byte(f.is_vararg as u8);
+        self.dump_byte(f.max_stack_size);
+        self.dump_code(f);
+        self.dump_constants(f);
+        self.dump_upvalues(f);
+        self.dump_protos(f);
+        self.dump_debug(f);
+    }
+
+    fn dump_header(&mut self) {
+        self.buffer.extend_from_slice(LUA_SIGNATURE);
+        self.dump_byte(LUAC_VERSION);
+        self.dump_byte(LUAC_FORMAT);
+        self.buffer.extend_from_slice(LUAC_DATA);
+        self.dump_byte(4);
+        self.dump_byte(self.layout.int_size);
+        self.dump_byte(self.layout.number_size);
+        self.dump_integer(LUAC_INT);
+        self.dump_number(LUAC_NUM);
+    }
+}
+
+/**
+ * luaU_dump: the binary chunk of a main function, which 'parse_all' reads back
+ */
+pub fn dump(f: &Proto, strip: bool) -> Vec<u8> {
+    write_chunk(f, strip, Layout::LUAC)
+}
+
+fn write_chunk(f: &Proto, strip: bool, layout: Layout) -> Vec<u8> {
+    let mut writer = LuaWriter { buffer: Vec::new(), strip, layout };
+    writer.dump_header();
+    writer.dump_byte(f.upvalues.len() as u8);
+    writer.dump_function(f, None);
+    writer.buffer
+}
+
+/* numeric constants of 'f' and the functions in it that 'layout' can't hold */
+fn check_constants(f: &Proto, layout: Layout) -> Result<(), String> {
+    for constant in &f.constants {
+        match *constant {
+            TValue::NUMINT(i) if layout.int_size == 4 && i32::try_from(i).is_err() => {
+                return Err(format!("integer constant {} doesn't fit in 4 bytes (function at line {})", i, f.line_defined));
+            },
+            TValue::NUMFLT(n) if layout.number_size == 4 && !n.is_nan() && n as f32 as f64 != n => {
+                return Err(format!("float constant {} isn't exact in 4 bytes (function at line {})", n, f.line_defined));
+            },
+            _ => {},
+        }
+    }
+    f.fns.iter().try_for_each(|child| check_constants(child, layout))
+}
+
+/**
+ * The binary chunk of a main function for a build with another layout, failing when a
+ * constant would change in it
+ */
+pub fn retarget(f: &Proto, strip: bool, layout: Layout) -> Result<Vec<u8>, String> {
+    layout.check()?;
+    check_constants(f, layout)?;
+    Ok(write_chunk(f, strip, layout))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dump, retarget};
+    use crate::{compiler::codegen::compile, core::parser::{Layout, chunk_layout, parse_all}};
+
+    fn assert_round_trip(chunk: &[u8]) {
+        let proto = parse_all(chunk).unwrap();
+        assert_eq!(dump(&proto, false), chunk);
+    }
+
+    #[test]
+    fn dump_luac_output() {
+        assert_round_trip(include_bytes!("../../helpers/out1"));
+        assert_round_trip(include_bytes!("../../helpers/out2"));
+        assert_round_trip(include_bytes!("../../helpers/luac.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/base.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/debug.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/math.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/string.out"));
+        assert_round_trip(include_bytes!("../../helpers/tests/table.out"));
+    }
+
+    #[test]
+    fn dump_stripped() {
+        // helpers/out2 is opcodes2.lua compiled with 'luac -s'
+        let proto = parse_all(include_bytes!("../../helpers/luac.out")).unwrap();
+        assert_eq!(dump(&proto, true), include_bytes!("../../helpers/out2"));
+    }
+
+    #[test]
+    fn retarget_layouts() {
+        // helpers/tests/layout32.lua compiled by a luac built with LUA_32BITS
+        let chunk = include_bytes!("../../helpers/tests/layout32.out");
+        let layout32 = Layout { big_endian: false, int_size: 4, number_size: 4 };
+        assert_eq!(chunk_layout(chunk), Ok(layout32));
+        let proto = parse_all(chunk).unwrap();
+        assert_eq!(retarget(&proto, false, layout32).unwrap(), chunk);
+
+        let luac = include_bytes!("../../helpers/tests/base.out");
+        let proto = parse_all(luac).unwrap();
+        for big_endian in [false, true] {
+            for (int_size, number_size) in [(8, 8), (4, 8), (8, 4)] {
+                let layout = Layout { big_endian, int_size, number_size };
+                let Ok(chunk) = retarget(&proto, false, layout) else { continue };
+                assert_eq!(chunk_layout(&chunk), Ok(layout));
+                assert_eq!(dump(&parse_all(&chunk).unwrap(), false), luac);
+            }
+        }
+        let big = Layout { big_endian: true, ..Layout::LUAC };
+        assert_eq!(chunk_layout(&retarget(&proto, true, big).unwrap()), Ok(big));
+    }
+
+    #[test]
+    fn report_lost_constants() {
+        let layout32 = Layout { big_endian: false, int_size: 4, number_size: 4 };
+        let retargeted = |source: &str| retarget(&compile(source.as_bytes(), "=test").unwrap(), false, layout32);
+        assert!(retargeted("return 2147483647, -2147483648, 0.5, 2^100, 1 / 0").is_ok());
+        assert_eq!(retargeted("return function() return 2147483648 end").unwrap_err(), "integer constant 2147483648 doesn't fit in 4 bytes (function at line 1)");
+        assert_eq!(retargeted("return 0.1").unwrap_err(), "float constant 0.1 isn't exact in 4 bytes (function at line 0)");
+        let layout = Layout { big_endian: false, int_size: 2, number_size: 8 };
+        assert_eq!(retarget(&compile(b"return", "=test").unwrap(), false, layout).unwrap_err(), "lua_Integer size mismatch");
+    }
+}
@@ -0,0 +1,605 @@
+/*
+ * Decoded instructions: one variant per opcode with its operands named and correctly signed,
+ * so code matching on them can't read an operand the opcode doesn't have
+ */
+
+use std::fmt;
+
+use super::opcodes::{self, EncodeResult, LuaInstruction, LuaOpcode, signed_arg};
+
+/**
+ * An instruction with its operands. Registers are 'a', 'b' and 'c', constant indices end
+ * in '_k', signed operands start with 's' and 'k' is the k bit where it is not a constant
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Move { a: u8, b: u8 },
+    LoadI { a: u8, sbx: i32 },
+    LoadF { a: u8, sbx: i32 },
+    LoadK { a: u8, bx: u32 },
+    LoadKX { a: u8 },
+    LoadFalse { a: u8 },
+    LFalseSkip { a: u8 },
+    LoadTrue { a: u8 },
+    /* R[A], ..., R[A+B] := nil */
+    LoadNil { a: u8, b: u8 },
+    GetUpval { a: u8, b: u8 },
+    SetUpval { a: u8, b: u8 },
+    GetTabUp { a: u8, b: u8, key_k: u8 },
+    GetTable { a: u8, b: u8, c: u8 },
+    GetI { a: u8, b: u8, c: u8 },
+    GetField { a: u8, b: u8, key_k: u8 },
+    /* the value is K[C] when 'k', else R[C] */
+    SetTabUp { a: u8, key_k: u8, c: u8, k: bool },
+    SetTable { a: u8, b: u8, c: u8, k: bool },
+    SetI { a: u8, b: u8, c: u8, k: bool },
+    SetField { a: u8, key_k: u8, c: u8, k: bool },
+    /* B is the encoded hash size, C the array size, continued in an EXTRAARG when 'k' */
+    NewTable { a: u8, b: u8, c: u8, k: bool },
+    Self_ { a: u8, b: u8, c: u8, k: bool },
+    AddI { a: u8, b: u8, sc: i32 },
+    AddK { a: u8, b: u8, c: u8 },
+    SubK { a: u8, b: u8, c: u8 },
+    MulK { a: u8, b: u8, c: u8 },
+    ModK { a: u8, b: u8, c: u8 },
+    PowK { a: u8, b: u8, c: u8 },
+    DivK { a: u8, b: u8, c: u8 },
+    IDivK { a: u8, b: u8, c: u8 },
+    BAndK { a: u8, b: u8, c: u8 },
+    BOrK { a: u8, b: u8, c: u8 },
+    BXorK { a: u8, b: u8, c: u8 },
+    ShrI { a: u8, b: u8, sc: i32 },
+    ShlI { a: u8, b: u8, sc: i32 },
+    Add { a: u8, b: u8, c: u8 },
+    Sub { a: u8, b: u8, c: u8 },
+    Mul { a: u8, b: u8, c: u8 },
+    Mod { a: u8, b: u8, c: u8 },
+    Pow { a: u8, b: u8, c: u8 },
+    Div { a: u8, b: u8, c: u8 },
+    IDiv { a: u8, b: u8, c: u8 },
+    BAnd { a: u8, b: u8, c: u8 },
+    BOr { a: u8, b: u8, c: u8 },
+    BXor { a: u8, b: u8, c: u8 },
+    Shl { a: u8, b: u8, c: u8 },
+    Shr { a: u8, b: u8, c: u8 },
+    /* 'tm' is the event, 'k' swaps the operands */
+    MmBin { a: u8, b: u8, tm: u8 },
+    MmBinI { a: u8, sb: i32, tm: u8, k: bool },
+    MmBinK { a: u8, b_k: u8, tm: u8, k: bool },
+    Unm { a: u8, b: u8 },
+    BNot { a: u8, b: u8 },
+    Not { a: u8, b: u8 },
+    Len { a: u8, b: u8 },
+    Concat { a: u8, b: u8 },
+    Close { a: u8 },
+    Tbc { a: u8 },
+    Jmp { sj: i32 },
+    Eq { a: u8, b: u8, k: bool },
+    Lt { a: u8, b: u8, k: bool },
+    Le { a: u8, b: u8, k: bool },
+    EqK { a: u8, b_k: u8, k: bool },
+    /* 'is_float' when the immediate came from a float */
+    EqI { a: u8, sb: i32, is_float: bool, k: bool },
+    LtI { a: u8, sb: i32, is_float: bool, k: bool },
+    LeI { a: u8, sb: i32, is_float: bool, k: bool },
+    GtI { a: u8, sb: i32, is_float: bool, k: bool },
+    GeI { a: u8, sb: i32, is_float: bool, k: bool },
+    Test { a: u8, k: bool },
+    TestSet { a: u8, b: u8, k: bool },
+    Call { a: u8, b: u8, c: u8 },
+    TailCall { a: u8, b: u8, c: u8, k: bool },
+    Return { a: u8, b: u8, c: u8, k: bool },
+    /* luac keeps A, the first register of the returned values, which are none */
+    Return0 { a: u8 },
+    Return1 { a: u8 },
+    ForLoop { a: u8, bx: u32 },
+    ForPrep { a: u8, bx: u32 },
+    TForPrep { a: u8, bx: u32 },
+    TForCall { a: u8, c: u8 },
+    TForLoop { a: u8, bx: u32 },
+    SetList { a: u8, b: u8, c: u8, k: bool },
+    Closure { a: u8, bx: u32 },
+    VarArg { a: u8, c: u8 },
+    VarArgPrep { a: u8 },
+    ExtraArg { ax: u32 },
+}
+
+impl LuaInstruction {
+    /**
+     * The instruction with the operands of its opcode
+     */
+    pub fn decoded(&self) -> Instruction {
+        use Instruction::*;
+        let args = &self.args;
+        let (a, b, c, k) = (args.get_A(), args.get_B(), args.get_C(), args.get_k());
+        let (sb, sc) = (args.get_sB(), args.get_sC());
+        match self.opcode {
+            LuaOpcode::MOVE_AB => Move { a, b },
+            LuaOpcode::LOADI_AsBx => LoadI { a, sbx: args.get_sBx() },
+            LuaOpcode::LOADF_AsBx => LoadF { a, sbx: args.get_sBx() },
+            LuaOpcode::LOADK_ABx => LoadK { a, bx: args.get_Bx() },
+            LuaOpcode::LOADKX_A => LoadKX { a },
+            LuaOpcode::LOADFALSE_A => LoadFalse { a },
+            LuaOpcode::LFALSESKIP_A => LFalseSkip { a },
+            LuaOpcode::LOADTRUE_A => LoadTrue { a },
+            LuaOpcode::LOADNIL_ABC => LoadNil { a, b },
+            LuaOpcode::GETUPVAL_AB => GetUpval { a, b },
+            LuaOpcode::SETUPVAL_AB => SetUpval { a, b },
+            LuaOpcode::GETTABUP_ABC => GetTabUp { a, b, key_k: c },
+            LuaOpcode::GETTABLE_ABC => GetTable { a, b, c },
+            LuaOpcode::GETI_ABC => GetI { a, b, c },
+            LuaOpcode::GETFIELD_ABC => GetField { a, b, key_k: c },
+            LuaOpcode::SETTABUP_ABC => SetTabUp { a, key_k: b, c, k },
+            LuaOpcode::SETTABLE_ABC => SetTable { a, b, c, k },
+            LuaOpcode::SETI_ABC => SetI { a, b, c, k },
+            LuaOpcode::SETFIELD_ABC => SetField { a, key_k: b, c, k },
+            LuaOpcode::NEWTABLE_ABCk => NewTable { a, b, c, k },
+            LuaOpcode::SELF_ABC => Self_ { a, b, c, k },
+            LuaOpcode::ADDI_ABsC => AddI { a, b, sc },
+            LuaOpcode::ADDK_ABC => AddK { a, b, c },
+            LuaOpcode::SUBK_ABC => SubK { a, b, c },
+            LuaOpcode::MULK_ABC => MulK { a, b, c },
+            LuaOpcode::MODK_ABC => ModK { a, b, c },
+            LuaOpcode::POWK_ABC => PowK { a, b, c },
+            LuaOpcode::DIVK_ABC => DivK { a, b, c },
+            LuaOpcode::IDIVK_ABC => IDivK { a, b, c },
+            LuaOpcode::BANDK_ABC => BAndK { a, b, c },
+            LuaOpcode::BORK_ABC => BOrK { a, b, c },
+            LuaOpcode::BXORK_ABC => BXorK { a, b, c },
+            LuaOpcode::SHRI_ABsC => ShrI { a, b, sc },
+            LuaOpcode::SHLI_ABsC => ShlI { a, b, sc },
+            LuaOpcode::ADD_ABC => Add { a, b, c },
+            LuaOpcode::SUB_ABC => Sub { a, b, c },
+            LuaOpcode::MUL_ABC => Mul { a, b, c },
+            LuaOpcode::MOD_ABC => Mod { a, b, c },
+            LuaOpcode::POW_ABC => Pow { a, b, c },
+            LuaOpcode::DIV_ABC => Div { a, b, c },
+            LuaOpcode::IDIV_ABC => IDiv { a, b, c },
+            LuaOpcode::BAND_ABC => BAnd { a, b, c },
+            LuaOpcode::BOR_ABC => BOr { a, b, c },
+            LuaOpcode::BXOR_ABC => BXor { a, b, c },
+            LuaOpcode::SHL_ABC => Shl { a, b, c },
+            LuaOpcode::SHR_ABC => Shr { a, b, c },
+            LuaOpcode::MMBIN_ABC => MmBin { a, b, tm: c },
+            LuaOpcode::MMBINI_AsBCk => MmBinI { a, sb, tm: c, k },
+            LuaOpcode::MMBINK_ABCk => MmBinK { a, b_k: b, tm: c, k },
+            LuaOpcode::UNM_AB => Unm { a, b },
+            LuaOpcode::BNOT_AB => BNot { a, b },
+            LuaOpcode::NOT_AB => Not { a, b },
+            LuaOpcode::LEN_AB => Len { a, b },
+            LuaOpcode::CONCAT_AB => Concat { a, b },
+            LuaOpcode::CLOSE_A => Close { a },
+            LuaOpcode::TBC_A => Tbc { a },
+            LuaOpcode::JMP_sJ => Jmp { sj: args.get_sJ() },
+            LuaOpcode::EQ_ABk => Eq { a, b, k },
+            LuaOpcode::LT_ABk => Lt { a, b, k },
+            LuaOpcode::LE_ABk => Le { a, b, k },
+            LuaOpcode::EQK_ABk => EqK { a, b_k: b, k },
+            LuaOpcode::EQI_AsBk => EqI { a, sb, is_float: c != 0, k },
+            LuaOpcode::LTI_AsBk => LtI { a, sb, is_float: c != 0, k },
+            LuaOpcode::LEI_AsBk => LeI { a, sb, is_float: c != 0, k },
+            LuaOpcode::GTI_AsBk => GtI { a, sb, is_float: c != 0, k },
+            LuaOpcode::GEI_AsBk => GeI { a, sb, is_float: c != 0, k },
+            LuaOpcode::TEST_Ak => Test { a, k },
+            LuaOpcode::TESTSET_ABk => TestSet { a, b, k },
+            LuaOpcode::CALL_ABC => Call { a, b, c },
+            LuaOpcode::TAILCALL_ABCk => TailCall { a, b, c, k },
+            LuaOpcode::RETURN_ABCk => Return { a, b, c, k },
+            LuaOpcode::RETURN0 => Return0 { a },
+            LuaOpcode::RETURN1_A => Return1 { a },
+            LuaOpcode::FORLOOP_ABx => ForLoop { a, bx: args.get_Bx() },
+            LuaOpcode::FORPREP_ABx => ForPrep { a, bx: args.get_Bx() },
+            LuaOpcode::TFORPREP_ABx => TForPrep { a, bx: args.get_Bx() },
+            LuaOpcode::TFORCALL_AC => TForCall { a, c },
+            LuaOpcode::TFORLOOP_ABx => TForLoop { a, bx: args.get_Bx() },
+            LuaOpcode::SETLIST_ABCk => SetList { a, b, c, k },
+            LuaOpcode::CLOSURE_ABx => Closure { a, bx: args.get_Bx() },
+            LuaOpcode::VARARG_AC => VarArg { a, c },
+            LuaOpcode::VARARGPREP_A => VarArgPrep { a },
+            LuaOpcode::EXTRAARG_Ax => ExtraArg { ax: args.get_Ax() },
+        }
+    }
+}
+
+impl Instruction {
+    pub fn decode(raw: u32) -> Instruction {
+        opcodes::decode(raw).decoded()
+    }
+
+    pub fn opcode(&self) -> LuaOpcode {
+        use Instruction::*;
+        match self {
+            Move { .. } => LuaOpcode::MOVE_AB,
+            LoadI { .. } => LuaOpcode::LOADI_AsBx,
+            LoadF { .. } => LuaOpcode::LOADF_AsBx,
+            LoadK { .. } => LuaOpcode::LOADK_ABx,
+            LoadKX { .. } => LuaOpcode::LOADKX_A,
+            LoadFalse { .. } => LuaOpcode::LOADFALSE_A,
+            LFalseSkip { .. } => LuaOpcode::LFALSESKIP_A,
+            LoadTrue { .. } => LuaOpcode::LOADTRUE_A,
+            LoadNil { .. } => LuaOpcode::LOADNIL_ABC,
+            GetUpval { .. } => LuaOpcode::GETUPVAL_AB,
+            SetUpval { .. } => LuaOpcode::SETUPVAL_AB,
+            GetTabUp { .. } => LuaOpcode::GETTABUP_ABC,
+            GetTable { .. } => LuaOpcode::GETTABLE_ABC,
+            GetI { .. } => LuaOpcode::GETI_ABC,
+            GetField { .. } => LuaOpcode::GETFIELD_ABC,
+            SetTabUp { .. } => LuaOpcode::SETTABUP_ABC,
+            SetTable { .. } => LuaOpcode::SETTABLE_ABC,
+            SetI { .. } => LuaOpcode::SETI_ABC,
+            SetField { .. } => LuaOpcode::SETFIELD_ABC,
+            NewTable { .. } => LuaOpcode::NEWTABLE_ABCk,
+            Self_ { .. } => LuaOpcode::SELF_ABC,
+            AddI { .. } => LuaOpcode::ADDI_ABsC,
+            AddK { .. } => LuaOpcode::ADDK_ABC,
+            SubK { .. } => LuaOpcode::SUBK_ABC,
+            MulK { .. } => LuaOpcode::MULK_ABC,
+            ModK { .. } => LuaOpcode::MODK_ABC,
+            PowK { .. } => LuaOpcode::POWK_ABC,
+            DivK { .. } => LuaOpcode::DIVK_ABC,
+            IDivK { .. } => LuaOpcode::IDIVK_ABC,
+            BAndK { .. } => LuaOpcode::BANDK_ABC,
+            BOrK { .. } => LuaOpcode::BORK_ABC,
+            BXorK { .. } => LuaOpcode::BXORK_ABC,
+            ShrI { .. } => LuaOpcode::SHRI_ABsC,
+            ShlI { .. } => LuaOpcode::SHLI_ABsC,
+            Add { .. } => LuaOpcode::ADD_ABC,
+            Sub { .. } => LuaOpcode::SUB_ABC,
+            Mul { .. } => LuaOpcode::MUL_ABC,
+            Mod { .. } => LuaOpcode::MOD_ABC,
+            Pow { .. } => LuaOpcode::POW_ABC,
+            Div { .. } => LuaOpcode::DIV_ABC,
+            IDiv { .. } => LuaOpcode::IDIV_ABC,
+            BAnd { .. } => LuaOpcode::BAND_ABC,
+            BOr { .. } => LuaOpcode::BOR_ABC,
+            BXor { .. } => LuaOpcode::BXOR_ABC,
+            Shl { .. } => LuaOpcode::SHL_ABC,
+            Shr { .. } => LuaOpcode::SHR_ABC,
+            MmBin { .. } => LuaOpcode::MMBIN_ABC,
+            MmBinI { .. } => LuaOpcode::MMBINI_AsBCk,
+            MmBinK { .. } => LuaOpcode::MMBINK_ABCk,
+            Unm { .. } => LuaOpcode::UNM_AB,
+            BNot { .. } => LuaOpcode::BNOT_AB,
+            Not { .. } => LuaOpcode::NOT_AB,
+            Len { .. } => LuaOpcode::LEN_AB,
+            Concat { .. } => LuaOpcode::CONCAT_AB,
+            Close { .. } => LuaOpcode::CLOSE_A,
+            Tbc { .. } => LuaOpcode::TBC_A,
+            Jmp { .. } => LuaOpcode::JMP_sJ,
+            Eq { .. } => LuaOpcode::EQ_ABk,
+            Lt { .. } => LuaOpcode::LT_ABk,
+            Le { .. } => LuaOpcode::LE_ABk,
+            EqK { .. } => LuaOpcode::EQK_ABk,
+            EqI { .. } => LuaOpcode::EQI_AsBk,
+            LtI { .. } => LuaOpcode::LTI_AsBk,
+            LeI { .. } => LuaOpcode::LEI_AsBk,
+            GtI { .. } => LuaOpcode::GTI_AsBk,
+            GeI { .. } => LuaOpcode::GEI_AsBk,
+            Test { .. } => LuaOpcode::TEST_Ak,
+            TestSet { .. } => LuaOpcode::TESTSET_ABk,
+            Call { .. } => LuaOpcode::CALL_ABC,
+            TailCall { .. } => LuaOpcode::TAILCALL_ABCk,
+            Return { .. } => LuaOpcode::RETURN_ABCk,
+            Return0 { .. } => LuaOpcode::RETURN0,
+            Return1 { .. } => LuaOpcode::RETURN1_A,
+            ForLoop { .. } => LuaOpcode::FORLOOP_ABx,
+            ForPrep { .. } => LuaOpcode::FORPREP_ABx,
+            TForPrep { .. } => LuaOpcode::TFORPREP_ABx,
+            TForCall { .. } => LuaOpcode::TFORCALL_AC,
+            TForLoop { .. } => LuaOpcode::TFORLOOP_ABx,
+            SetList { .. } => LuaOpcode::SETLIST_ABCk,
+            Closure { .. } => LuaOpcode::CLOSURE_ABx,
+            VarArg { .. } => LuaOpcode::VARARG_AC,
+            VarArgPrep { .. } => LuaOpcode::VARARGPREP_A,
+            ExtraArg { .. } => LuaOpcode::EXTRAARG_Ax,
+        }
+    }
+
+    /**
+     * The encoded instruction, failing for operands that don't fit their fields
+     */
+    pub fn encode(&self) -> EncodeResult {
+        use Instruction::*;
+        let op = self.opcode();
+        let abck = |a: u8, b: u32, c: u32, k: bool| opcodes::encode_abck(op, a.into(), b, c, k);
+        match *self {
+            Move { a, b } | LoadNil { a, b } | GetUpval { a, b } | SetUpval { a, b } | Unm { a, b } |
+            BNot { a, b } | Not { a, b } | Len { a, b } | Concat { a, b } => abck(a, b.into(), 0, false),
+            LoadI { a, sbx } | LoadF { a, sbx } => opcodes::encode_asbx(op, a.into(), sbx),
+            LoadK { a, bx } | ForLoop { a, bx } | ForPrep { a, bx } | TForPrep { a, bx } | TForLoop { a, bx } |
+            Closure { a, bx } => opcodes::encode_abx(op, a.into(), bx),
+            LoadKX { a } | LoadFalse { a } | LFalseSkip { a } | LoadTrue { a } | Close { a } | Tbc { a } |
+            VarArgPrep { a } => abck(a, 0, 0, false),
+            // B is the number of values plus one, as luaK_ret writes it
+            Return0 { a } => abck(a, 1, 0, false),
+            Return1 { a } => abck(a, 2, 0, false),
+            GetTabUp { a, b, key_k: c } | GetTable { a, b, c } | GetI { a, b, c } | GetField { a, b, key_k: c } |
+            AddK { a, b, c } | SubK { a, b, c } | MulK { a, b, c } | ModK { a, b, c } | PowK { a, b, c } |
+            DivK { a, b, c } | IDivK { a, b, c } | BAndK { a, b, c } | BOrK { a, b, c } | BXorK { a, b, c } |
+            Add { a, b, c } | Sub { a, b, c } | Mul { a, b, c } | Mod { a, b, c } | Pow { a, b, c } |
+            Div { a, b, c } | IDiv { a, b, c } | BAnd { a, b, c } | BOr { a, b, c } | BXor { a, b, c } |
+            Shl { a, b, c } | Shr { a, b, c } | MmBin { a, b, tm: c } | Call { a, b, c } => abck(a, b.into(), c.into(), false),
+            SetTabUp { a, key_k: b, c, k } | SetTable { a, b, c, k } | SetI { a, b, c, k } |
+            SetField { a, key_k: b, c, k } | NewTable { a, b, c, k } | Self_ { a, b, c, k } |
+            MmBinK { a, b_k: b, tm: c, k } | TailCall { a, b, c, k } | Return { a, b, c, k } |
+            SetList { a, b, c, k } => abck(a, b.into(), c.into(), k),
+            AddI { a, b, sc } | ShrI { a, b, sc } | ShlI { a, b, sc } => abck(a, b.into(), signed_arg(sc)?, false),
+            MmBinI { a, sb, tm, k } => abck(a, signed_arg(sb)?, tm.into(), k),
+            Jmp { sj } => opcodes::encode_sj(op, sj),
+            Eq { a, b, k } | Lt { a, b, k } | Le { a, b, k } | EqK { a, b_k: b, k } | TestSet { a, b, k } => {
+                abck(a, b.into(), 0, k)
+            },
+            EqI { a, sb, is_float, k } | LtI { a, sb, is_float, k } | LeI { a, sb, is_float, k } |
+            GtI { a, sb, is_float, k } | GeI { a, sb, is_float, k } => abck(a, signed_arg(sb)?, is_float.into(), k),
+            Test { a, k } => abck(a, 0, 0, k),
+            TForCall { a, c } | VarArg { a, c } => abck(a, 0, c.into(), false),
+            ExtraArg { ax } => opcodes::encode_ax(op, ax),
+        }
+    }
+}
+
+impl Instruction {
+    /**
+     * Operands in the order listings write them, and whether the last one carries a 'k'
+     * suffix. Tests write their k as a 0 or 1 operand instead
+     */
+    pub fn operands(&self) -> (Vec<i64>, bool) {
+        use Instruction::*;
+        let operands = match *self {
+            Move { a, b } | LoadNil { a, b } | GetUpval { a, b } | SetUpval { a, b } | Unm { a, b } |
+            BNot { a, b } | Not { a, b } | Len { a, b } | Concat { a, b } => vec![a.into(), b.into()],
+            LoadI { a, sbx } | LoadF { a, sbx } => vec![a.into(), sbx.into()],
+            LoadK { a, bx } | ForLoop { a, bx } | ForPrep { a, bx } | TForPrep { a, bx } | TForLoop { a, bx } |
+            Closure { a, bx } => vec![a.into(), bx.into()],
+            LoadKX { a } | LoadFalse { a } | LFalseSkip { a } | LoadTrue { a } | Close { a } | Tbc { a } |
+            Return0 { a } | Return1 { a } | VarArgPrep { a } => vec![a.into()],
+            GetTabUp { a, b, key_k: c } | GetTable { a, b, c } | GetI { a, b, c } | GetField { a, b, key_k: c } |
+            AddK { a, b, c } | SubK { a, b, c } | MulK { a, b, c } | ModK { a, b, c } | PowK { a, b, c } |
+            DivK { a, b, c } | IDivK { a, b, c } | BAndK { a, b, c } | BOrK { a, b, c } | BXorK { a, b, c } |
+            Add { a, b, c } | Sub { a, b, c } | Mul { a, b, c } | Mod { a, b, c } | Pow { a, b, c } |
+            Div { a, b, c } | IDiv { a, b, c } | BAnd { a, b, c } | BOr { a, b, c } | BXor { a, b, c } |
+            Shl { a, b, c } | Shr { a, b, c } | MmBin { a, b, tm: c } | Call { a, b, c } => {
+                vec![a.into(), b.into(), c.into()]
+            },
+            SetTabUp { a, key_k: b, c, k } | SetTable { a, b, c, k } | SetI { a, b, c, k } |
+            SetField { a, key_k: b, c, k } | NewTable { a, b, c, k } | Self_ { a, b, c, k } |
+            MmBinK { a, b_k: b, tm: c, k } | TailCall { a, b, c, k } | Return { a, b, c, k } |
+            SetList { a, b, c, k } => return (vec![a.into(), b.into(), c.into()], k),
+            MmBinI { a, sb, tm, k } => return (vec![a.into(), sb.into(), tm.into()], k),
+            AddI { a, b, sc } | ShrI { a, b, sc } | ShlI { a, b, sc } => vec![a.into(), b.into(), sc.into()],
+            Jmp { sj } => vec![sj.into()],
+            Eq { a, b, k } | Lt { a, b, k } | Le { a, b, k } | EqK { a, b_k: b, k } | TestSet { a, b, k } => {
+                vec![a.into(), b.into(), k.into()]
+            },
+            EqI { a, sb, is_float, k } | LtI { a, sb, is_float, k } | LeI { a, sb, is_float, k } |
+            GtI { a, sb, is_float, k } | GeI { a, sb, is_float, k } => vec![a.into(), sb.into(), k.into(), is_float.into()],
+            Test { a, k } => vec![a.into(), k.into()],
+            TForCall { a, c } | VarArg { a, c } => vec![a.into(), c.into()],
+            ExtraArg { ax } => vec![ax.into()],
+        };
+        (operands, false)
+    }
+
+    /**
+     * The instruction of 'opcode' from its listed operands, the inverse of 'operands'
+     */
+    pub fn from_operands(opcode: LuaOpcode, operands: &[i64], k: bool) -> Result<Instruction, String> {
+        use Instruction::*;
+        let arity = match opcode {
+            LuaOpcode::JMP_sJ | LuaOpcode::EXTRAARG_Ax | LuaOpcode::LOADKX_A | LuaOpcode::LOADFALSE_A |
+            LuaOpcode::LFALSESKIP_A | LuaOpcode::LOADTRUE_A | LuaOpcode::CLOSE_A | LuaOpcode::TBC_A |
+            LuaOpcode::RETURN0 | LuaOpcode::RETURN1_A | LuaOpcode::VARARGPREP_A => 1,
+            LuaOpcode::EQI_AsBk | LuaOpcode::LTI_AsBk | LuaOpcode::LEI_AsBk | LuaOpcode::GTI_AsBk |
+            LuaOpcode::GEI_AsBk => 4,
+            LuaOpcode::MOVE_AB | LuaOpcode::LOADNIL_ABC | LuaOpcode::GETUPVAL_AB | LuaOpcode::SETUPVAL_AB |
+            LuaOpcode::UNM_AB | LuaOpcode::BNOT_AB | LuaOpcode::NOT_AB | LuaOpcode::LEN_AB | LuaOpcode::CONCAT_AB |
+            LuaOpcode::LOADI_AsBx | LuaOpcode::LOADF_AsBx | LuaOpcode::LOADK_ABx | LuaOpcode::FORLOOP_ABx |
+            LuaOpcode::FORPREP_ABx | LuaOpcode::TFORPREP_ABx | LuaOpcode::TFORLOOP_ABx | LuaOpcode::CLOSURE_ABx |
+            LuaOpcode::TEST_Ak | LuaOpcode::TFORCALL_AC | LuaOpcode::VARARG_AC => 2,
+            _ => 3,
+        };
+        if operands.len() != arity {
+            return Err(format!("{} takes {} operands", opcode.mnemonic(), arity));
+        }
+        let suffix = matches!(opcode,
+            LuaOpcode::SETTABUP_ABC | LuaOpcode::SETTABLE_ABC | LuaOpcode::SETI_ABC | LuaOpcode::SETFIELD_ABC |
+            LuaOpcode::NEWTABLE_ABCk | LuaOpcode::SELF_ABC | LuaOpcode::MMBINI_AsBCk | LuaOpcode::MMBINK_ABCk |
+            LuaOpcode::TAILCALL_ABCk | LuaOpcode::RETURN_ABCk | LuaOpcode::SETLIST_ABCk);
+        if k && !suffix {
+            return Err(format!("{} has no k suffix", opcode.mnemonic()));
+        }
+        let unsigned = |i: usize, name: &str| {
+            u8::try_from(operands[i]).map_err(|_| format!("operand {} out of range ({})", name, operands[i]))
+        };
+        let wide = |i: usize, name: &str| {
+            u32::try_from(operands[i]).map_err(|_| format!("operand {} out of range ({})", name, operands[i]))
+        };
+        let signed = |i: usize, name: &str| {
+            i32::try_from(operands[i]).map_err(|_| format!("operand {} out of range ({})", name, operands[i]))
+        };
+        let flag = |i: usize, name: &str| match operands[i] {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(format!("operand {} must be 0 or 1 ({})", name, other)),
+        };
+        if opcode == LuaOpcode::JMP_sJ {
+            return Ok(Jmp { sj: signed(0, "sJ")? });
+        }
+        if opcode == LuaOpcode::EXTRAARG_Ax {
+            return Ok(ExtraArg { ax: wide(0, "Ax")? });
+        }
+        let a = unsigned(0, "A")?;
+        let instruction = match opcode {
+            LuaOpcode::MOVE_AB => Move { a, b: unsigned(1, "B")? },
+            LuaOpcode::LOADI_AsBx => LoadI { a, sbx: signed(1, "sBx")? },
+            LuaOpcode::LOADF_AsBx => LoadF { a, sbx: signed(1, "sBx")? },
+            LuaOpcode::LOADK_ABx => LoadK { a, bx: wide(1, "Bx")? },
+            LuaOpcode::LOADKX_A => LoadKX { a },
+            LuaOpcode::LOADFALSE_A => LoadFalse { a },
+            LuaOpcode::LFALSESKIP_A => LFalseSkip { a },
+            LuaOpcode::LOADTRUE_A => LoadTrue { a },
+            LuaOpcode::LOADNIL_ABC => LoadNil { a, b: unsigned(1, "B")? },
+            LuaOpcode::GETUPVAL_AB => GetUpval { a, b: unsigned(1, "B")? },
+            LuaOpcode::SETUPVAL_AB => SetUpval { a, b: unsigned(1, "B")? },
+            LuaOpcode::UNM_AB => Unm { a, b: unsigned(1, "B")? },
+            LuaOpcode::BNOT_AB => BNot { a, b: unsigned(1, "B")? },
+            LuaOpcode::NOT_AB => Not { a, b: unsigned(1, "B")? },
+            LuaOpcode::LEN_AB => Len { a, b: unsigned(1, "B")? },
+            LuaOpcode::CONCAT_AB => Concat { a, b: unsigned(1, "B")? },
+            LuaOpcode::CLOSE_A => Close { a },
+            LuaOpcode::TBC_A => Tbc { a },
+            LuaOpcode::EQ_ABk => Eq { a, b: unsigned(1, "B")?, k: flag(2, "k")? },
+            LuaOpcode::LT_ABk => Lt { a, b: unsigned(1, "B")?, k: flag(2, "k")? },
+            LuaOpcode::LE_ABk => Le { a, b: unsigned(1, "B")?, k: flag(2, "k")? },
+            LuaOpcode::EQK_ABk => EqK { a, b_k: unsigned(1, "B")?, k: flag(2, "k")? },
+            LuaOpcode::EQI_AsBk => EqI { a, sb: signed(1, "sB")?, k: flag(2, "k")?, is_float: flag(3, "C")? },
+            LuaOpcode::LTI_AsBk => LtI { a, sb: signed(1, "sB")?, k: flag(2, "k")?, is_float: flag(3, "C")? },
+            LuaOpcode::LEI_AsBk => LeI { a, sb: signed(1, "sB")?, k: flag(2, "k")?, is_float: flag(3, "C")? },
+            LuaOpcode::GTI_AsBk => GtI { a, sb: signed(1, "sB")?, k: flag(2, "k")?, is_float: flag(3, "C")? },
+            LuaOpcode::GEI_AsBk => GeI { a, sb: signed(1, "sB")?, k: flag(2, "k")?, is_float: flag(3, "C")? },
+            LuaOpcode::TEST_Ak => Test { a, k: flag(1, "k")? },
+            LuaOpcode::TESTSET_ABk => TestSet { a, b: unsigned(1, "B")?, k: flag(2, "k")? },
+            LuaOpcode::RETURN0 => Return0 { a },
+            LuaOpcode::RETURN1_A => Return1 { a },
+            LuaOpcode::FORLOOP_ABx => ForLoop { a, bx: wide(1, "Bx")? },
+            LuaOpcode::FORPREP_ABx => ForPrep { a, bx: wide(1, "Bx")? },
+            LuaOpcode::TFORPREP_ABx => TForPrep { a, bx: wide(1, "Bx")? },
+            LuaOpcode::TFORCALL_AC => TForCall { a, c: unsigned(1, "C")? },
+            LuaOpcode::TFORLOOP_ABx => TForLoop { a, bx: wide(1, "Bx")? },
+            LuaOpcode::CLOSURE_ABx => Closure { a, bx: wide(1, "Bx")? },
+            LuaOpcode::VARARG_AC => VarArg { a, c: unsigned(1, "C")? },
+            LuaOpcode::VARARGPREP_A => VarArgPrep { a },
+            LuaOpcode::ADDI_ABsC => AddI { a, b: unsigned(1, "B")?, sc: signed(2, "sC")? },
+            LuaOpcode::SHRI_ABsC => ShrI { a, b: unsigned(1, "B")?, sc: signed(2, "sC")? },
+            LuaOpcode::SHLI_ABsC => ShlI { a, b: unsigned(1, "B")?, sc: signed(2, "sC")? },
+            LuaOpcode::MMBINI_AsBCk => MmBinI { a, sb: signed(1, "sB")?, tm: unsigned(2, "C")?, k },
+            _ => {
+                let (b, c) = (unsigned(1, "B")?, unsigned(2, "C")?);
+                match opcode {
+                    LuaOpcode::GETTABUP_ABC => GetTabUp { a, b, key_k: c },
+                    LuaOpcode::GETTABLE_ABC => GetTable { a, b, c },
+                    LuaOpcode::GETI_ABC => GetI { a, b, c },
+                    LuaOpcode::GETFIELD_ABC => GetField { a, b, key_k: c },
+                    LuaOpcode::SETTABUP_ABC => SetTabUp { a, key_k: b, c, k },
+                    LuaOpcode::SETTABLE_ABC => SetTable { a, b, c, k },
+                    LuaOpcode::SETI_ABC => SetI { a, b, c, k },
+                    LuaOpcode::SETFIELD_ABC => SetField { a, key_k: b, c, k },
+                    LuaOpcode::NEWTABLE_ABCk => NewTable { a, b, c, k },
+                    LuaOpcode::SELF_ABC => Self_ { a, b, c, k },
+                    LuaOpcode::ADDK_ABC => AddK { a, b, c },
+                    LuaOpcode::SUBK_ABC => SubK { a, b, c },
+                    LuaOpcode::MULK_ABC => MulK { a, b, c },
+                    LuaOpcode::MODK_ABC => ModK { a, b, c },
+                    LuaOpcode::POWK_ABC => PowK { a, b, c },
+                    LuaOpcode::DIVK_ABC => DivK { a, b, c },
+                    LuaOpcode::IDIVK_ABC => IDivK { a, b, c },
+                    LuaOpcode::BANDK_ABC => BAndK { a, b, c },
+                    LuaOpcode::BORK_ABC => BOrK { a, b, c },
+                    LuaOpcode::BXORK_ABC => BXorK { a, b, c },
+                    LuaOpcode::ADD_ABC => Add { a, b, c },
+                    LuaOpcode::SUB_ABC => Sub { a, b, c },
+                    LuaOpcode::MUL_ABC => Mul { a, b, c },
+                    LuaOpcode::MOD_ABC => Mod { a, b, c },
+                    LuaOpcode::POW_ABC => Pow { a, b, c },
+                    LuaOpcode::DIV_ABC => Div { a, b, c },
+                    LuaOpcode::IDIV_ABC => IDiv { a, b, c },
+                    LuaOpcode::BAND_ABC => BAnd { a, b, c },
+                    LuaOpcode::BOR_ABC => BOr { a, b, c },
+                    LuaOpcode::BXOR_ABC => BXor { a, b, c },
+                    LuaOpcode::SHL_ABC => Shl { a, b, c },
+                    LuaOpcode::SHR_ABC => Shr { a, b, c },
+                    LuaOpcode::MMBIN_ABC => MmBin { a, b, tm: c },
+                    LuaOpcode::MMBINK_ABCk => MmBinK { a, b_k: b, tm: c, k },
+                    LuaOpcode::CALL_ABC => Call { a, b, c },
+                    LuaOpcode::TAILCALL_ABCk => TailCall { a, b, c, k },
+                    LuaOpcode::RETURN_ABCk => Return { a, b, c, k },
+                    LuaOpcode::SETLIST_ABCk => SetList { a, b, c, k },
+                    _ => unreachable!("{:?} has other operands", opcode),
+                }
+            },
+        };
+        Ok(instruction)
+    }
+}
+
+/**
+ * The instruction as listings write it: the opcode and its operands
+ */
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (operands, k) = self.operands();
+        write!(f, "{:<9}", self.opcode().mnemonic())?;
+        for operand in operands {
+            write!(f, " {}", operand)?;
+        }
+        if k {
+            write!(f, "k")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Instruction;
+    use crate::core::{builder::ProtoBuilder, opcodes::LuaOpcode, parser::parse_all, types::TValue};
+
+    #[test]
+    fn decode_named_operands() {
+        assert_eq!(Instruction::decode(0x00030000), Instruction::Move { a: 0, b: 3 });
+        let get = Instruction::GetTabUp { a: 1, b: 0, key_k: 7 };
+        let raw = get.encode().unwrap().raw();
+        assert_eq!(Instruction::decode(raw), get);
+        assert_eq!(get.opcode(), LuaOpcode::GETTABUP_ABC);
+        // the whole sC range, 128 included
+        for sc in [-127, 0, 128] {
+            let addi = Instruction::AddI { a: 0, b: 1, sc };
+            assert_eq!(Instruction::decode(addi.encode().unwrap().raw()), addi);
+        }
+        assert!(Instruction::AddI { a: 0, b: 1, sc: 129 }.encode().is_err());
+        assert!(Instruction::LoadK { a: 0, bx: 1 << 17 }.encode().is_err());
+    }
+
+    #[test]
+    fn encode_inverts_decoded() {
+        let chunks: [&[u8]; 4] = [
+            include_bytes!("../../helpers/out1"),
+            include_bytes!("../../helpers/luac.out"),
+            include_bytes!("../../helpers/tests/base.out"),
+            include_bytes!("../../helpers/tests/string.out"),
+        ];
+        for chunk in chunks {
+            let proto = parse_all(chunk).unwrap();
+            let mut protos = vec![&proto];
+            while let Some(p) = protos.pop() {
+                for instruction in &p.code {
+                    let decoded = instruction.decoded();
+                    assert_eq!(decoded.opcode(), instruction.opcode);
+                    assert_eq!(decoded.encode().unwrap().raw(), instruction.raw(), "{}", decoded);
+                }
+                protos.extend(p.fns.iter().map(|f| f.as_ref()));
+            }
+        }
+    }
+
+    #[test]
+    fn display_operands() {
+        assert_eq!(Instruction::GetTabUp { a: 0, b: 0, key_k: 1 }.to_string(), "GETTABUP  0 0 1");
+        assert_eq!(Instruction::SetField { a: 0, key_k: 1, c: 2, k: true }.to_string(), "SETFIELD  0 1 2k");
+        assert_eq!(Instruction::Jmp { sj: -4 }.to_string(), "JMP       -4");
+        assert_eq!(Instruction::Return0 { a: 2 }.to_string(), "RETURN0   2");
+        assert_eq!(Instruction::GtI { a: 1, sb: -2, is_float: true, k: false }.to_string(), "GTI       1 -2 0 1");
+    }
+
+    #[test]
+    fn display_with_constants() {
+        let mut f = ProtoBuilder::new("=test");
+        f.constant(TValue::from_str("a"));
+        let k = f.constant(TValue::from_str("b"));
+        f.emit(Instruction::LoadK { a: 0, bx: k as u32 }.encode().unwrap());
+        f.emit(Instruction::Return0 { a: 0 }.encode().unwrap());
+        let proto = f.build().unwrap();
+        assert_eq!(proto.display_opcode(&proto.code[0]), "LOADK     0 1\t; Str(b)");
+        assert_eq!(proto.display_opcode(&proto.code[1]), "RETURN0   0");
+    }
+}
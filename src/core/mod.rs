@@ -1,3 +1,7 @@
+pub mod asm;
+pub mod builder;
+pub mod dump;
+pub mod instruction;
 pub mod parser;
 pub mod types;
 pub mod opcodes;
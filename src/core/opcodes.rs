@@ -26,7 +26,8 @@ const A_ARG_SIZE: usize = 8;
 const B_ARG_POS: usize = OPCODE_BITSIZE + A_ARG_SIZE + 1;
 const B_ARG_SIZE: usize = A_ARG_SIZE;
 const C_ARG_POS: usize = B_ARG_POS + B_ARG_SIZE;
-const C_ARG_SIZE: usize = A_ARG_SIZE; 
+const C_ARG_SIZE: usize = A_ARG_SIZE;
+pub const MAXARG_C: u32 = (1 << C_ARG_SIZE) - 1;
 const BX_ARG_POS: usize = OPCODE_BITSIZE + A_ARG_SIZE;
 const BX_ARG_SIZE: usize = IX_BITSIZE - OPCODE_BITSIZE - A_ARG_SIZE;
 const AX_ARG_POS: usize = A_ARG_POS;
@@ -34,9 +35,19 @@ const AX_ARG_SIZE: usize = IX_BITSIZE - OPCODE_BITSIZE;
 const SJ_ARG_POS: usize = A_ARG_POS;
 const SJ_ARG_SIZE: usize = AX_ARG_SIZE;
 
+pub const MAXARG_A: u32 = (1 << A_ARG_SIZE) - 1;
+pub const MAXARG_B: u32 = (1 << B_ARG_SIZE) - 1;
+pub const MAXARG_BX: u32 = (1 << BX_ARG_SIZE) - 1;
+pub const MAXARG_AX: u32 = (1 << AX_ARG_SIZE) - 1;
+pub const MAXARG_SJ: u32 = (1 << SJ_ARG_SIZE) - 1;
+/* excess K of the signed arguments */
+pub const OFFSET_SBX: i32 = (MAXARG_BX >> 1) as i32;
+pub const OFFSET_SJ: i32 = (MAXARG_SJ >> 1) as i32;
+pub const OFFSET_SC: i32 = (MAXARG_C >> 1) as i32;
+
 macro_rules! bitmask {
     ($x:expr) => {
-        (1 << $x) - 1
+        ((1 << $x) - 1)
     };
 }
 
@@ -46,10 +57,13 @@ macro_rules! get_operand {
     };
 }
 
-// #[rustc_layout_scalar_valid_range_end(0b11111111111111111)]
+#[allow(non_camel_case_types)]
 type u17 = u32;
+#[allow(non_camel_case_types)]
 type i17 = i32;
+#[allow(non_camel_case_types)]
 type u25 = u32;
+#[allow(non_camel_case_types)]
 type i25 = i32;
 
 #[derive(Debug)]
@@ -57,6 +71,7 @@ pub struct LuaArgs {
     raw: u32
 }
 
+#[allow(non_snake_case)]
 impl LuaArgs {
     pub fn get_A(&self) -> u8 {
         get_operand!(self.raw, A_ARG_POS, A_ARG_SIZE) as u8
@@ -70,10 +85,8 @@ impl LuaArgs {
         get_operand!(self.raw, B_ARG_POS, B_ARG_SIZE) as u8
     }
 
-    pub fn get_sB(&self) -> i8 {
-        let b = get_operand!(self.raw, B_ARG_POS, B_ARG_SIZE) as u8;
-        const K: i32 = 0xff >> 1;
-        (b as i32 - K) as i8
+    pub fn get_sB(&self) -> i32 {
+        self.get_B() as i32 - OFFSET_SC
     }
 
     pub fn get_Bx(&self) -> u17 {
@@ -91,10 +104,8 @@ impl LuaArgs {
         get_operand!(self.raw, C_ARG_POS, C_ARG_SIZE) as u8
     }
 
-    pub fn get_sC(&self) -> i8 {
-        let c = self.get_C();
-        const K: i32 = 0xff >> 1;
-        (c as i32 - K) as i8
+    pub fn get_sC(&self) -> i32 {
+        self.get_C() as i32 - OFFSET_SC
     }
 
     pub fn get_k(&self) -> bool {
@@ -115,7 +126,19 @@ impl LuaArgs {
 ** has extra descriptions in the notes after the enumeration.
 */
 
-#[derive(PartialEq, Debug)]
+/* opcode names as luac lists them (lopnames.h) */
+const OPCODE_NAMES: [&str; 83] = [
+    "MOVE", "LOADI", "LOADF", "LOADK", "LOADKX", "LOADFALSE", "LFALSESKIP", "LOADTRUE", "LOADNIL",
+    "GETUPVAL", "SETUPVAL", "GETTABUP", "GETTABLE", "GETI", "GETFIELD", "SETTABUP", "SETTABLE", "SETI",
+    "SETFIELD", "NEWTABLE", "SELF", "ADDI", "ADDK", "SUBK", "MULK", "MODK", "POWK", "DIVK", "IDIVK", "BANDK",
+    "BORK", "BXORK", "SHRI", "SHLI", "ADD", "SUB", "MUL", "MOD", "POW", "DIV", "IDIV", "BAND", "BOR", "BXOR",
+    "SHL", "SHR", "MMBIN", "MMBINI", "MMBINK", "UNM", "BNOT", "NOT", "LEN", "CONCAT", "CLOSE", "TBC", "JMP",
+    "EQ", "LT", "LE", "EQK", "EQI", "LTI", "LEI", "GTI", "GEI", "TEST", "TESTSET", "CALL", "TAILCALL",
+    "RETURN", "RETURN0", "RETURN1", "FORLOOP", "FORPREP", "TFORPREP", "TFORCALL", "TFORLOOP", "SETLIST",
+    "CLOSURE", "VARARG", "VARARGPREP", "EXTRAARG"
+];
+
+#[derive(PartialEq, Debug, Clone, Copy)]
 #[allow(non_camel_case_types, dead_code)]
 #[repr(u8)]
 pub enum LuaOpcode {
@@ -133,7 +156,7 @@ pub enum LuaOpcode {
     LOADNIL_ABC,/*   A B     R[A], R[A+1], ..., R[A+B] := nil                */
     GETUPVAL_AB,/*  A B     R[A] := UpValue[B]                              */
     SETUPVAL_AB,/*  A B     UpValue[B] := R[A]                              */
-    GETTABUP_AB,/*  A B C   R[A] := UpValue[B][K[C]:string]                 */
+    GETTABUP_ABC,/*  A B C   R[A] := UpValue[B][K[C]:string]                 */
     GETTABLE_ABC,/*  A B C   R[A] := R[B][R[C]]                              */
     GETI_ABC,/*      A B C   R[A] := R[B][C]                                 */
     GETFIELD_ABC,/*  A B C   R[A] := R[B][K[C]:string]                       */
@@ -208,6 +231,52 @@ pub enum LuaOpcode {
     EXTRAARG_Ax/*   Ax      extra (larger) argument for previous opcode     */
 }
 
+impl LuaOpcode {
+    /**
+     * Instruction sets register A (the 'A' column of luaP_opmodes)
+     */
+    pub fn sets_a(&self) -> bool {
+        !matches!(self,
+            LuaOpcode::SETUPVAL_AB | LuaOpcode::SETTABUP_ABC | LuaOpcode::SETTABLE_ABC | LuaOpcode::SETI_ABC |
+            LuaOpcode::SETFIELD_ABC | LuaOpcode::MMBIN_ABC | LuaOpcode::MMBINI_AsBCk | LuaOpcode::MMBINK_ABCk |
+            LuaOpcode::CLOSE_A | LuaOpcode::TBC_A | LuaOpcode::JMP_sJ | LuaOpcode::EQ_ABk | LuaOpcode::LT_ABk |
+            LuaOpcode::LE_ABk | LuaOpcode::EQK_ABk | LuaOpcode::EQI_AsBk | LuaOpcode::LTI_AsBk | LuaOpcode::LEI_AsBk |
+            LuaOpcode::GTI_AsBk | LuaOpcode::GEI_AsBk | LuaOpcode::TEST_Ak | LuaOpcode::RETURN_ABCk |
+            LuaOpcode::RETURN0 | LuaOpcode::RETURN1_A | LuaOpcode::TFORPREP_ABx | LuaOpcode::TFORCALL_AC |
+            LuaOpcode::SETLIST_ABCk | LuaOpcode::EXTRAARG_Ax)
+    }
+
+    /**
+     * Instruction is a metamethod call following an arithmetic opcode (the 'MM' column of luaP_opmodes)
+     */
+    pub fn is_mm(&self) -> bool {
+        matches!(self, LuaOpcode::MMBIN_ABC | LuaOpcode::MMBINI_AsBCk | LuaOpcode::MMBINK_ABCk)
+    }
+
+    /**
+     * Instruction is a test, followed by the jump it may skip (the 'T' column of luaP_opmodes)
+     */
+    pub fn is_test(&self) -> bool {
+        matches!(self,
+            LuaOpcode::EQ_ABk | LuaOpcode::LT_ABk | LuaOpcode::LE_ABk | LuaOpcode::EQK_ABk | LuaOpcode::EQI_AsBk |
+            LuaOpcode::LTI_AsBk | LuaOpcode::LEI_AsBk | LuaOpcode::GTI_AsBk | LuaOpcode::GEI_AsBk |
+            LuaOpcode::TEST_Ak | LuaOpcode::TESTSET_ABk)
+    }
+}
+
+impl LuaOpcode {
+    /**
+     * The name without its format suffix
+     */
+    pub fn mnemonic(&self) -> &'static str {
+        OPCODE_NAMES[*self as usize]
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<LuaOpcode> {
+        (0..OPCODE_NAMES.len() as u8).map(LuaOpcode::from).find(|opcode| opcode.mnemonic() == mnemonic)
+    }
+}
+
 impl From<u8> for LuaOpcode {
     fn from(value: u8) -> Self {
         if value > 82 { panic!("Invalid opcode ix") };
@@ -233,23 +302,96 @@ pub struct LuaInstruction {
     pub args: LuaArgs,
 }
 
+impl LuaInstruction {
+    /**
+     * The encoded instruction
+     */
+    pub fn raw(&self) -> u32 {
+        self.args.raw
+    }
+}
+
 impl std::fmt::Display for LuaInstruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self.opcode)
     }
 }
 
+/**
+ * Operands out of the range of their field, as "operand B out of range (300 > 255)"
+ */
+pub type EncodeResult = Result<LuaInstruction, String>;
+
+fn check_arg(name: &str, value: u32, max: u32) -> Result<u32, String> {
+    if value > max {
+        return Err(format!("operand {} out of range ({} > {})", name, value, max));
+    }
+    Ok(value)
+}
+
+fn check_signed_arg(name: &str, value: i32, offset: i32, max: u32) -> Result<u32, String> {
+    if value < -offset || value > max as i32 - offset {
+        return Err(format!("operand {} out of range ({} not in [{}, {}])", name, value, -offset, max as i32 - offset));
+    }
+    Ok((value + offset) as u32)
+}
+
+/**
+ * The excess K form of a signed sB or sC operand, to pass as B or C
+ */
+pub fn signed_arg(value: i32) -> Result<u32, String> {
+    check_signed_arg("sC", value, OFFSET_SC, MAXARG_C)
+}
+
+/**
+ * CREATE_ABCk
+ */
+pub fn encode_abck(opcode: LuaOpcode, a: u32, b: u32, c: u32, k: bool) -> EncodeResult {
+    let raw = opcode as u32
+        | check_arg("A", a, MAXARG_A)? << A_ARG_POS
+        | (k as u32) << (B_ARG_POS - 1)
+        | check_arg("B", b, MAXARG_B)? << B_ARG_POS
+        | check_arg("C", c, MAXARG_C)? << C_ARG_POS;
+    Ok(decode(raw))
+}
+
+/**
+ * CREATE_ABx
+ */
+pub fn encode_abx(opcode: LuaOpcode, a: u32, bx: u32) -> EncodeResult {
+    let raw = opcode as u32 | check_arg("A", a, MAXARG_A)? << A_ARG_POS | check_arg("Bx", bx, MAXARG_BX)? << BX_ARG_POS;
+    Ok(decode(raw))
+}
+
+pub fn encode_asbx(opcode: LuaOpcode, a: u32, sbx: i32) -> EncodeResult {
+    encode_abx(opcode, a, check_signed_arg("sBx", sbx, OFFSET_SBX, MAXARG_BX)?)
+}
+
+/**
+ * CREATE_Ax
+ */
+pub fn encode_ax(opcode: LuaOpcode, ax: u32) -> EncodeResult {
+    Ok(decode(opcode as u32 | check_arg("Ax", ax, MAXARG_AX)? << AX_ARG_POS))
+}
+
+/**
+ * CREATE_sJ
+ */
+pub fn encode_sj(opcode: LuaOpcode, sj: i32) -> EncodeResult {
+    Ok(decode(opcode as u32 | check_signed_arg("sJ", sj, OFFSET_SJ, MAXARG_SJ)? << SJ_ARG_POS))
+}
+
 pub fn decode(raw: u32) -> LuaInstruction {
         let ix = (raw & bitmask!(OPCODE_BITSIZE)) as u8;
         LuaInstruction {
-            opcode: ix.try_into().expect("Failed to convert u8 to opcode"),
+            opcode: ix.into(),
             args: LuaArgs { raw },
         }
     }
 
 #[cfg(test)]
 mod test {
-    use crate::core::opcodes::{LuaOpcode, decode};
+    use crate::core::opcodes::{LuaOpcode, decode, encode_abck, encode_abx, encode_asbx, encode_ax, encode_sj, signed_arg};
 
     #[test]
     fn decode_move() {
@@ -258,4 +400,31 @@ mod test {
         assert_eq!(op.args.get_A(), 0);
         assert_eq!(op.args.get_B(), 3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn encode_inverts_decode() {
+        let op = encode_abck(LuaOpcode::MOVE_AB, 0, 3, 0, false).unwrap();
+        assert_eq!(op.raw(), 0x00030000);
+        let op = encode_abck(LuaOpcode::EQK_ABk, 1, 2, 0, true).unwrap();
+        assert_eq!((op.opcode, op.args.get_A(), op.args.get_B(), op.args.get_k()), (LuaOpcode::EQK_ABk, 1, 2, true));
+        let op = encode_abck(LuaOpcode::ADDI_ABsC, 0, 1, signed_arg(-5).unwrap(), false).unwrap();
+        assert_eq!(op.args.get_sC(), -5);
+        assert_eq!(encode_abx(LuaOpcode::LOADK_ABx, 2, 70000).unwrap().args.get_Bx(), 70000);
+        assert_eq!(encode_asbx(LuaOpcode::LOADI_AsBx, 0, -65535).unwrap().args.get_sBx(), -65535);
+        assert_eq!(encode_ax(LuaOpcode::EXTRAARG_Ax, (1 << 25) - 1).unwrap().args.get_Ax(), (1 << 25) - 1);
+        let op = encode_sj(LuaOpcode::JMP_sJ, -3).unwrap();
+        assert_eq!((op.opcode, op.args.get_sJ()), (LuaOpcode::JMP_sJ, -3));
+    }
+
+    #[test]
+    fn encode_checks_ranges() {
+        assert_eq!(encode_abck(LuaOpcode::ADD_ABC, 0, 256, 0, false).unwrap_err(), "operand B out of range (256 > 255)");
+        assert!(encode_abck(LuaOpcode::ADD_ABC, 256, 0, 0, false).is_err());
+        assert!(encode_abx(LuaOpcode::LOADK_ABx, 0, 1 << 17).is_err());
+        assert!(encode_asbx(LuaOpcode::LOADI_AsBx, 0, 65536).is_ok());
+        assert_eq!(encode_asbx(LuaOpcode::LOADI_AsBx, 0, 65537).unwrap_err(), "operand sBx out of range (65537 not in [-65535, 65536])");
+        assert!(encode_ax(LuaOpcode::EXTRAARG_Ax, 1 << 25).is_err());
+        assert!(encode_sj(LuaOpcode::JMP_sJ, (1 << 24) + 1).is_err());
+        assert!(signed_arg(128).is_ok() && signed_arg(129).is_err() && signed_arg(-127).is_ok() && signed_arg(-128).is_err());
+    }
+}
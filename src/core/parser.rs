@@ -1,130 +1,213 @@
 use std::{io::{Read, BufReader}, rc::Rc};
-use std::fmt::Write;
 
 use crate::core::types::TValue;
 
-use super::{opcodes::{LuaInstruction, LuaOpcode, self}, types::{UpvalueDescription, Proto}};
+use super::{instruction::Instruction, opcodes::{LuaInstruction, self}, types::{UpvalueDescription, Proto, AbsLineInfo, LocVar, string::LuaString}};
+
+/**
+ * Reasons for rejecting a binary chunk, reported as "bad binary format (why)"
+ */
+pub type LoadResult<T> = Result<T, String>;
+
+const TRUNCATED: &str = "truncated chunk";
+
+/* upvalues a function can have (MAXUPVAL), as instructions name them in a byte */
+const MAX_UPVALUES: usize = 255;
+
+/**
+ * Bounds on what a binary chunk can make the loader allocate, so chunks from untrusted
+ * sources fail to load instead of exhausting memory or the stack. Counts are per function
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadLimits {
+    /* bytes in the whole chunk */
+    pub max_size: usize,
+    pub max_string: usize,
+    pub max_code: usize,
+    pub max_constants: usize,
+    /* functions directly inside one, which CLOSURE can't index beyond MAXARG_Bx */
+    pub max_functions: usize,
+    pub max_locals: usize,
+    /* functions nested in the main one (LUAI_MAXCCALLS for the compiler) */
+    pub max_depth: usize,
+}
+
+impl Default for LoadLimits {
+    fn default() -> Self {
+        LoadLimits {
+            max_size: 1 << 28,
+            max_string: 1 << 26,
+            max_code: 1 << 25,
+            max_constants: 1 << 25,
+            max_functions: 1 << 17,
+            max_locals: 1 << 25,
+            max_depth: 200,
+        }
+    }
+}
+
+/**
+ * How a chunk stores integers, floats and instructions. Headers tell the layout of the build
+ * that wrote the chunk: 4 byte integers and floats for LUA_32BITS, big-endian for some targets
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub big_endian: bool,
+    pub int_size: u8,
+    pub number_size: u8,
+}
+
+impl Layout {
+    /* the one of the reference luac on 64-bit little-endian machines */
+    pub const LUAC: Layout = Layout { big_endian: false, int_size: 8, number_size: 8 };
+
+    /**
+     * Integers and floats are 4 or 8 bytes
+     */
+    pub fn check(&self) -> LoadResult<()> {
+        if !matches!(self.int_size, 4 | 8) {
+            return Err("lua_Integer size mismatch".to_string());
+        }
+        if !matches!(self.number_size, 4 | 8) {
+            return Err("lua_Number size mismatch".to_string());
+        }
+        Ok(())
+    }
+}
 
 struct LuaReader<R: Read> {
     reader: R,
-    int_size: u8,
-    number_size: u8,
+    layout: Layout,
+    limits: LoadLimits,
+    /* bytes left in the chunk, which every count must leave room for */
+    remaining: usize,
+    depth: usize,
 }
 
 impl<R: Read> LuaReader<R> {
-    fn new(reader: R, int_size: u8, number_size: u8 ) -> Self {
-        LuaReader { reader, int_size, number_size }
+    fn new(reader: R, layout: Layout, limits: LoadLimits, size: usize) -> Self {
+        LuaReader { reader, layout, limits, remaining: size, depth: 0 }
+    }
+
+    fn read_bytes(&mut self, buffer: &mut [u8]) -> LoadResult<()> {
+        self.reader.read_exact(buffer).map_err(|_| TRUNCATED.to_string())?;
+        self.remaining = self.remaining.saturating_sub(buffer.len());
+        Ok(())
+    }
+
+    /* a count of items taking at least 'item_size' bytes each */
+    fn read_count(&mut self, item_size: usize, limit: usize, what: &str) -> LoadResult<usize> {
+        let count = self.read_int()? as usize;
+        if count > limit {
+            return Err(format!("too many {} ({} > {})", what, count, limit));
+        }
+        if count.saturating_mul(item_size) > self.remaining {
+            return Err(TRUNCATED.to_string());
+        }
+        Ok(count)
     }
 
-    pub fn load_byte(&mut self) -> u8 {
+    pub fn load_byte(&mut self) -> LoadResult<u8> {
         let mut result = [0u8];
-        self.reader.read(&mut result).expect("Failed to read");
-        result[0]
+        self.read_bytes(&mut result)?;
+        Ok(result[0])
     }
 
-    pub fn read_unsigned(&mut self, limit: u64) -> u64 {
+    pub fn read_unsigned(&mut self, limit: u64) -> LoadResult<u64> {
         let mut x = 0_u64;
-        let mut b = 0_u8;
+        let limit = limit >> 7;
         loop {
-            b = self.load_byte();
-            if x >= limit { panic!("integer overflow") };
+            let b = self.load_byte()?;
+            if x >= limit { return Err("integer overflow".to_string()) };
             x = (x << 7) | (b & 0x7f) as u64;
             if (b & 0x80) != 0 { break; }
         }
-        x
+        Ok(x)
     }
 
-    pub fn read_size(&mut self) -> u64 {
-        self.read_unsigned(u64::MAX - 1)
+    pub fn read_size(&mut self) -> LoadResult<u64> {
+        self.read_unsigned(u64::MAX)
     }
 
-    pub fn read_instruction(&mut self) -> u32 {
-        let mut bytes: [u8; 4] = [0, 0, 0, 0];
-        self.reader.read(&mut bytes);
-        u32::from_le_bytes(bytes)
+    /* 'size' bytes in the byte order of the chunk */
+    fn read_word(&mut self, size: usize) -> LoadResult<u64> {
+        let mut bytes = [0u8; 8];
+        self.read_bytes(&mut bytes[..size])?;
+        if self.layout.big_endian {
+            bytes[..size].reverse();
+        }
+        Ok(u64::from_le_bytes(bytes))
     }
 
-    pub fn read_number(&mut self) -> f64 {
-        match self.number_size {
-            4 => {
-                let mut bytes: [u8; 4] = [0, 0, 0, 0];
-                self.reader.read(&mut bytes);
-                f32::from_le_bytes(bytes) as f64
-            }
-            8 => {
-                let mut bytes: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
-                self.reader.read(&mut bytes);
-                f64::from_le_bytes(bytes)
-            }
-            _ => { panic!("Bad num size") },
+    pub fn read_instruction(&mut self) -> LoadResult<u32> {
+        Ok(self.read_word(4)? as u32)
+    }
+
+    pub fn read_number(&mut self) -> LoadResult<f64> {
+        match self.layout.number_size {
+            4 => Ok(f32::from_bits(self.read_word(4)? as u32) as f64),
+            _ => Ok(f64::from_bits(self.read_word(8)?)),
         }
     }
 
-    pub fn read_integer(&mut self) -> i64 {
-        match self.int_size {
-            4 => {
-                let mut bytes: [u8; 4] = [0, 0, 0, 0];
-                self.reader.read(&mut bytes);
-                i32::from_le_bytes(bytes) as i64
-            }
-            8 => {
-                let mut bytes: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
-                self.reader.read(&mut bytes);
-                i64::from_le_bytes(bytes)
-            }
-            _ => { panic!("Bad int size") },
+    pub fn read_integer(&mut self) -> LoadResult<i64> {
+        match self.layout.int_size {
+            4 => Ok(self.read_word(4)? as u32 as i32 as i64),
+            _ => Ok(self.read_word(8)? as i64),
         }
     }
 
-    pub fn read_int(&mut self) -> i64 {
-        self.read_unsigned(u64::MAX) as i64
+    pub fn read_int(&mut self) -> LoadResult<i64> {
+        Ok(self.read_unsigned(i32::MAX as u64)? as i64)
     }
 
-    pub fn read_string(&mut self) -> Option<Rc<String>> {
-        const MAXSHORTLEN: usize = 40;
-        let str_size = self.read_size() as usize;
+    pub fn read_string(&mut self) -> LoadResult<Option<Rc<LuaString>>> {
+        let str_size = self.read_size()? as usize;
 
         if str_size == 0 {
-            None
+            Ok(None)
         } else { 
-            //let mut s = String::with_capacity(str_size - 1);
-            //let raw_s_ptr = unsafe { s.as_bytes_mut() };
-            let mut empty_buf = vec![0u8; str_size - 1];
-            let got_bytes = self.reader.read(&mut empty_buf).expect("Failed to read exact");
-            assert_eq!(got_bytes, { str_size-1 });
-            Some(Rc::new(String::from_utf8(empty_buf).expect("Failed to convert bytes to str")))
+            if str_size - 1 > self.limits.max_string {
+                return Err(format!("string too long ({} > {} bytes)", str_size - 1, self.limits.max_string));
+            }
+            if str_size - 1 > self.remaining {
+                return Err(TRUNCATED.to_string());
+            }
+            let mut buffer = vec![0; str_size - 1];
+            self.read_bytes(&mut buffer)?;
+            Ok(Some(Rc::new(LuaString::new(buffer))))
         }
     }
 
-    pub fn read_code(&mut self) -> Vec<LuaInstruction> {
-        let opcodes_count = self.read_int();
+    pub fn read_code(&mut self) -> LoadResult<Vec<LuaInstruction>> {
+        let opcodes_count = self.read_count(4, self.limits.max_code, "instructions")?;
         
         let mut result: Vec<LuaInstruction> = Vec::new();
         for _ in 0..opcodes_count {
-            let opcode = opcodes::decode(self.read_instruction());
+            let opcode = opcodes::decode(self.read_instruction()?);
             result.push(opcode);
         }
 
-        result
+        Ok(result)
     }
 
-    pub fn read_protos(&mut self) -> Vec<Proto> {
-        let proto_count = self.read_int();
-        let mut result: Vec<Proto> = Vec::new();
+    pub fn read_protos(&mut self, source: &Option<Rc<LuaString>>) -> LoadResult<Vec<Rc<Proto>>> {
+        let proto_count = self.read_count(1, self.limits.max_functions, "functions")?;
+        let mut result: Vec<Rc<Proto>> = Vec::new();
         for _ in 0..proto_count {
-            result.push(self.read_function());
+            result.push(Rc::new(self.read_function(source)?));
         }
 
-        result
+        Ok(result)
     }
 
-    pub fn read_upvalues(&mut self) -> Vec<UpvalueDescription> {
-        let upval_count = self.read_int();
+    pub fn read_upvalues(&mut self) -> LoadResult<Vec<UpvalueDescription>> {
+        let upval_count = self.read_count(3, MAX_UPVALUES, "upvalues")?;
         let mut upvals: Vec<UpvalueDescription> = Vec::new();
         for _ in 0..upval_count {
-            let instack = self.load_byte() != 0;
-            let idx = self.load_byte();
-            let kind = self.load_byte();
+            let instack = self.load_byte()? != 0;
+            let idx = self.load_byte()?;
+            let kind = self.load_byte()?;
             upvals.push( UpvalueDescription {
                 name: None,
                 instack,
@@ -133,11 +216,11 @@ impl<R: Read> LuaReader<R> {
             } )
         }
 
-        upvals
+        Ok(upvals)
     }
 
-    pub fn read_constants(&mut self) -> Vec<TValue> {
-        let const_count = self.read_int();
+    pub fn read_constants(&mut self) -> LoadResult<Vec<TValue>> {
+        let const_count = self.read_count(1, self.limits.max_constants, "constants")?;
 
         /*
         * #define LUA_TNIL                0
@@ -155,40 +238,85 @@ impl<R: Read> LuaReader<R> {
         */
         let mut result: Vec<TValue> = Vec::new();
         for _ in 0..const_count {
-            let const_type = self.load_byte();
+            let const_type = self.load_byte()?;
             let const_val = match const_type {
                 0 => { TValue::NIL },
-                1 => TValue::TBOOLEAN(true),
-                17 => TValue::TBOOLEAN(false),
-                19 => TValue::NUMFLT(self.read_number()),
-                3 => TValue::NUMINT(self.read_integer()),
-                4 | 20 => TValue::STR(self.read_string().unwrap()),
-                _ => panic!("Unknown type"),
+                1 => TValue::TBOOLEAN(false),
+                17 => TValue::TBOOLEAN(true),
+                19 => TValue::NUMFLT(self.read_number()?),
+                3 => TValue::NUMINT(self.read_integer()?),
+                4 | 20 => TValue::STR(self.read_string()?.ok_or_else(|| "bad format for constant string".to_string())?),
+                _ => return Err(format!("unknown constant type {}", const_type)),
             };
             result.push(const_val);
         }
 
-        result
+        Ok(result)
     }
 
-    fn read_function(&mut self) -> Proto {
-        let fn_name = self.read_string();
-        let line_defined = self.read_int() as usize;
-        let last_line_defined = self.read_int() as usize;
-        let num_params = self.load_byte();
-        let is_vararg = self.load_byte() == 1;
-        let max_stack_size = self.load_byte();
-    
-        let opcodes = self.read_code();
-        let constants = self.read_constants();
-        let upvals = self.read_upvalues();
-        let protos = self.read_protos();
-    
+    pub fn read_line_info(&mut self) -> LoadResult<Vec<i8>> {
+        let count = self.read_count(1, self.limits.max_code, "lines")?;
+        (0..count).map(|_| Ok(self.load_byte()? as i8)).collect()
+    }
+
+    pub fn read_abs_line_info(&mut self) -> LoadResult<Vec<AbsLineInfo>> {
+        let count = self.read_count(2, self.limits.max_code, "lines")?;
+        (0..count).map(|_| {
+            let pc = self.read_int()? as usize;
+            let line = self.read_int()?;
+            Ok(AbsLineInfo { pc, line })
+        }).collect()
+    }
+
+    pub fn read_loc_vars(&mut self) -> LoadResult<Vec<LocVar>> {
+        let count = self.read_count(3, self.limits.max_locals, "local variables")?;
+        (0..count).map(|_| {
+            let name = self.read_string()?;
+            let start_pc = self.read_int()? as usize;
+            let end_pc = self.read_int()? as usize;
+            Ok(LocVar { name, start_pc, end_pc })
+        }).collect()
+    }
+
+    pub fn read_upvalue_names(&mut self, upvals: &mut [UpvalueDescription]) -> LoadResult<()> {
+        let count = self.read_count(1, MAX_UPVALUES, "upvalue names")?;
+        for i in 0..count {
+            let name = self.read_string()?;
+            if let Some(upval) = upvals.get_mut(i) {
+                upval.name = name;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_function(&mut self, parent_source: &Option<Rc<LuaString>>) -> LoadResult<Proto> {
+        if self.depth > self.limits.max_depth {
+            return Err(format!("function nesting limit ({}) exceeded", self.limits.max_depth));
+        }
+        self.depth += 1;
+        // stripped or nested functions share the source of the parent
+        let fn_name = self.read_string()?.or_else(|| parent_source.clone());
+        let line_defined = self.read_int()? as usize;
+        let last_line_defined = self.read_int()? as usize;
+        let num_params = self.load_byte()?;
+        let is_vararg = self.load_byte()? != 0;
+        let max_stack_size = self.load_byte()?;
     
-        
+        let opcodes = self.read_code()?;
+        let constants = self.read_constants()?;
+        let mut upvals = self.read_upvalues()?;
+        let protos = self.read_protos(&fn_name)?;
+
+        let line_info = self.read_line_info()?;
+        let abs_line_info = self.read_abs_line_info()?;
+        let loc_vars = self.read_loc_vars()?;
+        self.read_upvalue_names(&mut upvals)?;
+        self.depth -= 1;
     
-        Proto {
+        Ok(Proto {
             fn_name,
+            line_defined,
+            last_line_defined,
             is_vararg,
             max_stack_size,
             num_params,
@@ -196,78 +324,124 @@ impl<R: Read> LuaReader<R> {
             code: opcodes,
             constants,
             fns: protos,
-        }
+            line_info,
+            abs_line_info,
+            loc_vars,
+        })
     }
     
 }
 
+pub const LUA_SIGNATURE: &[u8; 4] = b"\x1bLua";
+pub const LUAC_VERSION: u8 = 0x54;
+pub const LUAC_FORMAT: u8 = 0;
+pub const LUAC_DATA: &[u8; 6] = b"\x19\x93\r\n\x1a\n";
+pub const LUAC_INT: i64 = 0x5678;
+pub const LUAC_NUM: f64 = 370.5;
+
 #[derive(Debug)]
-#[repr(C, packed)]
 pub struct Header {
     signature: [u8; 4],
     version: u8,
     format: u8,
     data_chunk: [u8; 6],
     instruction_size: u8,
-    int_size: u8,
-    number_size: u8,
+    layout: Layout,
 }
 
-pub fn parse_header(data: &[u8]) -> Result<(usize, Header), std::io::Error> {    
-    let mut offset = 0;
-    const HEADER_SIZE: usize = std::mem::size_of::<Header>();
-    let p: *const [u8; HEADER_SIZE] = data.as_ptr() as *const [u8; HEADER_SIZE];                                                                          
-    let header: Header = unsafe { std::mem::transmute(*p)};
-    
-    //reader.consume(HEADER_SIZE);
-    //reader.fill_buf().unwrap();
-
-    offset += HEADER_SIZE;
-    let mut l_reader = LuaReader {
-        int_size: header.int_size,
-        number_size: header.number_size,
-        reader: BufReader::new(&data[offset..])
-    };
-
-    assert_eq!(header.signature, *b"\x1bLua");
-    assert_eq!(header.data_chunk, *b"\x19\x93\r\n\x1a\n");
-    
-    let test_int_value = l_reader.read_integer();
-    assert_eq!(test_int_value, 0x5678);
+/**
+ * checkHeader from lundump.c. The byte order is the one in which the test integer reads right
+ */
+fn parse_header<R: Read>(reader: &mut LuaReader<R>) -> LoadResult<Header> {
+    let mut signature = [0u8; 4];
+    reader.read_bytes(&mut signature).map_err(|_| "not a binary chunk".to_string())?;
+    if signature != *LUA_SIGNATURE {
+        return Err("not a binary chunk".to_string());
+    }
+    let version = reader.load_byte()?;
+    if version != LUAC_VERSION {
+        return Err("version mismatch".to_string());
+    }
+    let format = reader.load_byte()?;
+    if format != LUAC_FORMAT {
+        return Err("format mismatch".to_string());
+    }
+    let mut data_chunk = [0u8; 6];
+    reader.read_bytes(&mut data_chunk)?;
+    if data_chunk != *LUAC_DATA {
+        return Err("corrupted chunk".to_string());
+    }
+    let instruction_size = reader.load_byte()?;
+    if instruction_size != 4 {
+        return Err("Instruction size mismatch".to_string());
+    }
+    let int_size = reader.load_byte()?;
+    let number_size = reader.load_byte()?;
+    reader.layout = Layout { big_endian: false, int_size, number_size };
+    reader.layout.check()?;
+
+    let test = reader.read_integer()?;
+    let swapped = if int_size == 4 { (test as u32).swap_bytes() as i32 as i64 } else { test.swap_bytes() };
+    if test != LUAC_INT {
+        if swapped != LUAC_INT {
+            return Err("integer format mismatch".to_string());
+        }
+        reader.layout.big_endian = true;
+    }
+    if reader.read_number()? != LUAC_NUM {
+        return Err("float format mismatch".to_string());
+    }
 
-    let test_f_value = l_reader.read_number();
-    assert_eq!(test_f_value, 370.5f64);
+    Ok(Header { signature, version, format, data_chunk, instruction_size, layout: reader.layout })
+}
 
-    offset += header.number_size as usize + header.int_size as usize;
+/**
+ * The layout a chunk was written with, from its header
+ */
+pub fn chunk_layout(data: &[u8]) -> LoadResult<Layout> {
+    let mut lua_reader = LuaReader::new(BufReader::new(data), Layout::LUAC, LoadLimits::default(), data.len());
+    Ok(parse_header(&mut lua_reader)?.layout)
+}
 
-    Result::Ok((offset, header))
+pub fn parse_all(data: &[u8]) -> LoadResult<Proto> {
+    parse_with_limits(data, LoadLimits::default())
+}
 
+/**
+ * luaU_undump for chunks held to 'limits'
+ */
+pub fn parse_with_limits(data: &[u8], limits: LoadLimits) -> LoadResult<Proto> {
+    if data.len() > limits.max_size {
+        return Err(format!("chunk too large ({} > {} bytes)", data.len(), limits.max_size));
     }
-
-pub fn parse_all(data: &[u8]) -> Proto {
-    let (offset, header) = parse_header(data).unwrap();
-    let mut lua_reader = LuaReader::new(BufReader::new(&data[offset..]), header.int_size, header.number_size);
-    let _upvalues = lua_reader.load_byte();
-    let func = lua_reader.read_function();
-    println!("{:?}", func);
-    func
+    let mut lua_reader = LuaReader::new(BufReader::new(data), Layout::LUAC, limits, data.len());
+    parse_header(&mut lua_reader)?;
+    let _upvalues = lua_reader.load_byte()?;
+    lua_reader.read_function(&None)
 }
 
 
 impl Proto {
-    pub fn display_opcode(&self, opcode: &LuaInstruction) -> String {
-        let mut result = String::new();
-        write!(&mut result, "{:?}; ", opcode.opcode).expect("Failed to write");
-        let err = match opcode.opcode {
-            LuaOpcode::LOADI_AsBx => write!(&mut result, "R[{}] = {}", opcode.args.get_A(), opcode.args.get_sBx()),
-            LuaOpcode::LOADK_ABx => write!(&mut result, "R[{}] = Const[{}] ({})", opcode.args.get_A(), opcode.args.get_Bx(), self.constants.get(opcode.args.get_sBx() as usize).unwrap()),
-        //Opcode::OP_ADD(abc) => write!(&mut result, "R[{}] = R[{}] + R[{}]", abc.0, abc.2, abc.3),
-        //Opcode::OP_LOADK(abx) => write!(&mut result, "R[{}] = Const[{}] ({})", abx.0, abx.1, constants.get(abx.1 as usize).unwrap()),
-        //Opcode::OP_SETTABUP(abc) => write!(&mut result, "UpValue({})[{}] = {}", upvalues.get(abc.0 as usize).unwrap(), constants.get(abc.2 as usize).unwrap(), constants.get(abc.3 as usize).unwrap()),
-           _ => write!(&mut result, "TODO"),
+    /**
+     * The instruction as luac lists it, followed by the constant it uses if any
+     */
+    pub fn display_opcode(&self, instruction: &LuaInstruction) -> String {
+        let decoded = instruction.decoded();
+        let k = match decoded {
+            Instruction::LoadK { bx, .. } => Some(bx as usize),
+            Instruction::GetTabUp { key_k, .. } | Instruction::GetField { key_k, .. } |
+            Instruction::SetTabUp { key_k, .. } | Instruction::SetField { key_k, .. } => Some(key_k as usize),
+            Instruction::EqK { b_k, .. } | Instruction::MmBinK { b_k, .. } => Some(b_k as usize),
+            Instruction::AddK { c, .. } | Instruction::SubK { c, .. } | Instruction::MulK { c, .. } |
+            Instruction::ModK { c, .. } | Instruction::PowK { c, .. } | Instruction::DivK { c, .. } |
+            Instruction::IDivK { c, .. } | Instruction::BAndK { c, .. } | Instruction::BOrK { c, .. } |
+            Instruction::BXorK { c, .. } => Some(c as usize),
+            _ => None,
         };
-        err.expect("Failed to write");
-        result
+        match k.and_then(|k| self.constants.get(k)) {
+            Some(constant) => format!("{}\t; {}", decoded, constant),
+            None => decoded.to_string(),
+        }
     }
 }
 
@@ -286,4 +460,42 @@ impl std::fmt::Display for Proto {
             self.code.iter().map( |x| { self.display_opcode(x) } ).collect::<Vec<String>>().join("\n")
         )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LoadLimits, parse_all, parse_with_limits};
+    use crate::{compiler::codegen::compile, core::dump::dump};
+
+    fn chunk(source: &str) -> Vec<u8> {
+        dump(&compile(source.as_bytes(), "=test").unwrap(), false)
+    }
+
+    #[test]
+    fn counts_beyond_the_chunk() {
+        let valid = chunk("return");
+        /* the header, the upvalue count, then a main function claiming millions of instructions */
+        let mut forged = valid[..31].to_vec();
+        forged.extend([1, 0x80, 0x80, 0x80, 0, 1, 2, 0x07, 0x7f, 0x7f, 0xff]);
+        assert_eq!(parse_all(&forged).unwrap_err(), "truncated chunk");
+        let mut long_string = valid[..32].to_vec();
+        long_string.extend([0x7f, 0x7f, 0xff]);
+        assert_eq!(parse_all(&long_string).unwrap_err(), "truncated chunk");
+    }
+
+    #[test]
+    fn configured_limits() {
+        let source = chunk("local s = 'a long enough string'\nreturn function() return function() return s, 1, 2, 3 end end\n");
+        let limits = LoadLimits::default();
+        assert!(parse_with_limits(&source, limits).is_ok());
+        let rejected = |limits: LoadLimits| parse_with_limits(&source, limits).unwrap_err();
+        assert_eq!(rejected(LoadLimits { max_size: 100, ..limits }), format!("chunk too large ({} > 100 bytes)", source.len()));
+        assert_eq!(rejected(LoadLimits { max_string: 10, ..limits }), "string too long (20 > 10 bytes)");
+        assert!(rejected(LoadLimits { max_code: 3, ..limits }).starts_with("too many instructions"));
+        assert_eq!(rejected(LoadLimits { max_constants: 0, ..limits }), "too many constants (1 > 0)");
+        assert_eq!(rejected(LoadLimits { max_functions: 0, ..limits }), "too many functions (1 > 0)");
+        assert_eq!(rejected(LoadLimits { max_locals: 0, ..limits }), "too many local variables (1 > 0)");
+        assert_eq!(rejected(LoadLimits { max_depth: 1, ..limits }), "function nesting limit (1) exceeded");
+        assert!(parse_with_limits(&source, LoadLimits { max_depth: 2, ..limits }).is_ok());
+    }
+}
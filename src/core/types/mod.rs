@@ -1,23 +1,174 @@
 pub mod stack;
-use std::{rc::Rc, collections::LinkedList};
+pub mod number;
+pub mod table;
+pub mod string;
+pub mod userdata;
+use std::{rc::Rc, cell::RefCell, collections::{LinkedList, BTreeMap}};
 
-use self::stack::LuaStack;
+use self::{stack::{LuaStack, LuaStackView}, table::TableRef, string::LuaString, userdata::Userdata};
 
 use super::opcodes::LuaInstruction;
+use crate::vm::debug::Hook;
 
 pub type StackIndex = usize;
 
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum TValue {
     NIL,
     TBOOLEAN(bool),
     NUMFLT(f64),
     NUMINT(i64),
-    STR(Rc<String>),
+    STR(Rc<LuaString>),
     CLOSURE(Rc<Closure>),
+    TABLE(TableRef),
+    USERDATA(Rc<Userdata>),
+    LIGHTUSERDATA(*const u8),
     EMPTY,
 }
 
+/**
+ * The basic Lua types (LUA_TNIL...LUA_TUSERDATA), in the order of lua.h
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LuaType {
+    Nil,
+    Boolean,
+    LightUserdata,
+    Number,
+    String,
+    Table,
+    Function,
+    Userdata,
+}
+
+impl LuaType {
+    pub const COUNT: usize = 8;
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LuaType::Nil => "nil",
+            LuaType::Boolean => "boolean",
+            LuaType::LightUserdata | LuaType::Userdata => "userdata",
+            LuaType::Number => "number",
+            LuaType::String => "string",
+            LuaType::Table => "table",
+            LuaType::Function => "function",
+        }
+    }
+}
+
+impl TValue {
+    pub fn lua_type(&self) -> LuaType {
+        match self {
+            TValue::NIL | TValue::EMPTY => LuaType::Nil,
+            TValue::TBOOLEAN(_) => LuaType::Boolean,
+            TValue::NUMFLT(_) | TValue::NUMINT(_) => LuaType::Number,
+            TValue::STR(_) => LuaType::String,
+            TValue::CLOSURE(_) => LuaType::Function,
+            TValue::TABLE(_) => LuaType::Table,
+            TValue::USERDATA(_) => LuaType::Userdata,
+            TValue::LIGHTUSERDATA(_) => LuaType::LightUserdata,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            TValue::NIL | TValue::EMPTY => "nil",
+            TValue::TBOOLEAN(_) => "boolean",
+            TValue::NUMFLT(_) | TValue::NUMINT(_) => "number",
+            TValue::STR(_) => "string",
+            TValue::CLOSURE(_) => "function",
+            TValue::TABLE(_) => "table",
+            TValue::USERDATA(_) | TValue::LIGHTUSERDATA(_) => "userdata",
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, TValue::NIL | TValue::EMPTY)
+    }
+
+    /* nil and false are the only false values */
+    pub fn is_falsy(&self) -> bool {
+        matches!(self, TValue::NIL | TValue::EMPTY | TValue::TBOOLEAN(false))
+    }
+
+    pub fn from_str(s: &str) -> Self {
+        TValue::STR(Rc::new(LuaString::from(s)))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        TValue::STR(Rc::new(LuaString::from(bytes)))
+    }
+
+    /**
+     * luaV_tonumber_: numbers and numeric strings
+     */
+    pub fn to_number(&self) -> Option<f64> {
+        match self {
+            TValue::NUMFLT(f) => Some(*f),
+            TValue::NUMINT(i) => Some(*i as f64),
+            TValue::STR(s) => match number::str_to_number(s.as_bytes())? {
+                TValue::NUMINT(i) => Some(i as f64),
+                TValue::NUMFLT(f) => Some(f),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /**
+     * luaV_tointeger: integers, floats with an integral value and numeric strings
+     */
+    pub fn to_integer(&self, mode: number::F2IMode) -> Option<i64> {
+        match self {
+            TValue::NUMINT(i) => Some(*i),
+            TValue::NUMFLT(f) => number::float_to_integer(*f, mode),
+            TValue::STR(s) => match number::str_to_number(s.as_bytes())? {
+                TValue::NUMINT(i) => Some(i),
+                TValue::NUMFLT(f) => number::float_to_integer(f, mode),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /**
+     * Address of collectable objects, used to print and hash them
+     */
+    pub fn to_pointer(&self) -> Option<*const u8> {
+        match self {
+            TValue::STR(s) => Some(Rc::as_ptr(s) as *const u8),
+            TValue::CLOSURE(c) => Some(Rc::as_ptr(c) as *const u8),
+            TValue::TABLE(t) => Some(Rc::as_ptr(t) as *const u8),
+            TValue::USERDATA(u) => Some(Rc::as_ptr(u) as *const u8),
+            TValue::LIGHTUSERDATA(p) => Some(*p),
+            _ => None,
+        }
+    }
+
+    /**
+     * Primitive equality: no metamethods involved
+     */
+    pub fn raw_equals(&self, other: &TValue) -> bool {
+        match (self, other) {
+            (TValue::NIL | TValue::EMPTY, TValue::NIL | TValue::EMPTY) => true,
+            (TValue::TBOOLEAN(a), TValue::TBOOLEAN(b)) => a == b,
+            (TValue::NUMINT(a), TValue::NUMINT(b)) => a == b,
+            (TValue::NUMFLT(a), TValue::NUMFLT(b)) => a == b,
+            (TValue::NUMINT(i), TValue::NUMFLT(f)) | (TValue::NUMFLT(f), TValue::NUMINT(i)) => {
+                number::float_to_integer(*f, number::F2IMode::Exact) == Some(*i)
+            },
+            (TValue::STR(a), TValue::STR(b)) => a == b,
+            (TValue::CLOSURE(a), TValue::CLOSURE(b)) => Rc::ptr_eq(a, b),
+            (TValue::TABLE(a), TValue::TABLE(b)) => Rc::ptr_eq(a, b),
+            (TValue::USERDATA(a), TValue::USERDATA(b)) => Rc::ptr_eq(a, b),
+            (TValue::LIGHTUSERDATA(a), TValue::LIGHTUSERDATA(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
 impl std::fmt::Display for TValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -27,17 +178,49 @@ impl std::fmt::Display for TValue {
             TValue::NUMINT(i) => write!(f, "Int({})", i),
             TValue::STR(s) => write!(f, "Str({})", s),
             TValue::CLOSURE(c) => write!(f, "Closure:\n{:?}", c),
+            TValue::TABLE(t) => write!(f, "Table({:p})", Rc::as_ptr(t)),
+            TValue::USERDATA(u) => write!(f, "Userdata({:p})", Rc::as_ptr(u)),
+            TValue::LIGHTUSERDATA(p) => write!(f, "LightUserdata({:p})", p),
             TValue::EMPTY => write!(f, "Empty _system_ value"),
         }
     }
 }
 
+/**
+ * Error raised by the VM or by native functions. In Lua any value could be an error object
+ */
+#[derive(Debug, Clone)]
+pub struct LuaError {
+    pub value: TValue,
+}
+
+impl LuaError {
+    pub fn new(message: &str) -> Self {
+        Self { value: TValue::from_str(message) }
+    }
+
+    pub fn from_value(value: TValue) -> Self {
+        Self { value }
+    }
+}
+
+impl std::fmt::Display for LuaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            TValue::STR(s) => write!(f, "{}", s),
+            TValue::NUMINT(i) => write!(f, "{}", i),
+            TValue::NUMFLT(n) => write!(f, "{}", number::float_to_string(*n)),
+            other => write!(f, "(error object is a {} value)", other.type_name()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UpvalueDescription {
     pub instack: bool,
     pub idx: u8,
     pub kind: u8,
-    pub name: Option<Box<String>>
+    pub name: Option<Rc<LuaString>>
 }
 
 impl std::fmt::Display for UpvalueDescription {
@@ -46,22 +229,100 @@ impl std::fmt::Display for UpvalueDescription {
     }
 }
 
+#[derive(Debug)]
+pub struct AbsLineInfo {
+    pub pc: usize,
+    pub line: i64,
+}
+
+/* limit for the difference between lines in relative line info */
+pub const LIMLINEDIFF: i64 = 0x80;
+/* maximum number of successive instructions without absolute line information */
+pub const MAXIWTHABS: u8 = 128;
+/* marks an instruction whose line is in 'abs_line_info' */
+pub const ABSLINEINFO: i8 = -0x80;
+
+/**
+ * Line information of a function as luac saves it: differences from the previous instruction,
+ * with an absolute line when the difference doesn't fit or every MAXIWTHABS instructions
+ */
+#[derive(Debug)]
+pub struct LineInfoWriter {
+    pub line_info: Vec<i8>,
+    pub abs_line_info: Vec<AbsLineInfo>,
+    /* line of the last instruction saved */
+    previous_line: i64,
+    /* instructions since the last absolute line info */
+    iwthabs: u8,
+}
+
+impl LineInfoWriter {
+    pub fn new(line_defined: i64) -> Self {
+        Self { line_info: Vec::new(), abs_line_info: Vec::new(), previous_line: line_defined, iwthabs: 0 }
+    }
+
+    /**
+     * savelineinfo: the line of the next instruction
+     */
+    pub fn save(&mut self, line: i64) {
+        let mut line_dif = line - self.previous_line;
+        let pc = self.line_info.len();
+        if line_dif.abs() >= LIMLINEDIFF || {
+            let count = self.iwthabs;
+            self.iwthabs += 1;
+            count >= MAXIWTHABS
+        } {
+            self.abs_line_info.push(AbsLineInfo { pc, line });
+            line_dif = ABSLINEINFO as i64;
+            self.iwthabs = 1;
+        }
+        self.line_info.push(line_dif as i8);
+        self.previous_line = line;
+    }
+
+    /**
+     * removelastlineinfo: forces the next instruction to an absolute line if this one had it
+     */
+    pub fn remove_last(&mut self) {
+        let info = self.line_info.pop().expect("no line info to remove");
+        if info != ABSLINEINFO {
+            self.previous_line -= info as i64;
+            self.iwthabs -= 1;
+        } else {
+            self.abs_line_info.pop();
+            self.iwthabs = MAXIWTHABS + 1;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LocVar {
+    pub name: Option<Rc<LuaString>>,
+    pub start_pc: usize, /* first point where variable is active */
+    pub end_pc: usize, /* first point where variable is dead */
+}
+
 #[derive(Debug)]
 pub struct Proto {
-    pub fn_name: Option<Rc<String>>,
+    pub fn_name: Option<Rc<LuaString>>, /* chunk source, inherited from the parent when not dumped */
+    pub line_defined: usize,
+    pub last_line_defined: usize,
     pub num_params: u8,  /* number of fixed (named) parameters */
     pub is_vararg: bool,
     pub max_stack_size: u8, /* number of registers needed by this function */
     pub constants: Vec<TValue>,  /* constants used by the function */
     pub code: Vec<LuaInstruction>,  /* opcodes */
-    pub fns: Vec<Proto>,  /* functions defined inside the function */
+    pub fns: Vec<Rc<Proto>>,  /* functions defined inside the function */
     pub upvalues: Vec<UpvalueDescription>,  /* upvalue information */
+    pub line_info: Vec<i8>, /* line deltas per instruction (debug information) */
+    pub abs_line_info: Vec<AbsLineInfo>,
+    pub loc_vars: Vec<LocVar>,
 }
 
 #[derive(Debug)]
 pub enum  UpVal {
     Open(StackIndex),
-    Closed(&'static mut TValue),
+    Closed(TValue),
 }
 
 impl UpVal {
@@ -69,16 +330,16 @@ impl UpVal {
         Self::Open(stack_index)
     }
 
-    pub fn get_value(&self, stack: &stack::LuaStack) -> &TValue {
+    pub fn get_value<'a>(&'a self, stack: &'a stack::LuaStack) -> &'a TValue {
         match self {
             Self::Open(offset) => stack.get_at_offset(*offset),
             Self::Closed(val) => val,
         }
-    } 
+    }
 
-    pub fn set_value(&mut self, stack: &mut stack::LuaStack, value: &mut TValue) -> () {
+    pub fn set_value(&mut self, stack: &mut stack::LuaStack, value: TValue) {
         match self {
-            Self::Open(offset) => stack.set_at_offset(value.clone(), *offset),
+            Self::Open(offset) => stack.set_at_offset(value, *offset),
             Self::Closed(old) => {
                 *old = value;
             }
@@ -86,16 +347,35 @@ impl UpVal {
     }
 }
 
+pub type UpValRef = Rc<RefCell<UpVal>>;
+
 #[derive(Debug)]
 pub struct LuaClosure {
-    pub proto: &'static Proto,
-    pub upvalues: Vec<UpVal>,
+    pub proto: Rc<Proto>,
+    pub upvalues: RefCell<Vec<UpValRef>>, /* mutable so debug.upvaluejoin can share them */
 }
 
-#[derive(Debug)]
+impl LuaClosure {
+    pub fn upvalue(&self, index: usize) -> UpValRef {
+        self.upvalues.borrow()[index].clone()
+    }
+}
+
+/**
+ * Native function ABI: arguments are read through the stack view, results are pushed to it
+ * and the number of pushed results is returned
+ */
+pub type NativeFunction = Rc<dyn Fn(&mut LuaStackView) -> Result<usize, LuaError>>;
+
 pub struct CClosure {
-    pub fn_ptr: fn() -> (),
-    pub upvalues: Vec<TValue>,
+    pub fn_ptr: NativeFunction,
+    pub upvalues: RefCell<Vec<TValue>>,
+}
+
+impl std::fmt::Debug for CClosure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CClosure {{ fn_ptr: {:p}, upvalues: {:?} }}", Rc::as_ptr(&self.fn_ptr), self.upvalues.borrow())
+    }
 }
 
 #[derive(Debug)]
@@ -106,26 +386,14 @@ pub enum Closure {
 
 
 impl Closure {
-    pub fn new_lua(proto: &'static Proto) -> Self {
-        Closure::Lua(LuaClosure { proto, upvalues: Vec::with_capacity(proto.upvalues.len()), })
+    pub fn new_lua(proto: Rc<Proto>, upvalues: Vec<UpValRef>) -> Self {
+        Closure::Lua(LuaClosure { proto, upvalues: RefCell::new(upvalues) })
     }
 
-    pub fn init_upvalues(&mut self, upval_descrs: Vec<UpvalueDescription>, parent_upvalues: Vec<UpVal>) {
-        for (i, descr) in upval_descrs.iter().enumerate() {
-            if descr.instack {
-                todo!("Local upvalues are not supported yet");
-            } else {
-                self.set_upvalue(i, *parent_upvalues.get(descr.idx as usize).unwrap());
-            }
-        }
+    pub fn new_native<F>(function: F, upvalues: Vec<TValue>) -> Self
+    where F: Fn(&mut LuaStackView) -> Result<usize, LuaError> + 'static {
+        Closure::C(CClosure { fn_ptr: Rc::new(function), upvalues: RefCell::new(upvalues) })
     }
-
-    pub fn set_upvalue(&mut self, index: usize, value: UpVal) {
-        match self {
-            Closure::C(c_closure) => todo!(),
-            Closure::Lua(lua_closure) => { lua_closure.upvalues[index] = value; }
-        }
-    } 
 }
 
 pub enum ThreadMode {
@@ -133,14 +401,76 @@ pub enum ThreadMode {
     Stopped,
 }
 
+/* maximum number of stack slots a thread could use */
+pub const LUAI_MAXSTACK: usize = 1_000_000;
+/* maximum depth of nested native (Rust) calls */
+pub const LUAI_MAXCCALLS: usize = 200;
+/* minimum stack space available to a native function */
+pub const LUA_MINSTACK: usize = 20;
+
 pub struct LuaThread {
     pub stack: stack::LuaStack,
     pub current_call: LinkedList<CallInfo>,
     pub mode: ThreadMode,
-    pub open_upvalues: LinkedList<UpVal>,
+    pub open_upvalues: BTreeMap<StackIndex, UpValRef>,
     pub top: StackIndex, // stack current top ptr
+    pub n_ccalls: usize,
+    pub tbc_list: Vec<StackIndex>, /* stack slots of to-be-closed variables */
+    pub hook: Option<Hook>,
+    pub allow_hook: bool, /* false while a hook runs */
+    pub hook_count: u32, /* instructions left before the next count event */
+    pub old_pc: usize, /* last pc traced by the line hook */
+}
+
+impl LuaThread {
+    pub fn new() -> Self {
+        Self {
+            stack: LuaStack::new(LUA_MINSTACK * 2),
+            current_call: LinkedList::new(),
+            mode: ThreadMode::Stopped,
+            open_upvalues: BTreeMap::new(),
+            top: 0,
+            n_ccalls: 0,
+            tbc_list: Vec::new(),
+            hook: None,
+            allow_hook: true,
+            hook_count: 0,
+            old_pc: 0,
+        }
+    }
+
+    pub fn push(&mut self, value: TValue) {
+        self.stack.set_at_offset(value, self.top);
+        self.top += 1;
+    }
+
+    /**
+     * Returns the upvalue pointing to the stack slot, creating it when needed
+     */
+    pub fn find_upvalue(&mut self, index: StackIndex) -> UpValRef {
+        self.open_upvalues.entry(index).or_insert_with(|| Rc::new(RefCell::new(UpVal::new(index)))).clone()
+    }
+
+    /**
+     * Closes every open upvalue at or above the given level: the stack value is moved into the upvalue
+     */
+    pub fn close_upvalues(&mut self, level: StackIndex) {
+        let closed = self.open_upvalues.split_off(&level);
+        for (index, upval) in closed {
+            let value = self.stack.get_at_offset(index).clone();
+            *upval.borrow_mut() = UpVal::Closed(value);
+        }
+    }
 }
 
+impl Default for LuaThread {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub const LUA_MULTRET: i16 = -1;
+
 pub struct CallInfo {
     pub nresults: i16,
     pub nextraargs: usize,
@@ -148,39 +478,60 @@ pub struct CallInfo {
     pub top: StackIndex,
     pub fn_idx: StackIndex,
     pub pc: usize,
+    pub is_lua: bool,
+    pub is_fresh: bool, /* execution of this frame was started by a native call */
+    pub is_tail: bool, /* frame was reused by a tail call */
+    pub is_hooked: bool, /* a hook is running on top of this frame */
+    pub transfer: (usize, usize), /* first index and number of values moved by a call or return event */
 }
 
 impl CallInfo {
     pub fn new_lua(proto: &Proto, fn_idx: StackIndex) -> Self {
-        let lua_closure = Closure::new_lua(proto);
         Self {
             nextraargs: 0,
             fn_idx,
             pc: 0,
-            base: 0,
+            base: fn_idx + 1,
             nresults: 0,
             top: fn_idx + 1 + proto.max_stack_size as usize,
+            is_lua: true,
+            is_fresh: false,
+            is_tail: false,
+            is_hooked: false,
+            transfer: (0, 0),
         }
     }
 
-    pub fn get_closure<'a, 'stack>(&'a self, stack: &'stack LuaStack) -> &'stack Closure {
+    pub fn new_native(fn_idx: StackIndex, top: StackIndex) -> Self {
+        Self {
+            nextraargs: 0,
+            fn_idx,
+            pc: 0,
+            base: fn_idx + 1,
+            nresults: 0,
+            top,
+            is_lua: false,
+            is_fresh: false,
+            is_tail: false,
+            is_hooked: false,
+            transfer: (0, 0),
+        }
+    }
+
+    pub fn get_closure(&self, stack: &LuaStack) -> Rc<Closure> {
         let value_at_index = stack.get_at_offset(self.fn_idx);
         match value_at_index {
             TValue::CLOSURE(closure) => {
-                closure.as_ref()
+                closure.clone()
             },
             _ => { panic!("CallInfo points to {:?} instead of closure", value_at_index) }
         }
     }
 
-    pub fn get_lua_closure<'a, 'stack>(&self, stack: &'stack LuaStack) -> &'stack LuaClosure {
-        match self.get_closure(stack) {
-            Closure::Lua(closure) => {
-                closure
-            },
-            _ => {
-                panic!("Trying to get non-lua closure");
-            }
+    pub fn get_lua_proto(&self, stack: &LuaStack) -> Option<Rc<Proto>> {
+        match self.get_closure(stack).as_ref() {
+            Closure::Lua(closure) => Some(closure.proto.clone()),
+            Closure::C(_) => None,
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,329 @@
+/*
+ * Number <-> string conversions following lobject.c/lvm.c rules
+ */
+
+use super::TValue;
+
+const MAXSIGDIG: i32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum F2IMode {
+    /* no rounding; accepts only integral values */
+    Exact,
+    /* takes the floor of the number */
+    Floor,
+    /* takes the ceil of the number */
+    Ceil,
+}
+
+pub fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c)
+}
+
+fn hex_value(c: u8) -> Option<u32> {
+    (c as char).to_digit(16)
+}
+
+fn trim_spaces(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|c| !is_space(*c)).unwrap_or(s.len());
+    let end = s.iter().rposition(|c| !is_space(*c)).map(|e| e + 1).unwrap_or(start);
+    &s[start..end.max(start)]
+}
+
+fn split_sign(s: &[u8]) -> (bool, &[u8]) {
+    match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    }
+}
+
+fn is_hex_prefix(s: &[u8]) -> bool {
+    s.len() >= 2 && s[0] == b'0' && (s[1] == b'x' || s[1] == b'X')
+}
+
+/**
+ * l_str2int: decimal integers that fit, hex integers wrap around
+ */
+pub fn str_to_integer(s: &[u8]) -> Option<i64> {
+    let (neg, digits) = split_sign(trim_spaces(s));
+    let mut a = 0_u64;
+    if is_hex_prefix(digits) {
+        let hex = &digits[2..];
+        if hex.is_empty() {
+            return None;
+        }
+        for c in hex {
+            a = a.wrapping_mul(16).wrapping_add(hex_value(*c)? as u64);
+        }
+    } else {
+        if digits.is_empty() {
+            return None;
+        }
+        const MAXBY10: u64 = (i64::MAX / 10) as u64;
+        const MAXLASTD: u64 = (i64::MAX % 10) as u64;
+        for c in digits {
+            if !c.is_ascii_digit() {
+                return None;
+            }
+            let d = (c - b'0') as u64;
+            if a >= MAXBY10 && (a > MAXBY10 || d > MAXLASTD + neg as u64) {
+                return None;
+            }
+            a = a * 10 + d;
+        }
+    }
+    Some(if neg { 0_u64.wrapping_sub(a) as i64 } else { a as i64 })
+}
+
+/**
+ * lua_strx2number: hexadecimal float with optional binary exponent
+ */
+fn hex_str_to_float(s: &[u8]) -> Option<f64> {
+    let (neg, s) = split_sign(s);
+    if !is_hex_prefix(s) {
+        return None;
+    }
+    let mut r = 0.0_f64;
+    let mut sigdig = 0;
+    let mut nosigdig = 0;
+    let mut e = 0_i32;
+    let mut hasdot = false;
+    let mut i = 2;
+    while i < s.len() {
+        let c = s[i];
+        if c == b'.' {
+            if hasdot {
+                break;
+            }
+            hasdot = true;
+        } else if let Some(v) = hex_value(c) {
+            if sigdig == 0 && c == b'0' {
+                nosigdig += 1;
+            } else {
+                sigdig += 1;
+                if sigdig <= MAXSIGDIG {
+                    r = r * 16.0 + v as f64;
+                } else {
+                    e += 1;
+                }
+            }
+            if hasdot {
+                e -= 1;
+            }
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    if nosigdig + sigdig == 0 {
+        return None;
+    }
+    e *= 4;
+    if i < s.len() && (s[i] == b'p' || s[i] == b'P') {
+        i += 1;
+        let (neg1, rest) = split_sign(&s[i..]);
+        i = s.len() - rest.len();
+        if i >= s.len() || !s[i].is_ascii_digit() {
+            return None;
+        }
+        let mut exp1 = 0_i32;
+        while i < s.len() && s[i].is_ascii_digit() {
+            exp1 = exp1.saturating_mul(10).saturating_add((s[i] - b'0') as i32);
+            i += 1;
+        }
+        e = e.saturating_add(if neg1 { -exp1 } else { exp1 });
+    }
+    if i != s.len() {
+        return None;
+    }
+    Some(ldexp(if neg { -r } else { r }, e))
+}
+
+pub fn ldexp(x: f64, e: i32) -> f64 {
+    // split the exponent so intermediate powers never overflow/underflow early
+    let mut x = x;
+    let mut e = e;
+    while e > 1000 {
+        x *= 2f64.powi(1000);
+        e -= 1000;
+    }
+    while e < -1000 {
+        x *= 2f64.powi(-1000);
+        e += 1000;
+    }
+    x * 2f64.powi(e)
+}
+
+fn dec_str_to_float(s: &[u8]) -> Option<f64> {
+    // validate the strtod subset Lua accepts: [sign] digits [. digits] [e [sign] digits]
+    let (_, body) = split_sign(s);
+    let mut i = 0;
+    let mut digits = 0;
+    while i < body.len() && body[i].is_ascii_digit() { i += 1; digits += 1; }
+    if i < body.len() && body[i] == b'.' {
+        i += 1;
+        while i < body.len() && body[i].is_ascii_digit() { i += 1; digits += 1; }
+    }
+    if digits == 0 {
+        return None;
+    }
+    if i < body.len() && (body[i] == b'e' || body[i] == b'E') {
+        i += 1;
+        if i < body.len() && (body[i] == b'+' || body[i] == b'-') { i += 1; }
+        let exp_start = i;
+        while i < body.len() && body[i].is_ascii_digit() { i += 1; }
+        if i == exp_start {
+            return None;
+        }
+    }
+    if i != body.len() {
+        return None;
+    }
+    std::str::from_utf8(s).ok()?.parse::<f64>().ok()
+}
+
+/**
+ * l_str2d: any float numeral, rejecting 'inf' and 'nan'
+ */
+pub fn str_to_float(s: &[u8]) -> Option<f64> {
+    let s = trim_spaces(s);
+    if s.iter().any(|c| *c == b'n' || *c == b'N') {
+        return None;
+    }
+    if s.iter().any(|c| *c == b'x' || *c == b'X') {
+        hex_str_to_float(s)
+    } else {
+        dec_str_to_float(s)
+    }
+}
+
+/**
+ * luaO_str2num: tries an integer first, then a float
+ */
+pub fn str_to_number(s: &[u8]) -> Option<TValue> {
+    if s.contains(&0) {
+        return None;
+    }
+    if let Some(i) = str_to_integer(s) {
+        Some(TValue::NUMINT(i))
+    } else {
+        str_to_float(s).map(TValue::NUMFLT)
+    }
+}
+
+/**
+ * luaV_flttointns
+ */
+pub fn float_to_integer(f: f64, mode: F2IMode) -> Option<i64> {
+    let rounded = f.floor();
+    let value = if f != rounded {
+        match mode {
+            F2IMode::Exact => return None,
+            F2IMode::Floor => rounded,
+            F2IMode::Ceil => rounded + 1.0,
+        }
+    } else {
+        rounded
+    };
+    // -2^63 <= value < 2^63
+    if (-9223372036854775808.0..9223372036854775808.0).contains(&value) {
+        Some(value as i64)
+    } else {
+        None
+    }
+}
+
+/**
+ * Formats a float like C's "%.<precision>g"
+ */
+pub fn fmt_g(value: f64, precision: usize, alternate: bool) -> String {
+    if !value.is_finite() {
+        return fmt_non_finite(value);
+    }
+    let p = if precision == 0 { 1 } else { precision };
+    let sci = format!("{:.*e}", p - 1, value);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let x: i32 = exp.parse().unwrap();
+    let mut result = if x < -4 || x >= p as i32 {
+        let mut m = mantissa.to_string();
+        if !alternate {
+            m = strip_trailing_zeros(&m);
+        }
+        format!("{}e{}{:02}", m, if x < 0 { '-' } else { '+' }, x.abs())
+    } else {
+        let decimals = (p as i32 - 1 - x) as usize;
+        let fixed = format!("{:.*}", decimals, value);
+        if alternate { fixed } else { strip_trailing_zeros(&fixed) }
+    };
+    if alternate && !result.contains('.') {
+        match result.find('e') {
+            Some(pos) => result.insert(pos, '.'),
+            None => result.push('.'),
+        }
+    }
+    result
+}
+
+pub fn fmt_non_finite(value: f64) -> String {
+    if value.is_nan() {
+        if value.is_sign_negative() { "-nan".to_string() } else { "nan".to_string() }
+    } else if value > 0.0 {
+        "inf".to_string()
+    } else {
+        "-inf".to_string()
+    }
+}
+
+fn strip_trailing_zeros(s: &str) -> String {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+/**
+ * tostringbuff: floats use LUAI_NUMFFORMAT ("%.14g") and keep a '.0'
+ * suffix when they look like integers
+ */
+pub fn float_to_string(value: f64) -> String {
+    let mut s = fmt_g(value, 14, false);
+    if s.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
+        s.push_str(".0");
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_numerals() {
+        assert_eq!(str_to_integer(b" 42 "), Some(42));
+        assert_eq!(str_to_integer(b"0x10"), Some(16));
+        assert_eq!(str_to_integer(b"0xffffffffffffffff"), Some(-1));
+        assert_eq!(str_to_integer(b"9223372036854775808"), None);
+        assert_eq!(str_to_integer(b"-9223372036854775808"), Some(i64::MIN));
+        assert_eq!(str_to_float(b"0x1p4"), Some(16.0));
+        assert_eq!(str_to_float(b"0x.8"), Some(0.5));
+        assert_eq!(str_to_float(b"1e2"), Some(100.0));
+        assert_eq!(str_to_float(b".5"), Some(0.5));
+        assert_eq!(str_to_float(b"inf"), None);
+        assert_eq!(str_to_float(b"1e"), None);
+        assert!(str_to_number(b"1 2").is_none());
+    }
+
+    #[test]
+    fn format_floats() {
+        assert_eq!(float_to_string(1e15), "1e+15");
+        assert_eq!(float_to_string(2f64.powi(53)), "9.007199254741e+15");
+        assert_eq!(float_to_string(-0.0), "-0.0");
+        assert_eq!(float_to_string(14.0), "14.0");
+        assert_eq!(float_to_string(100.0 / 3.0), "33.333333333333");
+        assert_eq!(float_to_string(f64::INFINITY), "inf");
+        assert_eq!(fmt_g(0.0001, 6, false), "0.0001");
+        assert_eq!(fmt_g(100000.0, 6, true), "100000.");
+    }
+}
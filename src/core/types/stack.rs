@@ -1,78 +1,320 @@
-use super::{TValue, CallInfo, StackIndex};
+use std::{any::Any, rc::Rc};
+
+use crate::vm::{LuaVm, Origin};
+
+use super::{TValue, Closure, LuaError, LuaThread, StackIndex, LUAI_MAXSTACK, number::F2IMode, table::TableRef, string::LuaString, userdata::Userdata};
+
+/* slots kept free above the top for metamethod calls */
+const EXTRA_STACK: usize = 5;
 
 /**
  * Stack is an array of TValues
  */
 pub struct LuaStack {
     raw: Vec<TValue>,
-    top: usize,
 }
 
 impl LuaStack {
-    pub fn new(&self, capacity: usize) -> Self {
+    pub fn new(capacity: usize) -> Self {
         Self {
-            top: 0,
             raw: Vec::<TValue>::with_capacity(capacity),
         }
     }
 
-    pub fn top(&self) -> usize {
-        self.top
-    }
-
-    pub fn pop(&mut self) {
-        self.top -= 1;
+    pub fn size(&self) -> usize {
+        self.raw.len()
     }
 
-    pub fn push(&mut self, value: TValue) {
-        self.raw[self.top] = value;
-        self.top += 1;
+    /**
+     * Makes sure slots [0, size) exist, new slots are nil
+     */
+    pub fn ensure(&mut self, size: usize) {
+        if self.raw.len() < size {
+            self.raw.resize(size, TValue::NIL);
+        }
     }
 
     pub fn set_at_offset(&mut self, value: TValue, offset: StackIndex) {
+        self.ensure(offset + 1);
         self.raw[offset] = value;
     }
 
     pub fn get_at_offset(&self, offset: StackIndex) -> &TValue {
-        self.raw.get(offset).unwrap()
+        self.raw.get(offset).unwrap_or(&TValue::NIL)
     }
 
     pub fn get_at_offset_mut(&mut self, offset: StackIndex) -> &mut TValue {
+        self.ensure(offset + 1);
         self.raw.get_mut(offset).unwrap()
     }
 }
 
-type MutStackRef<'s> = &'s mut LuaStack;
-pub struct LuaStackView<'stack> {
-    stack: &'stack mut LuaStack,
-    base: usize,
-    top: usize,
-    num_args: u8,
-    num_varargs: usize,
+/**
+ * View of a native function frame: arguments live at [base, base + num_args),
+ * results are pushed above them
+ */
+pub struct LuaStackView<'a> {
+    pub vm: &'a mut LuaVm,
+    pub thread: &'a mut LuaThread,
+    closure: Rc<Closure>,
+    base: StackIndex,
+    num_args: usize,
 }
 
-impl<'stack> LuaStackView<'stack> {
+impl<'a> LuaStackView<'a> {
+
+    pub fn new(vm: &'a mut LuaVm, thread: &'a mut LuaThread, closure: Rc<Closure>, base: StackIndex, num_args: usize) -> Self {
+        Self { vm, thread, closure, base, num_args }
+    }
+
+    pub fn arg_count(&self) -> usize {
+        self.num_args
+    }
+
+    /**
+     * Arguments are numbered from 1 like in the C API, missing ones are nil
+     */
+    pub fn get_arg(&self, arg: usize) -> &TValue {
+        if arg == 0 || arg > self.num_args {
+            &TValue::NIL
+        } else {
+            self.thread.stack.get_at_offset(self.base + arg - 1)
+        }
+    }
+
+    pub fn set_arg(&mut self, arg: usize, value: TValue) {
+        if arg >= 1 && arg <= self.num_args {
+            self.thread.stack.set_at_offset(value, self.base + arg - 1);
+        }
+    }
+
+    pub fn args(&self) -> Vec<TValue> {
+        (1..=self.num_args).map(|i| self.get_arg(i).clone()).collect()
+    }
+
+    pub fn push(&mut self, value: TValue) {
+        self.thread.push(value);
+    }
+
+    /**
+     * Pushes every value as a result, the return value is meant to be returned from the native function
+     */
+    pub fn set_return_values(&mut self, values: &[TValue]) -> usize {
+        for value in values {
+            self.push(value.clone());
+        }
+        values.len()
+    }
+
+    pub fn closure(&self) -> &Rc<Closure> {
+        &self.closure
+    }
+
+    pub fn get_upvalue(&self, index: usize) -> TValue {
+        match self.closure.as_ref() {
+            Closure::C(c_closure) => c_closure.upvalues.borrow().get(index).cloned().unwrap_or(TValue::NIL),
+            Closure::Lua(_) => TValue::NIL,
+        }
+    }
+
+    pub fn set_upvalue(&mut self, index: usize, value: TValue) {
+        if let Closure::C(c_closure) = self.closure.as_ref() {
+            if let Some(slot) = c_closure.upvalues.borrow_mut().get_mut(index) {
+                *slot = value;
+            }
+        }
+    }
+
+    /**
+     * Calls a value with the given arguments and collects every result
+     */
+    pub fn call(&mut self, function: TValue, args: &[TValue]) -> Result<Vec<TValue>, LuaError> {
+        self.vm.call_value(self.thread, function, args)
+    }
+
+    /**
+     * luaL_error: the message is prefixed with the position of the calling Lua code
+     */
+    pub fn error(&self, message: &str) -> LuaError {
+        LuaError::new(&format!("{}{}", self.vm.where_(self.thread, 1), message))
+    }
+
+    /**
+     * luaG_runerror raised from native code: no position is added
+     */
+    pub fn runtime_error(&self, message: &str) -> LuaError {
+        self.vm.runtime_error(self.thread, message)
+    }
+
+    /**
+     * luaL_argerror
+     */
+    pub fn arg_error(&self, arg: usize, extra: &str) -> LuaError {
+        let mut arg = arg;
+        let (kind, name) = match self.vm.current_function_name(self.thread) {
+            Some((kind, name)) => (Some(kind), name),
+            None => (None, self.vm.global_function_name(&self.closure).unwrap_or_else(|| "?".to_string())),
+        };
+        if kind == Some("method") {
+            arg -= 1;
+            if arg == 0 {
+                return self.error(&format!("calling '{}' on bad self ({})", name, extra));
+            }
+        }
+        self.error(&format!("bad argument #{} to '{}' ({})", arg, name, extra))
+    }
+
+    /**
+     * luaL_typeerror
+     */
+    pub fn type_error(&self, arg: usize, expected: &str) -> LuaError {
+        let actual = if arg > self.num_args { "no value".to_string() } else { self.vm.obj_type_name(self.get_arg(arg)) };
+        self.arg_error(arg, &format!("{} expected, got {}", expected, actual))
+    }
+
+    pub fn check_any(&self, arg: usize) -> Result<&TValue, LuaError> {
+        if arg > self.num_args {
+            Err(self.arg_error(arg, "value expected"))
+        } else {
+            Ok(self.get_arg(arg))
+        }
+    }
+
+    pub fn check_integer(&self, arg: usize) -> Result<i64, LuaError> {
+        let value = self.get_arg(arg);
+        match value.to_integer(F2IMode::Exact) {
+            Some(i) => Ok(i),
+            None => {
+                if value.to_number().is_some() {
+                    Err(self.arg_error(arg, "number has no integer representation"))
+                } else {
+                    Err(self.type_error(arg, "number"))
+                }
+            }
+        }
+    }
+
+    pub fn opt_integer(&self, arg: usize, default: i64) -> Result<i64, LuaError> {
+        if self.get_arg(arg).is_nil() { Ok(default) } else { self.check_integer(arg) }
+    }
+
+    pub fn check_number(&self, arg: usize) -> Result<f64, LuaError> {
+        self.get_arg(arg).to_number().ok_or_else(|| self.type_error(arg, "number"))
+    }
+
+    pub fn opt_number(&self, arg: usize, default: f64) -> Result<f64, LuaError> {
+        if self.get_arg(arg).is_nil() { Ok(default) } else { self.check_number(arg) }
+    }
 
-    pub fn new(stack: &'stack mut LuaStack, call_info: &CallInfo) -> Self {
-        let closure = call_info.get_lua_closure(stack);
-        Self { stack, base: call_info.base, top: call_info.top, num_varargs: call_info.nextraargs, num_args: closure.proto.num_params }
+    /**
+     * luaL_checklstring: numbers are converted to strings
+     */
+    pub fn check_string(&self, arg: usize) -> Result<Rc<LuaString>, LuaError> {
+        match self.get_arg(arg) {
+            TValue::STR(s) => Ok(s.clone()),
+            TValue::NUMINT(i) => Ok(Rc::new(LuaString::from(i.to_string()))),
+            TValue::NUMFLT(f) => Ok(Rc::new(LuaString::from(super::number::float_to_string(*f)))),
+            _ => Err(self.type_error(arg, "string")),
+        }
     }
 
-    pub fn set_register(self, register: StackIndex, value: TValue) {
-        self.stack.set_at_offset(value, self.base + register as usize);
+    pub fn opt_string(&self, arg: usize, default: &str) -> Result<Rc<LuaString>, LuaError> {
+        if self.get_arg(arg).is_nil() { Ok(Rc::new(LuaString::from(default))) } else { self.check_string(arg) }
+    }
+
+    pub fn check_function(&self, arg: usize) -> Result<Rc<Closure>, LuaError> {
+        match self.get_arg(arg) {
+            TValue::CLOSURE(c) => Ok(c.clone()),
+            _ => Err(self.type_error(arg, "function")),
+        }
     }
 
-    pub fn get_register(&self, register: StackIndex) -> &TValue {
-        self.stack.get_at_offset(self.base + register as usize)
+    pub fn check_table(&self, arg: usize) -> Result<TableRef, LuaError> {
+        match self.get_arg(arg) {
+            TValue::TABLE(t) => Ok(t.clone()),
+            _ => Err(self.type_error(arg, "table")),
+        }
     }
 
-    pub fn get_register_mut(&mut self, register: StackIndex) -> &mut TValue {
-        self.stack.get_at_offset_mut(register)
+    /**
+     * luaL_checkudata: the type of the payload identifies the kind of userdata, 'name' is
+     * used in the error message
+     */
+    pub fn check_userdata<T: Any>(&self, arg: usize, name: &str) -> Result<Rc<Userdata>, LuaError> {
+        match self.get_arg(arg) {
+            TValue::USERDATA(userdata) if userdata.is::<T>() => Ok(userdata.clone()),
+            _ => Err(self.type_error(arg, name)),
+        }
     }
 
-    pub fn get_arg(&self, arg: usize) -> &TValue {  panic!("")  }
+    /**
+     * luaL_checkoption: index of the string argument in 'options'
+     */
+    pub fn check_option(&self, arg: usize, default: Option<&str>, options: &[&str]) -> Result<usize, LuaError> {
+        let name = match default {
+            Some(default) => self.opt_string(arg, default)?,
+            None => self.check_string(arg)?,
+        };
+        match options.iter().position(|option| option.as_bytes() == name.as_bytes()) {
+            Some(index) => Ok(index),
+            None => Err(self.arg_error(arg, &format!("invalid option '{}'", name))),
+        }
+    }
 
-    pub fn set_return_values(&mut self, values: &[TValue]) {
+    /**
+     * t[key] with metamethods
+     */
+    pub fn index(&mut self, t: &TValue, key: &TValue) -> Result<TValue, LuaError> {
+        self.vm.index(self.thread, t, key, Origin::Unknown)
+    }
 
+    /**
+     * t[key] = value with metamethods
+     */
+    pub fn set_index(&mut self, t: &TValue, key: TValue, value: TValue) -> Result<(), LuaError> {
+        self.vm.set_index(self.thread, t, key, value, Origin::Unknown)
     }
-}
\ No newline at end of file
+
+    /**
+     * luaL_len: length with '__len', which must be an integer
+     */
+    pub fn len(&mut self, value: &TValue) -> Result<i64, LuaError> {
+        let length = self.vm.length(self.thread, value, Origin::Unknown)?;
+        length.to_integer(F2IMode::Exact).ok_or_else(|| self.error("object length is not an integer"))
+    }
+
+    /**
+     * lua_checkstack: whether 'n' more values fit in the stack
+     */
+    pub fn check_stack(&self, n: usize) -> bool {
+        n <= LUAI_MAXSTACK && self.thread.top + EXTRA_STACK <= LUAI_MAXSTACK - n
+    }
+
+    /**
+     * luaL_tolstring: string representation honouring '__tostring' and '__name'
+     */
+    pub fn tolstring(&mut self, value: &TValue) -> Result<Rc<LuaString>, LuaError> {
+        let tm = self.vm.get_metafield(value, "__tostring");
+        if !tm.is_nil() {
+            let result = self.vm.call_tm(self.thread, tm, std::slice::from_ref(value))?;
+            return match crate::vm::coerce_to_string(&result) {
+                Some(s) => Ok(s),
+                None => Err(self.error("'__tostring' must return a string")),
+            };
+        }
+        if let Some(s) = crate::vm::coerce_to_string(value) {
+            return Ok(s);
+        }
+        Ok(Rc::new(LuaString::from(match value {
+            TValue::NIL | TValue::EMPTY => "nil".to_string(),
+            TValue::TBOOLEAN(b) => b.to_string(),
+            _ => {
+                let kind = match self.vm.get_metafield(value, "__name") {
+                    TValue::STR(name) => name.to_string(),
+                    _ => value.type_name().to_string(),
+                };
+                format!("{}: {:p}", kind, value.to_pointer().unwrap_or(std::ptr::null()))
+            }
+        })))
+    }
+}
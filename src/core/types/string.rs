@@ -0,0 +1,99 @@
+use std::{borrow::Cow, ops::Deref};
+
+/**
+ * Lua string: an immutable sequence of bytes, not necessarily valid UTF-8
+ */
+#[derive(Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LuaString(Vec<u8>);
+
+impl LuaString {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /**
+     * Text of the string, invalid sequences are replaced by U+FFFD
+     */
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+/**
+ * luaO_utf8esc: appends the UTF-8 encoding of 'x', with sequences of up to 6 bytes so any
+ * value up to 0x7FFFFFFF can be represented
+ */
+pub fn utf8_escape(buffer: &mut Vec<u8>, x: u32) {
+    debug_assert!(x <= 0x7FFF_FFFF);
+    // ascii
+    if x < 0x80 {
+        buffer.push(x as u8);
+        return;
+    }
+    let mut x = x;
+    let mut continuation = Vec::with_capacity(5);
+    // maximum that fits in the first byte
+    let mut mfb = 0x3f;
+    loop {
+        continuation.push(0x80 | (x & 0x3f) as u8);
+        x >>= 6;
+        mfb >>= 1;
+        if x <= mfb {
+            break;
+        }
+    }
+    buffer.push(((!mfb << 1) | x) as u8);
+    buffer.extend(continuation.iter().rev());
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> Self {
+        Self(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(s: String) -> Self {
+        Self(s.into_bytes())
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl std::fmt::Display for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_str_lossy())
+    }
+}
+
+impl std::fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_str_lossy())
+    }
+}
@@ -0,0 +1,320 @@
+use std::{rc::Rc, cell::RefCell, collections::HashMap};
+
+use super::{TValue, number::{float_to_integer, F2IMode}, string::LuaString};
+
+pub type TableRef = Rc<RefCell<LuaTable>>;
+
+/**
+ * Hashable form of a table key: floats with an integral value are normalized to integers
+ * and collectable objects are compared by identity
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum TKey {
+    Bool(bool),
+    Int(i64),
+    Float(u64),
+    Str(Rc<LuaString>),
+    Object(usize),
+}
+
+impl TKey {
+    fn from_value(key: &TValue) -> Option<TKey> {
+        match key {
+            TValue::NIL | TValue::EMPTY => None,
+            TValue::TBOOLEAN(b) => Some(TKey::Bool(*b)),
+            TValue::NUMINT(i) => Some(TKey::Int(*i)),
+            TValue::NUMFLT(f) => match float_to_integer(*f, F2IMode::Exact) {
+                Some(i) => Some(TKey::Int(i)),
+                None if f.is_nan() => None,
+                None => Some(TKey::Float(f.to_bits())),
+            },
+            TValue::STR(s) => Some(TKey::Str(s.clone())),
+            _ => key.to_pointer().map(|p| TKey::Object(p as usize)),
+        }
+    }
+}
+
+/**
+ * Lua table: integer keys 1..n live in the array part, everything else in an insertion ordered
+ * hash part. Removed hash entries are kept as tombstones (nil values) so 'next' keeps working
+ * while fields are cleared during a traversal
+ */
+#[derive(Debug, Default)]
+pub struct LuaTable {
+    array: Vec<TValue>,
+    hash: HashMap<TKey, usize>,
+    entries: Vec<(TValue, TValue)>,
+    tombstones: usize,
+    pub metatable: Option<TableRef>,
+}
+
+/* tombstones are only swept when there are more of them than this */
+const MIN_TOMBSTONES_TO_SWEEP: usize = 8;
+
+impl LuaTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(array_size: usize, hash_size: usize) -> Self {
+        Self {
+            array: Vec::with_capacity(array_size),
+            hash: HashMap::with_capacity(hash_size),
+            entries: Vec::with_capacity(hash_size),
+            ..Self::default()
+        }
+    }
+
+    pub fn new_ref() -> TableRef {
+        Rc::new(RefCell::new(Self::new()))
+    }
+
+    pub fn get(&self, key: &TValue) -> TValue {
+        match TKey::from_value(key) {
+            Some(TKey::Int(i)) => self.get_int(i),
+            Some(key) => self.get_hashed(&key),
+            None => TValue::NIL,
+        }
+    }
+
+    pub fn get_int(&self, key: i64) -> TValue {
+        if key >= 1 && (key as u64) <= self.array.len() as u64 {
+            return self.array[key as usize - 1].clone();
+        }
+        self.get_hashed(&TKey::Int(key))
+    }
+
+    pub fn get_str(&self, key: &str) -> TValue {
+        self.get_hashed(&TKey::Str(Rc::new(LuaString::from(key))))
+    }
+
+    fn get_hashed(&self, key: &TKey) -> TValue {
+        match self.hash.get(key) {
+            Some(index) => self.entries[*index].1.clone(),
+            None => TValue::NIL,
+        }
+    }
+
+    /**
+     * Raw assignment, fails for nil and NaN keys with the message Lua uses
+     */
+    pub fn set(&mut self, key: TValue, value: TValue) -> Result<(), &'static str> {
+        match TKey::from_value(&key) {
+            Some(TKey::Int(i)) => {
+                self.set_int(i, value);
+                Ok(())
+            },
+            Some(hashed) => {
+                self.set_hashed(hashed, key, value);
+                Ok(())
+            },
+            None if key.is_nil() => Err("table index is nil"),
+            None => Err("table index is NaN"),
+        }
+    }
+
+    pub fn set_int(&mut self, key: i64, value: TValue) {
+        let len = self.array.len();
+        if key >= 1 && (key as u64) <= len as u64 {
+            self.array[key as usize - 1] = value;
+        } else if key >= 1 && key as u64 == len as u64 + 1 && !value.is_nil() {
+            self.remove_hashed(&TKey::Int(key));
+            self.array.push(value);
+            self.migrate_to_array();
+        } else {
+            self.set_hashed(TKey::Int(key), TValue::NUMINT(key), value);
+        }
+    }
+
+    pub fn set_str(&mut self, key: &str, value: TValue) {
+        let key = Rc::new(LuaString::from(key));
+        self.set_hashed(TKey::Str(key.clone()), TValue::STR(key), value);
+    }
+
+    fn set_hashed(&mut self, hashed: TKey, key: TValue, value: TValue) {
+        if let Some(index) = self.hash.get(&hashed) {
+            let entry = &mut self.entries[*index];
+            match (entry.1.is_nil(), value.is_nil()) {
+                (true, false) => self.tombstones -= 1,
+                (false, true) => self.tombstones += 1,
+                _ => {},
+            }
+            entry.1 = value;
+            return;
+        }
+        if value.is_nil() {
+            return;
+        }
+        if self.tombstones > MIN_TOMBSTONES_TO_SWEEP && self.tombstones > self.entries.len() / 2 {
+            self.sweep();
+        }
+        // integral float keys are stored as integers
+        let key = match hashed {
+            TKey::Int(i) => TValue::NUMINT(i),
+            _ => key,
+        };
+        self.hash.insert(hashed, self.entries.len());
+        self.entries.push((key, value));
+    }
+
+    fn remove_hashed(&mut self, hashed: &TKey) -> TValue {
+        match self.hash.get(hashed) {
+            Some(index) => {
+                let old = std::mem::replace(&mut self.entries[*index].1, TValue::NIL);
+                if !old.is_nil() {
+                    self.tombstones += 1;
+                }
+                old
+            },
+            None => TValue::NIL,
+        }
+    }
+
+    /* moves keys following the array part from the hash part */
+    fn migrate_to_array(&mut self) {
+        loop {
+            let next = TKey::Int(self.array.len() as i64 + 1);
+            let value = self.remove_hashed(&next);
+            if value.is_nil() {
+                break;
+            }
+            self.array.push(value);
+        }
+    }
+
+    /* drops tombstones, only done when a new key is inserted */
+    fn sweep(&mut self) {
+        self.entries.retain(|(_, value)| !value.is_nil());
+        self.hash.clear();
+        for (index, (key, _)) in self.entries.iter().enumerate() {
+            self.hash.insert(TKey::from_value(key).unwrap(), index);
+        }
+        self.tombstones = 0;
+    }
+
+    /**
+     * luaH_getn: a border of the table, any index with a non nil value followed by nil
+     */
+    pub fn len(&self) -> i64 {
+        let n = self.array.len();
+        if n > 0 && self.array[n - 1].is_nil() {
+            let (mut lo, mut hi) = (0, n);
+            while hi - lo > 1 {
+                let m = (lo + hi) / 2;
+                if self.array[m - 1].is_nil() { hi = m } else { lo = m }
+            }
+            return lo as i64;
+        }
+        if self.entries.is_empty() || self.get_int(n as i64 + 1).is_nil() {
+            return n as i64;
+        }
+        // hash_search: unbound search for a border in the hash part
+        let mut i = n as u64 + 1;
+        let mut j = i * 2;
+        while !self.get_int(j as i64).is_nil() {
+            i = j;
+            if j > (i64::MAX as u64) / 2 {
+                // overflow: linear search
+                let mut k = 1;
+                while !self.get_int(k).is_nil() {
+                    k += 1;
+                }
+                return k - 1;
+            }
+            j *= 2;
+        }
+        while j - i > 1 {
+            let m = (i + j) / 2;
+            if self.get_int(m as i64).is_nil() { j = m } else { i = m }
+        }
+        i as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.array.iter().all(|v| v.is_nil()) && self.entries.iter().all(|(_, v)| v.is_nil())
+    }
+
+    /**
+     * luaH_next: the entry after 'key' in traversal order, Err when the key isn't in the table
+     */
+    pub fn next(&self, key: &TValue) -> Result<Option<(TValue, TValue)>, ()> {
+        let start_entry = match TKey::from_value(key) {
+            None if key.is_nil() => {
+                return Ok(self.next_from_array(0).or_else(|| self.next_from_entries(0)));
+            },
+            None => return Err(()),
+            Some(TKey::Int(i)) if i >= 1 && (i as u64) <= self.array.len() as u64 => {
+                return Ok(self.next_from_array(i as usize).or_else(|| self.next_from_entries(0)));
+            },
+            Some(hashed) => match self.hash.get(&hashed) {
+                Some(index) => index + 1,
+                None => return Err(()),
+            },
+        };
+        Ok(self.next_from_entries(start_entry))
+    }
+
+    fn next_from_array(&self, start: usize) -> Option<(TValue, TValue)> {
+        (start..self.array.len())
+            .find(|i| !self.array[*i].is_nil())
+            .map(|i| (TValue::NUMINT(i as i64 + 1), self.array[i].clone()))
+    }
+
+    fn next_from_entries(&self, start: usize) -> Option<(TValue, TValue)> {
+        self.entries.iter().skip(start).find(|(_, value)| !value.is_nil()).cloned()
+    }
+
+    /**
+     * Every non nil pair in traversal order
+     */
+    pub fn iter(&self) -> impl Iterator<Item = (TValue, TValue)> + '_ {
+        let array = self.array.iter().enumerate()
+            .filter(|(_, value)| !value.is_nil())
+            .map(|(i, value)| (TValue::NUMINT(i as i64 + 1), value.clone()));
+        let entries = self.entries.iter().filter(|(_, value)| !value.is_nil()).cloned();
+        array.chain(entries)
+    }
+
+    pub fn get_metatable(&self) -> Option<TableRef> {
+        self.metatable.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn integer_keys_move_to_array_part() {
+        let mut table = LuaTable::new();
+        table.set_int(3, TValue::NUMINT(30));
+        table.set_int(2, TValue::NUMINT(20));
+        table.set(TValue::NUMFLT(1.0), TValue::NUMINT(10)).unwrap();
+        assert_eq!(table.array.len(), 3);
+        assert_eq!(table.len(), 3);
+        assert!(table.get(&TValue::NUMFLT(2.0)).raw_equals(&TValue::NUMINT(20)));
+        table.set_int(3, TValue::NIL);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.set(TValue::NIL, TValue::NIL), Err("table index is nil"));
+        assert_eq!(table.set(TValue::NUMFLT(f64::NAN), TValue::NIL), Err("table index is NaN"));
+    }
+
+    #[test]
+    fn next_survives_clearing_fields() {
+        let mut table = LuaTable::new();
+        table.set_int(1, TValue::from_str("a"));
+        for name in ["x", "y", "z"] {
+            table.set_str(name, TValue::TBOOLEAN(true));
+        }
+        let mut key = TValue::NIL;
+        let mut seen = vec![];
+        while let Some((k, _)) = table.next(&key).unwrap() {
+            table.set(k.clone(), TValue::NIL).unwrap();
+            seen.push(k.clone());
+            key = k;
+        }
+        assert_eq!(seen.len(), 4);
+        assert!(table.is_empty());
+        assert!(table.next(&TValue::from_str("missing")).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::{any::Any, cell::{Ref, RefCell, RefMut}};
+
+use super::{TValue, table::TableRef};
+
+/**
+ * Full userdata: a host value owned by Lua, with its own metatable and user values. The
+ * payload is released (closing files, sockets...) when the last reference goes away
+ */
+pub struct Userdata {
+    data: RefCell<Box<dyn Any>>,
+    pub metatable: RefCell<Option<TableRef>>,
+    pub user_values: RefCell<Vec<TValue>>,
+}
+
+impl Userdata {
+    /**
+     * lua_newuserdatauv: 'num_values' user values, all nil
+     */
+    pub fn new<T: Any>(data: T, num_values: usize) -> Self {
+        Self {
+            data: RefCell::new(Box::new(data)),
+            metatable: RefCell::new(None),
+            user_values: RefCell::new(vec![TValue::NIL; num_values]),
+        }
+    }
+
+    pub fn get_metatable(&self) -> Option<TableRef> {
+        self.metatable.borrow().clone()
+    }
+
+    pub fn is<T: Any>(&self) -> bool {
+        self.data.borrow().is::<T>()
+    }
+
+    /**
+     * The payload when it is a 'T'
+     */
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.data.borrow(), |data| data.downcast_ref::<T>()).ok()
+    }
+
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.data.borrow_mut(), |data| data.downcast_mut::<T>()).ok()
+    }
+}
+
+impl std::fmt::Debug for Userdata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Userdata({:p})", self)
+    }
+}
@@ -1,15 +1,72 @@
 //#![feature(rustc_attrs)]
+// most of the interpreter is an API that the binary itself doesn't use yet
+#![allow(dead_code)]
 
 
 use std::fs;
 use std::env;
+use std::process;
 mod core;
+mod compiler;
+mod analysis;
 mod structures;
 mod vm;
+mod stdlib;
+
+/**
+ * -l lists a chunk, source or binary, in the syntax that -a assembles into a binary chunk;
+ * -d prints a binary chunk as Lua source; -g prints the control-flow graphs of a chunk in DOT
+ */
+fn run_tool(args: &[String]) -> Result<(), String> {
+    match args[0].as_str() {
+        "-l" | "-d" | "-g" => {
+            let chunk = fs::read(&args[1]).map_err(|e| e.to_string())?;
+            let proto = if chunk.starts_with(core::parser::LUA_SIGNATURE) {
+                let proto = core::parser::parse_all(&chunk)?;
+                analysis::verify::verify(&proto)?;
+                proto
+            } else {
+                compiler::codegen::compile(compiler::lexer::skip_comment(&chunk), &format!("@{}", args[1])).map_err(|e| e.to_string())?
+            };
+            match args[0].as_str() {
+                "-d" => print!("{}", compiler::decompile::decompile_source(&proto)?),
+                "-g" => print!("{}", analysis::cfg::chunk_dot(&proto)),
+                _ => print!("{}", core::asm::disassemble(&proto)),
+            }
+        },
+        _ => {
+            let text = fs::read_to_string(&args[1]).map_err(|e| e.to_string())?;
+            let proto = core::asm::assemble(&text).map_err(|message| format!("{}:{}", args[1], message))?;
+            let output = args.get(2).map_or("luac.out", String::as_str);
+            fs::write(output, core::dump::dump(&proto, false)).map_err(|e| e.to_string())?;
+        },
+    }
+    Ok(())
+}
 
 fn main() {
     let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() > 1 && (args[0] == "-l" || args[0] == "-d" || args[0] == "-g" || args[0] == "-a") {
+        if let Err(message) = run_tool(&args) {
+            eprintln!("RustyMoon: {}", message);
+            process::exit(1);
+        }
+        return;
+    }
     let result = fs::read(&args[0]).expect("Failed to read file");
-    let fn_info = core::parser::parse_all(&result);
-    println!("{}", fn_info);
+    let mut vm = vm::LuaVm::new();
+    stdlib::open_libs(&mut vm);
+    let mut thread = core::types::LuaThread::new();
+    let main_closure = match vm.load_chunk(compiler::lexer::skip_comment(&result), &format!("@{}", args[0]), "bt") {
+        Ok(main_closure) => main_closure,
+        Err(message) => {
+            eprintln!("lua: {}", message);
+            process::exit(1);
+        }
+    };
+    let script_args: Vec<core::types::TValue> = args[1..].iter().map(|arg| core::types::TValue::from_str(arg)).collect();
+    if let Err(error) = vm.protected_call(&mut thread, main_closure, &script_args) {
+        eprintln!("lua: {}", error);
+        process::exit(1);
+    }
 }
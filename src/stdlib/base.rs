@@ -0,0 +1,519 @@
+/*
+ * Basic library (lbaselib.c)
+ */
+
+use std::{rc::Rc, cell::RefCell, fs, io::{self, Read, Write}};
+
+use crate::{compiler, core::types::{TValue, LuaError, Closure, UpVal, string::LuaString, stack::LuaStackView, number::{self, is_space}}, vm::LuaVm};
+
+use super::{register, os_error_message, os::OsPermissions};
+
+pub fn open(vm: &mut LuaVm, permissions: OsPermissions) {
+    let globals = vm.globals.clone();
+    if permissions.files {
+        register(&globals, &[("dofile", dofile), ("loadfile", loadfile)]);
+    }
+    register(&globals, &[
+        ("assert", assert),
+        ("error", error),
+        ("getmetatable", get_metatable),
+        ("load", load),
+        ("next", next),
+        ("pcall", pcall),
+        ("print", print),
+        ("rawequal", raw_equal),
+        ("rawget", raw_get),
+        ("rawlen", raw_len),
+        ("rawset", raw_set),
+        ("select", select),
+        ("setmetatable", set_metatable),
+        ("tonumber", to_number),
+        ("tostring", to_string),
+        ("type", type_),
+        ("xpcall", xpcall),
+    ]);
+    let mut globals = globals.borrow_mut();
+    // 'pairs' and 'ipairs' always return the same iterator functions
+    let next_function = globals.get_str("next");
+    globals.set_str("pairs", TValue::CLOSURE(Rc::new(Closure::new_native(pairs, vec![next_function]))));
+    let ipairs_iterator = super::native(ipairs_aux);
+    globals.set_str("ipairs", TValue::CLOSURE(Rc::new(Closure::new_native(ipairs, vec![ipairs_iterator]))));
+    let gc_state = Rc::new(RefCell::new(GcState::default()));
+    globals.set_str("collectgarbage", TValue::CLOSURE(Rc::new(Closure::new_native(move |frame| {
+        collect_garbage_with(frame, &mut gc_state.borrow_mut())
+    }, vec![]))));
+    let warn_state = Rc::new(RefCell::new(WarnState::default()));
+    globals.set_str("warn", TValue::CLOSURE(Rc::new(Closure::new_native(move |frame| {
+        warn_with(frame, &mut warn_state.borrow_mut())
+    }, vec![]))));
+    globals.set_str("_G", TValue::TABLE(vm.globals.clone()));
+    globals.set_str("_VERSION", TValue::from_str("Lua 5.4"));
+}
+
+fn print(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let mut line = Vec::new();
+    for i in 1..=frame.arg_count() {
+        if i > 1 {
+            line.push(b'\t');
+        }
+        let value = frame.get_arg(i).clone();
+        line.extend_from_slice(frame.tolstring(&value)?.as_bytes());
+    }
+    line.push(b'\n');
+    // output errors are ignored like lua_writestring does
+    let _ = frame.vm.stdout.write_all(&line);
+    let _ = frame.vm.stdout.flush();
+    Ok(0)
+}
+
+fn type_(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let name = frame.check_any(1)?.type_name();
+    Ok(frame.set_return_values(&[TValue::from_str(name)]))
+}
+
+fn to_string(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let value = frame.check_any(1)?.clone();
+    let s = frame.tolstring(&value)?;
+    Ok(frame.set_return_values(&[TValue::STR(s)]))
+}
+
+/**
+ * l_str2int from lbaselib.c: an integer numeral in the given base
+ */
+fn str_to_int_base(s: &[u8], base: i64) -> Option<i64> {
+    let mut i = 0;
+    while i < s.len() && is_space(s[i]) {
+        i += 1;
+    }
+    let negative = s.get(i) == Some(&b'-');
+    if negative || s.get(i) == Some(&b'+') {
+        i += 1;
+    }
+    if !s.get(i)?.is_ascii_alphanumeric() {
+        return None;
+    }
+    let mut n: u64 = 0;
+    while i < s.len() && s[i].is_ascii_alphanumeric() {
+        let digit = if s[i].is_ascii_digit() { s[i] - b'0' } else { s[i].to_ascii_uppercase() - b'A' + 10 } as i64;
+        if digit >= base {
+            return None;
+        }
+        n = n.wrapping_mul(base as u64).wrapping_add(digit as u64);
+        i += 1;
+    }
+    while i < s.len() && is_space(s[i]) {
+        i += 1;
+    }
+    if i != s.len() {
+        return None;
+    }
+    Some(if negative { (n as i64).wrapping_neg() } else { n as i64 })
+}
+
+fn to_number(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let result = if frame.get_arg(2).is_nil() {
+        match frame.get_arg(1) {
+            TValue::NUMINT(_) | TValue::NUMFLT(_) => frame.get_arg(1).clone(),
+            TValue::STR(s) => number::str_to_number(s.as_bytes()).unwrap_or(TValue::NIL),
+            _ => {
+                frame.check_any(1)?;
+                TValue::NIL
+            }
+        }
+    } else {
+        let base = frame.check_integer(2)?;
+        let s = match frame.get_arg(1) {
+            TValue::STR(s) => s.clone(),
+            _ => return Err(frame.type_error(1, "string")),
+        };
+        if !(2..=36).contains(&base) {
+            return Err(frame.arg_error(2, "base out of range"));
+        }
+        match str_to_int_base(s.as_bytes(), base) {
+            Some(n) => TValue::NUMINT(n),
+            None => TValue::NIL,
+        }
+    };
+    Ok(frame.set_return_values(&[result]))
+}
+
+fn get_metatable(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let value = frame.check_any(1)?.clone();
+    let result = match frame.vm.get_metatable(&value) {
+        None => TValue::NIL,
+        Some(metatable) => {
+            let protected = metatable.borrow().get_str("__metatable");
+            if protected.is_nil() { TValue::TABLE(metatable) } else { protected }
+        }
+    };
+    Ok(frame.set_return_values(&[result]))
+}
+
+fn set_metatable(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let table = frame.check_table(1)?;
+    let metatable = match frame.get_arg(2) {
+        TValue::NIL => None,
+        TValue::TABLE(metatable) => Some(metatable.clone()),
+        _ => return Err(frame.type_error(2, "nil or table")),
+    };
+    if !frame.vm.get_metafield(frame.get_arg(1), "__metatable").is_nil() {
+        return Err(frame.error("cannot change a protected metatable"));
+    }
+    table.borrow_mut().metatable = metatable;
+    Ok(frame.set_return_values(&[TValue::TABLE(table)]))
+}
+
+fn raw_equal(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let a = frame.check_any(1)?.clone();
+    let b = frame.check_any(2)?;
+    let result = a.raw_equals(b);
+    Ok(frame.set_return_values(&[TValue::TBOOLEAN(result)]))
+}
+
+fn raw_len(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let len = match frame.get_arg(1) {
+        TValue::TABLE(table) => table.borrow().len(),
+        TValue::STR(s) => s.len() as i64,
+        _ => return Err(frame.type_error(1, "table or string")),
+    };
+    Ok(frame.set_return_values(&[TValue::NUMINT(len)]))
+}
+
+fn raw_get(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let table = frame.check_table(1)?;
+    let value = table.borrow().get(frame.check_any(2)?);
+    Ok(frame.set_return_values(&[value]))
+}
+
+fn raw_set(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let table = frame.check_table(1)?;
+    let key = frame.check_any(2)?.clone();
+    let value = frame.check_any(3)?.clone();
+    let result = table.borrow_mut().set(key, value);
+    result.map_err(|message| frame.runtime_error(message))?;
+    Ok(frame.set_return_values(&[TValue::TABLE(table)]))
+}
+
+fn next(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let table = frame.check_table(1)?;
+    let result = table.borrow().next(frame.get_arg(2));
+    match result {
+        Ok(Some((key, value))) => Ok(frame.set_return_values(&[key, value])),
+        Ok(None) => Ok(frame.set_return_values(&[TValue::NIL])),
+        Err(()) => Err(frame.runtime_error("invalid key to 'next'")),
+    }
+}
+
+fn pairs(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let value = frame.check_any(1)?.clone();
+    let tm = frame.vm.get_metafield(&value, "__pairs");
+    if tm.is_nil() {
+        let next_function = frame.get_upvalue(0);
+        Ok(frame.set_return_values(&[next_function, value, TValue::NIL]))
+    } else {
+        let mut results = frame.call(tm, &[value])?;
+        results.resize(3, TValue::NIL);
+        Ok(frame.set_return_values(&results))
+    }
+}
+
+fn ipairs_aux(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let i = frame.check_integer(2)?.wrapping_add(1);
+    let table = frame.get_arg(1).clone();
+    let value = frame.index(&table, &TValue::NUMINT(i))?;
+    if value.is_nil() {
+        Ok(frame.set_return_values(&[TValue::NIL]))
+    } else {
+        Ok(frame.set_return_values(&[TValue::NUMINT(i), value]))
+    }
+}
+
+fn ipairs(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let value = frame.check_any(1)?.clone();
+    let iterator = frame.get_upvalue(0);
+    Ok(frame.set_return_values(&[iterator, value, TValue::NUMINT(0)]))
+}
+
+fn select(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let top = frame.arg_count() as i64;
+    if let TValue::STR(s) = frame.get_arg(1) {
+        if s.as_bytes() == b"#" {
+            return Ok(frame.set_return_values(&[TValue::NUMINT(top - 1)]));
+        }
+    }
+    let mut n = frame.check_integer(1)?;
+    if n < 0 {
+        n += top;
+    } else if n > top {
+        n = top;
+    }
+    if n < 1 {
+        return Err(frame.arg_error(1, "index out of range"));
+    }
+    let values: Vec<TValue> = ((n + 1)..=top).map(|i| frame.get_arg(i as usize).clone()).collect();
+    Ok(frame.set_return_values(&values))
+}
+
+fn assert(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    if !frame.get_arg(1).is_falsy() {
+        let args = frame.args();
+        return Ok(frame.set_return_values(&args));
+    }
+    frame.check_any(1)?;
+    let message = match frame.arg_count() {
+        0 | 1 => TValue::from_str("assertion failed!"),
+        _ => frame.get_arg(2).clone(),
+    };
+    Err(raise(frame, message, 1))
+}
+
+/**
+ * luaB_error: string messages get the position of the given level
+ */
+fn raise(frame: &LuaStackView, message: TValue, level: i64) -> LuaError {
+    match &message {
+        TValue::STR(s) if level > 0 => {
+            LuaError::new(&format!("{}{}", frame.vm.where_(frame.thread, level as usize), s))
+        },
+        _ => LuaError::from_value(message),
+    }
+}
+
+fn error(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let level = frame.opt_integer(2, 1)?;
+    let message = frame.get_arg(1).clone();
+    Err(raise(frame, message, level))
+}
+
+fn pcall(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let function = frame.check_any(1)?.clone();
+    let args: Vec<TValue> = frame.args().into_iter().skip(1).collect();
+    let result = frame.vm.protected_call(frame.thread, function, &args);
+    finish_pcall(frame, result)
+}
+
+fn xpcall(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    frame.check_function(2)?;
+    let function = frame.get_arg(1).clone();
+    let handler = frame.get_arg(2).clone();
+    let args: Vec<TValue> = frame.args().into_iter().skip(2).collect();
+    let result = frame.vm.protected_call_with_handler(frame.thread, function, &args, Some(handler));
+    finish_pcall(frame, result)
+}
+
+fn finish_pcall(frame: &mut LuaStackView, result: Result<Vec<TValue>, LuaError>) -> Result<usize, LuaError> {
+    match result {
+        Ok(results) => {
+            frame.push(TValue::TBOOLEAN(true));
+            Ok(frame.set_return_values(&results) + 1)
+        },
+        Err(error) => Ok(frame.set_return_values(&[TValue::TBOOLEAN(false), error.value])),
+    }
+}
+
+/**
+ * Results of 'load': the function or nil plus the message
+ */
+fn load_aux(frame: &mut LuaStackView, result: Result<TValue, String>, env: Option<TValue>) -> Result<usize, LuaError> {
+    match result {
+        Ok(function) => {
+            if let (Some(env), TValue::CLOSURE(closure)) = (env, &function) {
+                if let Closure::Lua(lua_closure) = closure.as_ref() {
+                    if let Some(upvalue) = lua_closure.upvalues.borrow().first() {
+                        *upvalue.borrow_mut() = UpVal::Closed(env);
+                    }
+                }
+            }
+            Ok(frame.set_return_values(&[function]))
+        },
+        Err(message) => Ok(frame.set_return_values(&[TValue::NIL, TValue::from_str(&message)])),
+    }
+}
+
+fn load(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let mode = frame.opt_string(3, "bt")?;
+    let env = if frame.arg_count() >= 4 { Some(frame.get_arg(4).clone()) } else { None };
+    let (chunk, chunk_name) = match frame.get_arg(1) {
+        TValue::STR(_) | TValue::NUMINT(_) | TValue::NUMFLT(_) => {
+            let chunk = frame.check_string(1)?;
+            let chunk_name = frame.opt_string(2, &chunk.to_str_lossy())?;
+            (chunk.as_bytes().to_vec(), chunk_name)
+        },
+        _ => {
+            let chunk_name = frame.opt_string(2, "=(load)")?;
+            let reader = TValue::CLOSURE(frame.check_function(1)?);
+            let mut chunk = Vec::new();
+            loop {
+                let piece = frame.call(reader.clone(), &[])?.into_iter().next().unwrap_or(TValue::NIL);
+                if piece.is_nil() {
+                    break;
+                }
+                match crate::vm::coerce_to_string(&piece) {
+                    Some(s) if s.is_empty() => break,
+                    Some(s) => chunk.extend_from_slice(s.as_bytes()),
+                    None => return load_aux(frame, Err("reader function must return a string".to_string()), None),
+                }
+            }
+            (chunk, chunk_name)
+        }
+    };
+    let result = frame.vm.load_chunk(&chunk, &chunk_name.to_str_lossy(), &mode.to_str_lossy());
+    load_aux(frame, result, env)
+}
+
+/**
+ * luaL_loadfilex: the chunk in a file, or in stdin without a name, skipping a byte order mark
+ * and an initial comment line like '#!/usr/bin/lua'
+ */
+fn load_file(frame: &mut LuaStackView, file_name: Option<Rc<LuaString>>, mode: &str) -> Result<TValue, String> {
+    let (contents, chunk_name) = match &file_name {
+        Some(name) => match fs::read(name.to_str_lossy().as_ref()) {
+            Ok(contents) => (contents, format!("@{}", name)),
+            Err(e) => return Err(format!("cannot open {}: {}", name, os_error_message(&e))),
+        },
+        None => {
+            let mut contents = Vec::new();
+            if let Err(e) = io::stdin().read_to_end(&mut contents) {
+                return Err(format!("cannot read stdin: {}", os_error_message(&e)));
+            }
+            (contents, "=stdin".to_string())
+        }
+    };
+    let contents = compiler::lexer::skip_comment(&contents);
+    frame.vm.load_chunk(contents, &chunk_name, mode)
+}
+
+fn loadfile(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let file_name = if frame.get_arg(1).is_nil() { None } else { Some(frame.check_string(1)?) };
+    let mode = frame.opt_string(2, "bt")?;
+    let env = if frame.arg_count() >= 3 { Some(frame.get_arg(3).clone()) } else { None };
+    let result = load_file(frame, file_name, &mode.to_str_lossy());
+    load_aux(frame, result, env)
+}
+
+fn dofile(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let file_name = if frame.get_arg(1).is_nil() { None } else { Some(frame.check_string(1)?) };
+    let function = load_file(frame, file_name, "bt").map_err(|message| LuaError::new(&message))?;
+    let results = frame.call(function, &[])?;
+    Ok(frame.set_return_values(&results))
+}
+
+/**
+ * Values reported by 'collectgarbage': memory is reference counted, so there is no collector to tune
+ */
+struct GcState {
+    running: bool,
+    generational: bool,
+    pause: i64,
+    step_multiplier: i64,
+}
+
+impl Default for GcState {
+    fn default() -> Self {
+        Self { running: true, generational: false, pause: 200, step_multiplier: 100 }
+    }
+}
+
+fn collect_garbage_with(frame: &mut LuaStackView, state: &mut GcState) -> Result<usize, LuaError> {
+    let option = frame.opt_string(1, "collect")?;
+    let result = match option.to_str_lossy().as_ref() {
+        "stop" | "restart" => {
+            state.running = option.as_bytes() == b"restart";
+            TValue::NUMINT(0)
+        },
+        "collect" => TValue::NUMINT(0),
+        "count" => TValue::NUMFLT(0.0),
+        "step" => TValue::TBOOLEAN(true),
+        "setpause" => {
+            let previous = state.pause;
+            state.pause = frame.opt_integer(2, 0)?;
+            TValue::NUMINT(previous)
+        },
+        "setstepmul" => {
+            let previous = state.step_multiplier;
+            state.step_multiplier = frame.opt_integer(2, 0)?;
+            TValue::NUMINT(previous)
+        },
+        "isrunning" => TValue::TBOOLEAN(state.running),
+        "generational" | "incremental" => {
+            let previous = if state.generational { "generational" } else { "incremental" };
+            state.generational = option.as_bytes() == b"generational";
+            TValue::from_str(previous)
+        },
+        _ => return Err(frame.arg_error(1, &format!("invalid option '{}'", option))),
+    };
+    Ok(frame.set_return_values(&[result]))
+}
+
+/**
+ * Warning system of lauxlib: off by default, controlled by "@on" and "@off"
+ */
+#[derive(Default)]
+struct WarnState {
+    on: bool,
+}
+
+fn warn_with(frame: &mut LuaStackView, state: &mut WarnState) -> Result<usize, LuaError> {
+    frame.check_string(1)?;
+    let mut pieces = Vec::new();
+    for i in 1..=frame.arg_count() {
+        pieces.push(frame.check_string(i)?);
+    }
+    if pieces.len() == 1 && pieces[0].starts_with(b"@") {
+        match pieces[0].as_bytes() {
+            b"@on" => state.on = true,
+            b"@off" => state.on = false,
+            _ => {},
+        }
+        return Ok(0);
+    }
+    if state.on {
+        let mut stderr = io::stderr();
+        let message: Vec<u8> = pieces.iter().flat_map(|piece| piece.iter().copied()).collect();
+        let _ = writeln!(stderr, "Lua warning: {}", String::from_utf8_lossy(&message));
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::str_to_int_base;
+    use crate::{core::types::{TValue, LuaThread}, stdlib::{open_libs, test::run_chunk}, vm::LuaVm};
+
+    #[test]
+    fn integers_in_any_base() {
+        assert_eq!(str_to_int_base(b"ff", 16), Some(255));
+        assert_eq!(str_to_int_base(b"  -zz  ", 36), Some(-1295));
+        assert_eq!(str_to_int_base(b"102", 2), None);
+        assert_eq!(str_to_int_base(b"", 10), None);
+    }
+
+    #[test]
+    fn base_library_matches_reference() {
+        // helpers/tests/base.lua, expected output produced by the reference interpreter
+        let output = run_chunk(include_bytes!("../../helpers/tests/base.out"));
+        assert_eq!(output, include_str!("../../helpers/tests/base.expected"));
+    }
+
+    #[test]
+    fn load_files() {
+        let path = std::env::temp_dir().join(format!("rustymoon_loadfile_{}.lua", std::process::id()));
+        fs::write(&path, "#!/usr/bin/lua\nreturn x, ...").unwrap();
+        let mut vm = LuaVm::new();
+        open_libs(&mut vm);
+        let source = b"local path = ...
+            local f = assert(loadfile(path, 't', {x = 5}))
+            local x, y = f(7)
+            local binary, message = loadfile(path, 'b')
+            local missing, error = loadfile(path .. '.missing')
+            return string.format('%s %s %s %s|%s %s', x, y, binary, message, missing, error)";
+        let main = vm.load_chunk(source, "=test", "t").unwrap();
+        let results = vm.protected_call(&mut LuaThread::new(), main, &[TValue::from_str(&path.to_string_lossy())]);
+        fs::remove_file(&path).unwrap();
+        let expected = format!("5 7 nil attempt to load a text chunk (mode is 'b')|nil cannot open {}.missing: No such file or directory", path.to_string_lossy());
+        let results = results.unwrap();
+        assert!(results[0].raw_equals(&TValue::from_str(&expected)), "{}", results[0]);
+    }
+}
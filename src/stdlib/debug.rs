@@ -0,0 +1,407 @@
+/*
+ * Debug library (ldblib.c), over the debug interface of the VM. There are no coroutines, so
+ * the optional thread argument of the C library is not supported
+ */
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{core::types::{TValue, LuaError, Closure, UpValRef, stack::LuaStackView, table::LuaTable}, vm::{LuaVm, debug::{self, Hook, MASK_CALL, MASK_RET, MASK_LINE, MASK_COUNT}}};
+
+pub fn open(vm: &mut LuaVm) {
+    super::new_lib(vm, "debug", &[
+        ("gethook", get_hook),
+        ("getinfo", get_info),
+        ("getlocal", get_local),
+        ("getmetatable", get_metatable),
+        ("getupvalue", get_upvalue),
+        ("getuservalue", get_user_value),
+        ("sethook", set_hook),
+        ("setlocal", set_local),
+        ("setmetatable", set_metatable),
+        ("setupvalue", set_upvalue),
+        ("setuservalue", set_user_value),
+        ("traceback", traceback),
+        ("upvalueid", upvalue_id),
+        ("upvaluejoin", upvalue_join),
+    ]);
+}
+
+/**
+ * getmetatable without '__metatable' protection
+ */
+fn get_metatable(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let value = frame.check_any(1)?.clone();
+    let result = frame.vm.get_metatable(&value).map_or(TValue::NIL, TValue::TABLE);
+    Ok(frame.set_return_values(&[result]))
+}
+
+/**
+ * setmetatable for values of any type, returns the value
+ */
+fn set_metatable(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let metatable = match frame.get_arg(2) {
+        TValue::NIL => None,
+        TValue::TABLE(metatable) => Some(metatable.clone()),
+        _ => return Err(frame.type_error(2, "nil or table")),
+    };
+    let value = frame.get_arg(1).clone();
+    frame.vm.set_metatable(&value, metatable);
+    Ok(frame.set_return_values(&[value]))
+}
+
+/**
+ * getuservalue(u [, n]): the n-th user value and true, fail when there is no such value
+ */
+fn get_user_value(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let n = frame.opt_integer(2, 1)?;
+    let userdata = match frame.get_arg(1) {
+        TValue::USERDATA(userdata) => userdata.clone(),
+        _ => return Ok(frame.set_return_values(&[TValue::NIL])),
+    };
+    let value = match usize::try_from(n) {
+        Ok(n) if n >= 1 => userdata.user_values.borrow().get(n - 1).cloned(),
+        _ => None,
+    };
+    match value {
+        Some(value) => Ok(frame.set_return_values(&[value, TValue::TBOOLEAN(true)])),
+        None => Ok(frame.set_return_values(&[TValue::NIL])),
+    }
+}
+
+/**
+ * setuservalue(u, value [, n]): returns 'u', fail when it has no n-th user value
+ */
+fn set_user_value(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let n = frame.opt_integer(3, 1)?;
+    let userdata = match frame.get_arg(1) {
+        TValue::USERDATA(userdata) => userdata.clone(),
+        _ => return Err(frame.type_error(1, "userdata")),
+    };
+    let value = frame.check_any(2)?.clone();
+    let mut user_values = userdata.user_values.borrow_mut();
+    match usize::try_from(n) {
+        Ok(n) if n >= 1 && n <= user_values.len() => {
+            user_values[n - 1] = value;
+            drop(user_values);
+            Ok(frame.set_return_values(&[TValue::USERDATA(userdata)]))
+        },
+        _ => Ok(frame.set_return_values(&[TValue::NIL])),
+    }
+}
+
+/**
+ * getinfo(f | level [, what]): table with the fields selected by the option letters of 'what'
+ */
+fn get_info(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let options = frame.opt_string(2, "flnSrtu")?;
+    if options.first() == Some(&b'>') {
+        return Err(frame.arg_error(2, "invalid option '>'"));
+    }
+    let (function, level) = match frame.get_arg(1) {
+        TValue::CLOSURE(closure) => (closure.clone(), None),
+        _ => {
+            let level = frame.check_integer(1)?;
+            let call_info = usize::try_from(level).ok().and_then(|level| frame.thread.current_call.iter().nth(level));
+            match call_info {
+                Some(call_info) => (call_info.get_closure(&frame.thread.stack), Some(level as usize)),
+                // level out of range
+                None => return Ok(frame.set_return_values(&[TValue::NIL])),
+            }
+        },
+    };
+    if !options.iter().all(|c| b"SltunrLf".contains(c)) {
+        return Err(frame.arg_error(2, "invalid option"));
+    }
+    let info = frame.vm.get_info(frame.thread, &function, level);
+    let mut table = LuaTable::new();
+    let has = |option: u8| options.contains(&option);
+    if has(b'S') {
+        table.set_str("source", TValue::STR(info.source.clone()));
+        table.set_str("short_src", TValue::from_str(&info.short_src));
+        table.set_str("linedefined", TValue::NUMINT(info.line_defined));
+        table.set_str("lastlinedefined", TValue::NUMINT(info.last_line_defined));
+        table.set_str("what", TValue::from_str(info.what));
+    }
+    if has(b'l') {
+        table.set_str("currentline", TValue::NUMINT(info.current_line));
+    }
+    if has(b'u') {
+        table.set_str("nups", TValue::NUMINT(info.nups as i64));
+        table.set_str("nparams", TValue::NUMINT(info.nparams as i64));
+        table.set_str("isvararg", TValue::TBOOLEAN(info.is_vararg));
+    }
+    if has(b'n') {
+        if let Some(name) = &info.name {
+            table.set_str("name", TValue::from_str(name));
+        }
+        table.set_str("namewhat", TValue::from_str(info.namewhat));
+    }
+    if has(b'r') {
+        table.set_str("ftransfer", TValue::NUMINT(info.transfer.0 as i64));
+        table.set_str("ntransfer", TValue::NUMINT(info.transfer.1 as i64));
+    }
+    if has(b't') {
+        table.set_str("istailcall", TValue::TBOOLEAN(info.is_tail_call));
+    }
+    if has(b'L') {
+        if let Some(lines) = debug::active_lines(&function) {
+            let mut active = LuaTable::new();
+            for line in lines {
+                active.set_int(line, TValue::TBOOLEAN(true));
+            }
+            table.set_str("activelines", TValue::TABLE(Rc::new(RefCell::new(active))));
+        }
+    }
+    if has(b'f') {
+        table.set_str("func", TValue::CLOSURE(function));
+    }
+    Ok(frame.set_return_values(&[TValue::TABLE(Rc::new(RefCell::new(table)))]))
+}
+
+/**
+ * Stack level argument of getlocal/setlocal, 'level out of range' when there is no such level
+ */
+fn check_level(frame: &LuaStackView, arg: usize) -> Result<usize, LuaError> {
+    let level = frame.check_integer(arg)?;
+    match usize::try_from(level) {
+        Ok(level) if level < frame.thread.current_call.len() => Ok(level),
+        _ => Err(frame.arg_error(arg, "level out of range")),
+    }
+}
+
+/**
+ * getlocal(f | level, n): name and value of a local variable, only parameter names for
+ * functions that are not active
+ */
+fn get_local(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let n = frame.check_integer(2)?;
+    if let TValue::CLOSURE(closure) = frame.get_arg(1) {
+        let name = match closure.as_ref() {
+            Closure::Lua(lua_closure) if n > 0 => debug::get_local_name(&lua_closure.proto, n as usize, 0),
+            _ => None,
+        };
+        let name = name.map_or(TValue::NIL, |name| TValue::from_str(&name));
+        return Ok(frame.set_return_values(&[name]));
+    }
+    let level = check_level(frame, 1)?;
+    match frame.vm.find_local(frame.thread, level, n) {
+        Some((name, index)) => {
+            let value = frame.thread.stack.get_at_offset(index).clone();
+            Ok(frame.set_return_values(&[TValue::from_str(&name), value]))
+        },
+        None => Ok(frame.set_return_values(&[TValue::NIL])),
+    }
+}
+
+/**
+ * setlocal(level, n, value): the name of the assigned local, fail when there is none
+ */
+fn set_local(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    frame.check_integer(1)?;
+    let n = frame.check_integer(2)?;
+    let level = check_level(frame, 1)?;
+    let value = frame.check_any(3)?.clone();
+    match frame.vm.find_local(frame.thread, level, n) {
+        Some((name, index)) => {
+            frame.thread.stack.set_at_offset(value, index);
+            Ok(frame.set_return_values(&[TValue::from_str(&name)]))
+        },
+        None => Ok(frame.set_return_values(&[TValue::NIL])),
+    }
+}
+
+/**
+ * auxupvalue: name of the n-th upvalue of a function, "" for native ones and "(no name)"
+ * for stripped Lua functions
+ */
+fn upvalue_name(closure: &Closure, n: i64) -> Option<String> {
+    let index = usize::try_from(n).ok()?.checked_sub(1)?;
+    match closure {
+        Closure::C(c_closure) => (index < c_closure.upvalues.borrow().len()).then(String::new),
+        Closure::Lua(lua_closure) => {
+            let description = lua_closure.proto.upvalues.get(index)?;
+            Some(description.name.as_ref().map_or("(no name)".to_string(), |name| name.to_string()))
+        },
+    }
+}
+
+fn get_upvalue(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let n = frame.check_integer(2)?;
+    let closure = frame.check_function(1)?;
+    let name = match upvalue_name(&closure, n) {
+        Some(name) => name,
+        None => return Ok(0),
+    };
+    let index = n as usize - 1;
+    let value = match closure.as_ref() {
+        Closure::C(c_closure) => c_closure.upvalues.borrow()[index].clone(),
+        Closure::Lua(lua_closure) => lua_closure.upvalue(index).borrow().get_value(&frame.thread.stack).clone(),
+    };
+    Ok(frame.set_return_values(&[TValue::from_str(&name), value]))
+}
+
+fn set_upvalue(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let value = frame.check_any(3)?.clone();
+    let n = frame.check_integer(2)?;
+    let closure = frame.check_function(1)?;
+    let name = match upvalue_name(&closure, n) {
+        Some(name) => name,
+        None => return Ok(0),
+    };
+    let index = n as usize - 1;
+    match closure.as_ref() {
+        Closure::C(c_closure) => c_closure.upvalues.borrow_mut()[index] = value,
+        Closure::Lua(lua_closure) => lua_closure.upvalue(index).borrow_mut().set_value(&mut frame.thread.stack, value),
+    }
+    Ok(frame.set_return_values(&[TValue::from_str(&name)]))
+}
+
+/**
+ * checkupval: the function at 'arg_function' and its upvalue at 'arg_index'; 'id' is the
+ * identity of the upvalue, shared by every closure that shares it
+ */
+fn check_upvalue(frame: &LuaStackView, arg_function: usize, arg_index: usize) -> Result<(Rc<Closure>, i64, Option<*const u8>), LuaError> {
+    let n = frame.check_integer(arg_index)?;
+    let closure = frame.check_function(arg_function)?;
+    let id = usize::try_from(n).ok().and_then(|n| n.checked_sub(1)).and_then(|index| match closure.as_ref() {
+        Closure::Lua(lua_closure) => lua_closure.upvalues.borrow().get(index).map(|upvalue| Rc::as_ptr(upvalue) as *const u8),
+        Closure::C(c_closure) => {
+            let upvalues = c_closure.upvalues.borrow();
+            (index < upvalues.len()).then(|| upvalues[index..].as_ptr() as *const u8)
+        },
+    });
+    Ok((closure, n, id))
+}
+
+fn upvalue_id(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let (_, _, id) = check_upvalue(frame, 1, 2)?;
+    let result = id.map_or(TValue::NIL, TValue::LIGHTUSERDATA);
+    Ok(frame.set_return_values(&[result]))
+}
+
+/**
+ * upvaluejoin(f1, n1, f2, n2): the n1-th upvalue of f1 becomes the n2-th upvalue of f2
+ */
+fn upvalue_join(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let (f1, n1, id1) = check_upvalue(frame, 1, 2)?;
+    if id1.is_none() {
+        return Err(frame.arg_error(2, "invalid upvalue index"));
+    }
+    let (f2, n2, id2) = check_upvalue(frame, 3, 4)?;
+    if id2.is_none() {
+        return Err(frame.arg_error(4, "invalid upvalue index"));
+    }
+    let target = lua_upvalues(frame, &f1, 1)?;
+    let source = lua_upvalues(frame, &f2, 3)?;
+    let shared = source.borrow()[n2 as usize - 1].clone();
+    target.borrow_mut()[n1 as usize - 1] = shared;
+    Ok(0)
+}
+
+fn lua_upvalues<'a>(frame: &LuaStackView, closure: &'a Closure, arg: usize) -> Result<&'a RefCell<Vec<UpValRef>>, LuaError> {
+    match closure {
+        Closure::Lua(lua_closure) => Ok(&lua_closure.upvalues),
+        Closure::C(_) => Err(frame.arg_error(arg, "Lua function expected")),
+    }
+}
+
+/**
+ * sethook([f, mask [, count]]): 'mask' has 'c', 'r' and 'l' for call, return and line events,
+ * a positive count adds count events. No arguments turn hooks off
+ */
+fn set_hook(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    if frame.get_arg(1).is_nil() {
+        frame.thread.hook = None;
+        return Ok(0);
+    }
+    let mask = frame.check_string(2)?;
+    frame.check_function(1)?;
+    let count = frame.opt_integer(3, 0)?;
+    let mut bits = 0;
+    for (c, bit) in [(b'c', MASK_CALL), (b'r', MASK_RET), (b'l', MASK_LINE)] {
+        if mask.contains(&c) {
+            bits |= bit;
+        }
+    }
+    // C ints: larger counts wrap around
+    let count = count as i32;
+    if count > 0 {
+        bits |= MASK_COUNT;
+    }
+    let count = count.max(0) as u32;
+    frame.thread.hook = if bits == 0 {
+        None
+    } else {
+        Some(Hook { function: frame.get_arg(1).clone(), mask: bits, count })
+    };
+    frame.thread.hook_count = count;
+    Ok(0)
+}
+
+/**
+ * gethook(): the hook function, its mask and its count, fail when there is no hook
+ */
+fn get_hook(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let hook = match &frame.thread.hook {
+        Some(hook) => hook.clone(),
+        None => return Ok(frame.set_return_values(&[TValue::NIL])),
+    };
+    let mut mask = String::new();
+    for (c, bit) in [('c', MASK_CALL), ('r', MASK_RET), ('l', MASK_LINE)] {
+        if hook.mask & bit != 0 {
+            mask.push(c);
+        }
+    }
+    Ok(frame.set_return_values(&[hook.function, TValue::from_str(&mask), TValue::NUMINT(hook.count as i64)]))
+}
+
+/**
+ * traceback([message [, level]]): non string messages are returned untouched
+ */
+fn traceback(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let message = match frame.get_arg(1) {
+        TValue::STR(_) | TValue::NUMINT(_) | TValue::NUMFLT(_) => Some(frame.check_string(1)?),
+        TValue::NIL | TValue::EMPTY => None,
+        other => {
+            let other = other.clone();
+            return Ok(frame.set_return_values(&[other]));
+        },
+    };
+    let level = frame.opt_integer(2, 1)?;
+    let level = usize::try_from(level).unwrap_or(usize::MAX);
+    let result = frame.vm.traceback(frame.thread, message.as_ref().map(|m| m.as_bytes()), level);
+    Ok(frame.set_return_values(&[TValue::from_bytes(&result)]))
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::{core::types::{TValue, LuaThread, userdata::Userdata}, stdlib::test::run_chunk, vm::LuaVm};
+
+    #[test]
+    fn debug_library_matches_reference() {
+        // helpers/tests/debug.lua, with hooks, varargs and shared upvalues
+        let output = run_chunk(include_bytes!("../../helpers/tests/debug.out"));
+        assert_eq!(output, include_str!("../../helpers/tests/debug.expected"));
+    }
+
+    #[test]
+    fn user_values_are_numbered_from_one() {
+        let mut vm = LuaVm::new();
+        crate::stdlib::open_libs(&mut vm);
+        let mut thread = LuaThread::new();
+        let debug = vm.globals.borrow().get_str("debug");
+        let field = |name: &str| match &debug { TValue::TABLE(debug) => debug.borrow().get_str(name), _ => TValue::NIL };
+        let userdata = TValue::USERDATA(Rc::new(Userdata::new(0_u8, 2)));
+        let set = vm.call_value(&mut thread, field("setuservalue"), &[userdata.clone(), TValue::NUMINT(7), TValue::NUMINT(2)]).unwrap();
+        assert!(set[0].raw_equals(&userdata));
+        let get = vm.call_value(&mut thread, field("getuservalue"), &[userdata.clone(), TValue::NUMINT(2)]).unwrap();
+        assert!(get[0].raw_equals(&TValue::NUMINT(7)) && get[1].raw_equals(&TValue::TBOOLEAN(true)));
+        let missing = vm.call_value(&mut thread, field("getuservalue"), &[userdata.clone(), TValue::NUMINT(3)]).unwrap();
+        assert_eq!(missing.len(), 1);
+        assert!(missing[0].is_nil());
+        let failed = vm.call_value(&mut thread, field("setuservalue"), &[userdata, TValue::NIL, TValue::NUMINT(0)]).unwrap();
+        assert!(failed[0].is_nil());
+    }
+}
@@ -0,0 +1,926 @@
+/*
+ * Input/output library (liolib.c). File handles are userdata wrapping a LuaFile; the default
+ * input and output files are kept in the state shared by the library functions
+ */
+
+use std::{cell::RefCell, ffi::OsString, fs::{self, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, process::{Child, Command, ExitStatus, Stdio}, rc::Rc};
+
+use crate::{core::types::{TValue, LuaError, Closure, number::{fmt_g, str_to_number, F2IMode}, table::{LuaTable, TableRef}, userdata::Userdata, stack::LuaStackView}, vm::LuaVm};
+
+use super::{exec_result, file_result, os_error_message, os::OsPermissions, temp_file};
+
+/* LUAL_BUFFERSIZE: size of the chunks read from streams */
+const BUFFER_SIZE: usize = 8192;
+
+/* maximum number of arguments to 'f:lines'/'io.lines' */
+const MAXARGLINE: usize = 250;
+
+/* maximum length of a numeral read with the "n" format */
+const L_MAXLENNUM: usize = 200;
+
+/* name of file handles in error messages and in their '__name' */
+const FILE_HANDLE: &str = "FILE*";
+
+enum Stream {
+    File(fs::File),
+    Stdin,
+    /* goes through the VM output, like 'print' */
+    Stdout,
+    Stderr,
+    /* process started by 'io.popen', connected to its stdout or its stdin */
+    Pipe(Child),
+}
+
+/**
+ * How a handle is closed (the 'closef' of luaL_Stream)
+ */
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Closer {
+    /* standard files are never closed */
+    Standard,
+    File,
+    Pipe,
+}
+
+/**
+ * An open (or closed) file. Reads are buffered so characters can be pushed back while parsing
+ * numbers; writes go straight to the stream, so nothing is lost when a handle is never closed
+ */
+pub struct LuaFile {
+    stream: Option<Stream>,
+    closer: Closer,
+    readable: bool,
+    writable: bool,
+    input: Vec<u8>,
+    input_position: usize,
+}
+
+fn bad_descriptor() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
+impl LuaFile {
+    fn new(stream: Stream, closer: Closer, readable: bool, writable: bool) -> Self {
+        Self { stream: Some(stream), closer, readable, writable, input: Vec::new(), input_position: 0 }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    fn raw_read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.stream.as_mut() {
+            Some(Stream::File(file)) => file.read(buffer),
+            Some(Stream::Stdin) => io::stdin().read(buffer),
+            Some(Stream::Pipe(child)) => match child.stdout.as_mut() {
+                Some(stdout) => stdout.read(buffer),
+                None => Err(bad_descriptor()),
+            },
+            _ => Err(bad_descriptor()),
+        }
+    }
+
+    /**
+     * Refills the read buffer when it is exhausted, false at the end of the stream
+     */
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.input_position < self.input.len() {
+            return Ok(true);
+        }
+        if !self.readable {
+            return Err(bad_descriptor());
+        }
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let count = self.raw_read(&mut buffer)?;
+        buffer.truncate(count);
+        self.input = buffer;
+        self.input_position = 0;
+        Ok(count > 0)
+    }
+
+    fn getc(&mut self) -> io::Result<Option<u8>> {
+        if !self.fill()? {
+            return Ok(None);
+        }
+        let c = self.input[self.input_position];
+        self.input_position += 1;
+        Ok(Some(c))
+    }
+
+    /* pushes back the character just read, nothing at the end of the stream */
+    fn ungetc(&mut self, c: Option<u8>) {
+        if c.is_some() {
+            self.input_position -= 1;
+        }
+    }
+
+    /**
+     * Up to 'n' bytes, fewer at the end of the stream
+     */
+    fn read_bytes(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let mut result = Vec::new();
+        while result.len() < n && self.fill()? {
+            let available = &self.input[self.input_position..];
+            let count = available.len().min(n - result.len());
+            result.extend_from_slice(&available[..count]);
+            self.input_position += count;
+        }
+        Ok(result)
+    }
+
+    /**
+     * Bytes up to the end of line (not included) and whether the line ended with a newline
+     */
+    fn read_line(&mut self) -> io::Result<(Vec<u8>, bool)> {
+        let mut result = Vec::new();
+        while self.fill()? {
+            let available = &self.input[self.input_position..];
+            if let Some(end) = available.iter().position(|&c| c == b'\n') {
+                result.extend_from_slice(&available[..end]);
+                self.input_position += end + 1;
+                return Ok((result, true));
+            }
+            result.extend_from_slice(available);
+            self.input_position = self.input.len();
+        }
+        Ok((result, false))
+    }
+
+    /**
+     * Bytes read ahead but not consumed are given back to the stream before writing or seeking
+     */
+    fn discard_input(&mut self) -> io::Result<()> {
+        let unread = self.input.len() - self.input_position;
+        if unread > 0 {
+            if let Some(Stream::File(file)) = self.stream.as_mut() {
+                file.seek(SeekFrom::Current(-(unread as i64)))?;
+            }
+        }
+        self.input.clear();
+        self.input_position = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, stdout: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+        if !self.writable {
+            return Err(bad_descriptor());
+        }
+        self.discard_input()?;
+        match self.stream.as_mut() {
+            Some(Stream::File(file)) => file.write_all(bytes),
+            Some(Stream::Stdout) => stdout.write_all(bytes),
+            Some(Stream::Stderr) => io::stderr().write_all(bytes),
+            Some(Stream::Pipe(child)) => match child.stdin.as_mut() {
+                Some(stdin) => stdin.write_all(bytes),
+                None => Err(bad_descriptor()),
+            },
+            _ => Err(bad_descriptor()),
+        }
+    }
+
+    fn flush(&mut self, stdout: &mut dyn Write) -> io::Result<()> {
+        match self.stream.as_mut() {
+            Some(Stream::File(file)) => file.flush(),
+            Some(Stream::Stdout) => stdout.flush(),
+            Some(Stream::Pipe(child)) => child.stdin.as_mut().map_or(Ok(()), |stdin| stdin.flush()),
+            _ => Ok(()),
+        }
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let unread = (self.input.len() - self.input_position) as i64;
+        let position = match position {
+            // the OS position is ahead of the Lua position by the unread bytes
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - unread),
+            other => other,
+        };
+        let result = match self.stream.as_mut() {
+            Some(Stream::File(file)) => file.seek(position)?,
+            _ => return Err(io::Error::from_raw_os_error(libc::ESPIPE)),
+        };
+        self.input.clear();
+        self.input_position = 0;
+        Ok(result)
+    }
+
+    /**
+     * Closes a pipe, waiting for the process: its raw wait status
+     */
+    fn close_pipe(&mut self) -> io::Result<i32> {
+        match self.stream.take() {
+            Some(Stream::Pipe(mut child)) => {
+                // the process may be waiting for the end of its input
+                drop(child.stdin.take());
+                drop(child.stdout.take());
+                Ok(wait_status(child.wait()?))
+            },
+            _ => Err(bad_descriptor()),
+        }
+    }
+}
+
+impl Drop for LuaFile {
+    fn drop(&mut self) {
+        if self.closer == Closer::Pipe && !self.is_closed() {
+            let _ = self.close_pipe();
+        }
+    }
+}
+
+/**
+ * State shared by the library functions: the metatable of file handles and the default files
+ */
+struct IoState {
+    metatable: TableRef,
+    input: RefCell<TValue>,
+    output: RefCell<TValue>,
+}
+
+type IoFunction = fn(&mut LuaStackView, &IoState) -> Result<usize, LuaError>;
+
+pub fn open(vm: &mut LuaVm, permissions: OsPermissions) {
+    let library = super::new_lib(vm, "io", &[]);
+    let metatable = create_meta();
+    let new_std_file = |stream: Stream, readable: bool| {
+        new_file(&metatable, LuaFile::new(stream, Closer::Standard, readable, !readable))
+    };
+    let stdin = new_std_file(Stream::Stdin, true);
+    let stdout = new_std_file(Stream::Stdout, false);
+    let stderr = new_std_file(Stream::Stderr, false);
+    let state = Rc::new(IoState { metatable, input: RefCell::new(stdin.clone()), output: RefCell::new(stdout.clone()) });
+    let functions: [(&str, IoFunction, bool); 11] = [
+        ("close", io_close, true),
+        ("flush", io_flush, true),
+        ("input", io_input, permissions.files),
+        ("lines", io_lines, permissions.files),
+        ("open", io_open, permissions.files),
+        ("output", io_output, permissions.files),
+        ("popen", io_popen, permissions.popen),
+        ("read", io_read, true),
+        ("tmpfile", io_tmpfile, permissions.files),
+        ("type", io_type, true),
+        ("write", io_write, true),
+    ];
+    let mut library = library.borrow_mut();
+    for (name, function, _) in functions.into_iter().filter(|(_, _, allowed)| *allowed) {
+        let state = state.clone();
+        let closure = Closure::new_native(move |frame| function(frame, &state), vec![]);
+        library.set_str(name, TValue::CLOSURE(Rc::new(closure)));
+    }
+    library.set_str("stdin", stdin);
+    library.set_str("stdout", stdout);
+    library.set_str("stderr", stderr);
+}
+
+/**
+ * createmeta: metatable for file handles, with the methods in its '__index'
+ */
+fn create_meta() -> TableRef {
+    let methods = LuaTable::new_ref();
+    super::register(&methods, &[
+        ("read", f_read),
+        ("write", f_write),
+        ("lines", f_lines),
+        ("flush", f_flush),
+        ("seek", f_seek),
+        ("close", f_close),
+        ("setvbuf", f_setvbuf),
+    ]);
+    let metatable = LuaTable::new_ref();
+    super::register(&metatable, &[
+        ("__gc", f_gc),
+        ("__close", f_gc),
+        ("__tostring", f_tostring),
+    ]);
+    let mut meta = metatable.borrow_mut();
+    meta.set_str("__index", TValue::TABLE(methods));
+    meta.set_str("__name", TValue::from_str(FILE_HANDLE));
+    drop(meta);
+    metatable
+}
+
+fn new_file(metatable: &TableRef, file: LuaFile) -> TValue {
+    let userdata = Userdata::new(file, 0);
+    *userdata.metatable.borrow_mut() = Some(metatable.clone());
+    TValue::USERDATA(Rc::new(userdata))
+}
+
+fn to_lstream(frame: &LuaStackView, arg: usize) -> Result<Rc<Userdata>, LuaError> {
+    frame.check_userdata::<LuaFile>(arg, FILE_HANDLE)
+}
+
+/**
+ * tofile: an open file handle
+ */
+fn to_file(frame: &LuaStackView, arg: usize) -> Result<Rc<Userdata>, LuaError> {
+    let userdata = to_lstream(frame, arg)?;
+    if userdata.borrow::<LuaFile>().unwrap().is_closed() {
+        return Err(frame.error("attempt to use a closed file"));
+    }
+    Ok(userdata)
+}
+
+fn io_type(frame: &mut LuaStackView, _: &IoState) -> Result<usize, LuaError> {
+    frame.check_any(1)?;
+    let result = match frame.get_arg(1) {
+        TValue::USERDATA(userdata) => match userdata.borrow::<LuaFile>() {
+            Some(file) if file.is_closed() => TValue::from_str("closed file"),
+            Some(_) => TValue::from_str("file"),
+            None => TValue::NIL,
+        },
+        _ => TValue::NIL,
+    };
+    Ok(frame.set_return_values(&[result]))
+}
+
+fn f_tostring(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let userdata = to_lstream(frame, 1)?;
+    let text = if userdata.borrow::<LuaFile>().unwrap().is_closed() {
+        "file (closed)".to_string()
+    } else {
+        format!("file ({:p})", Rc::as_ptr(&userdata))
+    };
+    Ok(frame.set_return_values(&[TValue::from_str(&text)]))
+}
+
+/**
+ * aux_close: closes the handle with its own closing function
+ */
+fn aux_close(frame: &mut LuaStackView, userdata: &Userdata) -> usize {
+    let mut file = userdata.borrow_mut::<LuaFile>().unwrap();
+    match file.closer {
+        Closer::Standard => {
+            drop(file);
+            frame.set_return_values(&[TValue::NIL, TValue::from_str("cannot close standard file")])
+        },
+        Closer::File => {
+            let result = file.flush(&mut frame.vm.stdout);
+            file.stream = None;
+            drop(file);
+            file_result(frame, result, None)
+        },
+        Closer::Pipe => {
+            let result = file.close_pipe();
+            drop(file);
+            match result {
+                Ok(status) => exec_result(frame, status),
+                Err(error) => file_result(frame, Err(error), None),
+            }
+        },
+    }
+}
+
+fn f_close(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let userdata = to_file(frame, 1)?;
+    Ok(aux_close(frame, &userdata))
+}
+
+fn io_close(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    if frame.arg_count() == 0 {
+        // use default output
+        let output = state.output.borrow().clone();
+        let userdata = match output {
+            TValue::USERDATA(userdata) => userdata,
+            _ => unreachable!("default output is always a file"),
+        };
+        if userdata.borrow::<LuaFile>().unwrap().is_closed() {
+            return Err(frame.error("attempt to use a closed file"));
+        }
+        return Ok(aux_close(frame, &userdata));
+    }
+    f_close(frame)
+}
+
+/**
+ * f_gc: also '__close', ignoring closed files
+ */
+fn f_gc(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let userdata = to_lstream(frame, 1)?;
+    let closed = userdata.borrow::<LuaFile>().unwrap().is_closed();
+    if !closed {
+        aux_close(frame, &userdata);
+    }
+    Ok(0)
+}
+
+/**
+ * l_checkmode: whether 'mode' matches '[rwa]%+?b*'
+ */
+fn check_mode(mode: &[u8]) -> bool {
+    let rest = match mode.split_first() {
+        Some((c, rest)) if b"rwa".contains(c) => rest,
+        _ => return false,
+    };
+    let rest = rest.strip_prefix(b"+").unwrap_or(rest);
+    rest.iter().all(|&c| c == b'b')
+}
+
+/**
+ * fopen: a file handle for a mode accepted by check_mode
+ */
+fn fopen(filename: &[u8], mode: &[u8]) -> io::Result<LuaFile> {
+    let update = mode.contains(&b'+');
+    let mut options = OpenOptions::new();
+    match mode[0] {
+        b'r' => options.read(true).write(update),
+        b'w' => options.write(true).create(true).truncate(true).read(update),
+        _ => options.append(true).create(true).read(update),
+    };
+    let file = options.open(os_path(filename))?;
+    let readable = mode[0] == b'r' || update;
+    let writable = mode[0] != b'r' || update;
+    Ok(LuaFile::new(Stream::File(file), Closer::File, readable, writable))
+}
+
+/* file names and commands are passed to the OS as they are, up to the first zero like C would */
+#[cfg(unix)]
+fn os_path(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    std::ffi::OsStr::from_bytes(&bytes[..end]).to_owned()
+}
+
+/* Windows names are UTF-16, so the bytes are read as UTF-8 */
+#[cfg(windows)]
+fn os_path(bytes: &[u8]) -> OsString {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    OsString::from(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+/* the raw wait status of a process, as pclose returns it */
+#[cfg(unix)]
+fn wait_status(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status.into_raw()
+}
+
+/* processes on Windows always end with an exit code */
+#[cfg(windows)]
+fn wait_status(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(libc::EXIT_FAILURE)
+}
+
+/* the command line a shell runs, as popen and system start it */
+#[cfg(unix)]
+fn shell(program: &[u8]) -> Command {
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(os_path(program));
+    command
+}
+
+#[cfg(windows)]
+fn shell(program: &[u8]) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(os_path(program));
+    command
+}
+
+/**
+ * opencheck: opens a file or raises an error
+ */
+fn open_check(frame: &LuaStackView, state: &IoState, filename: &[u8], mode: &[u8]) -> Result<TValue, LuaError> {
+    match fopen(filename, mode) {
+        Ok(file) => Ok(new_file(&state.metatable, file)),
+        Err(error) => {
+            let message = format!("cannot open file '{}' ({})", String::from_utf8_lossy(filename), os_error_message(&error));
+            Err(frame.error(&message))
+        },
+    }
+}
+
+fn io_open(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    let filename = frame.check_string(1)?;
+    let mode = frame.opt_string(2, "r")?;
+    if !check_mode(&mode) {
+        return Err(frame.arg_error(2, "invalid mode"));
+    }
+    match fopen(&filename, &mode) {
+        Ok(file) => Ok(frame.set_return_values(&[new_file(&state.metatable, file)])),
+        Err(error) => Ok(file_result(frame, Err(error), Some(&filename))),
+    }
+}
+
+fn io_popen(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    let program = frame.check_string(1)?;
+    let mode = frame.opt_string(2, "r")?;
+    let reading = match mode.as_bytes() {
+        b"r" => true,
+        b"w" => false,
+        _ => return Err(frame.arg_error(2, "invalid mode")),
+    };
+    // the process output must come after everything written so far
+    let _ = frame.vm.stdout.flush();
+    let mut command = shell(&program);
+    if reading {
+        command.stdout(Stdio::piped());
+    } else {
+        command.stdin(Stdio::piped());
+    }
+    match command.spawn() {
+        Ok(child) => {
+            let file = LuaFile::new(Stream::Pipe(child), Closer::Pipe, reading, !reading);
+            Ok(frame.set_return_values(&[new_file(&state.metatable, file)]))
+        },
+        Err(error) => Ok(file_result(frame, Err(error), Some(&program))),
+    }
+}
+
+/**
+ * tmpfile: an anonymous file opened in "w+b" mode, removed as soon as it is created, or on
+ * Windows, where open files can't be removed, once it is closed
+ */
+fn io_tmpfile(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // FILE_FLAG_DELETE_ON_CLOSE
+        options.custom_flags(0x04000000);
+    }
+    #[cfg_attr(windows, allow(unused_variables))]
+    let (path, file) = match temp_file(&options) {
+        Ok(created) => created,
+        Err(error) => return Ok(file_result(frame, Err(error), None)),
+    };
+    #[cfg(unix)]
+    let _ = fs::remove_file(&path);
+    let file = LuaFile::new(Stream::File(file), Closer::File, true, true);
+    Ok(frame.set_return_values(&[new_file(&state.metatable, file)]))
+}
+
+/**
+ * getiofile: the default input or output, which must be open
+ */
+fn get_io_file(frame: &LuaStackView, state: &IoState, output: bool) -> Result<Rc<Userdata>, LuaError> {
+    let value = if output { state.output.borrow().clone() } else { state.input.borrow().clone() };
+    let userdata = match value {
+        TValue::USERDATA(userdata) => userdata,
+        _ => unreachable!("default files are always file handles"),
+    };
+    if userdata.borrow::<LuaFile>().unwrap().is_closed() {
+        return Err(frame.error(&format!("default {} file is closed", if output { "output" } else { "input" })));
+    }
+    Ok(userdata)
+}
+
+/**
+ * g_iofile: sets the default file from a file name or a handle, returns the current one
+ */
+fn g_iofile(frame: &mut LuaStackView, state: &IoState, output: bool) -> Result<usize, LuaError> {
+    let slot = if output { &state.output } else { &state.input };
+    let file = match frame.get_arg(1) {
+        TValue::NIL | TValue::EMPTY => None,
+        TValue::STR(_) | TValue::NUMINT(_) | TValue::NUMFLT(_) => {
+            let filename = frame.check_string(1)?;
+            Some(open_check(frame, state, &filename, if output { b"w" } else { b"r" })?)
+        },
+        _ => {
+            // check that it's a valid file handle
+            to_file(frame, 1)?;
+            Some(frame.get_arg(1).clone())
+        },
+    };
+    if let Some(file) = file {
+        *slot.borrow_mut() = file;
+    }
+    let current = slot.borrow().clone();
+    Ok(frame.set_return_values(&[current]))
+}
+
+fn io_input(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    g_iofile(frame, state, false)
+}
+
+fn io_output(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    g_iofile(frame, state, true)
+}
+
+/**
+ * aux_lines: iteration function reading the file with the formats given after it
+ */
+fn aux_lines(frame: &LuaStackView, file: TValue, formats: Vec<TValue>, to_close: bool) -> Result<TValue, LuaError> {
+    if formats.len() > MAXARGLINE {
+        return Err(frame.arg_error(MAXARGLINE + 2, "too many arguments"));
+    }
+    let iterator = Closure::new_native(move |frame| io_readline(frame, &file, &formats, to_close), vec![]);
+    Ok(TValue::CLOSURE(Rc::new(iterator)))
+}
+
+fn f_lines(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    to_file(frame, 1)?;
+    let args = frame.args();
+    let iterator = aux_lines(frame, args[0].clone(), args[1..].to_vec(), false)?;
+    Ok(frame.set_return_values(&[iterator]))
+}
+
+/**
+ * io.lines: also returns the file as the to-be-closed variable of a generic for when the
+ * iterator opened it
+ */
+fn io_lines(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    let formats = frame.args().into_iter().skip(1).collect();
+    if frame.get_arg(1).is_nil() {
+        let input = state.input.borrow().clone();
+        if let TValue::USERDATA(userdata) = &input {
+            if userdata.borrow::<LuaFile>().unwrap().is_closed() {
+                return Err(frame.error("attempt to use a closed file"));
+            }
+        }
+        let iterator = aux_lines(frame, input, formats, false)?;
+        return Ok(frame.set_return_values(&[iterator]));
+    }
+    let filename = frame.check_string(1)?;
+    let file = open_check(frame, state, &filename, b"r")?;
+    let iterator = aux_lines(frame, file.clone(), formats, true)?;
+    Ok(frame.set_return_values(&[iterator, TValue::NIL, TValue::NIL, file]))
+}
+
+/*
+ * READ
+ */
+
+/**
+ * read_number: reads the longest prefix of a numeral (as C's RN helpers do) and converts it;
+ * the look-ahead character is pushed back
+ */
+fn read_number(file: &mut LuaFile) -> io::Result<Option<TValue>> {
+    struct Rn<'a> {
+        file: &'a mut LuaFile,
+        c: Option<u8>,
+        buffer: Vec<u8>,
+        overflow: bool,
+    }
+    impl Rn<'_> {
+        /* adds the current char to the buffer (if not out of space) and reads the next one */
+        fn next(&mut self) -> io::Result<bool> {
+            if self.buffer.len() >= L_MAXLENNUM {
+                self.overflow = true;
+                return Ok(false);
+            }
+            self.buffer.push(self.c.unwrap());
+            self.c = self.file.getc()?;
+            Ok(true)
+        }
+
+        /* accepts the current char if it is in 'set' */
+        fn test2(&mut self, set: &[u8; 2]) -> io::Result<bool> {
+            match self.c {
+                Some(c) if c == set[0] || c == set[1] => self.next(),
+                _ => Ok(false),
+            }
+        }
+
+        fn read_digits(&mut self, hex: bool) -> io::Result<usize> {
+            let mut count = 0;
+            while let Some(c) = self.c {
+                let is_digit = if hex { c.is_ascii_hexdigit() } else { c.is_ascii_digit() };
+                if !is_digit || !self.next()? {
+                    break;
+                }
+                count += 1;
+            }
+            Ok(count)
+        }
+    }
+
+    let mut c = file.getc()?;
+    // skip spaces
+    while c.is_some_and(|c| c.is_ascii_whitespace() || c == 0x0b) {
+        c = file.getc()?;
+    }
+    let mut rn = Rn { file, c, buffer: Vec::new(), overflow: false };
+    let mut count = 0;
+    let mut hex = false;
+    // optional sign
+    rn.test2(b"-+")?;
+    if rn.test2(b"00")? {
+        if rn.test2(b"xX")? {
+            hex = true;
+        } else {
+            // count initial '0' as a valid digit
+            count = 1;
+        }
+    }
+    count += rn.read_digits(hex)?;
+    // decimal point
+    if rn.test2(b"..")? {
+        count += rn.read_digits(hex)?;
+    }
+    if count > 0 && rn.test2(if hex { b"pP" } else { b"eE" })? {
+        // exponent sign and digits
+        rn.test2(b"-+")?;
+        rn.read_digits(false)?;
+    }
+    // unread look-ahead char
+    let c = rn.c;
+    rn.file.ungetc(c);
+    if rn.overflow {
+        return Ok(None);
+    }
+    Ok(str_to_number(&rn.buffer))
+}
+
+/**
+ * g_read: the values read with each format, the failing one replaced by nil; an error of the
+ * stream is reported as fail, message and error number
+ */
+fn g_read(frame: &mut LuaStackView, userdata: &Userdata, first: usize, formats: &[TValue]) -> Result<Result<Vec<TValue>, io::Error>, LuaError> {
+    let mut file = userdata.borrow_mut::<LuaFile>().unwrap();
+    let mut results = Vec::new();
+    let read = |file: &mut LuaFile, n: usize, format: &TValue| -> Result<io::Result<(TValue, bool)>, LuaError> {
+        let result = match format {
+            TValue::NUMINT(_) | TValue::NUMFLT(_) => {
+                let count = match format.to_integer(F2IMode::Exact) {
+                    Some(count) => count as u64 as usize,
+                    None => return Err(frame.arg_error(n, "number has no integer representation")),
+                };
+                if count == 0 {
+                    // test for the end of the stream
+                    file.getc().map(|c| {
+                        file.ungetc(c);
+                        (TValue::from_str(""), c.is_some())
+                    })
+                } else {
+                    file.read_bytes(count).map(|bytes| (TValue::from_bytes(&bytes), !bytes.is_empty()))
+                }
+            },
+            TValue::STR(s) => {
+                // skip optional '*' (for compatibility)
+                let p = s.strip_prefix(b"*").unwrap_or(s);
+                match p.first() {
+                    Some(b'n') => read_number(file).map(|number| match number {
+                        Some(number) => (number, true),
+                        None => (TValue::NIL, false),
+                    }),
+                    Some(b'l') => file.read_line().map(|(line, newline)| (TValue::from_bytes(&line), newline || !line.is_empty())),
+                    Some(b'L') => file.read_line().map(|(mut line, newline)| {
+                        if newline {
+                            line.push(b'\n');
+                        }
+                        (TValue::from_bytes(&line), !line.is_empty())
+                    }),
+                    Some(b'a') => file.read_bytes(usize::MAX).map(|bytes| (TValue::from_bytes(&bytes), true)),
+                    _ => return Err(frame.arg_error(n, "invalid format")),
+                }
+            },
+            _ => {
+                let message = format!("string expected, got {}", frame.vm.obj_type_name(format));
+                return Err(frame.arg_error(n, &message));
+            },
+        };
+        Ok(result)
+    };
+    if formats.is_empty() {
+        match read(&mut file, first, &TValue::from_str("l"))? {
+            Ok((value, success)) => results.push(if success { value } else { TValue::NIL }),
+            Err(error) => return Ok(Err(error)),
+        }
+        return Ok(Ok(results));
+    }
+    for (i, format) in formats.iter().enumerate() {
+        match read(&mut file, first + i, format)? {
+            Ok((value, true)) => results.push(value),
+            Ok((_, false)) => {
+                results.push(TValue::NIL);
+                break;
+            },
+            Err(error) => return Ok(Err(error)),
+        }
+    }
+    Ok(Ok(results))
+}
+
+fn push_read_results(frame: &mut LuaStackView, results: Result<Vec<TValue>, io::Error>) -> usize {
+    match results {
+        Ok(values) => frame.set_return_values(&values),
+        Err(error) => file_result(frame, Err(error), None),
+    }
+}
+
+fn io_read(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    let file = get_io_file(frame, state, false)?;
+    let formats = frame.args();
+    let results = g_read(frame, &file, 1, &formats)?;
+    Ok(push_read_results(frame, results))
+}
+
+fn f_read(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let file = to_file(frame, 1)?;
+    let formats = frame.args();
+    let results = g_read(frame, &file, 2, &formats[1..])?;
+    Ok(push_read_results(frame, results))
+}
+
+/**
+ * io_readline: iteration function for 'lines'
+ */
+fn io_readline(frame: &mut LuaStackView, file: &TValue, formats: &[TValue], to_close: bool) -> Result<usize, LuaError> {
+    let userdata = match file {
+        TValue::USERDATA(userdata) => userdata.clone(),
+        _ => unreachable!("lines iterates over file handles"),
+    };
+    if userdata.borrow::<LuaFile>().unwrap().is_closed() {
+        return Err(frame.error("file is already closed"));
+    }
+    match g_read(frame, &userdata, 2, formats)? {
+        // read at least one value
+        Ok(values) if !values[0].is_nil() => Ok(frame.set_return_values(&values)),
+        Ok(_) => {
+            // EOF: close the file if the generator opened it
+            if to_close {
+                let mut file = userdata.borrow_mut::<LuaFile>().unwrap();
+                let _ = file.flush(&mut frame.vm.stdout);
+                file.stream = None;
+            }
+            Ok(0)
+        },
+        Err(error) => Err(frame.error(&os_error_message(&error))),
+    }
+}
+
+/*
+ * WRITE
+ */
+
+fn g_write(frame: &mut LuaStackView, userdata: &Rc<Userdata>, first: usize) -> Result<usize, LuaError> {
+    let mut result = Ok(());
+    for arg in first..=frame.arg_count() {
+        let bytes = match frame.get_arg(arg) {
+            TValue::NUMINT(i) => i.to_string().into_bytes(),
+            TValue::NUMFLT(f) => fmt_g(*f, 14, false).into_bytes(),
+            _ => frame.check_string(arg)?.as_bytes().to_vec(),
+        };
+        if result.is_ok() {
+            let mut file = userdata.borrow_mut::<LuaFile>().unwrap();
+            result = file.write(&mut frame.vm.stdout, &bytes);
+        }
+    }
+    match result {
+        Ok(()) => Ok(frame.set_return_values(&[TValue::USERDATA(userdata.clone())])),
+        Err(error) => Ok(file_result(frame, Err(error), None)),
+    }
+}
+
+fn io_write(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    let file = get_io_file(frame, state, true)?;
+    g_write(frame, &file, 1)
+}
+
+fn f_write(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let file = to_file(frame, 1)?;
+    g_write(frame, &file, 2)
+}
+
+fn f_seek(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let userdata = to_file(frame, 1)?;
+    let whence = frame.check_option(2, Some("cur"), &["set", "cur", "end"])?;
+    let offset = frame.opt_integer(3, 0)?;
+    let position = match whence {
+        0 => match u64::try_from(offset) {
+            Ok(offset) => SeekFrom::Start(offset),
+            Err(_) => return Ok(file_result(frame, Err(io::Error::from_raw_os_error(libc::EINVAL)), None)),
+        },
+        1 => SeekFrom::Current(offset),
+        _ => SeekFrom::End(offset),
+    };
+    let result = userdata.borrow_mut::<LuaFile>().unwrap().seek(position);
+    match result {
+        Ok(position) => Ok(frame.set_return_values(&[TValue::NUMINT(position as i64)])),
+        Err(error) => Ok(file_result(frame, Err(error), None)),
+    }
+}
+
+/**
+ * f_setvbuf: writes are never held back, so every mode behaves as "no"
+ */
+fn f_setvbuf(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    to_file(frame, 1)?;
+    frame.check_option(2, None, &["no", "full", "line"])?;
+    frame.opt_integer(3, BUFFER_SIZE as i64)?;
+    Ok(file_result(frame, Ok(()), None))
+}
+
+fn io_flush(frame: &mut LuaStackView, state: &IoState) -> Result<usize, LuaError> {
+    let userdata = get_io_file(frame, state, true)?;
+    let result = userdata.borrow_mut::<LuaFile>().unwrap().flush(&mut frame.vm.stdout);
+    Ok(file_result(frame, result, None))
+}
+
+fn f_flush(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let userdata = to_file(frame, 1)?;
+    let result = userdata.borrow_mut::<LuaFile>().unwrap().flush(&mut frame.vm.stdout);
+    Ok(file_result(frame, result, None))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stdlib::test::run_chunk;
+
+    #[test]
+    fn io_library_matches_reference() {
+        // helpers/tests/io.lua, on a temporary file, a tmpfile and pipes to the shell
+        let output = run_chunk(include_bytes!("../../helpers/tests/io.out"));
+        assert_eq!(output, include_str!("../../helpers/tests/io.expected"));
+    }
+}
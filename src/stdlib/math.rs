@@ -0,0 +1,369 @@
+/*
+ * Mathematical library (lmathlib.c), with the same xoshiro256** generator and seeding as the
+ * reference interpreter so seeded sequences are identical
+ */
+
+use std::{cell::RefCell, f64::consts::PI, rc::Rc, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{core::types::{TValue, LuaError, Closure, number::{float_to_integer, F2IMode}, stack::LuaStackView}, vm::LuaVm};
+
+pub fn open(vm: &mut LuaVm) {
+    let library = super::new_lib(vm, "math", &[
+        ("abs", abs),
+        ("acos", acos),
+        ("asin", asin),
+        ("atan", atan),
+        ("ceil", ceil),
+        ("cos", cos),
+        ("deg", deg),
+        ("exp", exp),
+        ("tointeger", to_integer),
+        ("floor", floor),
+        ("fmod", fmod),
+        ("ult", ult),
+        ("log", log),
+        ("max", max),
+        ("min", min),
+        ("modf", modf),
+        ("rad", rad),
+        ("sin", sin),
+        ("sqrt", sqrt),
+        ("tan", tan),
+        ("type", type_),
+    ]);
+    let mut library = library.borrow_mut();
+    library.set_str("pi", TValue::NUMFLT(PI));
+    library.set_str("huge", TValue::NUMFLT(f64::INFINITY));
+    library.set_str("maxinteger", TValue::NUMINT(i64::MAX));
+    library.set_str("mininteger", TValue::NUMINT(i64::MIN));
+    // random functions share their generator state (the upvalue of setrandfunc)
+    let state = Rc::new(RefCell::new(RanState::default()));
+    state.borrow_mut().randomize(Rc::as_ptr(&state) as u64);
+    let random_state = state.clone();
+    let random = Closure::new_native(move |frame| random(frame, &mut random_state.borrow_mut()), vec![]);
+    let random_seed = Closure::new_native(move |frame| random_seed(frame, &mut state.borrow_mut()), vec![]);
+    library.set_str("random", TValue::CLOSURE(Rc::new(random)));
+    library.set_str("randomseed", TValue::CLOSURE(Rc::new(random_seed)));
+}
+
+fn push_number(frame: &mut LuaStackView, n: f64) -> usize {
+    frame.set_return_values(&[TValue::NUMFLT(n)])
+}
+
+/**
+ * pushnumint: the float as an integer when it fits, otherwise as is
+ */
+fn number_or_integer(d: f64) -> TValue {
+    match float_to_integer(d, F2IMode::Exact) {
+        Some(n) => TValue::NUMINT(n),
+        None => TValue::NUMFLT(d),
+    }
+}
+
+fn abs(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    if let TValue::NUMINT(n) = frame.get_arg(1) {
+        let n = n.wrapping_abs();
+        return Ok(frame.set_return_values(&[TValue::NUMINT(n)]));
+    }
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x.abs()))
+}
+
+fn sin(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x.sin()))
+}
+
+fn cos(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x.cos()))
+}
+
+fn tan(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x.tan()))
+}
+
+fn asin(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x.asin()))
+}
+
+fn acos(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x.acos()))
+}
+
+fn atan(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let y = frame.check_number(1)?;
+    let x = frame.opt_number(2, 1.0)?;
+    Ok(push_number(frame, y.atan2(x)))
+}
+
+fn to_integer(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    match frame.get_arg(1).to_integer(F2IMode::Exact) {
+        Some(n) => Ok(frame.set_return_values(&[TValue::NUMINT(n)])),
+        None => {
+            frame.check_any(1)?;
+            // value is not convertible to integer
+            Ok(frame.set_return_values(&[TValue::NIL]))
+        },
+    }
+}
+
+fn floor(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let result = match frame.get_arg(1) {
+        // integer is its own floor
+        TValue::NUMINT(n) => TValue::NUMINT(*n),
+        _ => number_or_integer(frame.check_number(1)?.floor()),
+    };
+    Ok(frame.set_return_values(&[result]))
+}
+
+fn ceil(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let result = match frame.get_arg(1) {
+        // integer is its own ceiling
+        TValue::NUMINT(n) => TValue::NUMINT(*n),
+        _ => number_or_integer(frame.check_number(1)?.ceil()),
+    };
+    Ok(frame.set_return_values(&[result]))
+}
+
+fn fmod(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    if let (TValue::NUMINT(m), TValue::NUMINT(d)) = (frame.get_arg(1), frame.get_arg(2)) {
+        let (m, d) = (*m, *d);
+        // special cases -1 and 0 (avoids overflow with mininteger % -1)
+        let result = if (d as u64).wrapping_add(1) <= 1 {
+            if d == 0 {
+                return Err(frame.arg_error(2, "zero"));
+            }
+            0
+        } else {
+            m % d
+        };
+        return Ok(frame.set_return_values(&[TValue::NUMINT(result)]));
+    }
+    let a = frame.check_number(1)?;
+    let b = frame.check_number(2)?;
+    Ok(push_number(frame, a % b))
+}
+
+fn modf(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    if let TValue::NUMINT(n) = frame.get_arg(1) {
+        // number is its own integer part, with no fractional part
+        let n = *n;
+        return Ok(frame.set_return_values(&[TValue::NUMINT(n), TValue::NUMFLT(0.0)]));
+    }
+    let n = frame.check_number(1)?;
+    // integer part (rounds toward zero)
+    let ip = if n < 0.0 { n.ceil() } else { n.floor() };
+    // fractional part (test needed for inf/-inf)
+    let fraction = if n == ip { 0.0 } else { n - ip };
+    Ok(frame.set_return_values(&[number_or_integer(ip), TValue::NUMFLT(fraction)]))
+}
+
+fn sqrt(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x.sqrt()))
+}
+
+fn ult(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let a = frame.check_integer(1)?;
+    let b = frame.check_integer(2)?;
+    Ok(frame.set_return_values(&[TValue::TBOOLEAN((a as u64) < (b as u64))]))
+}
+
+fn log(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    let result = if frame.get_arg(2).is_nil() {
+        x.ln()
+    } else {
+        match frame.check_number(2)? {
+            2.0 => x.log2(),
+            10.0 => x.log10(),
+            base => x.ln() / base.ln(),
+        }
+    };
+    Ok(push_number(frame, result))
+}
+
+fn exp(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x.exp()))
+}
+
+fn deg(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x * (180.0 / PI)))
+}
+
+fn rad(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let x = frame.check_number(1)?;
+    Ok(push_number(frame, x * (PI / 180.0)))
+}
+
+fn min(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let n = frame.arg_count();
+    if n < 1 {
+        return Err(frame.arg_error(1, "value expected"));
+    }
+    let mut result = frame.get_arg(1).clone();
+    for i in 2..=n {
+        let value = frame.get_arg(i).clone();
+        if frame.vm.less_than(frame.thread, &value, &result)? {
+            result = value;
+        }
+    }
+    Ok(frame.set_return_values(&[result]))
+}
+
+fn max(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let n = frame.arg_count();
+    if n < 1 {
+        return Err(frame.arg_error(1, "value expected"));
+    }
+    let mut result = frame.get_arg(1).clone();
+    for i in 2..=n {
+        let value = frame.get_arg(i).clone();
+        if frame.vm.less_than(frame.thread, &result, &value)? {
+            result = value;
+        }
+    }
+    Ok(frame.set_return_values(&[result]))
+}
+
+fn type_(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let result = match frame.get_arg(1) {
+        TValue::NUMINT(_) => TValue::from_str("integer"),
+        TValue::NUMFLT(_) => TValue::from_str("float"),
+        _ => {
+            frame.check_any(1)?;
+            TValue::NIL
+        },
+    };
+    Ok(frame.set_return_values(&[result]))
+}
+
+/*
+ * Pseudo-random number generator based on 'xoshiro256**'
+ */
+
+/* number of binary digits in the mantissa of a float */
+const FIGS: u32 = f64::MANTISSA_DIGITS;
+
+#[derive(Default)]
+struct RanState([u64; 4]);
+
+impl RanState {
+    fn next(&mut self) -> u64 {
+        let s = &mut self.0;
+        let state0 = s[0];
+        let state1 = s[1];
+        let state2 = s[2] ^ state0;
+        let state3 = s[3] ^ state1;
+        let result = state1.wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        s[0] = state0 ^ state3;
+        s[1] = state1 ^ state2;
+        s[2] = state2 ^ (state1 << 17);
+        s[3] = state3.rotate_left(45);
+        result
+    }
+
+    /**
+     * setseed: the seeds are spread by discarding the first values
+     */
+    fn set_seed(&mut self, n1: u64, n2: u64) {
+        // 0xff avoids a zero state
+        self.0 = [n1, 0xff, n2, 0];
+        for _ in 0..16 {
+            self.next();
+        }
+    }
+
+    /**
+     * randseed: a "random" seed from the current time and an address
+     */
+    fn randomize(&mut self, address: u64) -> (u64, u64) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        self.set_seed(time, address);
+        (time, address)
+    }
+
+    /**
+     * project: 'ran' projected into [0, n], uniformly by retrying values past the smallest
+     * 2^b - 1 not smaller than 'n'
+     */
+    fn project(&mut self, ran: u64, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 {
+            // 'n + 1' is a power of 2: no bias
+            return ran & n;
+        }
+        let mut lim = n;
+        lim |= lim >> 1;
+        lim |= lim >> 2;
+        lim |= lim >> 4;
+        lim |= lim >> 8;
+        lim |= lim >> 16;
+        lim |= lim >> 32;
+        let mut ran = ran & lim;
+        while ran > n {
+            ran = self.next() & lim;
+        }
+        ran
+    }
+}
+
+/**
+ * I2d: the higher FIGS bits as a float in [0, 1)
+ */
+fn to_float(x: u64) -> f64 {
+    (x >> (64 - FIGS)) as f64 * (0.5 / (1u64 << (FIGS - 1)) as f64)
+}
+
+fn random(frame: &mut LuaStackView, state: &mut RanState) -> Result<usize, LuaError> {
+    let rv = state.next();
+    let (low, up) = match frame.arg_count() {
+        // float between 0 and 1
+        0 => return Ok(push_number(frame, to_float(rv))),
+        1 => {
+            let up = frame.check_integer(1)?;
+            if up == 0 {
+                // full random integer
+                return Ok(frame.set_return_values(&[TValue::NUMINT(rv as i64)]));
+            }
+            (1, up)
+        },
+        2 => (frame.check_integer(1)?, frame.check_integer(2)?),
+        _ => return Err(frame.error("wrong number of arguments")),
+    };
+    // random integer in the interval [low, up]
+    if low > up {
+        return Err(frame.arg_error(1, "interval is empty"));
+    }
+    let p = state.project(rv, (up as u64).wrapping_sub(low as u64));
+    Ok(frame.set_return_values(&[TValue::NUMINT(p.wrapping_add(low as u64) as i64)]))
+}
+
+fn random_seed(frame: &mut LuaStackView, state: &mut RanState) -> Result<usize, LuaError> {
+    let (n1, n2) = if frame.arg_count() == 0 {
+        state.randomize(frame.thread as *const _ as u64)
+    } else {
+        let n1 = frame.check_integer(1)?;
+        let n2 = frame.opt_integer(2, 0)?;
+        state.set_seed(n1 as u64, n2 as u64);
+        (n1 as u64, n2 as u64)
+    };
+    Ok(frame.set_return_values(&[TValue::NUMINT(n1 as i64), TValue::NUMINT(n2 as i64)]))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stdlib::test::run_chunk;
+
+    #[test]
+    fn math_library_matches_reference() {
+        // helpers/tests/math.lua, seeded random sequences included
+        let output = run_chunk(include_bytes!("../../helpers/tests/math.out"));
+        assert_eq!(output, include_str!("../../helpers/tests/math.expected"));
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * Standard libraries, registered into the globals of a VM (linit.c)
+ */
+
+pub mod base;
+pub mod debug;
+pub mod io;
+pub mod math;
+pub mod os;
+pub mod string;
+pub mod table;
+pub mod utf8;
+
+use std::{collections::hash_map::RandomState, ffi::CString, fs, hash::{BuildHasher, Hasher}, path::PathBuf, rc::Rc};
+
+use crate::{core::types::{TValue, LuaError, Closure, table::{LuaTable, TableRef}, stack::LuaStackView}, vm::LuaVm};
+
+pub type LibFunction = fn(&mut LuaStackView) -> Result<usize, LuaError>;
+
+pub fn open_libs(vm: &mut LuaVm) {
+    open_libs_with(vm, os::OsPermissions::default());
+}
+
+/**
+ * open_libs with only the os, io and file loading functions the host allows, e.g.
+ * OsPermissions::NONE for untrusted scripts
+ */
+pub fn open_libs_with(vm: &mut LuaVm, os: os::OsPermissions) {
+    base::open(vm, os);
+    string::open(vm);
+    table::open(vm);
+    math::open(vm);
+    os::open(vm, os);
+    utf8::open(vm);
+    io::open(vm, os);
+    debug::open(vm);
+}
+
+pub fn native(function: LibFunction) -> TValue {
+    TValue::CLOSURE(Rc::new(Closure::new_native(function, vec![])))
+}
+
+/**
+ * luaL_setfuncs
+ */
+pub fn register(table: &TableRef, functions: &[(&str, LibFunction)]) {
+    let mut table = table.borrow_mut();
+    for (name, function) in functions {
+        table.set_str(name, native(*function));
+    }
+}
+
+/**
+ * luaL_newlib: a new table with the functions, also stored as a global
+ */
+pub fn new_lib(vm: &mut LuaVm, name: &str, functions: &[(&str, LibFunction)]) -> TableRef {
+    let library = LuaTable::new_ref();
+    register(&library, functions);
+    vm.globals.borrow_mut().set_str(name, TValue::TABLE(library.clone()));
+    library
+}
+
+/**
+ * Text of an OS error as strerror would print it, without Rust's "(os error N)" suffix
+ */
+pub fn os_error_message(error: &std::io::Error) -> String {
+    let message = error.to_string();
+    match message.find(" (os error") {
+        Some(index) => message[..index].to_string(),
+        None => message,
+    }
+}
+
+/**
+ * luaL_fileresult: true on success, otherwise fail, the message (prefixed by the file name,
+ * if given) and the error number
+ */
+pub fn file_result(frame: &mut LuaStackView, result: std::io::Result<()>, filename: Option<&[u8]>) -> usize {
+    match result {
+        Ok(()) => frame.set_return_values(&[TValue::TBOOLEAN(true)]),
+        Err(error) => {
+            let mut message = Vec::new();
+            if let Some(filename) = filename {
+                message.extend_from_slice(filename);
+                message.extend_from_slice(b": ");
+            }
+            message.extend_from_slice(os_error_message(&error).as_bytes());
+            let errno = error.raw_os_error().unwrap_or(0) as i64;
+            frame.set_return_values(&[TValue::NIL, TValue::from_bytes(&message), TValue::NUMINT(errno)])
+        },
+    }
+}
+
+/**
+ * luaL_execresult: how a process terminated, from the status returned by system/pclose; -1
+ * means it couldn't be run, with the reason in errno
+ */
+pub fn exec_result(frame: &mut LuaStackView, status: i32) -> usize {
+    if status == -1 {
+        return file_result(frame, Err(std::io::Error::last_os_error()), None);
+    }
+    let (what, status) = process_status(status);
+    let success = if what == "exit" && status == 0 { TValue::TBOOLEAN(true) } else { TValue::NIL };
+    frame.set_return_values(&[success, TValue::from_str(what), TValue::NUMINT(status as i64)])
+}
+
+/* l_inspectstat: an exit code or the signal that killed the process */
+#[cfg(unix)]
+fn process_status(status: i32) -> (&'static str, i32) {
+    if libc::WIFEXITED(status) {
+        ("exit", libc::WEXITSTATUS(status))
+    } else if libc::WIFSIGNALED(status) {
+        ("signal", libc::WTERMSIG(status))
+    } else {
+        ("exit", status)
+    }
+}
+
+/* processes on Windows only have exit codes */
+#[cfg(windows)]
+fn process_status(status: i32) -> (&'static str, i32) {
+    ("exit", status)
+}
+
+/**
+ * A file under the temporary directory with a name nothing else has, created with 'options'
+ * as mkstemp would, so the name cannot be taken by someone else in between
+ */
+pub fn temp_file(options: &fs::OpenOptions) -> std::io::Result<(PathBuf, fs::File)> {
+    let mut options = options.clone();
+    options.create_new(true);
+    loop {
+        // each RandomState has new keys, which makes the names unpredictable
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        let path = std::env::temp_dir().join(format!("lua_{:06x}", hasher.finish() & 0xffffff));
+        match options.open(&path) {
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            result => return result.map(|file| (path, file)),
+        }
+    }
+}
+
+/**
+ * Byte string as a C string, cut at the first embedded zero like C would read it
+ */
+pub fn c_string(bytes: &[u8]) -> CString {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    CString::new(&bytes[..end]).unwrap()
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::{rc::Rc, cell::RefCell, io::Write};
+
+    use crate::{core::{parser::parse_all, types::LuaThread}, vm::LuaVm};
+
+    /**
+     * Output sink shared between the VM and the test
+     */
+    #[derive(Clone, Default)]
+    pub struct Capture(pub Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /**
+     * Runs a chunk compiled by the reference luac and returns what it printed
+     */
+    pub fn run_chunk(chunk: &[u8]) -> String {
+        let mut vm = LuaVm::new();
+        super::open_libs(&mut vm);
+        let capture = Capture::default();
+        vm.stdout = Box::new(capture.clone());
+        let mut thread = LuaThread::new();
+        let main = vm.load(Rc::new(parse_all(chunk).unwrap()));
+        if let Err(error) = vm.protected_call(&mut thread, main, &[]) {
+            panic!("{}", error);
+        }
+        let output = capture.0.borrow().clone();
+        String::from_utf8(output).unwrap()
+    }
+}
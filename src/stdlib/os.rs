@@ -0,0 +1,380 @@
+/*
+ * Operating system library (loslib.c). Functions with side effects outside the script
+ * (processes, files, the environment) can be left out by the host through OsPermissions
+ */
+
+use std::{ffi::CStr, fs, io::{self, Write}, mem};
+
+use crate::{core::types::{TValue, LuaError, number::F2IMode, stack::LuaStackView}, vm::LuaVm};
+
+use super::{LibFunction, c_string, exec_result, file_result, temp_file};
+
+/**
+ * Side-effecting functions the host allows; the ones not allowed are not registered. Besides
+ * the os ones, 'files' covers the io functions that open files by name, 'dofile' and
+ * 'loadfile', and 'popen' covers io.popen
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OsPermissions {
+    pub execute: bool,
+    pub exit: bool,
+    pub getenv: bool,
+    pub remove: bool,
+    pub rename: bool,
+    pub tmpname: bool,
+    pub files: bool,
+    pub popen: bool,
+}
+
+impl OsPermissions {
+    /* the full library, as for trusted scripts */
+    pub const ALL: Self = Self { execute: true, exit: true, getenv: true, remove: true, rename: true, tmpname: true, files: true, popen: true };
+    /* only the functions without side effects: in os, clock, date, difftime and time */
+    pub const NONE: Self = Self {
+        execute: false, exit: false, getenv: false, remove: false, rename: false, tmpname: false, files: false, popen: false,
+    };
+}
+
+impl Default for OsPermissions {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+/*
+ * Valid conversion specifiers for 'strftime' (C99, or the ones of the Windows runtime);
+ * options are grouped by length and the group of length 2 starts with '||'
+ */
+#[cfg(not(windows))]
+const STRFTIME_OPTIONS: &[u8] = b"aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%||EcECExEXEyEYOdOeOHOIOmOMOSOuOUOVOwOWOy";
+#[cfg(windows)]
+const STRFTIME_OPTIONS: &[u8] = b"aAbBcdHIjmMpSUwWxXyYzZ%||#c#x#d#H#I#j#m#M#S#U#w#W#y#Y";
+
+/* maximum size for an individual 'strftime' item */
+const SIZETIMEFMT: usize = 250;
+
+pub fn open(vm: &mut LuaVm, permissions: OsPermissions) {
+    let functions: [(&str, LibFunction, bool); 10] = [
+        ("clock", clock, true),
+        ("date", date, true),
+        ("difftime", difftime, true),
+        ("execute", execute, permissions.execute),
+        ("exit", exit, permissions.exit),
+        ("getenv", getenv, permissions.getenv),
+        ("remove", remove, permissions.remove),
+        ("rename", rename, permissions.rename),
+        ("time", time, true),
+        ("tmpname", tmpname, permissions.tmpname),
+    ];
+    let allowed: Vec<(&str, LibFunction)> = functions.iter()
+        .filter(|(_, _, allowed)| *allowed)
+        .map(|(name, function, _)| (*name, *function))
+        .collect();
+    super::new_lib(vm, "os", &allowed);
+}
+
+fn execute(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let command = if frame.get_arg(1).is_nil() { None } else { Some(c_string(&frame.check_string(1)?)) };
+    // output of the command must come after what the script already wrote
+    let _ = frame.vm.stdout.flush();
+    let status = unsafe { libc::system(command.as_ref().map_or(std::ptr::null(), |c| c.as_ptr())) };
+    if command.is_some() {
+        Ok(exec_result(frame, status))
+    } else {
+        // true if there is a shell
+        Ok(frame.set_return_values(&[TValue::TBOOLEAN(status != 0)]))
+    }
+}
+
+fn remove(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let filename = frame.check_string(1)?;
+    let result = unsafe { libc::remove(c_string(&filename).as_ptr()) };
+    let result = if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) };
+    Ok(file_result(frame, result, Some(&filename)))
+}
+
+fn rename(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let from = frame.check_string(1)?;
+    let to = frame.check_string(2)?;
+    let result = unsafe { libc::rename(c_string(&from).as_ptr(), c_string(&to).as_ptr()) };
+    let result = if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) };
+    Ok(file_result(frame, result, None))
+}
+
+/**
+ * tmpname: the name of a new empty file, left for the script to use
+ */
+fn tmpname(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    match temp_file(fs::OpenOptions::new().write(true)) {
+        Ok((path, _)) => Ok(frame.set_return_values(&[TValue::from_str(&path.to_string_lossy())])),
+        Err(_) => Err(frame.error("unable to generate a unique filename")),
+    }
+}
+
+fn getenv(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let name = frame.check_string(1)?;
+    let value = unsafe { libc::getenv(c_string(&name).as_ptr()) };
+    let result = if value.is_null() {
+        TValue::NIL
+    } else {
+        TValue::from_bytes(unsafe { CStr::from_ptr(value) }.to_bytes())
+    };
+    Ok(frame.set_return_values(&[result]))
+}
+
+extern "C" {
+    // processor time used by the program (not bound by the libc crate on every platform)
+    #[link_name = "clock"]
+    fn c_clock() -> libc::clock_t;
+}
+
+/* POSIX requires CLOCKS_PER_SEC to be one million, the Windows runtime counts milliseconds */
+#[cfg(not(windows))]
+const CLOCKS_PER_SEC: f64 = 1_000_000.0;
+#[cfg(windows)]
+const CLOCKS_PER_SEC: f64 = 1_000.0;
+
+fn clock(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let clock = unsafe { c_clock() } as f64 / CLOCKS_PER_SEC;
+    Ok(frame.set_return_values(&[TValue::NUMFLT(clock)]))
+}
+
+/*
+ * Time/Date operations
+ * { year=%Y, month=%m, day=%d, hour=%H, min=%M, sec=%S, wday=%w+1, yday=%j, isdst=? }
+ */
+
+fn set_field(frame: &mut LuaStackView, table: &TValue, key: &str, value: i32, delta: i64) -> Result<(), LuaError> {
+    frame.set_index(table, TValue::from_str(key), TValue::NUMINT(value as i64 + delta))
+}
+
+/**
+ * setallfields: fields from 'tm' into the table
+ */
+fn set_all_fields(frame: &mut LuaStackView, table: &TValue, tm: &libc::tm) -> Result<(), LuaError> {
+    set_field(frame, table, "year", tm.tm_year, 1900)?;
+    set_field(frame, table, "month", tm.tm_mon, 1)?;
+    set_field(frame, table, "day", tm.tm_mday, 0)?;
+    set_field(frame, table, "hour", tm.tm_hour, 0)?;
+    set_field(frame, table, "min", tm.tm_min, 0)?;
+    set_field(frame, table, "sec", tm.tm_sec, 0)?;
+    set_field(frame, table, "yday", tm.tm_yday, 1)?;
+    set_field(frame, table, "wday", tm.tm_wday, 1)?;
+    // undefined daylight saving time does not set the field
+    if tm.tm_isdst >= 0 {
+        frame.set_index(table, TValue::from_str("isdst"), TValue::TBOOLEAN(tm.tm_isdst != 0))?;
+    }
+    Ok(())
+}
+
+fn get_bool_field(frame: &mut LuaStackView, table: &TValue, key: &str) -> Result<i32, LuaError> {
+    let value = frame.index(table, &TValue::from_str(key))?;
+    Ok(if value.is_nil() { -1 } else { !value.is_falsy() as i32 })
+}
+
+/**
+ * getfield: an integer field minus 'delta', 'default' when absent (a negative default means
+ * the field is required)
+ */
+fn get_field(frame: &mut LuaStackView, table: &TValue, key: &str, default: i32, delta: i64) -> Result<i32, LuaError> {
+    let value = frame.index(table, &TValue::from_str(key))?;
+    match value.to_integer(F2IMode::Exact) {
+        Some(result) => {
+            let in_bounds = if result >= 0 { result - delta <= i32::MAX as i64 } else { i32::MIN as i64 + delta <= result };
+            if !in_bounds {
+                return Err(frame.error(&format!("field '{}' is out-of-bound", key)));
+            }
+            Ok((result - delta) as i32)
+        },
+        None if !value.is_nil() => Err(frame.error(&format!("field '{}' is not an integer", key))),
+        None if default < 0 => Err(frame.error(&format!("field '{}' missing in date table", key))),
+        None => Ok(default),
+    }
+}
+
+/**
+ * checkoption: the conversion specifier at the start of 'conversion', with its length
+ */
+fn check_option<'a>(frame: &LuaStackView, conversion: &'a [u8]) -> Result<&'a [u8], LuaError> {
+    let mut options = STRFTIME_OPTIONS;
+    // length of options being checked
+    let mut length = 1;
+    while !options.is_empty() && length <= conversion.len() {
+        if options[0] == b'|' {
+            // next block, options with the next length (skipping the whole '||')
+            length += 1;
+            options = &options[length..];
+        } else {
+            if options[..length] == conversion[..length] {
+                return Ok(&conversion[..length]);
+            }
+            options = &options[length..];
+        }
+    }
+    let shown = conversion.split(|&b| b == 0).next().unwrap_or_default();
+    Err(frame.arg_error(1, &format!("invalid conversion specifier '%{}'", String::from_utf8_lossy(shown))))
+}
+
+fn check_time(frame: &LuaStackView, arg: usize) -> Result<libc::time_t, LuaError> {
+    let t = frame.check_integer(arg)?;
+    libc::time_t::try_from(t).map_err(|_| frame.arg_error(arg, "time out-of-bounds"))
+}
+
+fn current_time() -> libc::time_t {
+    unsafe { libc::time(std::ptr::null_mut()) }
+}
+
+/* l_gmtime/l_localtime: false when 't' can't be broken down */
+#[cfg(unix)]
+fn broken_down_time(t: libc::time_t, utc: bool, tm: &mut libc::tm) -> bool {
+    let result = unsafe { if utc { libc::gmtime_r(&t, tm) } else { libc::localtime_r(&t, tm) } };
+    !result.is_null()
+}
+
+#[cfg(windows)]
+fn broken_down_time(t: libc::time_t, utc: bool, tm: &mut libc::tm) -> bool {
+    let result = unsafe { if utc { libc::gmtime_s(tm, &t) } else { libc::localtime_s(tm, &t) } };
+    result == 0
+}
+
+#[cfg(unix)]
+use libc::{mktime, strftime};
+
+// not bound by the libc crate for Windows; the time_t variants match the ones libc uses
+#[cfg(windows)]
+extern "C" {
+    fn strftime(s: *mut libc::c_char, max: libc::size_t, format: *const libc::c_char, tm: *const libc::tm) -> libc::size_t;
+    #[cfg_attr(all(target_arch = "x86", target_env = "gnu"), link_name = "_mktime32")]
+    #[cfg_attr(not(all(target_arch = "x86", target_env = "gnu")), link_name = "_mktime64")]
+    fn mktime(tm: *mut libc::tm) -> libc::time_t;
+}
+
+fn date(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let format = frame.opt_string(1, "%c")?;
+    let t = if frame.get_arg(2).is_nil() { current_time() } else { check_time(frame, 2)? };
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    let mut s: &[u8] = &format;
+    // UTC
+    let utc = s.first() == Some(&b'!');
+    if utc {
+        s = &s[1..];
+    }
+    if !broken_down_time(t, utc, &mut tm) {
+        return Err(frame.error("date result cannot be represented in this installation"));
+    }
+    // as C compares it, up to the first zero
+    if s.split(|&b| b == 0).next() == Some(b"*t") {
+        let table = TValue::TABLE(crate::core::types::table::LuaTable::new_ref());
+        set_all_fields(frame, &table, &tm)?;
+        return Ok(frame.set_return_values(&[table]));
+    }
+    let mut buffer = Vec::new();
+    while let Some((&c, rest)) = s.split_first() {
+        if c != b'%' {
+            buffer.push(c);
+            s = rest;
+            continue;
+        }
+        let option = check_option(frame, rest)?;
+        s = &rest[option.len()..];
+        let mut specifier = vec![b'%'];
+        specifier.extend_from_slice(option);
+        specifier.push(0);
+        let mut item = [0u8; SIZETIMEFMT];
+        let length = unsafe {
+            strftime(item.as_mut_ptr() as *mut libc::c_char, SIZETIMEFMT, specifier.as_ptr() as *const libc::c_char, &tm)
+        };
+        buffer.extend_from_slice(&item[..length]);
+    }
+    Ok(frame.set_return_values(&[TValue::from_bytes(&buffer)]))
+}
+
+/**
+ * os.time: the current time, or the time of a date table whose fields are normalized in
+ * place by mktime
+ */
+fn time(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let t = if frame.get_arg(1).is_nil() {
+        current_time()
+    } else {
+        let table = frame.check_table(1)?;
+        let table = TValue::TABLE(table);
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        tm.tm_year = get_field(frame, &table, "year", -1, 1900)?;
+        tm.tm_mon = get_field(frame, &table, "month", -1, 1)?;
+        tm.tm_mday = get_field(frame, &table, "day", -1, 0)?;
+        tm.tm_hour = get_field(frame, &table, "hour", 12, 0)?;
+        tm.tm_min = get_field(frame, &table, "min", 0, 0)?;
+        tm.tm_sec = get_field(frame, &table, "sec", 0, 0)?;
+        tm.tm_isdst = get_bool_field(frame, &table, "isdst")?;
+        let t = unsafe { mktime(&mut tm) };
+        // update fields with normalized values
+        set_all_fields(frame, &table, &tm)?;
+        t
+    };
+    if t == -1 {
+        return Err(frame.error("time result cannot be represented in this installation"));
+    }
+    Ok(frame.set_return_values(&[TValue::NUMINT(t as i64)]))
+}
+
+fn difftime(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let t1 = check_time(frame, 1)?;
+    let t2 = check_time(frame, 2)?;
+    // exact difference, rounded once
+    let difference = (t1 as i128 - t2 as i128) as f64;
+    Ok(frame.set_return_values(&[TValue::NUMFLT(difference)]))
+}
+
+fn exit(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let status = match frame.get_arg(1) {
+        TValue::TBOOLEAN(success) => if *success { libc::EXIT_SUCCESS } else { libc::EXIT_FAILURE },
+        _ => frame.opt_integer(1, libc::EXIT_SUCCESS as i64)? as i32,
+    };
+    // nothing buffered by the script may be lost
+    let _ = frame.vm.stdout.flush();
+    std::process::exit(status)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{core::types::{TValue, LuaThread}, stdlib::{open_libs_with, test::run_chunk}, vm::LuaVm};
+
+    use super::OsPermissions;
+
+    #[test]
+    fn os_library_matches_reference() {
+        // helpers/tests/os.lua, dates are formatted in UTC or compared relative to each other
+        let output = run_chunk(include_bytes!("../../helpers/tests/os.out"));
+        assert_eq!(output, include_str!("../../helpers/tests/os.expected"));
+    }
+
+    #[test]
+    fn denied_functions_are_not_registered() {
+        let mut vm = LuaVm::new();
+        open_libs_with(&mut vm, OsPermissions { getenv: true, ..OsPermissions::NONE });
+        let os = match vm.globals.borrow().get_str("os") {
+            TValue::TABLE(os) => os,
+            _ => panic!("os library not registered"),
+        };
+        let os = os.borrow();
+        for name in ["execute", "exit", "remove", "rename", "tmpname"] {
+            assert!(os.get_str(name).is_nil(), "{} should be denied", name);
+        }
+        for name in ["clock", "date", "difftime", "getenv", "time"] {
+            assert!(!os.get_str(name).is_nil(), "{} should be available", name);
+        }
+    }
+
+    #[test]
+    fn untrusted_scripts_cannot_reach_files() {
+        let mut vm = LuaVm::new();
+        open_libs_with(&mut vm, OsPermissions::NONE);
+        let source = b"assert(io.open == nil and io.popen == nil and io.lines == nil and io.tmpfile == nil)
+            assert(io.write and io.read and io.type and loadfile == nil)
+            return dofile('helpers/tests/base.lua')";
+        let main = vm.load_chunk(source, "=test", "t").unwrap();
+        let error = vm.protected_call(&mut LuaThread::new(), main, &[]).unwrap_err();
+        assert!(error.to_string().contains("attempt to call a nil value (global 'dofile')"), "{}", error);
+    }
+}
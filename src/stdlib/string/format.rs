@@ -0,0 +1,427 @@
+/*
+ * string.format (str_format in lstrlib.c): conversions follow C's printf as used by the
+ * reference implementation, '%q' writes values as literals that Lua can read back
+ */
+
+use std::rc::Rc;
+
+use crate::core::types::{TValue, LuaError, string::LuaString, stack::LuaStackView, number::fmt_g};
+
+const L_ESC: u8 = b'%';
+/* maximum size of a format specification such as "%-099.99d" */
+const MAX_FORMAT: usize = 32;
+
+/* valid flags for a, A, e, E, f, g and G conversions */
+const L_FMTFLAGSF: &[u8] = b"-+#0 ";
+/* valid flags for o, x and X conversions */
+const L_FMTFLAGSX: &[u8] = b"-#0";
+/* valid flags for d and i conversions */
+const L_FMTFLAGSI: &[u8] = b"-+0 ";
+/* valid flags for u conversions */
+const L_FMTFLAGSU: &[u8] = b"-0";
+/* valid flags for c, p and s conversions */
+const L_FMTFLAGSC: &[u8] = b"-";
+
+/**
+ * A validated conversion specification
+ */
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /* 'form' was already validated by 'check_format' */
+    fn parse(form: &[u8]) -> Self {
+        let mut spec = Spec::default();
+        let mut i = 1;
+        while let Some(flag) = form.get(i).filter(|c| b"-+ #0".contains(c)) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                _ => spec.zero = true,
+            }
+            i += 1;
+        }
+        while let Some(d) = form.get(i).filter(|c| c.is_ascii_digit()) {
+            spec.width = spec.width * 10 + (d - b'0') as usize;
+            i += 1;
+        }
+        if form.get(i) == Some(&b'.') {
+            i += 1;
+            let mut precision = 0;
+            while let Some(d) = form.get(i).filter(|c| c.is_ascii_digit()) {
+                precision = precision * 10 + (d - b'0') as usize;
+                i += 1;
+            }
+            spec.precision = Some(precision);
+        }
+        spec
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    /**
+     * Pads 'prefix' + 'body' to the field width. Zeros go between the prefix and the body
+     */
+    fn pad(&self, prefix: &str, body: &[u8], zero: bool, out: &mut Vec<u8>) {
+        let len = prefix.len() + body.len();
+        let fill = self.width.saturating_sub(len);
+        if self.left {
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(body);
+            out.resize(out.len() + fill, b' ');
+        } else if zero {
+            out.extend_from_slice(prefix.as_bytes());
+            out.resize(out.len() + fill, b'0');
+            out.extend_from_slice(body);
+        } else {
+            out.resize(out.len() + fill, b' ');
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(body);
+        }
+    }
+}
+
+fn skip_2_digits(form: &[u8], i: usize) -> usize {
+    let mut i = i;
+    for _ in 0..2 {
+        if form.get(i).is_some_and(|c| c.is_ascii_digit()) {
+            i += 1;
+        }
+    }
+    i
+}
+
+/**
+ * checkformat: only the given flags, a width of two digits and (when allowed) a precision of two digits
+ */
+fn check_format(frame: &LuaStackView, form: &[u8], flags: &[u8], precision: bool) -> Result<Spec, LuaError> {
+    let mut i = 1;
+    while form.get(i).is_some_and(|c| flags.contains(c)) {
+        i += 1;
+    }
+    if form.get(i) != Some(&b'0') {
+        i = skip_2_digits(form, i);
+        if form.get(i) == Some(&b'.') && precision {
+            i = skip_2_digits(form, i + 1);
+        }
+    }
+    if !form.get(i).is_some_and(|c| c.is_ascii_alphabetic()) {
+        return Err(frame.error(&format!("invalid conversion specification: '{}'", String::from_utf8_lossy(form))));
+    }
+    Ok(Spec::parse(form))
+}
+
+fn format_integer(spec: &Spec, value: i64, conversion: u8, out: &mut Vec<u8>) {
+    let (negative, magnitude) = match conversion {
+        b'd' | b'i' => (value < 0, value.unsigned_abs()),
+        _ => (false, value as u64),
+    };
+    let mut digits = match conversion {
+        b'o' => format!("{:o}", magnitude),
+        b'x' => format!("{:x}", magnitude),
+        b'X' => format!("{:X}", magnitude),
+        _ => magnitude.to_string(),
+    };
+    if spec.precision == Some(0) && magnitude == 0 {
+        digits.clear();
+    }
+    if let Some(precision) = spec.precision {
+        if digits.len() < precision {
+            digits.insert_str(0, &"0".repeat(precision - digits.len()));
+        }
+    }
+    let prefix = match conversion {
+        b'o' if spec.alternate && !digits.starts_with('0') => {
+            digits.insert(0, '0');
+            ""
+        },
+        b'x' if spec.alternate && magnitude != 0 => "0x",
+        b'X' if spec.alternate && magnitude != 0 => "0X",
+        b'd' | b'i' => spec.sign(negative),
+        _ => "",
+    };
+    // the '0' flag is ignored when a precision is given
+    spec.pad(prefix, digits.as_bytes(), spec.zero && spec.precision.is_none(), out);
+}
+
+/**
+ * Hexadecimal digits of a finite, non negative float like glibc's "%a": the leading digit
+ * is 1 for normal numbers and the fraction is rounded to nearest (ties to even)
+ */
+fn hex_float(value: f64, precision: Option<usize>, alternate: bool) -> String {
+    let bits = value.to_bits();
+    let biased_exponent = ((bits >> 52) & 0x7ff) as i64;
+    let mut fraction = bits & ((1 << 52) - 1);
+    let (mut leading, exponent) = match (biased_exponent, fraction) {
+        (0, 0) => (0, 0),
+        (0, _) => (0, -1022),
+        _ => (1, biased_exponent - 1023),
+    };
+    let mut digits = 13;
+    if let Some(precision) = precision.filter(|p| *p < 13) {
+        let shift = (13 - precision) * 4;
+        let remainder = fraction & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        fraction >>= shift;
+        let last = if precision == 0 { leading } else { fraction };
+        if remainder > half || (remainder == half && last & 1 == 1) {
+            fraction += 1;
+            if fraction >> (precision * 4) != 0 {
+                leading += 1;
+                fraction &= (1 << (precision * 4)) - 1;
+            }
+        }
+        digits = precision;
+    }
+    let mut hex = if digits == 0 { String::new() } else { format!("{:0width$x}", fraction, width = digits) };
+    match precision {
+        None => hex = hex.trim_end_matches('0').to_string(),
+        Some(precision) if precision > 13 => hex.push_str(&"0".repeat(precision - 13)),
+        _ => {},
+    }
+    let point = if hex.is_empty() && !alternate { "" } else { "." };
+    format!("{}{}{}p{:+}", leading, point, hex, exponent)
+}
+
+/**
+ * "%e" of a finite, non negative float: at least two exponent digits
+ */
+fn fmt_e(value: f64, precision: usize, alternate: bool) -> String {
+    let sci = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = sci.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let point = if alternate && precision == 0 { "." } else { "" };
+    format!("{}{}e{}{:02}", mantissa, point, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+fn format_float(spec: &Spec, value: f64, conversion: u8, out: &mut Vec<u8>) {
+    let negative = value.is_sign_negative();
+    let magnitude = value.abs();
+    let precision = spec.precision.unwrap_or(6);
+    let mut prefix = spec.sign(negative).to_string();
+    let body = if !value.is_finite() {
+        if value.is_nan() { "nan".to_string() } else { "inf".to_string() }
+    } else {
+        match conversion.to_ascii_lowercase() {
+            b'a' => {
+                prefix.push_str("0x");
+                hex_float(magnitude, spec.precision, spec.alternate)
+            },
+            b'e' => fmt_e(magnitude, precision, spec.alternate),
+            b'f' => {
+                let point = if spec.alternate && precision == 0 { "." } else { "" };
+                format!("{:.*}{}", precision, magnitude, point)
+            },
+            _ => fmt_g(magnitude, precision, spec.alternate),
+        }
+    };
+    let (prefix, body) = if conversion.is_ascii_uppercase() {
+        (prefix.to_ascii_uppercase(), body.to_ascii_uppercase())
+    } else {
+        (prefix, body)
+    };
+    // infinities and NaN are never padded with zeros
+    spec.pad(&prefix, body.as_bytes(), spec.zero && value.is_finite(), out);
+}
+
+/**
+ * addquoted: a string literal, control characters are written as decimal escapes
+ */
+fn add_quoted(s: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for (i, c) in s.iter().enumerate() {
+        match c {
+            b'"' | b'\\' | b'\n' => {
+                out.push(b'\\');
+                out.push(*c);
+            },
+            c if c.is_ascii_control() => {
+                let escape = if s.get(i + 1).is_some_and(|next| next.is_ascii_digit()) {
+                    format!("\\{:03}", c)
+                } else {
+                    format!("\\{}", c)
+                };
+                out.extend_from_slice(escape.as_bytes());
+            },
+            c => out.push(*c),
+        }
+    }
+    out.push(b'"');
+}
+
+/**
+ * addliteral: '%q', the value written so that Lua reads it back unchanged
+ */
+fn add_literal(frame: &mut LuaStackView, arg: usize, out: &mut Vec<u8>) -> Result<(), LuaError> {
+    match frame.get_arg(arg).clone() {
+        TValue::STR(s) => add_quoted(&s, out),
+        TValue::NUMFLT(n) => {
+            let literal = if n == f64::INFINITY {
+                "1e9999".to_string()
+            } else if n == f64::NEG_INFINITY {
+                "-1e9999".to_string()
+            } else if n.is_nan() {
+                "(0/0)".to_string()
+            } else {
+                format!("{}0x{}", if n.is_sign_negative() { "-" } else { "" }, hex_float(n.abs(), None, false))
+            };
+            out.extend_from_slice(literal.as_bytes());
+        },
+        // the minimum integer has no decimal literal: its absolute value overflows
+        TValue::NUMINT(i64::MIN) => out.extend_from_slice(format!("0x{:x}", i64::MIN).as_bytes()),
+        TValue::NUMINT(n) => out.extend_from_slice(n.to_string().as_bytes()),
+        value @ (TValue::NIL | TValue::EMPTY | TValue::TBOOLEAN(_)) => {
+            out.extend_from_slice(&frame.tolstring(&value)?);
+        },
+        _ => return Err(frame.arg_error(arg, "value has no literal form")),
+    }
+    Ok(())
+}
+
+fn add_string(frame: &mut LuaStackView, arg: usize, form: &[u8], out: &mut Vec<u8>) -> Result<(), LuaError> {
+    let value = frame.get_arg(arg).clone();
+    let s = frame.tolstring(&value)?;
+    if form.len() == 2 {
+        // no modifiers: keep entire string
+        out.extend_from_slice(&s);
+        return Ok(());
+    }
+    if s.contains(&0) {
+        return Err(frame.arg_error(arg, "string contains zeros"));
+    }
+    let spec = check_format(frame, form, L_FMTFLAGSC, true)?;
+    match spec.precision {
+        // no precision and the string is too long to be formatted
+        None if s.len() >= 100 => out.extend_from_slice(&s),
+        precision => {
+            let len = precision.map_or(s.len(), |p| p.min(s.len()));
+            spec.pad("", &s[..len], false, out);
+        },
+    }
+    Ok(())
+}
+
+pub fn format(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let top = frame.arg_count();
+    let mut arg = 1;
+    let strfrmt = frame.check_string(arg)?;
+    let mut out = Vec::with_capacity(strfrmt.len());
+    let mut i = 0;
+    while i < strfrmt.len() {
+        if strfrmt[i] != L_ESC {
+            out.push(strfrmt[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        if strfrmt.get(i) == Some(&L_ESC) {
+            // %%
+            out.push(L_ESC);
+            i += 1;
+            continue;
+        }
+        arg += 1;
+        if arg > top {
+            return Err(frame.arg_error(arg, "no value"));
+        }
+        // getformat: spans flags, width and precision ('0' is included as a flag)
+        let len = strfrmt[i..].iter().take_while(|c| b"-+#0 123456789.".contains(c)).count() + 1;
+        if len >= MAX_FORMAT - 10 {
+            return Err(frame.error("invalid format (too long)"));
+        }
+        let conversion = strfrmt.get(i + len - 1).copied().unwrap_or(0);
+        let mut form = vec![L_ESC];
+        form.extend_from_slice(&strfrmt[i..(i + len).min(strfrmt.len())]);
+        i += len;
+        match conversion {
+            b'c' => {
+                let spec = check_format(frame, &form, L_FMTFLAGSC, false)?;
+                let c = frame.check_integer(arg)? as u8;
+                spec.pad("", &[c], false, &mut out);
+            },
+            b'd' | b'i' | b'u' | b'o' | b'x' | b'X' => {
+                let n = frame.check_integer(arg)?;
+                let flags = match conversion {
+                    b'd' | b'i' => L_FMTFLAGSI,
+                    b'u' => L_FMTFLAGSU,
+                    _ => L_FMTFLAGSX,
+                };
+                let spec = check_format(frame, &form, flags, true)?;
+                format_integer(&spec, n, conversion, &mut out);
+            },
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'g' | b'G' => {
+                let n = frame.check_number(arg)?;
+                let spec = check_format(frame, &form, L_FMTFLAGSF, true)?;
+                format_float(&spec, n, conversion, &mut out);
+            },
+            b'p' => {
+                let spec = check_format(frame, &form, L_FMTFLAGSC, false)?;
+                let pointer = match frame.get_arg(arg).to_pointer() {
+                    Some(pointer) => format!("{:p}", pointer),
+                    None => "(null)".to_string(),
+                };
+                spec.pad("", pointer.as_bytes(), false, &mut out);
+            },
+            b'q' => {
+                if form.len() > 2 {
+                    return Err(frame.error("specifier '%q' cannot have modifiers"));
+                }
+                add_literal(frame, arg, &mut out)?;
+            },
+            b's' => add_string(frame, arg, &form, &mut out)?,
+            _ => {
+                // also the length modifiers of C ('l', 'h', ...)
+                let form = form.split(|c| *c == 0).next().unwrap_or(&[]);
+                return Err(frame.error(&format!("invalid conversion '{}' to 'format'", String::from_utf8_lossy(form))));
+            },
+        }
+    }
+    Ok(frame.set_return_values(&[TValue::STR(Rc::new(LuaString::new(out)))]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stdlib::test::run_chunk;
+
+    #[test]
+    fn format_matches_reference() {
+        // helpers/tests/format.lua, expected output produced by the reference interpreter
+        let output = run_chunk(include_bytes!("../../../helpers/tests/format.out"));
+        assert_eq!(output, include_str!("../../../helpers/tests/format.expected"));
+    }
+
+    #[test]
+    fn hexadecimal_floats() {
+        assert_eq!(hex_float(1.0, None, false), "1p+0");
+        assert_eq!(hex_float(0.1, None, false), "1.999999999999ap-4");
+        assert_eq!(hex_float(0.0, None, false), "0p+0");
+        assert_eq!(hex_float(f64::from_bits(1), None, false), "0.0000000000001p-1022");
+        assert_eq!(hex_float(1.5, Some(0), false), "2p+0");
+        assert_eq!(hex_float(2.5, Some(0), false), "1p+1");
+        assert_eq!(hex_float(1.96875, Some(1), false), "2.0p+0");
+        assert_eq!(hex_float(1.0, Some(3), false), "1.000p+0");
+        assert_eq!(hex_float(1.0, None, true), "1.p+0");
+        assert_eq!(hex_float(0.1, Some(14), false), "1.999999999999a0p-4");
+    }
+}
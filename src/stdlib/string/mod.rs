@@ -0,0 +1,493 @@
+/*
+ * String library (lstrlib.c). Strings are byte sequences, character classes follow the C locale
+ */
+
+pub mod pattern;
+pub mod format;
+pub mod pack;
+
+use std::{rc::Rc, cell::RefCell};
+
+use crate::{core::{dump as core_dump, types::{TValue, LuaType, LuaError, Closure, number, string::LuaString, stack::LuaStackView, table::{LuaTable, TableRef}}}, vm::{LuaVm, coerce_to_string, arith as vm_arith, meta::TMS}};
+
+use self::pattern::{MatchState, Capture, no_specials, find_plain};
+
+/* strings built by the library can't be larger than this */
+const MAXSIZE: usize = i32::MAX as usize;
+
+pub fn open(vm: &mut LuaVm) {
+    let string = super::new_lib(vm, "string", &[
+        ("byte", byte),
+        ("char", char),
+        ("dump", dump),
+        ("find", find),
+        ("format", format::format),
+        ("gmatch", gmatch),
+        ("gsub", gsub),
+        ("len", len),
+        ("lower", lower),
+        ("match", match_),
+        ("pack", pack::pack),
+        ("packsize", pack::packsize),
+        ("rep", rep),
+        ("reverse", reverse),
+        ("sub", sub),
+        ("unpack", pack::unpack),
+        ("upper", upper),
+    ]);
+    create_metatable(vm, string);
+}
+
+/**
+ * createmetatable: every string shares a metatable whose __index is the string table, so
+ * s:upper() works. Its arithmetic metamethods convert numerical strings to numbers
+ */
+fn create_metatable(vm: &mut LuaVm, string: TableRef) {
+    let metatable = LuaTable::new_ref();
+    super::register(&metatable, &[
+        ("__add", arith_add),
+        ("__sub", arith_sub),
+        ("__mul", arith_mul),
+        ("__mod", arith_mod),
+        ("__pow", arith_pow),
+        ("__div", arith_div),
+        ("__idiv", arith_idiv),
+        ("__unm", arith_unm),
+    ]);
+    metatable.borrow_mut().set_str("__index", TValue::TABLE(string));
+    vm.set_type_metatable(LuaType::String, Some(metatable));
+}
+
+/**
+ * tonum: the argument as a number, when it is one or a numerical string
+ */
+fn to_num(value: &TValue) -> Option<TValue> {
+    match value {
+        TValue::NUMINT(_) | TValue::NUMFLT(_) => Some(value.clone()),
+        TValue::STR(s) => number::str_to_number(s.as_bytes()),
+        _ => None,
+    }
+}
+
+/**
+ * trymt: the operation isn't on numbers, so defer to the second operand's metamethod
+ */
+fn try_metamethod(frame: &mut LuaStackView, event: TMS) -> Result<usize, LuaError> {
+    let (a, b) = (frame.get_arg(1).clone(), frame.get_arg(2).clone());
+    let tm = match b {
+        TValue::STR(_) => TValue::NIL,
+        _ => frame.vm.get_metafield(&b, event.name()),
+    };
+    if tm.is_nil() {
+        return Err(frame.error(&format!("attempt to {} a '{}' with a '{}'",
+            &event.name()[2..], a.type_name(), b.type_name())));
+    }
+    let result = frame.call(tm, &[a, b])?.into_iter().next().unwrap_or(TValue::NIL);
+    Ok(frame.set_return_values(&[result]))
+}
+
+fn arith(frame: &mut LuaStackView, event: TMS) -> Result<usize, LuaError> {
+    match (to_num(frame.get_arg(1)), to_num(frame.get_arg(2))) {
+        (Some(a), Some(b)) => match vm_arith::arith(event, &a, &b) {
+            Ok(result) => Ok(frame.set_return_values(&[result.unwrap_or(TValue::NIL)])),
+            Err(message) => Err(frame.runtime_error(message)),
+        },
+        _ => try_metamethod(frame, event),
+    }
+}
+
+fn arith_add(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    arith(frame, TMS::ADD)
+}
+
+fn arith_sub(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    arith(frame, TMS::SUB)
+}
+
+fn arith_mul(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    arith(frame, TMS::MUL)
+}
+
+fn arith_mod(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    arith(frame, TMS::MOD)
+}
+
+fn arith_pow(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    arith(frame, TMS::POW)
+}
+
+fn arith_div(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    arith(frame, TMS::DIV)
+}
+
+fn arith_idiv(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    arith(frame, TMS::IDIV)
+}
+
+fn arith_unm(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    arith(frame, TMS::UNM)
+}
+
+/**
+ * posrelatI: translates a relative initial position (negative means back from the end),
+ * clipped to [1, inf)
+ */
+fn pos_relative_init(pos: i64, len: usize) -> usize {
+    if pos > 0 {
+        pos as usize
+    } else if pos == 0 || pos < -(len as i64) {
+        1
+    } else {
+        (len as i64 + pos + 1) as usize
+    }
+}
+
+/**
+ * getendpos: optional end position, negative means back from the end, clipped to [0, len]
+ */
+fn end_position(frame: &LuaStackView, arg: usize, default: i64, len: usize) -> Result<usize, LuaError> {
+    let pos = frame.opt_integer(arg, default)?;
+    Ok(if pos > len as i64 {
+        len
+    } else if pos >= 0 {
+        pos as usize
+    } else if pos < -(len as i64) {
+        0
+    } else {
+        (len as i64 + pos + 1) as usize
+    })
+}
+
+fn len(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    Ok(frame.set_return_values(&[TValue::NUMINT(s.len() as i64)]))
+}
+
+fn sub(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    let start = pos_relative_init(frame.check_integer(2)?, s.len());
+    let end = end_position(frame, 3, -1, s.len())?;
+    let result = if start <= end { &s[start - 1..end] } else { &[][..] };
+    Ok(frame.set_return_values(&[TValue::from_bytes(result)]))
+}
+
+fn reverse(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    let result: Vec<u8> = s.iter().rev().copied().collect();
+    Ok(frame.set_return_values(&[TValue::from_bytes(&result)]))
+}
+
+fn lower(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    Ok(frame.set_return_values(&[TValue::from_bytes(&s.to_ascii_lowercase())]))
+}
+
+fn upper(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    Ok(frame.set_return_values(&[TValue::from_bytes(&s.to_ascii_uppercase())]))
+}
+
+fn rep(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    let n = frame.check_integer(2)?;
+    let sep = frame.opt_string(3, "")?;
+    if n <= 0 || s.is_empty() && sep.is_empty() {
+        return Ok(frame.set_return_values(&[TValue::from_str("")]));
+    }
+    let unit = s.len() + sep.len();
+    if unit > MAXSIZE / n as usize {
+        return Err(frame.error("resulting string too large"));
+    }
+    let mut result = Vec::with_capacity(n as usize * unit);
+    for i in 0..n {
+        if i > 0 {
+            result.extend_from_slice(&sep);
+        }
+        result.extend_from_slice(&s);
+    }
+    Ok(frame.set_return_values(&[TValue::STR(Rc::new(LuaString::new(result)))]))
+}
+
+fn byte(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    let pi = frame.opt_integer(2, 1)?;
+    let posi = pos_relative_init(pi, s.len());
+    let pose = end_position(frame, 3, pi, s.len())?;
+    if posi > pose {
+        // empty interval; return no values
+        return Ok(0);
+    }
+    if pose - posi >= i32::MAX as usize {
+        return Err(frame.error("string slice too long"));
+    }
+    let bytes: Vec<TValue> = s[posi - 1..pose].iter().map(|c| TValue::NUMINT(*c as i64)).collect();
+    Ok(frame.set_return_values(&bytes))
+}
+
+fn char(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let mut result = Vec::with_capacity(frame.arg_count());
+    for i in 1..=frame.arg_count() {
+        let c = frame.check_integer(i)? as u64;
+        if c > u8::MAX as u64 {
+            return Err(frame.arg_error(i, "value out of range"));
+        }
+        result.push(c as u8);
+    }
+    Ok(frame.set_return_values(&[TValue::STR(Rc::new(LuaString::new(result)))]))
+}
+
+/**
+ * str_dump: the binary chunk of a Lua function, loadable with 'load'
+ */
+fn dump(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let function = frame.check_function(1)?;
+    let strip = !frame.get_arg(2).is_falsy();
+    let chunk = match function.as_ref() {
+        Closure::Lua(closure) => core_dump::dump(&closure.proto, strip),
+        Closure::C(_) => return Err(frame.error("unable to dump given function")),
+    };
+    Ok(frame.set_return_values(&[TValue::STR(Rc::new(LuaString::new(chunk)))]))
+}
+
+fn capture_value(capture: Capture) -> TValue {
+    match capture {
+        Capture::Str(s) => TValue::from_bytes(s),
+        Capture::Position(position) => TValue::NUMINT(position as i64),
+    }
+}
+
+fn captures_to_values(frame: &LuaStackView, ms: &MatchState, s: Option<usize>, e: usize) -> Result<Vec<TValue>, LuaError> {
+    let captures = ms.get_captures(s, e).map_err(|message| frame.error(&message))?;
+    Ok(captures.into_iter().map(capture_value).collect())
+}
+
+/**
+ * str_find_aux: shared by 'find', which returns the match positions, and 'match'
+ */
+fn find_aux(frame: &mut LuaStackView, find: bool) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    let p = frame.check_string(2)?;
+    let init = pos_relative_init(frame.opt_integer(3, 1)?, s.len()) - 1;
+    if init > s.len() {
+        // start after string's end: cannot find anything
+        return Ok(frame.set_return_values(&[TValue::NIL]));
+    }
+    // explicit request or no special characters?
+    if find && (!frame.get_arg(4).is_falsy() || no_specials(&p)) {
+        if let Some(found) = find_plain(&s[init..], &p) {
+            let start = init + found;
+            return Ok(frame.set_return_values(&[TValue::NUMINT(start as i64 + 1), TValue::NUMINT((start + p.len()) as i64)]));
+        }
+    } else {
+        let anchor = p.first() == Some(&b'^');
+        let pattern = if anchor { &p[1..] } else { &p[..] };
+        let mut ms = MatchState::new(&s, pattern);
+        let mut s1 = init;
+        loop {
+            ms.reset();
+            if let Some(end) = ms.do_match(s1, 0).map_err(|message| frame.error(&message))? {
+                let mut results = vec![];
+                if find {
+                    results.push(TValue::NUMINT(s1 as i64 + 1));
+                    results.push(TValue::NUMINT(end as i64));
+                    results.extend(captures_to_values(frame, &ms, None, 0)?);
+                } else {
+                    results.extend(captures_to_values(frame, &ms, Some(s1), end)?);
+                }
+                return Ok(frame.set_return_values(&results));
+            }
+            if s1 >= s.len() || anchor {
+                break;
+            }
+            s1 += 1;
+        }
+    }
+    Ok(frame.set_return_values(&[TValue::NIL]))
+}
+
+fn find(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    find_aux(frame, true)
+}
+
+fn match_(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    find_aux(frame, false)
+}
+
+/**
+ * State of a 'gmatch' iterator
+ */
+struct GMatchState {
+    src: Rc<LuaString>,
+    pattern: Rc<LuaString>,
+    position: usize,
+    last_match: Option<usize>,
+}
+
+fn gmatch_aux(frame: &mut LuaStackView, state: &mut GMatchState) -> Result<usize, LuaError> {
+    let (src, pattern) = (state.src.clone(), state.pattern.clone());
+    let mut ms = MatchState::new(&src, &pattern);
+    for start in state.position..=src.len() {
+        ms.reset();
+        match ms.do_match(start, 0).map_err(|message| frame.error(&message))? {
+            Some(end) if Some(end) != state.last_match => {
+                state.position = end;
+                state.last_match = Some(end);
+                let captures = captures_to_values(frame, &ms, Some(start), end)?;
+                return Ok(frame.set_return_values(&captures));
+            },
+            _ => {},
+        }
+    }
+    state.position = src.len() + 1;
+    Ok(0)
+}
+
+fn gmatch(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let src = frame.check_string(1)?;
+    let pattern = frame.check_string(2)?;
+    // start after the end of the string: nothing to find
+    let position = pos_relative_init(frame.opt_integer(3, 1)?, src.len()).min(src.len() + 2) - 1;
+    let state = RefCell::new(GMatchState { src, pattern, position, last_match: None });
+    let iterator = Closure::new_native(move |frame| gmatch_aux(frame, &mut state.borrow_mut()), vec![]);
+    Ok(frame.set_return_values(&[TValue::CLOSURE(Rc::new(iterator))]))
+}
+
+/**
+ * add_s: expands the replacement string, '%0' is the whole match and '%1'..'%9' the captures
+ */
+fn add_string(frame: &LuaStackView, ms: &MatchState, src: &[u8], buffer: &mut Vec<u8>, s: usize, e: usize, replacement: &[u8]) -> Result<(), LuaError> {
+    let mut i = 0;
+    while i < replacement.len() {
+        let c = replacement[i];
+        i += 1;
+        if c != b'%' {
+            buffer.push(c);
+            continue;
+        }
+        let escaped = replacement.get(i).copied().unwrap_or(0);
+        i += 1;
+        match escaped {
+            b'%' => buffer.push(b'%'),
+            b'0' => buffer.extend_from_slice(&src[s..e]),
+            d if d.is_ascii_digit() => {
+                match ms.get_capture((d - b'1') as usize, s, e).map_err(|message| frame.error(&message))? {
+                    Capture::Str(capture) => buffer.extend_from_slice(capture),
+                    Capture::Position(position) => buffer.extend_from_slice(position.to_string().as_bytes()),
+                }
+            },
+            _ => return Err(frame.error("invalid use of '%' in replacement string")),
+        }
+    }
+    Ok(())
+}
+
+/**
+ * add_value: appends the replacement of the match s..e, returns whether the subject changed
+ * (function calls and table lookups resulting in nil or false keep the original text)
+ */
+fn add_value(frame: &mut LuaStackView, ms: &MatchState, src: &[u8], buffer: &mut Vec<u8>, s: usize, e: usize, replacement: &TValue) -> Result<bool, LuaError> {
+    let value = match replacement {
+        TValue::CLOSURE(_) => {
+            let captures = captures_to_values(frame, ms, Some(s), e)?;
+            frame.call(replacement.clone(), &captures)?.into_iter().next().unwrap_or(TValue::NIL)
+        },
+        TValue::TABLE(_) => {
+            let key = ms.get_capture(0, s, e).map_err(|message| frame.error(&message))?;
+            frame.index(replacement, &capture_value(key))?
+        },
+        _ => {
+            let replacement = coerce_to_string(replacement).unwrap();
+            add_string(frame, ms, src, buffer, s, e, &replacement)?;
+            return Ok(true);
+        },
+    };
+    if value.is_falsy() {
+        buffer.extend_from_slice(&src[s..e]);
+        return Ok(false);
+    }
+    match coerce_to_string(&value) {
+        Some(value) => {
+            buffer.extend_from_slice(&value);
+            Ok(true)
+        },
+        None => Err(frame.error(&format!("invalid replacement value (a {})", value.type_name()))),
+    }
+}
+
+fn gsub(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let src = frame.check_string(1)?;
+    let p = frame.check_string(2)?;
+    let replacement = frame.get_arg(3).clone();
+    let max_s = frame.opt_integer(4, src.len() as i64 + 1)?;
+    if !matches!(replacement, TValue::NUMINT(_) | TValue::NUMFLT(_) | TValue::STR(_) | TValue::CLOSURE(_) | TValue::TABLE(_)) {
+        return Err(frame.type_error(3, "string/function/table"));
+    }
+    let anchor = p.first() == Some(&b'^');
+    let pattern = if anchor { &p[1..] } else { &p[..] };
+    let mut ms = MatchState::new(&src, pattern);
+    let mut buffer = Vec::new();
+    let mut last_match = None;
+    let mut changed = false;
+    let mut position = 0;
+    let mut n = 0;
+    while n < max_s {
+        ms.reset();
+        match ms.do_match(position, 0).map_err(|message| frame.error(&message))? {
+            Some(end) if Some(end) != last_match => {
+                n += 1;
+                changed |= add_value(frame, &ms, &src, &mut buffer, position, end, &replacement)?;
+                position = end;
+                last_match = Some(end);
+            },
+            _ if position < src.len() => {
+                // otherwise, skip one character
+                buffer.push(src[position]);
+                position += 1;
+            },
+            // end of subject
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    let result = if changed {
+        buffer.extend_from_slice(&src[position..]);
+        TValue::STR(Rc::new(LuaString::new(buffer)))
+    } else {
+        frame.get_arg(1).clone()
+    };
+    Ok(frame.set_return_values(&[result, TValue::NUMINT(n)]))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stdlib::test::run_chunk;
+
+    #[test]
+    fn string_library_matches_reference() {
+        // helpers/tests/string.lua, expected output produced by the reference interpreter
+        let output = run_chunk(include_bytes!("../../../helpers/tests/string.out"));
+        assert_eq!(output, include_str!("../../../helpers/tests/string.expected"));
+    }
+
+    #[test]
+    fn pack_matches_reference() {
+        let output = run_chunk(include_bytes!("../../../helpers/tests/pack.out"));
+        assert_eq!(output, include_str!("../../../helpers/tests/pack.expected"));
+    }
+
+    #[test]
+    fn string_methods_match_reference() {
+        // helpers/tests/string_methods.lua
+        let output = run_chunk(include_bytes!("../../../helpers/tests/string_methods.out"));
+        assert_eq!(output, include_str!("../../../helpers/tests/string_methods.expected"));
+    }
+
+    #[test]
+    fn dump_matches_reference() {
+        // helpers/tests/dump.lua
+        let output = run_chunk(include_bytes!("../../../helpers/tests/dump.out"));
+        assert_eq!(output, include_str!("../../../helpers/tests/dump.expected"));
+    }
+}
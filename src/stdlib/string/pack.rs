@@ -0,0 +1,379 @@
+/*
+ * string.pack, string.unpack and string.packsize (the PACK/UNPACK part of lstrlib.c).
+ * Native sizes and alignment are the ones of a 64 bit C implementation
+ */
+
+use std::rc::Rc;
+
+use crate::core::types::{TValue, LuaError, string::LuaString, stack::LuaStackView};
+
+use super::{pos_relative_init, MAXSIZE};
+
+/* value used for padding */
+const LUAL_PACKPADBYTE: u8 = 0x00;
+/* maximum size for the binary representation of an integer */
+const MAXINTSIZE: usize = 16;
+/* size of a lua_Integer */
+const SZINT: usize = 8;
+/* size of size_t, also the size of C's 'long' */
+const SZSIZET: usize = 8;
+/* alignment of the most demanding native type (LUAI_MAXALIGN) */
+const MAXALIGN: usize = 8;
+const NATIVE_LITTLE: bool = cfg!(target_endian = "little");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KOption {
+    Int,        /* signed integers */
+    Uint,       /* unsigned integers */
+    Float,      /* single-precision floating-point numbers */
+    Number,     /* Lua "native" floating-point numbers */
+    Double,     /* double-precision floating-point numbers */
+    Char,       /* fixed-length strings */
+    String,     /* strings with prefixed length */
+    Zstr,       /* zero-terminated strings */
+    Padding,    /* padding */
+    Paddalign,  /* padding for alignment */
+    Nop,        /* no-op (configuration or spaces) */
+}
+
+/**
+ * State of a format string being read: current option and the settings made so far
+ */
+struct Header<'a> {
+    fmt: &'a [u8],
+    position: usize,
+    little: bool,
+    max_align: usize,
+}
+
+impl<'a> Header<'a> {
+    /* the format is a C string: it ends at the first zero */
+    fn new(fmt: &'a [u8]) -> Self {
+        let end = fmt.iter().position(|c| *c == 0).unwrap_or(fmt.len());
+        Self { fmt: &fmt[..end], position: 0, little: NATIVE_LITTLE, max_align: 1 }
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.fmt.len()
+    }
+
+    /**
+     * getnum: an integer numeral or 'default' when there is none
+     */
+    fn get_num(&mut self, default: i64) -> i64 {
+        if !self.fmt.get(self.position).is_some_and(|c| c.is_ascii_digit()) {
+            return default;
+        }
+        let mut a = 0;
+        loop {
+            a = a * 10 + (self.fmt[self.position] - b'0') as i64;
+            self.position += 1;
+            if !(self.fmt.get(self.position).is_some_and(|c| c.is_ascii_digit()) && a <= (MAXSIZE as i64 - 9) / 10) {
+                return a;
+            }
+        }
+    }
+
+    fn get_num_limit(&mut self, frame: &LuaStackView, default: usize) -> Result<usize, LuaError> {
+        let size = self.get_num(default as i64);
+        if size > MAXINTSIZE as i64 || size <= 0 {
+            return Err(frame.error(&format!("integral size ({}) out of limits [1,{}]", size, MAXINTSIZE)));
+        }
+        Ok(size as usize)
+    }
+
+    /**
+     * getoption: reads and classifies the next option and its size
+     */
+    fn get_option(&mut self, frame: &LuaStackView) -> Result<(KOption, usize), LuaError> {
+        let opt = self.fmt[self.position];
+        self.position += 1;
+        let option = match opt {
+            b'b' => (KOption::Int, 1),
+            b'B' => (KOption::Uint, 1),
+            b'h' => (KOption::Int, 2),
+            b'H' => (KOption::Uint, 2),
+            b'l' => (KOption::Int, SZSIZET),
+            b'L' => (KOption::Uint, SZSIZET),
+            b'j' => (KOption::Int, SZINT),
+            b'J' => (KOption::Uint, SZINT),
+            b'T' => (KOption::Uint, SZSIZET),
+            b'f' => (KOption::Float, 4),
+            b'n' => (KOption::Number, 8),
+            b'd' => (KOption::Double, 8),
+            b'i' => (KOption::Int, self.get_num_limit(frame, 4)?),
+            b'I' => (KOption::Uint, self.get_num_limit(frame, 4)?),
+            b's' => (KOption::String, self.get_num_limit(frame, SZSIZET)?),
+            b'c' => match self.get_num(-1) {
+                -1 => return Err(frame.error("missing size for format option 'c'")),
+                size => (KOption::Char, size as usize),
+            },
+            b'z' => (KOption::Zstr, 0),
+            b'x' => (KOption::Padding, 1),
+            b'X' => (KOption::Paddalign, 0),
+            b' ' => (KOption::Nop, 0),
+            b'<' => {
+                self.little = true;
+                (KOption::Nop, 0)
+            },
+            b'>' => {
+                self.little = false;
+                (KOption::Nop, 0)
+            },
+            b'=' => {
+                self.little = NATIVE_LITTLE;
+                (KOption::Nop, 0)
+            },
+            b'!' => {
+                self.max_align = self.get_num_limit(frame, MAXALIGN)?;
+                (KOption::Nop, 0)
+            },
+            _ => return Err(frame.error(&format!("invalid format option '{}'", opt as char))),
+        };
+        Ok(option)
+    }
+
+    /**
+     * getdetails: the next option, its size and the padding needed to align it at 'total_size'
+     */
+    fn get_details(&mut self, frame: &LuaStackView, total_size: usize) -> Result<(KOption, usize, usize), LuaError> {
+        let (option, size) = self.get_option(frame)?;
+        // usually, alignment follows size
+        let mut align = size;
+        if option == KOption::Paddalign {
+            // 'X' gets alignment from following option
+            let next = if self.at_end() { None } else { Some(self.get_option(frame)?) };
+            match next {
+                Some((next, next_align)) if next != KOption::Char && next_align != 0 => align = next_align,
+                _ => return Err(frame.arg_error(1, "invalid next option for option 'X'")),
+            }
+        }
+        if align <= 1 || option == KOption::Char {
+            return Ok((option, size, 0));
+        }
+        // enforce maximum alignment
+        let align = align.min(self.max_align);
+        if !align.is_power_of_two() {
+            return Err(frame.arg_error(1, "format asks for alignment not power of 2"));
+        }
+        Ok((option, size, (align - (total_size & (align - 1))) & (align - 1)))
+    }
+}
+
+/**
+ * packint: 'size' bytes of 'n', sign extended past the size of a Lua integer when negative
+ */
+fn pack_int(out: &mut Vec<u8>, n: u64, little: bool, size: usize, negative: bool) {
+    let mut bytes: Vec<u8> = (0..size).map(|i| if i < SZINT { (n >> (i * 8)) as u8 } else if negative { 0xff } else { 0 }).collect();
+    if !little {
+        bytes.reverse();
+    }
+    out.extend_from_slice(&bytes);
+}
+
+fn copy_with_endian(out: &mut Vec<u8>, native_le_bytes: &[u8], little: bool) {
+    if little {
+        out.extend_from_slice(native_le_bytes);
+    } else {
+        out.extend(native_le_bytes.iter().rev());
+    }
+}
+
+/**
+ * The reference implementation pushes a nil mark after the arguments of 'pack', so the first
+ * missing argument is reported as nil instead of "no value"
+ */
+fn check_present(frame: &LuaStackView, arg: usize, expected: &str) -> Result<(), LuaError> {
+    if arg > frame.arg_count() {
+        return Err(frame.arg_error(arg, &format!("{} expected, got nil", expected)));
+    }
+    Ok(())
+}
+
+pub fn pack(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let fmt = frame.check_string(1)?;
+    let mut h = Header::new(&fmt);
+    let mut out = Vec::new();
+    let mut arg = 1;
+    let mut total_size = 0;
+    while !h.at_end() {
+        let (option, size, ntoalign) = h.get_details(frame, total_size)?;
+        total_size += ntoalign + size;
+        out.resize(out.len() + ntoalign, LUAL_PACKPADBYTE);
+        arg += 1;
+        match option {
+            KOption::Int | KOption::Uint | KOption::Float | KOption::Number | KOption::Double => check_present(frame, arg, "number")?,
+            KOption::Char | KOption::String | KOption::Zstr => check_present(frame, arg, "string")?,
+            KOption::Padding | KOption::Paddalign | KOption::Nop => {},
+        }
+        match option {
+            KOption::Int => {
+                let n = frame.check_integer(arg)?;
+                if size < SZINT {
+                    let limit = 1_i64 << (size * 8 - 1);
+                    if !(-limit <= n && n < limit) {
+                        return Err(frame.arg_error(arg, "integer overflow"));
+                    }
+                }
+                pack_int(&mut out, n as u64, h.little, size, n < 0);
+            },
+            KOption::Uint => {
+                let n = frame.check_integer(arg)?;
+                if size < SZINT && (n as u64) >= 1_u64 << (size * 8) {
+                    return Err(frame.arg_error(arg, "unsigned overflow"));
+                }
+                pack_int(&mut out, n as u64, h.little, size, false);
+            },
+            KOption::Float => {
+                let f = frame.check_number(arg)? as f32;
+                copy_with_endian(&mut out, &f.to_le_bytes(), h.little);
+            },
+            KOption::Number | KOption::Double => {
+                let f = frame.check_number(arg)?;
+                copy_with_endian(&mut out, &f.to_le_bytes(), h.little);
+            },
+            KOption::Char => {
+                let s = frame.check_string(arg)?;
+                if s.len() > size {
+                    return Err(frame.arg_error(arg, "string longer than given size"));
+                }
+                out.extend_from_slice(&s);
+                // pad extra space
+                out.resize(out.len() + size - s.len(), LUAL_PACKPADBYTE);
+            },
+            KOption::String => {
+                let s = frame.check_string(arg)?;
+                if size < SZSIZET && s.len() as u64 >= 1_u64 << (size * 8) {
+                    return Err(frame.arg_error(arg, "string length does not fit in given size"));
+                }
+                pack_int(&mut out, s.len() as u64, h.little, size, false);
+                out.extend_from_slice(&s);
+                total_size += s.len();
+            },
+            KOption::Zstr => {
+                let s = frame.check_string(arg)?;
+                if s.contains(&0) {
+                    return Err(frame.arg_error(arg, "string contains zeros"));
+                }
+                out.extend_from_slice(&s);
+                out.push(0);
+                total_size += s.len() + 1;
+            },
+            KOption::Padding | KOption::Paddalign | KOption::Nop => {
+                if option == KOption::Padding {
+                    out.push(LUAL_PACKPADBYTE);
+                }
+                // undo increment
+                arg -= 1;
+            },
+        }
+    }
+    Ok(frame.set_return_values(&[TValue::STR(Rc::new(LuaString::new(out)))]))
+}
+
+pub fn packsize(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let fmt = frame.check_string(1)?;
+    let mut h = Header::new(&fmt);
+    let mut total_size = 0;
+    while !h.at_end() {
+        let (option, size, ntoalign) = h.get_details(frame, total_size)?;
+        if option == KOption::String || option == KOption::Zstr {
+            return Err(frame.arg_error(1, "variable-length format"));
+        }
+        // total space used by option
+        let size = size + ntoalign;
+        if total_size > MAXSIZE - size.min(MAXSIZE) {
+            return Err(frame.arg_error(1, "format result too large"));
+        }
+        total_size += size;
+    }
+    Ok(frame.set_return_values(&[TValue::NUMINT(total_size as i64)]))
+}
+
+/**
+ * unpackint: sign extends integers smaller than a Lua integer, larger ones must not overflow
+ */
+fn unpack_int(frame: &LuaStackView, bytes: &[u8], little: bool, signed: bool) -> Result<i64, LuaError> {
+    let size = bytes.len();
+    let byte = |i: usize| if little { bytes[i] } else { bytes[size - 1 - i] };
+    let limit = size.min(SZINT);
+    let mut res: u64 = 0;
+    for i in (0..limit).rev() {
+        res = (res << 8) | byte(i) as u64;
+    }
+    if size < SZINT {
+        if signed {
+            let mask = 1_u64 << (size * 8 - 1);
+            res = (res ^ mask).wrapping_sub(mask);
+        }
+    } else if size > SZINT {
+        let mask = if !signed || (res as i64) >= 0 { 0 } else { 0xff };
+        if (limit..size).any(|i| byte(i) != mask) {
+            return Err(frame.error(&format!("{}-byte integer does not fit into Lua Integer", size)));
+        }
+    }
+    Ok(res as i64)
+}
+
+fn le_array<const N: usize>(bytes: &[u8], little: bool) -> [u8; N] {
+    let mut array: [u8; N] = bytes[..N].try_into().unwrap();
+    if !little {
+        array.reverse();
+    }
+    array
+}
+
+pub fn unpack(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let fmt = frame.check_string(1)?;
+    let data = frame.check_string(2)?;
+    let ld = data.len();
+    let mut pos = pos_relative_init(frame.opt_integer(3, 1)?, ld) - 1;
+    if pos > ld {
+        return Err(frame.arg_error(3, "initial position out of string"));
+    }
+    let mut h = Header::new(&fmt);
+    let mut results = Vec::new();
+    while !h.at_end() {
+        let (option, size, ntoalign) = h.get_details(frame, pos)?;
+        if ntoalign + size > ld - pos {
+            return Err(frame.arg_error(2, "data string too short"));
+        }
+        // skip alignment
+        pos += ntoalign;
+        let item = &data[pos..pos + size];
+        match option {
+            KOption::Int | KOption::Uint => {
+                results.push(TValue::NUMINT(unpack_int(frame, item, h.little, option == KOption::Int)?));
+            },
+            KOption::Float => results.push(TValue::NUMFLT(f32::from_le_bytes(le_array(item, h.little)) as f64)),
+            KOption::Number | KOption::Double => results.push(TValue::NUMFLT(f64::from_le_bytes(le_array(item, h.little)))),
+            KOption::Char => results.push(TValue::from_bytes(item)),
+            KOption::String => {
+                let len = unpack_int(frame, item, h.little, false)? as u64;
+                if len > (ld - pos - size) as u64 {
+                    return Err(frame.arg_error(2, "data string too short"));
+                }
+                let len = len as usize;
+                results.push(TValue::from_bytes(&data[pos + size..pos + size + len]));
+                // skip string
+                pos += len;
+            },
+            KOption::Zstr => {
+                let len = data[pos..].iter().position(|c| *c == 0);
+                match len {
+                    Some(len) => {
+                        results.push(TValue::from_bytes(&data[pos..pos + len]));
+                        // skip string plus final '\0'
+                        pos += len + 1;
+                    },
+                    None => return Err(frame.arg_error(2, "unfinished string for format 'z'")),
+                }
+            },
+            KOption::Padding | KOption::Paddalign | KOption::Nop => {},
+        }
+        pos += size;
+    }
+    // next position
+    results.push(TValue::NUMINT(pos as i64 + 1));
+    Ok(frame.set_return_values(&results))
+}
@@ -0,0 +1,440 @@
+/*
+ * Lua patterns (the pattern matching part of lstrlib.c). Positions are byte offsets into the
+ * subject and the pattern, reading past the end of either yields '\0' like the C strings do
+ */
+
+use crate::core::types::number::is_space;
+
+pub const LUA_MAXCAPTURES: usize = 32;
+/* maximum recursion depth for 'do_match' */
+const MAXCCALLS: usize = 200;
+const L_ESC: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptureLen {
+    Unfinished,
+    Position,
+    Len(usize),
+}
+
+/**
+ * Value of a capture: a slice of the subject or a (1-based) position
+ */
+#[derive(Debug, PartialEq)]
+pub enum Capture<'a> {
+    Str(&'a [u8]),
+    Position(usize),
+}
+
+pub type MatchResult = Result<Option<usize>, String>;
+
+pub struct MatchState<'a> {
+    src: &'a [u8],
+    pattern: &'a [u8],
+    matchdepth: usize,
+    level: usize,
+    capture: [(usize, CaptureLen); LUA_MAXCAPTURES],
+}
+
+/**
+ * Check whether the pattern has no special characters, a plain search is enough then
+ */
+pub fn no_specials(pattern: &[u8]) -> bool {
+    !pattern.iter().any(|c| SPECIALS.contains(c))
+}
+
+/**
+ * lmemfind: first occurrence of 'needle' in 'haystack'
+ */
+pub fn find_plain(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/**
+ * Character classes of the C locale
+ */
+fn match_class(c: u8, cl: u8) -> bool {
+    let res = match cl.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => is_space(c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        b'z' => c == 0, /* deprecated option */
+        _ => return cl == c,
+    };
+    if cl.is_ascii_lowercase() { res } else { !res }
+}
+
+impl<'a> MatchState<'a> {
+    /**
+     * The pattern must not include the '^' anchor, callers handle it
+     */
+    pub fn new(src: &'a [u8], pattern: &'a [u8]) -> Self {
+        Self { src, pattern, matchdepth: MAXCCALLS, level: 0, capture: [(0, CaptureLen::Unfinished); LUA_MAXCAPTURES] }
+    }
+
+    /**
+     * reprepstate: forgets the captures of a previous attempt
+     */
+    pub fn reset(&mut self) {
+        self.level = 0;
+    }
+
+    fn p_at(&self, p: usize) -> u8 {
+        self.pattern.get(p).copied().unwrap_or(0)
+    }
+
+    fn s_at(&self, s: usize) -> u8 {
+        self.src.get(s).copied().unwrap_or(0)
+    }
+
+    fn check_capture(&self, l: u8) -> Result<usize, String> {
+        let l = l as i32 - b'1' as i32;
+        if l < 0 || l as usize >= self.level || self.capture[l as usize].1 == CaptureLen::Unfinished {
+            return Err(format!("invalid capture index %{}", l + 1));
+        }
+        Ok(l as usize)
+    }
+
+    fn capture_to_close(&self) -> Result<usize, String> {
+        (0..self.level).rev()
+            .find(|level| self.capture[*level].1 == CaptureLen::Unfinished)
+            .ok_or_else(|| "invalid pattern capture".to_string())
+    }
+
+    fn class_end(&self, p: usize) -> Result<usize, String> {
+        let mut p = p;
+        let c = self.p_at(p);
+        p += 1;
+        match c {
+            L_ESC => {
+                if p >= self.pattern.len() {
+                    return Err("malformed pattern (ends with '%')".to_string());
+                }
+                Ok(p + 1)
+            },
+            b'[' => {
+                if self.p_at(p) == b'^' {
+                    p += 1;
+                }
+                // look for a ']'
+                loop {
+                    if p >= self.pattern.len() {
+                        return Err("malformed pattern (missing ']')".to_string());
+                    }
+                    let c = self.p_at(p);
+                    p += 1;
+                    if c == L_ESC && p < self.pattern.len() {
+                        // skip escapes (e.g. '%]')
+                        p += 1;
+                    }
+                    if self.p_at(p) == b']' {
+                        break;
+                    }
+                }
+                Ok(p + 1)
+            },
+            _ => Ok(p),
+        }
+    }
+
+    /**
+     * matchbracketclass: 'p' is the '[' of the set and 'ec' its closing ']'
+     */
+    fn match_bracket_class(&self, c: u8, p: usize, ec: usize) -> bool {
+        let mut p = p;
+        let mut sig = true;
+        if self.p_at(p + 1) == b'^' {
+            sig = false;
+            p += 1;
+        }
+        p += 1;
+        while p < ec {
+            if self.p_at(p) == L_ESC {
+                p += 1;
+                if match_class(c, self.p_at(p)) {
+                    return sig;
+                }
+            } else if self.p_at(p + 1) == b'-' && p + 2 < ec {
+                p += 2;
+                if self.p_at(p - 2) <= c && c <= self.p_at(p) {
+                    return sig;
+                }
+            } else if self.p_at(p) == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        if s >= self.src.len() {
+            return false;
+        }
+        let c = self.src[s];
+        match self.p_at(p) {
+            b'.' => true,
+            L_ESC => match_class(c, self.p_at(p + 1)),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pattern.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        if s >= self.src.len() || self.src[s] != self.p_at(p) {
+            return Ok(None);
+        }
+        let (b, e) = (self.p_at(p), self.p_at(p + 1));
+        let mut cont = 1;
+        for (i, c) in self.src.iter().enumerate().skip(s + 1) {
+            if *c == e {
+                cont -= 1;
+                if cont == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if *c == b {
+                cont += 1;
+            }
+        }
+        // string ends out of balance
+        Ok(None)
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> MatchResult {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        // keeps trying to match with the maximum repetitions
+        loop {
+            if let Some(res) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(res));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, s: usize, p: usize, ep: usize) -> MatchResult {
+        let mut s = s;
+        loop {
+            if let Some(res) = self.do_match(s, ep + 1)? {
+                return Ok(Some(res));
+            } else if self.single_match(s, p, ep) {
+                // try with one more repetition
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: CaptureLen) -> MatchResult {
+        let level = self.level;
+        if level >= LUA_MAXCAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.capture[level] = (s, what);
+        self.level = level + 1;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            // undo capture
+            self.level -= 1;
+        }
+        Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> MatchResult {
+        let l = self.capture_to_close()?;
+        self.capture[l].1 = CaptureLen::Len(s - self.capture[l].0);
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            // undo capture
+            self.capture[l].1 = CaptureLen::Unfinished;
+        }
+        Ok(res)
+    }
+
+    fn match_capture(&self, s: usize, l: u8) -> Result<Option<usize>, String> {
+        let l = self.check_capture(l)?;
+        let (init, len) = self.capture[l];
+        match len {
+            CaptureLen::Len(len) if self.src.len() - s >= len && self.src[init..init + len] == self.src[s..s + len] => {
+                Ok(Some(s + len))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /**
+     * Tries to match the pattern from 'p' against the subject from 's', returns the end of the match
+     */
+    pub fn do_match(&mut self, s: usize, p: usize) -> MatchResult {
+        if self.matchdepth == 0 {
+            return Err("pattern too complex".to_string());
+        }
+        self.matchdepth -= 1;
+        let result = self.match_aux(s, p);
+        self.matchdepth += 1;
+        result
+    }
+
+    fn match_aux(&mut self, s: usize, p: usize) -> MatchResult {
+        let (mut s, mut p) = (s, p);
+        // tail calls of the reference implementation are iterations of this loop
+        loop {
+            if p == self.pattern.len() {
+                return Ok(Some(s));
+            }
+            match (self.p_at(p), self.p_at(p + 1)) {
+                (b'(', b')') => return self.start_capture(s, p + 2, CaptureLen::Position),
+                (b'(', _) => return self.start_capture(s, p + 1, CaptureLen::Unfinished),
+                (b')', _) => return self.end_capture(s, p + 1),
+                (b'$', _) if p + 1 == self.pattern.len() => {
+                    return Ok(if s == self.src.len() { Some(s) } else { None });
+                },
+                (L_ESC, b'b') => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                    },
+                    None => return Ok(None),
+                },
+                (L_ESC, b'f') => {
+                    p += 2;
+                    if self.p_at(p) != b'[' {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    if !self.match_bracket_class(previous, p, ep - 1) && self.match_bracket_class(self.s_at(s), p, ep - 1) {
+                        p = ep;
+                    } else {
+                        return Ok(None);
+                    }
+                },
+                (L_ESC, l) if l.is_ascii_digit() => match self.match_capture(s, l)? {
+                    Some(end) => {
+                        s = end;
+                        p += 2;
+                    },
+                    None => return Ok(None),
+                },
+                _ => {
+                    // pattern class plus optional suffix
+                    let ep = self.class_end(p)?;
+                    let suffix = self.p_at(ep);
+                    if !self.single_match(s, p, ep) {
+                        if matches!(suffix, b'*' | b'?' | b'-') {
+                            // accept empty
+                            p = ep + 1;
+                            continue;
+                        }
+                        return Ok(None);
+                    }
+                    // matched once
+                    match suffix {
+                        b'?' => {
+                            if let Some(res) = self.do_match(s + 1, ep + 1)? {
+                                return Ok(Some(res));
+                            }
+                            p = ep + 1;
+                        },
+                        b'+' => return self.max_expand(s + 1, p, ep),
+                        b'*' => return self.max_expand(s, p, ep),
+                        b'-' => return self.min_expand(s, p, ep),
+                        _ => {
+                            s += 1;
+                            p = ep;
+                        },
+                    }
+                },
+            }
+        }
+    }
+
+    /**
+     * get_onecapture: the i-th capture, or the whole match s..e when there are no captures
+     */
+    pub fn get_capture(&self, i: usize, s: usize, e: usize) -> Result<Capture<'a>, String> {
+        if i >= self.level {
+            if i != 0 {
+                return Err(format!("invalid capture index %{}", i + 1));
+            }
+            return Ok(Capture::Str(&self.src[s..e]));
+        }
+        let (init, len) = self.capture[i];
+        match len {
+            CaptureLen::Unfinished => Err("unfinished capture".to_string()),
+            CaptureLen::Position => Ok(Capture::Position(init + 1)),
+            CaptureLen::Len(len) => Ok(Capture::Str(&self.src[init..init + len])),
+        }
+    }
+
+    /**
+     * push_captures: every capture, or the whole match when there are none and 's' is given
+     */
+    pub fn get_captures(&self, s: Option<usize>, e: usize) -> Result<Vec<Capture<'a>>, String> {
+        let levels = if self.level == 0 && s.is_some() { 1 } else { self.level };
+        (0..levels).map(|i| self.get_capture(i, s.unwrap_or(0), e)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn find(src: &str, pattern: &str) -> Result<Option<(usize, usize, Vec<Capture<'static>>)>, String> {
+        let src: &'static [u8] = Box::leak(src.as_bytes().to_vec().into_boxed_slice());
+        let pattern: &'static [u8] = Box::leak(pattern.as_bytes().to_vec().into_boxed_slice());
+        let mut ms = MatchState::new(src, pattern);
+        for start in 0..=src.len() {
+            ms.reset();
+            if let Some(end) = ms.do_match(start, 0)? {
+                return Ok(Some((start, end, ms.get_captures(None, end)?)));
+            }
+        }
+        Ok(None)
+    }
+
+    #[test]
+    fn classes_sets_and_repetitions() {
+        assert_eq!(find("hello world", "o%s*w").unwrap().map(|m| (m.0, m.1)), Some((4, 7)));
+        assert_eq!(find("key = value", "(%w+)%s*=%s*(%w+)").unwrap().unwrap().2,
+            vec![Capture::Str(b"key"), Capture::Str(b"value")]);
+        assert_eq!(find("[[x]]", "[%]]+").unwrap().map(|m| (m.0, m.1)), Some((3, 5)));
+        assert_eq!(find("THE (quick) fox", "%((%a-)%)").unwrap().unwrap().2, vec![Capture::Str(b"quick")]);
+        assert_eq!(find("abc", "()b()").unwrap().unwrap().2, vec![Capture::Position(2), Capture::Position(3)]);
+        assert_eq!(find("x = f(a(b)c) + 1", "%b()").unwrap().map(|m| (m.0, m.1)), Some((5, 12)));
+        assert_eq!(find("THE (quick) fox", "%f[%a]%a+$").unwrap().map(|m| (m.0, m.1)), Some((12, 15)));
+        assert_eq!(find("say \"hi\" now", "([\"'])(.-)%1").unwrap().unwrap().2, vec![Capture::Str(b"\""), Capture::Str(b"hi")]);
+    }
+
+    #[test]
+    fn malformed_patterns() {
+        assert_eq!(find("a", "%").unwrap_err(), "malformed pattern (ends with '%')");
+        assert_eq!(find("a", "[a").unwrap_err(), "malformed pattern (missing ']')");
+        assert_eq!(find("a", "%b").unwrap_err(), "malformed pattern (missing arguments to '%b')");
+        assert_eq!(find("a", "%f").unwrap_err(), "missing '[' after '%f' in pattern");
+        assert_eq!(find("a", "(a").unwrap_err(), "unfinished capture");
+        assert_eq!(find("a", "a)").unwrap_err(), "invalid pattern capture");
+        assert_eq!(find("a", "%1").unwrap_err(), "invalid capture index %1");
+        assert_eq!(find(&"a".repeat(300), &".?".repeat(300)).unwrap_err(), "pattern too complex");
+    }
+}
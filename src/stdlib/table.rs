@@ -0,0 +1,398 @@
+/*
+ * Table library (ltablib.c). Elements are read and written with metamethods, so objects
+ * with '__index', '__newindex' and '__len' can stand in for tables
+ */
+
+use std::{cell::RefCell, rc::Rc, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{core::types::{TValue, LuaError, string::LuaString, table::LuaTable, stack::LuaStackView}, vm::{LuaVm, coerce_to_string}};
+
+/* operations that an object must define to mimic a table */
+const TAB_R: u8 = 1;
+const TAB_W: u8 = 2;
+const TAB_L: u8 = 4;
+const TAB_RW: u8 = TAB_R | TAB_W;
+
+/* arrays larger than this may use randomized pivots */
+const RANLIMIT: u32 = 100;
+
+pub fn open(vm: &mut LuaVm) {
+    super::new_lib(vm, "table", &[
+        ("concat", concat),
+        ("insert", insert),
+        ("pack", pack),
+        ("unpack", unpack),
+        ("remove", remove),
+        ("move", move_),
+        ("sort", sort),
+    ]);
+}
+
+/**
+ * checktab: 'arg' is a table or has a metatable with the required metamethods
+ */
+fn check_tab(frame: &LuaStackView, arg: usize, what: u8) -> Result<(), LuaError> {
+    let value = frame.get_arg(arg);
+    if let TValue::TABLE(_) = value {
+        return Ok(());
+    }
+    if let Some(metatable) = frame.vm.get_metatable(value) {
+        let metatable = metatable.borrow();
+        let has = |field: &str| !metatable.get_str(field).is_nil();
+        if (what & TAB_R == 0 || has("__index")) && (what & TAB_W == 0 || has("__newindex")) && (what & TAB_L == 0 || has("__len")) {
+            return Ok(());
+        }
+    }
+    Err(frame.type_error(arg, "table"))
+}
+
+fn aux_getn(frame: &mut LuaStackView, arg: usize, what: u8) -> Result<i64, LuaError> {
+    check_tab(frame, arg, what | TAB_L)?;
+    let value = frame.get_arg(arg).clone();
+    frame.len(&value)
+}
+
+fn get_i(frame: &mut LuaStackView, t: &TValue, i: i64) -> Result<TValue, LuaError> {
+    frame.index(t, &TValue::NUMINT(i))
+}
+
+fn set_i(frame: &mut LuaStackView, t: &TValue, i: i64, value: TValue) -> Result<(), LuaError> {
+    frame.set_index(t, TValue::NUMINT(i), value)
+}
+
+fn insert(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let t = frame.get_arg(1).clone();
+    // first empty element
+    let e = aux_getn(frame, 1, TAB_RW)?.wrapping_add(1);
+    let pos = match frame.arg_count() {
+        // insert new element at the end
+        2 => e,
+        3 => {
+            let pos = frame.check_integer(2)?;
+            // check whether 'pos' is in [1, e]
+            if (pos as u64).wrapping_sub(1) >= e as u64 {
+                return Err(frame.arg_error(2, "position out of bounds"));
+            }
+            // move up elements
+            let mut i = e;
+            while i > pos {
+                let value = get_i(frame, &t, i - 1)?;
+                set_i(frame, &t, i, value)?;
+                i -= 1;
+            }
+            pos
+        },
+        _ => return Err(frame.error("wrong number of arguments to 'insert'")),
+    };
+    let value = frame.get_arg(frame.arg_count()).clone();
+    set_i(frame, &t, pos, value)?;
+    Ok(0)
+}
+
+fn remove(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let t = frame.get_arg(1).clone();
+    let size = aux_getn(frame, 1, TAB_RW)?;
+    let mut pos = frame.opt_integer(2, size)?;
+    // check whether 'pos' is in [1, size + 1] when it is given
+    if pos != size && (pos as u64).wrapping_sub(1) > size as u64 {
+        return Err(frame.arg_error(2, "position out of bounds"));
+    }
+    let result = get_i(frame, &t, pos)?;
+    while pos < size {
+        let value = get_i(frame, &t, pos + 1)?;
+        set_i(frame, &t, pos, value)?;
+        pos += 1;
+    }
+    set_i(frame, &t, pos, TValue::NIL)?;
+    Ok(frame.set_return_values(&[result]))
+}
+
+/**
+ * tmove: copies a1[f..e] into a2[t..], in increasing order unless the ranges overlap
+ * in a way that would overwrite elements not yet copied
+ */
+fn move_(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let f = frame.check_integer(2)?;
+    let e = frame.check_integer(3)?;
+    let t = frame.check_integer(4)?;
+    let tt = if frame.get_arg(5).is_nil() { 1 } else { 5 };
+    check_tab(frame, 1, TAB_R)?;
+    check_tab(frame, tt, TAB_W)?;
+    let source = frame.get_arg(1).clone();
+    let destination = frame.get_arg(tt).clone();
+    if e >= f {
+        if !(f > 0 || e < i64::MAX + f) {
+            return Err(frame.arg_error(3, "too many elements to move"));
+        }
+        // number of elements to move
+        let n = e - f + 1;
+        if t > i64::MAX - n + 1 {
+            return Err(frame.arg_error(4, "destination wrap around"));
+        }
+        let other_table = tt != 1 && !frame.vm.equals(frame.thread, &source, &destination)?;
+        if t > e || t <= f || other_table {
+            for i in 0..n {
+                let value = get_i(frame, &source, f + i)?;
+                set_i(frame, &destination, t + i, value)?;
+            }
+        } else {
+            for i in (0..n).rev() {
+                let value = get_i(frame, &source, f + i)?;
+                set_i(frame, &destination, t + i, value)?;
+            }
+        }
+    }
+    Ok(frame.set_return_values(&[destination]))
+}
+
+fn add_field(frame: &mut LuaStackView, t: &TValue, i: i64, buffer: &mut Vec<u8>) -> Result<(), LuaError> {
+    let value = get_i(frame, t, i)?;
+    match coerce_to_string(&value) {
+        Some(s) => buffer.extend_from_slice(&s),
+        None => {
+            let message = format!("invalid value ({}) at index {} in table for 'concat'", value.type_name(), i);
+            return Err(frame.error(&message));
+        },
+    }
+    Ok(())
+}
+
+fn concat(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let t = frame.get_arg(1).clone();
+    let last = aux_getn(frame, 1, TAB_R)?;
+    let sep = frame.opt_string(2, "")?;
+    let mut i = frame.opt_integer(3, 1)?;
+    let last = frame.opt_integer(4, last)?;
+    let mut buffer = Vec::new();
+    while i < last {
+        add_field(frame, &t, i, &mut buffer)?;
+        buffer.extend_from_slice(&sep);
+        i += 1;
+    }
+    // add last value (if interval was not empty)
+    if i == last {
+        add_field(frame, &t, i, &mut buffer)?;
+    }
+    Ok(frame.set_return_values(&[TValue::STR(Rc::new(LuaString::new(buffer)))]))
+}
+
+fn pack(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let n = frame.arg_count();
+    let mut table = LuaTable::with_capacity(n, 1);
+    for (i, value) in frame.args().into_iter().enumerate() {
+        table.set_int(i as i64 + 1, value);
+    }
+    table.set_str("n", TValue::NUMINT(n as i64));
+    Ok(frame.set_return_values(&[TValue::TABLE(Rc::new(RefCell::new(table)))]))
+}
+
+fn unpack(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let t = frame.get_arg(1).clone();
+    let mut i = frame.opt_integer(2, 1)?;
+    let e = if frame.get_arg(3).is_nil() { frame.len(&t)? } else { frame.check_integer(3)? };
+    if i > e {
+        // empty range
+        return Ok(0);
+    }
+    // number of elements minus 1 (avoid overflows)
+    let n = (e as u64).wrapping_sub(i as u64);
+    if n >= i32::MAX as u64 || !frame.check_stack(n as usize + 1) {
+        return Err(frame.error("too many results to unpack"));
+    }
+    let mut values = Vec::with_capacity(n as usize + 1);
+    while i < e {
+        values.push(get_i(frame, &t, i)?);
+        i += 1;
+    }
+    values.push(get_i(frame, &t, e)?);
+    Ok(frame.set_return_values(&values))
+}
+
+/*
+ * Quicksort (based on 'Algorithms in MODULA-3', Robert Sedgewick; Addison-Wesley, 1993),
+ * the same comparisons in the same order as the reference implementation
+ */
+
+/**
+ * l_randomizePivot: "random" value used when a partition is too imbalanced
+ */
+fn randomize_pivot() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now.as_secs() as u32).wrapping_add(now.subsec_nanos())
+}
+
+struct Sorter<'a, 'b> {
+    frame: &'a mut LuaStackView<'b>,
+    table: TValue,
+    comparator: TValue,
+}
+
+impl Sorter<'_, '_> {
+    fn get(&mut self, i: u32) -> Result<TValue, LuaError> {
+        let table = self.table.clone();
+        get_i(self.frame, &table, i as i64)
+    }
+
+    fn set(&mut self, i: u32, value: TValue) -> Result<(), LuaError> {
+        let table = self.table.clone();
+        set_i(self.frame, &table, i as i64, value)
+    }
+
+    /* sort_comp: whether 'a' goes before 'b' */
+    fn less(&mut self, a: &TValue, b: &TValue) -> Result<bool, LuaError> {
+        if self.comparator.is_nil() {
+            return self.frame.vm.less_than(self.frame.thread, a, b);
+        }
+        let results = self.frame.call(self.comparator.clone(), &[a.clone(), b.clone()])?;
+        Ok(!results.first().unwrap_or(&TValue::NIL).is_falsy())
+    }
+
+    fn order_error(&self) -> LuaError {
+        self.frame.error("invalid order function for sorting")
+    }
+
+    /**
+     * Precondition: a[lo] <= P == a[up - 1] <= a[up], so it only needs to partition lo + 1 .. up - 2.
+     * Postcondition: a[lo .. i - 1] <= a[i] == P <= a[i + 1 .. up], returns 'i'
+     */
+    fn partition(&mut self, lo: u32, up: u32, pivot: &TValue) -> Result<u32, LuaError> {
+        let mut i = lo;
+        let mut j = up - 1;
+        // loop invariant: a[lo .. i] <= P <= a[j .. up]
+        loop {
+            // repeat ++i while a[i] < P
+            let a_i = loop {
+                i += 1;
+                let a_i = self.get(i)?;
+                if !self.less(&a_i, pivot)? {
+                    break a_i;
+                }
+                if i == up - 1 {
+                    // a[i] < P but a[up - 1] == P
+                    return Err(self.order_error());
+                }
+            };
+            // repeat --j while P < a[j]
+            let a_j = loop {
+                j = j.wrapping_sub(1);
+                let a_j = self.get(j)?;
+                if !self.less(pivot, &a_j)? {
+                    break a_j;
+                }
+                if j < i {
+                    // j < i but a[j] > P
+                    return Err(self.order_error());
+                }
+            };
+            if j < i {
+                // no elements out of place: swap pivot (a[up - 1]) with a[i]
+                self.set(up - 1, a_i)?;
+                self.set(i, pivot.clone())?;
+                return Ok(i);
+            }
+            // otherwise, swap a[i] - a[j] to restore invariant and repeat
+            self.set(i, a_j)?;
+            self.set(j, a_i)?;
+        }
+    }
+
+    /**
+     * choosePivot: an element in the middle (2nd-3th quarters) of [lo, up] "randomized" by 'rnd'
+     */
+    fn choose_pivot(lo: u32, up: u32, rnd: u32) -> u32 {
+        let r4 = (up - lo) / 4;
+        rnd % (r4 * 2) + (lo + r4)
+    }
+
+    fn sort(&mut self, lo: u32, up: u32, rnd: u32) -> Result<(), LuaError> {
+        let (mut lo, mut up, mut rnd) = (lo, up, rnd);
+        // loop for tail recursion
+        while lo < up {
+            // sort elements 'lo', 'p' and 'up'
+            let a_lo = self.get(lo)?;
+            let a_up = self.get(up)?;
+            if self.less(&a_up, &a_lo)? {
+                self.set(lo, a_up)?;
+                self.set(up, a_lo)?;
+            }
+            if up - lo == 1 {
+                // only 2 elements
+                return Ok(());
+            }
+            let mut p = if up - lo < RANLIMIT || rnd == 0 {
+                // middle element is a good pivot
+                (lo + up) / 2
+            } else {
+                // for larger intervals, it is worth a random pivot
+                Self::choose_pivot(lo, up, rnd)
+            };
+            let a_p = self.get(p)?;
+            let a_lo = self.get(lo)?;
+            if self.less(&a_p, &a_lo)? {
+                self.set(p, a_lo)?;
+                self.set(lo, a_p)?;
+            } else {
+                let a_up = self.get(up)?;
+                if self.less(&a_up, &a_p)? {
+                    self.set(p, a_up)?;
+                    self.set(up, a_p)?;
+                }
+            }
+            if up - lo == 2 {
+                // only 3 elements
+                return Ok(());
+            }
+            // swap the pivot (a[p]) with a[up - 1]
+            let pivot = self.get(p)?;
+            let a_up1 = self.get(up - 1)?;
+            self.set(p, a_up1)?;
+            self.set(up - 1, pivot.clone())?;
+            p = self.partition(lo, up, &pivot)?;
+            // a[lo .. p - 1] <= a[p] == P <= a[p + 1 .. up]
+            let n;
+            if p - lo < up - p {
+                // call recursively for the smaller lower interval, iterate on the upper one
+                self.sort(lo, p - 1, rnd)?;
+                n = p - lo;
+                lo = p + 1;
+            } else {
+                self.sort(p + 1, up, rnd)?;
+                n = up - p;
+                up = p - 1;
+            }
+            if up.wrapping_sub(lo) / 128 > n {
+                // partition too imbalanced: try a new randomization
+                rnd = randomize_pivot();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn sort(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let n = aux_getn(frame, 1, TAB_RW)?;
+    if n > 1 {
+        if n >= i32::MAX as i64 {
+            return Err(frame.arg_error(1, "array too big"));
+        }
+        let comparator = frame.get_arg(2).clone();
+        if !comparator.is_nil() && !matches!(comparator, TValue::CLOSURE(_)) {
+            return Err(frame.type_error(2, "function"));
+        }
+        let table = frame.get_arg(1).clone();
+        Sorter { frame, table, comparator }.sort(1, n as u32, 0)?;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stdlib::test::run_chunk;
+
+    #[test]
+    fn table_library_matches_reference() {
+        // helpers/tests/table.lua, including proxies that only implement the metamethods
+        let output = run_chunk(include_bytes!("../../helpers/tests/table.out"));
+        assert_eq!(output, include_str!("../../helpers/tests/table.expected"));
+    }
+}
@@ -0,0 +1,278 @@
+/*
+ * UTF-8 library (lutf8lib.c), over byte strings that are not necessarily valid UTF-8
+ */
+
+use std::rc::Rc;
+
+use crate::{core::types::{TValue, LuaError, string::{LuaString, utf8_escape}, number::F2IMode, stack::LuaStackView}, vm::LuaVm};
+
+const MAXUNICODE: u32 = 0x10FFFF;
+const MAXUTF: u32 = 0x7FFFFFFF;
+const MSG_INVALID: &str = "invalid UTF-8 code";
+
+/* pattern to match a single UTF-8 character */
+const UTF8PATT: &[u8] = b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*";
+
+pub fn open(vm: &mut LuaVm) {
+    let library = super::new_lib(vm, "utf8", &[
+        ("offset", byte_offset),
+        ("codepoint", codepoint),
+        ("char", utf_char),
+        ("len", utf_len),
+        ("codes", iter_codes),
+    ]);
+    library.borrow_mut().set_str("charpattern", TValue::from_bytes(UTF8PATT));
+}
+
+/* byte at 'i', or the terminating zero of C strings past the end */
+fn byte_at(s: &[u8], i: usize) -> u8 {
+    s.get(i).copied().unwrap_or(0)
+}
+
+fn is_cont(c: u8) -> bool {
+    c & 0xC0 == 0x80
+}
+
+/**
+ * u_posrelat: translates a relative string position, negative means back from the end
+ */
+fn pos_relative(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+/**
+ * utf8_decode: the code point starting at 's[i]' and the position after it, or None if the
+ * sequence is invalid. 'limits' stores the minimum value for each sequence length to reject
+ * overlong representations; its first entry rejects non-ascii bytes with no continuation bytes
+ */
+fn decode(s: &[u8], i: usize, strict: bool) -> Option<(u32, usize)> {
+    const LIMITS: [u32; 6] = [!0, 0x80, 0x800, 0x10000, 0x200000, 0x4000000];
+    let mut c = byte_at(s, i) as u32;
+    let mut result: u32 = 0;
+    let mut count = 0;
+    if c < 0x80 {
+        result = c;
+    } else {
+        // while it needs continuation bytes...
+        while c & 0x40 != 0 {
+            count += 1;
+            let cc = byte_at(s, i + count);
+            if !is_cont(cc) {
+                return None;
+            }
+            // add lower 6 bits from continuation byte
+            result = (result << 6) | (cc & 0x3F) as u32;
+            c <<= 1;
+        }
+        // add first byte
+        if count > 5 {
+            return None;
+        }
+        result |= (c & 0x7F) << (count * 5);
+        if result > MAXUTF || result < LIMITS[count] {
+            return None;
+        }
+    }
+    // check for invalid code points: too large or surrogates
+    if strict && (result > MAXUNICODE || (0xD800..=0xDFFF).contains(&result)) {
+        return None;
+    }
+    Some((result, i + count + 1))
+}
+
+/**
+ * utflen: number of characters that start in [i, j], or fail plus the position of the first
+ * invalid byte
+ */
+fn utf_len(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    let len = s.len() as i64;
+    let mut posi = pos_relative(frame.opt_integer(2, 1)?, s.len());
+    let mut posj = pos_relative(frame.opt_integer(3, -1)?, s.len());
+    let lax = !frame.get_arg(4).is_falsy();
+    if !(1 <= posi && posi - 1 <= len) {
+        return Err(frame.arg_error(2, "initial position out of bounds"));
+    }
+    posi -= 1;
+    posj -= 1;
+    if posj >= len {
+        return Err(frame.arg_error(3, "final position out of bounds"));
+    }
+    let mut n = 0;
+    while posi <= posj {
+        match decode(&s, posi as usize, !lax) {
+            Some((_, next)) => posi = next as i64,
+            None => return Ok(frame.set_return_values(&[TValue::NIL, TValue::NUMINT(posi + 1)])),
+        }
+        n += 1;
+    }
+    Ok(frame.set_return_values(&[TValue::NUMINT(n)]))
+}
+
+/**
+ * codepoint(s, [i, [j [, lax]]]): the code points of all characters that start in [i, j]
+ */
+fn codepoint(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    let posi = pos_relative(frame.opt_integer(2, 1)?, s.len());
+    let pose = pos_relative(frame.opt_integer(3, posi)?, s.len());
+    let lax = !frame.get_arg(4).is_falsy();
+    if posi < 1 {
+        return Err(frame.arg_error(2, "out of bounds"));
+    }
+    if pose > s.len() as i64 {
+        return Err(frame.arg_error(3, "out of bounds"));
+    }
+    if posi > pose {
+        // empty interval, no values
+        return Ok(0);
+    }
+    if pose - posi >= i32::MAX as i64 {
+        return Err(frame.error("string slice too long"));
+    }
+    // upper bound for the number of results
+    if !frame.check_stack((pose - posi) as usize + 1) {
+        return Err(frame.error("stack overflow (string slice too long)"));
+    }
+    let mut codes = Vec::new();
+    let mut i = posi as usize - 1;
+    while i < pose as usize {
+        let (code, next) = decode(&s, i, !lax).ok_or_else(|| frame.error(MSG_INVALID))?;
+        codes.push(TValue::NUMINT(code as i64));
+        i = next;
+    }
+    Ok(frame.set_return_values(&codes))
+}
+
+fn push_utf_char(frame: &LuaStackView, arg: usize, buffer: &mut Vec<u8>) -> Result<(), LuaError> {
+    let code = frame.check_integer(arg)? as u64;
+    if code > MAXUTF as u64 {
+        return Err(frame.arg_error(arg, "value out of range"));
+    }
+    utf8_escape(buffer, code as u32);
+    Ok(())
+}
+
+/**
+ * utfchar(n1, n2, ...): char(n1)..char(n2)...
+ */
+fn utf_char(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let mut buffer = Vec::new();
+    for arg in 1..=frame.arg_count() {
+        push_utf_char(frame, arg, &mut buffer)?;
+    }
+    Ok(frame.set_return_values(&[TValue::STR(Rc::new(LuaString::new(buffer)))]))
+}
+
+/**
+ * offset(s, n, [i]): index where the n-th character counting from position 'i' starts;
+ * 0 means the character at 'i'
+ */
+fn byte_offset(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    let len = s.len() as i64;
+    let mut n = frame.check_integer(2)?;
+    let default = if n >= 0 { 1 } else { len + 1 };
+    let mut posi = pos_relative(frame.opt_integer(3, default)?, s.len());
+    if !(1 <= posi && posi - 1 <= len) {
+        return Err(frame.arg_error(3, "position out of bounds"));
+    }
+    posi -= 1;
+    let cont_at = |i: i64| is_cont(byte_at(&s, i as usize));
+    if n == 0 {
+        // find beginning of current byte sequence
+        while posi > 0 && cont_at(posi) {
+            posi -= 1;
+        }
+    } else {
+        if cont_at(posi) {
+            return Err(frame.error("initial position is a continuation byte"));
+        }
+        if n < 0 {
+            // move back
+            while n < 0 && posi > 0 {
+                // find beginning of previous character
+                loop {
+                    posi -= 1;
+                    if !(posi > 0 && cont_at(posi)) {
+                        break;
+                    }
+                }
+                n += 1;
+            }
+        } else {
+            // do not move for 1st character
+            n -= 1;
+            while n > 0 && posi < len {
+                // find beginning of next character (cannot pass the end)
+                loop {
+                    posi += 1;
+                    if !cont_at(posi) {
+                        break;
+                    }
+                }
+                n -= 1;
+            }
+        }
+    }
+    let result = if n == 0 { TValue::NUMINT(posi + 1) } else { TValue::NIL };
+    Ok(frame.set_return_values(&[result]))
+}
+
+fn iter_aux(frame: &mut LuaStackView, strict: bool) -> Result<usize, LuaError> {
+    let s = frame.check_string(1)?;
+    let len = s.len() as u64;
+    let mut n = frame.get_arg(2).to_integer(F2IMode::Exact).unwrap_or(0) as u64;
+    if n < len {
+        // go to next character
+        while is_cont(byte_at(&s, n as usize)) {
+            n += 1;
+        }
+    }
+    // also handles the original 'n' being negative
+    if n >= len {
+        return Ok(0);
+    }
+    match decode(&s, n as usize, strict) {
+        Some((code, next)) if !is_cont(byte_at(&s, next)) => {
+            Ok(frame.set_return_values(&[TValue::NUMINT(n as i64 + 1), TValue::NUMINT(code as i64)]))
+        },
+        _ => Err(frame.error(MSG_INVALID)),
+    }
+}
+
+fn iter_aux_strict(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    iter_aux(frame, true)
+}
+
+fn iter_aux_lax(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    iter_aux(frame, false)
+}
+
+fn iter_codes(frame: &mut LuaStackView) -> Result<usize, LuaError> {
+    let lax = !frame.get_arg(2).is_falsy();
+    let s = frame.check_string(1)?;
+    if is_cont(byte_at(&s, 0)) {
+        return Err(frame.arg_error(1, MSG_INVALID));
+    }
+    let iterator = super::native(if lax { iter_aux_lax } else { iter_aux_strict });
+    Ok(frame.set_return_values(&[iterator, TValue::STR(s), TValue::NUMINT(0)]))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stdlib::test::run_chunk;
+
+    #[test]
+    fn utf8_library_matches_reference() {
+        // helpers/tests/utf8.lua, with overlong sequences, surrogates and lax mode
+        let output = run_chunk(include_bytes!("../../helpers/tests/utf8.out"));
+        assert_eq!(output, include_str!("../../helpers/tests/utf8.expected"));
+    }
+}
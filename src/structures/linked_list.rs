@@ -49,7 +49,7 @@ impl<T> LinkedList<T> {
     }
 
     pub fn peek_mut(&mut self) -> Option<&mut T> {
-        self.head.as_ref().map(| node | { &mut node.as_mut().value })
+        self.head.as_mut().map(| node | { &mut node.as_mut().value })
     }
 
     pub fn offset_peek(&self, offset: usize) -> Option<&T> {
@@ -51,16 +51,16 @@ mod tests {
         let mut stack = Stack::<i64>::new();
         stack.push(1);
         stack.push(2);
-        assert_eq!(stack.peek().map(|v| { *v }), Some(2));
+        assert_eq!(stack.peek().copied(), Some(2));
         assert_eq!(stack.size, 2);
         stack.push(33);
-        assert_eq!(stack.peek().map(|v| { *v }), Some(33));
+        assert_eq!(stack.peek().copied(), Some(33));
         assert_eq!(stack.size, 3);
-        assert_eq!(stack.offset_peek(stack.size - 1).map(|v| { *v }), Some(1));
+        assert_eq!(stack.offset_peek(stack.size - 1).copied(), Some(1));
 
         assert_eq!(stack.pop(), Some(33));
         assert_eq!(stack.size, 2);
-        assert_eq!(stack.peek().map(|v| { *v }), Some(2));
+        assert_eq!(stack.peek().copied(), Some(2));
 
         stack.pop();
         stack.pop();
@@ -0,0 +1,240 @@
+/*
+ * Primitive arithmetic and comparisons over numbers (lvm.c/lobject.c).
+ * Anything that isn't a number is left to metamethods
+ */
+
+use std::cmp::Ordering;
+
+use crate::core::types::{TValue, number::{float_to_integer, F2IMode}};
+
+use super::meta::TMS;
+
+fn to_float(value: &TValue) -> Option<f64> {
+    match value {
+        TValue::NUMINT(i) => Some(*i as f64),
+        TValue::NUMFLT(f) => Some(*f),
+        _ => None,
+    }
+}
+
+/**
+ * luaV_tointegerns: numbers only, floats must have an exact integer representation
+ */
+pub fn to_integer_strict(value: &TValue) -> Option<i64> {
+    match value {
+        TValue::NUMINT(i) => Some(*i),
+        TValue::NUMFLT(f) => float_to_integer(*f, F2IMode::Exact),
+        _ => None,
+    }
+}
+
+/**
+ * luaV_mod
+ */
+pub fn int_mod(m: i64, n: i64) -> Result<i64, &'static str> {
+    match n {
+        0 => Err("attempt to perform 'n%0'"),
+        -1 => Ok(0),
+        _ => {
+            let r = m % n;
+            if r != 0 && (r ^ n) < 0 { Ok(r + n) } else { Ok(r) }
+        }
+    }
+}
+
+/**
+ * luaV_idiv
+ */
+pub fn int_div(m: i64, n: i64) -> Result<i64, &'static str> {
+    match n {
+        0 => Err("attempt to divide by zero"),
+        -1 => Ok(m.wrapping_neg()),
+        _ => {
+            let q = m / n;
+            if (m ^ n) < 0 && m % n != 0 { Ok(q - 1) } else { Ok(q) }
+        }
+    }
+}
+
+/**
+ * luai_nummod
+ */
+pub fn float_mod(a: f64, b: f64) -> f64 {
+    let m = a % b;
+    if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) {
+        m + b
+    } else {
+        m
+    }
+}
+
+/**
+ * luaV_shiftl
+ */
+pub fn shift_left(x: i64, y: i64) -> i64 {
+    if y < 0 {
+        if y <= -64 { 0 } else { ((x as u64) >> (-y) as u32) as i64 }
+    } else if y >= 64 {
+        0
+    } else {
+        ((x as u64) << y as u32) as i64
+    }
+}
+
+fn int_arith(op: TMS, a: i64, b: i64) -> Result<i64, &'static str> {
+    Ok(match op {
+        TMS::ADD => a.wrapping_add(b),
+        TMS::SUB => a.wrapping_sub(b),
+        TMS::MUL => a.wrapping_mul(b),
+        TMS::MOD => int_mod(a, b)?,
+        TMS::IDIV => int_div(a, b)?,
+        TMS::BAND => a & b,
+        TMS::BOR => a | b,
+        TMS::BXOR => a ^ b,
+        TMS::SHL => shift_left(a, b),
+        TMS::SHR => shift_left(a, b.wrapping_neg()),
+        TMS::UNM => a.wrapping_neg(),
+        TMS::BNOT => !a,
+        _ => unreachable!("not an integer operation"),
+    })
+}
+
+fn float_arith(op: TMS, a: f64, b: f64) -> f64 {
+    match op {
+        TMS::ADD => a + b,
+        TMS::SUB => a - b,
+        TMS::MUL => a * b,
+        TMS::MOD => float_mod(a, b),
+        TMS::POW => if b == 2.0 { a * a } else { a.powf(b) },
+        TMS::DIV => a / b,
+        TMS::IDIV => (a / b).floor(),
+        TMS::UNM => -a,
+        _ => unreachable!("not a float operation"),
+    }
+}
+
+/**
+ * luaO_rawarith without string coercion: Ok(None) means a metamethod is needed
+ */
+pub fn arith(op: TMS, a: &TValue, b: &TValue) -> Result<Option<TValue>, &'static str> {
+    if op.is_bitwise() {
+        return match (to_integer_strict(a), to_integer_strict(b)) {
+            (Some(x), Some(y)) => Ok(Some(TValue::NUMINT(int_arith(op, x, y)?))),
+            _ => Ok(None),
+        };
+    }
+    match (a, b) {
+        (TValue::NUMINT(x), TValue::NUMINT(y)) if op != TMS::POW && op != TMS::DIV => {
+            Ok(Some(TValue::NUMINT(int_arith(op, *x, *y)?)))
+        },
+        _ => match (to_float(a), to_float(b)) {
+            (Some(x), Some(y)) => Ok(Some(TValue::NUMFLT(float_arith(op, x, y)))),
+            _ => Ok(None),
+        },
+    }
+}
+
+const MAXINTFITSF: u64 = 1 << 53;
+
+fn int_fits_float(i: i64) -> bool {
+    MAXINTFITSF.wrapping_add(i as u64) <= 2 * MAXINTFITSF
+}
+
+fn lt_int_float(i: i64, f: f64) -> bool {
+    if int_fits_float(i) {
+        (i as f64) < f
+    } else {
+        match float_to_integer(f, F2IMode::Ceil) {
+            Some(fi) => i < fi,
+            None => f > 0.0,
+        }
+    }
+}
+
+fn le_int_float(i: i64, f: f64) -> bool {
+    if int_fits_float(i) {
+        (i as f64) <= f
+    } else {
+        match float_to_integer(f, F2IMode::Floor) {
+            Some(fi) => i <= fi,
+            None => f > 0.0,
+        }
+    }
+}
+
+fn lt_float_int(f: f64, i: i64) -> bool {
+    if int_fits_float(i) {
+        f < (i as f64)
+    } else {
+        match float_to_integer(f, F2IMode::Floor) {
+            Some(fi) => fi < i,
+            None => f < 0.0,
+        }
+    }
+}
+
+fn le_float_int(f: f64, i: i64) -> bool {
+    if int_fits_float(i) {
+        f <= (i as f64)
+    } else {
+        match float_to_integer(f, F2IMode::Ceil) {
+            Some(fi) => fi <= i,
+            None => f < 0.0,
+        }
+    }
+}
+
+/**
+ * LTnum: None when any of values isn't a number
+ */
+pub fn less_than(a: &TValue, b: &TValue) -> Option<bool> {
+    match (a, b) {
+        (TValue::NUMINT(x), TValue::NUMINT(y)) => Some(x < y),
+        (TValue::NUMINT(x), TValue::NUMFLT(y)) => Some(lt_int_float(*x, *y)),
+        (TValue::NUMFLT(x), TValue::NUMINT(y)) => Some(lt_float_int(*x, *y)),
+        (TValue::NUMFLT(x), TValue::NUMFLT(y)) => Some(x < y),
+        (TValue::STR(x), TValue::STR(y)) => Some(x.as_bytes().cmp(y.as_bytes()) == Ordering::Less),
+        _ => None,
+    }
+}
+
+/**
+ * LEnum: None when any of values isn't a number
+ */
+pub fn less_equal(a: &TValue, b: &TValue) -> Option<bool> {
+    match (a, b) {
+        (TValue::NUMINT(x), TValue::NUMINT(y)) => Some(x <= y),
+        (TValue::NUMINT(x), TValue::NUMFLT(y)) => Some(le_int_float(*x, *y)),
+        (TValue::NUMFLT(x), TValue::NUMINT(y)) => Some(le_float_int(*x, *y)),
+        (TValue::NUMFLT(x), TValue::NUMFLT(y)) => Some(x <= y),
+        (TValue::STR(x), TValue::STR(y)) => Some(x.as_bytes().cmp(y.as_bytes()) != Ordering::Greater),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn integer_division_rounds_to_floor() {
+        assert_eq!(int_div(7, 2), Ok(3));
+        assert_eq!(int_div(-7, 2), Ok(-4));
+        assert_eq!(int_mod(-7, 2), Ok(1));
+        assert_eq!(int_mod(7, -2), Ok(-1));
+        assert_eq!(int_div(i64::MIN, -1), Ok(i64::MIN));
+        assert_eq!(int_div(1, 0), Err("attempt to divide by zero"));
+        assert_eq!(int_mod(1, 0), Err("attempt to perform 'n%0'"));
+        assert_eq!(float_mod(-7.0, 2.0), 1.0);
+        assert_eq!(shift_left(1, 64), 0);
+        assert_eq!(shift_left(-1, -1), i64::MAX);
+    }
+
+    #[test]
+    fn mixed_comparisons() {
+        assert_eq!(less_than(&TValue::NUMINT(1), &TValue::NUMFLT(1.5)), Some(true));
+        assert_eq!(less_equal(&TValue::NUMFLT(f64::NAN), &TValue::NUMINT(1)), Some(false));
+        assert_eq!(less_than(&TValue::NUMINT(i64::MAX), &TValue::NUMFLT(9223372036854775808.0)), Some(true));
+        assert_eq!(less_than(&TValue::NUMINT(1), &TValue::NIL), None);
+    }
+}
@@ -0,0 +1,615 @@
+/*
+ * Debug information helpers: line numbers, chunk ids and symbolic execution
+ * used to name values in error messages (ldebug.c), plus the debug interface
+ * behind the debug library: stack introspection and hooks
+ */
+
+use std::rc::Rc;
+
+use crate::core::{types::{Proto, TValue, Closure, LuaThread, LuaError, StackIndex, string::LuaString}, instruction::Instruction};
+
+use super::{LuaVm, meta::TMS};
+
+/* size of the chunk id buffer, LUA_IDSIZE */
+const LUA_IDSIZE: usize = 60;
+
+/* event masks of hooks (LUA_MASKCALL...) */
+pub const MASK_CALL: u8 = 1 << 0;
+pub const MASK_RET: u8 = 1 << 1;
+pub const MASK_LINE: u8 = 1 << 2;
+pub const MASK_COUNT: u8 = 1 << 3;
+
+/* levels shown at the top and at the bottom of long tracebacks */
+const LEVELS1: usize = 10;
+const LEVELS2: usize = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Call,
+    Return,
+    Line,
+    Count,
+    TailCall,
+}
+
+impl HookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Call => "call",
+            HookEvent::Return => "return",
+            HookEvent::Line => "line",
+            HookEvent::Count => "count",
+            HookEvent::TailCall => "tail call",
+        }
+    }
+}
+
+/**
+ * Hook of a thread: the function is called with the event name and, for line events, the new line
+ */
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub function: TValue,
+    pub mask: u8,
+    pub count: u32,
+}
+
+/**
+ * lua_Debug: what lua_getinfo tells about a function, the fields that depend on the
+ * activation keep their defaults for functions that are not running
+ */
+#[derive(Debug)]
+pub struct DebugInfo {
+    pub name: Option<String>,
+    pub namewhat: &'static str,
+    pub what: &'static str,
+    pub source: Rc<LuaString>,
+    pub short_src: String,
+    pub current_line: i64,
+    pub line_defined: i64,
+    pub last_line_defined: i64,
+    pub nups: usize,
+    pub nparams: u8,
+    pub is_vararg: bool,
+    pub is_tail_call: bool,
+    pub transfer: (usize, usize),
+}
+
+/**
+ * luaG_getfuncline: None for stripped functions
+ */
+pub fn get_func_line(proto: &Proto, pc: usize) -> Option<i64> {
+    if proto.line_info.is_empty() {
+        return None;
+    }
+    let (mut base_pc, mut base_line) = match proto.abs_line_info.iter().rev().find(|info| info.pc <= pc) {
+        Some(info) => (Some(info.pc), info.line),
+        None => (None, proto.line_defined as i64),
+    };
+    loop {
+        let next = base_pc.map_or(0, |p| p + 1);
+        if next > pc {
+            break;
+        }
+        base_line += *proto.line_info.get(next)? as i64;
+        base_pc = Some(next);
+    }
+    Some(base_line)
+}
+
+/**
+ * luaO_chunkid: printable form of a chunk source
+ */
+pub fn chunk_id(source: &str) -> String {
+    const RETS: &str = "...";
+    const PRE: &str = "[string \"";
+    const POS: &str = "\"]";
+    let bytes = source.as_bytes();
+    let bufflen = LUA_IDSIZE;
+    if let Some(rest) = source.strip_prefix('=') {
+        if bytes.len() <= bufflen {
+            rest.to_string()
+        } else {
+            String::from_utf8_lossy(&rest.as_bytes()[..bufflen - 1]).into_owned()
+        }
+    } else if let Some(rest) = source.strip_prefix('@') {
+        if bytes.len() <= bufflen {
+            rest.to_string()
+        } else {
+            let keep = bufflen - RETS.len() - 1;
+            let rest = rest.as_bytes();
+            format!("{}{}", RETS, String::from_utf8_lossy(&rest[rest.len() - keep..]))
+        }
+    } else {
+        let available = bufflen - (PRE.len() + RETS.len() + POS.len()) - 1;
+        let newline = bytes.iter().position(|c| *c == b'\n');
+        if bytes.len() < available && newline.is_none() {
+            format!("{}{}{}", PRE, source, POS)
+        } else {
+            let len = newline.unwrap_or(bytes.len()).min(available);
+            format!("{}{}{}{}", PRE, String::from_utf8_lossy(&bytes[..len]), RETS, POS)
+        }
+    }
+}
+
+/**
+ * Short source of a function for messages, "?" when the chunk is stripped
+ */
+pub fn short_source(proto: &Proto) -> String {
+    match &proto.fn_name {
+        Some(source) => chunk_id(&source.to_str_lossy()),
+        None => "?".to_string(),
+    }
+}
+
+/**
+ * luaF_getlocalname: name of the n-th (1-based) local variable active at pc
+ */
+pub fn get_local_name(proto: &Proto, local_number: usize, pc: usize) -> Option<String> {
+    let mut local_number = local_number;
+    for var in proto.loc_vars.iter().take_while(|var| var.start_pc <= pc) {
+        if pc < var.end_pc {
+            local_number -= 1;
+            if local_number == 0 {
+                return var.name.as_ref().map(|n| n.to_string());
+            }
+        }
+    }
+    None
+}
+
+pub fn upvalue_name(proto: &Proto, index: usize) -> String {
+    match proto.upvalues.get(index).and_then(|u| u.name.as_ref()) {
+        Some(name) => name.to_string(),
+        None => "?".to_string(),
+    }
+}
+
+/**
+ * findsetreg: last instruction before 'lastpc' that modified register 'reg'
+ */
+fn find_set_reg(proto: &Proto, lastpc: usize, reg: usize) -> Option<usize> {
+    let mut lastpc = lastpc;
+    if proto.code.get(lastpc).is_some_and(|i| i.opcode.is_mm()) {
+        lastpc -= 1;
+    }
+    let mut setreg = None;
+    let mut jmptarget = 0_usize;
+    for pc in 0..lastpc {
+        let instruction = &proto.code[pc];
+        let a = instruction.args.get_A() as usize;
+        let change = match instruction.decoded() {
+            Instruction::LoadNil { b, .. } => a <= reg && reg <= a + b as usize,
+            Instruction::TForCall { .. } => reg >= a + 2,
+            Instruction::Call { .. } | Instruction::TailCall { .. } => reg >= a,
+            Instruction::Jmp { sj } => {
+                let dest = pc as i64 + 1 + sj as i64;
+                if dest <= lastpc as i64 && dest > jmptarget as i64 {
+                    jmptarget = dest as usize;
+                }
+                false
+            },
+            _ => instruction.opcode.sets_a() && reg == a,
+        };
+        if change {
+            setreg = if pc < jmptarget { None } else { Some(pc) };
+        }
+    }
+    setreg
+}
+
+fn constant_name(proto: &Proto, index: usize) -> (Option<&'static str>, String) {
+    match proto.constants.get(index) {
+        Some(TValue::STR(s)) => (Some("constant"), s.to_string()),
+        _ => (None, "?".to_string()),
+    }
+}
+
+/**
+ * basicgetobjname: locals, upvalues and constants. Updates 'pc' to the instruction that set the register
+ */
+fn basic_get_obj_name(proto: &Proto, pc: &mut Option<usize>, reg: usize) -> (Option<&'static str>, String) {
+    let current = pc.unwrap_or(0);
+    if let Some(name) = get_local_name(proto, reg + 1, current) {
+        return (Some("local"), name);
+    }
+    *pc = find_set_reg(proto, current, reg);
+    if let Some(setpc) = *pc {
+        match proto.code[setpc].decoded() {
+            Instruction::Move { a, b } if b < a => return basic_get_obj_name(proto, pc, b as usize),
+            Instruction::GetUpval { b, .. } => {
+                return (Some("upvalue"), upvalue_name(proto, b as usize));
+            },
+            Instruction::LoadK { bx, .. } => return constant_name(proto, bx as usize),
+            Instruction::LoadKX { .. } => {
+                if let Some(extra) = proto.code.get(setpc + 1) {
+                    return constant_name(proto, extra.args.get_Ax() as usize);
+                }
+            },
+            _ => {},
+        }
+    }
+    (None, "?".to_string())
+}
+
+/**
+ * rname: name for register 'c' only when it holds a constant string
+ */
+fn register_name(proto: &Proto, pc: usize, c: usize) -> String {
+    let mut pc = Some(pc);
+    match basic_get_obj_name(proto, &mut pc, c) {
+        (Some("constant"), name) => name,
+        _ => "?".to_string(),
+    }
+}
+
+fn is_env(proto: &Proto, pc: usize, table: usize, is_upvalue: bool) -> &'static str {
+    let name = if is_upvalue {
+        upvalue_name(proto, table)
+    } else {
+        let mut pc = Some(pc);
+        basic_get_obj_name(proto, &mut pc, table).1
+    };
+    if name == "_ENV" { "global" } else { "field" }
+}
+
+/**
+ * getobjname: extends basic_get_obj_name with table accesses
+ */
+pub fn get_obj_name(proto: &Proto, lastpc: usize, reg: usize) -> Option<(&'static str, String)> {
+    let mut pc = Some(lastpc);
+    let (kind, name) = basic_get_obj_name(proto, &mut pc, reg);
+    if let Some(kind) = kind {
+        return Some((kind, name));
+    }
+    let pc = pc?;
+    match proto.code[pc].decoded() {
+        Instruction::GetTabUp { b, key_k, .. } => {
+            let (_, name) = constant_name(proto, key_k as usize);
+            Some((is_env(proto, pc, b as usize, true), name))
+        },
+        Instruction::GetTable { b, c, .. } => {
+            let name = register_name(proto, pc, c as usize);
+            Some((is_env(proto, pc, b as usize, false), name))
+        },
+        Instruction::GetI { .. } => Some(("field", "integer index".to_string())),
+        Instruction::GetField { b, key_k, .. } => {
+            let (_, name) = constant_name(proto, key_k as usize);
+            Some((is_env(proto, pc, b as usize, false), name))
+        },
+        Instruction::Self_ { c, k, .. } => {
+            let c = c as usize;
+            let name = if k { constant_name(proto, c).1 } else { register_name(proto, pc, c) };
+            Some(("method", name))
+        },
+        _ => None,
+    }
+}
+
+/**
+ * funcnamefromcode: name of a function called by the instruction at 'pc'
+ */
+pub fn func_name_from_code(proto: &Proto, pc: usize) -> Option<(&'static str, String)> {
+    let instruction = proto.code.get(pc)?;
+    let tm = match instruction.decoded() {
+        Instruction::Call { a, .. } | Instruction::TailCall { a, .. } => return get_obj_name(proto, pc, a as usize),
+        Instruction::TForCall { .. } => return Some(("for iterator", "for iterator".to_string())),
+        Instruction::Self_ { .. } | Instruction::GetTabUp { .. } | Instruction::GetTable { .. } |
+        Instruction::GetI { .. } | Instruction::GetField { .. } => TMS::INDEX,
+        Instruction::SetTabUp { .. } | Instruction::SetTable { .. } | Instruction::SetI { .. } |
+        Instruction::SetField { .. } => TMS::NEWINDEX,
+        Instruction::MmBin { tm, .. } | Instruction::MmBinI { tm, .. } | Instruction::MmBinK { tm, .. } => TMS::from(tm),
+        Instruction::Unm { .. } => TMS::UNM,
+        Instruction::BNot { .. } => TMS::BNOT,
+        Instruction::Len { .. } => TMS::LEN,
+        Instruction::Concat { .. } => TMS::CONCAT,
+        Instruction::Eq { .. } => TMS::EQ,
+        Instruction::Lt { .. } | Instruction::LtI { .. } | Instruction::GtI { .. } => TMS::LT,
+        Instruction::Le { .. } | Instruction::LeI { .. } | Instruction::GeI { .. } => TMS::LE,
+        Instruction::Close { .. } | Instruction::Return { .. } => TMS::CLOSE,
+        _ => return None,
+    };
+    Some(("metamethod", tm.name()[2..].to_string()))
+}
+
+/**
+ * Lines with code in a Lua function (collectvalidlines), the VARARGPREP of vararg functions is
+ * not an active line. None for native functions
+ */
+pub fn active_lines(function: &Closure) -> Option<Vec<i64>> {
+    let proto = match function {
+        Closure::Lua(lua_closure) => &lua_closure.proto,
+        Closure::C(_) => return None,
+    };
+    let first = if proto.is_vararg { 1 } else { 0 };
+    Some((first..proto.line_info.len()).filter_map(|pc| get_func_line(proto, pc)).collect())
+}
+
+impl LuaVm {
+
+    /**
+     * getfuncname: how the caller of the function at 'level' refers to it
+     */
+    pub fn function_name(&self, thread: &LuaThread, level: usize) -> Option<(&'static str, String)> {
+        let mut frames = thread.current_call.iter().skip(level);
+        let current = frames.next()?;
+        if current.is_tail {
+            return None;
+        }
+        let caller = frames.next()?;
+        if caller.is_hooked {
+            return Some(("hook", "?".to_string()));
+        }
+        if !caller.is_lua {
+            return None;
+        }
+        let proto = caller.get_lua_proto(&thread.stack)?;
+        func_name_from_code(&proto, caller.pc.saturating_sub(1))
+    }
+
+    /**
+     * lua_getinfo for 'function', which is running at 'level' of the call stack when given
+     */
+    pub fn get_info(&self, thread: &LuaThread, function: &Closure, level: Option<usize>) -> DebugInfo {
+        let mut info = match function {
+            Closure::C(c_closure) => DebugInfo {
+                name: None,
+                namewhat: "",
+                what: "C",
+                source: Rc::new(LuaString::from("=[C]")),
+                short_src: chunk_id("=[C]"),
+                current_line: -1,
+                line_defined: -1,
+                last_line_defined: -1,
+                nups: c_closure.upvalues.borrow().len(),
+                nparams: 0,
+                is_vararg: true,
+                is_tail_call: false,
+                transfer: (0, 0),
+            },
+            Closure::Lua(lua_closure) => {
+                let proto = &lua_closure.proto;
+                DebugInfo {
+                    name: None,
+                    namewhat: "",
+                    what: if proto.line_defined == 0 { "main" } else { "Lua" },
+                    source: proto.fn_name.clone().unwrap_or_else(|| Rc::new(LuaString::from("=?"))),
+                    short_src: short_source(proto),
+                    current_line: -1,
+                    line_defined: proto.line_defined as i64,
+                    last_line_defined: proto.last_line_defined as i64,
+                    nups: proto.upvalues.len(),
+                    nparams: proto.num_params,
+                    is_vararg: proto.is_vararg,
+                    is_tail_call: false,
+                    transfer: (0, 0),
+                }
+            },
+        };
+        if let Some(call_info) = level.and_then(|level| thread.current_call.iter().nth(level)) {
+            if let Closure::Lua(lua_closure) = function {
+                info.current_line = get_func_line(&lua_closure.proto, call_info.pc.saturating_sub(1)).unwrap_or(-1);
+            }
+            info.is_tail_call = call_info.is_tail;
+            info.transfer = call_info.transfer;
+            if let Some((namewhat, name)) = self.function_name(thread, level.unwrap()) {
+                info.namewhat = namewhat;
+                info.name = Some(name);
+            }
+        }
+        info
+    }
+
+    /**
+     * luaG_findlocal: name and stack slot of the n-th local of the function at 'level'.
+     * Negative numbers are varargs, slots without a name in use by the function are temporaries
+     */
+    pub fn find_local(&self, thread: &LuaThread, level: usize, n: i64) -> Option<(String, StackIndex)> {
+        let call_info = thread.current_call.iter().nth(level)?;
+        let base = call_info.fn_idx + 1;
+        let mut name = None;
+        if call_info.is_lua {
+            let proto = call_info.get_lua_proto(&thread.stack)?;
+            if n < 0 {
+                let nextra = call_info.nextraargs as i64;
+                if proto.is_vararg && n >= -nextra {
+                    return Some(("(vararg)".to_string(), (call_info.fn_idx as i64 - nextra - (n + 1)) as usize));
+                }
+                return None;
+            }
+            if n > 0 {
+                name = get_local_name(&proto, n as usize, call_info.pc.saturating_sub(1));
+            }
+        }
+        let name = match name {
+            Some(name) => name,
+            None => {
+                // slots up to the function called by this one
+                let limit = if level == 0 { thread.top } else { thread.current_call.iter().nth(level - 1)?.fn_idx };
+                if n <= 0 || (limit as i64 - base as i64) < n {
+                    return None;
+                }
+                let name = if call_info.is_lua { "(temporary)" } else { "(C temporary)" };
+                name.to_string()
+            },
+        };
+        Some((name, base + n as usize - 1))
+    }
+
+    /**
+     * luaL_traceback: one line per active function from 'level' on, skipping the middle of
+     * very deep stacks
+     */
+    pub fn traceback(&self, thread: &LuaThread, message: Option<&[u8]>, level: usize) -> Vec<u8> {
+        let last = thread.current_call.len().saturating_sub(1);
+        let mut limit_to_show = if last.saturating_sub(level) > LEVELS1 + LEVELS2 { Some(LEVELS1) } else { None };
+        let mut result = Vec::new();
+        if let Some(message) = message {
+            result.extend_from_slice(message);
+            result.push(b'\n');
+        }
+        result.extend_from_slice(b"stack traceback:");
+        let mut level = level;
+        while let Some(call_info) = thread.current_call.iter().nth(level) {
+            level += 1;
+            if limit_to_show == Some(0) {
+                // too many levels, skip to the last ones
+                let n = last - level - LEVELS2 + 1;
+                result.extend_from_slice(format!("\n\t...\t(skipping {} levels)", n).as_bytes());
+                level += n;
+                limit_to_show = None;
+                continue;
+            }
+            limit_to_show = limit_to_show.map(|limit| limit - 1);
+            let function = call_info.get_closure(&thread.stack);
+            let info = self.get_info(thread, &function, Some(level - 1));
+            let position = if info.current_line <= 0 {
+                format!("\n\t{}: in ", info.short_src)
+            } else {
+                format!("\n\t{}:{}: in ", info.short_src, info.current_line)
+            };
+            result.extend_from_slice(position.as_bytes());
+            let name = if let Some(name) = self.global_function_name(&function) {
+                format!("function '{}'", name)
+            } else if let Some(name) = &info.name {
+                format!("{} '{}'", info.namewhat, name)
+            } else if info.what == "main" {
+                "main chunk".to_string()
+            } else if info.what != "C" {
+                format!("function <{}:{}>", info.short_src, info.line_defined)
+            } else {
+                "?".to_string()
+            };
+            result.extend_from_slice(name.as_bytes());
+            if info.is_tail_call {
+                result.extend_from_slice(b"\n\t(...tail calls...)");
+            }
+        }
+        result
+    }
+
+    /**
+     * luaD_hook: runs the hook of the thread for an event of the running function. The hook
+     * can't trigger other hooks and sees 'transfer' as the values moved by call and return events
+     */
+    pub fn call_hook(&mut self, thread: &mut LuaThread, event: HookEvent, line: Option<i64>, transfer: (usize, usize)) -> Result<(), LuaError> {
+        let function = match &thread.hook {
+            Some(hook) if thread.allow_hook => hook.function.clone(),
+            _ => return Ok(()),
+        };
+        let old_top = thread.top;
+        self.protect_frame(thread);
+        if let Some(call_info) = thread.current_call.front_mut() {
+            call_info.is_hooked = true;
+            if transfer.1 != 0 {
+                call_info.transfer = transfer;
+            }
+        }
+        thread.allow_hook = false;
+        let line = line.map_or(TValue::NIL, TValue::NUMINT);
+        let result = self.call_value(thread, function, &[TValue::from_str(event.name()), line]);
+        thread.allow_hook = true;
+        result?;
+        if let Some(call_info) = thread.current_call.front_mut() {
+            call_info.is_hooked = false;
+            call_info.transfer = (0, 0);
+        }
+        thread.top = old_top;
+        Ok(())
+    }
+
+    fn hook_enabled(thread: &LuaThread, mask: u8) -> bool {
+        thread.hook.as_ref().is_some_and(|hook| hook.mask & mask != 0)
+    }
+
+    /**
+     * luaD_hookcall: call event of a Lua function about to run its first instruction
+     */
+    pub fn hook_call(&mut self, thread: &mut LuaThread, proto: &Proto) -> Result<(), LuaError> {
+        thread.old_pc = 0;
+        if Self::hook_enabled(thread, MASK_CALL) {
+            let call_info = thread.current_call.front_mut().unwrap();
+            let event = if call_info.is_tail { HookEvent::TailCall } else { HookEvent::Call };
+            // hooks assume 'pc' is already incremented
+            call_info.pc += 1;
+            self.call_hook(thread, event, None, (1, proto.num_params as usize))?;
+            thread.current_call.front_mut().unwrap().pc -= 1;
+        }
+        Ok(())
+    }
+
+    /**
+     * Call event of a native function, its arguments are the transferred values
+     */
+    pub fn hook_native_call(&mut self, thread: &mut LuaThread, num_args: usize) -> Result<(), LuaError> {
+        if Self::hook_enabled(thread, MASK_CALL) {
+            self.call_hook(thread, HookEvent::Call, None, (1, num_args))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * rethook: return event of the running function, whose 'nres' results are on top of the
+     * stack. Vararg functions are seen with their fixed parameters above the varargs again
+     */
+    pub fn hook_return(&mut self, thread: &mut LuaThread, nres: usize) -> Result<(), LuaError> {
+        if Self::hook_enabled(thread, MASK_RET) {
+            let first_result = thread.top - nres;
+            let call_info = thread.current_call.front().unwrap();
+            let delta = match call_info.get_lua_proto(&thread.stack) {
+                Some(proto) if proto.is_vararg => call_info.nextraargs + proto.num_params as usize + 1,
+                _ => 0,
+            };
+            let call_info = thread.current_call.front_mut().unwrap();
+            call_info.fn_idx += delta;
+            let ftransfer = first_result - call_info.fn_idx;
+            self.call_hook(thread, HookEvent::Return, None, (ftransfer, nres))?;
+            thread.current_call.front_mut().unwrap().fn_idx -= delta;
+        }
+        // the caller continues after the call
+        if let Some(caller) = thread.current_call.iter().nth(1) {
+            if caller.is_lua {
+                thread.old_pc = caller.pc.saturating_sub(1);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * luaG_traceexec: count and line events before the instruction at 'pc' of the running
+     * function. Line events happen when the line changes or the code jumps back
+     */
+    pub fn trace_exec(&mut self, thread: &mut LuaThread, proto: &Proto, pc: usize) -> Result<(), LuaError> {
+        let (mask, count) = match &thread.hook {
+            Some(hook) => (hook.mask, hook.count),
+            None => return Ok(()),
+        };
+        if mask & (MASK_LINE | MASK_COUNT) == 0 {
+            return Ok(());
+        }
+        let count_hook = mask & MASK_COUNT != 0 && {
+            thread.hook_count -= 1;
+            thread.hook_count == 0
+        };
+        if count_hook {
+            thread.hook_count = count;
+        } else if mask & MASK_LINE == 0 {
+            return Ok(());
+        }
+        // hooks assume 'pc' is already incremented
+        thread.current_call.front_mut().unwrap().pc = pc + 1;
+        if count_hook {
+            self.call_hook(thread, HookEvent::Count, None, (0, 0))?;
+        }
+        if mask & MASK_LINE != 0 {
+            let old_pc = if thread.old_pc < proto.code.len() { thread.old_pc } else { 0 };
+            let new_line = get_func_line(proto, pc);
+            if pc <= old_pc || get_func_line(proto, old_pc) != new_line {
+                self.call_hook(thread, HookEvent::Line, new_line, (0, 0))?;
+            }
+            thread.old_pc = pc;
+        }
+        thread.current_call.front_mut().unwrap().pc = pc;
+        Ok(())
+    }
+}
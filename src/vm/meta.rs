@@ -0,0 +1,341 @@
+use std::rc::Rc;
+
+use crate::core::types::{TValue, LuaType, LuaThread, LuaError, table::TableRef};
+
+use super::{LuaVm, Origin, arith};
+
+/*
+ * WARNING: if you change the order of this enumeration,
+ * grep "ORDER TM" and "ORDER OP"
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[repr(u8)]
+pub enum TMS {
+    INDEX = 0,
+    NEWINDEX,
+    GC,
+    MODE,
+    LEN,
+    EQ,
+    ADD,
+    SUB,
+    MUL,
+    MOD,
+    POW,
+    DIV,
+    IDIV,
+    BAND,
+    BOR,
+    BXOR,
+    SHL,
+    SHR,
+    UNM,
+    BNOT,
+    LT,
+    LE,
+    CONCAT,
+    CALL,
+    CLOSE,
+}
+
+const TM_NAMES: [&str; 25] = [
+    "__index", "__newindex", "__gc", "__mode", "__len", "__eq",
+    "__add", "__sub", "__mul", "__mod", "__pow", "__div", "__idiv",
+    "__band", "__bor", "__bxor", "__shl", "__shr",
+    "__unm", "__bnot", "__lt", "__le", "__concat", "__call", "__close",
+];
+
+impl TMS {
+    pub fn name(&self) -> &'static str {
+        TM_NAMES[*self as usize]
+    }
+
+    pub fn is_bitwise(&self) -> bool {
+        matches!(self, TMS::BAND | TMS::BOR | TMS::BXOR | TMS::SHL | TMS::SHR | TMS::BNOT)
+    }
+}
+
+impl From<u8> for TMS {
+    fn from(value: u8) -> Self {
+        if value > TMS::CLOSE as u8 { panic!("Invalid metamethod ix") };
+        unsafe { core::mem::transmute::<u8, TMS>(value) }
+    }
+}
+
+/* limit for table tag-method chains (to avoid infinite loops) */
+const MAXTAGLOOP: usize = 2000;
+
+/**
+ * Metamethod lookup and dispatch (ltm.c and the slow paths of lvm.c)
+ */
+impl LuaVm {
+
+    pub fn get_metatable(&self, value: &TValue) -> Option<TableRef> {
+        match value {
+            TValue::TABLE(table) => table.borrow().get_metatable(),
+            TValue::USERDATA(userdata) => userdata.get_metatable(),
+            _ => self.get_type_metatable(value.lua_type()),
+        }
+    }
+
+    /**
+     * The metatable shared by all values of a type; tables and full userdata have their own
+     */
+    pub fn get_type_metatable(&self, lua_type: LuaType) -> Option<TableRef> {
+        self.type_metatables[lua_type as usize].clone()
+    }
+
+    /**
+     * Lets hosts give strings, numbers, booleans, nil or functions a metatable, as
+     * debug.setmetatable does
+     */
+    pub fn set_type_metatable(&mut self, lua_type: LuaType, metatable: Option<TableRef>) {
+        self.type_metatables[lua_type as usize] = metatable;
+    }
+
+    /**
+     * lua_setmetatable: values other than tables and full userdata share the metatable of their type
+     */
+    pub fn set_metatable(&mut self, value: &TValue, metatable: Option<TableRef>) {
+        match value {
+            TValue::TABLE(table) => table.borrow_mut().metatable = metatable,
+            TValue::USERDATA(userdata) => *userdata.metatable.borrow_mut() = metatable,
+            _ => self.set_type_metatable(value.lua_type(), metatable),
+        }
+    }
+
+    /**
+     * luaL_getmetafield: a field of the metatable, nil when there is none
+     */
+    pub fn get_metafield(&self, value: &TValue, name: &str) -> TValue {
+        match self.get_metatable(value) {
+            Some(metatable) => metatable.borrow().get_str(name),
+            None => TValue::NIL,
+        }
+    }
+
+    /**
+     * luaT_gettmbyobj
+     */
+    pub fn get_tm(&self, value: &TValue, event: TMS) -> TValue {
+        self.get_metafield(value, event.name())
+    }
+
+    /**
+     * luaT_objtypename: honours '__name' in the metatable
+     */
+    pub fn obj_type_name(&self, value: &TValue) -> String {
+        if let TValue::TABLE(_) | TValue::USERDATA(_) = value {
+            if let TValue::STR(name) = self.get_metafield(value, "__name") {
+                return name.to_string();
+            }
+        }
+        value.type_name().to_string()
+    }
+
+    /**
+     * Calls a metamethod keeping the registers of the running Lua function safe, returns its first result
+     */
+    pub fn call_tm(&mut self, thread: &mut LuaThread, tm: TValue, args: &[TValue]) -> Result<TValue, LuaError> {
+        self.protect_frame(thread);
+        let results = self.call_value(thread, tm, args)?;
+        Ok(results.into_iter().next().unwrap_or(TValue::NIL))
+    }
+
+    /**
+     * luaV_finishget: t[key] honouring '__index'
+     */
+    pub fn index(&mut self, thread: &mut LuaThread, t: &TValue, key: &TValue, origin: Origin) -> Result<TValue, LuaError> {
+        let mut t = t.clone();
+        let mut origin = origin;
+        for _ in 0..MAXTAGLOOP {
+            let tm = match &t {
+                TValue::TABLE(table) => {
+                    let table = table.borrow();
+                    let value = table.get(key);
+                    if !value.is_nil() {
+                        return Ok(value);
+                    }
+                    let tm = match &table.metatable {
+                        Some(metatable) => metatable.borrow().get_str(TMS::INDEX.name()),
+                        None => TValue::NIL,
+                    };
+                    if tm.is_nil() {
+                        return Ok(TValue::NIL);
+                    }
+                    tm
+                },
+                _ => {
+                    let tm = self.get_tm(&t, TMS::INDEX);
+                    if tm.is_nil() {
+                        return Err(self.type_error(thread, &t, "index", origin));
+                    }
+                    tm
+                }
+            };
+            if let TValue::CLOSURE(_) = tm {
+                return self.call_tm(thread, tm, &[t, key.clone()]);
+            }
+            t = tm;
+            origin = Origin::Unknown;
+        }
+        Err(self.runtime_error(thread, "'__index' chain too long; possible loop"))
+    }
+
+    /**
+     * luaV_finishset: t[key] = value honouring '__newindex'
+     */
+    pub fn set_index(&mut self, thread: &mut LuaThread, t: &TValue, key: TValue, value: TValue, origin: Origin) -> Result<(), LuaError> {
+        let mut t = t.clone();
+        let mut origin = origin;
+        for _ in 0..MAXTAGLOOP {
+            let tm = match &t {
+                TValue::TABLE(table) => {
+                    let tm = {
+                        let table = table.borrow();
+                        match &table.metatable {
+                            Some(metatable) if table.get(&key).is_nil() => metatable.borrow().get_str(TMS::NEWINDEX.name()),
+                            _ => TValue::NIL,
+                        }
+                    };
+                    if tm.is_nil() {
+                        let result = table.borrow_mut().set(key, value);
+                        return result.map_err(|message| self.runtime_error(thread, message));
+                    }
+                    tm
+                },
+                _ => {
+                    let tm = self.get_tm(&t, TMS::NEWINDEX);
+                    if tm.is_nil() {
+                        return Err(self.type_error(thread, &t, "index", origin));
+                    }
+                    tm
+                }
+            };
+            if let TValue::CLOSURE(_) = tm {
+                self.call_tm(thread, tm, &[t, key, value])?;
+                return Ok(());
+            }
+            t = tm;
+            origin = Origin::Unknown;
+        }
+        Err(self.runtime_error(thread, "'__newindex' chain too long; possible loop"))
+    }
+
+    /**
+     * luaT_trybinTM: calls the metamethod of the first operand having one or reports the faulty operand
+     */
+    pub fn try_bin_tm(&mut self, thread: &mut LuaThread, p1: &TValue, p2: &TValue, event: TMS, origins: (Origin, Origin)) -> Result<TValue, LuaError> {
+        let mut tm = self.get_tm(p1, event);
+        if tm.is_nil() {
+            tm = self.get_tm(p2, event);
+        }
+        if !tm.is_nil() {
+            return self.call_tm(thread, tm, &[p1.clone(), p2.clone()]);
+        }
+        let is_number = |v: &TValue| matches!(v, TValue::NUMINT(_) | TValue::NUMFLT(_));
+        if event == TMS::CONCAT {
+            let is_string = |v: &TValue| is_number(v) || matches!(v, TValue::STR(_));
+            let (value, origin) = if is_string(p1) { (p2, origins.1) } else { (p1, origins.0) };
+            return Err(self.type_error(thread, value, "concatenate", origin));
+        }
+        if event.is_bitwise() {
+            if is_number(p1) && is_number(p2) {
+                let origin = if arith::to_integer_strict(p1).is_none() { origins.0 } else { origins.1 };
+                let info = self.varinfo(thread, origin);
+                return Err(self.runtime_error(thread, &format!("number{} has no integer representation", info)));
+            }
+            let (value, origin) = if !is_number(p1) { (p1, origins.0) } else { (p2, origins.1) };
+            return Err(self.type_error(thread, value, "perform bitwise operation on", origin));
+        }
+        let (value, origin) = if !is_number(p1) { (p1, origins.0) } else { (p2, origins.1) };
+        Err(self.type_error(thread, value, "perform arithmetic on", origin))
+    }
+
+    /**
+     * luaT_callorderTM
+     */
+    fn call_order_tm(&mut self, thread: &mut LuaThread, p1: &TValue, p2: &TValue, event: TMS) -> Result<bool, LuaError> {
+        let mut tm = self.get_tm(p1, event);
+        if tm.is_nil() {
+            tm = self.get_tm(p2, event);
+        }
+        if tm.is_nil() {
+            return Err(self.order_error(thread, p1, p2));
+        }
+        Ok(!self.call_tm(thread, tm, &[p1.clone(), p2.clone()])?.is_falsy())
+    }
+
+    /**
+     * luaG_ordererror
+     */
+    fn order_error(&self, thread: &LuaThread, p1: &TValue, p2: &TValue) -> LuaError {
+        let (t1, t2) = (self.obj_type_name(p1), self.obj_type_name(p2));
+        if t1 == t2 {
+            self.runtime_error(thread, &format!("attempt to compare two {} values", t1))
+        } else {
+            self.runtime_error(thread, &format!("attempt to compare {} with {}", t1, t2))
+        }
+    }
+
+    pub fn less_than(&mut self, thread: &mut LuaThread, p1: &TValue, p2: &TValue) -> Result<bool, LuaError> {
+        match arith::less_than(p1, p2) {
+            Some(result) => Ok(result),
+            None => self.call_order_tm(thread, p1, p2, TMS::LT),
+        }
+    }
+
+    pub fn less_equal(&mut self, thread: &mut LuaThread, p1: &TValue, p2: &TValue) -> Result<bool, LuaError> {
+        match arith::less_equal(p1, p2) {
+            Some(result) => Ok(result),
+            None => self.call_order_tm(thread, p1, p2, TMS::LE),
+        }
+    }
+
+    /**
+     * luaV_equalobj: tables (or userdata) with different identity may be equal through '__eq'
+     */
+    pub fn equals(&mut self, thread: &mut LuaThread, p1: &TValue, p2: &TValue) -> Result<bool, LuaError> {
+        let distinct = match (p1, p2) {
+            (TValue::TABLE(a), TValue::TABLE(b)) => !Rc::ptr_eq(a, b),
+            (TValue::USERDATA(a), TValue::USERDATA(b)) => !Rc::ptr_eq(a, b),
+            _ => return Ok(p1.raw_equals(p2)),
+        };
+        if !distinct {
+            return Ok(true);
+        }
+        let mut tm = self.get_tm(p1, TMS::EQ);
+        if tm.is_nil() {
+            tm = self.get_tm(p2, TMS::EQ);
+        }
+        if tm.is_nil() {
+            return Ok(false);
+        }
+        Ok(!self.call_tm(thread, tm, &[p1.clone(), p2.clone()])?.is_falsy())
+    }
+
+    /**
+     * luaV_objlen
+     */
+    pub fn length(&mut self, thread: &mut LuaThread, value: &TValue, origin: Origin) -> Result<TValue, LuaError> {
+        let tm = match value {
+            TValue::STR(s) => return Ok(TValue::NUMINT(s.len() as i64)),
+            TValue::TABLE(table) => {
+                let tm = self.get_tm(value, TMS::LEN);
+                if tm.is_nil() {
+                    return Ok(TValue::NUMINT(table.borrow().len()));
+                }
+                tm
+            },
+            _ => {
+                let tm = self.get_tm(value, TMS::LEN);
+                if tm.is_nil() {
+                    return Err(self.type_error(thread, value, "get length of", origin));
+                }
+                tm
+            }
+        };
+        self.call_tm(thread, tm, &[value.clone(), value.clone()])
+    }
+}
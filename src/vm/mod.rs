@@ -1,364 +1,1287 @@
-use std::rc::Rc;
+pub mod arith;
+pub mod debug;
+pub mod meta;
 
-use crate::core::{types::{Closure, TValue, LuaThread, stack::LuaStackView, StackIndex, CallInfo}, opcodes::LuaOpcode};
+use std::{rc::Rc, cell::RefCell, io::{self, Write}};
 
-pub struct LuaVm ();
+use crate::core::{types::{Closure, TValue, LuaType, LuaThread, StackIndex, CallInfo, LuaError, Proto, UpVal, LUA_MULTRET, LUAI_MAXSTACK, LUAI_MAXCCALLS, LUA_MINSTACK, stack::LuaStackView, number::{self, F2IMode}, table::{LuaTable, TableRef}, string::LuaString}, instruction::Instruction, opcodes::{LuaOpcode, MAXARG_C}, parser::{self, LUA_SIGNATURE, LoadLimits}};
+use crate::{analysis::verify::verify, compiler};
 
-enum Order {
-    Less,
-    LessOrEqual,
-    Equal,
-    Greater,
-    GreaterOrEqual,
+use self::meta::TMS;
+
+pub struct LuaVm {
+    pub globals: TableRef,
+    pub stdout: Box<dyn Write>,
+    /* bounds for the binary chunks 'load_chunk' reads */
+    pub load_limits: LoadLimits,
+    /* metatables shared by all values of a basic type (G(L)->mt), indexed by LuaType */
+    type_metatables: [Option<TableRef>; LuaType::COUNT],
 }
 
-fn compare(left: &TValue, right: &TValue, k: bool, mode: Order) -> usize {
-    let mut result = 0_usize;
-    match mode {
-        Order::Less => {
-            if let (TValue::NUMINT(l_val), TValue::NUMINT(r_val)) = (left, right) {
-                result = if (l_val < r_val) != k { 1 } else { 0 };
-            } else if let (TValue::NUMFLT(l_val), TValue::NUMFLT(r_val)) = (left, right) {
-                result = if (l_val < r_val) != k { 1 } else { 0 };
-            } else {
-                panic!("Don't know how to compare otjer types yet")
+/**
+ * Where a faulty value came from, used to describe it in error messages
+ */
+#[derive(Debug, Clone, Copy)]
+pub enum Origin {
+    Register(usize),
+    Upvalue(usize),
+    Unknown,
+}
+
+impl Default for LuaVm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn arith_event(opcode: &LuaOpcode) -> TMS {
+    match opcode {
+        LuaOpcode::ADDI_ABsC | LuaOpcode::ADDK_ABC | LuaOpcode::ADD_ABC => TMS::ADD,
+        LuaOpcode::SUBK_ABC | LuaOpcode::SUB_ABC => TMS::SUB,
+        LuaOpcode::MULK_ABC | LuaOpcode::MUL_ABC => TMS::MUL,
+        LuaOpcode::MODK_ABC | LuaOpcode::MOD_ABC => TMS::MOD,
+        LuaOpcode::POWK_ABC | LuaOpcode::POW_ABC => TMS::POW,
+        LuaOpcode::DIVK_ABC | LuaOpcode::DIV_ABC => TMS::DIV,
+        LuaOpcode::IDIVK_ABC | LuaOpcode::IDIV_ABC => TMS::IDIV,
+        LuaOpcode::BANDK_ABC | LuaOpcode::BAND_ABC => TMS::BAND,
+        LuaOpcode::BORK_ABC | LuaOpcode::BOR_ABC => TMS::BOR,
+        LuaOpcode::BXORK_ABC | LuaOpcode::BXOR_ABC => TMS::BXOR,
+        LuaOpcode::SHRI_ABsC | LuaOpcode::SHR_ABC => TMS::SHR,
+        LuaOpcode::SHLI_ABsC | LuaOpcode::SHL_ABC => TMS::SHL,
+        _ => panic!("{:?} is not an arithmetic opcode", opcode),
+    }
+}
+
+/**
+ * Converts numbers to strings for concatenation (luaO_tostring), None for anything else
+ */
+pub fn coerce_to_string(value: &TValue) -> Option<Rc<LuaString>> {
+    match value {
+        TValue::STR(s) => Some(s.clone()),
+        TValue::NUMINT(i) => Some(Rc::new(LuaString::from(i.to_string()))),
+        TValue::NUMFLT(f) => Some(Rc::new(LuaString::from(number::float_to_string(*f)))),
+        _ => None,
+    }
+}
+
+impl LuaVm {
+
+    pub fn new() -> Self {
+        Self {
+            globals: LuaTable::new_ref(),
+            stdout: Box::new(io::stdout()),
+            load_limits: LoadLimits::default(),
+            type_metatables: Default::default(),
+        }
+    }
+
+    /**
+     * Creates the main closure of a chunk: its first upvalue is _ENV, set to the globals table
+     */
+    pub fn load(&mut self, proto: Rc<Proto>) -> TValue {
+        let upvalues = proto.upvalues.iter().enumerate()
+            .map(|(i, _)| {
+                let value = if i == 0 { TValue::TABLE(self.globals.clone()) } else { TValue::NIL };
+                Rc::new(RefCell::new(UpVal::Closed(value)))
+            })
+            .collect();
+        TValue::CLOSURE(Rc::new(Closure::new_lua(proto, upvalues)))
+    }
+
+    /**
+     * luaL_loadbufferx: 'mode' tells which chunk kinds ("b" binary, "t" text) are accepted.
+     * Binary chunks are held to 'load_limits' and verified before they can run. Errors are the
+     * message 'load' returns
+     */
+    pub fn load_chunk(&mut self, chunk: &[u8], chunk_name: &str, mode: &str) -> Result<TValue, String> {
+        let is_binary = chunk.first() == Some(&LUA_SIGNATURE[0]);
+        let kind = if is_binary { "binary" } else { "text" };
+        if !mode.contains(if is_binary { 'b' } else { 't' }) {
+            return Err(format!("attempt to load a {} chunk (mode is '{}')", kind, mode));
+        }
+        if !is_binary {
+            return compiler::codegen::compile(chunk, chunk_name).map(|proto| self.load(Rc::new(proto)));
+        }
+        match parser::parse_with_limits(chunk, self.load_limits).and_then(|proto| verify(&proto).map(|_| proto)) {
+            Ok(proto) => Ok(self.load(Rc::new(proto))),
+            Err(why) => {
+                let name = match chunk_name.as_bytes().first() {
+                    Some(b'@') | Some(b'=') => &chunk_name[1..],
+                    Some(first) if *first == LUA_SIGNATURE[0] => "binary string",
+                    _ => chunk_name,
+                };
+                Err(format!("{}: bad binary format ({})", name, why))
             }
-        },
-        Order::LessOrEqual => {
-            if let (TValue::NUMINT(l_val), TValue::NUMINT(r_val)) = (left, right) {
-                result = if (l_val <= r_val) != k { 1 } else { 0 };
-            } else if let (TValue::NUMFLT(l_val), TValue::NUMFLT(r_val)) = (left, right) {
-                result = if (l_val <= r_val) != k { 1 } else { 0 };
-            } else {
-                panic!("Don't know how to compare otjer types yet")
+        }
+    }
+
+    /**
+     * Calls the function at fn_idx with arguments up to the thread top (luaD_call).
+     * Results are moved to fn_idx and the top is set after the last one
+     */
+    pub fn call(&mut self, thread: &mut LuaThread, fn_idx: StackIndex, nresults: i16) -> Result<(), LuaError> {
+        if thread.n_ccalls >= LUAI_MAXCCALLS {
+            return Err(self.runtime_error(thread, "C stack overflow"));
+        }
+        thread.n_ccalls += 1;
+        let result = match self.precall(thread, fn_idx, nresults) {
+            Ok(true) => {
+                thread.current_call.front_mut().unwrap().is_fresh = true;
+                self.execute(thread)
+            },
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        thread.n_ccalls -= 1;
+        result
+    }
+
+    /**
+     * Pushes function and arguments on top of the stack, calls it and collects every result
+     */
+    pub fn call_value(&mut self, thread: &mut LuaThread, function: TValue, args: &[TValue]) -> Result<Vec<TValue>, LuaError> {
+        let fn_idx = thread.top;
+        thread.push(function);
+        for arg in args {
+            thread.push(arg.clone());
+        }
+        self.call(thread, fn_idx, LUA_MULTRET)?;
+        let results = (fn_idx..thread.top).map(|i| thread.stack.get_at_offset(i).clone()).collect();
+        thread.top = fn_idx;
+        Ok(results)
+    }
+
+    /**
+     * Calls a value in protected mode: on error the thread is restored to the state before the call
+     */
+    pub fn protected_call(&mut self, thread: &mut LuaThread, function: TValue, args: &[TValue]) -> Result<Vec<TValue>, LuaError> {
+        self.protected_call_with_handler(thread, function, args, None)
+    }
+
+    /**
+     * luaD_pcall: the message handler runs before the stack is unwound so it can inspect it,
+     * pending to-be-closed variables are closed with the error object
+     */
+    pub fn protected_call_with_handler(&mut self, thread: &mut LuaThread, function: TValue, args: &[TValue], handler: Option<TValue>) -> Result<Vec<TValue>, LuaError> {
+        let old_top = thread.top;
+        let old_depth = thread.current_call.len();
+        let old_ccalls = thread.n_ccalls;
+        let mut error = match self.call_value(thread, function, args) {
+            Ok(results) => return Ok(results),
+            Err(error) => error,
+        };
+        if let Some(handler) = handler {
+            self.protect_frame(thread);
+            error = match self.call_value(thread, handler, &[error.value]) {
+                Ok(results) => LuaError::from_value(results.into_iter().next().unwrap_or(TValue::NIL)),
+                Err(_) => LuaError::new("error in error handling"),
+            };
+        }
+        while thread.current_call.len() > old_depth {
+            thread.current_call.pop_front();
+        }
+        thread.n_ccalls = old_ccalls;
+        thread.close_upvalues(old_top);
+        // errors raised by closing methods replace the original one
+        loop {
+            thread.top = thread.top.max(old_top);
+            match self.close_tbc(thread, old_top, Some(error.value.clone())) {
+                Ok(()) => break,
+                Err(close_error) => {
+                    while thread.current_call.len() > old_depth {
+                        thread.current_call.pop_front();
+                    }
+                    thread.n_ccalls = old_ccalls;
+                    error = close_error;
+                }
             }
         }
-        Order::Equal => {
-            if let (TValue::NUMINT(l_val), TValue::NUMINT(r_val)) = (left, right) {
-                result = if (l_val == r_val) != k { 1 } else { 0 };
-            } else if let (TValue::NUMFLT(l_val), TValue::NUMFLT(r_val)) = (left, right) {
-                result = if (l_val == r_val) != k { 1 } else { 0 };
-            } else {
-                panic!("Don't know how to compare otjer types yet")
+        thread.top = old_top;
+        Err(error)
+    }
+
+    /**
+     * Raises the top to the end of the running Lua frame, so nested calls don't overwrite its registers.
+     * Native frames already keep their top after the last pushed value
+     */
+    pub fn protect_frame(&self, thread: &mut LuaThread) {
+        if let Some(call_info) = thread.current_call.front() {
+            if call_info.is_lua && thread.top < call_info.top {
+                thread.top = call_info.top;
             }
-        },
-        Order::Greater => {
-            if let (TValue::NUMINT(l_val), TValue::NUMINT(r_val)) = (left, right) {
-                result = if (l_val > r_val) != k { 1 } else { 0 };
-            } else if let (TValue::NUMFLT(l_val), TValue::NUMFLT(r_val)) = (left, right) {
-                result = if (l_val > r_val) != k { 1 } else { 0 };
-            } else {
-                panic!("Don't know how to compare otjer types yet")
+        }
+    }
+
+    /**
+     * Closes to-be-closed variables at or above 'level' calling their '__close' metamethods
+     */
+    pub fn close_tbc(&mut self, thread: &mut LuaThread, level: StackIndex, error: Option<TValue>) -> Result<(), LuaError> {
+        while let Some(&index) = thread.tbc_list.last() {
+            if index < level {
+                break;
             }
-        },
-        Order::GreaterOrEqual => {
-            if let (TValue::NUMINT(l_val), TValue::NUMINT(r_val)) = (left, right) {
-                result = if (l_val >= r_val) != k { 1 } else { 0 };
-            } else if let (TValue::NUMFLT(l_val), TValue::NUMFLT(r_val)) = (left, right) {
-                result = if (l_val >= r_val) != k { 1 } else { 0 };
-            } else {
-                panic!("Don't know how to compare otjer types yet")
+            thread.tbc_list.pop();
+            let value = thread.stack.get_at_offset(index).clone();
+            let tm = self.get_tm(&value, TMS::CLOSE);
+            self.protect_frame(thread);
+            thread.top = thread.top.max(index + 1);
+            self.call_value(thread, tm, &[value, error.clone().unwrap_or(TValue::NIL)])?;
+        }
+        Ok(())
+    }
+
+    /**
+     * tryfuncTM: a non function value is called through its '__call' metamethod,
+     * which is inserted below the arguments
+     */
+    fn try_func_tm(&mut self, thread: &mut LuaThread, fn_idx: StackIndex) -> Result<(), LuaError> {
+        let value = thread.stack.get_at_offset(fn_idx).clone();
+        let tm = self.get_tm(&value, TMS::CALL);
+        if tm.is_nil() {
+            return Err(self.call_error(thread, &value));
+        }
+        for i in (fn_idx..thread.top).rev() {
+            let value = thread.stack.get_at_offset(i).clone();
+            thread.stack.set_at_offset(value, i + 1);
+        }
+        thread.top += 1;
+        thread.stack.set_at_offset(tm, fn_idx);
+        Ok(())
+    }
+
+    fn get_function(&mut self, thread: &mut LuaThread, fn_idx: StackIndex) -> Result<Rc<Closure>, LuaError> {
+        loop {
+            if let TValue::CLOSURE(closure) = thread.stack.get_at_offset(fn_idx) {
+                return Ok(closure.clone());
             }
-        },
-    };
+            self.try_func_tm(thread, fn_idx)?;
+        }
+    }
 
-    result
-}
+    /**
+     * luaD_precall: returns true when a Lua frame was pushed and should be executed,
+     * native functions are run to completion here
+     */
+    fn precall(&mut self, thread: &mut LuaThread, fn_idx: StackIndex, nresults: i16) -> Result<bool, LuaError> {
+        let closure = self.get_function(thread, fn_idx)?;
+        match closure.as_ref() {
+            Closure::C(_) => {
+                self.call_native(thread, fn_idx, nresults, closure.clone())?;
+                Ok(false)
+            },
+            Closure::Lua(lua_closure) => {
+                let proto = &lua_closure.proto;
+                let frame_size = proto.max_stack_size as usize;
+                self.check_stack(thread, fn_idx + 1 + frame_size)?;
+                let mut new_call_info = CallInfo::new_lua(proto, fn_idx);
+                new_call_info.nresults = nresults;
+                let mut narg = thread.top - fn_idx - 1;
+                while narg < proto.num_params as usize {
+                    thread.push(TValue::NIL);
+                    narg += 1;
+                }
+                thread.current_call.push_front(new_call_info);
+                Ok(true)
+            }
+        }
+    }
 
-impl LuaVm {
+    fn check_stack(&self, thread: &mut LuaThread, size: usize) -> Result<(), LuaError> {
+        if size > LUAI_MAXSTACK {
+            return Err(self.runtime_error(thread, "stack overflow"));
+        }
+        thread.stack.ensure(size);
+        Ok(())
+    }
 
-    pub fn step(&mut self, thread: &mut LuaThread) {
-        let call_info = thread.current_call.front_mut().unwrap();
-        let lua_closure = match call_info.get_closure(&thread.stack) {
-            Closure::Lua(lua_closure) => { lua_closure },
-            Closure::C(_) => { panic!("C closure shouldn't occur here") }
+    /**
+     * Runs a native function: arguments are above fn_idx, results are moved to fn_idx
+     */
+    fn call_native(&mut self, thread: &mut LuaThread, fn_idx: StackIndex, nresults: i16, closure: Rc<Closure>) -> Result<(), LuaError> {
+        let function = match closure.as_ref() {
+            Closure::C(c_closure) => c_closure.fn_ptr.clone(),
+            Closure::Lua(_) => panic!("Expected native closure"),
+        };
+        let base = fn_idx + 1;
+        let num_args = thread.top - base;
+        self.check_stack(thread, thread.top + LUA_MINSTACK)?;
+        let mut call_info = CallInfo::new_native(fn_idx, thread.top + LUA_MINSTACK);
+        call_info.nresults = nresults;
+        thread.current_call.push_front(call_info);
+        if thread.hook.is_some() {
+            self.hook_native_call(thread, num_args)?;
+        }
+        let n = {
+            let mut view = LuaStackView::new(self, thread, closure, base, num_args);
+            function(&mut view)?
         };
+        let n = n.min(thread.top - base);
+        if thread.hook.is_some() {
+            self.hook_return(thread, n)?;
+        }
+        let call_info = thread.current_call.pop_front().unwrap();
+        self.move_results(thread, call_info.fn_idx, n, call_info.nresults);
+        Ok(())
+    }
 
-        let instruction = lua_closure.proto.code.get(call_info.pc).expect("No more opcodes");
-        let mut frame = LuaStackView::new(&mut thread.stack, &mut call_info);
-        call_info.pc += 1;
-        match instruction.opcode {
-            LuaOpcode::MOVE_AB => {
-                frame.set_register(instruction.args.get_A().into(), frame.get_register(instruction.args.get_B().into()).clone());
-            },
-            LuaOpcode::LOADI_AsBx => {
-                frame.set_register(instruction.args.get_A().into(), TValue::NUMINT(instruction.args.get_sBx().into()));
-            },
-            LuaOpcode::LOADF_AsBx => {
-                frame.set_register(instruction.args.get_A().into(), TValue::NUMFLT(instruction.args.get_sBx().into()));
-            },
-            LuaOpcode::LOADK_ABx => {
-                frame.set_register(instruction.args.get_A().into(), lua_closure.proto.constants.get(instruction.args.get_Bx() as usize).unwrap().clone());
-            },
-            LuaOpcode::LOADKX_A => {
-                let extra_arg = lua_closure.proto.code.get(call_info.pc).unwrap();
-                let val = lua_closure.proto.constants.get(extra_arg.args.get_Ax() as usize).unwrap();
-                frame.set_register(instruction.args.get_A().into(), val.clone());
-                call_info.pc +=1;
-            },
-            LuaOpcode::LOADFALSE_A => {
-                frame.set_register(instruction.args.get_A().into(), TValue::TBOOLEAN(false));
-            },
-            LuaOpcode::LFALSESKIP_A => 
-            {
-                frame.set_register(instruction.args.get_A().into(), TValue::TBOOLEAN(false));
-                call_info.pc += 1;
-            },
-            LuaOpcode::LOADTRUE_A => {
-                frame.set_register(instruction.args.get_A().into(), TValue::TBOOLEAN(true));
-            },
-            LuaOpcode::LOADNIL_ABC => {
-                let count = instruction.args.get_B();
-                let base = instruction.args.get_A() as StackIndex;
-                for i in 0..count {
-                    frame.set_register(base + (i as StackIndex), TValue::NIL);
-                }
-            },
-            LuaOpcode::GETUPVAL_AB => {
-                let upval = lua_closure.upvalues.get(instruction.args.get_B() as usize).unwrap();
-                frame.set_register(instruction.args.get_A().into(), *upval.get_value(&thread.stack));
-            },
-            LuaOpcode::SETUPVAL_AB => {
-                let val = frame.get_register_mut(instruction.args.get_A().into());
-                lua_closure.upvalues[instruction.args.get_B() as usize].set_value(&mut thread.stack, val);
+    /**
+     * moveresults: the last 'nres' values on the stack are moved to 'res'
+     */
+    fn move_results(&self, thread: &mut LuaThread, res: StackIndex, nres: usize, wanted: i16) {
+        let wanted = if wanted == LUA_MULTRET { nres } else { wanted as usize };
+        let first_result = thread.top - nres;
+        for i in 0..wanted {
+            let value = if i < nres { thread.stack.get_at_offset(first_result + i).clone() } else { TValue::NIL };
+            thread.stack.set_at_offset(value, res + i);
+        }
+        thread.top = res + wanted;
+    }
+
+    /**
+     * luaD_poscall: finishes the current frame, returns true if the frame was entered from native code
+     */
+    fn pos_call(&mut self, thread: &mut LuaThread, nres: usize) -> Result<bool, LuaError> {
+        if thread.hook.is_some() {
+            self.hook_return(thread, nres)?;
+        }
+        let call_info = thread.current_call.pop_front().unwrap();
+        self.move_results(thread, call_info.fn_idx, nres, call_info.nresults);
+        Ok(call_info.is_fresh)
+    }
+
+    /**
+     * Values from 'ra' up to the top, left by the instruction before; an error rather than an
+     * underflow for bytecode where no instruction set the top
+     */
+    fn open_count(&self, thread: &LuaThread, ra: usize) -> Result<usize, LuaError> {
+        thread.top.checked_sub(ra).ok_or_else(|| self.runtime_error(thread, "no open values on the stack"))
+    }
+
+    /**
+     * Base of a vararg function once its extra arguments are dropped (luaT_adjustvarargs undone)
+     */
+    fn vararg_base(&self, thread: &LuaThread, fn_idx: usize, delta: usize) -> Result<usize, LuaError> {
+        fn_idx.checked_sub(delta).ok_or_else(|| self.runtime_error(thread, "vararg adjustment beyond the stack"))
+    }
+
+    /**
+     * Builds an error message with the position of the current Lua function (luaG_runerror)
+     */
+    pub fn runtime_error(&self, thread: &LuaThread, message: &str) -> LuaError {
+        let position = match thread.current_call.front() {
+            Some(call_info) if call_info.is_lua => {
+                let proto = call_info.get_lua_proto(&thread.stack).unwrap();
+                let line = debug::get_func_line(&proto, call_info.pc.saturating_sub(1)).unwrap_or(-1);
+                format!("{}:{}: ", debug::short_source(&proto), line)
             },
-            LuaOpcode::ADDI_ABsC => {
-                let immediate = instruction.args.get_sC();
-                let dst = instruction.args.get_A();
-                let tval = frame.get_register(instruction.args.get_B().into());
-                let result = match tval {
-                    TValue::NUMFLT(f) => TValue::NUMFLT(f + immediate as f64),
-                    TValue::NUMINT(i) => TValue::NUMINT(i + immediate as i64),
-                    _ => {
-                        todo!("Add meta methods for arith support")
+            _ => String::new(),
+        };
+        LuaError::new(&format!("{}{}", position, message))
+    }
+
+    /**
+     * luaL_where: position of the function at the given level of the call stack, if it's a Lua one
+     */
+    pub fn where_(&self, thread: &LuaThread, level: usize) -> String {
+        if let Some(call_info) = thread.current_call.iter().nth(level) {
+            if call_info.is_lua {
+                let proto = call_info.get_lua_proto(&thread.stack).unwrap();
+                if let Some(line) = debug::get_func_line(&proto, call_info.pc.saturating_sub(1)) {
+                    if line > 0 {
+                        return format!("{}:{}: ", debug::short_source(&proto), line);
                     }
-                };
-                frame.set_register(dst.into(), result);
-            },
-            LuaOpcode::JMP_sJ => {
-                call_info.pc = (call_info.pc as i64 + instruction.args.get_sJ() as i64) as usize;
-            },
-            LuaOpcode::EQ_ABk => {
-                // if ((R[A] == R[B]) ~= k) then pc++
-                
-                call_info.pc += compare(
-                    frame.get_register(instruction.args.get_A().into()),
-                    frame.get_register(instruction.args.get_B().into()),
-                    instruction.args.get_k(),
-                    Order::Equal
-                );
-            },
-            LuaOpcode::LT_ABk => {
-                call_info.pc += compare(
-                    frame.get_register(instruction.args.get_A().into()),
-                    frame.get_register(instruction.args.get_B().into()),
-                    instruction.args.get_k(),
-                    Order::Less
-                );
-            },
-            LuaOpcode::LE_ABk => {
-                call_info.pc += compare(
-                    frame.get_register(instruction.args.get_A().into()),
-                    frame.get_register(instruction.args.get_B().into()),
-                    instruction.args.get_k(),
-                    Order::LessOrEqual
-                );
-            },
-            LuaOpcode::EQK_ABk => {
-                call_info.pc += compare(
-                    frame.get_register(instruction.args.get_A().into()),
-                    lua_closure.proto.constants.get(instruction.args.get_B() as usize).unwrap(),
-                    instruction.args.get_k(),
-                    Order::Equal
-                );
-            },
-            LuaOpcode::EQI_AsBk => {
-                let left = frame.get_register(instruction.args.get_A().into());
-                let right = instruction.args.get_sB();
-                let k = instruction.args.get_k();
-
-                if let TValue::NUMINT(val_int) = left {
-                    call_info.pc += if (*val_int == (right as i64)) != k { 1 } else { 0 }    
-                } else if let TValue::NUMFLT(val_flt) = left {
-                    call_info.pc += if (*val_flt == (right as f64)) != k { 1 } else { 0 }
-                } else {
-                    todo!();
-                }
-            },
-            LuaOpcode::LTI_AsBk => {
-                let left = frame.get_register(instruction.args.get_A().into());
-                let right = instruction.args.get_sB();
-                let k = instruction.args.get_k();
-
-                if let TValue::NUMINT(val_int) = left {
-                    call_info.pc += if (*val_int < (right as i64)) != k { 1 } else { 0 }    
-                } else if let TValue::NUMFLT(val_flt) = left {
-                    call_info.pc += if (*val_flt < (right as f64)) != k { 1 } else { 0 }
-                } else {
-                    todo!();
                 }
-            },
-            LuaOpcode::LEI_AsBk => {
-                let left = frame.get_register(instruction.args.get_A().into());
-                let right = instruction.args.get_sB();
-                let k = instruction.args.get_k();
-
-                if let TValue::NUMINT(val_int) = left {
-                    call_info.pc += if (*val_int <= (right as i64)) != k { 1 } else { 0 }    
-                } else if let TValue::NUMFLT(val_flt) = left {
-                    call_info.pc += if (*val_flt <= (right as f64)) != k { 1 } else { 0 }
-                } else {
-                    todo!();
+            }
+        }
+        String::new()
+    }
+
+    /**
+     * getfuncname for the running function: how the calling Lua code refers to it
+     */
+    pub fn current_function_name(&self, thread: &LuaThread) -> Option<(&'static str, String)> {
+        self.function_name(thread, 0)
+    }
+
+    /**
+     * pushglobalfuncname: "name" or "library.name" when the function is reachable from the globals
+     */
+    pub fn global_function_name(&self, closure: &Rc<Closure>) -> Option<String> {
+        let function = TValue::CLOSURE(closure.clone());
+        let globals = self.globals.borrow();
+        let mut libraries = vec![];
+        for (key, value) in globals.iter() {
+            if let TValue::STR(name) = &key {
+                if value.raw_equals(&function) {
+                    return Some(name.to_string());
                 }
-            },
-            LuaOpcode::GTI_AsBk => {
-                let left = frame.get_register(instruction.args.get_A().into());
-                let right = instruction.args.get_sB();
-                let k = instruction.args.get_k();
-
-                if let TValue::NUMINT(val_int) = left {
-                    call_info.pc += if (*val_int > (right as i64)) != k { 1 } else { 0 }    
-                } else if let TValue::NUMFLT(val_flt) = left {
-                    call_info.pc += if (*val_flt > (right as f64)) != k { 1 } else { 0 }
-                } else {
-                    todo!();
+                if let TValue::TABLE(library) = value {
+                    if !Rc::ptr_eq(&library, &self.globals) {
+                        libraries.push((name.clone(), library));
+                    }
                 }
-            },
-            LuaOpcode::GEI_AsBk => {
-                let left = frame.get_register(instruction.args.get_A().into());
-                let right = instruction.args.get_sB();
-                let k = instruction.args.get_k();
-
-                if let TValue::NUMINT(val_int) = left {
-                    call_info.pc += if (*val_int >= (right as i64)) != k { 1 } else { 0 }    
-                } else if let TValue::NUMFLT(val_flt) = left {
-                    call_info.pc += if (*val_flt >= (right as f64)) != k { 1 } else { 0 }
-                } else {
-                    todo!();
+            }
+        }
+        for (library_name, library) in libraries {
+            for (key, value) in library.borrow().iter() {
+                if let TValue::STR(name) = key {
+                    if value.raw_equals(&function) {
+                        return Some(format!("{}.{}", library_name, name));
+                    }
                 }
+            }
+        }
+        None
+    }
+
+    /**
+     * varinfo: describes a value used by the current Lua function, e.g. " (local 'x')"
+     */
+    fn varinfo(&self, thread: &LuaThread, origin: Origin) -> String {
+        let call_info = match thread.current_call.front() {
+            Some(call_info) if call_info.is_lua => call_info,
+            _ => return String::new(),
+        };
+        let proto = call_info.get_lua_proto(&thread.stack).unwrap();
+        let info = match origin {
+            Origin::Upvalue(index) => Some(("upvalue", debug::upvalue_name(&proto, index))),
+            Origin::Register(reg) => debug::get_obj_name(&proto, call_info.pc.saturating_sub(1), reg),
+            Origin::Unknown => None,
+        };
+        match info {
+            Some((kind, name)) => format!(" ({} '{}')", kind, name),
+            None => String::new(),
+        }
+    }
+
+    /**
+     * luaG_typeerror
+     */
+    pub fn type_error(&self, thread: &LuaThread, value: &TValue, op: &str, origin: Origin) -> LuaError {
+        let info = self.varinfo(thread, origin);
+        self.runtime_error(thread, &format!("attempt to {} a {} value{}", op, value.type_name(), info))
+    }
+
+    /**
+     * luaG_callerror: names the called value from the calling instruction
+     */
+    fn call_error(&self, thread: &LuaThread, value: &TValue) -> LuaError {
+        let name = match thread.current_call.front() {
+            Some(call_info) if call_info.is_lua => {
+                let proto = call_info.get_lua_proto(&thread.stack).unwrap();
+                debug::func_name_from_code(&proto, call_info.pc.saturating_sub(1))
             },
-            LuaOpcode::TEST_Ak => {
-                // if (not R[A] == k) then pc++
-                let left = frame.get_register(instruction.args.get_A().into());
-                let k = instruction.args.get_k();
-                let result = match left {
-                    TValue::NIL => true,
-                    TValue::TBOOLEAN(b) => !b,
-                    _ => true, 
-                };
-                if result != k {
-                    call_info.pc += 1;
+            _ => None,
+        };
+        let info = match name {
+            Some((kind, name)) => format!(" ({} '{}')", kind, name),
+            None => String::new(),
+        };
+        self.runtime_error(thread, &format!("attempt to call a {} value{}", value.type_name(), info))
+    }
+
+    /**
+     * luaV_concat: concatenates 'total' values starting at 'first', the result is stored at 'first'
+     */
+    fn concat(&mut self, thread: &mut LuaThread, base: StackIndex, first: StackIndex, total: usize) -> Result<(), LuaError> {
+        let mut top = first + total;
+        let mut total = total;
+        while total > 1 {
+            let p1 = thread.stack.get_at_offset(top - 2).clone();
+            let p2 = thread.stack.get_at_offset(top - 1).clone();
+            let n = if coerce_to_string(&p1).is_none() || coerce_to_string(&p2).is_none() {
+                let origins = (Origin::Register(top - 2 - base), Origin::Register(top - 1 - base));
+                let result = self.try_bin_tm(thread, &p1, &p2, TMS::CONCAT, origins)?;
+                thread.stack.set_at_offset(result, top - 2);
+                2
+            } else {
+                // collect as many string-convertible values as possible
+                let mut n = 2;
+                while n < total && coerce_to_string(thread.stack.get_at_offset(top - n - 1)).is_some() {
+                    n += 1;
                 }
-            },
-            LuaOpcode::CALL_ABC => {
-                let fn_idx = instruction.args.get_A() as StackIndex;
-                let number_of_args = instruction.args.get_B() as usize;
-                let nresults = instruction.args.get_C() - 1;
-                if number_of_args != 0 {
-                    thread.top = fn_idx + number_of_args;
+                let mut result = Vec::new();
+                for i in (top - n)..top {
+                    result.extend_from_slice(&coerce_to_string(thread.stack.get_at_offset(i)).unwrap());
                 }
-                let closure = match frame.get_register(fn_idx) {
-                    TValue::CLOSURE(closure) => {
-                        closure.as_ref()
-                    },
-                    _ => panic!("Expected TValue at offset to be closure"),
-                };
-                match closure {
-                    Closure::C(c_closure) => {
-                        // Implement C-gate here
-                        (c_closure.fn_ptr)();
-                    },
-                    Closure::Lua(lua_closure) => {
-                        let mut new_call_info = CallInfo::new_lua(lua_closure.proto, fn_idx);
-                        new_call_info.nresults = nresults.into();
-                        thread.current_call.push_front(new_call_info);
+                thread.stack.set_at_offset(TValue::STR(Rc::new(LuaString::new(result))), top - n);
+                n
+            };
+            total -= n - 1;
+            top -= n - 1;
+        }
+        Ok(())
+    }
+
+    /**
+     * forprep: prepares a numeric loop, returns true when the loop must be skipped
+     */
+    fn for_prep(&self, thread: &mut LuaThread, ra: StackIndex) -> Result<bool, LuaError> {
+        let init = thread.stack.get_at_offset(ra).clone();
+        let limit = thread.stack.get_at_offset(ra + 1).clone();
+        let step = thread.stack.get_at_offset(ra + 2).clone();
+        if let (TValue::NUMINT(init), TValue::NUMINT(step)) = (&init, &step) {
+            let (init, step) = (*init, *step);
+            if step == 0 {
+                return Err(self.runtime_error(thread, "'for' step is zero"));
+            }
+            thread.stack.set_at_offset(TValue::NUMINT(init), ra + 3);
+            let mode = if step < 0 { F2IMode::Ceil } else { F2IMode::Floor };
+            let limit = match limit.to_integer(mode) {
+                Some(limit) => limit,
+                None => {
+                    let flimit = limit.to_number().ok_or_else(|| self.for_error(thread, &limit, "limit"))?;
+                    if flimit > 0.0 {
+                        if step < 0 { return Ok(true); }
+                        i64::MAX
+                    } else {
+                        if step > 0 { return Ok(true); }
+                        i64::MIN
                     }
                 }
+            };
+            if if step > 0 { init > limit } else { init < limit } {
+                return Ok(true);
+            }
+            let count = if step > 0 {
+                let count = (limit as u64).wrapping_sub(init as u64);
+                if step != 1 { count / step as u64 } else { count }
+            } else {
+                let count = (init as u64).wrapping_sub(limit as u64);
+                count / ((-(step + 1)) as u64 + 1)
+            };
+            thread.stack.set_at_offset(TValue::NUMINT(count as i64), ra + 1);
+        } else {
+            let flimit = limit.to_number().ok_or_else(|| self.for_error(thread, &limit, "limit"))?;
+            let fstep = step.to_number().ok_or_else(|| self.for_error(thread, &step, "step"))?;
+            let finit = init.to_number().ok_or_else(|| self.for_error(thread, &init, "initial value"))?;
+            if fstep == 0.0 {
+                return Err(self.runtime_error(thread, "'for' step is zero"));
+            }
+            if if 0.0 < fstep { flimit < finit } else { finit < flimit } {
+                return Ok(true);
+            }
+            thread.stack.set_at_offset(TValue::NUMFLT(flimit), ra + 1);
+            thread.stack.set_at_offset(TValue::NUMFLT(fstep), ra + 2);
+            thread.stack.set_at_offset(TValue::NUMFLT(finit), ra);
+            thread.stack.set_at_offset(TValue::NUMFLT(finit), ra + 3);
+        }
+        Ok(false)
+    }
 
-                /*
-                Proto *p = clLvalue(s2v(func))->p;
-                int narg = cast_int(L->top.p - func) - 1;  /* number of real arguments */
-                int nfixparams = p->numparams;
-                int fsize = p->maxstacksize;  /* frame size */
-                checkstackGCp(L, fsize, func);
-                CallInfo *ci = L->ci = next_ci(L);  /* new frame */
-                ci->func.p = func;
-                ci->nresults = nresults;
-                ci->callstatus = 0;
-                ci->top.p = func + 1 + fsize;
-                ci->u.l.savedpc = p->code;  /* starting point */
-                for (; narg < nfixparams; narg++)
-                    setnilvalue(s2v(L->top.p++));  /* complete missing arguments */
-                lua_assert(ci->top.p <= L->stack_last.p);
-                return ci;
-                 */
-            },
-            LuaOpcode::TAILCALL_ABCk => {
+    fn for_error(&self, thread: &LuaThread, value: &TValue, what: &str) -> LuaError {
+        self.runtime_error(thread, &format!("bad 'for' {} (number expected, got {})", what, value.type_name()))
+    }
 
-            },
-            LuaOpcode::RETURN0 => {
-                // pop call info
-                thread.current_call.pop_front();
-                // empty stack frame
-                todo!();
-            },
-            LuaOpcode::RETURN1_A => {
-                // pop call info
-                thread.current_call.pop_front();
-                // empty stack frame
-                todo!();
-                // set return values
-            },
-            LuaOpcode::CLOSURE_ABx => {
-                let proto = lua_closure.proto.fns.get(instruction.args.get_Bx() as usize).unwrap();
-                let mut new_closure = Closure::new_lua(proto);
-                thread.stack.set_at_offset(TValue::CLOSURE(Rc::new(new_closure)), instruction.args.get_A().into());
-                new_closure.init_upvalues(proto.upvalues, lua_closure.upvalues);
+    /**
+     * Runs Lua frames until the frame that started this execution returns (luaV_execute)
+     */
+    pub fn execute(&mut self, thread: &mut LuaThread) -> Result<(), LuaError> {
+        'newframe: loop {
+            let closure = {
+                let call_info = thread.current_call.front().expect("No active call");
+                call_info.get_closure(&thread.stack)
+            };
+            let lua_closure = match closure.as_ref() {
+                Closure::Lua(lua_closure) => { lua_closure },
+                Closure::C(_) => { panic!("C closure shouldn't occur here") }
+            };
+            let proto = lua_closure.proto.clone();
+            let mut base = thread.current_call.front().unwrap().base;
+            // vararg functions get their call event after VARARGPREP
+            if thread.hook.is_some() && thread.current_call.front().unwrap().pc == 0 && !proto.is_vararg {
+                self.hook_call(thread, &proto)?;
             }
-            LuaOpcode::VARARG_AC => {
-                let n = instruction.args.get_C() as i64 - 1;
-                let wanted = call_info.nextraargs;
-                let count = if n < 0 { wanted } else { n as usize };
-                let wh = instruction.args.get_A() as usize;
-                for i in 0..wanted {
-                    match i < count {
-                        true =>  todo!("vararg"), //self.stack.set_at_offset(value, wh + i),
-                        false => frame.set_register(wh + i, TValue::NIL),
-                    };
+
+            macro_rules! reg {
+                ($idx:expr) => { thread.stack.get_at_offset(base + ($idx) as usize) };
+            }
+            macro_rules! set_reg {
+                ($idx:expr, $value:expr) => {{
+                    let value = $value;
+                    thread.stack.set_at_offset(value, base + ($idx) as usize);
+                }};
+            }
+            macro_rules! konst {
+                ($idx:expr) => { &proto.constants[($idx) as usize] };
+            }
+            macro_rules! jump {
+                ($offset:expr) => {{
+                    let call_info = thread.current_call.front_mut().unwrap();
+                    call_info.pc = (call_info.pc as i64 + ($offset) as i64) as usize;
+                }};
+            }
+            macro_rules! cond_jump {
+                ($cond:expr, $k:expr) => {{
+                    if $cond != $k {
+                        jump!(1);
+                    }
+                }};
+            }
+
+            loop {
+                if thread.hook.is_some() {
+                    let pc = thread.current_call.front().unwrap().pc;
+                    if pc != 0 || !proto.is_vararg {
+                        self.trace_exec(thread, &proto, pc)?;
+                    }
+                }
+                let pc = {
+                    let call_info = thread.current_call.front_mut().unwrap();
+                    let pc = call_info.pc;
+                    call_info.pc += 1;
+                    pc
                 };
-            },
-            LuaOpcode::VARARGPREP_A => {
-                //ProtectNT(luaT_adjustvarargs(L, GETARG_A(i), ci, cl->p));
-                //updatebase(ci);  /* function has new base after adjustment */ 
-                
-                let nfixparams = instruction.args.get_A();
-                let total_args_count = thread.top - call_info.fn_idx;
-                let vararg_count = total_args_count - 1 - nfixparams as usize;
-                call_info.nextraargs = vararg_count;
-                
-                thread.stack.set_at_offset(thread.stack.get_at_offset(call_info.fn_idx).clone(), thread.top);
-                thread.top += 1;
-
-                for i in 1..=nfixparams as usize {
-                    thread.stack.set_at_offset(thread.stack.get_at_offset(call_info.fn_idx + i).clone(), thread.top);
-                    thread.stack.set_at_offset(TValue::NIL, call_info.fn_idx + i);
-                    thread.top += 1;
+                let instruction = proto.code.get(pc).expect("No more opcodes");
+                let opcode = instruction.opcode;
+                match instruction.decoded() {
+                    Instruction::Move { a, b } => {
+                        set_reg!(a, reg!(b).clone());
+                    },
+                    Instruction::LoadI { a, sbx } => {
+                        set_reg!(a, TValue::NUMINT(sbx.into()));
+                    },
+                    Instruction::LoadF { a, sbx } => {
+                        set_reg!(a, TValue::NUMFLT(sbx.into()));
+                    },
+                    Instruction::LoadK { a, bx } => {
+                        set_reg!(a, konst!(bx).clone());
+                    },
+                    Instruction::LoadKX { a } => {
+                        let extra_arg = &proto.code[pc + 1];
+                        set_reg!(a, konst!(extra_arg.args.get_Ax()).clone());
+                        jump!(1);
+                    },
+                    Instruction::LoadFalse { a } => {
+                        set_reg!(a, TValue::TBOOLEAN(false));
+                    },
+                    Instruction::LFalseSkip { a } => {
+                        set_reg!(a, TValue::TBOOLEAN(false));
+                        jump!(1);
+                    },
+                    Instruction::LoadTrue { a } => {
+                        set_reg!(a, TValue::TBOOLEAN(true));
+                    },
+                    Instruction::LoadNil { a, b } => {
+                        for i in 0..=b as usize {
+                            set_reg!(a as usize + i, TValue::NIL);
+                        }
+                    },
+                    Instruction::GetUpval { a, b } => {
+                        let upval = lua_closure.upvalue(b as usize);
+                        let value = upval.borrow().get_value(&thread.stack).clone();
+                        set_reg!(a, value);
+                    },
+                    Instruction::SetUpval { a, b } => {
+                        let value = reg!(a).clone();
+                        lua_closure.upvalue(b as usize).borrow_mut().set_value(&mut thread.stack, value);
+                    },
+                    Instruction::AddI { a, b, sc } | Instruction::ShrI { a, b, sc } => {
+                        let result = arith::arith(arith_event(&opcode), reg!(b), &TValue::NUMINT(sc.into()));
+                        if let Some(value) = result.map_err(|m| self.runtime_error(thread, m))? {
+                            set_reg!(a, value);
+                            jump!(1);
+                        }
+                    },
+                    Instruction::ShlI { a, b, sc } => {
+                        let result = arith::arith(TMS::SHL, &TValue::NUMINT(sc.into()), reg!(b));
+                        if let Some(value) = result.map_err(|m| self.runtime_error(thread, m))? {
+                            set_reg!(a, value);
+                            jump!(1);
+                        }
+                    },
+                    Instruction::AddK { a, b, c } | Instruction::SubK { a, b, c } | Instruction::MulK { a, b, c } |
+                    Instruction::ModK { a, b, c } | Instruction::PowK { a, b, c } | Instruction::DivK { a, b, c } |
+                    Instruction::IDivK { a, b, c } | Instruction::BAndK { a, b, c } | Instruction::BOrK { a, b, c } |
+                    Instruction::BXorK { a, b, c } => {
+                        let result = arith::arith(arith_event(&opcode), reg!(b), konst!(c));
+                        if let Some(value) = result.map_err(|m| self.runtime_error(thread, m))? {
+                            set_reg!(a, value);
+                            jump!(1);
+                        }
+                    },
+                    Instruction::Add { a, b, c } | Instruction::Sub { a, b, c } | Instruction::Mul { a, b, c } |
+                    Instruction::Mod { a, b, c } | Instruction::Pow { a, b, c } | Instruction::Div { a, b, c } |
+                    Instruction::IDiv { a, b, c } | Instruction::BAnd { a, b, c } | Instruction::BOr { a, b, c } |
+                    Instruction::BXor { a, b, c } | Instruction::Shl { a, b, c } | Instruction::Shr { a, b, c } => {
+                        let result = arith::arith(arith_event(&opcode), reg!(b), reg!(c));
+                        if let Some(value) = result.map_err(|m| self.runtime_error(thread, m))? {
+                            set_reg!(a, value);
+                            jump!(1);
+                        }
+                    },
+                    mm @ (Instruction::MmBin { a, tm, .. } | Instruction::MmBinI { a, tm, .. } | Instruction::MmBinK { a, tm, .. }) => {
+                        // the result goes to the destination of the arithmetic opcode that failed
+                        let destination = proto.code[pc - 1].args.get_A() as usize;
+                        let event = TMS::from(tm);
+                        let a = a as usize;
+                        let p1 = reg!(a).clone();
+                        let (p2, origin, flip) = match mm {
+                            Instruction::MmBinI { sb, k, .. } => (TValue::NUMINT(sb.into()), Origin::Unknown, k),
+                            Instruction::MmBinK { b_k, k, .. } => (konst!(b_k).clone(), Origin::Unknown, k),
+                            Instruction::MmBin { b, .. } => (reg!(b).clone(), Origin::Register(b as usize), false),
+                            _ => unreachable!(),
+                        };
+                        let result = if flip {
+                            self.try_bin_tm(thread, &p2, &p1, event, (origin, Origin::Register(a)))?
+                        } else {
+                            self.try_bin_tm(thread, &p1, &p2, event, (Origin::Register(a), origin))?
+                        };
+                        set_reg!(destination, result);
+                    },
+                    Instruction::Unm { a, b } | Instruction::BNot { a, b } => {
+                        let event = if opcode == LuaOpcode::UNM_AB { TMS::UNM } else { TMS::BNOT };
+                        let b = b as usize;
+                        let value = reg!(b).clone();
+                        let result = match arith::arith(event, &value, &value).map_err(|m| self.runtime_error(thread, m))? {
+                            Some(result) => result,
+                            None => self.try_bin_tm(thread, &value, &value, event, (Origin::Register(b), Origin::Register(b)))?,
+                        };
+                        set_reg!(a, result);
+                    },
+                    Instruction::Not { a, b } => {
+                        let value = reg!(b).is_falsy();
+                        set_reg!(a, TValue::TBOOLEAN(value));
+                    },
+                    Instruction::Len { a, b } => {
+                        let b = b as usize;
+                        let value = reg!(b).clone();
+                        let result = self.length(thread, &value, Origin::Register(b))?;
+                        set_reg!(a, result);
+                    },
+                    Instruction::Concat { a, b } => {
+                        let ra = base + a as usize;
+                        let n = b as usize;
+                        thread.top = ra + n;
+                        self.concat(thread, base, ra, n)?;
+                    },
+                    Instruction::Close { a } => {
+                        let ra = base + a as usize;
+                        thread.close_upvalues(ra);
+                        self.close_tbc(thread, ra, None)?;
+                    },
+                    Instruction::Tbc { a } => {
+                        let value = reg!(a).clone();
+                        if !value.is_falsy() {
+                            if self.get_tm(&value, TMS::CLOSE).is_nil() {
+                                let name = debug::get_local_name(&proto, a as usize + 1, pc).unwrap_or_else(|| "?".to_string());
+                                return Err(self.runtime_error(thread, &format!("variable '{}' got a non-closable value", name)));
+                            }
+                            thread.tbc_list.push(base + a as usize);
+                        }
+                    },
+                    Instruction::Jmp { sj } => {
+                        jump!(sj);
+                    },
+                    Instruction::Eq { a, b, k } => {
+                        let (p1, p2) = (reg!(a).clone(), reg!(b).clone());
+                        let cond = self.equals(thread, &p1, &p2)?;
+                        cond_jump!(cond, k);
+                    },
+                    Instruction::Lt { a, b, k } => {
+                        let (p1, p2) = (reg!(a).clone(), reg!(b).clone());
+                        let cond = self.less_than(thread, &p1, &p2)?;
+                        cond_jump!(cond, k);
+                    },
+                    Instruction::Le { a, b, k } => {
+                        let (p1, p2) = (reg!(a).clone(), reg!(b).clone());
+                        let cond = self.less_equal(thread, &p1, &p2)?;
+                        cond_jump!(cond, k);
+                    },
+                    Instruction::EqK { a, b_k, k } => {
+                        let cond = reg!(a).raw_equals(konst!(b_k));
+                        cond_jump!(cond, k);
+                    },
+                    Instruction::EqI { a, sb, k, .. } => {
+                        let cond = match reg!(a) {
+                            TValue::NUMINT(i) => *i == sb as i64,
+                            TValue::NUMFLT(f) => *f == sb as f64,
+                            _ => false,
+                        };
+                        cond_jump!(cond, k);
+                    },
+                    Instruction::LtI { a, sb, is_float, k } | Instruction::LeI { a, sb, is_float, k } |
+                    Instruction::GtI { a, sb, is_float, k } | Instruction::GeI { a, sb, is_float, k } => {
+                        let p1 = reg!(a).clone();
+                        let immediate = if is_float { TValue::NUMFLT(sb as f64) } else { TValue::NUMINT(sb.into()) };
+                        let cond = match opcode {
+                            LuaOpcode::LTI_AsBk => self.less_than(thread, &p1, &immediate)?,
+                            LuaOpcode::LEI_AsBk => self.less_equal(thread, &p1, &immediate)?,
+                            LuaOpcode::GTI_AsBk => self.less_than(thread, &immediate, &p1)?,
+                            _ => self.less_equal(thread, &immediate, &p1)?,
+                        };
+                        cond_jump!(cond, k);
+                    },
+                    Instruction::Test { a, k } => {
+                        let cond = !reg!(a).is_falsy();
+                        cond_jump!(cond, k);
+                    },
+                    Instruction::TestSet { a, b, k } => {
+                        let value = reg!(b).clone();
+                        if value.is_falsy() == k {
+                            jump!(1);
+                        } else {
+                            set_reg!(a, value);
+                        }
+                    },
+                    Instruction::Call { a, b, c } => {
+                        let ra = base + a as usize;
+                        let b = b as usize;
+                        let nresults = c as i16 - 1;
+                        if b != 0 {
+                            thread.top = ra + b;
+                        }
+                        if self.precall(thread, ra, nresults)? {
+                            continue 'newframe;
+                        }
+                    },
+                    Instruction::TailCall { a, b, c, k } => {
+                        let ra = base + a as usize;
+                        let mut b = b as usize;
+                        let nparams1 = c as usize;
+                        let delta = if nparams1 != 0 { thread.current_call.front().unwrap().nextraargs + nparams1 } else { 0 };
+                        if b != 0 {
+                            thread.top = ra + b;
+                        } else {
+                            b = self.open_count(thread, ra)?;
+                        }
+                        if k {
+                            thread.close_upvalues(base);
+                        }
+                        let old_top = thread.top;
+                        let callee = self.get_function(thread, ra)?;
+                        b += thread.top - old_top;
+                        match callee.as_ref() {
+                            Closure::Lua(callee_closure) => {
+                                let callee_proto = &callee_closure.proto;
+                                let old_call_info = thread.current_call.pop_front().unwrap();
+                                let fn_idx = self.vararg_base(thread, old_call_info.fn_idx, delta)?;
+                                for i in 0..b {
+                                    let value = thread.stack.get_at_offset(ra + i).clone();
+                                    thread.stack.set_at_offset(value, fn_idx + i);
+                                }
+                                let mut narg1 = b;
+                                while narg1 <= callee_proto.num_params as usize {
+                                    thread.stack.set_at_offset(TValue::NIL, fn_idx + narg1);
+                                    narg1 += 1;
+                                }
+                                thread.top = fn_idx + narg1;
+                                let mut new_call_info = CallInfo::new_lua(callee_proto, fn_idx);
+                                new_call_info.nresults = old_call_info.nresults;
+                                new_call_info.is_fresh = old_call_info.is_fresh;
+                                new_call_info.is_tail = true;
+                                thread.current_call.push_front(new_call_info);
+                                self.check_stack(thread, fn_idx + 1 + callee_proto.max_stack_size as usize)?;
+                                continue 'newframe;
+                            },
+                            Closure::C(_) => {
+                                self.call_native(thread, ra, LUA_MULTRET, callee.clone())?;
+                                let n = self.open_count(thread, ra)?;
+                                let fn_idx = self.vararg_base(thread, thread.current_call.front().unwrap().fn_idx, delta)?;
+                                thread.current_call.front_mut().unwrap().fn_idx = fn_idx;
+                                if self.pos_call(thread, n)? {
+                                    return Ok(());
+                                }
+                                continue 'newframe;
+                            }
+                        }
+                    },
+                    Instruction::Return { a, b, c, k } => {
+                        let ra = base + a as usize;
+                        let n = match b {
+                            0 => self.open_count(thread, ra)?,
+                            b => b as usize - 1,
+                        };
+                        if k {
+                            thread.close_upvalues(base);
+                            thread.top = ra + n;
+                            self.close_tbc(thread, base, None)?;
+                        }
+                        let nparams1 = c as usize;
+                        if nparams1 != 0 {
+                            let call_info = thread.current_call.front().unwrap();
+                            let fn_idx = self.vararg_base(thread, call_info.fn_idx, call_info.nextraargs + nparams1)?;
+                            thread.current_call.front_mut().unwrap().fn_idx = fn_idx;
+                        }
+                        thread.top = ra + n;
+                        if self.pos_call(thread, n)? {
+                            return Ok(());
+                        }
+                        continue 'newframe;
+                    },
+                    Instruction::Return0 { a } => {
+                        thread.top = base + a as usize;
+                        if self.pos_call(thread, 0)? {
+                            return Ok(());
+                        }
+                        continue 'newframe;
+                    },
+                    Instruction::Return1 { a } => {
+                        thread.top = base + a as usize + 1;
+                        if self.pos_call(thread, 1)? {
+                            return Ok(());
+                        }
+                        continue 'newframe;
+                    },
+                    Instruction::ForLoop { a, bx } => {
+                        let a = a as usize;
+                        if let TValue::NUMINT(step) = *reg!(a + 2) {
+                            let count = match reg!(a + 1) { TValue::NUMINT(count) => *count as u64, _ => 0 };
+                            if count > 0 {
+                                let index = match reg!(a) { TValue::NUMINT(i) => *i, _ => 0 }.wrapping_add(step);
+                                set_reg!(a + 1, TValue::NUMINT((count - 1) as i64));
+                                set_reg!(a, TValue::NUMINT(index));
+                                set_reg!(a + 3, TValue::NUMINT(index));
+                                jump!(-(bx as i64));
+                            }
+                        } else {
+                            let float = |value: &TValue| match value { TValue::NUMFLT(f) => *f, _ => 0.0 };
+                            let step = float(reg!(a + 2));
+                            let limit = float(reg!(a + 1));
+                            let index = float(reg!(a)) + step;
+                            if if 0.0 < step { index <= limit } else { limit <= index } {
+                                set_reg!(a, TValue::NUMFLT(index));
+                                set_reg!(a + 3, TValue::NUMFLT(index));
+                                jump!(-(bx as i64));
+                            }
+                        }
+                    },
+                    Instruction::ForPrep { a, bx } => {
+                        if self.for_prep(thread, base + a as usize)? {
+                            jump!(bx as i64 + 1);
+                        }
+                    },
+                    Instruction::TForPrep { bx, .. } => {
+                        jump!(bx);
+                    },
+                    Instruction::TForCall { a, c } => {
+                        let a = a as usize;
+                        for i in 0..3 {
+                            let value = reg!(a + i).clone();
+                            set_reg!(a + 4 + i, value);
+                        }
+                        thread.top = base + a + 4 + 3;
+                        self.call(thread, base + a + 4, c as i16)?;
+                    },
+                    Instruction::TForLoop { a, bx } => {
+                        let a = a as usize;
+                        let control = reg!(a + 4).clone();
+                        if !control.is_nil() {
+                            set_reg!(a + 2, control);
+                            jump!(-(bx as i64));
+                        }
+                    },
+                    Instruction::Closure { a, bx } => {
+                        let child = proto.fns[bx as usize].clone();
+                        let upvalues = child.upvalues.iter().map(|descr| {
+                            if descr.instack {
+                                thread.find_upvalue(base + descr.idx as usize)
+                            } else {
+                                lua_closure.upvalue(descr.idx as usize)
+                            }
+                        }).collect();
+                        set_reg!(a, TValue::CLOSURE(Rc::new(Closure::new_lua(child, upvalues))));
+                    },
+                    Instruction::VarArg { a, c } => {
+                        let ra = base + a as usize;
+                        let n = c as i64 - 1;
+                        let (nextra, fn_idx) = {
+                            let call_info = thread.current_call.front().unwrap();
+                            (call_info.nextraargs, call_info.fn_idx)
+                        };
+                        let wanted = if n < 0 {
+                            self.check_stack(thread, ra + nextra)?;
+                            thread.top = ra + nextra;
+                            nextra
+                        } else {
+                            n as usize
+                        };
+                        for i in 0..wanted {
+                            let value = if i < nextra { thread.stack.get_at_offset(fn_idx - nextra + i).clone() } else { TValue::NIL };
+                            thread.stack.set_at_offset(value, ra + i);
+                        }
+                    },
+                    Instruction::VarArgPrep { a } => {
+                        // luaT_adjustvarargs: function and fixed parameters are copied above the varargs
+                        let nfixparams = a as usize;
+                        let fn_idx = thread.current_call.front().unwrap().fn_idx;
+                        let actual = thread.top - fn_idx - 1;
+                        self.check_stack(thread, thread.top + proto.max_stack_size as usize + 1)?;
+                        let function = thread.stack.get_at_offset(fn_idx).clone();
+                        thread.push(function);
+                        for i in 1..=nfixparams {
+                            let value = thread.stack.get_at_offset(fn_idx + i).clone();
+                            thread.push(value);
+                            thread.stack.set_at_offset(TValue::NIL, fn_idx + i);
+                        }
+                        let call_info = thread.current_call.front_mut().unwrap();
+                        call_info.nextraargs = actual - nfixparams;
+                        call_info.fn_idx += actual + 1;
+                        call_info.top += actual + 1;
+                        call_info.base = call_info.fn_idx + 1;
+                        base = call_info.base;
+                        if thread.hook.is_some() {
+                            self.hook_call(thread, &proto)?;
+                            // the next instruction is seen as a new line
+                            thread.old_pc = 1;
+                        }
+                    },
+                    Instruction::GetTabUp { a, b, key_k } => {
+                        let upval = lua_closure.upvalue(b as usize);
+                        let table = upval.borrow().get_value(&thread.stack).clone();
+                        let value = self.index(thread, &table, konst!(key_k), Origin::Upvalue(b as usize))?;
+                        set_reg!(a, value);
+                    },
+                    Instruction::GetTable { a, b, c } => {
+                        let (table, key) = (reg!(b).clone(), reg!(c).clone());
+                        let value = self.index(thread, &table, &key, Origin::Register(b as usize))?;
+                        set_reg!(a, value);
+                    },
+                    Instruction::GetI { a, b, c } => {
+                        let table = reg!(b).clone();
+                        let value = self.index(thread, &table, &TValue::NUMINT(c.into()), Origin::Register(b as usize))?;
+                        set_reg!(a, value);
+                    },
+                    Instruction::GetField { a, b, key_k } => {
+                        let table = reg!(b).clone();
+                        let value = self.index(thread, &table, konst!(key_k), Origin::Register(b as usize))?;
+                        set_reg!(a, value);
+                    },
+                    Instruction::SetTabUp { a, key_k, c, k } => {
+                        let upval = lua_closure.upvalue(a as usize);
+                        let table = upval.borrow().get_value(&thread.stack).clone();
+                        let key = konst!(key_k).clone();
+                        let value = if k { konst!(c).clone() } else { reg!(c).clone() };
+                        self.set_index(thread, &table, key, value, Origin::Upvalue(a as usize))?;
+                    },
+                    set @ (Instruction::SetTable { a, c, k, .. } | Instruction::SetI { a, c, k, .. } |
+                           Instruction::SetField { a, c, k, .. }) => {
+                        let table = reg!(a).clone();
+                        let key = match set {
+                            Instruction::SetTable { b, .. } => reg!(b).clone(),
+                            Instruction::SetI { b, .. } => TValue::NUMINT(b.into()),
+                            Instruction::SetField { key_k, .. } => konst!(key_k).clone(),
+                            _ => unreachable!(),
+                        };
+                        let value = if k { konst!(c).clone() } else { reg!(c).clone() };
+                        self.set_index(thread, &table, key, value, Origin::Register(a as usize))?;
+                    },
+                    Instruction::NewTable { a, b, c, k } => {
+                        let hash_size = if b > 0 { 1_usize << (b - 1) } else { 0 };
+                        let mut array_size = c as usize;
+                        if k {
+                            array_size += proto.code[pc + 1].args.get_Ax() as usize * (MAXARG_C as usize + 1);
+                        }
+                        jump!(1);
+                        let table = LuaTable::with_capacity(array_size, hash_size);
+                        set_reg!(a, TValue::TABLE(Rc::new(RefCell::new(table))));
+                    },
+                    Instruction::Self_ { a, b, c, k } => {
+                        let object = reg!(b).clone();
+                        let key = if k { konst!(c).clone() } else { reg!(c).clone() };
+                        set_reg!(a as usize + 1, object.clone());
+                        let method = self.index(thread, &object, &key, Origin::Register(b as usize))?;
+                        set_reg!(a, method);
+                    },
+                    Instruction::SetList { a, b, c, k } => {
+                        let ra = base + a as usize;
+                        let n = match b as usize {
+                            0 => self.open_count(thread, ra + 1)?,
+                            n => n,
+                        };
+                        let mut last = c as usize;
+                        if k {
+                            last += proto.code[pc + 1].args.get_Ax() as usize * (MAXARG_C as usize + 1);
+                            jump!(1);
+                        }
+                        if let TValue::TABLE(table) = reg!(a) {
+                            let mut table = table.borrow_mut();
+                            for i in 1..=n {
+                                table.set_int((last + i) as i64, thread.stack.get_at_offset(ra + i).clone());
+                            }
+                        }
+                    },
+                    Instruction::ExtraArg { .. } => {
+                        // only reached by code that jumps over the instruction it extends
+                        return Err(self.runtime_error(thread, "EXTRAARG executed on its own"));
+                    }
                 }
+            }
+        }
+    }
+}
 
-                call_info.fn_idx = total_args_count;
-                call_info.top = total_args_count;
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
 
-                
-                
-            },
-            LuaOpcode::EXTRAARG_Ax => {
-                panic!("That shouldn't be executed");
+    use crate::core::{builder::ProtoBuilder, opcodes::{LuaOpcode, encode_abck, encode_abx, encode_asbx, encode_ax, encode_sj}, parser::parse_all, types::{TValue, LuaType, LuaThread, Closure, LuaError, stack::LuaStackView, table::LuaTable}};
+    use super::{LuaVm, Origin};
+
+    fn native(f: impl Fn(&mut LuaStackView) -> Result<usize, LuaError> + 'static) -> TValue {
+        TValue::CLOSURE(Rc::new(Closure::new_native(f, vec![])))
+    }
+
+    #[test]
+    fn native_receives_arguments_and_returns_many_results() {
+        let mut vm = LuaVm::new();
+        let mut thread = LuaThread::new();
+        let sum_and_count = native(|frame| {
+            let mut sum = 0;
+            for i in 1..=frame.arg_count() {
+                sum += frame.check_integer(i)?;
+            }
+            frame.push(TValue::NUMINT(sum));
+            frame.push(TValue::NUMINT(frame.arg_count() as i64));
+            Ok(2)
+        });
+        let results = vm.call_value(&mut thread, sum_and_count, &[TValue::NUMINT(1), TValue::NUMINT(2), TValue::NUMINT(39)]).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].raw_equals(&TValue::NUMINT(42)));
+        assert!(results[1].raw_equals(&TValue::NUMINT(3)));
+        assert_eq!(thread.top, 0);
+        assert!(thread.current_call.is_empty());
+    }
+
+    #[test]
+    fn native_closure_keeps_state_in_upvalues() {
+        let mut vm = LuaVm::new();
+        let mut thread = LuaThread::new();
+        let counter = TValue::CLOSURE(Rc::new(Closure::new_native(|frame| {
+            let next = match frame.get_upvalue(0) { TValue::NUMINT(i) => i + 1, _ => 1 };
+            frame.set_upvalue(0, TValue::NUMINT(next));
+            Ok(frame.set_return_values(&[TValue::NUMINT(next)]))
+        }, vec![TValue::NUMINT(0)])));
+        vm.call_value(&mut thread, counter.clone(), &[]).unwrap();
+        let results = vm.call_value(&mut thread, counter, &[]).unwrap();
+        assert!(results[0].raw_equals(&TValue::NUMINT(2)));
+    }
+
+    #[test]
+    fn native_errors_are_protected() {
+        let mut vm = LuaVm::new();
+        let mut thread = LuaThread::new();
+        let failing = native(|frame| Err(frame.type_error(1, "number")));
+        let error = vm.protected_call(&mut thread, failing, &[]).unwrap_err();
+        assert_eq!(error.to_string(), "bad argument #1 to '?' (number expected, got no value)");
+        assert!(thread.current_call.is_empty());
+        assert_eq!(thread.top, 0);
+    }
+
+    #[test]
+    fn lua_calls_native_functions() {
+        // helpers/tests/call_native.lua
+        let chunk = include_bytes!("../../helpers/tests/call_native.out");
+        let mut vm = LuaVm::new();
+        let mut thread = LuaThread::new();
+        let main = vm.load(Rc::new(parse_all(chunk).unwrap()));
+        let divmod = native(|frame| {
+            let (a, b) = (frame.check_integer(1)?, frame.check_integer(2)?);
+            if b == 0 {
+                return Err(frame.error("division by zero"));
             }
-            _ => todo!("Not implemented")
+            Ok(frame.set_return_values(&[TValue::NUMINT(a / b), TValue::NUMINT(a % b)]))
+        });
+        let results = vm.call_value(&mut thread, main.clone(), &[divmod.clone(), TValue::NUMINT(17), TValue::NUMINT(5)]).unwrap();
+        let expected = [3, 2, 3, 2];
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(expected) {
+            assert!(result.raw_equals(&TValue::NUMINT(expected)), "{} != {}", result, expected);
         }
+
+        let error = vm.protected_call(&mut thread, main, &[divmod, TValue::NUMINT(1), TValue::NUMINT(0)]).unwrap_err();
+        assert_eq!(error.to_string(), "call_native.lua:4: division by zero");
     }
 
-    pub fn init(&mut self) {
+    #[test]
+    fn hosts_set_metatables_for_primitive_types() {
+        let mut vm = LuaVm::new();
+        let mut thread = LuaThread::new();
+        let metatable = LuaTable::new_ref();
+        metatable.borrow_mut().set_str("__index", native(|frame| {
+            let doubled = frame.check_integer(1)? * 2;
+            Ok(frame.set_return_values(&[TValue::NUMINT(doubled)]))
+        }));
+        vm.set_type_metatable(LuaType::Number, Some(metatable.clone()));
+        let value = vm.index(&mut thread, &TValue::NUMINT(21), &TValue::from_str("double"), Origin::Unknown).unwrap();
+        assert!(value.raw_equals(&TValue::NUMINT(42)));
+        assert!(vm.get_metatable(&TValue::NUMFLT(0.5)).is_some_and(|mt| Rc::ptr_eq(&mt, &metatable)));
+        assert!(vm.get_metatable(&TValue::TBOOLEAN(true)).is_none());
 
+        vm.set_type_metatable(LuaType::Number, None);
+        assert!(vm.index(&mut thread, &TValue::NUMINT(21), &TValue::from_str("double"), Origin::Unknown).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn run_built_prototypes() {
+        // function(n) local sum = 0; for i = 1, n do sum = sum + i * k end; return sum end, with k an upvalue
+        let mut f = ProtoBuilder::new("=built");
+        f.params(1, false).upvalue("k", true, 0);
+        f.emit(encode_asbx(LuaOpcode::LOADI_AsBx, 1, 0).unwrap());
+        f.emit(encode_asbx(LuaOpcode::LOADI_AsBx, 2, 1).unwrap());
+        f.emit(encode_abck(LuaOpcode::MOVE_AB, 3, 0, 0, false).unwrap());
+        f.emit(encode_asbx(LuaOpcode::LOADI_AsBx, 4, 1).unwrap());
+        f.emit_jump(LuaOpcode::FORPREP_ABx, 2, "loop").unwrap();
+        f.label("body").unwrap();
+        f.emit(encode_abck(LuaOpcode::GETUPVAL_AB, 6, 0, 0, false).unwrap());
+        f.emit(encode_abck(LuaOpcode::MUL_ABC, 6, 5, 6, false).unwrap());
+        f.emit(encode_abck(LuaOpcode::MMBIN_ABC, 5, 6, 8, false).unwrap());
+        f.emit(encode_abck(LuaOpcode::ADD_ABC, 1, 1, 6, false).unwrap());
+        f.emit(encode_abck(LuaOpcode::MMBIN_ABC, 1, 6, 6, false).unwrap());
+        f.label("loop").unwrap();
+        f.emit_jump(LuaOpcode::FORLOOP_ABx, 2, "body").unwrap();
+        f.emit(encode_abck(LuaOpcode::RETURN1_A, 1, 0, 0, false).unwrap());
+        let sum = f.build().unwrap();
+
+        // main: k = 3; return sum(n)
+        let mut main = ProtoBuilder::new("=built");
+        main.params(0, true).upvalue("_ENV", true, 0);
+        let three = main.constant(TValue::NUMINT(3)) as u32;
+        let child = main.child(sum) as u32;
+        main.emit(encode_abck(LuaOpcode::VARARGPREP_A, 0, 0, 0, false).unwrap());
+        main.emit(encode_abx(LuaOpcode::LOADK_ABx, 0, three).unwrap());
+        main.emit(encode_abx(LuaOpcode::CLOSURE_ABx, 1, child).unwrap());
+        main.emit(encode_abck(LuaOpcode::VARARG_AC, 2, 0, 2, false).unwrap());
+        main.emit(encode_abck(LuaOpcode::CALL_ABC, 1, 2, 0, false).unwrap());
+        main.emit(encode_abck(LuaOpcode::RETURN_ABCk, 1, 0, 1, false).unwrap());
+        let main = main.build().unwrap();
+        assert_eq!(main.fns[0].fn_name, main.fn_name);
+
+        let mut vm = LuaVm::new();
+        let mut thread = LuaThread::new();
+        let main = vm.load(Rc::new(main));
+        let results = vm.call_value(&mut thread, main, &[TValue::NUMINT(4)]).unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].raw_equals(&TValue::NUMINT(30)), "{}", results[0]);
+    }
+
+    #[test]
+    fn stray_extra_arg_is_an_error() {
+        // JMP over LOADKX lands on its EXTRAARG; only unverified prototypes get here
+        let mut f = ProtoBuilder::new("=test");
+        f.line(1);
+        f.emit(encode_sj(LuaOpcode::JMP_sJ, 1).unwrap());
+        f.emit(encode_abck(LuaOpcode::LOADKX_A, 0, 0, 0, false).unwrap());
+        f.emit(encode_ax(LuaOpcode::EXTRAARG_Ax, 0).unwrap());
+        f.emit(encode_abck(LuaOpcode::RETURN0, 0, 0, 0, false).unwrap());
+        let mut vm = LuaVm::new();
+        let main = vm.load(Rc::new(f.build().unwrap()));
+        let error = vm.protected_call(&mut LuaThread::new(), main, &[]).unwrap_err();
+        assert_eq!(error.to_string(), "test:1: EXTRAARG executed on its own");
+    }
+
+    #[test]
+    fn missing_open_values_are_an_error() {
+        // JMP skips the CALL that sets the top for RETURN; only unverified prototypes get here
+        let mut f = ProtoBuilder::new("=test");
+        f.line(1).params(0, true).max_stack_size(8);
+        f.emit(encode_abck(LuaOpcode::VARARGPREP_A, 0, 0, 0, false).unwrap());
+        f.emit(encode_sj(LuaOpcode::JMP_sJ, 1).unwrap());
+        f.emit(encode_abck(LuaOpcode::CALL_ABC, 5, 1, 0, false).unwrap());
+        f.emit(encode_abck(LuaOpcode::RETURN_ABCk, 5, 0, 1, false).unwrap());
+        let mut vm = LuaVm::new();
+        let main = vm.load(Rc::new(f.build().unwrap()));
+        let error = vm.protected_call(&mut LuaThread::new(), main, &[]).unwrap_err();
+        assert_eq!(error.to_string(), "test:1: no open values on the stack");
+    }
+}